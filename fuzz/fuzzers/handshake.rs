@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate rustls;
+
+use rustls::fuzzing::fuzz_handshake_message;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fuzz_handshake_message(data);
+});
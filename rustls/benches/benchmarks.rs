@@ -4,6 +4,8 @@ use bencher::{benchmark_group, benchmark_main, Bencher};
 mod test_utils;
 use test_utils::*;
 
+use rustls::cipher_suite::{TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384};
+use rustls::crypto::hash::{Context, Hash};
 use rustls::ServerConnection;
 
 use std::io;
@@ -16,5 +18,160 @@ fn bench_ewouldblock(c: &mut Bencher) {
     c.iter(|| server.read_tls(&mut read_ewouldblock));
 }
 
-benchmark_group!(benches, bench_ewouldblock);
+fn bench_server_connection_new(c: &mut Bencher) {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    c.iter(|| ServerConnection::new(Arc::clone(&server_config)).unwrap());
+}
+
+/// Accepting a connection, up to the point that the server has
+/// chosen its parameters and replied to the ClientHello.
+fn bench_server_accept_client_hello(c: &mut Bencher) {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut client_hello = Vec::new();
+    client
+        .write_tls(&mut client_hello)
+        .unwrap();
+
+    c.iter(|| {
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        server
+            .read_tls(&mut &client_hello[..])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        server
+    });
+}
+
+// Roughly the size of a handshake transcript by the time it is last
+// hashed, with a small and a large certificate chain respectively.
+const SMALL_TRANSCRIPT: usize = 4 * 1024;
+const LARGE_TRANSCRIPT: usize = 32 * 1024;
+
+fn transcript_context(hash: &dyn Hash, len: usize) -> Box<dyn Context> {
+    let mut ctx = hash.start();
+    ctx.update(&vec![0x5a; len]);
+    ctx
+}
+
+fn bench_fork_finish(c: &mut Bencher, hash: &dyn Hash, len: usize) {
+    let ctx = transcript_context(hash, len);
+    c.iter(|| ctx.fork_finish());
+}
+
+fn bench_fork_then_finish(c: &mut Bencher, hash: &dyn Hash, len: usize) {
+    let ctx = transcript_context(hash, len);
+    c.iter(|| ctx.fork().finish());
+}
+
+fn bench_sha256_fork_finish_small(c: &mut Bencher) {
+    bench_fork_finish(
+        c,
+        TLS13_AES_128_GCM_SHA256.hash_provider(),
+        SMALL_TRANSCRIPT,
+    );
+}
+
+fn bench_sha256_fork_then_finish_small(c: &mut Bencher) {
+    bench_fork_then_finish(
+        c,
+        TLS13_AES_128_GCM_SHA256.hash_provider(),
+        SMALL_TRANSCRIPT,
+    );
+}
+
+fn bench_sha256_fork_finish_large(c: &mut Bencher) {
+    bench_fork_finish(
+        c,
+        TLS13_AES_128_GCM_SHA256.hash_provider(),
+        LARGE_TRANSCRIPT,
+    );
+}
+
+fn bench_sha256_fork_then_finish_large(c: &mut Bencher) {
+    bench_fork_then_finish(
+        c,
+        TLS13_AES_128_GCM_SHA256.hash_provider(),
+        LARGE_TRANSCRIPT,
+    );
+}
+
+fn bench_sha384_fork_finish_small(c: &mut Bencher) {
+    bench_fork_finish(
+        c,
+        TLS13_AES_256_GCM_SHA384.hash_provider(),
+        SMALL_TRANSCRIPT,
+    );
+}
+
+fn bench_sha384_fork_then_finish_small(c: &mut Bencher) {
+    bench_fork_then_finish(
+        c,
+        TLS13_AES_256_GCM_SHA384.hash_provider(),
+        SMALL_TRANSCRIPT,
+    );
+}
+
+fn bench_sha384_fork_finish_large(c: &mut Bencher) {
+    bench_fork_finish(
+        c,
+        TLS13_AES_256_GCM_SHA384.hash_provider(),
+        LARGE_TRANSCRIPT,
+    );
+}
+
+fn bench_sha384_fork_then_finish_large(c: &mut Bencher) {
+    bench_fork_then_finish(
+        c,
+        TLS13_AES_256_GCM_SHA384.hash_provider(),
+        LARGE_TRANSCRIPT,
+    );
+}
+
+fn bench_compute_many(c: &mut Bencher, hash: &dyn Hash) {
+    let transcript = vec![0x5a; LARGE_TRANSCRIPT];
+    let messages = transcript
+        .chunks(256)
+        .collect::<Vec<_>>();
+    c.iter(|| hash.compute_many(&messages));
+}
+
+fn bench_update_each(c: &mut Bencher, hash: &dyn Hash) {
+    let transcript = vec![0x5a; LARGE_TRANSCRIPT];
+    let messages = transcript
+        .chunks(256)
+        .collect::<Vec<_>>();
+    c.iter(|| {
+        let mut ctx = hash.start();
+        for m in &messages {
+            ctx.update(m);
+        }
+        ctx.finish()
+    });
+}
+
+fn bench_sha256_compute_many(c: &mut Bencher) {
+    bench_compute_many(c, TLS13_AES_128_GCM_SHA256.hash_provider());
+}
+
+fn bench_sha256_update_each(c: &mut Bencher) {
+    bench_update_each(c, TLS13_AES_128_GCM_SHA256.hash_provider());
+}
+
+benchmark_group!(
+    benches,
+    bench_ewouldblock,
+    bench_server_connection_new,
+    bench_server_accept_client_hello,
+    bench_sha256_fork_finish_small,
+    bench_sha256_fork_then_finish_small,
+    bench_sha256_fork_finish_large,
+    bench_sha256_fork_then_finish_large,
+    bench_sha384_fork_finish_small,
+    bench_sha384_fork_then_finish_small,
+    bench_sha384_fork_finish_large,
+    bench_sha384_fork_then_finish_large,
+    bench_sha256_compute_many,
+    bench_sha256_update_each
+);
 benchmark_main!(benches);
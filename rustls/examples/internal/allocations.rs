@@ -0,0 +1,189 @@
+// This program counts heap allocations made by rustls handshakes, with
+// and without a shared BufferPool.
+//
+// It replaces the global allocator, so is kept apart from the timing
+// benchmarks in bench.rs.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rustls::crypto::ring::Ring;
+use rustls::server::NoServerSessionStorage;
+use rustls::BufferPool;
+use rustls::RootCertStore;
+use rustls::{ClientConfig, ClientConnection};
+use rustls::{ConnectionCommon, SideData};
+use rustls::{ServerConfig, ServerConnection};
+
+/// The system allocator, counting how many allocations are made.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn path_for(key_type: &str, part: &str) -> String {
+    format!("test-ca/{}/{}", key_type, part)
+}
+
+fn make_server_config(
+    key_type: &str,
+    version: &'static rustls::SupportedProtocolVersion,
+) -> ServerConfig<Ring> {
+    let chain = rustls_pemfile::certs(&mut io::BufReader::new(
+        fs::File::open(path_for(key_type, "end.fullchain")).unwrap(),
+    ))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+    let key = rustls::PrivateKey(
+        rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
+            fs::File::open(path_for(key_type, "end.key")).unwrap(),
+        ))
+        .unwrap()
+        .remove(0),
+    );
+
+    let mut cfg = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .expect("bad certs/private key?");
+    cfg.session_storage = Arc::new(NoServerSessionStorage {});
+    cfg
+}
+
+fn make_client_config(
+    key_type: &str,
+    version: &'static rustls::SupportedProtocolVersion,
+) -> ClientConfig<Ring> {
+    let mut root_store = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(fs::File::open(path_for(key_type, "ca.cert")).unwrap());
+    root_store.add_parsable_certificates(rustls_pemfile::certs(&mut rootbuf).unwrap());
+
+    let mut config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.resumption = rustls::client::Resumption::disabled();
+    config
+}
+
+fn transfer<L, R, LS, RS>(left: &mut L, right: &mut R)
+where
+    L: DerefMut + Deref<Target = ConnectionCommon<LS>>,
+    R: DerefMut + Deref<Target = ConnectionCommon<RS>>,
+    LS: SideData,
+    RS: SideData,
+{
+    let mut buf = [0u8; 262144];
+
+    while left.wants_write() {
+        let sz = left
+            .write_tls(&mut buf.as_mut())
+            .unwrap();
+        if sz == 0 {
+            return;
+        }
+
+        let mut offs = 0;
+        loop {
+            offs += right
+                .read_tls(&mut io::Cursor::new(&buf[offs..sz]))
+                .unwrap();
+            if sz == offs {
+                break;
+            }
+        }
+
+        right.process_new_packets().unwrap();
+    }
+}
+
+fn do_handshake(client: &mut ClientConnection, server: &mut ServerConnection) {
+    while server.is_handshaking() || client.is_handshaking() {
+        transfer(client, server);
+        transfer(server, client);
+    }
+}
+
+fn count_allocations(
+    key_type: &str,
+    version: &'static rustls::SupportedProtocolVersion,
+    rounds: usize,
+) {
+    for pooled in [false, true] {
+        let mut client_config = make_client_config(key_type, version);
+        let mut server_config = make_server_config(key_type, version);
+        if pooled {
+            let pool = Arc::new(BufferPool::new(64));
+            client_config = client_config.with_buffer_pool(Arc::clone(&pool));
+            server_config = server_config.with_buffer_pool(pool);
+        }
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(server_config);
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..rounds {
+            let server_name = "localhost".try_into().unwrap();
+            let mut client =
+                ClientConnection::new(Arc::clone(&client_config), server_name).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(&mut client, &mut server);
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+        println!(
+            "allocations\t{:?}\t{}\t{}\t{:.2}\tallocations/handshake",
+            version.version,
+            key_type,
+            if pooled { "pooled" } else { "unpooled" },
+            (allocations as f64) / (rounds as f64)
+        );
+    }
+}
+
+fn main() {
+    let rounds = env::args()
+        .nth(1)
+        .map(|arg| {
+            arg.parse::<usize>()
+                .expect("1st arg must be handshake count integer")
+        })
+        .unwrap_or(256);
+
+    for version in [&rustls::version::TLS12, &rustls::version::TLS13] {
+        for key_type in ["rsa", "ecdsa", "eddsa"] {
+            count_allocations(key_type, version, rounds);
+        }
+    }
+}
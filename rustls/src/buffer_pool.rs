@@ -0,0 +1,178 @@
+use crate::msgs::message::OpaqueMessage;
+
+use std::fmt;
+use std::sync::Mutex;
+
+/// A pool of byte buffers, reused for the TLS records a connection sends.
+///
+/// Without a pool, each record written by a connection is encoded into
+/// a freshly allocated buffer, which is freed once [`write_tls`] has
+/// written it out.  Handshakes send many small records, so servers
+/// accepting connections at a high rate spend a noticeable amount of
+/// time in the allocator.
+///
+/// A `BufferPool` keeps those buffers once they have been written, and
+/// hands them out again for later records -- on the same connection or
+/// any other sharing the pool.  Share one between connections by giving
+/// it to [`ServerConfig::with_buffer_pool`] or
+/// [`ClientConfig::with_buffer_pool`].
+///
+/// The bytes sent are the same whether or not a pool is used.
+///
+/// [`write_tls`]: crate::ConnectionCommon::write_tls
+/// [`ServerConfig::with_buffer_pool`]: crate::ServerConfig::with_buffer_pool
+/// [`ClientConfig::with_buffer_pool`]: crate::ClientConfig::with_buffer_pool
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Make a new, empty, pool which retains at most `max_buffers`
+    /// buffers.
+    ///
+    /// Each buffer is at most the size of one TLS record, so the memory
+    /// held by the pool is bounded by roughly `max_buffers * 18KiB`.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }
+    }
+
+    /// How many buffers are currently waiting for reuse.
+    pub fn len(&self) -> usize {
+        self.buffers
+            .lock()
+            .map(|buffers| buffers.len())
+            .unwrap_or_default()
+    }
+
+    /// Whether there are no buffers waiting for reuse.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take an empty buffer with room for at least `capacity` bytes.
+    pub(crate) fn take(&self, capacity: usize) -> Vec<u8> {
+        let reused = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop());
+
+        match reused {
+            Some(mut buf) => {
+                buf.reserve(capacity);
+                buf
+            }
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Return `buf` to the pool, unless the pool is full or `buf`
+    /// is unusually large.
+    pub(crate) fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > OpaqueMessage::MAX_WIRE_SIZE {
+            return;
+        }
+
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.max_buffers {
+                buf.clear();
+                buffers.push(buf);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("len", &self.len())
+            .field("max_buffers", &self.max_buffers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+    use crate::common_state::{CommonState, Side};
+    use crate::enums::{AlertDescription, ProtocolVersion};
+    use crate::msgs::base::Payload;
+    use crate::msgs::enums::AlertLevel;
+    use crate::msgs::message::{Message, MessagePayload, OpaqueMessage};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(2);
+        assert!(pool.is_empty());
+
+        let mut buf = pool.take(100);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        let buf = pool.take(50);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn pool_is_bounded() {
+        let pool = BufferPool::new(2);
+        for _ in 0..4 {
+            pool.put(Vec::with_capacity(16));
+        }
+        assert_eq!(pool.len(), 2);
+
+        pool.take(0);
+        pool.put(Vec::new());
+        pool.put(Vec::with_capacity(OpaqueMessage::MAX_WIRE_SIZE + 1));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn pooled_records_are_identical() {
+        fn send_records(common: &mut CommonState) -> Vec<u8> {
+            // Enough data to need several records.
+            common.send_msg(
+                Message {
+                    version: ProtocolVersion::TLSv1_2,
+                    payload: MessagePayload::ApplicationData(Payload::new(vec![0x5a; 40_000])),
+                },
+                false,
+            );
+            common.send_msg(
+                Message::build_alert(AlertLevel::Warning, AlertDescription::CloseNotify),
+                false,
+            );
+
+            let mut out = Vec::new();
+            while !common.sendable_tls.is_empty() {
+                common
+                    .sendable_tls
+                    .write_to(&mut out)
+                    .unwrap();
+            }
+            out
+        }
+
+        let expected = send_records(&mut CommonState::new(Side::Server));
+
+        let pool = Arc::new(BufferPool::new(8));
+        let mut common = CommonState::new(Side::Server);
+        common.set_buffer_pool(Some(Arc::clone(&pool)));
+        assert_eq!(send_records(&mut common), expected);
+        assert!(!pool.is_empty());
+
+        // ... and again, now the buffers are reused.
+        assert_eq!(send_records(&mut common), expected);
+        assert!(!pool.is_empty());
+    }
+}
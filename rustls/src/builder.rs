@@ -1,6 +1,6 @@
 use crate::crypto::{CryptoProvider, KeyExchange};
 use crate::error::Error;
-use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
+use crate::suites::{self, SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
 use crate::versions;
 
 use std::fmt;
@@ -172,6 +172,17 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
     pub fn with_safe_default_cipher_suites(self) -> ConfigBuilder<S, WantsKxGroups> {
         self.with_cipher_suites(DEFAULT_CIPHER_SUITES)
     }
+
+    /// Choose the default set of cipher suites ([`DEFAULT_CIPHER_SUITES`]), but
+    /// preferring ChaCha20-Poly1305 if this CPU lacks AES-GCM acceleration.
+    ///
+    /// The CPU is probed when this is called.  Without AES hardware, ChaCha20-Poly1305
+    /// is faster and its implementation is more resistant to timing side channels.
+    pub fn with_aes_hardware_detection(self) -> ConfigBuilder<S, WantsKxGroups> {
+        self.with_cipher_suites(&suites::default_cipher_suites(
+            suites::aes_hardware_available(),
+        ))
+    }
 }
 
 /// Config builder state where the caller must supply key exchange groups.
@@ -183,7 +194,26 @@ pub struct WantsKxGroups {
 }
 
 impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroups> {
+    /// Remove any CBC-mode (MAC-then-encrypt) cipher suites from those
+    /// chosen, whichever list they came from.
+    ///
+    /// rustls implements no such suites, so this is a guard: a config
+    /// built with it can never negotiate one.  If no suites remain,
+    /// choosing protocol versions fails.
+    pub fn without_cbc_suites(mut self) -> Self {
+        self.state
+            .cipher_suites
+            .retain(|suite| !suite.is_cbc());
+        self
+    }
+
     /// Choose a specific set of key exchange groups.
+    ///
+    /// This may be a single group, to insist on it: handshakes with peers
+    /// that don't support any of `kx_groups` fail, with
+    /// [`PeerIncompatible::NoKxGroupsInCommon`] on the server side.
+    ///
+    /// [`PeerIncompatible::NoKxGroupsInCommon`]: crate::PeerIncompatible::NoKxGroupsInCommon
     pub fn with_kx_groups(
         self,
         kx_groups: &[&'static <<S::CryptoProvider as CryptoProvider>::KeyExchange as KeyExchange>::SupportedGroup],
@@ -290,3 +320,46 @@ mod sealed {
     impl<C: CryptoProvider> Sealed for crate::ClientConfig<C> {}
     impl<C: CryptoProvider> Sealed for crate::ServerConfig<C> {}
 }
+
+#[cfg(all(test, feature = "tls12"))]
+mod tests {
+    use super::*;
+    use crate::crypto::ring::Ring;
+    use crate::enums::CipherSuite;
+    use crate::suites::CipherSuiteCommon;
+    use crate::tls12::Tls12CipherSuite;
+    use crate::ClientConfig;
+
+    #[test]
+    fn without_cbc_suites_drops_cbc_code_points() {
+        let gcm = match crate::tls12::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls12(gcm) => gcm,
+            _ => unreachable!(),
+        };
+        let cbc = Tls12CipherSuite {
+            common: CipherSuiteCommon {
+                suite: CipherSuite::TLS_RSA_WITH_AES_128_CBC_SHA,
+                bulk: gcm.common.bulk,
+                hash_provider: gcm.common.hash_provider,
+                aead_algorithm: gcm.common.aead_algorithm,
+            },
+            ..*gcm
+        };
+        let cbc = SupportedCipherSuite::Tls12(Box::leak(Box::new(cbc)));
+        assert!(cbc.is_cbc());
+
+        let builder = ClientConfig::<Ring>::builder()
+            .with_cipher_suites(&[cbc, crate::tls12::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256])
+            .without_cbc_suites();
+        assert_eq!(
+            builder.state.cipher_suites,
+            vec![crate::tls12::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]
+        );
+    }
+
+    #[test]
+    fn unknown_code_point_is_not_cbc() {
+        assert!(!CipherSuite::Unknown(0xfafa).is_cbc());
+        assert!(CipherSuite::TLS_ECDH_ECDSA_WITH_AES_128_CBC_SHA_draft.is_cbc());
+    }
+}
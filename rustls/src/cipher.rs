@@ -1,8 +1,8 @@
+use crate::crypto::cipher::{Iv, Nonce};
 use crate::error::Error;
-use crate::msgs::codec;
 use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
 
-use ring::{aead, hkdf};
+use ring::aead;
 
 /// Objects with this trait can decrypt TLS messages.
 pub trait MessageDecrypter: Send + Sync {
@@ -28,58 +28,8 @@ impl dyn MessageDecrypter {
     }
 }
 
-/// A write or read IV.
-#[derive(Default)]
-pub(crate) struct Iv(pub(crate) [u8; ring::aead::NONCE_LEN]);
-
-impl Iv {
-    #[cfg(feature = "tls12")]
-    fn new(value: [u8; ring::aead::NONCE_LEN]) -> Self {
-        Self(value)
-    }
-
-    #[cfg(feature = "tls12")]
-    pub(crate) fn copy(value: &[u8]) -> Self {
-        debug_assert_eq!(value.len(), ring::aead::NONCE_LEN);
-        let mut iv = Self::new(Default::default());
-        iv.0.copy_from_slice(value);
-        iv
-    }
-
-    #[cfg(test)]
-    pub(crate) fn value(&self) -> &[u8; 12] {
-        &self.0
-    }
-}
-
-pub(crate) struct IvLen;
-
-impl hkdf::KeyType for IvLen {
-    fn len(&self) -> usize {
-        aead::NONCE_LEN
-    }
-}
-
-impl From<hkdf::Okm<'_, IvLen>> for Iv {
-    fn from(okm: hkdf::Okm<IvLen>) -> Self {
-        let mut r = Self(Default::default());
-        okm.fill(&mut r.0[..]).unwrap();
-        r
-    }
-}
-
-pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
-    let mut nonce = [0u8; ring::aead::NONCE_LEN];
-    codec::put_u64(seq, &mut nonce[4..]);
-
-    nonce
-        .iter_mut()
-        .zip(iv.0.iter())
-        .for_each(|(nonce, iv)| {
-            *nonce ^= *iv;
-        });
-
-    aead::Nonce::assume_unique_for_key(nonce)
+pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> aead::Nonce {
+    aead::Nonce::assume_unique_for_key(Nonce::new(iv, seq).0)
 }
 
 /// A `MessageEncrypter` which doesn't work.
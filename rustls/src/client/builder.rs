@@ -1,10 +1,15 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
 use crate::client::handy;
 use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::common_state;
+use crate::compress;
 use crate::crypto::{CryptoProvider, KeyExchange};
 use crate::error::Error;
 use crate::key_log::NoKeyLog;
+use crate::msgs::enums::CertificateType;
 use crate::suites::SupportedCipherSuite;
+use crate::time::DefaultTimeProvider;
+use crate::tls13::RecordPadding;
 use crate::verify;
 use crate::{anchors, key, versions};
 
@@ -114,9 +119,31 @@ impl<C: CryptoProvider> ConfigBuilder<ClientConfig<C>, WantsClientCert<C>> {
             enable_sni: true,
             verifier: self.state.verifier,
             key_log: Arc::new(NoKeyLog {}),
+            handshake_observer: None,
+            record_padding: RecordPadding::None,
+            grease: false,
+            client_hello_layout: None,
+            require_ems: false,
+            signature_schemes: None,
+            buffer_pool: None,
+            retained_plaintext_capacity: common_state::DEFAULT_RETAINED_PLAINTEXT_CAPACITY,
+            write_buffer_threshold: 0,
+            unknown_extension_handler: None,
+            delegated_credentials: false,
+            server_cert_types: vec![CertificateType::X509],
+            client_cert_types: vec![CertificateType::X509],
+            pinned_server_keys: Vec::new(),
+            client_raw_public_key: None,
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
             enable_early_data: false,
+            cert_compressors: compress::default_cert_compressors(),
+            max_decompressed_certificate_len: compress::DEFAULT_MAX_DECOMPRESSED_CERTIFICATE_LEN,
+            preshared_keys: Vec::new(),
+            max_hello_retry: 1,
+            key_shares: 1,
+            max_certificate_chain_len: 16,
+            time_provider: Arc::new(DefaultTimeProvider),
             provider: PhantomData,
         }
     }
@@ -1,28 +1,38 @@
+use crate::buffer_pool::BufferPool;
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
 use crate::common_state::{CommonState, Protocol, Side};
+use crate::compress::CertificateCompressor;
 use crate::conn::{ConnectionCommon, ConnectionCore};
-use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::crypto::{CryptoProvider, KeyExchange, SupportedGroup};
 use crate::dns_name::{DnsName, DnsNameRef, InvalidDnsNameError};
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
+use crate::handshake_observer::HandshakeObserver;
 #[cfg(feature = "logging")]
 use crate::log::trace;
-use crate::msgs::enums::NamedGroup;
+use crate::msgs::enums::{CertificateType, ExtensionType, NamedGroup};
+use crate::msgs::fragmenter::{MessageFragmenter, PACKET_OVERHEAD};
 use crate::msgs::handshake::ClientExtension;
 use crate::msgs::persist;
+use crate::psk::PresharedKey;
 use crate::sign;
-use crate::suites::SupportedCipherSuite;
+use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
+use crate::template::ConfigTemplate;
+use crate::time::{TimeProvider, UnixTime};
+use crate::tls13::RecordPadding;
+use crate::unknown_extensions::UnknownExtensionHandler;
 use crate::verify;
 use crate::versions;
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
 use crate::KeyLog;
+use crate::RootCertStore;
 
 use super::handy::{ClientSessionMemoryCache, NoClientSessionStorage};
 use super::hs;
 
 use std::marker::PhantomData;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use std::{fmt, io, mem};
@@ -114,6 +124,29 @@ pub trait ResolvesClientCert: Send + Sync {
 ///
 /// These must be created via the [`ClientConfig::builder()`] function.
 ///
+/// To make a variant of an existing config, clone it and assign the
+/// public fields you need to change, for example
+/// [`ClientConfig::alpn_protocols`]:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rustls::ClientConfig;
+/// # use rustls::crypto::ring::Ring;
+/// # let h2_config = Arc::new(
+/// #     ClientConfig::<Ring>::builder()
+/// #         .with_safe_defaults()
+/// #         .with_root_certificates(rustls::RootCertStore::empty())
+/// #         .with_no_client_auth(),
+/// # );
+/// let mut h1_config = ClientConfig::clone(&h2_config);
+/// h1_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+/// ```
+///
+/// The cipher suites, key exchange groups, protocol versions and
+/// verifier can only be chosen with the builder, so a variant keeps
+/// those of the original.  Shared parts, like the session store, stay
+/// shared.
+///
 /// # Defaults
 ///
 /// * [`ClientConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
@@ -121,6 +154,28 @@ pub trait ResolvesClientCert: Send + Sync {
 ///    ids or tickets, with a max of eight tickets per server.
 /// * [`ClientConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
 /// * [`ClientConfig::key_log`]: key material is not logged.
+/// * [`ClientConfig::with_max_hello_retry`]: one HelloRetryRequest is allowed.
+/// * [`ClientConfig::with_key_shares`]: a key share is sent for one group.
+/// * [`ClientConfig::handshake_observer`]: handshakes are not observed.
+/// * [`ClientConfig::with_record_padding`]: records are not padded.
+/// * [`ClientConfig::with_grease`]: no GREASE values are sent.
+/// * [`ClientConfig::with_client_hello_layout`]: extensions are sent in rustls's own order.
+/// * [`ClientConfig::with_require_ems`]: TLS1.2 servers need not support extended master secret.
+/// * [`ClientConfig::with_signature_schemes`]: all schemes supported by the verifier and our keys.
+/// * [`ClientConfig::with_buffer_pool`]: each record is sent from a newly allocated buffer.
+/// * [`ClientConfig::with_retained_plaintext_capacity`]: 1 KiB.
+/// * [`ClientConfig::with_write_buffering`]: each write is sent in its own record(s).
+/// * [`ClientConfig::with_unknown_extension_handler`]: unknown extensions are ignored.
+/// * [`ClientConfig::with_delegated_credentials`]: delegated credentials are not accepted.
+/// * [`ClientConfig::with_server_cert_types`]: servers must authenticate with certificates.
+/// * [`ClientConfig::with_client_cert_types`]: client authentication uses certificates.
+/// * [`ClientConfig::with_pinned_server_keys`]: no raw public keys are accepted.
+/// * [`ClientConfig::with_client_raw_public_key`]: we have no raw public key.
+/// * [`ClientConfig::cert_compressors`]: the codecs enabled by crate features.
+/// * [`ClientConfig::max_decompressed_certificate_len`]: 64 KiB.
+/// * [`ClientConfig::with_max_certificate_chain_len`]: 16 certificates.
+/// * [`ClientConfig::preshared_keys`]: the default is empty -- no external PSKs are offered.
+/// * [`ClientConfig::time_provider`]: the system clock.
 pub struct ClientConfig<C: CryptoProvider> {
     /// List of ciphersuites, in preference order.
     pub(super) cipher_suites: Vec<SupportedCipherSuite>,
@@ -146,6 +201,8 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// Out of range values are reported as errors from ClientConnection::new.
     ///
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
+    ///
+    /// See also [`ClientConfig::with_max_send_fragment`].
     pub max_fragment_size: Option<usize>,
 
     /// How to decide what client auth certificate/keys to use.
@@ -168,6 +225,10 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Observes the outcome of handshakes, for example to collect metrics.
+    /// The default is `None`.
+    pub handshake_observer: Option<Arc<dyn HandshakeObserver>>,
+
     /// Allows traffic secrets to be extracted after the handshake,
     /// e.g. for kTLS setup.
     #[cfg(feature = "secret_extraction")]
@@ -180,6 +241,100 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// The default is false.
     pub enable_early_data: bool,
 
+    /// Codecs we can use to decompress the server's certificate in TLS1.3,
+    /// as described in RFC8879, most preferred first.  The server compresses
+    /// with the first one it also supports.  If empty, we don't ask for
+    /// compressed certificates.
+    ///
+    /// The default is [`crate::compress::default_cert_compressors`].
+    pub cert_compressors: Vec<Arc<dyn CertificateCompressor>>,
+
+    /// The largest certificate message we are prepared to decompress.
+    /// A compressed certificate claiming to be larger than this is
+    /// rejected before any decompression is done.
+    ///
+    /// The default is 64 KiB.
+    pub max_decompressed_certificate_len: usize,
+
+    /// External pre-shared keys we offer to servers in TLS1.3.  A server
+    /// which accepts one of these does not send a certificate.
+    ///
+    /// The default is empty.
+    pub preshared_keys: Vec<Arc<PresharedKey>>,
+
+    /// How many TLS1.3 HelloRetryRequests we tolerate from a server
+    /// during one handshake.  See [`ClientConfig::with_max_hello_retry`].
+    pub(super) max_hello_retry: usize,
+
+    /// How many groups we send TLS1.3 key shares for in our first
+    /// ClientHello.  See [`ClientConfig::with_key_shares`].
+    pub(super) key_shares: usize,
+
+    /// The most certificates we accept in the server's Certificate message.
+    /// See [`ClientConfig::with_max_certificate_chain_len`].
+    pub(super) max_certificate_chain_len: usize,
+
+    /// How to pad TLS1.3 records.  See [`ClientConfig::with_record_padding`].
+    pub(super) record_padding: RecordPadding,
+
+    /// Whether to send GREASE values.  See [`ClientConfig::with_grease`].
+    pub(super) grease: bool,
+
+    /// How to arrange our ClientHello extensions.
+    /// See [`ClientConfig::with_client_hello_layout`].
+    pub(super) client_hello_layout: Option<ClientHelloLayout>,
+
+    /// Whether TLS1.2 servers must use extended master secret.
+    /// See [`ClientConfig::with_require_ems`].
+    pub(super) require_ems: bool,
+
+    /// The only signature schemes we offer and accept, if restricted.
+    /// See [`ClientConfig::with_signature_schemes`].
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
+    /// Supplies buffers for the records we send.
+    /// See [`ClientConfig::with_buffer_pool`].
+    pub(super) buffer_pool: Option<Arc<BufferPool>>,
+
+    /// Memory kept by each connection's received plaintext buffer when
+    /// idle.  See [`ClientConfig::with_retained_plaintext_capacity`].
+    pub(super) retained_plaintext_capacity: usize,
+
+    /// How much plaintext to accumulate from small writes before sending it.
+    /// See [`ClientConfig::with_write_buffering`].
+    pub(super) write_buffer_threshold: usize,
+
+    /// Told about extensions in the server's hello that we don't understand.
+    /// See [`ClientConfig::with_unknown_extension_handler`].
+    pub(super) unknown_extension_handler: Option<Arc<dyn UnknownExtensionHandler>>,
+
+    /// Whether to accept delegated credentials from TLS1.3 servers.
+    /// See [`ClientConfig::with_delegated_credentials`].
+    pub(super) delegated_credentials: bool,
+
+    /// The certificate types we accept from servers, in preference order.
+    /// See [`ClientConfig::with_server_cert_types`].
+    pub(super) server_cert_types: Vec<CertificateType>,
+
+    /// The certificate types we can authenticate with, in preference order.
+    /// See [`ClientConfig::with_client_cert_types`].
+    pub(super) client_cert_types: Vec<CertificateType>,
+
+    /// The SubjectPublicKeyInfos of the servers we accept, when they
+    /// authenticate with raw public keys.
+    /// See [`ClientConfig::with_pinned_server_keys`].
+    pub(super) pinned_server_keys: Vec<Vec<u8>>,
+
+    /// Our raw public key, for servers which accept one for client auth.
+    /// See [`ClientConfig::with_client_raw_public_key`].
+    pub(super) client_raw_public_key: Option<sign::RawPublicKey>,
+
+    /// Where we learn the current time, for verifying the server's
+    /// certificate and expiring stored sessions.
+    ///
+    /// The default is [`crate::time::DefaultTimeProvider`].
+    pub time_provider: Arc<dyn TimeProvider>,
+
     pub(crate) provider: PhantomData<C>,
 }
 
@@ -187,8 +342,13 @@ pub struct ClientConfig<C: CryptoProvider> {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Tls12Resumption {
     /// Disable 1.2 resumption.
+    ///
+    /// As with `SessionIdOnly`, no `session_ticket` extension is sent.
     Disabled,
     /// Support 1.2 resumption using session ids only.
+    ///
+    /// The `session_ticket` extension is not sent, even when TLS1.3 is
+    /// enabled, and TLS1.2 tickets are never stored.
     SessionIdOnly,
     /// Support 1.2 resumption using session ids or RFC 5077 tickets.
     ///
@@ -199,6 +359,63 @@ pub enum Tls12Resumption {
     SessionIdOrTickets,
 }
 
+/// An arrangement of the extensions in a ClientHello.
+///
+/// Extensions are sent in the order their types appear in the layout.
+/// Extensions rustls would send, but whose type is not in the layout, are
+/// not sent at all -- with exceptions that the protocol requires:
+///
+/// * `cookie` is always echoed after a HelloRetryRequest, and
+///   `early_data` is always sent when offering 0-RTT data; unless
+///   listed, these come after the listed extensions.
+/// * `pre_shared_key` is always last, wherever it is in the layout.
+///
+/// Listing an extension type does not make rustls send it: types for
+/// which rustls has nothing to send are skipped.  Note that leaving out
+/// an extension can make handshakes fail; for example TLS1.3 needs
+/// `supported_versions` and `key_share`.
+///
+/// GREASE extensions (see [`ClientConfig::with_grease`]) are not subject
+/// to the layout, and are sent first.
+#[derive(Clone, Debug)]
+pub struct ClientHelloLayout {
+    extension_order: Vec<ExtensionType>,
+}
+
+impl ClientHelloLayout {
+    /// Make a layout sending extensions in `extension_order`.
+    pub fn new(extension_order: Vec<ExtensionType>) -> Self {
+        Self { extension_order }
+    }
+
+    /// Rearrange `exts` according to this layout.
+    pub(super) fn apply(&self, exts: &mut Vec<ClientExtension>) {
+        let psk = match exts.last() {
+            Some(ClientExtension::PresharedKey(_)) => exts.pop(),
+            _ => None,
+        };
+
+        let mut ordered = Vec::with_capacity(exts.len());
+        for typ in &self.extension_order {
+            if let Some(pos) = exts
+                .iter()
+                .position(|ext| ext.get_type() == *typ)
+            {
+                ordered.push(exts.remove(pos));
+            }
+        }
+
+        ordered.extend(exts.drain(..).filter(|ext| {
+            matches!(
+                ext.get_type(),
+                ExtensionType::Cookie | ExtensionType::EarlyData
+            )
+        }));
+        ordered.extend(psk);
+        *exts = ordered;
+    }
+}
+
 impl<C: CryptoProvider> Clone for ClientConfig<C> {
     fn clone(&self) -> Self {
         Self {
@@ -212,9 +429,31 @@ impl<C: CryptoProvider> Clone for ClientConfig<C> {
             enable_sni: self.enable_sni,
             verifier: Arc::clone(&self.verifier),
             key_log: Arc::clone(&self.key_log),
+            handshake_observer: self.handshake_observer.clone(),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: self.enable_secret_extraction,
             enable_early_data: self.enable_early_data,
+            cert_compressors: self.cert_compressors.clone(),
+            max_decompressed_certificate_len: self.max_decompressed_certificate_len,
+            preshared_keys: self.preshared_keys.clone(),
+            max_hello_retry: self.max_hello_retry,
+            key_shares: self.key_shares,
+            max_certificate_chain_len: self.max_certificate_chain_len,
+            record_padding: self.record_padding.clone(),
+            grease: self.grease,
+            client_hello_layout: self.client_hello_layout.clone(),
+            require_ems: self.require_ems,
+            signature_schemes: self.signature_schemes.clone(),
+            buffer_pool: self.buffer_pool.clone(),
+            retained_plaintext_capacity: self.retained_plaintext_capacity,
+            write_buffer_threshold: self.write_buffer_threshold,
+            unknown_extension_handler: self.unknown_extension_handler.clone(),
+            delegated_credentials: self.delegated_credentials,
+            server_cert_types: self.server_cert_types.clone(),
+            client_cert_types: self.client_cert_types.clone(),
+            pinned_server_keys: self.pinned_server_keys.clone(),
+            client_raw_public_key: self.client_raw_public_key.clone(),
+            time_provider: Arc::clone(&self.time_provider),
             provider: PhantomData,
         }
     }
@@ -243,6 +482,51 @@ impl<C: CryptoProvider> ClientConfig<C> {
         }
     }
 
+    /// Make a configuration negotiating the parameters named in `template`,
+    /// trusting `root_store` and without client authentication.
+    ///
+    /// Key exchange groups are resolved using the crypto provider `C`, for
+    /// example with `ClientConfig::<Ring>::from_template(...)`.
+    ///
+    /// This fails if `template` names a cipher suite, key exchange group
+    /// or protocol version that is unknown, or if its cipher suites cannot
+    /// be used with its protocol versions.
+    pub fn from_template(
+        template: &ConfigTemplate,
+        root_store: RootCertStore,
+    ) -> Result<Self, Error> {
+        let mut cipher_suites = template.resolve_cipher_suites()?;
+        if cipher_suites.is_empty() {
+            cipher_suites = DEFAULT_CIPHER_SUITES.to_vec();
+        }
+
+        let mut kx_groups = template.resolve_kx_groups::<C>()?;
+        if kx_groups.is_empty() {
+            kx_groups = C::KeyExchange::all_kx_groups().to_vec();
+        }
+
+        let mut versions = template.resolve_versions()?;
+        if versions.is_empty() {
+            versions = versions::DEFAULT_VERSIONS.to_vec();
+        }
+
+        let mut config = Self::builder()
+            .with_cipher_suites(&cipher_suites)
+            .with_kx_groups(&kx_groups)
+            .with_protocol_versions(&versions)?
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        config.alpn_protocols = template.resolve_alpn_protocols();
+        Ok(config)
+    }
+
+    /// The current time, according to [`Self::time_provider`].
+    pub(crate) fn current_time(&self) -> Result<UnixTime, Error> {
+        self.time_provider
+            .current_time()
+            .ok_or(Error::FailedToGetCurrentTime)
+    }
+
     /// We support a given TLS version if it's quoted in the configured
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
@@ -254,6 +538,312 @@ impl<C: CryptoProvider> ClientConfig<C> {
                 .any(|cs| cs.version().version == v)
     }
 
+    /// The names of the configured key exchange groups, in preference
+    /// order.
+    pub fn kx_groups_named(&self) -> Vec<NamedGroup> {
+        self.kx_groups
+            .iter()
+            .map(|group| group.name())
+            .collect()
+    }
+
+    /// The IANA identifiers of the configured cipher suites, in
+    /// preference order.
+    pub fn cipher_suites_named(&self) -> Vec<CipherSuite> {
+        self.cipher_suites
+            .iter()
+            .map(|suite| suite.suite())
+            .collect()
+    }
+
+    /// Set how many TLS1.3 HelloRetryRequest round trips will be
+    /// tolerated during a handshake.
+    ///
+    /// A server sends a HelloRetryRequest when it does not support the key
+    /// share we offered, asking us to send another ClientHello.  If a server
+    /// asks for more retries than this, the handshake fails.  Setting this
+    /// to zero means we never send a second ClientHello.
+    ///
+    /// The default is 1.  RFC8446 section 4.1.4 requires a client to abort
+    /// the handshake on a second HelloRetryRequest, so larger values are
+    /// treated as 1.
+    pub fn with_max_hello_retry(mut self, n: usize) -> Self {
+        self.max_hello_retry = n.min(1);
+        self
+    }
+
+    /// Send TLS1.3 key shares for up to `n` key exchange groups in our
+    /// first ClientHello, rather than just one.
+    ///
+    /// The groups are our first `n`, in the order given to
+    /// [`ConfigBuilder::with_kx_groups`], except that a group the server
+    /// chose before comes first.  If the server accepts any of them, no
+    /// HelloRetryRequest is needed; but each extra key share costs a key
+    /// generation, and makes the ClientHello larger.
+    ///
+    /// The default is 1.  At least one key share is always sent.
+    ///
+    /// [`ConfigBuilder::with_kx_groups`]: crate::ConfigBuilder::with_kx_groups
+    pub fn with_key_shares(mut self, n: usize) -> Self {
+        self.key_shares = n;
+        self
+    }
+
+    /// Reject servers which send more than `n` certificates, counting
+    /// the end-entity certificate, before verifying any of them.
+    ///
+    /// This is a cheap sanity check on the server's Certificate message,
+    /// separate from the verifier's own limit on chain depth: it stops a
+    /// badly configured server from making us examine dozens of
+    /// certificates.  Failures are reported as
+    /// [`PeerMisbehaved::TooManyCertificates`].
+    ///
+    /// The default is 16.
+    ///
+    /// [`PeerMisbehaved::TooManyCertificates`]: crate::PeerMisbehaved::TooManyCertificates
+    pub fn with_max_certificate_chain_len(mut self, n: usize) -> Self {
+        self.max_certificate_chain_len = n;
+        self
+    }
+
+    /// Set how TLS1.3 records are padded, to obscure the length of
+    /// their contents.  See [`RecordPadding`] for the options.
+    ///
+    /// The default is [`RecordPadding::None`].
+    pub fn with_record_padding(mut self, policy: RecordPadding) -> Self {
+        self.record_padding = policy;
+        self
+    }
+
+    /// Set whether to send GREASE values ([RFC8701]) in the ClientHello.
+    ///
+    /// GREASE values are reserved code points that no implementation
+    /// supports.  Sending them in our cipher suites, extensions, supported
+    /// groups and supported versions checks that servers correctly ignore
+    /// values they don't understand, which keeps those extension points
+    /// usable.  It also makes our ClientHello look more like a browser's.
+    ///
+    /// The values are chosen at random for each connection.  A server
+    /// which selects one of them is rejected like for any other value we
+    /// didn't offer.
+    ///
+    /// The default is `false`.
+    ///
+    /// [RFC8701]: https://datatracker.ietf.org/doc/html/rfc8701
+    pub fn with_grease(mut self, enabled: bool) -> Self {
+        self.grease = enabled;
+        self
+    }
+
+    /// Set the order, and presence, of the extensions in our ClientHello.
+    ///
+    /// This is for clients which need their ClientHello to resemble that
+    /// of some other implementation.  See [`ClientHelloLayout`] for how
+    /// the layout is applied.
+    ///
+    /// The default is to use rustls's own order.
+    pub fn with_client_hello_layout(mut self, layout: ClientHelloLayout) -> Self {
+        self.client_hello_layout = Some(layout);
+        self
+    }
+
+    /// Set whether TLS1.2 servers must support extended master
+    /// secret ([RFC7627]).
+    ///
+    /// We always offer extended master secret.  Without it, a TLS1.2
+    /// connection is open to the triple handshake attack; when this is
+    /// `true`, we abort the handshake with a server that doesn't
+    /// confirm it.  This has no effect on TLS1.3 connections.
+    ///
+    /// The default is `false`.
+    ///
+    /// [RFC7627]: https://datatracker.ietf.org/doc/html/rfc7627
+    pub fn with_require_ems(mut self, required: bool) -> Self {
+        self.require_ems = required;
+        self
+    }
+
+    /// Only offer and accept the signature schemes in `schemes`.
+    ///
+    /// The `signature_algorithms` extension we send is reduced to those
+    /// of the verifier's schemes which are in `schemes`, and a server
+    /// signing its handshake with any other scheme is rejected.  If we
+    /// authenticate with a client certificate, we only sign with one of
+    /// `schemes`.
+    ///
+    /// This fails if `schemes` is empty.  By default, all schemes
+    /// supported by the verifier and our keys are used.
+    pub fn with_signature_schemes(mut self, schemes: &[SignatureScheme]) -> Result<Self, Error> {
+        if schemes.is_empty() {
+            return Err(Error::General("no signature schemes configured".into()));
+        }
+        self.signature_schemes = Some(schemes.to_vec());
+        Ok(self)
+    }
+
+    /// Whether [`Self::with_signature_schemes`] allows `scheme`.
+    pub(crate) fn allows_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+        self.signature_schemes
+            .as_ref()
+            .map_or(true, |schemes| schemes.contains(&scheme))
+    }
+
+    /// Encode the records connections send into buffers taken from
+    /// `pool`, returning them to it once written.
+    ///
+    /// This reduces allocations for clients making many connections;
+    /// see [`BufferPool`].
+    ///
+    /// The default is to allocate a new buffer for each record.
+    pub fn with_buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Set how many bytes of memory a connection's received plaintext
+    /// buffer may keep once the application has read everything from it.
+    ///
+    /// The buffer is allocated as records are decrypted, and grows to
+    /// hold however much the application has yet to read.  Once that
+    /// has all been read, it shrinks back to at most `bytes`.  A lower
+    /// value saves memory for clients holding many idle connections, at
+    /// the cost of more allocations when a connection becomes busy again.
+    ///
+    /// The default is 1 KiB.
+    pub fn with_retained_plaintext_capacity(mut self, bytes: usize) -> Self {
+        self.retained_plaintext_capacity = bytes;
+        self
+    }
+
+    /// Hold back small writes to a connection's `writer()`, so that they
+    /// can share TLS records.
+    ///
+    /// Written plaintext accumulates until there are `threshold` bytes
+    /// of it, or the application calls `flush()` on the writer; only then
+    /// is it encrypted and queued for [`Connection::write_tls`].  A single
+    /// write of at least `threshold` bytes is sent immediately.  Sending
+    /// close_notify also sends any held-back data first.
+    ///
+    /// This saves the per-record overhead for protocols which make many
+    /// small writes, but applications must remember to flush.
+    ///
+    /// The default is 0: writes are not held back.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    pub fn with_write_buffering(mut self, threshold: usize) -> Self {
+        self.write_buffer_threshold = threshold;
+        self
+    }
+
+    /// Give each extension in the server's hello that rustls does not
+    /// understand to `handler`, which may abort the handshake.
+    ///
+    /// The default is to ignore such extensions.
+    pub fn with_unknown_extension_handler(
+        mut self,
+        handler: Arc<dyn UnknownExtensionHandler>,
+    ) -> Self {
+        self.unknown_extension_handler = Some(handler);
+        self
+    }
+
+    /// Offer the `delegated_credential` extension (RFC 9345) to TLS1.3
+    /// servers, allowing them to authenticate with a short-lived key
+    /// delegated by their certificate's key.
+    ///
+    /// The server's end-entity certificate must carry the DelegationUsage
+    /// extension, and the credential must be valid now and for at most
+    /// seven more days.
+    ///
+    /// The default is `false`.
+    pub fn with_delegated_credentials(mut self, enabled: bool) -> Self {
+        self.delegated_credentials = enabled;
+        self
+    }
+
+    /// Accept servers which authenticate with any of `types`, in
+    /// preference order, using the `server_certificate_type` extension
+    /// (RFC 7250).
+    ///
+    /// Servers which don't support the extension, and all TLS1.2 servers,
+    /// use [`CertificateType::X509`].  Servers which can't use any of
+    /// `types` are rejected with [`PeerIncompatible::NoCertificateTypesInCommon`].
+    /// Raw public keys are checked against [`ClientConfig::with_pinned_server_keys`].
+    ///
+    /// The default is `[X509]`.
+    ///
+    /// [`CertificateType::X509`]: crate::CertificateType::X509
+    /// [`PeerIncompatible::NoCertificateTypesInCommon`]: crate::PeerIncompatible::NoCertificateTypesInCommon
+    pub fn with_server_cert_types(mut self, types: Vec<CertificateType>) -> Self {
+        self.server_cert_types = types;
+        self
+    }
+
+    /// Offer to authenticate with any of `types`, in preference order,
+    /// using the `client_certificate_type` extension (RFC 7250).
+    ///
+    /// If the server asks for a type we can't provide, we send no
+    /// certificate.  A raw public key must be configured with
+    /// [`ClientConfig::with_client_raw_public_key`]; certificates come
+    /// from the client certificate resolver.
+    ///
+    /// The default is `[X509]`.
+    pub fn with_client_cert_types(mut self, types: Vec<CertificateType>) -> Self {
+        self.client_cert_types = types;
+        self
+    }
+
+    /// Accept servers which authenticate with a raw public key (RFC 7250)
+    /// in `keys`, each a DER-encoded SubjectPublicKeyInfo, in place of a
+    /// certificate chain.  The verifier is not consulted for them; a key
+    /// not in `keys` is rejected with [`CertificateError::UnknownRawPublicKey`].
+    ///
+    /// This also calls [`ClientConfig::with_server_cert_types`] with only
+    /// [`CertificateType::RawPublicKey`]: call it afterwards to accept
+    /// certificates too.
+    ///
+    /// The default is empty.
+    ///
+    /// [`CertificateError::UnknownRawPublicKey`]: crate::CertificateError::UnknownRawPublicKey
+    /// [`CertificateType::RawPublicKey`]: crate::CertificateType::RawPublicKey
+    pub fn with_pinned_server_keys(mut self, keys: Vec<Vec<u8>>) -> Self {
+        self.pinned_server_keys = keys;
+        self.with_server_cert_types(vec![CertificateType::RawPublicKey])
+    }
+
+    /// Offer to authenticate with the raw public key (RFC 7250) `key`, if
+    /// a TLS1.3 server asks for client authentication.
+    ///
+    /// This also calls [`ClientConfig::with_client_cert_types`] preferring
+    /// [`CertificateType::RawPublicKey`], then [`CertificateType::X509`]:
+    /// servers which only accept certificates get one from the client
+    /// certificate resolver as usual.
+    ///
+    /// The default is `None`.
+    ///
+    /// [`CertificateType::RawPublicKey`]: crate::CertificateType::RawPublicKey
+    /// [`CertificateType::X509`]: crate::CertificateType::X509
+    pub fn with_client_raw_public_key(mut self, key: sign::RawPublicKey) -> Self {
+        self.client_raw_public_key = Some(key);
+        self.with_client_cert_types(vec![CertificateType::RawPublicKey, CertificateType::X509])
+    }
+
+    /// Limit the plaintext carried by each TLS record we send to `size`
+    /// bytes, rather than the protocol maximum of 16384.
+    ///
+    /// This is purely local: nothing is negotiated with the peer, which
+    /// may still send full-size records.  It sets [`Self::max_fragment_size`],
+    /// which counts the 5-byte record header as well.
+    ///
+    /// Returns [`Error::BadMaxFragmentSize`] if `size` is smaller than 27
+    /// or larger than 16384.
+    pub fn with_max_send_fragment(mut self, size: usize) -> Result<Self, Error> {
+        let max_fragment_size = size + PACKET_OVERHEAD;
+        MessageFragmenter::default().set_max_fragment_size(Some(max_fragment_size))?;
+        self.max_fragment_size = Some(max_fragment_size);
+        Ok(self)
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -343,12 +933,15 @@ impl Default for Resumption {
 ///
 /// # Making one
 ///
-/// If you have a DNS name as a `&str`, this type implements `TryFrom<&str>`,
-/// so you can do:
+/// If you have a DNS name or IP address literal as a `&str`, this type
+/// implements `TryFrom<&str>`, so you can do:
 ///
 /// ```
 /// # use rustls::ServerName;
 /// ServerName::try_from("example.com").expect("invalid DNS name");
+/// ServerName::try_from("192.0.2.1").expect("invalid IP address");
+/// ServerName::try_from("[2001:db8::1]").expect("invalid IP address");
+/// ServerName::try_from("bücher.example").expect("invalid DNS name");
 ///
 /// // or, alternatively...
 ///
@@ -396,15 +989,37 @@ impl ServerName {
 }
 
 /// Attempt to make a ServerName from a string by parsing
-/// it as a DNS name.
+/// it as a DNS name, or failing that an IPv4 or IPv6 address.
+///
+/// Internationalized domain names may be given in Unicode: they are
+/// converted to their A-label (`xn--...`) form by UTS46 processing,
+/// and names with disallowed labels are rejected.  The A-label form
+/// is what is sent in SNI and matched against the server's certificate.
+///
+/// IPv6 addresses may be enclosed in square brackets, as they
+/// are in URLs.
 impl TryFrom<&str> for ServerName {
     type Error = InvalidDnsNameError;
     fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if !s.is_ascii() {
+            let ascii = idna::domain_to_ascii_strict(s).map_err(|_| InvalidDnsNameError)?;
+            return DnsNameRef::try_from(ascii.as_str()).map(|dns| Self::DnsName(dns.to_owned()));
+        }
+
         match DnsNameRef::try_from(s) {
             Ok(dns) => Ok(Self::DnsName(dns.to_owned())),
-            Err(InvalidDnsNameError) => match s.parse() {
-                Ok(ip) => Ok(Self::IpAddress(ip)),
-                Err(_) => Err(InvalidDnsNameError),
+            Err(InvalidDnsNameError) => match s
+                .strip_prefix('[')
+                .and_then(|s| s.strip_suffix(']'))
+            {
+                Some(ipv6) => match ipv6.parse::<Ipv6Addr>() {
+                    Ok(ip) => Ok(Self::IpAddress(IpAddr::V6(ip))),
+                    Err(_) => Err(InvalidDnsNameError),
+                },
+                None => match s.parse() {
+                    Ok(ip) => Ok(Self::IpAddress(ip)),
+                    Err(_) => Err(InvalidDnsNameError),
+                },
             },
         }
     }
@@ -570,7 +1185,44 @@ impl ClientConnection {
         name: ServerName,
     ) -> Result<Self, Error> {
         Ok(Self {
-            inner: ConnectionCore::for_client(config, name, Vec::new(), Protocol::Tcp)?.into(),
+            inner: ConnectionCore::for_client(config, name, None, Vec::new(), Protocol::Tcp)?
+                .into(),
+        })
+    }
+
+    /// Make a new ClientConnection which sends `sni_name` in the Server
+    /// Name Indication extension, but verifies the server's certificate
+    /// against `verification_name`.
+    ///
+    /// This is for deployments where the name used to reach a server
+    /// differs from the name it is authenticated as, for example a CDN
+    /// edge which fronts several origins.
+    ///
+    /// # Security
+    ///
+    /// `verification_name` alone decides which server we are talking to:
+    /// a server holding a valid certificate for it is accepted whatever
+    /// `sni_name` is, and `sni_name` is not checked against anything.
+    /// Make sure `verification_name` comes from a trustworthy source, not
+    /// from the peer or from `sni_name` itself.  Sessions are stored and
+    /// resumed under `verification_name`.
+    ///
+    /// `sni_name` is sent in the clear (and not at all if it is an IP
+    /// address, or [`ClientConfig::enable_sni`] is false).
+    pub fn new_with_verification_name<C: CryptoProvider>(
+        config: Arc<ClientConfig<C>>,
+        sni_name: ServerName,
+        verification_name: ServerName,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            inner: ConnectionCore::for_client(
+                config,
+                verification_name,
+                Some(sni_name),
+                Vec::new(),
+                Protocol::Tcp,
+            )?
+            .into(),
         })
     }
 
@@ -621,9 +1273,10 @@ impl ClientConnection {
             .data
             .early_data
             .check_write(data.len())
-            .map(|sz| {
+            .and_then(|sz| {
                 self.inner
                     .send_early_plaintext(&data[..sz])
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
             })
     }
 
@@ -633,6 +1286,17 @@ impl ClientConnection {
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
         self.inner.extract_secrets()
     }
+
+    /// Extract the TLS1.2 master secret, for decrypting captured traffic
+    /// while debugging.
+    ///
+    /// See [`ConnectionCommon::dangerous_extract_tls12_master_secret`].
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_tls12_master_secret(self) -> Result<[u8; 48], Error> {
+        self.inner
+            .dangerous_extract_tls12_master_secret()
+    }
 }
 
 impl Deref for ClientConnection {
@@ -672,6 +1336,7 @@ impl ConnectionCore<ClientConnectionData> {
     pub(crate) fn for_client<C: CryptoProvider>(
         config: Arc<ClientConfig<C>>,
         name: ServerName,
+        sni_name: Option<ServerName>,
         extra_exts: Vec<ClientExtension>,
         proto: Protocol,
     ) -> Result<Self, Error> {
@@ -682,6 +1347,11 @@ impl ConnectionCore<ClientConnectionData> {
         {
             common_state.enable_secret_extraction = config.enable_secret_extraction;
         }
+        common_state.handshake_observer = config.handshake_observer.clone();
+        common_state.record_padding = config.record_padding.clone();
+        common_state.set_buffer_pool(config.buffer_pool.clone());
+        common_state.set_retained_plaintext_capacity(config.retained_plaintext_capacity);
+        common_state.write_buffer_threshold = config.write_buffer_threshold;
         let mut data = ClientConnectionData::new();
 
         let mut cx = hs::ClientContext {
@@ -689,7 +1359,14 @@ impl ConnectionCore<ClientConnectionData> {
             data: &mut data,
         };
 
-        let state = hs::start_handshake(name, extra_exts, config, &mut cx)?;
+        #[cfg(feature = "tracing")]
+        let _span = cx
+            .common
+            .handshake_span
+            .clone()
+            .entered();
+
+        let state = hs::start_handshake(name, sni_name, extra_exts, config, &mut cx)?;
         Ok(Self::new(state, data, common_state))
     }
 
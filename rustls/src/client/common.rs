@@ -4,6 +4,7 @@ use crate::log::{debug, trace};
 use crate::msgs::enums::ExtensionType;
 use crate::msgs::handshake::ServerExtension;
 use crate::msgs::handshake::{CertificatePayload, DistinguishedName};
+use crate::psk::PresharedKey;
 use crate::{sign, SignatureScheme};
 
 use std::sync::Arc;
@@ -12,43 +13,55 @@ use std::sync::Arc;
 pub(super) struct ServerCertDetails {
     pub(super) cert_chain: CertificatePayload,
     pub(super) ocsp_response: Vec<u8>,
+    /// OCSP responses for `cert_chain[1..]`, as stapled with status_request_v2.
+    pub(super) intermediate_ocsp_responses: Vec<Vec<u8>>,
 }
 
 impl ServerCertDetails {
-    pub(super) fn new(cert_chain: CertificatePayload, ocsp_response: Vec<u8>) -> Self {
+    pub(super) fn new(
+        cert_chain: CertificatePayload,
+        ocsp_response: Vec<u8>,
+        intermediate_ocsp_responses: Vec<Vec<u8>>,
+    ) -> Self {
         Self {
             cert_chain,
             ocsp_response,
+            intermediate_ocsp_responses,
         }
     }
 }
 
 pub(super) struct ClientHelloDetails {
     pub(super) sent_extensions: Vec<ExtensionType>,
+    /// External PSKs we offered, in the order we offered them.
+    pub(super) offered_psks: Vec<Arc<PresharedKey>>,
 }
 
 impl ClientHelloDetails {
     pub(super) fn new() -> Self {
         Self {
             sent_extensions: Vec::new(),
+            offered_psks: Vec::new(),
         }
     }
 
-    pub(super) fn server_sent_unsolicited_extensions(
+    /// The first of `received_exts` which we did not send, and which is
+    /// not in `allowed_unsolicited`.
+    pub(super) fn server_sent_unsolicited_extension(
         &self,
         received_exts: &[ServerExtension],
         allowed_unsolicited: &[ExtensionType],
-    ) -> bool {
+    ) -> Option<ExtensionType> {
         for ext in received_exts {
             let ext_type = ext.get_type();
             if !self.sent_extensions.contains(&ext_type) && !allowed_unsolicited.contains(&ext_type)
             {
                 trace!("Unsolicited extension {:?}", ext_type);
-                return true;
+                return Some(ext_type);
             }
         }
 
-        false
+        None
     }
 }
 
@@ -90,4 +103,30 @@ impl ClientAuthDetails {
         debug!("Client auth requested but no cert/sigscheme available");
         Self::Empty { auth_context_tls13 }
     }
+
+    /// Authenticate with the raw public key `key`, sent in place of a
+    /// certificate chain.
+    pub(super) fn raw_public_key(
+        key: &sign::RawPublicKey,
+        sigschemes: &[SignatureScheme],
+        auth_context_tls13: Option<Vec<u8>>,
+    ) -> Self {
+        match key.key.choose_scheme(sigschemes) {
+            Some(signer) => {
+                debug!("Attempting client auth with raw public key");
+                Self::Verify {
+                    certkey: Arc::new(sign::CertifiedKey::new(
+                        vec![key.spki.clone()],
+                        Arc::clone(&key.key),
+                    )),
+                    signer,
+                    auth_context_tls13,
+                }
+            }
+            None => {
+                debug!("Client auth requested but raw public key has no sigscheme available");
+                Self::Empty { auth_context_tls13 }
+            }
+        }
+    }
 }
@@ -215,7 +215,8 @@ mod test {
     fn test_noclientsessionstorage_does_nothing() {
         let c = NoClientSessionStorage {};
         let name = "example.com".try_into().unwrap();
-        let now = crate::ticketer::TimeBase::now().unwrap();
+        let now =
+            crate::time::UnixTime::since_unix_epoch(std::time::Duration::from_secs(1_700_000_000));
 
         c.set_kx_hint(&name, NamedGroup::X25519);
         assert_eq!(None, c.kx_hint(&name));
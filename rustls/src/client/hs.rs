@@ -4,25 +4,28 @@ use crate::check::inappropriate_handshake_message;
 use crate::common_state::{CommonState, State};
 use crate::conn::ConnectionRandoms;
 use crate::crypto::{CryptoProvider, KeyExchange, KeyExchangeError, SupportedGroup};
-use crate::enums::{AlertDescription, CipherSuite, ContentType, HandshakeType, ProtocolVersion};
+use crate::enums::{
+    AlertDescription, CipherSuite, ContentType, HandshakeType, ProtocolVersion, SignatureScheme,
+};
 use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
 use crate::hash_hs::HandshakeHashBuffer;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::msgs::base::Payload;
-use crate::msgs::enums::{Compression, ExtensionType};
+use crate::msgs::enums::{CertificateType, Compression, ExtensionType, NamedGroup};
 use crate::msgs::enums::{ECPointFormat, PSKKeyExchangeMode};
+use crate::msgs::handshake::CertificateStatusRequestItemV2;
 use crate::msgs::handshake::ConvertProtocolNameList;
 use crate::msgs::handshake::{CertificateStatusRequest, ClientSessionTicket};
-use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
+use crate::msgs::handshake::{ClientExtension, HasServerExtensions, ServerExtension};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{HelloRetryRequest, KeyShareEntry};
-use crate::msgs::handshake::{Random, SessionId};
+use crate::msgs::handshake::{Random, SessionId, UnknownExtension};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::rand::GetRandomFailed;
-use crate::ticketer::TimeBase;
 use crate::tls13::key_schedule::KeyScheduleEarly;
+use crate::unknown_extensions::handle_unknown_extensions;
 use crate::SupportedCipherSuite;
 
 #[cfg(feature = "tls12")]
@@ -64,7 +67,7 @@ fn find_session(
             None
         })
         .and_then(|resuming| {
-            let retrieved = persist::Retrieved::new(resuming, TimeBase::now().ok()?);
+            let retrieved = persist::Retrieved::new(resuming, config.current_time().ok()?);
             match retrieved.has_expired() {
                 false => Some(retrieved),
                 true => None,
@@ -89,6 +92,7 @@ fn find_session(
 
 pub(super) fn start_handshake<C: CryptoProvider>(
     server_name: ServerName,
+    sni_name: Option<ServerName>,
     extra_exts: Vec<ClientExtension>,
     config: Arc<ClientConfig<C>>,
     cx: &mut ClientContext<'_>,
@@ -108,10 +112,10 @@ pub(super) fn start_handshake<C: CryptoProvider>(
         cx,
     );
 
-    let key_share = if config.supports_version(ProtocolVersion::TLSv1_3) {
-        Some(tls13::initial_key_share(&config, &server_name)?)
+    let key_shares = if config.supports_version(ProtocolVersion::TLSv1_3) {
+        tls13::initial_key_shares(&config, &server_name)?
     } else {
-        None
+        Vec::new()
     };
 
     #[cfg_attr(not(feature = "tls12"), allow(unused_mut))]
@@ -121,8 +125,11 @@ pub(super) fn start_handshake<C: CryptoProvider>(
         if let ClientSessionValue::Tls12(inner) = &mut _resuming.value {
             // If we have a ticket, we use the sessionid as a signal that
             // we're  doing an abbreviated handshake.  See section 3.4 in
-            // RFC5077.
-            if !inner.ticket().is_empty() {
+            // RFC5077.  Without ticket support, the ticket isn't sent, so
+            // resume with the real session ID.
+            if !inner.ticket().is_empty()
+                && config.resumption.tls12_resumption == Tls12Resumption::SessionIdOrTickets
+            {
                 inner.session_id = SessionId::random::<C>()?;
             }
             session_id = Some(inner.session_id);
@@ -147,7 +154,7 @@ pub(super) fn start_handshake<C: CryptoProvider>(
     Ok(emit_client_hello_for_retry::<C>(
         transcript_buffer,
         None,
-        key_share,
+        key_shares,
         extra_exts,
         None,
         ClientHelloInput {
@@ -157,9 +164,11 @@ pub(super) fn start_handshake<C: CryptoProvider>(
             #[cfg(feature = "tls12")]
             using_ems: false,
             sent_tls13_fake_ccs: false,
+            hello_retries: 0,
             hello: ClientHelloDetails::new(),
             session_id,
             server_name,
+            sni_name,
         },
         cx,
     ))
@@ -169,7 +178,7 @@ struct ExpectServerHello<C: CryptoProvider> {
     input: ClientHelloInput<C>,
     transcript_buffer: HandshakeHashBuffer,
     early_key_schedule: Option<KeyScheduleEarly>,
-    offered_key_share: Option<C::KeyExchange>,
+    offered_key_shares: Vec<C::KeyExchange>,
     suite: Option<SupportedCipherSuite>,
 }
 
@@ -185,15 +194,20 @@ struct ClientHelloInput<C: CryptoProvider> {
     #[cfg(feature = "tls12")]
     using_ems: bool,
     sent_tls13_fake_ccs: bool,
+    hello_retries: usize,
     hello: ClientHelloDetails,
     session_id: SessionId,
+    /// The name we verify the server's certificate against, and store
+    /// sessions under.
     server_name: ServerName,
+    /// The name to send in the SNI extension, if not `server_name`.
+    sni_name: Option<ServerName>,
 }
 
 fn emit_client_hello_for_retry<C: CryptoProvider>(
     mut transcript_buffer: HandshakeHashBuffer,
     retryreq: Option<&HelloRetryRequest>,
-    key_share: Option<C::KeyExchange>,
+    key_shares: Vec<C::KeyExchange>,
     extra_exts: Vec<ClientExtension>,
     suite: Option<SupportedCipherSuite>,
     mut input: ClientHelloInput<C>,
@@ -215,43 +229,132 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
     // should be unreachable thanks to config builder
     assert!(!supported_versions.is_empty());
 
+    // GREASE values are derived from our random, so they are the same
+    // in any retried ClientHello.
+    let grease = |index: usize| match config.grease {
+        true => Some(grease_value(input.random.0[index])),
+        false => None,
+    };
+
+    if let Some(version) = grease(0) {
+        supported_versions.insert(0, ProtocolVersion::Unknown(version));
+    }
+
+    let mut named_groups: Vec<_> = config
+        .kx_groups
+        .iter()
+        .map(|skxg| skxg.name())
+        .collect();
+    if let Some(group) = grease(1) {
+        named_groups.insert(0, NamedGroup::Unknown(group));
+    }
+
+    // Certificate chains are verified with every scheme the verifier supports,
+    // even those excluded from handshake signatures.
+    let cert_schemes = config
+        .verifier
+        .supported_verify_schemes();
+    let sig_schemes: Vec<_> = cert_schemes
+        .iter()
+        .copied()
+        .filter(|scheme| config.allows_signature_scheme(*scheme))
+        .collect();
+    let send_cert_schemes = sig_schemes != cert_schemes;
+
     let mut exts = vec![
         ClientExtension::SupportedVersions(supported_versions),
         ClientExtension::ECPointFormats(ECPointFormat::SUPPORTED.to_vec()),
-        ClientExtension::NamedGroups(
-            config
-                .kx_groups
-                .iter()
-                .map(|skxg| skxg.name())
-                .collect(),
-        ),
-        ClientExtension::SignatureAlgorithms(
-            config
-                .verifier
-                .supported_verify_schemes(),
-        ),
+        ClientExtension::NamedGroups(named_groups),
+        ClientExtension::SignatureAlgorithms(sig_schemes),
         ClientExtension::ExtendedMasterSecretRequest,
         ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
     ];
 
-    if let (Some(sni_name), true) = (input.server_name.for_sni(), config.enable_sni) {
+    if support_tls13 && config.delegated_credentials {
+        // These are the schemes we accept for the certificate's signature
+        // over the credential.
+        exts.push(ClientExtension::DelegatedCredential(
+            cert_schemes
+                .iter()
+                .copied()
+                .filter(SignatureScheme::supported_in_tls13)
+                .collect(),
+        ));
+    }
+
+    // Certificate types other than X509 are only supported in TLS1.3,
+    // and X509 alone is implied by leaving the extensions out.
+    if support_tls13 && config.server_cert_types != [CertificateType::X509] {
+        exts.push(ClientExtension::ServerCertificateType(
+            config.server_cert_types.clone(),
+        ));
+    }
+    if support_tls13 && config.client_cert_types != [CertificateType::X509] {
+        exts.push(ClientExtension::ClientCertificateType(
+            config.client_cert_types.clone(),
+        ));
+    }
+
+    if send_cert_schemes {
+        exts.push(ClientExtension::SignatureAlgorithmsCert(cert_schemes));
+    }
+
+    if support_tls12
+        && config
+            .verifier
+            .requests_intermediate_ocsp_responses()
+    {
+        // status_request_v2 is TLS1.2-only: TLS1.3 staples per-certificate instead.
+        exts.push(ClientExtension::CertificateStatusRequestV2(vec![
+            CertificateStatusRequestItemV2::build_ocsp_multi(),
+        ]));
+    }
+
+    let sni_name = input
+        .sni_name
+        .as_ref()
+        .unwrap_or(&input.server_name);
+    if let (Some(sni_name), true) = (sni_name.for_sni(), config.enable_sni) {
         exts.push(ClientExtension::make_sni(sni_name));
     }
 
-    if let Some(key_share) = &key_share {
+    if !key_shares.is_empty() {
         debug_assert!(support_tls13);
-        let key_share = KeyShareEntry::new(key_share.group(), key_share.pub_key());
-        exts.push(ClientExtension::KeyShare(vec![key_share]));
+        exts.push(ClientExtension::KeyShare(
+            key_shares
+                .iter()
+                .map(|kx| KeyShareEntry::new(kx.group(), kx.pub_key()))
+                .collect(),
+        ));
     }
 
     if let Some(cookie) = retryreq.and_then(HelloRetryRequest::get_cookie) {
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
+    if support_tls13 && !config.cert_compressors.is_empty() {
+        exts.push(ClientExtension::CertificateCompressionAlgorithms(
+            config
+                .cert_compressors
+                .iter()
+                .map(|c| c.algorithm())
+                .collect(),
+        ));
+    }
+
+    let external_psks = match support_tls13 {
+        true => tls13::external_psks_to_offer(config, suite.and_then(|suite| suite.tls13())),
+        false => Vec::new(),
+    };
+
     if support_tls13 {
-        // We could support PSK_KE here too. Such connections don't
-        // have forward secrecy, and are similar to TLS1.2 resumption.
-        let psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
+        // We only offer PSK_KE for use with external PSKs, leaving the
+        // server to choose between it and PSK_DHE_KE.  PSK_KE connections
+        // don't have forward secrecy, and are similar to TLS1.2 resumption.
+        let mut psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
+        if !external_psks.is_empty() {
+            psk_modes.push(PSKKeyExchangeMode::PSK_KE);
+        }
         exts.push(ClientExtension::PresharedKeyModes(psk_modes));
     }
 
@@ -271,17 +374,41 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
     // Do we have a SessionID or ticket cached for this host?
     let tls13_session = prepare_resumption(&input.resuming, &mut exts, suite, cx, config);
 
-    // Note what extensions we sent.
+    // And any external PSKs go after that.
+    tls13::prepare_external_psks(&external_psks, &mut exts);
+
+    if let Some(layout) = &config.client_hello_layout {
+        layout.apply(&mut exts);
+    }
+
+    if let Some(typ) = grease(2) {
+        exts.insert(
+            0,
+            ClientExtension::Unknown(UnknownExtension {
+                typ: ExtensionType::Unknown(typ),
+                payload: Payload::empty(),
+            }),
+        );
+    }
+
+    // Note what extensions and PSKs we sent.
     input.hello.sent_extensions = exts
         .iter()
         .map(ClientExtension::get_type)
         .collect();
+    input.hello.offered_psks = external_psks
+        .iter()
+        .map(|(psk, _)| Arc::clone(psk))
+        .collect();
 
     let mut cipher_suites: Vec<_> = config
         .cipher_suites
         .iter()
         .map(|cs| cs.suite())
         .collect();
+    if let Some(suite) = grease(3) {
+        cipher_suites.insert(0, CipherSuite::Unknown(suite));
+    }
     // We don't do renegotiation at all, in fact.
     cipher_suites.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
 
@@ -304,6 +431,13 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         None
     };
 
+    tls13::fill_in_external_psk_binders(
+        &external_psks,
+        usize::from(early_key_schedule.is_some()),
+        &transcript_buffer,
+        &mut chp,
+    );
+
     let ch = Message {
         // "This value MUST be set to 0x0303 for all records generated
         //  by a TLS 1.3 implementation other than an initial ClientHello
@@ -326,6 +460,7 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
 
     transcript_buffer.add_message(&ch);
     cx.common.send_msg(ch, false);
+    handshake_event!(retry = retryreq.is_some(), "ClientHello sent");
 
     // Calculate the hash of ClientHello and use it to derive EarlyTrafficSecret
     let early_key_schedule = early_key_schedule.map(|(resuming_suite, schedule)| {
@@ -349,17 +484,24 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         input,
         transcript_buffer,
         early_key_schedule,
-        offered_key_share: key_share,
+        offered_key_shares: key_shares,
         suite,
     };
 
-    if support_tls13 && retryreq.is_none() {
+    if support_tls13 && next.input.hello_retries < next.input.config.max_hello_retry {
         Box::new(ExpectServerHelloOrHelloRetryRequest { next, extra_exts })
     } else {
         Box::new(next)
     }
 }
 
+/// Map `seed` onto one of the sixteen RFC8701 GREASE values,
+/// which all look like `0x?A?A`.
+fn grease_value(seed: u8) -> u16 {
+    let byte = (seed & 0xf0) | 0x0a;
+    u16::from_be_bytes([byte, byte])
+}
+
 /// Prepare resumption with the session state retrieved from storage.
 ///
 /// This function will push onto `exts` to
@@ -384,9 +526,9 @@ fn prepare_resumption<'a>(
     let resuming = match resuming {
         Some(resuming) if !resuming.ticket().is_empty() => resuming,
         _ => {
-            if config.supports_version(ProtocolVersion::TLSv1_3)
-                || config.resumption.tls12_resumption == Tls12Resumption::SessionIdOrTickets
-            {
+            // TLS1.3 tickets don't need this extension, so only TLS1.2
+            // ticket support decides whether we send it.
+            if config.resumption.tls12_resumption == Tls12Resumption::SessionIdOrTickets {
                 // If we don't have a ticket, request one.
                 exts.push(ClientExtension::SessionTicket(ClientSessionTicket::Request));
             }
@@ -485,6 +627,19 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
         let config = &self.input.config;
         let tls13_supported = config.supports_version(TLSv1_3);
 
+        // Only TLS1.3 servers send a key share in the ServerHello, and they
+        // must then say so with the supported_versions extension.
+        if server_hello
+            .get_supported_versions()
+            .is_none()
+            && server_hello.get_key_share().is_some()
+        {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::MissingExtension,
+                PeerMisbehaved::MissingExtension(ExtensionType::SupportedVersions),
+            ));
+        }
+
         let server_version = if server_hello.legacy_version == TLSv1_2 {
             server_hello
                 .get_supported_versions()
@@ -514,6 +669,16 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
                     });
                 }
 
+                if !config
+                    .server_cert_types
+                    .contains(&CertificateType::X509)
+                {
+                    return Err(cx.common.send_fatal_alert(
+                        AlertDescription::HandshakeFailure,
+                        PeerIncompatible::NoCertificateTypesInCommon,
+                    ));
+                }
+
                 TLSv1_2
             }
             _ => {
@@ -543,15 +708,35 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
             ));
         }
 
+        cx.common.peer_extensions = server_hello
+            .extensions
+            .iter()
+            .map(|ext| ext.get_type())
+            .collect();
+
+        handle_unknown_extensions(
+            config
+                .unknown_extension_handler
+                .as_ref(),
+            cx.common,
+            server_hello
+                .extensions
+                .iter()
+                .filter_map(|ext| match ext {
+                    ServerExtension::Unknown(unknown) => Some(unknown),
+                    _ => None,
+                }),
+        )?;
+
         let allowed_unsolicited = [ExtensionType::RenegotiationInfo];
-        if self
+        if let Some(ext_type) = self
             .input
             .hello
-            .server_sent_unsolicited_extensions(&server_hello.extensions, &allowed_unsolicited)
+            .server_sent_unsolicited_extension(&server_hello.extensions, &allowed_unsolicited)
         {
             return Err(cx.common.send_fatal_alert(
                 AlertDescription::UnsupportedExtension,
-                PeerMisbehaved::UnsolicitedServerHelloExtension,
+                PeerMisbehaved::UnsolicitedExtension(ext_type),
             ));
         }
 
@@ -606,11 +791,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
                 cx.common.suite = Some(suite);
             }
         }
+        handshake_event!(version = ?version, suite = ?suite, "ServerHello received");
 
         // Start our handshake hash, and input the server-hello.
         let mut transcript = self
             .transcript_buffer
-            .start_hash(suite.hash_algorithm());
+            .start_hash(suite.hash_provider());
         transcript.add_message(&m);
 
         let randoms = ConnectionRandoms::new(self.input.random, server_hello.random);
@@ -639,8 +825,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
                     transcript,
                     self.early_key_schedule,
                     self.input.hello,
-                    // We always send a key share when TLS 1.3 is enabled.
-                    self.offered_key_share.unwrap(),
+                    self.offered_key_shares,
                     self.input.sent_tls13_fake_ccs,
                 )
             }
@@ -674,7 +859,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
     }
 
     fn handle_hello_retry_request(
-        self,
+        mut self,
         cx: &mut ClientContext<'_>,
         m: Message,
     ) -> NextStateOrError {
@@ -690,12 +875,15 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
         let cookie = hrr.get_cookie();
         let req_group = hrr.get_requested_key_share_group();
 
-        // We always send a key share when TLS 1.3 is enabled.
-        let offered_key_share = self.next.offered_key_share.unwrap();
+        let offered_key_shares = self.next.offered_key_shares;
 
         // A retry request is illegal if it contains no cookie and asks for
         // retry of a group we already sent.
-        if cookie.is_none() && req_group == Some(offered_key_share.group()) {
+        if cookie.is_none()
+            && offered_key_shares
+                .iter()
+                .any(|kx| req_group == Some(kx.group()))
+        {
             return Err({
                 cx.common.send_fatal_alert(
                     AlertDescription::IllegalParameter,
@@ -780,19 +968,27 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
         let transcript = self
             .next
             .transcript_buffer
-            .start_hash(cs.hash_algorithm());
+            .start_hash(cs.hash_provider());
         let mut transcript_buffer = transcript.into_hrr_buffer();
         transcript_buffer.add_message(&m);
 
+        self.next.input.hello_retries += 1;
+
         // Early data is not allowed after HelloRetryrequest
         if cx.data.early_data.is_enabled() {
             cx.data.early_data.rejected();
         }
 
-        let key_share = match req_group {
-            Some(group) if group != offered_key_share.group() => {
-                match KeyExchange::start(group, &config.kx_groups) {
-                    Ok(kx) => kx,
+        // If the server asks for a group, we send a key share for just that
+        // group in the retried ClientHello.
+        let key_shares = match req_group {
+            Some(group) => match offered_key_shares
+                .into_iter()
+                .find(|kx| kx.group() == group)
+            {
+                Some(kx) => vec![kx],
+                None => match KeyExchange::start(group, &config.kx_groups) {
+                    Ok(kx) => vec![kx],
                     Err(KeyExchangeError::UnsupportedGroup) => {
                         return Err(cx.common.send_fatal_alert(
                             AlertDescription::IllegalParameter,
@@ -800,15 +996,15 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
                         ));
                     }
                     Err(KeyExchangeError::GetRandomFailed) => return Err(GetRandomFailed.into()),
-                }
-            }
-            _ => offered_key_share,
+                },
+            },
+            None => offered_key_shares,
         };
 
         Ok(emit_client_hello_for_retry::<C>(
             transcript_buffer,
             Some(hrr),
-            Some(key_share),
+            key_shares,
             self.extra_exts,
             Some(cs),
             self.next.input,
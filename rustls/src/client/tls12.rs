@@ -4,13 +4,15 @@ use crate::conn::ConnectionRandoms;
 use crate::crypto::{CryptoProvider, KeyExchange, KeyExchangeError};
 use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
-use crate::error::{Error, InvalidMessage, PeerMisbehaved};
+use crate::error::{Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+use crate::handshake_observer::ResumptionKind;
 use crate::hash_hs::HandshakeHash;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
+use crate::msgs::enums::{CertificateStatusType, CertificateType};
 use crate::msgs::handshake::{
     CertificatePayload, HandshakeMessagePayload, HandshakePayload, NewSessionTicketPayload,
     ServerECDHParams, SessionId,
@@ -21,7 +23,6 @@ use crate::sign::Signer;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
 use crate::suites::SupportedCipherSuite;
-use crate::ticketer::TimeBase;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
 use crate::verify::{self, DigitallySignedStruct};
 
@@ -29,7 +30,7 @@ use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
 use crate::client::common::ClientAuthDetails;
 use crate::client::common::ServerCertDetails;
-use crate::client::{hs, ClientConfig, ServerName};
+use crate::client::{hs, ClientConfig, ServerName, Tls12Resumption};
 use crate::rand::GetRandomFailed;
 
 use subtle::ConstantTimeEq;
@@ -82,6 +83,12 @@ mod server_hello {
 
             // Doing EMS?
             self.using_ems = server_hello.ems_support_acked();
+            if self.config.require_ems && !self.using_ems {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::HandshakeFailure,
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired,
+                ));
+            }
 
             // Might the server send a ticket?
             let must_issue_new_ticket = if server_hello
@@ -95,13 +102,22 @@ mod server_hello {
             };
 
             // Might the server send a CertificateStatus between Certificate and
-            // ServerKeyExchange?
-            let may_send_cert_status = server_hello
+            // ServerKeyExchange?  If so, which kind?
+            let cert_status_type = if server_hello
+                .find_extension(ExtensionType::StatusRequestV2)
+                .is_some()
+            {
+                debug!("Server may staple OCSP responses for the whole chain");
+                Some(CertificateStatusType::OCSPMulti)
+            } else if server_hello
                 .find_extension(ExtensionType::StatusRequest)
-                .is_some();
-            if may_send_cert_status {
+                .is_some()
+            {
                 debug!("Server may staple OCSP response");
-            }
+                Some(CertificateStatusType::OCSP)
+            } else {
+                None
+            };
 
             // See if we're successfully resuming.
             if let Some(ref resuming) = self.resuming_session {
@@ -118,6 +134,12 @@ mod server_hello {
                         return Err(PeerMisbehaved::ResumptionOfferedWithVariedEms.into());
                     }
 
+                    cx.common
+                        .note_resumption(match resuming.ticket().is_empty() {
+                            true => ResumptionKind::Tls12SessionId,
+                            false => ResumptionKind::Tls12Ticket,
+                        });
+
                     let secrets =
                         ConnectionSecrets::new_resume(self.randoms, suite, resuming.secret());
                     self.config.key_log.log(
@@ -174,7 +196,7 @@ mod server_hello {
                 using_ems: self.using_ems,
                 transcript: self.transcript,
                 suite,
-                may_send_cert_status,
+                cert_status_type,
                 must_issue_new_ticket,
             }))
         }
@@ -190,16 +212,12 @@ struct ExpectCertificate<C: CryptoProvider> {
     using_ems: bool,
     transcript: HandshakeHash,
     pub(super) suite: &'static Tls12CipherSuite,
-    may_send_cert_status: bool,
+    cert_status_type: Option<CertificateStatusType>,
     must_issue_new_ticket: bool,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_chain = require_handshake_msg_move!(
             m,
@@ -207,7 +225,14 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             HandshakePayload::Certificate
         )?;
 
-        if self.may_send_cert_status {
+        if server_cert_chain.len() > self.config.max_certificate_chain_len {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::BadCertificate,
+                PeerMisbehaved::TooManyCertificates,
+            ));
+        }
+
+        if let Some(cert_status_type) = self.cert_status_type {
             Ok(Box::new(ExpectCertificateStatusOrServerKx {
                 config: self.config,
                 resuming_session: self.resuming_session,
@@ -218,10 +243,11 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
                 transcript: self.transcript,
                 suite: self.suite,
                 server_cert_chain,
+                cert_status_type,
                 must_issue_new_ticket: self.must_issue_new_ticket,
             }))
         } else {
-            let server_cert = ServerCertDetails::new(server_cert_chain, vec![]);
+            let server_cert = ServerCertDetails::new(server_cert_chain, vec![], vec![]);
 
             Ok(Box::new(ExpectServerKx {
                 config: self.config,
@@ -249,6 +275,7 @@ struct ExpectCertificateStatusOrServerKx<C: CryptoProvider> {
     transcript: HandshakeHash,
     suite: &'static Tls12CipherSuite,
     server_cert_chain: CertificatePayload,
+    cert_status_type: CertificateStatusType,
     must_issue_new_ticket: bool,
 }
 
@@ -271,14 +298,16 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateStatusO
                 using_ems: self.using_ems,
                 transcript: self.transcript,
                 suite: self.suite,
-                server_cert: ServerCertDetails::new(self.server_cert_chain, vec![]),
+                server_cert: ServerCertDetails::new(self.server_cert_chain, vec![], vec![]),
                 must_issue_new_ticket: self.must_issue_new_ticket,
             })
             .handle(cx, m),
             MessagePayload::Handshake {
                 parsed:
                     HandshakeMessagePayload {
-                        payload: HandshakePayload::CertificateStatus(..),
+                        payload:
+                            HandshakePayload::CertificateStatus(..)
+                            | HandshakePayload::CertificateStatusMulti(..),
                         ..
                     },
                 ..
@@ -292,6 +321,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateStatusO
                 transcript: self.transcript,
                 suite: self.suite,
                 server_cert_chain: self.server_cert_chain,
+                cert_status_type: self.cert_status_type,
                 must_issue_new_ticket: self.must_issue_new_ticket,
             })
             .handle(cx, m),
@@ -317,6 +347,7 @@ struct ExpectCertificateStatus<C: CryptoProvider> {
     transcript: HandshakeHash,
     suite: &'static Tls12CipherSuite,
     server_cert_chain: CertificatePayload,
+    cert_status_type: CertificateStatusType,
     must_issue_new_ticket: bool,
 }
 
@@ -327,19 +358,38 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateStatus<
         m: Message,
     ) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
-        let server_cert_ocsp_response = require_handshake_msg_move!(
-            m,
-            HandshakeType::CertificateStatus,
-            HandshakePayload::CertificateStatus
-        )?
-        .into_inner();
+        let (server_cert_ocsp_response, intermediate_ocsp_responses) = match self.cert_status_type {
+            CertificateStatusType::OCSPMulti => {
+                let mut responses = require_handshake_msg_move!(
+                    m,
+                    HandshakeType::CertificateStatus,
+                    HandshakePayload::CertificateStatusMulti
+                )?
+                .into_inner()
+                .into_iter();
+                (responses.next().unwrap_or_default(), responses.collect())
+            }
+            _ => {
+                let response = require_handshake_msg_move!(
+                    m,
+                    HandshakeType::CertificateStatus,
+                    HandshakePayload::CertificateStatus
+                )?
+                .into_inner();
+                (response, vec![])
+            }
+        };
 
         trace!(
             "Server stapled OCSP response is {:?}",
             &server_cert_ocsp_response
         );
 
-        let server_cert = ServerCertDetails::new(self.server_cert_chain, server_cert_ocsp_response);
+        let server_cert = ServerCertDetails::new(
+            self.server_cert_chain,
+            server_cert_ocsp_response,
+            intermediate_ocsp_responses,
+        );
 
         Ok(Box::new(ExpectServerKx {
             config: self.config,
@@ -490,6 +540,7 @@ fn emit_finished(
 ) {
     let vh = transcript.get_current_hash();
     let verify_data = secrets.client_verify_data(&vh);
+    common.note_tls12_finished(&verify_data);
     let verify_data_payload = Payload::new(verify_data);
 
     let f = Message {
@@ -617,15 +668,34 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
         // We ignore certreq.certtypes as a result, since the information it contains
         // is entirely duplicated in certreq.sigschemes.
 
+        let sigschemes = certreq
+            .sigschemes
+            .iter()
+            .copied()
+            .filter(|scheme| {
+                self.config
+                    .allows_signature_scheme(*scheme)
+            })
+            .collect::<Vec<_>>();
+
         const NO_CONTEXT: Option<Vec<u8>> = None; // TLS 1.2 doesn't use a context.
-        let client_auth = ClientAuthDetails::resolve(
-            self.config
-                .client_auth_cert_resolver
-                .as_ref(),
-            Some(&certreq.canames),
-            &certreq.sigschemes,
-            NO_CONTEXT,
-        );
+        let client_auth = match self
+            .config
+            .client_cert_types
+            .contains(&CertificateType::X509)
+        {
+            true => ClientAuthDetails::resolve(
+                self.config
+                    .client_auth_cert_resolver
+                    .as_ref(),
+                Some(&certreq.canames),
+                &sigschemes,
+                NO_CONTEXT,
+            ),
+            false => ClientAuthDetails::Empty {
+                auth_context_tls13: NO_CONTEXT,
+            },
+        };
 
         Ok(Box::new(ExpectServerDone {
             config: self.config,
@@ -707,7 +777,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
             .cert_chain
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
+        let now = st.config.current_time()?.into();
         let cert_verified = st
             .config
             .verifier
@@ -722,6 +792,23 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
                 cx.common
                     .send_cert_verify_error_alert(err)
             })?;
+        for (cert, ocsp_response) in intermediates
+            .iter()
+            .zip(
+                &st.server_cert
+                    .intermediate_ocsp_responses,
+            )
+            .filter(|(_, ocsp_response)| !ocsp_response.is_empty())
+        {
+            st.config
+                .verifier
+                .verify_intermediate_ocsp_response(cert, ocsp_response, now)
+                .map_err(|err| {
+                    cx.common
+                        .send_cert_verify_error_alert(err)
+                })?;
+        }
+        handshake_event!("server certificate verified");
 
         // 3.
         // Build up the contents of the signed message.
@@ -744,6 +831,16 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
                 return Err(PeerMisbehaved::SignedKxWithWrongAlgorithm.into());
             }
 
+            if !st
+                .config
+                .allows_signature_scheme(sig.scheme)
+            {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+                ));
+            }
+
             st.config
                 .verifier
                 .verify_tls12_signature(&message, &st.server_cert.cert_chain[0], sig)
@@ -776,6 +873,8 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
                 Err(KeyExchangeError::GetRandomFailed) => return Err(GetRandomFailed.into()),
             };
 
+        cx.common.kx_group = Some(named_group);
+
         // 5b.
         let mut transcript = st.transcript;
         emit_clientkx(&mut transcript, cx.common, kx.pub_key());
@@ -970,12 +1069,16 @@ impl<C: CryptoProvider> ExpectFinished<C> {
             }
         }
 
+        if self.config.resumption.tls12_resumption != Tls12Resumption::SessionIdOrTickets {
+            ticket = Vec::new();
+        }
+
         if self.session_id.is_empty() && ticket.is_empty() {
             debug!("Session not saved: server didn't allocate id or ticket");
             return;
         }
 
-        let time_now = match TimeBase::now() {
+        let time_now = match self.config.current_time() {
             Ok(time_now) => time_now,
             #[allow(unused_variables)]
             Err(e) => {
@@ -1031,6 +1134,8 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
 
         // Hash this message too.
         st.transcript.add_message(&m);
+        cx.common
+            .note_tls12_finished(&finished.0);
 
         st.save_session(cx);
 
@@ -1042,7 +1147,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
             emit_finished(&st.secrets, &mut st.transcript, cx.common);
         }
 
-        cx.common.start_traffic();
+        cx.common.note_transcript_hash(
+            st.transcript
+                .get_current_hash()
+                .as_ref(),
+        );
+        cx.common.start_traffic()?;
         Ok(Box::new(ExpectTraffic {
             secrets: st.secrets,
             _cert_verified: st.cert_verified,
@@ -1092,4 +1202,9 @@ impl State<ClientConnectionData> for ExpectTraffic {
         self.secrets
             .extract_secrets(Side::Client)
     }
+
+    #[cfg(feature = "secret_extraction")]
+    fn extract_tls12_master_secret(&self) -> Result<[u8; 48], Error> {
+        Ok(self.secrets.master_secret)
+    }
 }
@@ -4,19 +4,22 @@ use crate::common_state::Protocol;
 #[cfg(feature = "secret_extraction")]
 use crate::common_state::Side;
 use crate::common_state::{CommonState, State};
+use crate::compress;
 use crate::conn::ConnectionRandoms;
-use crate::crypto::{CryptoProvider, KeyExchange, SupportedGroup};
+use crate::crypto::{hmac, CryptoProvider, KeyExchange, SupportedGroup};
 use crate::enums::{
     AlertDescription, ContentType, HandshakeType, ProtocolVersion, SignatureScheme,
 };
-use crate::error::{Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+use crate::error::{CertificateError, Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+use crate::handshake_observer::ResumptionKind;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
-use crate::msgs::enums::ExtensionType;
 use crate::msgs::enums::KeyUpdateRequest;
+use crate::msgs::enums::{CertificateType, ExtensionType};
+use crate::msgs::handshake::DelegatedCredentialPayload;
 use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
 use crate::msgs::handshake::{CertificateEntry, CertificatePayloadTLS13};
 use crate::msgs::handshake::{ClientExtension, ServerExtension};
@@ -25,14 +28,18 @@ use crate::msgs::handshake::{HasServerExtensions, ServerHelloPayload};
 use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+use crate::psk::PresharedKey;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
+use crate::time::{TimeProvider, UnixTime};
 use crate::tls13::key_schedule::{
     KeyScheduleEarly, KeyScheduleHandshake, KeySchedulePreHandshake, KeyScheduleTraffic,
 };
 use crate::tls13::Tls13CipherSuite;
+use crate::unknown_extensions::handle_unknown_extensions;
 use crate::verify::{self, DigitallySignedStruct};
-use crate::{sign, KeyLog};
+use crate::x509::{self, CertificateFields};
+use crate::{sign, Certificate, KeyLog};
 
 use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
@@ -40,7 +47,6 @@ use crate::client::common::ServerCertDetails;
 use crate::client::common::{ClientAuthDetails, ClientHelloDetails};
 use crate::client::{hs, ClientConfig, ClientSessionStore, ServerName};
 
-use crate::ticketer::TimeBase;
 use subtle::ConstantTimeEq;
 
 use crate::sign::{CertifiedKey, Signer};
@@ -73,30 +79,57 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
     transcript: HandshakeHash,
     early_key_schedule: Option<KeyScheduleEarly>,
     hello: ClientHelloDetails,
-    our_key_share: C::KeyExchange,
+    our_key_shares: Vec<C::KeyExchange>,
     mut sent_tls13_fake_ccs: bool,
 ) -> hs::NextStateOrError {
     validate_server_hello(cx.common, server_hello)?;
 
-    let their_key_share = server_hello
-        .get_key_share()
-        .ok_or_else(|| {
-            cx.common.send_fatal_alert(
-                AlertDescription::MissingExtension,
-                PeerMisbehaved::MissingKeyShare,
-            )
-        })?;
+    let their_key_share = server_hello.get_key_share();
+    let our_key_share = match their_key_share {
+        Some(their_key_share) => match our_key_shares
+            .into_iter()
+            .find(|ours| ours.group() == their_key_share.group)
+        {
+            Some(ours) => Some(ours),
+            None => {
+                return Err({
+                    cx.common.send_fatal_alert(
+                        AlertDescription::IllegalParameter,
+                        PeerMisbehaved::WrongGroupForKeyShare,
+                    )
+                });
+            }
+        },
+        None => None,
+    };
 
-    if our_key_share.group() != their_key_share.group {
-        return Err({
-            cx.common.send_fatal_alert(
-                AlertDescription::IllegalParameter,
-                PeerMisbehaved::WrongGroupForKeyShare,
-            )
-        });
-    }
+    // Any external PSKs we offered follow our resumption ticket, if we
+    // offered one.
+    let first_external_psk = usize::from(early_key_schedule.is_some());
+    let external_psk = server_hello
+        .get_psk_index()
+        .and_then(|selected_psk| (selected_psk as usize).checked_sub(first_external_psk))
+        .map(|index| hello.offered_psks.get(index));
+
+    let key_schedule_pre_handshake = if let Some(external_psk) = &external_psk {
+        let external_psk = match external_psk {
+            Some(psk) if psk.hash() == suite.common.hash_provider.algorithm() => psk,
+            _ => {
+                return Err({
+                    cx.common.send_fatal_alert(
+                        AlertDescription::IllegalParameter,
+                        PeerMisbehaved::SelectedInvalidPsk,
+                    )
+                });
+            }
+        };
 
-    let key_schedule_pre_handshake = if let (Some(selected_psk), Some(early_key_schedule)) =
+        debug!("Using external PSK");
+        cx.data.early_data.rejected();
+        cx.common.early_traffic = false;
+        resuming_session.take();
+        KeySchedulePreHandshake::from(KeyScheduleEarly::new(suite, external_psk.key()))
+    } else if let (Some(selected_psk), Some(early_key_schedule)) =
         (server_hello.get_psk_index(), early_key_schedule)
     {
         if let Some(ref resuming) = resuming_session {
@@ -133,6 +166,8 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
             }
 
             debug!("Resuming using PSK");
+            cx.common
+                .note_resumption(ResumptionKind::Tls13Psk);
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
             return Err(PeerMisbehaved::SelectedUnofferedPsk.into());
@@ -147,15 +182,32 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
         KeySchedulePreHandshake::new(suite)
     };
 
-    let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
-        Ok(key_schedule_pre_handshake.into_handshake(secret))
-    })?;
+    let key_schedule = match (their_key_share, our_key_share) {
+        (Some(their_key_share), Some(our_key_share)) => {
+            let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
+                Ok(key_schedule_pre_handshake.into_handshake(secret))
+            })?;
 
-    // Remember what KX group the server liked for next time.
-    config
-        .resumption
-        .store
-        .set_kx_hint(&server_name, their_key_share.group);
+            cx.common.kx_group = Some(their_key_share.group);
+
+            // Remember what KX group the server liked for next time.
+            config
+                .resumption
+                .store
+                .set_kx_hint(&server_name, their_key_share.group);
+            key_schedule
+        }
+        // Only an external PSK may be used without a key exchange (`psk_ke`).
+        _ if external_psk.is_some() => {
+            key_schedule_pre_handshake.into_handshake_without_key_exchange()
+        }
+        _ => {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::MissingExtension,
+                PeerMisbehaved::MissingKeyShare,
+            ));
+        }
+    };
 
     // If we change keying when a subsequent handshake message is being joined,
     // the two halves will have different record layer protections.  Disallow this.
@@ -176,6 +228,7 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
     Ok(Box::new(ExpectEncryptedExtensions {
         config,
         resuming_session,
+        using_external_psk: external_psk.is_some(),
         server_name,
         randoms,
         suite,
@@ -201,11 +254,17 @@ fn validate_server_hello(
     Ok(())
 }
 
-pub(super) fn initial_key_share<C: CryptoProvider>(
+/// Generate the key shares for our first ClientHello.
+///
+/// These are made afresh for every connection, even when we hope to resume:
+/// we only resume using `psk_dhe_ke`, which needs a key share anyway, and an
+/// unused share has been sent in the clear, so offering it again in another
+/// connection would let observers link the two.
+pub(super) fn initial_key_shares<C: CryptoProvider>(
     config: &ClientConfig<C>,
     server_name: &ServerName,
-) -> Result<C::KeyExchange, Error> {
-    let group = config
+) -> Result<Vec<C::KeyExchange>, Error> {
+    let first = config
         .resumption
         .store
         .kx_hint(server_name)
@@ -223,7 +282,19 @@ pub(super) fn initial_key_share<C: CryptoProvider>(
         })
         .name();
 
-    KeyExchange::start(group, &config.kx_groups).map_err(|_| Error::FailedToGetRandomBytes)
+    let others = config
+        .kx_groups
+        .iter()
+        .map(|kx_group| kx_group.name())
+        .filter(|group| *group != first);
+
+    std::iter::once(first)
+        .chain(others)
+        .take(config.key_shares.max(1))
+        .map(|group| {
+            KeyExchange::start(group, &config.kx_groups).map_err(|_| Error::FailedToGetRandomBytes)
+        })
+        .collect()
 }
 
 /// This implements the horrifying TLS1.3 hack where PSK binders have a
@@ -235,7 +306,7 @@ pub(super) fn fill_in_psk_binder(
 ) -> KeyScheduleEarly {
     // We need to know the hash function of the suite we're trying to resume into.
     let suite = resuming.suite();
-    let suite_hash = suite.hash_algorithm();
+    let suite_hash = suite.common.hash_provider;
 
     // The binder is calculated over the clienthello, but doesn't include itself or its
     // length, or the length of its container.
@@ -248,12 +319,80 @@ pub(super) fn fill_in_psk_binder(
     let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
-        ch.set_psk_binder(real_binder.as_ref());
+        ch.set_psk_binder(0, real_binder.as_ref());
     };
 
     key_schedule
 }
 
+/// Choose which of our external PSKs to offer, each with one of our TLS1.3
+/// suites that shares its hash function.  After a HelloRetryRequest, `suite`
+/// is the one the server chose.
+pub(super) fn external_psks_to_offer(
+    config: &ClientConfig<impl CryptoProvider>,
+    suite: Option<&'static Tls13CipherSuite>,
+) -> Vec<(Arc<PresharedKey>, &'static Tls13CipherSuite)> {
+    config
+        .preshared_keys
+        .iter()
+        .filter_map(|psk| {
+            let usable = |suite: &&'static Tls13CipherSuite| {
+                suite.common.hash_provider.algorithm() == psk.hash()
+            };
+            let suite = match suite {
+                Some(suite) => Some(suite).filter(usable),
+                None => config
+                    .cipher_suites
+                    .iter()
+                    .filter_map(|scs| scs.tls13())
+                    .find(usable),
+            }?;
+            Some((Arc::clone(psk), suite))
+        })
+        .collect()
+}
+
+/// Offer our external PSKs, after any resumption ticket.  Their binders are
+/// filled in later by `fill_in_external_psk_binders`.
+pub(super) fn prepare_external_psks(
+    psks: &[(Arc<PresharedKey>, &'static Tls13CipherSuite)],
+    exts: &mut Vec<ClientExtension>,
+) {
+    for (psk, suite) in psks {
+        let binder = vec![0u8; suite.common.hash_provider.output_len()];
+        let psk_identity = PresharedKeyIdentity::new(psk.identity().to_vec(), 0);
+        match exts.last_mut() {
+            Some(ClientExtension::PresharedKey(offer)) => offer.push(psk_identity, binder),
+            _ => exts.push(ClientExtension::PresharedKey(PresharedKeyOffer::new(
+                psk_identity,
+                binder,
+            ))),
+        }
+    }
+}
+
+/// Compute the binders for external PSKs added by `prepare_external_psks`.
+/// The first is at `first_index` in the offer.
+pub(super) fn fill_in_external_psk_binders(
+    psks: &[(Arc<PresharedKey>, &'static Tls13CipherSuite)],
+    first_index: usize,
+    transcript: &HandshakeHashBuffer,
+    hmp: &mut HandshakeMessagePayload,
+) {
+    let binder_plaintext = hmp.get_encoding_for_binder_signing();
+
+    for (i, (psk, suite)) in psks.iter().enumerate() {
+        let handshake_hash =
+            transcript.get_hash_given(suite.common.hash_provider, &binder_plaintext);
+        let key_schedule = KeyScheduleEarly::new(suite, psk.key());
+        let binder = key_schedule.external_psk_binder_key_and_sign_verify_data(&handshake_hash);
+
+        if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
+            ch.set_psk_binder(first_index + i, binder.as_ref());
+        };
+    }
+}
+
 pub(super) fn prepare_resumption(
     config: &ClientConfig<impl CryptoProvider>,
     cx: &mut ClientContext<'_>,
@@ -282,8 +421,9 @@ pub(super) fn prepare_resumption(
     let obfuscated_ticket_age = resuming_session.obfuscated_ticket_age();
 
     let binder_len = resuming_suite
-        .hash_algorithm()
-        .output_len;
+        .common
+        .hash_provider
+        .output_len();
     let binder = vec![0u8; binder_len];
 
     let psk_identity =
@@ -304,7 +444,8 @@ pub(super) fn derive_early_traffic_secret(
     // For middlebox compatibility
     emit_fake_ccs(sent_tls13_fake_ccs, cx.common);
 
-    let client_hello_hash = transcript_buffer.get_hash_given(resuming_suite.hash_algorithm(), &[]);
+    let client_hello_hash =
+        transcript_buffer.get_hash_given(resuming_suite.common.hash_provider, &[]);
     early_key_schedule.client_early_traffic_secret(
         &client_hello_hash,
         key_log,
@@ -345,10 +486,15 @@ fn validate_encrypted_extensions(
         ));
     }
 
-    if hello.server_sent_unsolicited_extensions(exts, &[]) {
+    common.peer_extensions.extend(
+        exts.iter()
+            .map(ServerExtension::get_type),
+    );
+
+    if let Some(ext_type) = hello.server_sent_unsolicited_extension(exts, &[]) {
         return Err(common.send_fatal_alert(
             AlertDescription::UnsupportedExtension,
-            PeerMisbehaved::UnsolicitedEncryptedExtension,
+            PeerMisbehaved::UnsolicitedExtension(ext_type),
         ));
     }
 
@@ -369,6 +515,7 @@ fn validate_encrypted_extensions(
 struct ExpectEncryptedExtensions<C: CryptoProvider> {
     config: Arc<ClientConfig<C>>,
     resuming_session: Option<persist::Tls13ClientSessionValue>,
+    using_external_psk: bool,
     server_name: ServerName,
     randoms: ConnectionRandoms,
     suite: &'static Tls13CipherSuite,
@@ -388,6 +535,16 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectEncryptedExtension
         self.transcript.add_message(&m);
 
         validate_encrypted_extensions(cx.common, &self.hello, exts)?;
+        handle_unknown_extensions(
+            self.config
+                .unknown_extension_handler
+                .as_ref(),
+            cx.common,
+            exts.iter().filter_map(|ext| match ext {
+                ServerExtension::Unknown(unknown) => Some(unknown),
+                _ => None,
+            }),
+        )?;
         hs::process_alpn_protocol(cx.common, &self.config, exts.get_alpn_protocol())?;
 
         #[cfg(feature = "quic")]
@@ -443,10 +600,31 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectEncryptedExtension
                 cert_verified,
                 sig_verified,
             }))
+        } else if self.using_external_psk {
+            if exts.early_data_extension_offered() {
+                return Err(PeerMisbehaved::EarlyDataExtensionWithoutResumption.into());
+            }
+
+            // The server proved knowledge of the PSK with its Finished
+            // message; there is no certificate to verify.
+            let cert_verified = verify::ServerCertVerified::assertion();
+            let sig_verified = verify::HandshakeSignatureValid::assertion();
+            Ok(Box::new(ExpectFinished {
+                config: self.config,
+                server_name: self.server_name,
+                randoms: self.randoms,
+                suite: self.suite,
+                transcript: self.transcript,
+                key_schedule: self.key_schedule,
+                client_auth: None,
+                cert_verified,
+                sig_verified,
+            }))
         } else {
             if exts.early_data_extension_offered() {
                 return Err(PeerMisbehaved::EarlyDataExtensionWithoutResumption.into());
             }
+            let raw_public_keys = RawPublicKeys::negotiate(&self.config, cx.common, exts)?;
             Ok(Box::new(ExpectCertificateOrCertReq {
                 config: self.config,
                 server_name: self.server_name,
@@ -454,11 +632,65 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectEncryptedExtension
                 suite: self.suite,
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
+                raw_public_keys,
             }))
         }
     }
 }
 
+/// Which peers authenticate with raw public keys (RFC 7250) rather than
+/// certificate chains, as agreed in EncryptedExtensions.
+#[derive(Clone, Copy, Debug)]
+struct RawPublicKeys {
+    server: bool,
+    client: bool,
+}
+
+impl RawPublicKeys {
+    fn negotiate<C: CryptoProvider>(
+        config: &ClientConfig<C>,
+        common: &mut CommonState,
+        exts: &Vec<ServerExtension>,
+    ) -> Result<Self, Error> {
+        // Extensions we didn't send are already rejected as unsolicited,
+        // so a missing extension means X509.
+        let server = match exts.get_server_certificate_type() {
+            Some(typ) if !config.server_cert_types.contains(&typ) => {
+                return Err(common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::SelectedUnofferedCertificateType,
+                ));
+            }
+            Some(typ) => typ,
+            None if !config
+                .server_cert_types
+                .contains(&CertificateType::X509) =>
+            {
+                return Err(common.send_fatal_alert(
+                    AlertDescription::HandshakeFailure,
+                    PeerIncompatible::NoCertificateTypesInCommon,
+                ));
+            }
+            None => CertificateType::X509,
+        };
+        let client = match exts.get_client_certificate_type() {
+            Some(typ) if !config.client_cert_types.contains(&typ) => {
+                return Err(common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::SelectedUnofferedCertificateType,
+                ));
+            }
+            Some(typ) => typ,
+            None => CertificateType::X509,
+        };
+
+        Ok(Self {
+            server: server == CertificateType::RawPublicKey,
+            client: client == CertificateType::RawPublicKey,
+        })
+    }
+}
+
 struct ExpectCertificateOrCertReq<C: CryptoProvider> {
     config: Arc<ClientConfig<C>>,
     server_name: ServerName,
@@ -466,6 +698,7 @@ struct ExpectCertificateOrCertReq<C: CryptoProvider> {
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
+    raw_public_keys: RawPublicKeys,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertReq<C> {
@@ -478,6 +711,14 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
                         ..
                     },
                 ..
+            }
+            | MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(..),
+                        ..
+                    },
+                ..
             } => Box::new(ExpectCertificate {
                 config: self.config,
                 server_name: self.server_name,
@@ -486,6 +727,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 client_auth: None,
+                raw_public_keys: self.raw_public_keys,
             })
             .handle(cx, m),
             MessagePayload::Handshake {
@@ -502,6 +744,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
                 suite: self.suite,
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
+                raw_public_keys: self.raw_public_keys,
             })
             .handle(cx, m),
             payload => Err(inappropriate_handshake_message(
@@ -509,6 +752,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
                 &[ContentType::Handshake],
                 &[
                     HandshakeType::Certificate,
+                    HandshakeType::CompressedCertificate,
                     HandshakeType::CertificateRequest,
                 ],
             )),
@@ -526,6 +770,7 @@ struct ExpectCertificateRequest<C: CryptoProvider> {
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
+    raw_public_keys: RawPublicKeys,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest<C> {
@@ -557,7 +802,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             .unwrap_or(&no_sigschemes)
             .iter()
             .cloned()
-            .filter(|scheme| tls13_sign_schemes.contains(scheme))
+            .filter(|scheme| {
+                tls13_sign_schemes.contains(scheme)
+                    && self
+                        .config
+                        .allows_signature_scheme(*scheme)
+            })
             .collect::<Vec<SignatureScheme>>();
 
         if compat_sigschemes.is_empty() {
@@ -567,14 +817,30 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             ));
         }
 
-        let client_auth = ClientAuthDetails::resolve(
-            self.config
-                .client_auth_cert_resolver
-                .as_ref(),
-            certreq.get_authorities_extension(),
-            &compat_sigschemes,
-            Some(certreq.context.0.clone()),
-        );
+        let auth_context = Some(certreq.context.0.clone());
+        let client_auth = match &self.config.client_raw_public_key {
+            Some(raw_public_key) if self.raw_public_keys.client => {
+                ClientAuthDetails::raw_public_key(raw_public_key, &compat_sigschemes, auth_context)
+            }
+            _ if self.raw_public_keys.client
+                || !self
+                    .config
+                    .client_cert_types
+                    .contains(&CertificateType::X509) =>
+            {
+                ClientAuthDetails::Empty {
+                    auth_context_tls13: auth_context,
+                }
+            }
+            _ => ClientAuthDetails::resolve(
+                self.config
+                    .client_auth_cert_resolver
+                    .as_ref(),
+                certreq.get_authorities_extension(),
+                &compat_sigschemes,
+                auth_context,
+            ),
+        };
 
         Ok(Box::new(ExpectCertificate {
             config: self.config,
@@ -584,6 +850,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_auth: Some(client_auth),
+            raw_public_keys: self.raw_public_keys,
         }))
     }
 }
@@ -596,15 +863,49 @@ struct ExpectCertificate<C: CryptoProvider> {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     client_auth: Option<ClientAuthDetails>,
+    raw_public_keys: RawPublicKeys,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
     fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
-        let cert_chain = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        let decompressed;
+        let cert_chain = match &m.payload {
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CertificateTLS13(cert_chain),
+                        ..
+                    },
+                ..
+            } => cert_chain,
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(compressed),
+                        ..
+                    },
+                ..
+            } => {
+                decompressed = compress::decompress_certificate(
+                    &self.config.cert_compressors,
+                    self.config
+                        .max_decompressed_certificate_len,
+                    compressed,
+                    cx.common,
+                )?;
+                &decompressed
+            }
+            payload => {
+                return Err(inappropriate_handshake_message(
+                    payload,
+                    &[ContentType::Handshake],
+                    &[
+                        HandshakeType::Certificate,
+                        HandshakeType::CompressedCertificate,
+                    ],
+                ));
+            }
+        };
         self.transcript.add_message(&m);
 
         // This is only non-empty for client auth.
@@ -615,6 +916,13 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             ));
         }
 
+        if cert_chain.entries.len() > self.config.max_certificate_chain_len {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::BadCertificate,
+                PeerMisbehaved::TooManyCertificates,
+            ));
+        }
+
         if cert_chain.any_entry_has_duplicate_extension()
             || cert_chain.any_entry_has_unknown_extension()
         {
@@ -624,8 +932,35 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             ));
         }
 
-        let server_cert =
-            ServerCertDetails::new(cert_chain.convert(), cert_chain.get_end_entity_ocsp());
+        // Delegated credentials are only allowed on the end-entity
+        // certificate, and only if we offered them.
+        let delegated_credential = cert_chain
+            .get_end_entity_delegated_credential()
+            .cloned();
+        if (delegated_credential.is_some()
+            && (!self.config.delegated_credentials || self.raw_public_keys.server))
+            || cert_chain.any_intermediate_has_delegated_credential()
+        {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::UnexpectedMessage,
+                PeerMisbehaved::UnsolicitedDelegatedCredential,
+            ));
+        }
+
+        // A raw public key is sent alone, in place of the end-entity
+        // certificate.
+        if self.raw_public_keys.server && cert_chain.entries.len() != 1 {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::BadCertificate,
+                CertificateError::BadEncoding,
+            ));
+        }
+
+        let server_cert = ServerCertDetails::new(
+            cert_chain.convert(),
+            cert_chain.get_end_entity_ocsp(),
+            vec![],
+        );
 
         Ok(Box::new(ExpectCertificateVerify {
             config: self.config,
@@ -635,7 +970,9 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             server_cert,
+            delegated_credential,
             client_auth: self.client_auth,
+            raw_public_keys: self.raw_public_keys,
         }))
     }
 }
@@ -649,7 +986,9 @@ struct ExpectCertificateVerify<C: CryptoProvider> {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     server_cert: ServerCertDetails,
+    delegated_credential: Option<DelegatedCredentialPayload>,
     client_auth: Option<ClientAuthDetails>,
+    raw_public_keys: RawPublicKeys,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<C> {
@@ -662,42 +1001,77 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<
 
         trace!("Server cert is {:?}", self.server_cert.cert_chain);
 
-        // 1. Verify the certificate chain.
+        // 1. Verify the certificate chain, or that we know the raw public key.
         let (end_entity, intermediates) = self
             .server_cert
             .cert_chain
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = self
-            .config
-            .verifier
-            .verify_server_cert(
+        let now = self.config.current_time()?;
+        let cert_verified = match self.raw_public_keys.server {
+            true => verify::verify_raw_public_key(&self.config.pinned_server_keys, end_entity)
+                .map(|_| verify::ServerCertVerified::assertion()),
+            false => self.config.verifier.verify_server_cert(
                 end_entity,
                 intermediates,
                 &self.server_name,
                 &self.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| {
+                now.into(),
+            ),
+        }
+        .map_err(|err| {
+            cx.common
+                .send_cert_verify_error_alert(err)
+        })?;
+        handshake_event!("server certificate verified");
+
+        // 1a. Verify the delegated credential, if any, which then signs the
+        // handshake in place of the certificate's key.
+        if let Some(dc) = &self.delegated_credential {
+            verify_delegated_credential(&self.config, dc, end_entity, now).map_err(|err| {
                 cx.common
                     .send_cert_verify_error_alert(err)
             })?;
+        }
 
         // 2. Verify their signature on the handshake.
-        let handshake_hash = self.transcript.get_current_hash();
-        let sig_verified = self
+        if !self
             .config
-            .verifier
-            .verify_tls13_signature(
-                &verify::construct_tls13_server_verify_message(&handshake_hash),
-                &self.server_cert.cert_chain[0],
-                cert_verify,
-            )
-            .map_err(|err| {
-                cx.common
-                    .send_cert_verify_error_alert(err)
-            })?;
+            .allows_signature_scheme(cert_verify.scheme)
+        {
+            return Err(cx.common.send_fatal_alert(
+                AlertDescription::IllegalParameter,
+                PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+            ));
+        }
+
+        let handshake_hash = self.transcript.get_current_hash();
+        let message = verify::construct_tls13_server_verify_message(&handshake_hash);
+        let sig_verified = match &self.delegated_credential {
+            Some(dc) if cert_verify.scheme != dc.dc_cert_verify_algorithm => {
+                Err(PeerMisbehaved::InvalidDelegatedCredential.into())
+            }
+            Some(dc) => self
+                .config
+                .verifier
+                .verify_tls13_signature_with_spki(&message, &dc.spki.0, cert_verify),
+            None if self.raw_public_keys.server => self
+                .config
+                .verifier
+                .verify_tls13_signature_with_spki(
+                    &message,
+                    &self.server_cert.cert_chain[0].0,
+                    cert_verify,
+                ),
+            None => self
+                .config
+                .verifier
+                .verify_tls13_signature(&message, &self.server_cert.cert_chain[0], cert_verify),
+        }
+        .map_err(|err| {
+            cx.common
+                .send_cert_verify_error_alert(err)
+        })?;
 
         cx.common.peer_certificates = Some(self.server_cert.cert_chain);
         self.transcript.add_message(&m);
@@ -716,6 +1090,53 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<
     }
 }
 
+/// Check a delegated credential attached to `end_entity`, whose chain
+/// is already verified: RFC 9345 section 4.1.3.
+fn verify_delegated_credential<C: CryptoProvider>(
+    config: &ClientConfig<C>,
+    dc: &DelegatedCredentialPayload,
+    end_entity: &Certificate,
+    now: UnixTime,
+) -> Result<(), Error> {
+    // Credentials may not be valid for longer than this.
+    const MAX_VALID_TIME: u64 = 7 * 24 * 60 * 60;
+
+    if !x509::allows_delegation(&end_entity.0) {
+        return Err(PeerMisbehaved::InvalidDelegatedCredential.into());
+    }
+
+    let not_before = CertificateFields::parse(&end_entity.0)
+        .and_then(|cert| cert.not_before())
+        .ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    let expiry = not_before + u64::from(dc.valid_time);
+    if expiry <= now.as_secs() {
+        return Err(PeerMisbehaved::ExpiredDelegatedCredential.into());
+    }
+    if expiry > now.as_secs() + MAX_VALID_TIME {
+        return Err(PeerMisbehaved::InvalidDelegatedCredential.into());
+    }
+
+    // We offered the schemes the verifier supports, for the certificate's
+    // signature over the credential.
+    if !dc.algorithm.supported_in_tls13()
+        || !config
+            .verifier
+            .supported_verify_schemes()
+            .contains(&dc.algorithm)
+    {
+        return Err(PeerMisbehaved::InvalidDelegatedCredential.into());
+    }
+
+    config
+        .verifier
+        .verify_tls13_signature(
+            &dc.signed_message(end_entity),
+            end_entity,
+            &DigitallySignedStruct::new(dc.algorithm, dc.signature.0.clone()),
+        )
+        .map(|_| ())
+}
+
 fn emit_certificate_tls13(
     transcript: &mut HandshakeHash,
     certkey: Option<&CertifiedKey>,
@@ -774,7 +1195,7 @@ fn emit_certverify_tls13(
 
 fn emit_finished_tls13(
     transcript: &mut HandshakeHash,
-    verify_data: ring::hmac::Tag,
+    verify_data: hmac::Tag,
     common: &mut CommonState,
 ) {
     let verify_data_payload = Payload::new(verify_data.as_ref());
@@ -899,10 +1320,16 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
         /* Now move to our application traffic keys. */
         cx.common.check_aligned_handshake()?;
         let key_schedule_traffic = key_schedule_pre_finished.into_traffic(cx.common);
-        cx.common.start_traffic();
+        cx.common.note_transcript_hash(
+            st.transcript
+                .get_current_hash()
+                .as_ref(),
+        );
+        cx.common.start_traffic()?;
 
         let st = ExpectTraffic {
             session_storage: Arc::clone(&st.config.resumption.store),
+            time_provider: Arc::clone(&st.config.time_provider),
             server_name: st.server_name,
             suite: st.suite,
             transcript: st.transcript,
@@ -926,6 +1353,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
 // and application data.
 struct ExpectTraffic {
     session_storage: Arc<dyn ClientSessionStore>,
+    time_provider: Arc<dyn TimeProvider>,
     server_name: ServerName,
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
@@ -954,11 +1382,10 @@ impl ExpectTraffic {
             .key_schedule
             .resumption_master_secret_and_derive_ticket_psk(&handshake_hash, &nst.nonce.0);
 
-        let time_now = match TimeBase::now() {
-            Ok(t) => t,
-            #[allow(unused_variables)]
-            Err(e) => {
-                debug!("Session not saved: {}", e);
+        let time_now = match self.time_provider.current_time() {
+            Some(t) => t,
+            None => {
+                debug!("Session not saved: {}", Error::FailedToGetCurrentTime);
                 return Ok(());
             }
         };
@@ -1017,7 +1444,7 @@ impl ExpectTraffic {
 
         if common.should_update_key(key_update_request)? {
             self.key_schedule
-                .update_encrypter_and_notify(common);
+                .update_encrypter_and_notify(common)?;
         }
 
         // Update our read-side keys.
@@ -1076,6 +1503,13 @@ impl State<ClientConnectionData> for ExpectTraffic {
         self.key_schedule
             .extract_secrets(Side::Client)
     }
+
+    fn send_key_update_request(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        self.key_schedule
+            .update_encrypter_and_notify(common)?;
+        common.perhaps_write_key_update();
+        Ok(())
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -1,47 +1,64 @@
+use crate::buffer_pool::BufferPool;
 use crate::enums::{AlertDescription, ContentType, HandshakeType, ProtocolVersion};
 use crate::error::{Error, InvalidMessage, PeerMisbehaved};
+use crate::handshake_observer::{HandshakeObserver, HandshakeParams, ResumptionKind};
 use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::{debug, warn};
 use crate::msgs::alert::AlertMessagePayload;
 use crate::msgs::base::Payload;
-use crate::msgs::enums::{AlertLevel, KeyUpdateRequest};
+use crate::msgs::enums::{AlertLevel, ExtensionType, KeyUpdateRequest, NamedGroup};
 use crate::msgs::fragmenter::MessageFragmenter;
-#[cfg(feature = "quic")]
-use crate::msgs::message::MessagePayload;
-use crate::msgs::message::{BorrowedPlainMessage, Message, OpaqueMessage, PlainMessage};
+use crate::msgs::message::{
+    BorrowedPlainMessage, Message, MessageNeedsMore, MessagePayload, OpaqueMessage, PlainMessage,
+};
 #[cfg(feature = "quic")]
 use crate::quic;
 use crate::record_layer;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
 use crate::suites::SupportedCipherSuite;
+use crate::time::{DefaultMonotonicClock, MonotonicClock};
 #[cfg(feature = "tls12")]
 use crate::tls12::ConnectionSecrets;
+use crate::tls13::RecordPadding;
 use crate::vecbuf::ChunkVecBuffer;
 
+use std::mem;
+use std::sync::Arc;
+use std::time::Instant;
+
 /// Connection state common to both client and server connections.
 pub struct CommonState {
     pub(crate) negotiated_version: Option<ProtocolVersion>,
     pub(crate) side: Side,
     pub(crate) record_layer: record_layer::RecordLayer,
     pub(crate) suite: Option<SupportedCipherSuite>,
+    pub(crate) kx_group: Option<NamedGroup>,
     pub(crate) alpn_protocol: Option<Vec<u8>>,
     pub(crate) aligned_handshake: bool,
     pub(crate) may_send_application_data: bool,
     pub(crate) may_receive_application_data: bool,
     pub(crate) early_traffic: bool,
     sent_fatal_alert: bool,
+    /// If we have signaled end of stream.
+    pub(crate) has_sent_close_notify: bool,
     /// If the peer has signaled end of stream.
     pub(crate) has_received_close_notify: bool,
     pub(crate) has_seen_eof: bool,
     pub(crate) received_middlebox_ccs: u8,
     pub(crate) peer_certificates: Option<Vec<key::Certificate>>,
+    pub(crate) peer_extensions: Vec<ExtensionType>,
+    channel_binding: Option<Box<ChannelBinding>>,
     message_fragmenter: MessageFragmenter,
     pub(crate) received_plaintext: ChunkVecBuffer,
+    /// The record most recently taken by `next_plaintext_record`.
+    pub(crate) taken_plaintext_record: Vec<u8>,
     sendable_plaintext: ChunkVecBuffer,
     pub(crate) sendable_tls: ChunkVecBuffer,
     queued_key_update_message: Option<Vec<u8>>,
+    /// Our TLS1.3 write key is nearly exhausted and should be replaced.
+    pub(crate) refresh_traffic_keys_pending: bool,
 
     #[allow(dead_code)] // only read for QUIC
     /// Protocol whose key schedule should be used. Unused for TLS < 1.3.
@@ -50,6 +67,21 @@ pub struct CommonState {
     pub(crate) quic: quic::Quic,
     #[cfg(feature = "secret_extraction")]
     pub(crate) enable_secret_extraction: bool,
+    pub(crate) handshake_observer: Option<Arc<dyn HandshakeObserver>>,
+    pub(crate) record_padding: RecordPadding,
+    handshake_deadline: Option<Instant>,
+    handshake_clock: Arc<dyn MonotonicClock>,
+    /// Small writes, held back until there are `write_buffer_threshold`
+    /// bytes of them or the application flushes.
+    pub(crate) buffered_plaintext: Vec<u8>,
+    pub(crate) write_buffer_threshold: usize,
+    buffer_pool: Option<Arc<BufferPool>>,
+    resumed: bool,
+    pub(crate) stats: ConnectionStats,
+    /// We have sent handshake messages, and not yet received the peer's reply.
+    awaiting_peer_flight: bool,
+    #[cfg(feature = "tracing")]
+    pub(crate) handshake_span: tracing::Span,
 }
 
 impl CommonState {
@@ -59,27 +91,45 @@ impl CommonState {
             side,
             record_layer: record_layer::RecordLayer::new(),
             suite: None,
+            kx_group: None,
             alpn_protocol: None,
             aligned_handshake: true,
             may_send_application_data: false,
             may_receive_application_data: false,
             early_traffic: false,
             sent_fatal_alert: false,
+            has_sent_close_notify: false,
             has_received_close_notify: false,
             has_seen_eof: false,
             received_middlebox_ccs: 0,
             peer_certificates: None,
+            peer_extensions: Vec::new(),
+            channel_binding: None,
             message_fragmenter: MessageFragmenter::default(),
             received_plaintext: ChunkVecBuffer::new(Some(DEFAULT_RECEIVED_PLAINTEXT_LIMIT)),
+            taken_plaintext_record: Vec::new(),
             sendable_plaintext: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             sendable_tls: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             queued_key_update_message: None,
+            refresh_traffic_keys_pending: false,
 
             protocol: Protocol::Tcp,
             #[cfg(feature = "quic")]
             quic: quic::Quic::default(),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
+            handshake_observer: None,
+            record_padding: RecordPadding::None,
+            handshake_deadline: None,
+            handshake_clock: Arc::new(DefaultMonotonicClock),
+            buffered_plaintext: Vec::new(),
+            write_buffer_threshold: 0,
+            buffer_pool: None,
+            resumed: false,
+            stats: ConnectionStats::default(),
+            awaiting_peer_flight: false,
+            #[cfg(feature = "tracing")]
+            handshake_span: tracing::debug_span!(target: "rustls::handshake", "handshake", side = ?side),
         }
     }
 
@@ -90,6 +140,17 @@ impl CommonState {
         !self.sendable_tls.is_empty()
     }
 
+    /// Returns the number of TLS bytes that [`Connection::write_tls`] would
+    /// currently write, if given an unlimited amount of space.
+    ///
+    /// This includes encrypted application data and handshake messages, but not
+    /// plaintext that is buffered awaiting handshake completion.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    pub fn pending_write_bytes(&self) -> usize {
+        self.sendable_tls.len()
+    }
+
     /// Returns true if the connection is currently performing the TLS handshake.
     ///
     /// During this time plaintext written to the connection is buffered in memory. After
@@ -101,6 +162,27 @@ impl CommonState {
         !(self.may_send_application_data && self.may_receive_application_data)
     }
 
+    /// Returns the current state of this connection.
+    ///
+    /// This summarises [`CommonState::is_handshaking`] and whether each
+    /// peer has closed its direction of the connection.
+    pub fn state(&self) -> ConnectionState {
+        if self.sent_fatal_alert || (self.has_sent_close_notify && self.has_received_close_notify) {
+            ConnectionState::Closed
+        } else if self.has_sent_close_notify || self.has_received_close_notify {
+            ConnectionState::Closing
+        } else if self.is_handshaking() {
+            ConnectionState::Handshaking
+        } else {
+            ConnectionState::Established
+        }
+    }
+
+    /// Returns counters describing the traffic on this connection so far.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
     /// Retrieves the certificate chain used by the peer to authenticate.
     ///
     /// The order of the certificate chain is as it appears in the TLS
@@ -120,6 +202,21 @@ impl CommonState {
         self.peer_certificates.as_deref()
     }
 
+    /// Retrieves the types of the extensions the peer sent in its hello,
+    /// in the order it sent them.
+    ///
+    /// For servers, these are from the client's ClientHello (the second one,
+    /// if a HelloRetryRequest was needed).
+    ///
+    /// For clients, these are from the server's ServerHello and, in TLS1.3,
+    /// its EncryptedExtensions.
+    ///
+    /// Only the types are recorded, not the extensions' contents.  This is
+    /// empty until the relevant messages have been received.
+    pub fn peer_extensions(&self) -> Vec<ExtensionType> {
+        self.peer_extensions.clone()
+    }
+
     /// Retrieves the protocol agreed with the peer via ALPN.
     ///
     /// A return value of `None` after handshake completion
@@ -136,6 +233,16 @@ impl CommonState {
         self.suite
     }
 
+    /// Retrieves the key exchange group used to agree the connection's
+    /// keys.
+    ///
+    /// This returns `None` until the key exchange is done, and for
+    /// handshakes that do no key exchange at all: TLS1.2 resumptions,
+    /// and TLS1.3 handshakes using a preshared key alone (`psk_ke`).
+    pub fn negotiated_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.kx_group
+    }
+
     /// Retrieves the protocol version agreed with the peer.
     ///
     /// This returns `None` until the version is agreed.
@@ -143,6 +250,74 @@ impl CommonState {
         self.negotiated_version
     }
 
+    /// Returns true if the handshake resumed an earlier session, rather
+    /// than authenticating the peer afresh.
+    ///
+    /// In TLS1.3 this means a resumption PSK (ticket) was accepted, and in
+    /// TLS1.2 that an abbreviated handshake using a ticket or session ID was
+    /// done.  Handshakes using an external PSK are not resumptions.
+    ///
+    /// This returns `false` until the handshake completes.
+    pub fn is_resumed(&self) -> bool {
+        self.resumed && !self.is_handshaking()
+    }
+
+    /// Retrieves the hash of the handshake transcript, using the hash
+    /// function of the negotiated cipher suite.
+    ///
+    /// This covers every handshake message up to and including both
+    /// `Finished` messages, and so is the same for both peers.  It is
+    /// unique to this connection, but it is not secret: it is computed
+    /// over messages an on-path attacker can see in TLS1.2.  It must not
+    /// be used as keying material.  For channel binding, prefer
+    /// `tls-exporter` (RFC9266), which is available using
+    /// `export_keying_material`.
+    ///
+    /// The return value is `None` until the handshake completes.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        self.channel_binding
+            .as_ref()?
+            .transcript_hash
+            .clone()
+    }
+
+    /// Retrieves the `tls-unique` channel binding (RFC5929) for a TLS1.2
+    /// connection: the `verify_data` of the first `Finished` message.
+    ///
+    /// `tls-unique` is only safe to use if extended master secret is
+    /// negotiated: otherwise a man-in-the-middle can arrange for two
+    /// connections to have the same value (the "triple handshake" attack).
+    /// It is not defined for TLS1.3, where this returns `None`.  Prefer
+    /// `tls-exporter` (RFC9266) where possible.
+    ///
+    /// The return value is `None` until the handshake completes.
+    pub fn tls_unique(&self) -> Option<Vec<u8>> {
+        match self.is_handshaking() {
+            true => None,
+            false => self
+                .channel_binding
+                .as_ref()?
+                .tls_unique
+                .clone(),
+        }
+    }
+
+    /// Note the transcript hash once the handshake is complete.
+    pub(crate) fn note_transcript_hash(&mut self, hash: &[u8]) {
+        self.channel_binding
+            .get_or_insert_with(Box::default)
+            .transcript_hash = Some(hash.to_vec());
+    }
+
+    /// Note the `verify_data` of a TLS1.2 `Finished` message: the first of
+    /// these is the `tls-unique` channel binding.
+    pub(crate) fn note_tls12_finished(&mut self, verify_data: &[u8]) {
+        self.channel_binding
+            .get_or_insert_with(Box::default)
+            .tls_unique
+            .get_or_insert_with(|| verify_data.to_vec());
+    }
+
     pub(crate) fn is_tls13(&self) -> bool {
         matches!(self.negotiated_version, Some(ProtocolVersion::TLSv1_3))
     }
@@ -166,12 +341,29 @@ impl CommonState {
             }
         }
 
+        if self.awaiting_peer_flight
+            && self.is_handshaking()
+            && matches!(msg.payload, MessagePayload::Handshake { .. })
+        {
+            self.awaiting_peer_flight = false;
+            self.stats.handshake_round_trips += 1;
+        }
+
         let mut cx = Context { common: self, data };
         match state.handle(&mut cx, msg) {
             Ok(next) => {
                 state = next;
                 Ok(state)
             }
+            // Application data is only acceptable outside the handshake, and
+            // as early data (which the handshake state itself accepts).
+            Err(Error::InappropriateMessage {
+                got_type: ContentType::ApplicationData,
+                ..
+            }) if !self.may_receive_application_data => Err(self.send_fatal_alert(
+                AlertDescription::UnexpectedMessage,
+                PeerMisbehaved::ApplicationDataDuringHandshake,
+            )),
             Err(e @ Error::InappropriateMessage { .. })
             | Err(e @ Error::InappropriateHandshakeMessage { .. }) => {
                 Err(self.send_fatal_alert(AlertDescription::UnexpectedMessage, e))
@@ -185,18 +377,68 @@ impl CommonState {
     ///
     /// If internal buffers are too small, this function will not accept
     /// all the data.
-    pub(crate) fn send_some_plaintext(&mut self, data: &[u8]) -> usize {
+    pub(crate) fn send_some_plaintext(&mut self, data: &[u8]) -> Result<usize, Error> {
         self.perhaps_write_key_update();
         self.send_plain(data, Limit::Yes)
     }
 
-    pub(crate) fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
+    /// Whether `send_checked_plaintext` can take `len` bytes without
+    /// exceeding the buffer limit.
+    pub(crate) fn has_room_for_plaintext(&self, len: usize) -> bool {
+        let buffer = match self.may_send_application_data {
+            true => &self.sendable_tls,
+            false => &self.sendable_plaintext,
+        };
+        buffer.apply_limit(len) == len
+    }
+
+    /// Like `send_some_plaintext`, but ignoring the buffer limit: the
+    /// caller has checked with `has_room_for_plaintext`.
+    ///
+    /// This still stops early if the write key needs replacing.
+    pub(crate) fn send_checked_plaintext(&mut self, data: &[u8]) -> Result<usize, Error> {
+        self.perhaps_write_key_update();
+        self.send_plain(data, Limit::No)
+    }
+
+    /// Like `send_some_plaintext`, but holding back small writes (see
+    /// `write_buffer_threshold`) so they can share records.
+    pub(crate) fn buffer_some_plaintext(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let threshold = self.write_buffer_threshold;
+        if threshold == 0 || (self.buffered_plaintext.is_empty() && data.len() >= threshold) {
+            return self.send_some_plaintext(data);
+        }
+
+        let len = data
+            .len()
+            .min(threshold.saturating_sub(self.buffered_plaintext.len()));
+        self.buffered_plaintext
+            .extend_from_slice(&data[..len]);
+        if self.buffered_plaintext.len() >= threshold {
+            self.flush_buffered_plaintext()?;
+        }
+        Ok(len)
+    }
+
+    /// Send any writes held back by `buffer_some_plaintext`.
+    pub(crate) fn flush_buffered_plaintext(&mut self) -> Result<(), Error> {
+        if self.buffered_plaintext.is_empty() {
+            return Ok(());
+        }
+        let buffered = mem::take(&mut self.buffered_plaintext);
+        let sent = self.send_some_plaintext(&buffered);
+        self.buffered_plaintext = buffered;
+        self.buffered_plaintext.drain(..sent?);
+        Ok(())
+    }
+
+    pub(crate) fn send_early_plaintext(&mut self, data: &[u8]) -> Result<usize, Error> {
         debug_assert!(self.early_traffic);
         debug_assert!(self.record_layer.is_encrypting());
 
         if data.is_empty() {
             // Don't send empty fragments.
-            return 0;
+            return Ok(0);
         }
 
         self.send_appdata_encrypt(data, Limit::Yes)
@@ -224,12 +466,19 @@ impl CommonState {
             .message_fragmenter
             .fragment_message(&m);
         for m in iter {
-            self.send_single_fragment(m);
+            // Handshake messages and alerts never use up the write key:
+            // only application data is sent in bulk under one key.
+            if self.send_single_fragment(m).is_err() {
+                break;
+            }
         }
     }
 
     /// Like send_msg_encrypt, but operate on an appdata directly.
-    fn send_appdata_encrypt(&mut self, payload: &[u8], limit: Limit) -> usize {
+    ///
+    /// Fails if the record layer cannot encrypt; nothing from the failed
+    /// fragment onwards is counted as sent.
+    fn send_appdata_encrypt(&mut self, payload: &[u8], limit: Limit) -> Result<usize, Error> {
         // Here, the limit on sendable_tls applies to encrypted data,
         // but we're respecting it for plaintext data -- so we'll
         // be out by whatever the cipher+record overhead is.  That's a
@@ -246,31 +495,53 @@ impl CommonState {
             ProtocolVersion::TLSv1_2,
             &payload[..len],
         );
+        let mut sent = 0;
         for m in iter {
-            self.send_single_fragment(m);
+            let len = m.payload.len();
+            if let Err(err) = self.send_single_fragment(m) {
+                self.stats.application_bytes_sent += sent as u64;
+                return Err(err);
+            }
+            sent += len;
+
+            // Stop once the key must be replaced: the caller does that
+            // before sending the rest.
+            if self.refresh_traffic_keys_pending {
+                break;
+            }
         }
+        self.stats.application_bytes_sent += sent as u64;
 
-        len
+        Ok(sent)
     }
 
-    fn send_single_fragment(&mut self, m: BorrowedPlainMessage) {
+    fn send_single_fragment(&mut self, m: BorrowedPlainMessage) -> Result<(), Error> {
         // Close connection once we start to run out of
-        // sequence space.
-        if self
-            .record_layer
-            .wants_close_before_encrypt()
+        // sequence space.  TLS1.3 replaces the key instead: see below.
+        if !self.is_tls13()
+            && self
+                .record_layer
+                .wants_close_before_encrypt()
         {
             self.send_close_notify();
         }
 
-        // Refuse to wrap counter at all costs.  This
-        // is basically untestable unfortunately.
-        if self.record_layer.encrypt_exhausted() {
-            return;
+        // Refuse to wrap counter at all costs.
+        let em = self.record_layer.encrypt_outgoing(m)?;
+        self.queue_tls_message(em);
+
+        // Once a TLS1.3 key has protected as many records as it should,
+        // it must be replaced before the next one.  This is driven by
+        // `ConnectionCore`, as the key schedule lives in the handshake state.
+        if self.is_tls13()
+            && self
+                .record_layer
+                .wants_close_before_encrypt()
+        {
+            self.refresh_traffic_keys_pending = true;
         }
 
-        let em = self.record_layer.encrypt_outgoing(m);
-        self.queue_tls_message(em);
+        Ok(())
     }
 
     /// Encrypt and send some plaintext `data`.  `limit` controls
@@ -278,7 +549,7 @@ impl CommonState {
     ///
     /// Returns the number of bytes written from `data`: this might
     /// be less than `data.len()` if buffer limits were exceeded.
-    fn send_plain(&mut self, data: &[u8], limit: Limit) -> usize {
+    fn send_plain(&mut self, data: &[u8], limit: Limit) -> Result<usize, Error> {
         if !self.may_send_application_data {
             // If we haven't completed handshaking, buffer
             // plaintext to send once we do.
@@ -290,27 +561,112 @@ impl CommonState {
                     .sendable_plaintext
                     .append(data.to_vec()),
             };
-            return len;
+            return Ok(len);
         }
 
         debug_assert!(self.record_layer.is_encrypting());
 
         if data.is_empty() {
             // Don't send empty fragments.
-            return 0;
+            return Ok(0);
         }
 
         self.send_appdata_encrypt(data, limit)
     }
 
-    pub(crate) fn start_outgoing_traffic(&mut self) {
+    pub(crate) fn start_outgoing_traffic(&mut self) -> Result<(), Error> {
         self.may_send_application_data = true;
-        self.flush_plaintext();
+        self.flush_plaintext()
     }
 
-    pub(crate) fn start_traffic(&mut self) {
+    pub(crate) fn start_traffic(&mut self) -> Result<(), Error> {
         self.may_receive_application_data = true;
-        self.start_outgoing_traffic();
+        self.start_outgoing_traffic()?;
+
+        if let (Some(protocol_version), Some(cipher_suite)) = (self.negotiated_version, self.suite)
+        {
+            handshake_event!(
+                side = ?self.side,
+                version = ?protocol_version,
+                suite = ?cipher_suite,
+                resumed = self.resumed,
+                "handshake finished"
+            );
+
+            if let Some(observer) = &self.handshake_observer {
+                observer.on_handshake_complete(&HandshakeParams {
+                    side: self.side,
+                    protocol_version,
+                    cipher_suite,
+                    resumed: self.resumed,
+                });
+            }
+        }
+
+        // Close the handshake span: this is the end of the handshake.
+        #[cfg(feature = "tracing")]
+        {
+            self.handshake_span = tracing::Span::none();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn note_resumption(&mut self, kind: ResumptionKind) {
+        self.resumed = true;
+        if let Some(observer) = &self.handshake_observer {
+            observer.on_resumption(kind);
+        }
+    }
+
+    pub(crate) fn note_handshake_failed(&self, err: &Error) {
+        if let Some(observer) = &self.handshake_observer {
+            if self.is_handshaking() {
+                observer.on_handshake_failed(err);
+            }
+        }
+    }
+
+    /// Sets a time by which the handshake must complete.
+    ///
+    /// rustls does no I/O, and so cannot notice the deadline passing by
+    /// itself.  Instead, once it has passed, [`Connection::process_new_packets`]
+    /// fails with [`Error::HandshakeTimedOut`] if the handshake is still in
+    /// progress.  I/O wrappers should also use [`CommonState::handshake_deadline`]
+    /// to bound how long they wait for the peer.
+    ///
+    /// No alert is sent to the peer.  The deadline has no effect once the
+    /// handshake is complete.
+    ///
+    /// The current time is read from the clock set with
+    /// [`CommonState::set_handshake_clock`].
+    ///
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    pub fn set_handshake_deadline(&mut self, deadline: Instant) {
+        self.handshake_deadline = Some(deadline);
+    }
+
+    /// Sets the clock used to check the deadline set with
+    /// [`CommonState::set_handshake_deadline`].
+    ///
+    /// The default is [`crate::time::DefaultMonotonicClock`].
+    pub fn set_handshake_clock(&mut self, clock: Arc<dyn MonotonicClock>) {
+        self.handshake_clock = clock;
+    }
+
+    /// Returns the deadline set with [`CommonState::set_handshake_deadline`],
+    /// if any.
+    pub fn handshake_deadline(&self) -> Option<Instant> {
+        self.handshake_deadline
+    }
+
+    pub(crate) fn check_handshake_deadline(&self) -> Result<(), Error> {
+        match self.handshake_deadline {
+            Some(deadline) if self.is_handshaking() && self.handshake_clock.now() >= deadline => {
+                Err(Error::HandshakeTimedOut)
+            }
+            _ => Ok(()),
+        }
     }
 
     /// Sets a limit on the internal buffers used to buffer
@@ -361,25 +717,61 @@ impl CommonState {
         self.sendable_tls.set_limit(limit);
     }
 
-    /// Send any buffered plaintext.  Plaintext is buffered if
-    /// written during handshake.
-    fn flush_plaintext(&mut self) {
+    /// Send plaintext buffered before the handshake completed.
+    ///
+    /// This stops early if the write key needs replacing, leaving
+    /// the rest buffered.
+    pub(crate) fn flush_plaintext(&mut self) -> Result<(), Error> {
         if !self.may_send_application_data {
-            return;
+            return Ok(());
         }
 
-        while let Some(buf) = self.sendable_plaintext.pop() {
-            self.send_plain(&buf, Limit::No);
+        while let Some(mut buf) = self.sendable_plaintext.pop() {
+            let sent = self.send_plain(&buf, Limit::No)?;
+            if sent < buf.len() {
+                buf.drain(..sent);
+                self.sendable_plaintext.prepend(buf);
+                break;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Limit the memory kept by the received plaintext buffer, once the
+    /// application has read everything from it, to `retained` bytes.
+    pub(crate) fn set_retained_plaintext_capacity(&mut self, retained: usize) {
+        self.received_plaintext
+            .set_retained_capacity(Some(retained));
+    }
+
+    /// Encode outgoing records into buffers from `pool`, and return
+    /// them there once written.
+    pub(crate) fn set_buffer_pool(&mut self, pool: Option<Arc<BufferPool>>) {
+        self.sendable_tls.set_pool(pool.clone());
+        self.buffer_pool = pool;
     }
 
     // Put m into sendable_tls for writing.
     fn queue_tls_message(&mut self, m: OpaqueMessage) {
-        self.sendable_tls.append(m.encode());
+        let buf = match &self.buffer_pool {
+            Some(pool) => {
+                let mut buf = pool.take(m.encoded_len());
+                pool.put(m.encode_into(&mut buf));
+                buf
+            }
+            None => m.encode(),
+        };
+        self.stats.records_sent += 1;
+        self.sendable_tls.append(buf);
     }
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub(crate) fn send_msg(&mut self, m: Message, must_encrypt: bool) {
+        if self.is_handshaking() && matches!(m.payload, MessagePayload::Handshake { .. }) {
+            self.awaiting_peer_flight = true;
+        }
+
         #[cfg(feature = "quic")]
         {
             if let Protocol::Quic = self.protocol {
@@ -413,6 +805,7 @@ impl CommonState {
     }
 
     pub(crate) fn take_received_plaintext(&mut self, bytes: Payload) {
+        self.stats.application_bytes_received += bytes.0.len() as u64;
         self.received_plaintext.append(bytes.0);
     }
 
@@ -493,10 +886,22 @@ impl CommonState {
     /// [`Connection::write_tls`] call.  This informs the peer that the
     /// connection is being closed.
     ///
+    /// This only closes our sending direction: data from the peer can still
+    /// be received and read until it sends its own close_notify.  Writing
+    /// further plaintext after this is an error.
+    ///
     /// [`Connection::write_tls`]: crate::Connection::write_tls
     pub fn send_close_notify(&mut self) {
+        // Held-back writes precede the close_notify, whatever the buffer limit.
+        if !self.buffered_plaintext.is_empty() {
+            let buffered = mem::take(&mut self.buffered_plaintext);
+            // If this fails, the close_notify can't be sent either.
+            let _ = self.send_plain(&buffered, Limit::No);
+        }
+
         debug!("Sending warning alert {:?}", AlertDescription::CloseNotify);
         self.send_warning_alert_no_log(AlertDescription::CloseNotify);
+        self.has_sent_close_notify = true;
     }
 
     fn send_warning_alert_no_log(&mut self, desc: AlertDescription) {
@@ -536,11 +941,12 @@ impl CommonState {
             && (self.may_send_application_data || self.sendable_tls.is_empty())
     }
 
-    pub(crate) fn current_io_state(&self) -> IoState {
+    pub(crate) fn current_io_state(&self, needs_more: Option<MessageNeedsMore>) -> IoState {
         IoState {
             tls_bytes_to_write: self.sendable_tls.len(),
             plaintext_bytes_to_read: self.received_plaintext.len(),
             peer_has_closed: self.has_received_close_notify,
+            needs_more,
         }
     }
 
@@ -567,22 +973,73 @@ impl CommonState {
         }
     }
 
-    pub(crate) fn enqueue_key_update_notification(&mut self) {
+    pub(crate) fn enqueue_key_update_notification(&mut self) -> Result<(), Error> {
+        self.stats.key_updated = true;
         let message = PlainMessage::from(Message::build_key_update_notify());
         self.queued_key_update_message = Some(
             self.record_layer
-                .encrypt_outgoing(message.borrow())
+                .encrypt_outgoing(message.borrow())?
                 .encode(),
         );
+        Ok(())
     }
 
     pub(crate) fn perhaps_write_key_update(&mut self) {
         if let Some(message) = self.queued_key_update_message.take() {
+            self.stats.records_sent += 1;
             self.sendable_tls.append(message);
         }
     }
 }
 
+/// The lifecycle state of a connection, as returned by [`CommonState::state`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// The handshake is in progress.  Application data written now
+    /// is buffered until the handshake completes.
+    Handshaking,
+    /// The handshake is complete, and application data can flow in
+    /// both directions.
+    Established,
+    /// One peer has sent a close_notify alert, but the other has not.
+    ///
+    /// If we sent it, we can no longer write but can still read data
+    /// from the peer.  If the peer sent it, no more data will
+    /// arrive but we can still write.
+    Closing,
+    /// The connection has been closed: both peers have sent a
+    /// close_notify alert, or we have sent a fatal alert.
+    Closed,
+}
+
+/// Counters describing the traffic on a connection, as returned by
+/// [`CommonState::stats`].
+///
+/// Application data counts are of plaintext, and include early data.
+/// Record counts include every TLS record, whether it carried
+/// application data, handshake messages or alerts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// Application data bytes accepted for sending.
+    pub application_bytes_sent: u64,
+    /// Application data bytes received from the peer.
+    pub application_bytes_received: u64,
+    /// TLS records queued for sending.
+    pub records_sent: u64,
+    /// TLS records received from the peer.
+    pub records_received: u64,
+    /// How many times the handshake waited on a reply from the peer.
+    ///
+    /// A full TLS1.3 handshake is one round trip for the client;
+    /// a full TLS1.2 handshake is two.
+    pub handshake_round_trips: u32,
+    /// Whether the traffic keys have been replaced by a TLS1.3 KeyUpdate,
+    /// in either direction.
+    pub key_updated: bool,
+}
+
 /// Values of this structure are returned from [`Connection::process_new_packets`]
 /// and tell the caller the current I/O state of the TLS connection.
 ///
@@ -592,6 +1049,7 @@ pub struct IoState {
     tls_bytes_to_write: usize,
     plaintext_bytes_to_read: usize,
     peer_has_closed: bool,
+    needs_more: Option<MessageNeedsMore>,
 }
 
 impl IoState {
@@ -620,6 +1078,25 @@ impl IoState {
     pub fn peer_has_closed(&self) -> bool {
         self.peer_has_closed
     }
+
+    /// `Some` if the TLS data received so far ends part-way through
+    /// a message, which cannot be processed until more arrives.
+    ///
+    /// This is distinct from an error: truncated messages are never
+    /// treated as malformed.
+    pub fn needs_more(&self) -> Option<MessageNeedsMore> {
+        self.needs_more
+    }
+}
+
+/// Channel binding values, recorded during the handshake.
+///
+/// This is boxed in `CommonState` to keep connections small: most
+/// applications never use it.
+#[derive(Default)]
+struct ChannelBinding {
+    transcript_hash: Option<Vec<u8>>,
+    tls_unique: Option<Vec<u8>>,
 }
 
 pub(crate) trait State<Data>: Send + Sync {
@@ -642,6 +1119,22 @@ pub(crate) trait State<Data>: Send + Sync {
     fn extract_secrets(&self) -> Result<PartiallyExtractedSecrets, Error> {
         Err(Error::HandshakeNotComplete)
     }
+
+    #[cfg(feature = "secret_extraction")]
+    fn extract_tls12_master_secret(&self) -> Result<[u8; 48], Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    /// Send any session tickets held back until the application's
+    /// first write; see `ServerConfig::with_deferred_tickets`.
+    fn send_deferred_tickets(&mut self, _cx: &mut Context<'_, Data>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Replace our TLS1.3 write key, and tell the peer with a KeyUpdate message.
+    fn send_key_update_request(&mut self, _common: &mut CommonState) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
 }
 
 pub(crate) struct Context<'a, Data> {
@@ -681,3 +1174,61 @@ enum Limit {
 
 const DEFAULT_RECEIVED_PLAINTEXT_LIMIT: usize = 16 * 1024;
 const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// How much memory the received plaintext buffer keeps once read out.
+pub(crate) const DEFAULT_RETAINED_PLAINTEXT_CAPACITY: usize = 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::{CommonState, Side};
+    use crate::cipher::MessageEncrypter;
+    use crate::error::Error;
+    use crate::msgs::base::Payload;
+    use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage};
+
+    #[test]
+    fn idle_received_plaintext_capacity_is_bounded() {
+        let mut common = CommonState::new(Side::Server);
+        common.set_retained_plaintext_capacity(128);
+
+        // A burst of small records...
+        for _ in 0..256 {
+            common.take_received_plaintext(Payload::new(vec![0x5a; 64]));
+        }
+        assert!(common.received_plaintext.capacity() > 256 * 64);
+
+        // ... which the application then reads.
+        let mut buf = [0u8; 4096];
+        while common
+            .received_plaintext
+            .read(&mut buf)
+            .unwrap()
+            > 0
+        {}
+        assert!(common.received_plaintext.capacity() <= 128);
+    }
+
+    struct FailingEncrypter;
+
+    impl MessageEncrypter for FailingEncrypter {
+        fn encrypt(&self, _m: BorrowedPlainMessage, _seq: u64) -> Result<OpaqueMessage, Error> {
+            Err(Error::EncryptError)
+        }
+    }
+
+    #[test]
+    fn failed_encryption_is_not_counted_as_sent() {
+        let mut common = CommonState::new(Side::Client);
+        common
+            .record_layer
+            .set_message_encrypter(Box::new(FailingEncrypter), u64::MAX);
+        common.may_send_application_data = true;
+
+        assert_eq!(
+            common.send_some_plaintext(b"hello"),
+            Err(Error::EncryptError)
+        );
+        assert_eq!(common.stats.application_bytes_sent, 0);
+        assert!(!common.wants_write());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::common_state::CommonState;
+use crate::enums::AlertDescription;
+use crate::error::{Error, PeerMisbehaved};
+use crate::msgs::base::PayloadU24;
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::handshake::{CertificatePayloadTLS13, CompressedCertificatePayload};
+
+pub use crate::msgs::enums::CertificateCompressionAlgorithm;
+
+use std::fmt::Debug;
+#[cfg(any(feature = "brotli", feature = "zlib"))]
+use std::io::Write;
+use std::sync::Arc;
+
+/// A codec which compresses and decompresses TLS1.3 `Certificate`
+/// messages, as described in RFC8879.
+///
+/// Configure these with [`ClientConfig::cert_compressors`] and
+/// [`ServerConfig::cert_compressors`].  The `brotli` and `zlib` crate
+/// features provide [`BrotliCompressor`] and [`ZlibCompressor`]
+/// respectively.
+///
+/// [`ClientConfig::cert_compressors`]: crate::ClientConfig::cert_compressors
+/// [`ServerConfig::cert_compressors`]: crate::ServerConfig::cert_compressors
+pub trait CertificateCompressor: Debug + Send + Sync {
+    /// Which compression algorithm this implements.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+
+    /// Compress `input`, returning the compressed data.
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, CompressionFailed>;
+
+    /// Decompress `input`, writing the result to `output`.
+    ///
+    /// `output` is exactly as long as the peer claims the decompressed data
+    /// is.  This must fail if the decompressed data is any other length, and
+    /// must never produce more than `output.len()` bytes: that bounds the work
+    /// a peer can cause with a small input that decompresses to a huge one.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed>;
+}
+
+/// A [`CertificateCompressor`] failed to compress its input.
+#[derive(Debug)]
+pub struct CompressionFailed;
+
+/// A [`CertificateCompressor`] failed to decompress its input.
+#[derive(Debug)]
+pub struct DecompressionFailed;
+
+/// Returns the codecs enabled by crate features, most preferred first.
+///
+/// This is the default for [`ClientConfig::cert_compressors`] and
+/// [`ServerConfig::cert_compressors`].  It is empty unless the `brotli`
+/// or `zlib` crate features are enabled.
+///
+/// [`ClientConfig::cert_compressors`]: crate::ClientConfig::cert_compressors
+/// [`ServerConfig::cert_compressors`]: crate::ServerConfig::cert_compressors
+pub fn default_cert_compressors() -> Vec<Arc<dyn CertificateCompressor>> {
+    vec![
+        #[cfg(feature = "brotli")]
+        Arc::new(BrotliCompressor),
+        #[cfg(feature = "zlib")]
+        Arc::new(ZlibCompressor),
+    ]
+}
+
+/// The default for [`ClientConfig::max_decompressed_certificate_len`].
+///
+/// [`ClientConfig::max_decompressed_certificate_len`]: crate::ClientConfig::max_decompressed_certificate_len
+pub(crate) const DEFAULT_MAX_DECOMPRESSED_CERTIFICATE_LEN: usize = 64 * 1024;
+
+/// Compress `cert` using `compressor`, or return `None` if that fails and
+/// it should be sent uncompressed instead.
+pub(crate) fn compress_certificate(
+    compressor: &dyn CertificateCompressor,
+    cert: &CertificatePayloadTLS13,
+) -> Option<CompressedCertificatePayload> {
+    let uncompressed = cert.get_encoding();
+    let compressed = compressor
+        .compress(&uncompressed)
+        .ok()?;
+    Some(CompressedCertificatePayload {
+        alg: compressor.algorithm(),
+        uncompressed_len: uncompressed.len() as u32,
+        compressed: PayloadU24::new(compressed),
+    })
+}
+
+/// Decompress a `CompressedCertificate` message from our peer, using one of
+/// the `offered` codecs.  The result may be no longer than `max_len`.
+pub(crate) fn decompress_certificate(
+    offered: &[Arc<dyn CertificateCompressor>],
+    max_len: usize,
+    compressed: &CompressedCertificatePayload,
+    common: &mut CommonState,
+) -> Result<CertificatePayloadTLS13, Error> {
+    let compressor = match offered
+        .iter()
+        .find(|c| c.algorithm() == compressed.alg)
+    {
+        Some(compressor) => compressor,
+        None => {
+            return Err(common.send_fatal_alert(
+                AlertDescription::IllegalParameter,
+                PeerMisbehaved::SelectedUnofferedCertCompression,
+            ));
+        }
+    };
+
+    // Check the claimed length before doing any work.  The codec may not
+    // produce more than this, so it also bounds the decompressed size.
+    let len = compressed.uncompressed_len as usize;
+    if len == 0 {
+        return Err(common.send_fatal_alert(
+            AlertDescription::BadCertificate,
+            PeerMisbehaved::InvalidCertCompression,
+        ));
+    }
+    if len > max_len {
+        return Err(common.send_fatal_alert(
+            AlertDescription::BadCertificate,
+            PeerMisbehaved::DecompressedCertificateTooLarge,
+        ));
+    }
+
+    let mut decompressed = vec![0u8; len];
+    if compressor
+        .decompress(&compressed.compressed.0, &mut decompressed)
+        .is_err()
+    {
+        return Err(common.send_fatal_alert(
+            AlertDescription::BadCertificate,
+            PeerMisbehaved::InvalidCertCompression,
+        ));
+    }
+
+    let mut rd = Reader::init(&decompressed);
+    CertificatePayloadTLS13::read(&mut rd)
+        .and_then(|cert| {
+            rd.expect_empty("CertificatePayloadTLS13")
+                .map(|_| cert)
+        })
+        .map_err(|err| common.send_fatal_alert(AlertDescription::DecodeError, err))
+}
+
+/// Brotli certificate compression (RFC7932), using the `brotli` crate.
+#[cfg(feature = "brotli")]
+#[cfg_attr(docsrs, doc(cfg(feature = "brotli")))]
+#[derive(Debug)]
+pub struct BrotliCompressor;
+
+#[cfg(feature = "brotli")]
+impl CertificateCompressor for BrotliCompressor {
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Brotli
+    }
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, CompressionFailed> {
+        let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 9, 22);
+        writer
+            .write_all(input)
+            .map_err(|_| CompressionFailed)?;
+        Ok(writer.into_inner())
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+        use std::io::Read;
+
+        let mut reader = brotli::Decompressor::new(input, 4096);
+        reader
+            .read_exact(output)
+            .map_err(|_| DecompressionFailed)?;
+        match reader.read(&mut [0u8]) {
+            Ok(0) => Ok(()),
+            _ => Err(DecompressionFailed),
+        }
+    }
+}
+
+/// Zlib certificate compression (RFC1950), using the `flate2` crate.
+#[cfg(feature = "zlib")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zlib")))]
+#[derive(Debug)]
+pub struct ZlibCompressor;
+
+#[cfg(feature = "zlib")]
+impl CertificateCompressor for ZlibCompressor {
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Zlib
+    }
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, CompressionFailed> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder
+            .write_all(input)
+            .map_err(|_| CompressionFailed)?;
+        encoder
+            .finish()
+            .map_err(|_| CompressionFailed)
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+        let mut decompress = flate2::Decompress::new(true);
+        match decompress.decompress(input, output, flate2::FlushDecompress::Finish) {
+            Ok(flate2::Status::StreamEnd)
+                if decompress.total_in() == input.len() as u64
+                    && decompress.total_out() == output.len() as u64 =>
+            {
+                Ok(())
+            }
+            _ => Err(DecompressionFailed),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "brotli", feature = "zlib")))]
+mod test {
+    use super::*;
+    use crate::common_state::Side;
+
+    fn check_codec(codec: &dyn CertificateCompressor) {
+        let input = b"hello world hello world hello world hello world".repeat(10);
+        let compressed = codec.compress(&input).unwrap();
+        assert!(compressed.len() < input.len());
+
+        let mut output = vec![0u8; input.len()];
+        codec
+            .decompress(&compressed, &mut output)
+            .unwrap();
+        assert_eq!(output, input);
+
+        // wrong claimed lengths are rejected
+        let mut short = vec![0u8; input.len() - 1];
+        assert!(codec
+            .decompress(&compressed, &mut short)
+            .is_err());
+        let mut long = vec![0u8; input.len() + 1];
+        assert!(codec
+            .decompress(&compressed, &mut long)
+            .is_err());
+    }
+
+    fn check_bomb(codec: &dyn CertificateCompressor) {
+        // a megabyte of zeroes compresses to almost nothing...
+        let compressed = codec
+            .compress(&vec![0u8; 1 << 20])
+            .unwrap();
+        assert!(compressed.len() < 2048);
+
+        // ...but only fills the space claimed for it
+        let mut output = vec![0u8; 1024];
+        assert!(codec
+            .decompress(&compressed, &mut output)
+            .is_err());
+    }
+
+    fn check_decompression_limit(codec: Arc<dyn CertificateCompressor>) {
+        let bomb = CompressedCertificatePayload {
+            alg: codec.algorithm(),
+            uncompressed_len: 0,
+            compressed: PayloadU24::new(
+                codec
+                    .compress(&vec![0u8; 1 << 20])
+                    .unwrap(),
+            ),
+        };
+        let offered = [codec];
+
+        // honestly claiming a huge length is rejected before decompression
+        let mut common = CommonState::new(Side::Client);
+        let honest = CompressedCertificatePayload {
+            uncompressed_len: 1 << 20,
+            ..bomb.clone()
+        };
+        assert_eq!(
+            decompress_certificate(&offered, 1024, &honest, &mut common).unwrap_err(),
+            Error::PeerMisbehaved(PeerMisbehaved::DecompressedCertificateTooLarge)
+        );
+
+        // lying about the length only inflates as far as the claimed length
+        let mut common = CommonState::new(Side::Client);
+        let liar = CompressedCertificatePayload {
+            uncompressed_len: 1024,
+            ..bomb
+        };
+        assert_eq!(
+            decompress_certificate(&offered, 1024, &liar, &mut common).unwrap_err(),
+            Error::PeerMisbehaved(PeerMisbehaved::InvalidCertCompression)
+        );
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli() {
+        check_codec(&BrotliCompressor);
+        check_bomb(&BrotliCompressor);
+        check_decompression_limit(Arc::new(BrotliCompressor));
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn zlib() {
+        check_codec(&ZlibCompressor);
+        check_bomb(&ZlibCompressor);
+        check_decompression_limit(Arc::new(ZlibCompressor));
+    }
+}
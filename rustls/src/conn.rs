@@ -1,6 +1,8 @@
 use crate::common_state::{CommonState, Context, IoState, State};
+#[cfg(feature = "secret_extraction")]
+use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType};
-use crate::error::{Error, PeerMisbehaved};
+use crate::error::{Error, InvalidMessage, PeerMisbehaved};
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::deframer::{Deframed, MessageDeframer};
@@ -50,6 +52,16 @@ impl Connection {
         }
     }
 
+    /// Takes the next record of received plaintext, without copying it.
+    ///
+    /// See [`ConnectionCommon::next_plaintext_record()`] for more information.
+    pub fn next_plaintext_record(&mut self) -> Option<&[u8]> {
+        match self {
+            Self::Client(conn) => conn.next_plaintext_record(),
+            Self::Server(conn) => conn.next_plaintext_record(),
+        }
+    }
+
     /// Returns an object that allows writing plaintext.
     pub fn writer(&mut self) -> Writer {
         match self {
@@ -93,6 +105,17 @@ impl Connection {
         }
     }
 
+    /// Extract the TLS1.2 master secret, for decrypting captured traffic
+    /// while debugging.
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_tls12_master_secret(self) -> Result<[u8; 48], Error> {
+        match self {
+            Self::Client(conn) => conn.dangerous_extract_tls12_master_secret(),
+            Self::Server(conn) => conn.dangerous_extract_tls12_master_secret(),
+        }
+    }
+
     /// This function uses `io` to complete any outstanding IO for this connection.
     ///
     /// See [`ConnectionCommon::complete_io()`] for more information.
@@ -143,8 +166,9 @@ impl<'a> io::Read for Reader<'a> {
     /// connection, so the underlying TCP connection should be half-closed too.
     ///
     /// If the peer closes the TLS session uncleanly (a TCP EOF without sending a
-    /// `close_notify` alert) this function returns `Err(ErrorKind::UnexpectedEof.into())`
-    /// once any pending data has been read.
+    /// `close_notify` alert) this function returns an error of kind
+    /// `ErrorKind::UnexpectedEof` once any pending data has been read.  This
+    /// might indicate a truncation attack, so is never reported as `Ok(0)`.
     ///
     /// Note that support for `close_notify` varies in peer TLS libraries: many do not
     /// support it and uncleanly close the TCP connection (this might be
@@ -165,7 +189,7 @@ impl<'a> io::Read for Reader<'a> {
                 // cleanly closed; don't care about TCP EOF: express this as Ok(0)
                 (true, _) => {}
                 // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                (false, true) => return Err(unexpected_eof()),
                 // connection still going, but need more data: signal `WouldBlock` so that
                 // the caller knows this
                 (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
@@ -183,8 +207,9 @@ impl<'a> io::Read for Reader<'a> {
     /// should be half-closed too.
     ///
     /// If the peer closes the TLS session uncleanly (a TCP EOF without sending a
-    /// `close_notify` alert) this function returns `Err(ErrorKind::UnexpectedEof.into())`
-    /// once any pending data has been read.
+    /// `close_notify` alert) this function returns an error of kind
+    /// `ErrorKind::UnexpectedEof` once any pending data has been read.  This
+    /// might indicate a truncation attack, so is never reported as `Ok(0)`.
     ///
     /// Note that support for `close_notify` varies in peer TLS libraries: many do not
     /// support it and uncleanly close the TCP connection (this might be
@@ -209,7 +234,7 @@ impl<'a> io::Read for Reader<'a> {
                 // cleanly closed; don't care about TCP EOF: express this as Ok(0)
                 (true, _) => {}
                 // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                (false, true) => return Err(unexpected_eof()),
                 // connection still going, but need more data: signal `WouldBlock` so that
                 // the caller knows this
                 (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
@@ -220,28 +245,88 @@ impl<'a> io::Read for Reader<'a> {
     }
 }
 
+/// The error returned by [`Reader`] when the transport reached EOF without
+/// the peer sending a `close_notify` alert.
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "peer closed connection without sending TLS close_notify",
+    )
+}
+
+/// The error returned by [`Writer`] when plaintext cannot be sent.
+fn write_error(err: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
 /// Internal trait implemented by the [`ServerConnection`]/[`ClientConnection`]
 /// allowing them to be the subject of a [`Writer`].
 pub(crate) trait PlaintextSink {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
     fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize>;
+    fn write_coalesced(&mut self, bufs: &[&[u8]]) -> io::Result<bool>;
     fn flush(&mut self) -> io::Result<()>;
 }
 
 impl<T> PlaintextSink for ConnectionCommon<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        Ok(self.send_some_plaintext(buf))
+        self.check_not_write_closed()?;
+        self.send_deferred_tickets_before_write()?;
+        self.refresh_traffic_keys_before_write()?;
+        self.buffer_some_plaintext(buf)
+            .map_err(write_error)
     }
 
     fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.check_not_write_closed()?;
+        self.send_deferred_tickets_before_write()?;
+        self.refresh_traffic_keys_before_write()?;
         let mut sz = 0;
         for buf in bufs {
-            sz += self.send_some_plaintext(buf);
+            let written = self
+                .buffer_some_plaintext(buf)
+                .map_err(write_error)?;
+            sz += written;
+            // What we accept must be a prefix of `bufs`.
+            if written < buf.len() {
+                break;
+            }
         }
         Ok(sz)
     }
 
+    fn write_coalesced(&mut self, bufs: &[&[u8]]) -> io::Result<bool> {
+        self.check_not_write_closed()?;
+        self.send_deferred_tickets_before_write()?;
+        self.refresh_traffic_keys_before_write()?;
+        self.flush_buffered_plaintext()
+            .map_err(write_error)?;
+
+        let data = bufs.concat();
+        if !self.has_room_for_plaintext(data.len()) {
+            return Ok(false);
+        }
+
+        let mut sent = self
+            .send_checked_plaintext(&data)
+            .map_err(write_error)?;
+        while sent < data.len() {
+            // We stopped to replace the write key.
+            self.refresh_traffic_keys_before_write()?;
+            sent += self
+                .send_checked_plaintext(&data[sent..])
+                .map_err(write_error)?;
+        }
+        Ok(true)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
+        if !self.buffered_plaintext.is_empty() {
+            self.send_deferred_tickets_before_write()?;
+            self.refresh_traffic_keys_before_write()?;
+            self.flush_buffered_plaintext()
+                .map_err(write_error)?;
+        }
         Ok(())
     }
 }
@@ -259,6 +344,29 @@ impl<'a> Writer<'a> {
     pub(crate) fn new(sink: &'a mut dyn PlaintextSink) -> Writer<'a> {
         Writer { sink }
     }
+
+    /// Send the concatenation of `bufs` to the peer, in as few TLS
+    /// records as possible.
+    ///
+    /// Unlike [`io::Write::write_vectored`], which sends each buffer in
+    /// its own record(s), this fills each record up to the maximum
+    /// fragment size before starting the next one.  For protocols
+    /// which send many small messages, that saves the per-record
+    /// overhead.
+    ///
+    /// This fails with [`io::ErrorKind::WriteZero`] if the buffer limit
+    /// (see [`CommonState::set_buffer_limit`]) does not leave room for
+    /// all the data.  In that case none of it is accepted: send the
+    /// buffered records with [`Connection::write_tls`] and try again.
+    pub fn write_all_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<()> {
+        match self.sink.write_coalesced(bufs)? {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "buffer limit leaves no room for the whole write",
+            )),
+        }
+    }
 }
 
 impl<'a> io::Write for Writer<'a> {
@@ -279,6 +387,10 @@ impl<'a> io::Write for Writer<'a> {
         self.sink.write_vectored(bufs)
     }
 
+    /// Send any plaintext held back by write buffering (see
+    /// `ClientConfig::with_write_buffering` and
+    /// `ServerConfig::with_write_buffering`).  This does not write
+    /// anything to the network: call [`Connection::write_tls`] for that.
     fn flush(&mut self) -> io::Result<()> {
         self.sink.flush()
     }
@@ -337,6 +449,25 @@ impl<Data> ConnectionCommon<Data> {
         Writer::new(self)
     }
 
+    /// Takes the next record of received plaintext, without copying it.
+    ///
+    /// This returns the payload of one decrypted application data record
+    /// (or what remains of it, if some was read with [`reader()`]).  It is
+    /// borrowed from the connection until the next call.  Records of other
+    /// types are processed by rustls itself.
+    ///
+    /// This returns `None` if no plaintext is waiting.  It does not report
+    /// whether the peer has closed the connection: use
+    /// [`IoState::peer_has_closed`] or [`reader()`] for that.
+    ///
+    /// [`reader()`]: ConnectionCommon::reader
+    /// [`IoState::peer_has_closed`]: crate::IoState::peer_has_closed
+    pub fn next_plaintext_record(&mut self) -> Option<&[u8]> {
+        let common = &mut self.core.common_state;
+        common.taken_plaintext_record = common.received_plaintext.pop()?;
+        Some(&common.taken_plaintext_record)
+    }
+
     /// This function uses `io` to complete any outstanding IO for
     /// this connection.
     ///
@@ -563,6 +694,59 @@ impl<Data> ConnectionCommon<Data> {
             rx: (record_layer.read_seq(), rx),
         })
     }
+
+    /// Extract the 48-byte master secret of a TLS1.2 connection.
+    ///
+    /// Together with the client and server randoms this is enough to
+    /// decrypt the connection's traffic, so it is only available when
+    /// secret extraction is enabled.  This fails for TLS1.3 connections,
+    /// and before the handshake completes.
+    ///
+    /// Like [`ConnectionCommon::extract_secrets`], this consumes the
+    /// connection.
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_tls12_master_secret(self) -> Result<[u8; 48], Error> {
+        if !self.enable_secret_extraction {
+            return Err(Error::General("Secret extraction is disabled".into()));
+        }
+
+        if self.negotiated_version != Some(ProtocolVersion::TLSv1_2) {
+            return Err(Error::General(
+                "Master secret is only available for TLS1.2".into(),
+            ));
+        }
+
+        self.core
+            .state?
+            .extract_tls12_master_secret()
+    }
+
+    fn check_not_write_closed(&self) -> io::Result<()> {
+        match self.has_sent_close_notify {
+            true => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "cannot write after sending TLS close_notify",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn send_deferred_tickets_before_write(&mut self) -> io::Result<()> {
+        // A connection that has already failed reports that elsewhere.
+        if self.core.state.is_err() {
+            return Ok(());
+        }
+        self.core
+            .send_deferred_tickets()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    fn refresh_traffic_keys_before_write(&mut self) -> io::Result<()> {
+        self.core
+            .maybe_refresh_traffic_keys()
+            .map_err(write_error)
+    }
 }
 
 impl<'a, Data> From<&'a mut ConnectionCommon<Data>> for Context<'a, Data> {
@@ -611,7 +795,50 @@ impl<Data> ConnectionCore<Data> {
         }
     }
 
+    pub(crate) fn send_deferred_tickets(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            Ok(state) => state.send_deferred_tickets(&mut Context {
+                common: &mut self.common_state,
+                data: &mut self.data,
+            }),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Replace our TLS1.3 write key if the record layer asked for it, having
+    /// used up most of the sequence space under the current one.
+    ///
+    /// This then sends any plaintext that was held back waiting for the
+    /// new key, which may itself use up the new key.
+    pub(crate) fn maybe_refresh_traffic_keys(&mut self) -> Result<(), Error> {
+        // Handshake keys are replaced soon enough anyway.
+        if self.common_state.is_handshaking() {
+            return Ok(());
+        }
+
+        while mem::take(
+            &mut self
+                .common_state
+                .refresh_traffic_keys_pending,
+        ) {
+            match &mut self.state {
+                Ok(state) => state.send_key_update_request(&mut self.common_state)?,
+                Err(e) => return Err(e.clone()),
+            }
+            self.common_state.flush_plaintext()?;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn process_new_packets(&mut self) -> Result<IoState, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = self
+            .common_state
+            .handshake_span
+            .clone()
+            .entered();
+
         let mut state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
             Ok(state) => state,
             Err(e) => {
@@ -620,10 +847,32 @@ impl<Data> ConnectionCore<Data> {
             }
         };
 
-        while let Some(msg) = self.deframe()? {
+        if let Err(e) = self
+            .common_state
+            .check_handshake_deadline()
+        {
+            self.common_state
+                .note_handshake_failed(&e);
+            self.state = Err(e.clone());
+            return Err(e);
+        }
+
+        loop {
+            let msg = match self.deframe() {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(e) => {
+                    self.common_state
+                        .note_handshake_failed(&e);
+                    return Err(e);
+                }
+            };
+
             match self.process_msg(msg, state) {
                 Ok(new) => state = new,
                 Err(e) => {
+                    self.common_state
+                        .note_handshake_failed(&e);
                     self.state = Err(e.clone());
                     return Err(e);
                 }
@@ -631,15 +880,20 @@ impl<Data> ConnectionCore<Data> {
         }
 
         self.state = Ok(state);
-        Ok(self.common_state.current_io_state())
+        self.maybe_refresh_traffic_keys()?;
+        Ok(self
+            .common_state
+            .current_io_state(self.message_deframer.needs_more()))
     }
 
     /// Pull a message out of the deframer and send any messages that need to be sent as a result.
     fn deframe(&mut self) -> Result<Option<PlainMessage>, Error> {
-        match self
+        let popped = self
             .message_deframer
-            .pop(&mut self.common_state.record_layer)
-        {
+            .pop(&mut self.common_state.record_layer);
+        self.common_state.stats.records_received = self.message_deframer.records_read();
+
+        match popped {
             Ok(Some(Deframed {
                 want_close_before_decrypt,
                 aligned,
@@ -660,6 +914,11 @@ impl<Data> ConnectionCore<Data> {
                 Ok(Some(message))
             }
             Ok(None) => Ok(None),
+            // The record's length prefix was too large; we rejected it without
+            // buffering the record.
+            Err(err @ Error::InvalidMessage(InvalidMessage::MessageTooLarge)) => Err(self
+                .common_state
+                .send_fatal_alert(AlertDescription::RecordOverflow, err)),
             Err(err @ Error::InvalidMessage(_)) => {
                 #[cfg(feature = "quic")]
                 if self.common_state.is_quic() {
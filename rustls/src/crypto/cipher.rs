@@ -0,0 +1,165 @@
+use crate::crypto::hkdf::Hkdf;
+use crate::msgs::codec;
+
+/// Size of TLS nonces (incorrectly termed "IV" in the standards) for all
+/// supported cipher suites (AES-GCM, ChaCha20Poly1305).
+pub const NONCE_LEN: usize = 12;
+
+/// A write or read IV.
+#[derive(Default)]
+pub struct Iv(pub(crate) [u8; NONCE_LEN]);
+
+impl Iv {
+    /// Build an `Iv` from its value.
+    pub fn new(value: [u8; NONCE_LEN]) -> Self {
+        Self(value)
+    }
+
+    /// Build an `Iv` by copying a slice of exactly [`NONCE_LEN`] bytes.
+    pub fn copy(value: &[u8]) -> Self {
+        debug_assert_eq!(value.len(), NONCE_LEN);
+        let mut iv = Self::new(Default::default());
+        iv.0.copy_from_slice(value);
+        iv
+    }
+}
+
+impl From<[u8; NONCE_LEN]> for Iv {
+    fn from(value: [u8; NONCE_LEN]) -> Self {
+        Self::new(value)
+    }
+}
+
+impl AsRef<[u8]> for Iv {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A per-record nonce.
+pub struct Nonce(pub [u8; NONCE_LEN]);
+
+impl Nonce {
+    /// Combine an `Iv` and a sequence number to produce the nonce for
+    /// that record.
+    ///
+    /// This is the construction in RFC8446 section 5.3: the sequence number
+    /// is encoded big-endian, left-padded with zeroes to [`NONCE_LEN`] bytes,
+    /// and XORed with the IV.
+    pub fn new(iv: &Iv, seq: u64) -> Self {
+        let mut nonce = Self([0u8; NONCE_LEN]);
+        codec::put_u64(seq, &mut nonce.0[4..]);
+
+        nonce
+            .0
+            .iter_mut()
+            .zip(iv.0.iter())
+            .for_each(|(nonce, iv)| {
+                *nonce ^= *iv;
+            });
+
+        nonce
+    }
+}
+
+/// An AEAD key, stored as a value.
+pub struct AeadKey {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl AeadKey {
+    /// Maximum supported AEAD key size: supports up to 256-bit keys.
+    pub const MAX_LEN: usize = 32;
+}
+
+impl AsRef<[u8]> for AeadKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+/// Derive the `key_len`-byte traffic key for `traffic_secret`, as
+/// described in RFC8446 section 7.3.
+pub fn derive_traffic_key(hkdf: &dyn Hkdf, traffic_secret: &[u8], key_len: usize) -> AeadKey {
+    expand_key(hkdf, traffic_secret, b"key", key_len)
+}
+
+/// Derive the traffic IV for `traffic_secret`, as described in
+/// RFC8446 section 7.3.
+pub fn derive_traffic_iv(hkdf: &dyn Hkdf, traffic_secret: &[u8]) -> Iv {
+    expand_iv(hkdf, traffic_secret, b"iv")
+}
+
+/// `HKDF-Expand-Label` where the output is an AEAD key of `key_len` bytes.
+pub(crate) fn expand_key(hkdf: &dyn Hkdf, secret: &[u8], label: &[u8], key_len: usize) -> AeadKey {
+    debug_assert!(key_len <= AeadKey::MAX_LEN);
+    let mut key = AeadKey {
+        buf: [0u8; AeadKey::MAX_LEN],
+        used: key_len,
+    };
+    hkdf.expand_label(secret, label, &[], &mut key.buf[..key_len])
+        .unwrap();
+    key
+}
+
+/// `HKDF-Expand-Label` where the output is an IV.
+pub(crate) fn expand_iv(hkdf: &dyn Hkdf, secret: &[u8], label: &[u8]) -> Iv {
+    let mut iv = Iv::default();
+    hkdf.expand_label(secret, label, &[], &mut iv.0)
+        .unwrap();
+    iv
+}
+
+#[cfg(test)]
+mod test {
+    use super::{derive_traffic_iv, derive_traffic_key, Iv, Nonce};
+    use crate::crypto::ring::hkdf::HKDF_SHA256;
+
+    #[test]
+    fn nonce_is_iv_xor_padded_sequence_number() {
+        // server_handshake_traffic_secret IV, from RFC8448 section 3.
+        let iv = Iv::new([
+            0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x30,
+        ]);
+
+        assert_eq!(Nonce::new(&iv, 0).0, iv.0);
+        assert_eq!(
+            Nonce::new(&iv, 1).0,
+            [0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x31]
+        );
+        assert_eq!(
+            Nonce::new(&iv, 0x0102_0304_0506_0708).0,
+            [0x5d, 0x31, 0x3e, 0xb2, 0x66, 0x10, 0x75, 0xea, 0x16, 0x06, 0x0c, 0x38]
+        );
+        assert_eq!(
+            Nonce::new(&iv, u64::MAX).0,
+            [0x5d, 0x31, 0x3e, 0xb2, 0x98, 0xed, 0x89, 0x11, 0xec, 0xff, 0xf4, 0xcf]
+        );
+    }
+
+    #[test]
+    fn traffic_key_and_iv() {
+        // server_handshake_traffic_secret, from RFC8448 section 3.
+        let secret = [
+            0xb6, 0x7b, 0x7d, 0x69, 0x0c, 0xc1, 0x6c, 0x4e, 0x75, 0xe5, 0x42, 0x13, 0xcb, 0x2d,
+            0x37, 0xb4, 0xe9, 0xc9, 0x12, 0xbc, 0xde, 0xd9, 0x10, 0x5d, 0x42, 0xbe, 0xfd, 0x59,
+            0xd3, 0x91, 0xad, 0x38,
+        ];
+
+        let key = derive_traffic_key(&HKDF_SHA256, &secret, 16);
+        assert_eq!(
+            key.as_ref(),
+            &[
+                0x3f, 0xce, 0x51, 0x60, 0x09, 0xc2, 0x17, 0x27, 0xd0, 0xf2, 0xe4, 0xe8, 0x6e, 0xe4,
+                0x03, 0xbc,
+            ]
+        );
+
+        let iv = derive_traffic_iv(&HKDF_SHA256, &secret);
+        assert_eq!(
+            iv.as_ref(),
+            &[0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x30]
+        );
+    }
+}
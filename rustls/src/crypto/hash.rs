@@ -0,0 +1,186 @@
+use alloc::boxed::Box;
+
+pub use crate::msgs::enums::HashAlgorithm;
+
+/// Describes a single cryptographic hash function.
+///
+/// This interface can do both one-shot and incremental hashing, using
+/// [`Hash::hash()`] and [`Hash::start()`] respectively.
+pub trait Hash: Send + Sync {
+    /// Start an incremental hash computation.
+    fn start(&self) -> Box<dyn Context>;
+
+    /// Return the output of this hash function with input `data`.
+    fn hash(&self, data: &[u8]) -> Output;
+
+    /// Return the output of this hash function with input the
+    /// concatenation of all the slices in `data`.
+    ///
+    /// This is equivalent to, but can be cheaper than, feeding each slice
+    /// to a context from [`Hash::start()`].
+    fn compute_many(&self, data: &[&[u8]]) -> Output {
+        let mut ctx = self.start();
+        for d in data {
+            ctx.update(d);
+        }
+        ctx.finish()
+    }
+
+    /// Return the output of this hash function with empty input.
+    ///
+    /// TLS1.3 needs this at several points in every handshake. The default
+    /// implementation uses [`precomputed_empty()`] where it can, and only
+    /// falls back to hashing an empty slice for other algorithms.
+    /// Providers of such algorithms should override this to return a
+    /// stored constant.
+    fn compute_empty(&self) -> Output {
+        precomputed_empty(self.algorithm()).unwrap_or_else(|| self.hash(&[]))
+    }
+
+    /// The length in bytes of this hash function's output.
+    fn output_len(&self) -> usize;
+
+    /// Which hash function this is, eg, `HashAlgorithm::SHA256`.
+    fn algorithm(&self) -> HashAlgorithm;
+}
+
+/// Return the hash of the empty string for `algorithm`, if it is
+/// one of SHA256, SHA384 or SHA512.
+///
+/// This does not compute anything: the outputs are stored constants.
+pub fn precomputed_empty(algorithm: HashAlgorithm) -> Option<Output> {
+    let bytes: &[u8] = match algorithm {
+        HashAlgorithm::SHA256 => &SHA256_EMPTY,
+        HashAlgorithm::SHA384 => &SHA384_EMPTY,
+        HashAlgorithm::SHA512 => &SHA512_EMPTY,
+        _ => return None,
+    };
+    Some(Output::new(bytes))
+}
+
+static SHA256_EMPTY: [u8; 32] = [
+    0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+    0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+];
+
+static SHA384_EMPTY: [u8; 48] = [
+    0x38, 0xb0, 0x60, 0xa7, 0x51, 0xac, 0x96, 0x38, 0x4c, 0xd9, 0x32, 0x7e, 0xb1, 0xb1, 0xe3, 0x6a,
+    0x21, 0xfd, 0xb7, 0x11, 0x14, 0xbe, 0x07, 0x43, 0x4c, 0x0c, 0xc7, 0xbf, 0x63, 0xf6, 0xe1, 0xda,
+    0x27, 0x4e, 0xde, 0xbf, 0xe7, 0x6f, 0x65, 0xfb, 0xd5, 0x1a, 0xd2, 0xf1, 0x48, 0x98, 0xb9, 0x5b,
+];
+
+static SHA512_EMPTY: [u8; 64] = [
+    0xcf, 0x83, 0xe1, 0x35, 0x7e, 0xef, 0xb8, 0xbd, 0xf1, 0x54, 0x28, 0x50, 0xd6, 0x6d, 0x80, 0x07,
+    0xd6, 0x20, 0xe4, 0x05, 0x0b, 0x57, 0x15, 0xdc, 0x83, 0xf4, 0xa9, 0x21, 0xd3, 0x6c, 0xe9, 0xce,
+    0x47, 0xd0, 0xd1, 0x3c, 0x5d, 0x85, 0xf2, 0xb0, 0xff, 0x83, 0x18, 0xd2, 0x87, 0x7e, 0xec, 0x2f,
+    0x63, 0xb9, 0x31, 0xbd, 0x47, 0x41, 0x7a, 0x81, 0xa5, 0x38, 0x32, 0x7a, 0xf9, 0x27, 0xda, 0x3e,
+];
+
+/// A hash output, stored as a value.
+pub struct Output {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Output {
+    /// Build a `hash::Output` from a slice of no more than `Output::MAX_LEN` bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut output = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        output.buf[..bytes.len()].copy_from_slice(bytes);
+        output
+    }
+
+    /// Maximum supported hash output size: supports up to SHA512.
+    pub const MAX_LEN: usize = 64;
+}
+
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+/// How to incrementally compute a hash.
+pub trait Context: Send + Sync {
+    /// Finish the computation, returning the resulting output.
+    ///
+    /// The computation remains valid, and more data can be added later with
+    /// [`Context::update()`].
+    ///
+    /// Compare with [`Context::finish()`] which consumes the computation
+    /// and prevents any further data being added.  This can be more efficient
+    /// because it avoids a hash context copy to apply Merkle-Damgård padding
+    /// (if required).
+    fn fork_finish(&self) -> Output;
+
+    /// Fork the computation, producing another context that has the
+    /// same prefix as this one.
+    fn fork(&self) -> Box<dyn Context>;
+
+    /// Terminate and finish the computation, returning the resulting output.
+    ///
+    /// Further data cannot be added after this, because the context is consumed.
+    fn finish(self: Box<Self>) -> Output;
+
+    /// Add `data` to computation.
+    fn update(&mut self, data: &[u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Hash` which cannot compute anything.
+    struct NoCompute(HashAlgorithm);
+
+    impl Hash for NoCompute {
+        fn start(&self) -> Box<dyn Context> {
+            panic!("started a hash context");
+        }
+
+        fn hash(&self, _data: &[u8]) -> Output {
+            panic!("computed a hash");
+        }
+
+        fn output_len(&self) -> usize {
+            unreachable!()
+        }
+
+        fn algorithm(&self) -> HashAlgorithm {
+            self.0
+        }
+    }
+
+    #[test]
+    fn compute_empty_uses_stored_constant() {
+        for alg in [
+            HashAlgorithm::SHA256,
+            HashAlgorithm::SHA384,
+            HashAlgorithm::SHA512,
+        ] {
+            assert_eq!(
+                NoCompute(alg).compute_empty().as_ref(),
+                precomputed_empty(alg).unwrap().as_ref()
+            );
+        }
+    }
+
+    #[test]
+    fn precomputed_empty_is_correct() {
+        for (alg, digest_alg) in [
+            (HashAlgorithm::SHA256, &ring::digest::SHA256),
+            (HashAlgorithm::SHA384, &ring::digest::SHA384),
+            (HashAlgorithm::SHA512, &ring::digest::SHA512),
+        ] {
+            assert_eq!(
+                precomputed_empty(alg).unwrap().as_ref(),
+                ring::digest::digest(digest_alg, &[]).as_ref()
+            );
+        }
+        assert!(precomputed_empty(HashAlgorithm::SHA1).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::crypto::hmac;
+
+use core::fmt;
+
+/// A concrete HKDF implementation (RFC5869), for a single cryptographic hash function.
+///
+/// You should have one object that implements this trait for HKDF-SHA256, one
+/// for HKDF-SHA384, etc.
+pub trait Hkdf: Send + Sync {
+    /// HKDF-Extract: derive a pseudorandom key from `salt` and the input
+    /// keying material `ikm`.
+    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Prk;
+
+    /// HKDF-Expand: fill `output` with keying material derived from the
+    /// pseudorandom key `prk` and the concatenation of the slices in `info`.
+    ///
+    /// Fails if `output` is longer than 255 times the hash output length.
+    fn expand(
+        &self,
+        prk: &[u8],
+        info: &[&[u8]],
+        output: &mut [u8],
+    ) -> Result<(), OutputLengthError>;
+
+    /// `HKDF-Expand-Label`, as defined for TLS1.3 in RFC8446 section 7.1.
+    ///
+    /// `output.len()` is used as the `Length` of the `HkdfLabel` structure.
+    fn expand_label(
+        &self,
+        secret: &[u8],
+        label: &[u8],
+        context: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), OutputLengthError> {
+        const LABEL_PREFIX: &[u8] = b"tls13 ";
+
+        let output_len = u16::to_be_bytes(output.len() as u16);
+        let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
+        let context_len = u8::to_be_bytes(context.len() as u8);
+
+        let info = &[
+            &output_len[..],
+            &label_len[..],
+            LABEL_PREFIX,
+            label,
+            &context_len[..],
+            context,
+        ];
+        self.expand(secret, info, output)
+    }
+
+    /// Compute the HMAC of `message` using `key`, with the same hash function.
+    ///
+    /// This is used for computing Finished messages and PSK binders.
+    fn hmac_sign(&self, key: &[u8], message: &[u8]) -> hmac::Tag;
+
+    /// The length in bytes of the underlying hash function's output.
+    fn hash_output_len(&self) -> usize;
+}
+
+/// A pseudorandom key (or another secret of the hash output length), stored as a value.
+#[derive(Clone)]
+pub struct Prk {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Prk {
+    /// Build a `Prk` by copying a slice of no more than `Prk::MAX_LEN` bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut prk = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        prk.buf[..bytes.len()].copy_from_slice(bytes);
+        prk
+    }
+
+    /// Maximum supported length: supports up to SHA512.
+    pub const MAX_LEN: usize = 64;
+}
+
+impl AsRef<[u8]> for Prk {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+impl fmt::Debug for Prk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prk")
+            .field("len", &self.used)
+            .finish()
+    }
+}
+
+/// The requested output of [`Hkdf::expand()`] was too long.
+#[derive(Debug)]
+pub struct OutputLengthError;
+
+#[cfg(test)]
+mod test {
+    use super::{Hkdf, OutputLengthError, Prk};
+    use crate::crypto::hmac;
+
+    /// An HKDF that records its `info` input rather than expanding.
+    struct RecordInfo;
+
+    impl Hkdf for RecordInfo {
+        fn extract(&self, _salt: &[u8], _ikm: &[u8]) -> Prk {
+            unreachable!()
+        }
+
+        fn expand(
+            &self,
+            _prk: &[u8],
+            info: &[&[u8]],
+            output: &mut [u8],
+        ) -> Result<(), OutputLengthError> {
+            let info = info.concat();
+            output.copy_from_slice(&info[..output.len()]);
+            Ok(())
+        }
+
+        fn hmac_sign(&self, _key: &[u8], _message: &[u8]) -> hmac::Tag {
+            unreachable!()
+        }
+
+        fn hash_output_len(&self) -> usize {
+            32
+        }
+    }
+
+    #[test]
+    fn expand_label_builds_hkdf_label() {
+        // struct {
+        //     uint16 length = Length;
+        //     opaque label<7..255> = "tls13 " + Label;
+        //     opaque context<0..255> = Context;
+        // } HkdfLabel;
+        let mut output = [0u8; 15];
+        RecordInfo
+            .expand_label(&[], b"key", &[0xaa, 0xbb], &mut output)
+            .unwrap();
+        assert_eq!(
+            &output,
+            &[
+                0x00, 0x0f, 0x09, b't', b'l', b's', b'1', b'3', b' ', b'k', b'e', b'y', 0x02, 0xaa,
+                0xbb,
+            ]
+        );
+    }
+}
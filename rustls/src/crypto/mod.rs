@@ -1,11 +1,29 @@
-use crate::rand::GetRandomFailed;
+// The provider interfaces use only `core` and `alloc`, ready for `no_std`
+// targets; the *ring* provider, and the rest of the crate, still use `std`.
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+pub use crate::rand::GetRandomFailed;
 use crate::{Error, NamedGroup};
 
-use std::fmt::Debug;
+use core::fmt::Debug;
+use core::marker::PhantomData;
 
 /// *ring* based CryptoProvider.
+#[allow(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
 pub mod ring;
 
+/// Hashing interfaces.
+pub mod hash;
+
+/// HMAC interfaces.
+pub mod hmac;
+
+/// HKDF interfaces.
+pub mod hkdf;
+
+/// AEAD nonce and traffic key helpers.
+pub mod cipher;
+
 /// Pluggable crypto galore.
 pub trait CryptoProvider: Send + Sync + 'static {
     /// KeyExchange operations that are supported by the provider.
@@ -13,6 +31,35 @@ pub trait CryptoProvider: Send + Sync + 'static {
 
     /// Fill the given buffer with random bytes.
     fn fill_random(buf: &mut [u8]) -> Result<(), GetRandomFailed>;
+
+    /// HMAC-SHA256, for uses of HMAC outside of a cipher suite.
+    fn hmac_sha256() -> &'static dyn hmac::Hmac;
+}
+
+/// A [`CryptoProvider`] assembled from the parts of two others.
+///
+/// Key exchange comes from `Kx`, and everything else (randomness and
+/// HMAC) from `Base`.  For example, `CompositeProvider<MyKeyExchange, Ring>`
+/// uses a custom key exchange with *ring* for the rest.
+///
+/// Cipher suites carry their own hash, HKDF and AEAD implementations, so
+/// those are mixed by choosing suites when building a config, with
+/// [`ConfigBuilder::with_cipher_suites()`](crate::ConfigBuilder::with_cipher_suites).
+pub struct CompositeProvider<Kx, Base> {
+    _kx: PhantomData<fn() -> Kx>,
+    _base: PhantomData<fn() -> Base>,
+}
+
+impl<Kx: KeyExchange, Base: CryptoProvider> CryptoProvider for CompositeProvider<Kx, Base> {
+    type KeyExchange = Kx;
+
+    fn fill_random(buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+        Base::fill_random(buf)
+    }
+
+    fn hmac_sha256() -> &'static dyn hmac::Hmac {
+        Base::hmac_sha256()
+    }
 }
 
 /// An in-progress key exchange over a [SupportedGroup].
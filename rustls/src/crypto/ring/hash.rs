@@ -0,0 +1,108 @@
+use crate::crypto;
+use crate::msgs::enums::HashAlgorithm;
+
+use ring::digest;
+
+pub(crate) static SHA256: Hash = Hash(&digest::SHA256, HashAlgorithm::SHA256);
+pub(crate) static SHA384: Hash = Hash(&digest::SHA384, HashAlgorithm::SHA384);
+
+pub(crate) struct Hash(&'static digest::Algorithm, HashAlgorithm);
+
+impl crypto::hash::Hash for Hash {
+    fn start(&self) -> Box<dyn crypto::hash::Context> {
+        Box::new(Context(digest::Context::new(self.0)))
+    }
+
+    fn hash(&self, data: &[u8]) -> crypto::hash::Output {
+        convert(digest::digest(self.0, data))
+    }
+
+    fn compute_many(&self, data: &[&[u8]]) -> crypto::hash::Output {
+        let mut ctx = digest::Context::new(self.0);
+        for d in data {
+            ctx.update(d);
+        }
+        convert(ctx.finish())
+    }
+
+    fn output_len(&self) -> usize {
+        self.0.output_len
+    }
+
+    fn algorithm(&self) -> HashAlgorithm {
+        self.1
+    }
+}
+
+struct Context(digest::Context);
+
+impl crypto::hash::Context for Context {
+    fn fork_finish(&self) -> crypto::hash::Output {
+        convert(self.0.clone().finish())
+    }
+
+    fn fork(&self) -> Box<dyn crypto::hash::Context> {
+        Box::new(Self(self.0.clone()))
+    }
+
+    fn finish(self: Box<Self>) -> crypto::hash::Output {
+        convert(self.0.finish())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+fn convert(val: digest::Digest) -> crypto::hash::Output {
+    crypto::hash::Output::new(val.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SHA256, SHA384};
+    use crate::crypto::hash::Hash;
+
+    #[test]
+    fn fork_finish_does_not_disturb_context() {
+        for hash in [&SHA256, &SHA384] {
+            let mut ctx = hash.start();
+            ctx.update(b"hello");
+            assert_eq!(ctx.fork_finish().as_ref(), hash.hash(b"hello").as_ref());
+
+            ctx.update(b" world");
+            assert_eq!(
+                ctx.fork_finish().as_ref(),
+                hash.hash(b"hello world").as_ref()
+            );
+            assert_eq!(ctx.finish().as_ref(), hash.hash(b"hello world").as_ref());
+        }
+    }
+
+    #[test]
+    fn fork_is_independent() {
+        for hash in [&SHA256, &SHA384] {
+            let mut ctx = hash.start();
+            ctx.update(b"hello");
+
+            let mut fork = ctx.fork();
+            fork.update(b" fork");
+            ctx.update(b" world");
+
+            assert_eq!(fork.finish().as_ref(), hash.hash(b"hello fork").as_ref());
+            assert_eq!(ctx.finish().as_ref(), hash.hash(b"hello world").as_ref());
+        }
+    }
+
+    #[test]
+    fn compute_many_hashes_concatenation() {
+        let (a, b) = (&b"hello"[..], &b" world"[..]);
+        for hash in [&SHA256, &SHA384] {
+            assert_eq!(
+                hash.compute_many(&[a, b]).as_ref(),
+                hash.hash(&[a, b].concat()).as_ref()
+            );
+            assert_eq!(hash.compute_many(&[]).as_ref(), hash.hash(b"").as_ref());
+        }
+    }
+}
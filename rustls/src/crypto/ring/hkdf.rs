@@ -0,0 +1,120 @@
+use crate::crypto;
+use crate::crypto::hkdf::{OutputLengthError, Prk};
+
+use ring::{hkdf, hmac};
+
+pub(crate) static HKDF_SHA256: Hkdf = Hkdf(hkdf::HKDF_SHA256, hmac::HMAC_SHA256);
+pub(crate) static HKDF_SHA384: Hkdf = Hkdf(hkdf::HKDF_SHA384, hmac::HMAC_SHA384);
+
+pub(crate) struct Hkdf(hkdf::Algorithm, hmac::Algorithm);
+
+impl crypto::hkdf::Hkdf for Hkdf {
+    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Prk {
+        // *ring* does not allow access to the bytes of a `hkdf::Prk`, but
+        // HKDF-Extract is simply HMAC with the salt as the key.
+        Prk::new(hmac::sign(&hmac::Key::new(self.1, salt), ikm).as_ref())
+    }
+
+    fn expand(
+        &self,
+        prk: &[u8],
+        info: &[&[u8]],
+        output: &mut [u8],
+    ) -> Result<(), OutputLengthError> {
+        hkdf::Prk::new_less_safe(self.0, prk)
+            .expand(info, Len(output.len()))
+            .and_then(|okm| okm.fill(output))
+            .map_err(|_| OutputLengthError)
+    }
+
+    fn hmac_sign(&self, key: &[u8], message: &[u8]) -> crypto::hmac::Tag {
+        crypto::hmac::Tag::new(hmac::sign(&hmac::Key::new(self.1, key), message).as_ref())
+    }
+
+    fn hash_output_len(&self) -> usize {
+        self.1.digest_algorithm().output_len
+    }
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HKDF_SHA256;
+    use crate::crypto::hkdf::Hkdf;
+
+    #[test]
+    fn rfc5869_test_case_1() {
+        let ikm = [0x0b; 22];
+        let salt = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+        ];
+        let info = [0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9];
+
+        let prk = HKDF_SHA256.extract(&salt, &ikm);
+        assert_eq!(
+            prk.as_ref(),
+            &[
+                0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b,
+                0xba, 0x63, 0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a,
+                0xd7, 0xc2, 0xb3, 0xe5,
+            ]
+        );
+
+        let mut okm = [0u8; 42];
+        HKDF_SHA256
+            .expand(prk.as_ref(), &[&info[..5], &info[5..]], &mut okm)
+            .unwrap();
+        assert_eq!(
+            &okm[..],
+            &[
+                0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+                0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+                0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn rfc5869_test_case_3() {
+        let ikm = [0x0b; 22];
+
+        let prk = HKDF_SHA256.extract(&[], &ikm);
+        assert_eq!(
+            prk.as_ref(),
+            &[
+                0x19, 0xef, 0x24, 0xa3, 0x2c, 0x71, 0x7b, 0x16, 0x7f, 0x33, 0xa9, 0x1d, 0x6f, 0x64,
+                0x8b, 0xdf, 0x96, 0x59, 0x67, 0x76, 0xaf, 0xdb, 0x63, 0x77, 0xac, 0x43, 0x4c, 0x1c,
+                0x29, 0x3c, 0xcb, 0x04,
+            ]
+        );
+
+        let mut okm = [0u8; 42];
+        HKDF_SHA256
+            .expand(prk.as_ref(), &[], &mut okm)
+            .unwrap();
+        assert_eq!(
+            &okm[..],
+            &[
+                0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63, 0xc1, 0x8f, 0x71, 0x5f, 0x80, 0x2a, 0x06, 0x3c,
+                0x5a, 0x31, 0xb8, 0xa1, 0x1f, 0x5c, 0x5e, 0xe1, 0x87, 0x9e, 0xc3, 0x45, 0x4e, 0x5f,
+                0x3c, 0x73, 0x8d, 0x2d, 0x9d, 0x20, 0x13, 0x95, 0xfa, 0xa4, 0xb6, 0x1a, 0x96, 0xc8,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn expand_rejects_overlong_output() {
+        let prk = HKDF_SHA256.extract(&[], &[]);
+        let mut okm = vec![0u8; 255 * 32 + 1];
+        assert!(HKDF_SHA256
+            .expand(prk.as_ref(), &[], &mut okm)
+            .is_err());
+    }
+}
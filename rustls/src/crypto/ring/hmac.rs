@@ -0,0 +1,57 @@
+use crate::crypto;
+
+use ring::hmac;
+
+pub(crate) static HMAC_SHA256: Hmac = Hmac(hmac::HMAC_SHA256);
+
+pub(crate) struct Hmac(hmac::Algorithm);
+
+impl crypto::hmac::Hmac for Hmac {
+    fn with_key(&self, key: &[u8]) -> Box<dyn crypto::hmac::Key> {
+        Box::new(Key(hmac::Key::new(self.0, key)))
+    }
+
+    fn hash_output_len(&self) -> usize {
+        self.0.digest_algorithm().output_len
+    }
+}
+
+struct Key(hmac::Key);
+
+impl crypto::hmac::Key for Key {
+    fn sign(&self, data: &[&[u8]]) -> crypto::hmac::Tag {
+        let mut ctx = hmac::Context::with_key(&self.0);
+        for d in data {
+            ctx.update(d);
+        }
+        crypto::hmac::Tag::new(ctx.sign().as_ref())
+    }
+
+    fn tag_len(&self) -> usize {
+        self.0
+            .algorithm()
+            .digest_algorithm()
+            .output_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HMAC_SHA256;
+    use crate::crypto::hmac::Hmac;
+
+    #[test]
+    fn rfc4231_test_case_2() {
+        let key = HMAC_SHA256.with_key(b"Jefe");
+        let tag = key.sign(&[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(key.tag_len(), 32);
+        assert_eq!(
+            tag.as_ref(),
+            &[
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+                0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+    }
+}
@@ -3,6 +3,7 @@ use crate::error::{Error, PeerMisbehaved};
 use crate::msgs::enums::NamedGroup;
 use crate::rand::GetRandomFailed;
 use crate::server::ProducesTickets;
+use crate::time::TimeProvider;
 
 use ring::aead;
 use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey};
@@ -11,6 +12,10 @@ use ring::rand::{SecureRandom, SystemRandom};
 use std::fmt;
 use std::sync::Arc;
 
+pub(crate) mod hash;
+pub(crate) mod hkdf;
+pub(crate) mod hmac;
+
 /// Default crypto provider.
 #[derive(Debug)]
 pub struct Ring;
@@ -23,6 +28,10 @@ impl CryptoProvider for Ring {
             .fill(buf)
             .map_err(|_| GetRandomFailed)
     }
+
+    fn hmac_sha256() -> &'static dyn crate::crypto::hmac::Hmac {
+        &hmac::HMAC_SHA256
+    }
 }
 
 /// An in-progress key exchange.  This has the algorithm,
@@ -161,6 +170,24 @@ impl Ticketer {
             make_ticket_generator,
         )?))
     }
+
+    /// Like [`Ticketer::new`], but measures ticket key lifetimes with
+    /// `time_provider` rather than the system clock.
+    ///
+    /// Pass the same provider as [`ServerConfig::time_provider`].
+    ///
+    /// [`ServerConfig::time_provider`]: crate::ServerConfig::time_provider
+    pub fn new_with_time_provider(
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Result<Arc<dyn ProducesTickets>, Error> {
+        Ok(Arc::new(
+            crate::ticketer::TicketSwitcher::new_with_time_provider(
+                6 * 60 * 60,
+                make_ticket_generator,
+                time_provider,
+            )?,
+        ))
+    }
 }
 
 fn make_ticket_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
@@ -239,7 +266,7 @@ impl ProducesTickets for AeadTicketer {
 }
 
 #[cfg(test)]
-use crate::ticketer::TimeBase;
+use crate::time::{DefaultTimeProvider, UnixTime};
 
 #[test]
 fn basic_pairwise_test() {
@@ -253,19 +280,25 @@ fn basic_pairwise_test() {
 #[test]
 fn ticketswitcher_switching_test() {
     let t = Arc::new(crate::ticketer::TicketSwitcher::new(1, make_ticket_generator).unwrap());
-    let now = TimeBase::now().unwrap();
+    let now = DefaultTimeProvider
+        .current_time()
+        .unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
     {
         // Trigger new ticketer
-        t.maybe_roll(TimeBase(now.0 + std::time::Duration::from_secs(10)));
+        t.maybe_roll(UnixTime::since_unix_epoch(std::time::Duration::from_secs(
+            now.as_secs() + 10,
+        )));
     }
     let cipher2 = t.encrypt(b"ticket 2").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     {
         // Trigger new ticketer
-        t.maybe_roll(TimeBase(now.0 + std::time::Duration::from_secs(20)));
+        t.maybe_roll(UnixTime::since_unix_epoch(std::time::Duration::from_secs(
+            now.as_secs() + 20,
+        )));
     }
     let cipher3 = t.encrypt(b"ticket 3").unwrap();
     assert!(t.decrypt(&cipher1).is_none());
@@ -281,13 +314,17 @@ fn fail_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
 #[test]
 fn ticketswitcher_recover_test() {
     let mut t = crate::ticketer::TicketSwitcher::new(1, make_ticket_generator).unwrap();
-    let now = TimeBase::now().unwrap();
+    let now = DefaultTimeProvider
+        .current_time()
+        .unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
     t.generator = fail_generator;
     {
         // Failed new ticketer
-        t.maybe_roll(TimeBase(now.0 + std::time::Duration::from_secs(10)));
+        t.maybe_roll(UnixTime::since_unix_epoch(std::time::Duration::from_secs(
+            now.as_secs() + 10,
+        )));
     }
     t.generator = make_ticket_generator;
     let cipher2 = t.encrypt(b"ticket 2").unwrap();
@@ -295,10 +332,56 @@ fn ticketswitcher_recover_test() {
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     {
         // recover
-        t.maybe_roll(TimeBase(now.0 + std::time::Duration::from_secs(20)));
+        t.maybe_roll(UnixTime::since_unix_epoch(std::time::Duration::from_secs(
+            now.as_secs() + 20,
+        )));
     }
     let cipher3 = t.encrypt(b"ticket 3").unwrap();
     assert!(t.decrypt(&cipher1).is_none());
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
+
+#[cfg(test)]
+#[derive(Debug)]
+struct FixedTimeProvider(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl TimeProvider for FixedTimeProvider {
+    fn current_time(&self) -> Option<UnixTime> {
+        Some(UnixTime::since_unix_epoch(std::time::Duration::from_secs(
+            self.0
+                .load(std::sync::atomic::Ordering::SeqCst),
+        )))
+    }
+}
+
+#[test]
+fn ticketswitcher_uses_time_provider() {
+    let time = Arc::new(FixedTimeProvider(1_000.into()));
+    let time_provider = Arc::clone(&time);
+    let t = crate::ticketer::TicketSwitcher::new_with_time_provider(
+        1,
+        make_ticket_generator,
+        time_provider,
+    )
+    .unwrap();
+    let cipher1 = t.encrypt(b"ticket 1").unwrap();
+
+    // Still within the lifetime: no switch.
+    time.0
+        .store(1_001, std::sync::atomic::Ordering::SeqCst);
+    let cipher2 = t.encrypt(b"ticket 2").unwrap();
+    assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
+
+    // Past one lifetime: the key that made cipher1 is demoted.
+    time.0
+        .store(1_010, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
+
+    // Past another: it is discarded.
+    time.0
+        .store(1_020, std::sync::atomic::Ordering::SeqCst);
+    assert!(t.decrypt(&cipher1).is_none());
+    assert!(t.decrypt(&cipher2).is_none());
+}
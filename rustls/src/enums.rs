@@ -2,6 +2,8 @@
 #![allow(missing_docs)]
 use crate::msgs::codec::{Codec, Reader};
 
+use std::fmt;
+
 enum_builder! {
     /// The `AlertDescription` TLS protocol enum.  Values in this enum are taken
     /// from the various RFCs covering TLS, and are listed by IANA.
@@ -71,6 +73,7 @@ enum_builder! {
         CertificateURL => 0x15,
         CertificateStatus => 0x16,
         KeyUpdate => 0x18,
+        CompressedCertificate => 0x19,
         MessageHash => 0xfe
     }
 }
@@ -495,6 +498,249 @@ enum_builder! {
     }
 }
 
+impl CipherSuite {
+    /// The name of this cipher suite in the IANA "TLS Cipher Suites" registry,
+    /// or `None` if this is an unrecognised suite.
+    ///
+    /// This differs from the variant name only for the TLS1.3 suites, which
+    /// IANA names without a version marker (eg. `TLS_AES_128_GCM_SHA256`).
+    pub fn iana_name(&self) -> Option<&'static str> {
+        match self {
+            Self::TLS13_AES_128_GCM_SHA256 => Some("TLS_AES_128_GCM_SHA256"),
+            Self::TLS13_AES_256_GCM_SHA384 => Some("TLS_AES_256_GCM_SHA384"),
+            Self::TLS13_CHACHA20_POLY1305_SHA256 => Some("TLS_CHACHA20_POLY1305_SHA256"),
+            Self::TLS13_AES_128_CCM_SHA256 => Some("TLS_AES_128_CCM_SHA256"),
+            Self::TLS13_AES_128_CCM_8_SHA256 => Some("TLS_AES_128_CCM_8_SHA256"),
+            _ => self.as_str(),
+        }
+    }
+
+    /// Whether this is one of the registered CBC-mode, MAC-then-encrypt suites.
+    pub(crate) fn is_cbc(&self) -> bool {
+        matches!(
+            self,
+            Self::TLS_RSA_EXPORT_WITH_RC2_CBC_40_MD5
+                | Self::TLS_RSA_WITH_IDEA_CBC_SHA
+                | Self::TLS_RSA_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_RSA_WITH_DES_CBC_SHA
+                | Self::TLS_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DH_DSS_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_DES_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DH_RSA_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_DES_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DHE_DSS_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_DES_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DHE_RSA_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_DES_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DH_anon_EXPORT_WITH_DES40_CBC_SHA
+                | Self::TLS_DH_anon_WITH_DES_CBC_SHA
+                | Self::TLS_DH_anon_WITH_3DES_EDE_CBC_SHA
+                | Self::SSL_FORTEZZA_KEA_WITH_FORTEZZA_CBC_SHA
+                | Self::TLS_KRB5_WITH_DES_CBC_SHA_or_SSL_FORTEZZA_KEA_WITH_RC4_128_SHA
+                | Self::TLS_KRB5_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_KRB5_WITH_IDEA_CBC_SHA
+                | Self::TLS_KRB5_WITH_DES_CBC_MD5
+                | Self::TLS_KRB5_WITH_3DES_EDE_CBC_MD5
+                | Self::TLS_KRB5_WITH_IDEA_CBC_MD5
+                | Self::TLS_KRB5_EXPORT_WITH_DES_CBC_40_SHA
+                | Self::TLS_KRB5_EXPORT_WITH_RC2_CBC_40_SHA
+                | Self::TLS_KRB5_EXPORT_WITH_DES_CBC_40_MD5
+                | Self::TLS_KRB5_EXPORT_WITH_RC2_CBC_40_MD5
+                | Self::TLS_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_AES_128_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_AES_128_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_DH_anon_WITH_AES_128_CBC_SHA
+                | Self::TLS_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_AES_256_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_AES_256_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_DH_anon_WITH_AES_256_CBC_SHA
+                | Self::TLS_RSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_RSA_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DH_DSS_WITH_AES_128_CBC_SHA256
+                | Self::TLS_DH_RSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_AES_128_CBC_SHA256
+                | Self::TLS_RSA_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_DH_anon_WITH_CAMELLIA_128_CBC_SHA
+                | Self::TLS_ECDH_ECDSA_WITH_DES_CBC_SHA_draft
+                | Self::TLS_ECDH_ECDSA_WITH_3DES_EDE_CBC_SHA_draft
+                | Self::TLS_ECDH_ECDSA_WITH_AES_128_CBC_SHA_draft
+                | Self::TLS_ECDH_ECDSA_WITH_AES_256_CBC_SHA_draft
+                | Self::TLS_ECDH_ECNRA_WITH_DES_CBC_SHA_draft
+                | Self::TLS_ECDH_ECNRA_WITH_3DES_EDE_CBC_SHA_draft
+                | Self::TLS_ECMQV_ECDSA_WITH_DES_CBC_SHA_draft
+                | Self::TLS_ECMQV_ECDSA_WITH_3DES_EDE_CBC_SHA_draft
+                | Self::TLS_ECMQV_ECNRA_WITH_DES_CBC_SHA_draft
+                | Self::TLS_ECMQV_ECNRA_WITH_3DES_EDE_CBC_SHA_draft
+                | Self::TLS_ECDH_anon_WITH_DES_CBC_SHA_draft
+                | Self::TLS_ECDH_anon_WITH_3DES_EDE_CBC_SHA_draft
+                | Self::TLS_ECDH_anon_EXPORT_WITH_DES40_CBC_SHA_draft
+                | Self::TLS_RSA_EXPORT1024_WITH_RC2_CBC_56_MD5
+                | Self::TLS_RSA_EXPORT1024_WITH_DES_CBC_SHA
+                | Self::TLS_DHE_DSS_EXPORT1024_WITH_DES_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_DH_DSS_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DH_RSA_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DHE_RSA_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DH_anon_WITH_AES_128_CBC_SHA256
+                | Self::TLS_DH_anon_WITH_AES_256_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_3DES_EDE_CBC_RMD
+                | Self::TLS_DHE_DSS_WITH_AES_128_CBC_RMD
+                | Self::TLS_DHE_DSS_WITH_AES_256_CBC_RMD
+                | Self::TLS_DHE_RSA_WITH_3DES_EDE_CBC_RMD
+                | Self::TLS_DHE_RSA_WITH_AES_128_CBC_RMD
+                | Self::TLS_DHE_RSA_WITH_AES_256_CBC_RMD
+                | Self::TLS_RSA_WITH_3DES_EDE_CBC_RMD
+                | Self::TLS_RSA_WITH_AES_128_CBC_RMD
+                | Self::TLS_RSA_WITH_AES_256_CBC_RMD
+                | Self::TLS_RSA_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_DH_anon_WITH_CAMELLIA_256_CBC_SHA
+                | Self::TLS_PSK_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_PSK_WITH_AES_128_CBC_SHA
+                | Self::TLS_PSK_WITH_AES_256_CBC_SHA
+                | Self::TLS_DHE_PSK_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_DHE_PSK_WITH_AES_128_CBC_SHA
+                | Self::TLS_DHE_PSK_WITH_AES_256_CBC_SHA
+                | Self::TLS_RSA_PSK_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_RSA_PSK_WITH_AES_128_CBC_SHA
+                | Self::TLS_RSA_PSK_WITH_AES_256_CBC_SHA
+                | Self::TLS_RSA_WITH_SEED_CBC_SHA
+                | Self::TLS_DH_DSS_WITH_SEED_CBC_SHA
+                | Self::TLS_DH_RSA_WITH_SEED_CBC_SHA
+                | Self::TLS_DHE_DSS_WITH_SEED_CBC_SHA
+                | Self::TLS_DHE_RSA_WITH_SEED_CBC_SHA
+                | Self::TLS_DH_anon_WITH_SEED_CBC_SHA
+                | Self::TLS_PSK_WITH_AES_128_CBC_SHA256
+                | Self::TLS_PSK_WITH_AES_256_CBC_SHA384
+                | Self::TLS_DHE_PSK_WITH_AES_128_CBC_SHA256
+                | Self::TLS_DHE_PSK_WITH_AES_256_CBC_SHA384
+                | Self::TLS_RSA_PSK_WITH_AES_128_CBC_SHA256
+                | Self::TLS_RSA_PSK_WITH_AES_256_CBC_SHA384
+                | Self::TLS_RSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DH_DSS_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DH_RSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DHE_RSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DH_anon_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_RSA_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_DH_DSS_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_DH_RSA_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_DHE_RSA_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_DH_anon_WITH_CAMELLIA_256_CBC_SHA256
+                | Self::TLS_ECDH_ECDSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDH_ECDSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDH_ECDSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDHE_ECDSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDH_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDH_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDH_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDHE_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDH_anon_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDH_anon_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDH_anon_WITH_AES_256_CBC_SHA
+                | Self::TLS_SRP_SHA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_SRP_SHA_RSA_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_SRP_SHA_DSS_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_SRP_SHA_WITH_AES_128_CBC_SHA
+                | Self::TLS_SRP_SHA_RSA_WITH_AES_128_CBC_SHA
+                | Self::TLS_SRP_SHA_DSS_WITH_AES_128_CBC_SHA
+                | Self::TLS_SRP_SHA_WITH_AES_256_CBC_SHA
+                | Self::TLS_SRP_SHA_RSA_WITH_AES_256_CBC_SHA
+                | Self::TLS_SRP_SHA_DSS_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA384
+                | Self::TLS_ECDH_ECDSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_ECDH_ECDSA_WITH_AES_256_CBC_SHA384
+                | Self::TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA384
+                | Self::TLS_ECDH_RSA_WITH_AES_128_CBC_SHA256
+                | Self::TLS_ECDH_RSA_WITH_AES_256_CBC_SHA384
+                | Self::TLS_ECDHE_PSK_WITH_3DES_EDE_CBC_SHA
+                | Self::TLS_ECDHE_PSK_WITH_AES_128_CBC_SHA
+                | Self::TLS_ECDHE_PSK_WITH_AES_256_CBC_SHA
+                | Self::TLS_ECDHE_PSK_WITH_AES_128_CBC_SHA256
+                | Self::TLS_ECDHE_PSK_WITH_AES_256_CBC_SHA384
+                | Self::TLS_RSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_RSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DH_DSS_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DH_DSS_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DH_RSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DH_RSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DHE_DSS_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DHE_DSS_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DHE_RSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DHE_RSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DH_anon_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DH_anon_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_ECDSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_ECDSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDH_ECDSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_ECDH_ECDSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_RSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_RSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDH_RSA_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_ECDH_RSA_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_PSK_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_PSK_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_DHE_PSK_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_DHE_PSK_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_RSA_PSK_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_RSA_PSK_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_PSK_WITH_ARIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_PSK_WITH_ARIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_ECDSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_ECDSA_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_ECDH_ECDSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_ECDH_ECDSA_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_RSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_RSA_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_ECDH_RSA_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_ECDH_RSA_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_PSK_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_PSK_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_DHE_PSK_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_DHE_PSK_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_RSA_PSK_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_RSA_PSK_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::TLS_ECDHE_PSK_WITH_CAMELLIA_128_CBC_SHA256
+                | Self::TLS_ECDHE_PSK_WITH_CAMELLIA_256_CBC_SHA384
+                | Self::SSL_RSA_FIPS_WITH_DES_CBC_SHA
+                | Self::SSL_RSA_FIPS_WITH_3DES_EDE_CBC_SHA
+        )
+    }
+}
+
+/// Renders the IANA name and code point, eg. `TLS_AES_128_GCM_SHA256 (0x1301)`.
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.iana_name() {
+            Some(name) => write!(f, "{} ({:#06x})", name, self.get_u16()),
+            None => write!(f, "Unknown ({:#06x})", self.get_u16()),
+        }
+    }
+}
+
 enum_builder! {
     /// The `SignatureScheme` TLS protocol enum.  Values in this enum are taken
     /// from the various RFCs covering TLS, and are listed by IANA.
@@ -534,6 +780,21 @@ impl SignatureScheme {
             _ => SignatureAlgorithm::Unknown(0),
         }
     }
+
+    /// Whether this scheme may be used for signatures in TLS1.3 handshakes.
+    pub(crate) fn supported_in_tls13(&self) -> bool {
+        matches!(
+            *self,
+            Self::ECDSA_NISTP256_SHA256
+                | Self::ECDSA_NISTP384_SHA384
+                | Self::ECDSA_NISTP521_SHA512
+                | Self::RSA_PSS_SHA256
+                | Self::RSA_PSS_SHA384
+                | Self::RSA_PSS_SHA512
+                | Self::ED25519
+                | Self::ED448
+        )
+    }
 }
 
 enum_builder! {
@@ -567,4 +828,35 @@ mod tests {
             AlertDescription::NoApplicationProtocol,
         );
     }
+
+    #[test]
+    fn test_cipher_suite_names() {
+        for (suite, expected) in [
+            (
+                CipherSuite::TLS13_AES_128_GCM_SHA256,
+                "TLS_AES_128_GCM_SHA256 (0x1301)",
+            ),
+            (
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                "TLS_CHACHA20_POLY1305_SHA256 (0x1303)",
+            ),
+            (
+                CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 (0xc030)",
+            ),
+            (
+                CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV,
+                "TLS_EMPTY_RENEGOTIATION_INFO_SCSV (0x00ff)",
+            ),
+            (CipherSuite::Unknown(0xfafa), "Unknown (0xfafa)"),
+        ] {
+            assert_eq!(suite.to_string(), expected);
+        }
+
+        assert_eq!(
+            CipherSuite::TLS13_AES_256_GCM_SHA384.iana_name(),
+            Some("TLS_AES_256_GCM_SHA384")
+        );
+        assert_eq!(CipherSuite::Unknown(0xfafa).iana_name(), None);
+    }
 }
@@ -1,4 +1,5 @@
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
+use crate::msgs::enums::ExtensionType;
 use crate::msgs::handshake::KeyExchangeAlgorithm;
 use crate::rand;
 
@@ -82,6 +83,12 @@ pub enum Error {
     /// is complete.
     HandshakeNotComplete,
 
+    /// The handshake was still in progress at the deadline set with
+    /// [`CommonState::set_handshake_deadline`].
+    ///
+    /// [`CommonState::set_handshake_deadline`]: crate::CommonState::set_handshake_deadline
+    HandshakeTimedOut,
+
     /// The peer sent an oversized record/fragment.
     PeerSentOversizedRecord,
 
@@ -161,8 +168,10 @@ impl From<InvalidMessage> for Error {
 /// Please file a bug against rustls if you see `Error::PeerMisbehaved` in
 /// the wild.
 pub enum PeerMisbehaved {
+    ApplicationDataDuringHandshake,
     AttemptedDowngradeToTls12WhenTls13IsSupported,
     BadCertChainExtensions,
+    DecompressedCertificateTooLarge,
     DisallowedEncryptedExtension,
     DuplicateClientHelloExtensions,
     DuplicateEncryptedExtensions,
@@ -173,6 +182,7 @@ pub enum PeerMisbehaved {
     EarlyDataAttemptedInSecondClientHello,
     EarlyDataExtensionWithoutResumption,
     EarlyDataOfferedWithVariedCipherSuite,
+    ExpiredDelegatedCredential,
     HandshakeHashVariedAfterRetry,
     IllegalHelloRetryRequestWithEmptyCookie,
     IllegalHelloRetryRequestWithNoChanges,
@@ -183,12 +193,16 @@ pub enum PeerMisbehaved {
     IllegalMiddleboxChangeCipherSpec,
     IllegalTlsInnerPlaintext,
     IncorrectBinder,
+    InvalidCertCompression,
+    InvalidDelegatedCredential,
+    InvalidHelloRetryCookie,
     InvalidMaxEarlyDataSize,
     InvalidKeyShare,
     KeyEpochWithPendingFragment,
     KeyUpdateReceivedInQuicConnection,
     MessageInterleavedWithHandshakeMessage,
     MissingBinderInPskExtension,
+    MissingExtension(ExtensionType),
     MissingKeyShare,
     MissingPskModesExtension,
     MissingQuicTransportParameters,
@@ -208,6 +222,8 @@ pub enum PeerMisbehaved {
     SelectedInvalidPsk,
     SelectedTls12UsingTls13VersionExtension,
     SelectedUnofferedApplicationProtocol,
+    SelectedUnofferedCertCompression,
+    SelectedUnofferedCertificateType,
     SelectedUnofferedCipherSuite,
     SelectedUnofferedCompression,
     SelectedUnofferedKxGroup,
@@ -218,12 +234,13 @@ pub enum PeerMisbehaved {
     ServerNameMustContainOneHostName,
     SignedKxWithWrongAlgorithm,
     SignedHandshakeWithUnadvertisedSigScheme,
+    TooManyCertificates,
     TooMuchEarlyDataReceived,
     UnexpectedCleartextExtension,
     UnsolicitedCertExtension,
-    UnsolicitedEncryptedExtension,
+    UnsolicitedDelegatedCredential,
+    UnsolicitedExtension(ExtensionType),
     UnsolicitedSctList,
-    UnsolicitedServerHelloExtension,
     WrongGroupForKeyShare,
 }
 
@@ -244,15 +261,18 @@ impl From<PeerMisbehaved> for Error {
 /// versions.
 pub enum PeerIncompatible {
     EcPointsExtensionRequired,
+    ExtendedMasterSecretExtensionRequired,
     KeyShareExtensionRequired,
     NamedGroupsExtensionRequired,
     NoCertificateRequestSignatureSchemesInCommon,
+    NoCertificateTypesInCommon,
     NoCipherSuitesInCommon,
     NoEcPointFormatsInCommon,
     NoKxGroupsInCommon,
     NoSignatureSchemesInCommon,
     NullCompressionRequired,
     ServerDoesNotSupportTls12Or13,
+    ServerNameExtensionRequired,
     ServerSentHelloRetryRequestWithUnknownExtension,
     ServerTlsVersionIsDisabledByOurConfig,
     SignatureAlgorithmsExtensionRequired,
@@ -261,6 +281,7 @@ pub enum PeerIncompatible {
     Tls12NotOfferedOrEnabled,
     Tls13RequiredForQuic,
     UncompressedEcPointsRequired,
+    UnknownExtensionRejected(ExtensionType),
 }
 
 impl From<PeerIncompatible> for Error {
@@ -314,6 +335,27 @@ pub enum CertificateError {
     /// reasons.
     ApplicationVerificationFailure,
 
+    /// The certificate chain has more intermediate certificates than
+    /// the verifier allows.
+    ChainTooLong,
+
+    /// A certificate in the chain has a public key smaller than the
+    /// verifier allows.
+    WeakKey,
+
+    /// The certificate requires a stapled OCSP response, but none was
+    /// provided.
+    MissingOcspResponse,
+
+    /// The stapled OCSP response could not be parsed, was not correctly
+    /// signed by the certificate's issuer, is not for the certificate, or
+    /// is not current.
+    InvalidOcspResponse,
+
+    /// The peer's raw public key (RFC 7250) is not one of those we
+    /// were configured to accept.
+    UnknownRawPublicKey,
+
     /// Any other error.
     ///
     /// This can be used by custom verifiers to expose the underlying error
@@ -342,6 +384,11 @@ impl PartialEq<Self> for CertificateError {
             (NotValidForName, NotValidForName) => true,
             (InvalidPurpose, InvalidPurpose) => true,
             (ApplicationVerificationFailure, ApplicationVerificationFailure) => true,
+            (ChainTooLong, ChainTooLong) => true,
+            (WeakKey, WeakKey) => true,
+            (MissingOcspResponse, MissingOcspResponse) => true,
+            (InvalidOcspResponse, InvalidOcspResponse) => true,
+            (UnknownRawPublicKey, UnknownRawPublicKey) => true,
             _ => false,
         }
     }
@@ -362,13 +409,32 @@ impl From<CertificateError> for AlertDescription {
             Revoked => Self::CertificateRevoked,
             UnknownIssuer => Self::UnknownCA,
             BadSignature => Self::DecryptError,
-            InvalidPurpose => Self::UnsupportedCertificate,
+            InvalidPurpose | WeakKey => Self::UnsupportedCertificate,
             ApplicationVerificationFailure => Self::AccessDenied,
+            MissingOcspResponse | InvalidOcspResponse => Self::BadCertificateStatusResponse,
             // RFC 5246/RFC 8446
             // certificate_unknown
             //  Some other (unspecified) issue arose in processing the
             //  certificate, rendering it unacceptable.
-            Other(_) => Self::CertificateUnknown,
+            ChainTooLong | UnknownRawPublicKey | Other(_) => Self::CertificateUnknown,
+        }
+    }
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Other(err) => write!(f, "{}", err),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl StdError for CertificateError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Other(err) => Some(err.as_ref()),
+            _ => None,
         }
     }
 }
@@ -466,6 +532,24 @@ impl From<webpki::Error> for CertRevocationListError {
     }
 }
 
+impl fmt::Display for CertRevocationListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Other(err) => write!(f, "{}", err),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl StdError for CertRevocationListError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Other(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 impl From<CertRevocationListError> for Error {
     #[inline]
     fn from(e: CertRevocationListError) -> Self {
@@ -520,6 +604,7 @@ impl fmt::Display for Error {
             Self::EncryptError => write!(f, "cannot encrypt message"),
             Self::PeerSentOversizedRecord => write!(f, "peer sent excess record size"),
             Self::HandshakeNotComplete => write!(f, "handshake not complete"),
+            Self::HandshakeTimedOut => write!(f, "handshake deadline passed"),
             Self::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             Self::FailedToGetCurrentTime => write!(f, "failed to get current time"),
             Self::FailedToGetRandomBytes => write!(f, "failed to get random bytes"),
@@ -538,7 +623,16 @@ impl From<SystemTimeError> for Error {
     }
 }
 
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::InvalidCertificate(err) => Some(err),
+            Self::InvalidCertRevocationList(err) => Some(err),
+            Self::FailedToGetRandomBytes => Some(&rand::GetRandomFailed),
+            _ => None,
+        }
+    }
+}
 
 impl From<rand::GetRandomFailed> for Error {
     fn from(_: rand::GetRandomFailed) -> Self {
@@ -567,6 +661,11 @@ mod tests {
             ApplicationVerificationFailure,
             ApplicationVerificationFailure
         );
+        assert_eq!(ChainTooLong, ChainTooLong);
+        assert_eq!(WeakKey, WeakKey);
+        assert_eq!(MissingOcspResponse, MissingOcspResponse);
+        assert_eq!(InvalidOcspResponse, InvalidOcspResponse);
+        assert_eq!(UnknownRawPublicKey, UnknownRawPublicKey);
         let other = Other(std::sync::Arc::from(Box::from("")));
         assert_ne!(other, other);
         assert_ne!(BadEncoding, Expired);
@@ -669,6 +768,7 @@ mod tests {
             Error::FailedToGetCurrentTime,
             Error::FailedToGetRandomBytes,
             Error::HandshakeNotComplete,
+            Error::HandshakeTimedOut,
             Error::PeerSentOversizedRecord,
             Error::NoApplicationProtocol,
             Error::BadMaxFragmentSize,
@@ -688,6 +788,41 @@ mod tests {
         assert_eq!(err, Error::FailedToGetRandomBytes);
     }
 
+    #[test]
+    fn error_sources() {
+        use super::{rand, CertRevocationListError, CertificateError};
+        use std::error::Error as StdError;
+        use std::sync::Arc;
+
+        let err = Error::FailedToGetRandomBytes;
+        assert!(err
+            .source()
+            .unwrap()
+            .is::<rand::GetRandomFailed>());
+
+        let err = Error::InvalidCertificate(CertificateError::Expired);
+        let source = err.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<CertificateError>(),
+            Some(&CertificateError::Expired)
+        );
+        assert!(source.source().is_none());
+
+        let inner = std::io::Error::new(std::io::ErrorKind::Other, "custom verifier failed");
+        let err = Error::InvalidCertificate(CertificateError::Other(Arc::new(inner)));
+        let source = err.source().unwrap().source().unwrap();
+        assert_eq!(source.to_string(), "custom verifier failed");
+        assert!(source.is::<std::io::Error>());
+
+        let err = Error::InvalidCertRevocationList(CertRevocationListError::BadSignature);
+        assert!(err
+            .source()
+            .unwrap()
+            .is::<CertRevocationListError>());
+
+        assert!(Error::DecryptError.source().is_none());
+    }
+
     #[test]
     fn time_error_mapping() {
         use std::time::SystemTime;
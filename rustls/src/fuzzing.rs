@@ -0,0 +1,66 @@
+//! Entry points for fuzzing rustls's parsers.
+//!
+//! These are only available with the `fuzzing` feature, and have no
+//! stability guarantees.
+
+use crate::enums::ProtocolVersion;
+use crate::error::InvalidMessage;
+use crate::msgs::codec::Reader;
+use crate::msgs::handshake::HandshakeMessagePayload;
+
+/// Decode `data` as a single handshake message, including its header.
+///
+/// Messages which differ between TLS1.2 and TLS1.3 are accepted in
+/// either form. This never panics: malformed input is an `Err`.
+pub fn fuzz_handshake_message(data: &[u8]) -> Result<(), InvalidMessage> {
+    read_handshake_message(data, ProtocolVersion::TLSv1_3)
+        .or_else(|_| read_handshake_message(data, ProtocolVersion::TLSv1_2))
+}
+
+fn read_handshake_message(data: &[u8], vers: ProtocolVersion) -> Result<(), InvalidMessage> {
+    let mut rdr = Reader::init(data);
+    HandshakeMessagePayload::read_version(&mut rdr, vers)?;
+    rdr.expect_empty("HandshakeMessagePayload")
+}
+
+#[cfg(all(test, feature = "fuzzing"))]
+mod tests {
+    use super::fuzz_handshake_message;
+
+    static CORPUS: &[&[u8]] = &[
+        include_bytes!("../../fuzz/corpus/handshake/tls12-certificate.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls12-client-hello.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls12-server-hello-done.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls12-server-hello.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls12-server-key-exchange.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls13-client-hello.bin"),
+        include_bytes!("../../fuzz/corpus/handshake/tls13-server-hello.bin"),
+    ];
+
+    #[test]
+    fn corpus_decodes() {
+        for data in CORPUS {
+            assert!(fuzz_handshake_message(data).is_ok());
+        }
+    }
+
+    #[test]
+    fn truncated_corpus_is_rejected() {
+        for data in CORPUS {
+            for len in 0..data.len() {
+                assert!(fuzz_handshake_message(&data[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_corpus_does_not_panic() {
+        for data in CORPUS {
+            for i in 0..data.len() {
+                let mut data = data.to_vec();
+                data[i] ^= 0xff;
+                let _ = fuzz_handshake_message(&data);
+            }
+        }
+    }
+}
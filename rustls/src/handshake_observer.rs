@@ -0,0 +1,66 @@
+use crate::common_state::Side;
+use crate::enums::ProtocolVersion;
+use crate::error::Error;
+use crate::suites::SupportedCipherSuite;
+
+/// This trait allows observing the outcome of handshakes, for example
+/// to collect metrics.
+///
+/// Configure one using [`ClientConfig::handshake_observer`] or
+/// [`ServerConfig::handshake_observer`].  All methods have empty default
+/// implementations, so implementors only need to provide the callbacks
+/// they are interested in.
+///
+/// Callbacks are made synchronously from within
+/// [`Connection::process_new_packets()`], so should be cheap.  You'll
+/// likely want some interior mutability in your implementation to make
+/// this useful.
+///
+/// [`ClientConfig::handshake_observer`]: crate::ClientConfig::handshake_observer
+/// [`ServerConfig::handshake_observer`]: crate::ServerConfig::handshake_observer
+/// [`Connection::process_new_packets()`]: crate::Connection::process_new_packets
+pub trait HandshakeObserver: Send + Sync {
+    /// Called once, when the handshake completes successfully.
+    fn on_handshake_complete(&self, _params: &HandshakeParams) {}
+
+    /// Called once, when the handshake fails with `error`.
+    ///
+    /// This is not called for errors that occur after the handshake
+    /// has completed.
+    fn on_handshake_failed(&self, _error: &Error) {}
+
+    /// Called when the peers agree to resume a previous session.
+    ///
+    /// This is called before [`HandshakeObserver::on_handshake_complete`]
+    /// for the same connection.  It is not a guarantee that the resumed
+    /// handshake will complete successfully.
+    fn on_resumption(&self, _kind: ResumptionKind) {}
+}
+
+/// Properties of a successfully completed handshake, passed to
+/// [`HandshakeObserver::on_handshake_complete`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HandshakeParams {
+    /// Which side of the connection this is.
+    pub side: Side,
+    /// The protocol version agreed with the peer.
+    pub protocol_version: ProtocolVersion,
+    /// The cipher suite agreed with the peer.
+    pub cipher_suite: SupportedCipherSuite,
+    /// Whether this handshake resumed a previous session.
+    pub resumed: bool,
+}
+
+/// The mechanism by which a session was resumed, passed to
+/// [`HandshakeObserver::on_resumption`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResumptionKind {
+    /// TLS1.2 resumption using a server-side session cache, keyed by session ID.
+    Tls12SessionId,
+    /// TLS1.2 resumption using a stateless session ticket (RFC5077).
+    Tls12Ticket,
+    /// TLS1.3 resumption using a pre-shared key from a ticket.
+    Tls13Psk,
+}
@@ -255,9 +255,36 @@
 //!   it for your application. If you want to disable TLS 1.2 for security reasons,
 //!   consider explicitly enabling TLS 1.3 only in the config builder API.
 //!
+//! - `tracing`: this makes the rustls crate depend on the `tracing` crate.
+//!   rustls then emits a span covering each handshake, and `debug`-level events
+//!   under the `rustls::handshake` target for its major state transitions.  Like
+//!   the `logging` output, these do not contain secret key data.
+//!
+//! - `brotli`: enables brotli certificate compression (RFC8879), using the
+//!   `brotli` crate.  See the `compress` module.
+//!
+//! - `zlib`: enables zlib certificate compression (RFC8879), using the
+//!   `flate2` crate.  See the `compress` module.
+//!
+//! - `key_schedule_steps`: exposes the individual steps of the TLS1.3 key
+//!   schedule as plain functions, so that they (and the underlying HKDF
+//!   implementation) can be checked against known test vectors.  This is
+//!   intended for testing only and is not part of the stable API.
+//!
 //! - `read_buf`: When building with Rust Nightly, adds support for the unstable
 //!   `std::io::ReadBuf` and related APIs. This reduces costs from initializing
 //!   buffers. Will do nothing on non-Nightly releases.
+//!
+//! - `fuzzing`: exposes entry points into the message parsers for fuzz
+//!   testing.  These are not part of the stable API.
+//!
+//! - `serde`: implements `Serialize` and `Deserialize` for `ConfigTemplate`,
+//!   using the `serde` crate.
+//!
+//! - `testing`: adds `ServerConfig::self_signed()`, which makes a server
+//!   configuration with a freshly generated certificate, and
+//!   `Tls13CipherSuite::with_confidentiality_limit()`, which makes key
+//!   updates happen sooner.  These are for use in tests.
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code, unused_must_use)]
@@ -308,6 +335,13 @@
 #[allow(unused_extern_crates)]
 extern crate test;
 
+// The crypto provider interfaces are written against `core` and `alloc`
+// only, in preparation for supporting `no_std` targets.  There is no
+// `no_std` feature, embedded (e.g. thumbv7) build or `no_std` test yet:
+// the rest of the crate, *ring* 0.16 and webpki all still require `std`,
+// so none can be built until they are replaced or made optional.
+extern crate alloc;
+
 // log for logging (optional).
 #[cfg(feature = "logging")]
 use log;
@@ -320,24 +354,44 @@ mod log {
     macro_rules! warn     ( ($($tt:tt)*) => {{}} );
 }
 
+// tracing for structured handshake events (optional).
+#[macro_use]
+mod handshake_trace {
+    #[cfg(feature = "tracing")]
+    macro_rules! handshake_event ( ($($tt:tt)*) => {
+        ::tracing::debug!(target: "rustls::handshake", $($tt)*)
+    } );
+    #[cfg(not(feature = "tracing"))]
+    macro_rules! handshake_event ( ($($tt:tt)*) => {{}} );
+}
+
 #[macro_use]
 mod msgs;
 mod anchors;
+mod buffer_pool;
 mod cipher;
 mod common_state;
+/// Certificate compression (RFC8879).
+pub mod compress;
 mod conn;
 /// Crypto provider interface.
 pub mod crypto;
 mod dns_name;
 mod error;
+mod handshake_observer;
 mod hash_hs;
 mod limited_cache;
+mod ocsp;
+mod psk;
 mod rand;
 mod record_layer;
 mod stream;
+/// Sources of the current time.
+pub mod time;
 #[cfg(feature = "tls12")]
 mod tls12;
 mod tls13;
+mod unknown_extensions;
 mod vecbuf;
 mod verify;
 #[cfg(test)]
@@ -352,6 +406,7 @@ mod key;
 mod key_log;
 mod key_log_file;
 mod suites;
+mod template;
 mod ticketer;
 mod versions;
 
@@ -374,10 +429,11 @@ pub mod internal {
 
 // The public interface is:
 pub use crate::anchors::{OwnedTrustAnchor, RootCertStore};
+pub use crate::buffer_pool::BufferPool;
 pub use crate::builder::{
     ConfigBuilder, ConfigSide, WantsCipherSuites, WantsKxGroups, WantsVerifier, WantsVersions,
 };
-pub use crate::common_state::{CommonState, IoState, Side};
+pub use crate::common_state::{CommonState, ConnectionState, ConnectionStats, IoState, Side};
 pub use crate::conn::{Connection, ConnectionCommon, Reader, SideData, Writer};
 pub use crate::crypto::ring::Ticketer;
 pub use crate::crypto::ring::{SupportedKxGroup, ALL_KX_GROUPS};
@@ -389,11 +445,15 @@ pub use crate::error::{
     CertRevocationListError, CertificateError, Error, InvalidMessage, PeerIncompatible,
     PeerMisbehaved,
 };
+pub use crate::handshake_observer::{HandshakeObserver, HandshakeParams, ResumptionKind};
 pub use crate::key::{Certificate, PrivateKey};
 pub use crate::key_log::{KeyLog, NoKeyLog};
 pub use crate::key_log_file::KeyLogFile;
-pub use crate::msgs::enums::NamedGroup;
+pub use crate::msgs::enums::{CertificateType, ExtensionType, NamedGroup};
 pub use crate::msgs::handshake::DistinguishedName;
+pub use crate::msgs::message::MessageNeedsMore;
+pub use crate::msgs::named_group::UnknownNamedGroupError;
+pub use crate::psk::{PresharedKey, PskKeyExchangePolicy};
 pub use crate::stream::{Stream, StreamOwned};
 pub use crate::suites::{
     BulkAlgorithm, SupportedCipherSuite, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
@@ -401,11 +461,16 @@ pub use crate::suites::{
 #[cfg(feature = "secret_extraction")]
 #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
 pub use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
+pub use crate::template::ConfigTemplate;
 pub use crate::ticketer::TicketSwitcher;
 #[cfg(feature = "tls12")]
 pub use crate::tls12::Tls12CipherSuite;
-pub use crate::tls13::Tls13CipherSuite;
-pub use crate::verify::DigitallySignedStruct;
+#[cfg(feature = "key_schedule_steps")]
+#[doc(hidden)]
+pub use crate::tls13::key_schedule::steps as key_schedule_steps;
+pub use crate::tls13::{RecordPadding, Tls13CipherSuite};
+pub use crate::unknown_extensions::{UnknownExtensionAction, UnknownExtensionHandler};
+pub use crate::verify::{DigitallySignedStruct, MinimumKeySize};
 pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
 
 /// Items for use in a client.
@@ -420,17 +485,19 @@ pub mod client {
     mod tls13;
 
     pub use crate::dns_name::InvalidDnsNameError;
+    pub use crate::verify::verify_chain;
     pub use builder::WantsClientCert;
     pub use client_conn::{
-        ClientConfig, ClientConnection, ClientConnectionData, ClientSessionStore,
-        ResolvesClientCert, Resumption, ServerName, Tls12Resumption, WriteEarlyData,
+        ClientConfig, ClientConnection, ClientConnectionData, ClientHelloLayout,
+        ClientSessionStore, ResolvesClientCert, Resumption, ServerName, Tls12Resumption,
+        WriteEarlyData,
     };
     pub use handy::ClientSessionMemoryCache;
 
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::verify::{
         verify_server_cert_signed_by_trust_anchor, verify_server_name, HandshakeSignatureValid,
-        ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+        IntermediateFetcher, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
     };
     #[cfg(feature = "dangerous_configuration")]
     pub use client_conn::danger::DangerousClientConfig;
@@ -457,11 +524,12 @@ pub mod server {
         UnparsedCertRevocationList,
     };
     pub use builder::WantsServerCert;
-    pub use handy::ResolvesServerCertUsingSni;
     pub use handy::{NoServerSessionStorage, ServerSessionMemoryCache};
+    pub use handy::{ResolvesServerCertUsingSignatureScheme, ResolvesServerCertUsingSni};
     pub use server_conn::StoresServerSessions;
     pub use server_conn::{
-        Accepted, Acceptor, ReadEarlyData, ServerConfig, ServerConnection, ServerConnectionData,
+        Accepted, Acceptor, AlpnPolicy, ReadEarlyData, ServerConfig, ServerConnection,
+        ServerConnectionData,
     };
     pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert};
 
@@ -479,6 +547,7 @@ pub use server::{ServerConfig, ServerConnection};
 ///
 /// [`ALL_CIPHER_SUITES`] is provided as an array of all of these values.
 pub mod cipher_suite {
+    pub use crate::suites::lookup;
     pub use crate::suites::CipherSuiteCommon;
     #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256;
@@ -517,5 +586,9 @@ pub mod sign;
 /// APIs for implementing QUIC TLS
 pub mod quic;
 
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
 /// This is the rustls manual.
 pub mod manual;
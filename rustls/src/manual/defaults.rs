@@ -20,6 +20,8 @@ is quicker than un-accelerated chacha20-poly1305.
 However, if you know your application will run on a platform without that, you should
 _definitely_ change the default order to prefer chacha20-poly1305: both the performance and
 the implementation security will be improved.  We think this is an uncommon case.
+If you're not sure, `ConfigBuilder::with_aes_hardware_detection()` makes this decision
+at runtime.
 
 ### Why is x25519 preferred for key exchange over nistp256?
 
@@ -7,7 +7,7 @@ use super::message::PlainMessage;
 use crate::enums::{ContentType, ProtocolVersion};
 use crate::error::{Error, InvalidMessage, PeerMisbehaved};
 use crate::msgs::codec;
-use crate::msgs::message::{MessageError, OpaqueMessage};
+use crate::msgs::message::{MessageError, MessageNeedsMore, OpaqueMessage};
 use crate::record_layer::{Decrypted, RecordLayer};
 
 /// This deframer works to reconstruct TLS messages from a stream of arbitrary-sized reads.
@@ -31,6 +31,12 @@ pub struct MessageDeframer {
 
     /// What size prefix of `buf` is used.
     used: usize,
+
+    /// Set by `pop()` if `buf` ends part-way through a message.
+    needs_more: Option<MessageNeedsMore>,
+
+    /// How many records have been parsed out of `buf`.
+    records_read: u64,
 }
 
 impl MessageDeframer {
@@ -40,6 +46,7 @@ impl MessageDeframer {
     /// failed, `Ok(None)` if no full message is buffered or if trial decryption failed, and
     /// `Ok(Some(_))` if a valid message was found and decrypted successfully.
     pub fn pop(&mut self, record_layer: &mut RecordLayer) -> Result<Option<Deframed>, Error> {
+        self.needs_more = None;
         if let Some(last_err) = self.last_error.clone() {
             return Err(last_err);
         } else if self.used == 0 {
@@ -56,7 +63,10 @@ impl MessageDeframer {
                         // We're joining a handshake payload, and we've seen the full payload.
                         Some(len) if len <= meta.payload.len() => break len,
                         // Not enough data, and we can't parse any more out of the buffer (QUIC).
-                        _ if meta.quic => return Ok(None),
+                        _ if meta.quic => {
+                            self.needs_more = Some(MessageNeedsMore { needed: None });
+                            return Ok(None);
+                        }
                         // Try parsing some more of the encrypted buffered data.
                         _ => meta.message.end,
                     }
@@ -69,11 +79,15 @@ impl MessageDeframer {
             // If so, deframe it and place the message onto the frames output queue.
             let mut rd = codec::Reader::init(&self.buf[start..self.used]);
             let m = match OpaqueMessage::read(&mut rd) {
-                Ok(m) => m,
+                Ok(m) => {
+                    self.records_read += 1;
+                    m
+                }
                 Err(msg_err) => {
                     let err_kind = match msg_err {
-                        MessageError::TooShortForHeader | MessageError::TooShortForLength => {
-                            return Ok(None)
+                        MessageError::NeedsMore(needs_more) => {
+                            self.needs_more = Some(needs_more);
+                            return Ok(None);
                         }
                         MessageError::InvalidEmptyPayload => InvalidMessage::InvalidEmptyPayload,
                         MessageError::MessageTooLarge => InvalidMessage::MessageTooLarge,
@@ -148,7 +162,12 @@ impl MessageDeframer {
             // If we don't know the payload size yet or if the payload size is larger
             // than the currently buffered payload, we need to wait for more data.
             match self.append_hs(msg.version, &msg.payload.0, end, false)? {
-                HandshakePayloadState::Blocked => return Ok(None),
+                // The rest of the handshake message will arrive in records we don't have yet,
+                // whose size we can't know.
+                HandshakePayloadState::Blocked => {
+                    self.needs_more = Some(MessageNeedsMore { needed: None });
+                    return Ok(None);
+                }
                 HandshakePayloadState::Complete(len) => break len,
                 HandshakePayloadState::Continue => continue,
             }
@@ -186,6 +205,17 @@ impl MessageDeframer {
         }))
     }
 
+    /// Whether the last call to `pop()` stopped part-way through a message, and
+    /// if so, how much more data is needed.
+    pub fn needs_more(&self) -> Option<MessageNeedsMore> {
+        self.needs_more
+    }
+
+    /// How many records `pop()` has parsed so far.
+    pub fn records_read(&self) -> u64 {
+        self.records_read
+    }
+
     /// Fuses this deframer's error and returns the set value.
     ///
     /// Any future calls to `pop` will return `err` again.
@@ -430,8 +460,8 @@ const READ_SIZE: usize = 4096;
 
 #[cfg(test)]
 mod tests {
-    use super::MessageDeframer;
-    use crate::msgs::message::{Message, OpaqueMessage};
+    use super::{MessageDeframer, READ_SIZE};
+    use crate::msgs::message::{Message, MessageNeedsMore, OpaqueMessage};
     use crate::record_layer::RecordLayer;
     use crate::{ContentType, Error, InvalidMessage};
 
@@ -526,6 +556,41 @@ mod tests {
         Message::try_from(m).unwrap();
     }
 
+    #[test]
+    fn check_needs_more_byte_at_a_time() {
+        let mut d = MessageDeframer::default();
+        let mut rl = RecordLayer::new();
+        assert!(d.pop(&mut rl).unwrap().is_none());
+        assert_eq!(d.needs_more(), None);
+
+        for (i, byte) in FIRST_MESSAGE[..FIRST_MESSAGE.len() - 1]
+            .iter()
+            .enumerate()
+        {
+            assert_len(1, input_bytes(&mut d, &[*byte]));
+            assert!(d.pop(&mut rl).unwrap().is_none());
+
+            let needed = match i + 1 {
+                read @ 0..=4 => 5 - read,
+                read => FIRST_MESSAGE.len() - read,
+            };
+            assert_eq!(
+                d.needs_more(),
+                Some(MessageNeedsMore {
+                    needed: Some(needed)
+                })
+            );
+        }
+
+        assert_len(
+            1,
+            input_bytes(&mut d, &FIRST_MESSAGE[FIRST_MESSAGE.len() - 1..]),
+        );
+        pop_first(&mut d, &mut rl);
+        assert_eq!(d.needs_more(), None);
+        assert!(d.last_error.is_none());
+    }
+
     #[test]
     fn check_incremental() {
         let mut d = MessageDeframer::default();
@@ -676,6 +741,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_oversized_length_rejected_before_buffering() {
+        // Headers claiming one byte more than allowed, and the largest length
+        // allowed.
+        let too_large = (OpaqueMessage::MAX_WIRE_SIZE - 5 + 1) as u16;
+        let largest = too_large - 1;
+
+        let mut d = MessageDeframer::default();
+        let mut header = vec![0x17, 0x03, 0x03];
+        header.extend_from_slice(&too_large.to_be_bytes());
+        assert_len(header.len(), input_bytes(&mut d, &header));
+
+        let mut rl = RecordLayer::new();
+        assert_eq!(
+            d.pop(&mut rl).unwrap_err(),
+            Error::InvalidMessage(InvalidMessage::MessageTooLarge)
+        );
+        assert!(d.buf.len() <= READ_SIZE);
+
+        let mut d = MessageDeframer::default();
+        let mut header = vec![0x17, 0x03, 0x03];
+        header.extend_from_slice(&largest.to_be_bytes());
+        assert_len(header.len(), input_bytes(&mut d, &header));
+        assert!(d.pop(&mut rl).unwrap().is_none());
+    }
+
     #[test]
     fn test_empty_applicationdata() {
         let mut d = MessageDeframer::default();
@@ -101,9 +101,14 @@ enum_builder! {
         UseSRTP => 0x000e,
         Heartbeat => 0x000f,
         ALProtocolNegotiation => 0x0010,
+        StatusRequestV2 => 0x0011,
         SCT => 0x0012,
+        ClientCertificateType => 0x0013,
+        ServerCertificateType => 0x0014,
         Padding => 0x0015,
         ExtendedMasterSecret => 0x0017,
+        CompressCertificate => 0x001b,
+        DelegatedCredential => 0x0022,
         SessionTicket => 0x0023,
         PreSharedKey => 0x0029,
         EarlyData => 0x002a,
@@ -275,7 +280,35 @@ enum_builder! {
     @U8
     EnumName: CertificateStatusType;
     EnumVal{
-        OCSP => 0x01
+        OCSP => 0x01,
+        OCSPMulti => 0x02
+    }
+}
+
+enum_builder! {
+    /// The `CertificateCompressionAlgorithm` TLS protocol enum.  Values in this
+    /// enum are taken from RFC8879, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: CertificateCompressionAlgorithm;
+    EnumVal{
+        Zlib => 0x0001,
+        Brotli => 0x0002,
+        Zstd => 0x0003
+    }
+}
+
+enum_builder! {
+    /// The `CertificateType` TLS protocol enum, used by the
+    /// `client_certificate_type` and `server_certificate_type` extensions
+    /// (RFC 7250).  Values in this enum are taken from the various RFCs
+    /// covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: CertificateType;
+    EnumVal{
+        X509 => 0x00,
+        RawPublicKey => 0x02
     }
 }
 
@@ -326,8 +359,13 @@ pub(crate) mod tests {
         );
         test_enum8::<CertificateStatusType>(
             CertificateStatusType::OCSP,
-            CertificateStatusType::OCSP,
+            CertificateStatusType::OCSPMulti,
+        );
+        test_enum16::<CertificateCompressionAlgorithm>(
+            CertificateCompressionAlgorithm::Zlib,
+            CertificateCompressionAlgorithm::Zstd,
         );
+        test_enum8::<CertificateType>(CertificateType::X509, CertificateType::RawPublicKey);
     }
 
     pub(crate) fn test_enum8<T: Codec>(first: T, last: T) {
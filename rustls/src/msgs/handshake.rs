@@ -9,8 +9,9 @@ use crate::log::warn;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec::{self, Codec, ListLength, Reader, TlsListElement};
 use crate::msgs::enums::{
-    CertificateStatusType, ClientCertificateType, Compression, ECCurveType, ECPointFormat,
-    ExtensionType, KeyUpdateRequest, NamedGroup, PSKKeyExchangeMode, ServerNameType,
+    CertificateCompressionAlgorithm, CertificateStatusType, CertificateType, ClientCertificateType,
+    Compression, ECCurveType, ECPointFormat, ExtensionType, KeyUpdateRequest, NamedGroup,
+    PSKKeyExchangeMode, ServerNameType,
 };
 use crate::rand;
 use crate::verify::DigitallySignedStruct;
@@ -430,6 +431,13 @@ impl PresharedKeyOffer {
             binders: vec![PresharedKeyBinder::from(binder)],
         }
     }
+
+    /// Add another entry.
+    pub fn push(&mut self, id: PresharedKeyIdentity, binder: Vec<u8>) {
+        self.identities.push(id);
+        self.binders
+            .push(PresharedKeyBinder::from(binder));
+    }
 }
 
 impl Codec for PresharedKeyOffer {
@@ -517,6 +525,59 @@ impl CertificateStatusRequest {
     }
 }
 
+// --- RFC6961 multiple certificate status request ---
+#[derive(Clone, Debug)]
+pub enum CertificateStatusRequestItemV2 {
+    OCSPMulti(OCSPCertificateStatusRequest),
+    Unknown((CertificateStatusType, PayloadU16)),
+}
+
+impl Codec for CertificateStatusRequestItemV2 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::OCSPMulti(ref r) => {
+                CertificateStatusType::OCSPMulti.encode(bytes);
+                let mut sub = Vec::new();
+                r.responder_ids.encode(&mut sub);
+                r.extensions.encode(&mut sub);
+                PayloadU16::new(sub).encode(bytes);
+            }
+            Self::Unknown((typ, payload)) => {
+                typ.encode(bytes);
+                payload.encode(bytes);
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        let typ = CertificateStatusType::read(r)?;
+        let request = PayloadU16::read(r)?;
+
+        match typ {
+            CertificateStatusType::OCSPMulti => {
+                let mut sub = Reader::init(&request.0);
+                let ocsp_req = OCSPCertificateStatusRequest::read(&mut sub)?;
+                sub.expect_empty("CertificateStatusRequestItemV2")
+                    .map(|_| Self::OCSPMulti(ocsp_req))
+            }
+            _ => Ok(Self::Unknown((typ, request))),
+        }
+    }
+}
+
+impl TlsListElement for CertificateStatusRequestItemV2 {
+    const SIZE_LEN: ListLength = ListLength::U16;
+}
+
+impl CertificateStatusRequestItemV2 {
+    pub fn build_ocsp_multi() -> Self {
+        Self::OCSPMulti(OCSPCertificateStatusRequest {
+            responder_ids: Vec::new(),
+            extensions: PayloadU16::empty(),
+        })
+    }
+}
+
 // ---
 
 impl TlsListElement for PSKKeyExchangeMode {
@@ -531,11 +592,20 @@ impl TlsListElement for ProtocolVersion {
     const SIZE_LEN: ListLength = ListLength::U8;
 }
 
+impl TlsListElement for CertificateCompressionAlgorithm {
+    const SIZE_LEN: ListLength = ListLength::U8;
+}
+
+impl TlsListElement for CertificateType {
+    const SIZE_LEN: ListLength = ListLength::U8;
+}
+
 #[derive(Clone, Debug)]
 pub enum ClientExtension {
     ECPointFormats(Vec<ECPointFormat>),
     NamedGroups(Vec<NamedGroup>),
     SignatureAlgorithms(Vec<SignatureScheme>),
+    SignatureAlgorithmsCert(Vec<SignatureScheme>),
     ServerName(Vec<ServerName>),
     SessionTicket(ClientSessionTicket),
     Protocols(Vec<ProtocolName>),
@@ -546,9 +616,14 @@ pub enum ClientExtension {
     Cookie(PayloadU16),
     ExtendedMasterSecretRequest,
     CertificateStatusRequest(CertificateStatusRequest),
+    CertificateStatusRequestV2(Vec<CertificateStatusRequestItemV2>),
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    CertificateCompressionAlgorithms(Vec<CertificateCompressionAlgorithm>),
+    DelegatedCredential(Vec<SignatureScheme>),
+    ClientCertificateType(Vec<CertificateType>),
+    ServerCertificateType(Vec<CertificateType>),
     Unknown(UnknownExtension),
 }
 
@@ -558,6 +633,7 @@ impl ClientExtension {
             Self::ECPointFormats(_) => ExtensionType::ECPointFormats,
             Self::NamedGroups(_) => ExtensionType::EllipticCurves,
             Self::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
+            Self::SignatureAlgorithmsCert(_) => ExtensionType::SignatureAlgorithmsCert,
             Self::ServerName(_) => ExtensionType::ServerName,
             Self::SessionTicket(_) => ExtensionType::SessionTicket,
             Self::Protocols(_) => ExtensionType::ALProtocolNegotiation,
@@ -568,9 +644,14 @@ impl ClientExtension {
             Self::Cookie(_) => ExtensionType::Cookie,
             Self::ExtendedMasterSecretRequest => ExtensionType::ExtendedMasterSecret,
             Self::CertificateStatusRequest(_) => ExtensionType::StatusRequest,
+            Self::CertificateStatusRequestV2(_) => ExtensionType::StatusRequestV2,
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::CertificateCompressionAlgorithms(_) => ExtensionType::CompressCertificate,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -584,7 +665,9 @@ impl Codec for ClientExtension {
         match *self {
             Self::ECPointFormats(ref r) => r.encode(&mut sub),
             Self::NamedGroups(ref r) => r.encode(&mut sub),
-            Self::SignatureAlgorithms(ref r) => r.encode(&mut sub),
+            Self::SignatureAlgorithms(ref r)
+            | Self::SignatureAlgorithmsCert(ref r)
+            | Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::ServerName(ref r) => r.encode(&mut sub),
             Self::SessionTicket(ClientSessionTicket::Request)
             | Self::ExtendedMasterSecretRequest
@@ -597,9 +680,14 @@ impl Codec for ClientExtension {
             Self::PresharedKey(ref r) => r.encode(&mut sub),
             Self::Cookie(ref r) => r.encode(&mut sub),
             Self::CertificateStatusRequest(ref r) => r.encode(&mut sub),
+            Self::CertificateStatusRequestV2(ref r) => r.encode(&mut sub),
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r);
             }
+            Self::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
+            Self::ClientCertificateType(ref r) | Self::ServerCertificateType(ref r) => {
+                r.encode(&mut sub);
+            }
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -616,6 +704,10 @@ impl Codec for ClientExtension {
             ExtensionType::ECPointFormats => Self::ECPointFormats(Vec::read(&mut sub)?),
             ExtensionType::EllipticCurves => Self::NamedGroups(Vec::read(&mut sub)?),
             ExtensionType::SignatureAlgorithms => Self::SignatureAlgorithms(Vec::read(&mut sub)?),
+            ExtensionType::SignatureAlgorithmsCert => {
+                Self::SignatureAlgorithmsCert(Vec::read(&mut sub)?)
+            }
+            ExtensionType::DelegatedCredential => Self::DelegatedCredential(Vec::read(&mut sub)?),
             ExtensionType::ServerName => Self::ServerName(Vec::read(&mut sub)?),
             ExtensionType::SessionTicket => {
                 if sub.any_left() {
@@ -638,11 +730,23 @@ impl Codec for ClientExtension {
                 let csr = CertificateStatusRequest::read(&mut sub)?;
                 Self::CertificateStatusRequest(csr)
             }
+            ExtensionType::StatusRequestV2 => {
+                Self::CertificateStatusRequestV2(Vec::read(&mut sub)?)
+            }
             ExtensionType::TransportParameters => Self::TransportParameters(sub.rest().to_vec()),
             ExtensionType::TransportParametersDraft => {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData if !sub.any_left() => Self::EarlyData,
+            ExtensionType::CompressCertificate => {
+                Self::CertificateCompressionAlgorithms(Vec::read(&mut sub)?)
+            }
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(Vec::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(Vec::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -695,10 +799,13 @@ pub enum ServerExtension {
     PresharedKey(u16),
     ExtendedMasterSecretAck,
     CertificateStatusAck,
+    CertificateStatusV2Ack,
     SupportedVersions(ProtocolVersion),
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    ClientCertificateType(CertificateType),
+    ServerCertificateType(CertificateType),
     Unknown(UnknownExtension),
 }
 
@@ -714,10 +821,13 @@ impl ServerExtension {
             Self::PresharedKey(_) => ExtensionType::PreSharedKey,
             Self::ExtendedMasterSecretAck => ExtensionType::ExtendedMasterSecret,
             Self::CertificateStatusAck => ExtensionType::StatusRequest,
+            Self::CertificateStatusV2Ack => ExtensionType::StatusRequestV2,
             Self::SupportedVersions(_) => ExtensionType::SupportedVersions,
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -734,6 +844,7 @@ impl Codec for ServerExtension {
             | Self::SessionTicketAck
             | Self::ExtendedMasterSecretAck
             | Self::CertificateStatusAck
+            | Self::CertificateStatusV2Ack
             | Self::EarlyData => {}
             Self::RenegotiationInfo(ref r) => r.encode(&mut sub),
             Self::Protocols(ref r) => r.encode(&mut sub),
@@ -743,6 +854,7 @@ impl Codec for ServerExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r);
             }
+            Self::ClientCertificateType(r) | Self::ServerCertificateType(r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -760,6 +872,7 @@ impl Codec for ServerExtension {
             ExtensionType::ServerName => Self::ServerNameAck,
             ExtensionType::SessionTicket => Self::SessionTicketAck,
             ExtensionType::StatusRequest => Self::CertificateStatusAck,
+            ExtensionType::StatusRequestV2 => Self::CertificateStatusV2Ack,
             ExtensionType::RenegotiationInfo => Self::RenegotiationInfo(PayloadU8::read(&mut sub)?),
             ExtensionType::ALProtocolNegotiation => Self::Protocols(Vec::read(&mut sub)?),
             ExtensionType::KeyShare => Self::KeyShare(KeyShareEntry::read(&mut sub)?),
@@ -773,6 +886,12 @@ impl Codec for ServerExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData => Self::EarlyData,
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(CertificateType::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -889,6 +1008,22 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_sigalgs_cert_extension(&self) -> Option<&[SignatureScheme]> {
+        let ext = self.find_extension(ExtensionType::SignatureAlgorithmsCert)?;
+        match *ext {
+            ClientExtension::SignatureAlgorithmsCert(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
+    pub fn get_delegated_credential_extension(&self) -> Option<&[SignatureScheme]> {
+        let ext = self.find_extension(ExtensionType::DelegatedCredential)?;
+        match *ext {
+            ClientExtension::DelegatedCredential(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
     pub fn get_namedgroups_extension(&self) -> Option<&[NamedGroup]> {
         let ext = self.find_extension(ExtensionType::EllipticCurves)?;
         match *ext {
@@ -944,6 +1079,38 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_cert_compression_extension(&self) -> Option<&[CertificateCompressionAlgorithm]> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            ClientExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
+
+    pub fn get_client_certificate_type_extension(&self) -> Option<&[CertificateType]> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ClientExtension::ClientCertificateType(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_server_certificate_type_extension(&self) -> Option<&[CertificateType]> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ClientExtension::ServerCertificateType(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_cookie_extension(&self) -> Option<&PayloadU16> {
+        let ext = self.find_extension(ExtensionType::Cookie)?;
+        match *ext {
+            ClientExtension::Cookie(ref ck) => Some(ck),
+            _ => None,
+        }
+    }
+
     pub fn has_keyshare_extension_with_duplicates(&self) -> bool {
         if let Some(entries) = self.get_keyshare_extension() {
             let mut seen = collections::HashSet::new();
@@ -988,10 +1155,10 @@ impl ClientHelloPayload {
             .unwrap_or(false)
     }
 
-    pub fn set_psk_binder(&mut self, binder: impl Into<Vec<u8>>) {
+    pub fn set_psk_binder(&mut self, index: usize, binder: impl Into<Vec<u8>>) {
         let last_extension = self.extensions.last_mut();
         if let Some(ClientExtension::PresharedKey(ref mut offer)) = last_extension {
-            offer.binders[0] = PresharedKeyBinder::from(binder.into());
+            offer.binders[index] = PresharedKeyBinder::from(binder.into());
         }
     }
 
@@ -1004,6 +1171,66 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    /// Return the JA3 fingerprint string of this ClientHello.
+    ///
+    /// This is the decimal legacy version, cipher suites, extension types,
+    /// named groups and point formats, in the order offered.  Values within
+    /// a field are separated by `-`, and the fields by `,`.  GREASE values
+    /// are left out.  The JA3 hash is the MD5 of this string.
+    pub fn ja3_string(&self) -> String {
+        fn field(values: impl Iterator<Item = u16>) -> String {
+            values
+                .filter(|v| !is_grease(*v))
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+
+        [
+            self.client_version
+                .get_u16()
+                .to_string(),
+            field(
+                self.cipher_suites
+                    .iter()
+                    .map(CipherSuite::get_u16),
+            ),
+            field(
+                self.extensions
+                    .iter()
+                    .map(|ext| ext.get_type().get_u16()),
+            ),
+            field(
+                self.get_namedgroups_extension()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(NamedGroup::get_u16),
+            ),
+            field(
+                self.get_ecpoints_extension()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|pf| u16::from(pf.get_u8())),
+            ),
+        ]
+        .join(",")
+    }
+
+    pub fn ocsp_multi_offered(&self) -> bool {
+        match self.find_extension(ExtensionType::StatusRequestV2) {
+            Some(ClientExtension::CertificateStatusRequestV2(items)) => items
+                .iter()
+                .any(|item| matches!(item, CertificateStatusRequestItemV2::OCSPMulti(_))),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `value` is one of the RFC8701 GREASE values, `0x?A?A`.
+pub(crate) fn is_grease(value: u16) -> bool {
+    let [hi, lo] = value.to_be_bytes();
+    hi == lo && lo & 0x0f == 0x0a
 }
 
 #[derive(Debug)]
@@ -1262,9 +1489,64 @@ impl TlsListElement for key::Certificate {
 // That's annoying. It means the parsing is not
 // context-free any more.
 
+/// A delegated credential (RFC 9345): a short-lived public key, signed by
+/// the key of the end-entity certificate it accompanies.
+#[derive(Clone, Debug)]
+pub struct DelegatedCredentialPayload {
+    /// Seconds from the certificate's notBefore until the credential expires.
+    pub valid_time: u32,
+    /// The scheme the credential's key signs CertificateVerify with.
+    pub dc_cert_verify_algorithm: SignatureScheme,
+    /// The DER-encoded SubjectPublicKeyInfo of the credential's key.
+    pub spki: PayloadU24,
+    /// The scheme the certificate's key signed the credential with.
+    pub algorithm: SignatureScheme,
+    pub signature: PayloadU16,
+}
+
+impl DelegatedCredentialPayload {
+    /// The message the certificate `end_entity` signs to make this
+    /// credential.
+    pub fn signed_message(&self, end_entity: &key::Certificate) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.resize(64, 0x20u8);
+        msg.extend_from_slice(b"TLS, server delegated credentials\x00");
+        msg.extend_from_slice(&end_entity.0);
+        self.encode_credential(&mut msg);
+        self.algorithm.encode(&mut msg);
+        msg
+    }
+
+    fn encode_credential(&self, bytes: &mut Vec<u8>) {
+        self.valid_time.encode(bytes);
+        self.dc_cert_verify_algorithm
+            .encode(bytes);
+        self.spki.encode(bytes);
+    }
+}
+
+impl Codec for DelegatedCredentialPayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.encode_credential(bytes);
+        self.algorithm.encode(bytes);
+        self.signature.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        Ok(Self {
+            valid_time: u32::read(r)?,
+            dc_cert_verify_algorithm: SignatureScheme::read(r)?,
+            spki: PayloadU24::read(r)?,
+            algorithm: SignatureScheme::read(r)?,
+            signature: PayloadU16::read(r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
+    DelegatedCredential(DelegatedCredentialPayload),
     Unknown(UnknownExtension),
 }
 
@@ -1272,6 +1554,7 @@ impl CertificateExtension {
     pub fn get_type(&self) -> ExtensionType {
         match *self {
             Self::CertificateStatus(_) => ExtensionType::StatusRequest,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -1282,6 +1565,13 @@ impl CertificateExtension {
             _ => None,
         }
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        match *self {
+            Self::DelegatedCredential(ref dc) => Some(dc),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1291,6 +1581,7 @@ impl Codec for CertificateExtension {
         let mut sub: Vec<u8> = Vec::new();
         match *self {
             Self::CertificateStatus(ref r) => r.encode(&mut sub),
+            Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1308,6 +1599,9 @@ impl Codec for CertificateExtension {
                 let st = CertificateStatus::read(&mut sub)?;
                 Self::CertificateStatus(st)
             }
+            ExtensionType::DelegatedCredential => {
+                Self::DelegatedCredential(DelegatedCredentialPayload::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1364,9 +1658,10 @@ impl CertificateEntry {
     }
 
     pub fn has_unknown_extension(&self) -> bool {
-        self.exts
-            .iter()
-            .any(|ext| ext.get_type() != ExtensionType::StatusRequest)
+        self.exts.iter().any(|ext| {
+            ext.get_type() != ExtensionType::StatusRequest
+                && ext.get_type() != ExtensionType::DelegatedCredential
+        })
     }
 
     pub fn get_ocsp_response(&self) -> Option<&Vec<u8>> {
@@ -1375,12 +1670,42 @@ impl CertificateEntry {
             .find(|ext| ext.get_type() == ExtensionType::StatusRequest)
             .and_then(CertificateExtension::get_cert_status)
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.exts
+            .iter()
+            .find_map(CertificateExtension::get_delegated_credential)
+    }
 }
 
 impl TlsListElement for CertificateEntry {
     const SIZE_LEN: ListLength = ListLength::U24 { max: 0x1_0000 };
 }
 
+/// A TLS1.3 `Certificate` message, compressed as described in RFC8879.
+#[derive(Clone, Debug)]
+pub struct CompressedCertificatePayload {
+    pub alg: CertificateCompressionAlgorithm,
+    pub uncompressed_len: u32,
+    pub compressed: PayloadU24,
+}
+
+impl Codec for CompressedCertificatePayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.alg.encode(bytes);
+        codec::u24(self.uncompressed_len).encode(bytes);
+        self.compressed.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        Ok(Self {
+            alg: CertificateCompressionAlgorithm::read(r)?,
+            uncompressed_len: codec::u24::read(r)?.0,
+            compressed: PayloadU24::read(r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct CertificatePayloadTLS13 {
     pub context: PayloadU8,
@@ -1439,6 +1764,24 @@ impl CertificatePayloadTLS13 {
         false
     }
 
+    /// Whether any entry but the end-entity's carries a delegated credential.
+    pub fn any_intermediate_has_delegated_credential(&self) -> bool {
+        self.entries
+            .iter()
+            .skip(1)
+            .any(|entry| {
+                entry
+                    .get_delegated_credential()
+                    .is_some()
+            })
+    }
+
+    pub fn get_end_entity_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.entries
+            .first()
+            .and_then(CertificateEntry::get_delegated_credential)
+    }
+
     pub fn get_end_entity_ocsp(&self) -> Vec<u8> {
         self.entries
             .first()
@@ -1658,6 +2001,22 @@ pub trait HasServerExtensions {
         }
     }
 
+    fn get_client_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ServerExtension::ClientCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_server_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ServerExtension::ServerCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
     fn early_data_extension_offered(&self) -> bool {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
@@ -2031,6 +2390,57 @@ impl CertificateStatus {
     }
 }
 
+/// The RFC6961 `ocsp_multi` form of the CertificateStatus message.
+///
+/// This has an OCSP response for each certificate in the chain, in the
+/// same order.  Responses are empty where the server has none.
+#[derive(Debug)]
+pub struct CertificateStatusMulti {
+    pub ocsp_responses: Vec<PayloadU24>,
+}
+
+impl TlsListElement for PayloadU24 {
+    const SIZE_LEN: ListLength = ListLength::U24 { max: 0x1_0000 };
+}
+
+impl Codec for CertificateStatusMulti {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        CertificateStatusType::OCSPMulti.encode(bytes);
+        self.ocsp_responses.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        match CertificateStatusType::read(r)? {
+            CertificateStatusType::OCSPMulti => Self::read_responses(r),
+            _ => Err(InvalidMessage::InvalidCertificateStatusType),
+        }
+    }
+}
+
+impl CertificateStatusMulti {
+    pub fn new(ocsp_responses: Vec<Vec<u8>>) -> Self {
+        Self {
+            ocsp_responses: ocsp_responses
+                .into_iter()
+                .map(PayloadU24::new)
+                .collect(),
+        }
+    }
+
+    fn read_responses(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        Ok(Self {
+            ocsp_responses: Vec::read(r)?,
+        })
+    }
+
+    pub fn into_inner(self) -> Vec<Vec<u8>> {
+        self.ocsp_responses
+            .into_iter()
+            .map(|response| response.0)
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub enum HandshakePayload {
     HelloRequest,
@@ -2039,6 +2449,7 @@ pub enum HandshakePayload {
     HelloRetryRequest(HelloRetryRequest),
     Certificate(CertificatePayload),
     CertificateTLS13(CertificatePayloadTLS13),
+    CompressedCertificate(CompressedCertificatePayload),
     ServerKeyExchange(ServerKeyExchangePayload),
     CertificateRequest(CertificateRequestPayload),
     CertificateRequestTLS13(CertificateRequestPayloadTLS13),
@@ -2052,6 +2463,7 @@ pub enum HandshakePayload {
     KeyUpdate(KeyUpdateRequest),
     Finished(Payload),
     CertificateStatus(CertificateStatus),
+    CertificateStatusMulti(CertificateStatusMulti),
     MessageHash(Payload),
     Unknown(Payload),
 }
@@ -2066,6 +2478,7 @@ impl HandshakePayload {
             HelloRetryRequest(ref x) => x.encode(bytes),
             Certificate(ref x) => x.encode(bytes),
             CertificateTLS13(ref x) => x.encode(bytes),
+            CompressedCertificate(ref x) => x.encode(bytes),
             ServerKeyExchange(ref x) => x.encode(bytes),
             ClientKeyExchange(ref x) => x.encode(bytes),
             CertificateRequest(ref x) => x.encode(bytes),
@@ -2077,6 +2490,7 @@ impl HandshakePayload {
             KeyUpdate(ref x) => x.encode(bytes),
             Finished(ref x) => x.encode(bytes),
             CertificateStatus(ref x) => x.encode(bytes),
+            CertificateStatusMulti(ref x) => x.encode(bytes),
             MessageHash(ref x) => x.encode(bytes),
             Unknown(ref x) => x.encode(bytes),
         }
@@ -2144,6 +2558,10 @@ impl HandshakeMessagePayload {
             HandshakeType::Certificate => {
                 HandshakePayload::Certificate(CertificatePayload::read(&mut sub)?)
             }
+            HandshakeType::CompressedCertificate => {
+                let p = CompressedCertificatePayload::read(&mut sub)?;
+                HandshakePayload::CompressedCertificate(p)
+            }
             HandshakeType::ServerKeyExchange => {
                 let p = ServerKeyExchangePayload::read(&mut sub)?;
                 HandshakePayload::ServerKeyExchange(p)
@@ -2185,9 +2603,17 @@ impl HandshakeMessagePayload {
                 HandshakePayload::EndOfEarlyData
             }
             HandshakeType::Finished => HandshakePayload::Finished(Payload::read(&mut sub)),
-            HandshakeType::CertificateStatus => {
-                HandshakePayload::CertificateStatus(CertificateStatus::read(&mut sub)?)
-            }
+            HandshakeType::CertificateStatus => match CertificateStatusType::read(&mut sub)? {
+                CertificateStatusType::OCSP => {
+                    HandshakePayload::CertificateStatus(CertificateStatus {
+                        ocsp_response: PayloadU24::read(&mut sub)?,
+                    })
+                }
+                CertificateStatusType::OCSPMulti => HandshakePayload::CertificateStatusMulti(
+                    CertificateStatusMulti::read_responses(&mut sub)?,
+                ),
+                _ => return Err(InvalidMessage::InvalidCertificateStatusType),
+            },
             HandshakeType::MessageHash => {
                 // does not appear on the wire
                 return Err(InvalidMessage::UnexpectedMessage("MessageHash"));
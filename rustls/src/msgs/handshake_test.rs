@@ -4,14 +4,15 @@ use crate::key::Certificate;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec::{put_u16, Codec, Reader};
 use crate::msgs::enums::{
-    ClientCertificateType, Compression, ECCurveType, ECPointFormat, ExtensionType,
+    CertificateType, ClientCertificateType, Compression, ECCurveType, ECPointFormat, ExtensionType,
     KeyUpdateRequest, NamedGroup, PSKKeyExchangeMode, ServerNameType,
 };
 use crate::msgs::handshake::{
     CertReqExtension, CertificateEntry, CertificateExtension, CertificatePayloadTLS13,
     CertificateRequestPayload, CertificateRequestPayloadTLS13, CertificateStatus,
-    CertificateStatusRequest, ClientExtension, ClientHelloPayload, ClientSessionTicket,
-    ConvertProtocolNameList, ConvertServerNameList, DistinguishedName, ECDHEServerKeyExchange,
+    CertificateStatusMulti, CertificateStatusRequest, CertificateStatusRequestItemV2,
+    ClientExtension, ClientHelloPayload, ClientSessionTicket, ConvertProtocolNameList,
+    ConvertServerNameList, DelegatedCredentialPayload, DistinguishedName, ECDHEServerKeyExchange,
     ECParameters, HandshakeMessagePayload, HandshakePayload, HasServerExtensions,
     HelloRetryExtension, HelloRetryRequest, KeyShareEntry, NewSessionTicketExtension,
     NewSessionTicketPayload, NewSessionTicketPayloadTLS13, PresharedKeyBinder,
@@ -303,6 +304,24 @@ fn can_roundtrip_certstatusreq_for_ocsp() {
     assert_eq!(csr.get_encoding(), bytes.to_vec());
 }
 
+#[test]
+fn can_roundtrip_certstatusreqv2_for_ocsp_multi() {
+    let ext = ClientExtension::CertificateStatusRequestV2(vec![
+        CertificateStatusRequestItemV2::build_ocsp_multi(),
+    ]);
+    let bytes = [
+        0, 17, // CertificateStatusRequestV2
+        0, 9, 0, 7, // list of one item
+        2, // OCSPMulti
+        0, 4, 0, 0, 0, 0,
+    ];
+    assert_eq!(ext.get_encoding(), bytes.to_vec());
+
+    let csr = ClientExtension::read(&mut Reader::init(&bytes)).unwrap();
+    println!("{:?}", csr);
+    assert_eq!(csr.get_encoding(), bytes.to_vec());
+}
+
 #[test]
 fn can_roundtrip_certstatusreq_for_other() {
     let bytes = [
@@ -365,6 +384,7 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::ECPointFormats(ECPointFormat::SUPPORTED.to_vec()),
             ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
             ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
+            ClientExtension::SignatureAlgorithmsCert(vec![SignatureScheme::RSA_PKCS1_SHA1]),
             ClientExtension::make_sni(DnsNameRef::try_from("hello").unwrap()),
             ClientExtension::SessionTicket(ClientSessionTicket::Request),
             ClientExtension::SessionTicket(ClientSessionTicket::Offer(Payload(vec![]))),
@@ -385,7 +405,16 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::Cookie(PayloadU16(vec![1, 2, 3])),
             ClientExtension::ExtendedMasterSecretRequest,
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
+            ClientExtension::CertificateStatusRequestV2(vec![
+                CertificateStatusRequestItemV2::build_ocsp_multi(),
+            ]),
             ClientExtension::TransportParameters(vec![1, 2, 3]),
+            ClientExtension::DelegatedCredential(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
+            ClientExtension::ClientCertificateType(vec![CertificateType::RawPublicKey]),
+            ClientExtension::ServerCertificateType(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -513,6 +542,38 @@ fn client_get_sigalgs_extension() {
     });
 }
 
+#[test]
+fn client_get_sigalgs_cert_extension() {
+    test_client_extension_getter(ExtensionType::SignatureAlgorithmsCert, |chp| {
+        chp.get_sigalgs_cert_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_delegated_credential_extension() {
+    test_client_extension_getter(ExtensionType::DelegatedCredential, |chp| {
+        chp.get_delegated_credential_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_client_certificate_type_extension() {
+    test_client_extension_getter(ExtensionType::ClientCertificateType, |chp| {
+        chp.get_client_certificate_type_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_server_certificate_type_extension() {
+    test_client_extension_getter(ExtensionType::ServerCertificateType, |chp| {
+        chp.get_server_certificate_type_extension()
+            .is_some()
+    });
+}
+
 #[test]
 fn client_get_namedgroups_extension() {
     test_client_extension_getter(ExtensionType::EllipticCurves, |chp| {
@@ -736,6 +797,13 @@ fn certentry_get_ocsp_response() {
     });
 }
 
+#[test]
+fn certentry_get_delegated_credential() {
+    test_cert_extension_getter(ExtensionType::DelegatedCredential, |ce| {
+        ce.get_delegated_credential().is_some()
+    });
+}
+
 fn get_sample_serverhellopayload() -> ServerHelloPayload {
     ServerHelloPayload {
         legacy_version: ProtocolVersion::TLSv1_2,
@@ -753,8 +821,11 @@ fn get_sample_serverhellopayload() -> ServerHelloPayload {
             ServerExtension::PresharedKey(3),
             ServerExtension::ExtendedMasterSecretAck,
             ServerExtension::CertificateStatusAck,
+            ServerExtension::CertificateStatusV2Ack,
             ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_2),
             ServerExtension::TransportParameters(vec![1, 2, 3]),
+            ServerExtension::ClientCertificateType(CertificateType::RawPublicKey),
+            ServerExtension::ServerCertificateType(CertificateType::X509),
             ServerExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -799,6 +870,13 @@ fn get_sample_certificatepayloadtls13() -> CertificatePayloadTLS13 {
                 CertificateExtension::CertificateStatus(CertificateStatus {
                     ocsp_response: PayloadU24(vec![1, 2, 3]),
                 }),
+                CertificateExtension::DelegatedCredential(DelegatedCredentialPayload {
+                    valid_time: 3600,
+                    dc_cert_verify_algorithm: SignatureScheme::ECDSA_NISTP256_SHA256,
+                    spki: PayloadU24(vec![1, 2, 3]),
+                    algorithm: SignatureScheme::RSA_PSS_SHA256,
+                    signature: PayloadU16(vec![4, 5, 6]),
+                }),
                 CertificateExtension::Unknown(UnknownExtension {
                     typ: ExtensionType::Unknown(12345),
                     payload: Payload(vec![1, 2, 3]),
@@ -877,6 +955,10 @@ fn get_sample_certificatestatus() -> CertificateStatus {
     }
 }
 
+fn get_sample_certificatestatusmulti() -> CertificateStatusMulti {
+    CertificateStatusMulti::new(vec![vec![1, 2, 3], vec![], vec![4, 5]])
+}
+
 fn get_all_tls12_handshake_payloads() -> Vec<HandshakeMessagePayload> {
     vec![
         HandshakeMessagePayload {
@@ -947,6 +1029,10 @@ fn get_all_tls12_handshake_payloads() -> Vec<HandshakeMessagePayload> {
             typ: HandshakeType::CertificateStatus,
             payload: HandshakePayload::CertificateStatus(get_sample_certificatestatus()),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::CertificateStatus,
+            payload: HandshakePayload::CertificateStatusMulti(get_sample_certificatestatusmulti()),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::Unknown(99),
             payload: HandshakePayload::Unknown(Payload(vec![1, 2, 3])),
@@ -1195,3 +1281,31 @@ fn can_decode_server_hello_from_api_devicecheck_apple_com() {
     let hm = HandshakeMessagePayload::read(&mut r).unwrap();
     println!("msg: {:?}", hm);
 }
+
+#[test]
+fn ja3_string_of_known_client_hello() {
+    let bytes = include_bytes!("../../../fuzz/corpus/handshake/tls13-client-hello.bin");
+    let hmp =
+        HandshakeMessagePayload::read_version(&mut Reader::init(bytes), ProtocolVersion::TLSv1_3)
+            .unwrap();
+    let mut ch = match hmp.payload {
+        HandshakePayload::ClientHello(ch) => ch,
+        _ => unreachable!(),
+    };
+
+    let expected = "771,4866-4865-4867-49196-49195-52393-49200-49199-52392-255,\
+                    43-11-10-13-23-5-0-51-45-35,29-23-24,0";
+    assert_eq!(ch.ja3_string(), expected);
+
+    // GREASE values are ignored
+    ch.cipher_suites
+        .insert(0, CipherSuite::Unknown(0x1a1a));
+    ch.extensions.insert(
+        0,
+        ClientExtension::Unknown(UnknownExtension {
+            typ: ExtensionType::Unknown(0xfafa),
+            payload: Payload::empty(),
+        }),
+    );
+    assert_eq!(ch.ja3_string(), expected);
+}
@@ -84,13 +84,17 @@ impl OpaqueMessage {
     /// `MessageError` allows callers to distinguish between valid prefixes (might
     /// become valid if we read more data) and invalid data.
     pub fn read(r: &mut Reader) -> Result<Self, MessageError> {
-        let typ = ContentType::read(r).map_err(|_| MessageError::TooShortForHeader)?;
+        let available = r.left();
+        let too_short_for_header =
+            |_| MessageError::needs_more(usize::from(Self::HEADER_SIZE) - available);
+
+        let typ = ContentType::read(r).map_err(too_short_for_header)?;
         // Don't accept any new content-types.
         if let ContentType::Unknown(_) = typ {
             return Err(MessageError::InvalidContentType);
         }
 
-        let version = ProtocolVersion::read(r).map_err(|_| MessageError::TooShortForHeader)?;
+        let version = ProtocolVersion::read(r).map_err(too_short_for_header)?;
         // Accept only versions 0x03XX for any XX.
         match version {
             ProtocolVersion::Unknown(ref v) if (v & 0xff00) != 0x0300 => {
@@ -99,7 +103,7 @@ impl OpaqueMessage {
             _ => {}
         };
 
-        let len = u16::read(r).map_err(|_| MessageError::TooShortForHeader)?;
+        let len = u16::read(r).map_err(too_short_for_header)?;
 
         // Reject undersize messages
         //  implemented per section 5.1 of RFC8446 (TLSv1.3)
@@ -109,13 +113,14 @@ impl OpaqueMessage {
         }
 
         // Reject oversize messages
-        if len >= Self::MAX_PAYLOAD {
+        if len > Self::MAX_PAYLOAD {
             return Err(MessageError::MessageTooLarge);
         }
 
+        let left = r.left();
         let mut sub = r
             .sub(len as usize)
-            .map_err(|_| MessageError::TooShortForLength)?;
+            .map_err(|_| MessageError::needs_more(len as usize - left))?;
         let payload = Payload::read(&mut sub);
 
         Ok(Self {
@@ -126,14 +131,26 @@ impl OpaqueMessage {
     }
 
     pub fn encode(self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        self.typ.encode(&mut buf);
-        self.version.encode(&mut buf);
-        (self.payload.0.len() as u16).encode(&mut buf);
-        self.payload.encode(&mut buf);
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
         buf
     }
 
+    /// The length of this message on the wire.
+    pub(crate) fn encoded_len(&self) -> usize {
+        usize::from(Self::HEADER_SIZE) + self.payload.0.len()
+    }
+
+    /// Append this message's wire encoding to `buf`, returning the
+    /// buffer which held its payload so it may be reused.
+    pub(crate) fn encode_into(self, buf: &mut Vec<u8>) -> Vec<u8> {
+        self.typ.encode(buf);
+        self.version.encode(buf);
+        (self.payload.0.len() as u16).encode(buf);
+        buf.extend_from_slice(&self.payload.0);
+        self.payload.0
+    }
+
     /// Force conversion into a plaintext message.
     ///
     /// This should only be used for messages that are known to be in plaintext. Otherwise, the
@@ -283,10 +300,29 @@ impl<'a> BorrowedPlainMessage<'a> {
 
 #[derive(Debug)]
 pub enum MessageError {
-    TooShortForHeader,
-    TooShortForLength,
+    /// The data is a valid prefix of a message.
+    NeedsMore(MessageNeedsMore),
     InvalidEmptyPayload,
     MessageTooLarge,
     InvalidContentType,
     UnknownProtocolVersion,
 }
+
+impl MessageError {
+    fn needs_more(needed: usize) -> Self {
+        Self::NeedsMore(MessageNeedsMore {
+            needed: Some(needed),
+        })
+    }
+}
+
+/// Received TLS data ends part-way through a message.
+///
+/// This is not an error: the message can be processed once the
+/// rest of it has been received.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageNeedsMore {
+    /// The minimum number of further bytes needed before any more
+    /// progress can be made, if this is known.
+    pub needed: Option<usize>,
+}
@@ -13,6 +13,7 @@ pub mod enums;
 pub mod fragmenter;
 pub mod handshake;
 pub mod message;
+pub mod named_group;
 pub mod persist;
 
 #[cfg(test)]
@@ -0,0 +1,95 @@
+//! Names for [`NamedGroup`]s.
+//!
+//! This is kept apart from `enums`, which is generated.
+
+use crate::msgs::enums::NamedGroup;
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+impl NamedGroup {
+    /// The conventional lower-case name for this group, eg. `x25519` or `secp256r1`.
+    ///
+    /// These are the names in the IANA "TLS Supported Groups" registry.
+    /// Returns `None` for unrecognised groups.
+    pub fn name(&self) -> Option<&'static str> {
+        NAMED_GROUP_NAMES
+            .iter()
+            .find(|(group, _)| group == self)
+            .map(|(_, name)| *name)
+    }
+}
+
+static NAMED_GROUP_NAMES: &[(NamedGroup, &str)] = &[
+    (NamedGroup::secp256r1, "secp256r1"),
+    (NamedGroup::secp384r1, "secp384r1"),
+    (NamedGroup::secp521r1, "secp521r1"),
+    (NamedGroup::X25519, "x25519"),
+    (NamedGroup::X448, "x448"),
+    (NamedGroup::FFDHE2048, "ffdhe2048"),
+    (NamedGroup::FFDHE3072, "ffdhe3072"),
+    (NamedGroup::FFDHE4096, "ffdhe4096"),
+    (NamedGroup::FFDHE6144, "ffdhe6144"),
+    (NamedGroup::FFDHE8192, "ffdhe8192"),
+];
+
+impl fmt::Display for NamedGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Unknown ({:#06x})", self.get_u16()),
+        }
+    }
+}
+
+/// Parses the names produced by `NamedGroup`'s `Display` implementation.
+///
+/// Matching is ASCII case-insensitive, so `X25519` and `x25519` are equivalent.
+impl FromStr for NamedGroup {
+    type Err = UnknownNamedGroupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NAMED_GROUP_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(group, _)| *group)
+            .ok_or_else(|| UnknownNamedGroupError(s.to_string()))
+    }
+}
+
+/// The provided string did not name a known [`NamedGroup`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownNamedGroupError(String);
+
+impl fmt::Display for UnknownNamedGroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown named group {:?}", self.0)
+    }
+}
+
+impl StdError for UnknownNamedGroupError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_group_names() {
+        for (group, name) in NAMED_GROUP_NAMES {
+            assert_eq!(group.to_string(), *name);
+            assert_eq!(name.parse::<NamedGroup>(), Ok(*group));
+        }
+
+        assert_eq!(NamedGroup::X25519.to_string(), "x25519");
+        assert_eq!(NamedGroup::secp256r1.to_string(), "secp256r1");
+        assert_eq!("X25519".parse::<NamedGroup>(), Ok(NamedGroup::X25519));
+        assert_eq!(NamedGroup::Unknown(0x1234).to_string(), "Unknown (0x1234)");
+        assert_eq!(NamedGroup::Unknown(0x1234).name(), None);
+
+        let err = "curve9999"
+            .parse::<NamedGroup>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown named group \"curve9999\"");
+    }
+}
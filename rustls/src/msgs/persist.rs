@@ -6,7 +6,7 @@ use crate::msgs::base::{PayloadU16, PayloadU8};
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SessionId;
-use crate::ticketer::TimeBase;
+use crate::time::UnixTime;
 #[cfg(feature = "tls12")]
 use crate::tls12::Tls12CipherSuite;
 use crate::tls13::Tls13CipherSuite;
@@ -17,11 +17,11 @@ use std::mem;
 
 pub struct Retrieved<T> {
     pub value: T,
-    retrieved_at: TimeBase,
+    retrieved_at: UnixTime,
 }
 
 impl<T> Retrieved<T> {
-    pub fn new(value: T, retrieved_at: TimeBase) -> Self {
+    pub fn new(value: T, retrieved_at: UnixTime) -> Self {
         Self {
             value,
             retrieved_at,
@@ -82,7 +82,7 @@ impl Tls13ClientSessionValue {
         ticket: Vec<u8>,
         secret: Vec<u8>,
         server_cert_chain: Vec<key::Certificate>,
-        time_now: TimeBase,
+        time_now: UnixTime,
         lifetime_secs: u32,
         age_add: u32,
         max_early_data_size: u32,
@@ -157,7 +157,7 @@ impl Tls12ClientSessionValue {
         ticket: Vec<u8>,
         master_secret: Vec<u8>,
         server_cert_chain: Vec<key::Certificate>,
-        time_now: TimeBase,
+        time_now: UnixTime,
         lifetime_secs: u32,
         extended_ms: bool,
     ) -> Self {
@@ -216,7 +216,7 @@ impl ClientSessionCommon {
     fn new(
         ticket: Vec<u8>,
         secret: Vec<u8>,
-        time_now: TimeBase,
+        time_now: UnixTime,
         lifetime_secs: u32,
         server_cert_chain: Vec<key::Certificate>,
     ) -> Self {
@@ -358,7 +358,7 @@ impl ServerSessionValue {
         client_cert_chain: Option<CertificatePayload>,
         alpn: Option<Vec<u8>>,
         application_data: Vec<u8>,
-        creation_time: TimeBase,
+        creation_time: UnixTime,
         age_obfuscation_offset: u32,
     ) -> Self {
         Self {
@@ -380,7 +380,7 @@ impl ServerSessionValue {
         self.extended_ms = true;
     }
 
-    pub fn set_freshness(mut self, obfuscated_client_age_ms: u32, time_now: TimeBase) -> Self {
+    pub fn set_freshness(mut self, obfuscated_client_age_ms: u32, time_now: UnixTime) -> Self {
         let client_age_ms = obfuscated_client_age_ms.wrapping_sub(self.age_obfuscation_offset);
         let server_age_ms = (time_now
             .as_secs()
@@ -407,7 +407,7 @@ mod tests {
     use super::*;
     use crate::enums::*;
     use crate::msgs::codec::{Codec, Reader};
-    use crate::ticketer::TimeBase;
+    use crate::time::UnixTime;
 
     #[test]
     fn serversessionvalue_is_debug() {
@@ -419,7 +419,7 @@ mod tests {
             None,
             None,
             vec![4, 5, 6],
-            TimeBase::now().unwrap(),
+            UnixTime::since_unix_epoch(std::time::Duration::from_secs(1_700_000_000)),
             0x12345678,
         );
         println!("{:?}", ssv);
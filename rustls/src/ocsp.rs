@@ -0,0 +1,268 @@
+// Verification of stapled OCSP responses (RFC 6960), for certificates which
+// require them.
+
+use crate::anchors::RootCertStore;
+use crate::error::{CertificateError, Error};
+use crate::key::Certificate;
+use crate::x509::{der_expect, der_next, der_raw, generalized_time, CertificateFields};
+
+use ring::signature;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Verify that `response` is a DER-encoded OCSP response, signed by the
+/// issuer of `end_entity`, saying `end_entity` is not revoked at `now`.
+///
+/// The issuer must be one of `intermediates` or a trust anchor in `roots`,
+/// and must have signed `end_entity`.  Responses signed by a delegated OCSP
+/// responder are not supported.
+pub(crate) fn verify_response(
+    response: &[u8],
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    roots: &RootCertStore,
+    now: SystemTime,
+) -> Result<(), Error> {
+    if response.is_empty() {
+        return Err(CertificateError::MissingOcspResponse.into());
+    }
+
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::FailedToGetCurrentTime)?
+        .as_secs();
+
+    let status = response_status(response, end_entity, intermediates, roots)
+        .ok_or(CertificateError::InvalidOcspResponse)?;
+
+    if now < status.this_update
+        || status
+            .next_update
+            .map_or(false, |next_update| next_update <= now)
+    {
+        return Err(CertificateError::InvalidOcspResponse.into());
+    }
+
+    match status.cert_status {
+        GOOD => Ok(()),
+        REVOKED => Err(CertificateError::Revoked.into()),
+        _ => Err(CertificateError::InvalidOcspResponse.into()),
+    }
+}
+
+/// A certificate or trust anchor which may have issued the end-entity
+/// certificate.
+struct Issuer<'a> {
+    /// The subject Name, including its tag and length.
+    subject: &'a [u8],
+    /// The contents of the subjectPublicKeyInfo SEQUENCE.
+    spki: &'a [u8],
+}
+
+impl<'a> Issuer<'a> {
+    /// The public key, without the count of unused bits.
+    fn public_key(&self) -> Option<&'a [u8]> {
+        let (_algorithm, rest) = der_expect(self.spki, 0x30)?;
+        let (key, _) = der_expect(rest, 0x03)?;
+        key.get(1..)
+    }
+}
+
+/// The parts of a SingleResponse we check.
+struct Status {
+    cert_status: u8,
+    this_update: u64,
+    next_update: Option<u64>,
+}
+
+// CertStatus tags.
+const GOOD: u8 = 0x80;
+const REVOKED: u8 = 0xa1;
+
+/// Check the signature on `response`, and find the status it gives for
+/// `end_entity`.
+fn response_status(
+    response: &[u8],
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    roots: &RootCertStore,
+) -> Option<Status> {
+    // id-pkix-ocsp-basic
+    const BASIC_RESPONSE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+    // Matching the issuer's name is not enough: a server may send a
+    // certificate with the right name but its own key, which path
+    // building ignores.  The issuer must have signed `end_entity`.
+    let cert = CertificateFields::parse(&end_entity.0)?;
+    let (cert_tbs, cert_algorithm, cert_signature) = signed_parts(&end_entity.0)?;
+    let issuer = intermediates
+        .iter()
+        .filter_map(|candidate| CertificateFields::parse(&candidate.0))
+        .map(|fields| Issuer {
+            subject: fields.subject,
+            spki: fields.spki,
+        })
+        .chain(roots.roots.iter().map(|anchor| Issuer {
+            subject: anchor.subject().as_ref(),
+            spki: anchor.to_trust_anchor().spki,
+        }))
+        .find(|candidate| {
+            candidate.subject == cert.issuer
+                && is_signed_by(candidate, cert_tbs, cert_algorithm, cert_signature)
+        })?;
+
+    // OCSPResponse: a successful responseStatus, and responseBytes.
+    let (response, _) = der_expect(response, 0x30)?;
+    let (response_status, rest) = der_expect(response, 0x0a)?;
+    if response_status != [0] {
+        return None;
+    }
+    let (response_bytes, _) = der_expect(rest, 0xa0)?;
+    let (response_bytes, _) = der_expect(response_bytes, 0x30)?;
+    let (response_type, rest) = der_expect(response_bytes, 0x06)?;
+    if response_type != BASIC_RESPONSE {
+        return None;
+    }
+    let (basic, _) = der_expect(rest, 0x04)?;
+
+    // BasicOCSPResponse: signed by the issuer.
+    let (tbs, signature_algorithm, signature) = signed_parts(basic)?;
+    if !is_signed_by(&issuer, tbs, signature_algorithm, signature) {
+        return None;
+    }
+
+    // ResponseData: skip the optional version, responderID and producedAt.
+    let (mut tbs, _) = der_expect(tbs, 0x30)?;
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.2;
+    }
+    let (_responder_id, rest) = der_raw(tbs)?;
+    let (_produced_at, rest) = der_expect(rest, 0x18)?;
+    let (mut responses, _) = der_expect(rest, 0x30)?;
+
+    while !responses.is_empty() {
+        let (single, rest) = der_expect(responses, 0x30)?;
+        responses = rest;
+
+        let (cert_id, rest) = der_expect(single, 0x30)?;
+        if !cert_id_matches(cert_id, &cert, &issuer)? {
+            continue;
+        }
+
+        let (cert_status, _, rest) = der_next(rest)?;
+        let (this_update, rest) = der_expect(rest, 0x18)?;
+        let next_update = match der_expect(rest, 0xa0) {
+            Some((next_update, _)) => Some(generalized_time(der_expect(next_update, 0x18)?.0)?),
+            None => None,
+        };
+
+        return Some(Status {
+            cert_status,
+            this_update: generalized_time(this_update)?,
+            next_update,
+        });
+    }
+
+    None
+}
+
+/// Split a DER-encoded signed structure -- a certificate or a
+/// BasicOCSPResponse -- into its signed data, the object identifier of
+/// its signature algorithm, and its signature.
+fn signed_parts(signed: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (signed, _) = der_expect(signed, 0x30)?;
+    let (tbs, rest) = der_raw(signed)?;
+    let (signature_algorithm, rest) = der_expect(rest, 0x30)?;
+    let (signature_algorithm, _) = der_expect(signature_algorithm, 0x06)?;
+    let (signature, _) = der_expect(rest, 0x03)?;
+    Some((tbs, signature_algorithm, signature.get(1..)?))
+}
+
+/// Whether the key of `signer` made `signature` over `tbs`, using the
+/// algorithm identified by `oid`.
+fn is_signed_by(signer: &Issuer<'_>, tbs: &[u8], oid: &[u8], signature: &[u8]) -> bool {
+    let public_key = match signer.public_key() {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+    signature_algorithms(oid)
+        .iter()
+        .any(|alg| {
+            signature::UnparsedPublicKey::new(*alg, public_key)
+                .verify(tbs, signature)
+                .is_ok()
+        })
+}
+
+/// Whether the CertID `cert_id` identifies `cert`, issued by `issuer`.
+///
+/// Returns `None` if `cert_id` cannot be parsed, or uses an unsupported
+/// hash algorithm.
+fn cert_id_matches(
+    cert_id: &[u8],
+    cert: &CertificateFields<'_>,
+    issuer: &Issuer<'_>,
+) -> Option<bool> {
+    const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+    const SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+    const SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+    const SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+    let (hash_algorithm, rest) = der_expect(cert_id, 0x30)?;
+    let (hash_algorithm, _) = der_expect(hash_algorithm, 0x06)?;
+    let (issuer_name_hash, rest) = der_expect(rest, 0x04)?;
+    let (issuer_key_hash, rest) = der_expect(rest, 0x04)?;
+    let (serial, _) = der_expect(rest, 0x02)?;
+
+    let hash_algorithm = match hash_algorithm {
+        SHA1 => &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        SHA256 => &ring::digest::SHA256,
+        SHA384 => &ring::digest::SHA384,
+        SHA512 => &ring::digest::SHA512,
+        _ => return None,
+    };
+    let hash = |data: &[u8]| ring::digest::digest(hash_algorithm, data);
+
+    Some(
+        serial == cert.serial
+            && issuer_name_hash == hash(cert.issuer).as_ref()
+            && issuer_key_hash == hash(issuer.public_key()?).as_ref(),
+    )
+}
+
+/// The signature algorithms which may have made a signature identified by
+/// the DER-encoded object identifier `oid`.
+fn signature_algorithms(oid: &[u8]) -> &'static [&'static dyn signature::VerificationAlgorithm] {
+    const SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+    const SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+    const SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+    const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+    const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+    static RSA_SHA256: &[&dyn signature::VerificationAlgorithm] =
+        &[&signature::RSA_PKCS1_2048_8192_SHA256];
+    static RSA_SHA384: &[&dyn signature::VerificationAlgorithm] =
+        &[&signature::RSA_PKCS1_2048_8192_SHA384];
+    static RSA_SHA512: &[&dyn signature::VerificationAlgorithm] =
+        &[&signature::RSA_PKCS1_2048_8192_SHA512];
+    static ECDSA_SHA256: &[&dyn signature::VerificationAlgorithm] = &[
+        &signature::ECDSA_P256_SHA256_ASN1,
+        &signature::ECDSA_P384_SHA256_ASN1,
+    ];
+    static ECDSA_SHA384: &[&dyn signature::VerificationAlgorithm] = &[
+        &signature::ECDSA_P256_SHA384_ASN1,
+        &signature::ECDSA_P384_SHA384_ASN1,
+    ];
+    static EDDSA: &[&dyn signature::VerificationAlgorithm] = &[&signature::ED25519];
+
+    match oid {
+        SHA256_WITH_RSA => RSA_SHA256,
+        SHA384_WITH_RSA => RSA_SHA384,
+        SHA512_WITH_RSA => RSA_SHA512,
+        ECDSA_WITH_SHA256 => ECDSA_SHA256,
+        ECDSA_WITH_SHA384 => ECDSA_SHA384,
+        ED25519 => EDDSA,
+        _ => &[],
+    }
+}
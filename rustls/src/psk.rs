@@ -0,0 +1,86 @@
+use crate::bs_debug::BsDebug;
+use crate::crypto::hash::HashAlgorithm;
+use crate::error::Error;
+
+use std::fmt;
+
+/// A TLS1.3 pre-shared key that was agreed out-of-band, rather than
+/// issued by the server in an earlier session (RFC8446 section 2.2).
+///
+/// A handshake using a `PresharedKey` is authenticated by knowledge of the
+/// key alone, so neither peer sends a certificate.  Configure these with
+/// [`ClientConfig::preshared_keys`] and [`ServerConfig::preshared_keys`].
+///
+/// [`ClientConfig::preshared_keys`]: crate::ClientConfig::preshared_keys
+/// [`ServerConfig::preshared_keys`]: crate::ServerConfig::preshared_keys
+#[derive(Clone)]
+pub struct PresharedKey {
+    identity: Vec<u8>,
+    key: Vec<u8>,
+    hash: HashAlgorithm,
+}
+
+impl PresharedKey {
+    /// Make a new `PresharedKey`.
+    ///
+    /// `identity` names the key to the server, and is sent in the clear.
+    /// `key` is the secret itself.  The key may only be used with TLS1.3
+    /// cipher suites whose hash function is `hash`.
+    ///
+    /// This fails if `identity` is empty or longer than 65535 bytes, as
+    /// it could not be sent (RFC8446 section 4.2.11).
+    pub fn new(identity: Vec<u8>, key: Vec<u8>, hash: HashAlgorithm) -> Result<Self, Error> {
+        if identity.is_empty() || identity.len() > usize::from(u16::MAX) {
+            return Err(Error::General(
+                "PSK identity must be between 1 and 65535 bytes".into(),
+            ));
+        }
+
+        Ok(Self {
+            identity,
+            key,
+            hash,
+        })
+    }
+
+    /// The identity of this key.
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+
+    /// The hash function of the cipher suites this key can be used with.
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PresharedKey")
+            .field("identity", &BsDebug(&self.identity))
+            .field("hash", &self.hash)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How a server chooses between the TLS1.3 PSK key exchange modes a client
+/// offers, when using one of its [`PresharedKey`]s.
+///
+/// In `psk_dhe_ke` mode, the PSK is combined with an ephemeral (EC)DHE key
+/// exchange, so the connection has forward secrecy: recording it and later
+/// learning the PSK does not reveal its contents.  In `psk_ke` mode, the
+/// key schedule depends only on the PSK.  That saves a key exchange, but
+/// lacks forward secrecy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PskKeyExchangePolicy {
+    /// Use `psk_dhe_ke` if the client offers it, and `psk_ke` otherwise.
+    PreferDhe,
+    /// Use `psk_ke` if the client offers it, and `psk_dhe_ke` otherwise.
+    PreferPskOnly,
+    /// Only use `psk_dhe_ke`.
+    RequireDhe,
+}
@@ -1,18 +1,19 @@
 /// This module contains optional APIs for implementing QUIC TLS.
-use crate::cipher::{Iv, IvLen};
 use crate::client::{ClientConfig, ClientConnectionData, ServerName};
 use crate::common_state::{CommonState, Protocol, Side};
 use crate::conn::{ConnectionCore, SideData};
+use crate::crypto::cipher::{self, Iv, Nonce};
+use crate::crypto::hkdf::Prk;
 use crate::crypto::CryptoProvider;
 use crate::enums::{AlertDescription, ProtocolVersion};
 use crate::error::Error;
 use crate::msgs::handshake::{ClientExtension, ServerExtension};
 use crate::server::{ServerConfig, ServerConnectionData};
 use crate::suites::BulkAlgorithm;
-use crate::tls13::key_schedule::hkdf_expand;
+use crate::tls13::key_schedule::{hkdf_expand_label_aead_key, hkdf_expand_label_prk};
 use crate::tls13::{Tls13CipherSuite, TLS13_AES_128_GCM_SHA256_INTERNAL};
 
-use ring::{aead, hkdf};
+use ring::aead;
 
 use std::collections::VecDeque;
 use std::fmt::{self, Debug};
@@ -156,7 +157,7 @@ impl ClientConnection {
             Version::V1 | Version::V2 => ClientExtension::TransportParameters(params),
         };
 
-        let mut inner = ConnectionCore::for_client(config, name, vec![ext], Protocol::Quic)?;
+        let mut inner = ConnectionCore::for_client(config, name, None, vec![ext], Protocol::Quic)?;
         inner.common_state.quic.version = quic_version;
         Ok(Self {
             inner: inner.into(),
@@ -387,7 +388,7 @@ pub(crate) struct Quic {
     pub(crate) params: Option<Vec<u8>>,
     pub(crate) alert: Option<AlertDescription>,
     pub(crate) hs_queue: VecDeque<(bool, Vec<u8>)>,
-    pub(crate) early_secret: Option<ring::hkdf::Prk>,
+    pub(crate) early_secret: Option<Prk>,
     pub(crate) hs_secrets: Option<Secrets>,
     pub(crate) traffic_secrets: Option<Secrets>,
     /// Whether keys derived from traffic_secrets have been passed to the QUIC implementation
@@ -433,9 +434,9 @@ impl Quic {
 #[derive(Clone, Debug)]
 pub struct Secrets {
     /// Secret used to encrypt packets transmitted by the client
-    client: hkdf::Prk,
+    client: Prk,
     /// Secret used to encrypt packets transmitted by the server
-    server: hkdf::Prk,
+    server: Prk,
     /// Cipher suite used with these secrets
     suite: &'static Tls13CipherSuite,
     side: Side,
@@ -444,8 +445,8 @@ pub struct Secrets {
 
 impl Secrets {
     pub(crate) fn new(
-        client: hkdf::Prk,
-        server: hkdf::Prk,
+        client: Prk,
+        server: Prk,
         suite: &'static Tls13CipherSuite,
         side: Side,
         version: Version,
@@ -467,12 +468,13 @@ impl Secrets {
     }
 
     fn update(&mut self) {
-        let hkdf_alg = self.suite.hkdf_algorithm;
-        self.client = hkdf_expand(&self.client, hkdf_alg, self.version.key_update_label(), &[]);
-        self.server = hkdf_expand(&self.server, hkdf_alg, self.version.key_update_label(), &[]);
+        let hkdf = self.suite.hkdf_provider;
+        let label = self.version.key_update_label();
+        self.client = hkdf_expand_label_prk(hkdf, &self.client, label, &[]);
+        self.server = hkdf_expand_label_prk(hkdf, &self.server, label, &[]);
     }
 
-    fn local_remote(&self) -> (&hkdf::Prk, &hkdf::Prk) {
+    fn local_remote(&self) -> (&Prk, &Prk) {
         match self.side {
             Side::Client => (&self.client, &self.server),
             Side::Server => (&self.server, &self.client),
@@ -489,11 +491,7 @@ pub struct DirectionalKeys {
 }
 
 impl DirectionalKeys {
-    pub(crate) fn new(
-        suite: &'static Tls13CipherSuite,
-        secret: &hkdf::Prk,
-        version: Version,
-    ) -> Self {
+    pub(crate) fn new(suite: &'static Tls13CipherSuite, secret: &Prk, version: Version) -> Self {
         Self {
             header: HeaderProtectionKey::new(suite, secret, version),
             packet: PacketKey::new(suite, secret, version),
@@ -505,14 +503,20 @@ impl DirectionalKeys {
 pub struct HeaderProtectionKey(aead::quic::HeaderProtectionKey);
 
 impl HeaderProtectionKey {
-    fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk, version: Version) -> Self {
+    fn new(suite: &'static Tls13CipherSuite, secret: &Prk, version: Version) -> Self {
         let alg = match suite.common.bulk {
             BulkAlgorithm::Aes128Gcm => &aead::quic::AES_128,
             BulkAlgorithm::Aes256Gcm => &aead::quic::AES_256,
             BulkAlgorithm::Chacha20Poly1305 => &aead::quic::CHACHA20,
         };
 
-        Self(hkdf_expand(secret, alg, version.header_key_label(), &[]))
+        let mut buf = [0u8; 32];
+        let key = &mut buf[..alg.key_len()];
+        suite
+            .hkdf_provider
+            .expand_label(secret.as_ref(), version.header_key_label(), &[], key)
+            .unwrap();
+        Self(aead::quic::HeaderProtectionKey::new(alg, key).unwrap())
     }
 
     /// Adds QUIC Header Protection.
@@ -647,15 +651,19 @@ pub struct PacketKey {
 }
 
 impl PacketKey {
-    fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk, version: Version) -> Self {
+    fn new(suite: &'static Tls13CipherSuite, secret: &Prk, version: Version) -> Self {
         Self {
-            key: aead::LessSafeKey::new(hkdf_expand(
-                secret,
+            key: aead::LessSafeKey::new(hkdf_expand_label_aead_key(
+                suite.hkdf_provider,
                 suite.common.aead_algorithm,
+                secret,
                 version.packet_key_label(),
-                &[],
             )),
-            iv: hkdf_expand(secret, IvLen, version.packet_iv_label(), &[]),
+            iv: cipher::expand_iv(
+                suite.hkdf_provider,
+                secret.as_ref(),
+                version.packet_iv_label(),
+            ),
             suite,
         }
     }
@@ -771,13 +779,15 @@ impl Keys {
         const CLIENT_LABEL: &[u8] = b"client in";
         const SERVER_LABEL: &[u8] = b"server in";
         let salt = version.initial_salt();
-        let hs_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(client_dst_connection_id);
+        let suite = TLS13_AES_128_GCM_SHA256_INTERNAL;
+        let hkdf = suite.hkdf_provider;
+        let hs_secret = hkdf.extract(salt, client_dst_connection_id);
 
         let secrets = Secrets {
             version,
-            client: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, CLIENT_LABEL, &[]),
-            server: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, SERVER_LABEL, &[]),
-            suite: TLS13_AES_128_GCM_SHA256_INTERNAL,
+            client: hkdf_expand_label_prk(hkdf, &hs_secret, CLIENT_LABEL, &[]),
+            server: hkdf_expand_label_prk(hkdf, &hs_secret, SERVER_LABEL, &[]),
+            suite,
             side,
         };
         Self::new(&secrets)
@@ -822,13 +832,8 @@ pub enum KeyChange {
 }
 
 /// Compute the nonce to use for encrypting or decrypting `packet_number`
-fn nonce_for(packet_number: u64, iv: &Iv) -> ring::aead::Nonce {
-    let mut out = [0; aead::NONCE_LEN];
-    out[4..].copy_from_slice(&packet_number.to_be_bytes());
-    for (out, inp) in out.iter_mut().zip(iv.0.iter()) {
-        *out ^= inp;
-    }
-    aead::Nonce::assume_unique_for_key(out)
+fn nonce_for(packet_number: u64, iv: &Iv) -> aead::Nonce {
+    aead::Nonce::assume_unique_for_key(Nonce::new(iv, packet_number).0)
 }
 
 /// QUIC protocol version
@@ -913,7 +918,7 @@ mod test {
             0x0f, 0x21, 0x63, 0x2b,
         ];
 
-        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, SECRET);
+        let secret = Prk::new(SECRET);
         use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
         let hpk =
             HeaderProtectionKey::new(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL, &secret, version);
@@ -965,66 +970,40 @@ mod test {
 
     #[test]
     fn key_update_test_vector() {
-        fn equal_prk(x: &hkdf::Prk, y: &hkdf::Prk) -> bool {
-            let mut x_data = [0; 16];
-            let mut y_data = [0; 16];
-            let x_okm = x
-                .expand(&[b"info"], &aead::quic::AES_128)
-                .unwrap();
-            x_okm.fill(&mut x_data[..]).unwrap();
-            let y_okm = y
-                .expand(&[b"info"], &aead::quic::AES_128)
-                .unwrap();
-            y_okm.fill(&mut y_data[..]).unwrap();
-            x_data == y_data
-        }
-
         let mut secrets = Secrets {
             // Constant dummy values for reproducibility
-            client: hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
-                &[
-                    0xb8, 0x76, 0x77, 0x08, 0xf8, 0x77, 0x23, 0x58, 0xa6, 0xea, 0x9f, 0xc4, 0x3e,
-                    0x4a, 0xdd, 0x2c, 0x96, 0x1b, 0x3f, 0x52, 0x87, 0xa6, 0xd1, 0x46, 0x7e, 0xe0,
-                    0xae, 0xab, 0x33, 0x72, 0x4d, 0xbf,
-                ],
-            ),
-            server: hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
-                &[
-                    0x42, 0xdc, 0x97, 0x21, 0x40, 0xe0, 0xf2, 0xe3, 0x98, 0x45, 0xb7, 0x67, 0x61,
-                    0x34, 0x39, 0xdc, 0x67, 0x58, 0xca, 0x43, 0x25, 0x9b, 0x87, 0x85, 0x06, 0x82,
-                    0x4e, 0xb1, 0xe4, 0x38, 0xd8, 0x55,
-                ],
-            ),
+            client: Prk::new(&[
+                0xb8, 0x76, 0x77, 0x08, 0xf8, 0x77, 0x23, 0x58, 0xa6, 0xea, 0x9f, 0xc4, 0x3e, 0x4a,
+                0xdd, 0x2c, 0x96, 0x1b, 0x3f, 0x52, 0x87, 0xa6, 0xd1, 0x46, 0x7e, 0xe0, 0xae, 0xab,
+                0x33, 0x72, 0x4d, 0xbf,
+            ]),
+            server: Prk::new(&[
+                0x42, 0xdc, 0x97, 0x21, 0x40, 0xe0, 0xf2, 0xe3, 0x98, 0x45, 0xb7, 0x67, 0x61, 0x34,
+                0x39, 0xdc, 0x67, 0x58, 0xca, 0x43, 0x25, 0x9b, 0x87, 0x85, 0x06, 0x82, 0x4e, 0xb1,
+                0xe4, 0x38, 0xd8, 0x55,
+            ]),
             suite: TLS13_AES_128_GCM_SHA256_INTERNAL,
             side: Side::Client,
             version: Version::V1,
         };
         secrets.update();
 
-        assert!(equal_prk(
-            &secrets.client,
-            &hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
-                &[
-                    0x42, 0xca, 0xc8, 0xc9, 0x1c, 0xd5, 0xeb, 0x40, 0x68, 0x2e, 0x43, 0x2e, 0xdf,
-                    0x2d, 0x2b, 0xe9, 0xf4, 0x1a, 0x52, 0xca, 0x6b, 0x22, 0xd8, 0xe6, 0xcd, 0xb1,
-                    0xe8, 0xac, 0xa9, 0x6, 0x1f, 0xce
-                ]
-            )
-        ));
-        assert!(equal_prk(
-            &secrets.server,
-            &hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
-                &[
-                    0xeb, 0x7f, 0x5e, 0x2a, 0x12, 0x3f, 0x40, 0x7d, 0xb4, 0x99, 0xe3, 0x61, 0xca,
-                    0xe5, 0x90, 0xd4, 0xd9, 0x92, 0xe1, 0x4b, 0x7a, 0xce, 0x3, 0xc2, 0x44, 0xe0,
-                    0x42, 0x21, 0x15, 0xb6, 0xd3, 0x8a
-                ]
-            )
-        ));
+        assert_eq!(
+            secrets.client.as_ref(),
+            &[
+                0x42, 0xca, 0xc8, 0xc9, 0x1c, 0xd5, 0xeb, 0x40, 0x68, 0x2e, 0x43, 0x2e, 0xdf, 0x2d,
+                0x2b, 0xe9, 0xf4, 0x1a, 0x52, 0xca, 0x6b, 0x22, 0xd8, 0xe6, 0xcd, 0xb1, 0xe8, 0xac,
+                0xa9, 0x6, 0x1f, 0xce
+            ]
+        );
+        assert_eq!(
+            secrets.server.as_ref(),
+            &[
+                0xeb, 0x7f, 0x5e, 0x2a, 0x12, 0x3f, 0x40, 0x7d, 0xb4, 0x99, 0xe3, 0x61, 0xca, 0xe5,
+                0x90, 0xd4, 0xd9, 0x92, 0xe1, 0x4b, 0x7a, 0xce, 0x3, 0xc2, 0x44, 0xe0, 0x42, 0x21,
+                0x15, 0xb6, 0xd3, 0x8a
+            ]
+        );
     }
 
     #[test]
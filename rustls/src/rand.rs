@@ -2,6 +2,8 @@
 
 use crate::crypto::CryptoProvider;
 
+use std::fmt;
+
 /// Make a Vec<u8> of the given size
 /// containing random material.
 pub(crate) fn random_vec<C: CryptoProvider>(len: usize) -> Result<Vec<u8>, GetRandomFailed> {
@@ -17,5 +19,19 @@ pub(crate) fn random_u32<C: CryptoProvider>() -> Result<u32, GetRandomFailed> {
     Ok(u32::from_be_bytes(buf))
 }
 
+/// Random material generation failed.
+///
+/// Returned by [`CryptoProvider::fill_random`], and the
+/// [`source`](std::error::Error::source) of [`Error::FailedToGetRandomBytes`].
+///
+/// [`Error::FailedToGetRandomBytes`]: crate::Error::FailedToGetRandomBytes
 #[derive(Debug)]
 pub struct GetRandomFailed;
+
+impl fmt::Display for GetRandomFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to get random bytes")
+    }
+}
+
+impl std::error::Error for GetRandomFailed {}
@@ -25,7 +25,10 @@ pub struct RecordLayer {
     message_encrypter: Box<dyn MessageEncrypter>,
     message_decrypter: Box<dyn MessageDecrypter>,
     write_seq: u64,
+    write_seq_max: u64,
     read_seq: u64,
+    read_failures: u64,
+    read_failures_max: u64,
     encrypt_state: DirectionState,
     decrypt_state: DirectionState,
 
@@ -42,7 +45,10 @@ impl RecordLayer {
             message_encrypter: <dyn MessageEncrypter>::invalid(),
             message_decrypter: <dyn MessageDecrypter>::invalid(),
             write_seq: 0,
+            write_seq_max: SEQ_SOFT_LIMIT,
             read_seq: 0,
+            read_failures: 0,
+            read_failures_max: u64::MAX,
             encrypt_state: DirectionState::Invalid,
             decrypt_state: DirectionState::Invalid,
             trial_decryption_len: None,
@@ -81,6 +87,7 @@ impl RecordLayer {
     pub(crate) fn prepare_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
         self.message_encrypter = cipher;
         self.write_seq = 0;
+        self.write_seq_max = SEQ_SOFT_LIMIT;
         self.encrypt_state = DirectionState::Prepared;
     }
 
@@ -108,8 +115,16 @@ impl RecordLayer {
 
     /// Set and start using the given `MessageEncrypter` for future outgoing
     /// message encryption.
-    pub(crate) fn set_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
+    ///
+    /// `max_messages` is the number of messages that may be encrypted
+    /// before the key should be replaced: see [`RecordLayer::wants_close_before_encrypt`].
+    pub(crate) fn set_message_encrypter(
+        &mut self,
+        cipher: Box<dyn MessageEncrypter>,
+        max_messages: u64,
+    ) {
         self.prepare_message_encrypter(cipher);
+        self.write_seq_max = max_messages.min(SEQ_SOFT_LIMIT);
         self.start_encrypting();
     }
 
@@ -124,14 +139,19 @@ impl RecordLayer {
     /// Set and start using the given `MessageDecrypter` for future incoming
     /// message decryption, and enable "trial decryption" mode for when TLS1.3
     /// 0-RTT is attempted but rejected by the server.
+    ///
+    /// `max_failures` is the AEAD's integrity limit: the connection fails
+    /// once that many messages have been dropped for failing to decrypt.
     pub(crate) fn set_message_decrypter_with_trial_decryption(
         &mut self,
         cipher: Box<dyn MessageDecrypter>,
         max_length: usize,
+        max_failures: u64,
     ) {
         self.prepare_message_decrypter(cipher);
         self.start_decrypting();
         self.trial_decryption_len = Some(max_length);
+        self.read_failures_max = max_failures;
     }
 
     pub(crate) fn finish_trial_decryption(&mut self) {
@@ -139,9 +159,14 @@ impl RecordLayer {
     }
 
     /// Return true if we are getting close to encrypting too many
-    /// messages with our encryption key.
+    /// messages with our encryption key, and should refresh it (TLS1.3)
+    /// or close the connection (TLS1.2).
+    ///
+    /// This is the AEAD's confidentiality limit, where one was given
+    /// to `set_message_encrypter`, or otherwise the point where the
+    /// sequence number nears wrapping.
     pub(crate) fn wants_close_before_encrypt(&self) -> bool {
-        self.write_seq == SEQ_SOFT_LIMIT
+        self.write_seq == self.write_seq_max
     }
 
     /// Return true if we outright refuse to do anything with the
@@ -176,6 +201,12 @@ impl RecordLayer {
         // failure has already happened.
         let want_close_before_decrypt = self.read_seq == SEQ_SOFT_LIMIT;
 
+        // Unlike the above, the peer is now about to wrap the sequence
+        // number.  Refuse to go further rather than reuse a nonce.
+        if self.read_seq >= SEQ_HARD_LIMIT {
+            return Err(Error::DecryptError);
+        }
+
         let encrypted_len = encr.payload.0.len();
         match self
             .message_decrypter
@@ -189,6 +220,13 @@ impl RecordLayer {
                 }))
             }
             Err(Error::DecryptError) if self.doing_trial_decryption(encrypted_len) => {
+                // Each of these is a chance for the peer to forge a message,
+                // so only so many are tolerated over the connection's lifetime.
+                self.read_failures += 1;
+                if self.read_failures >= self.read_failures_max {
+                    return Err(Error::DecryptError);
+                }
+
                 trace!("Dropping undecryptable message after aborted early_data");
                 Ok(None)
             }
@@ -200,14 +238,22 @@ impl RecordLayer {
     ///
     /// `plain` is a TLS message we'd like to send.  This function
     /// panics if the requisite keying material hasn't been established yet.
-    pub(crate) fn encrypt_outgoing(&mut self, plain: BorrowedPlainMessage) -> OpaqueMessage {
+    ///
+    /// Returns `Error::EncryptError` once the sequence number is exhausted
+    /// (see [`RecordLayer::encrypt_exhausted`]); the key must be
+    /// replaced before anything more can be sent.
+    pub(crate) fn encrypt_outgoing(
+        &mut self,
+        plain: BorrowedPlainMessage,
+    ) -> Result<OpaqueMessage, Error> {
         debug_assert!(self.encrypt_state == DirectionState::Active);
-        assert!(!self.encrypt_exhausted());
+        if self.encrypt_exhausted() {
+            return Err(Error::EncryptError);
+        }
         let seq = self.write_seq;
         self.write_seq += 1;
         self.message_encrypter
             .encrypt(plain, seq)
-            .unwrap()
     }
 }
 
@@ -219,3 +265,141 @@ pub struct Decrypted {
     /// The decrypted message.
     pub plaintext: PlainMessage,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enums::{ContentType, ProtocolVersion};
+    use crate::msgs::base::Payload;
+
+    struct PassThroughEncrypter;
+
+    impl MessageEncrypter for PassThroughEncrypter {
+        fn encrypt(&self, m: BorrowedPlainMessage, _seq: u64) -> Result<OpaqueMessage, Error> {
+            Ok(m.to_unencrypted_opaque())
+        }
+    }
+
+    struct PassThroughDecrypter;
+
+    impl MessageDecrypter for PassThroughDecrypter {
+        fn decrypt(&self, m: OpaqueMessage, _seq: u64) -> Result<PlainMessage, Error> {
+            Ok(m.into_plain_message())
+        }
+    }
+
+    struct FailingDecrypter;
+
+    impl MessageDecrypter for FailingDecrypter {
+        fn decrypt(&self, _m: OpaqueMessage, _seq: u64) -> Result<PlainMessage, Error> {
+            Err(Error::DecryptError)
+        }
+    }
+
+    fn plain() -> PlainMessage {
+        PlainMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(b"hello".to_vec()),
+        }
+    }
+
+    #[test]
+    fn encrypt_refuses_to_wrap_sequence_number() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_encrypter(Box::new(PassThroughEncrypter), u64::MAX);
+
+        record_layer.write_seq = SEQ_SOFT_LIMIT - 1;
+        assert!(!record_layer.wants_close_before_encrypt());
+        assert!(record_layer
+            .encrypt_outgoing(plain().borrow())
+            .is_ok());
+        assert!(record_layer.wants_close_before_encrypt());
+
+        record_layer.write_seq = SEQ_HARD_LIMIT - 1;
+        assert!(!record_layer.encrypt_exhausted());
+        assert!(record_layer
+            .encrypt_outgoing(plain().borrow())
+            .is_ok());
+        assert!(record_layer.encrypt_exhausted());
+        assert_eq!(
+            record_layer
+                .encrypt_outgoing(plain().borrow())
+                .unwrap_err(),
+            Error::EncryptError
+        );
+        assert_eq!(record_layer.write_seq, SEQ_HARD_LIMIT);
+
+        // A new key starts the sequence again.
+        record_layer.set_message_encrypter(Box::new(PassThroughEncrypter), u64::MAX);
+        assert!(!record_layer.encrypt_exhausted());
+        assert!(record_layer
+            .encrypt_outgoing(plain().borrow())
+            .is_ok());
+    }
+
+    #[test]
+    fn decrypt_refuses_to_wrap_sequence_number() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_decrypter(Box::new(PassThroughDecrypter));
+
+        record_layer.read_seq = SEQ_SOFT_LIMIT;
+        let decrypted = record_layer
+            .decrypt_incoming(plain().into_unencrypted_opaque())
+            .unwrap()
+            .unwrap();
+        assert!(decrypted.want_close_before_decrypt);
+
+        record_layer.read_seq = SEQ_HARD_LIMIT - 1;
+        assert!(record_layer
+            .decrypt_incoming(plain().into_unencrypted_opaque())
+            .is_ok());
+        assert_eq!(
+            record_layer
+                .decrypt_incoming(plain().into_unencrypted_opaque())
+                .unwrap_err(),
+            Error::DecryptError
+        );
+        assert_eq!(record_layer.read_seq, SEQ_HARD_LIMIT);
+    }
+
+    #[test]
+    fn trial_decryption_failures_are_limited() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_decrypter_with_trial_decryption(
+            Box::new(FailingDecrypter),
+            1024,
+            3,
+        );
+
+        // Below the integrity limit, bad messages are dropped.
+        for _ in 0..2 {
+            assert!(record_layer
+                .decrypt_incoming(plain().into_unencrypted_opaque())
+                .unwrap()
+                .is_none());
+        }
+
+        // Reaching it is fatal, even though trial decryption could continue.
+        assert_eq!(
+            record_layer
+                .decrypt_incoming(plain().into_unencrypted_opaque())
+                .unwrap_err(),
+            Error::DecryptError
+        );
+        assert_eq!(record_layer.read_failures, 3);
+    }
+
+    #[test]
+    fn decryption_failures_are_fatal_outside_trial_decryption() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_decrypter(Box::new(FailingDecrypter));
+
+        assert_eq!(
+            record_layer
+                .decrypt_incoming(plain().into_unencrypted_opaque())
+                .unwrap_err(),
+            Error::DecryptError
+        );
+    }
+}
@@ -1,10 +1,16 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::common_state;
+use crate::compress;
 use crate::crypto::{CryptoProvider, KeyExchange};
 use crate::error::Error;
 use crate::key;
+use crate::msgs::enums::CertificateType;
+use crate::psk::PskKeyExchangePolicy;
 use crate::server::handy;
-use crate::server::{ResolvesServerCert, ServerConfig};
-use crate::suites::SupportedCipherSuite;
+use crate::server::{AlpnPolicy, ResolvesServerCert, ServerConfig};
+use crate::suites::{SuitableSuites, SupportedCipherSuite};
+use crate::time::DefaultTimeProvider;
+use crate::tls13::RecordPadding;
 use crate::verify;
 use crate::versions;
 use crate::NoKeyLog;
@@ -92,6 +98,7 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsServerCert<C>> {
     /// Sets a custom [`ResolvesServerCert`].
     pub fn with_cert_resolver(self, cert_resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig<C> {
         ServerConfig {
+            suitable_suites: SuitableSuites::new(&self.state.cipher_suites),
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
             verifier: self.state.verifier,
@@ -103,11 +110,35 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsServerCert<C>> {
             alpn_protocols: Vec::new(),
             versions: self.state.versions,
             key_log: Arc::new(NoKeyLog {}),
+            handshake_observer: None,
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
             max_early_data_size: 0,
             send_half_rtt_data: false,
-            send_tls13_tickets: 4,
+            send_tls13_tickets: 2,
+            hello_retry_for_preferred_group: false,
+            cert_compressors: compress::default_cert_compressors(),
+            preshared_keys: Vec::new(),
+            psk_key_exchange_policy: PskKeyExchangePolicy::PreferDhe,
+            hello_retry_cookie_key: None,
+            record_padding: RecordPadding::None,
+            session_id_resumption: true,
+            defer_tls13_tickets: false,
+            cipher_suite_selector: None,
+            require_sni: false,
+            alpn_policy: AlpnPolicy::Strict,
+            alpn_selector: None,
+            strict_resumption: false,
+            signature_schemes: None,
+            buffer_pool: None,
+            retained_plaintext_capacity: common_state::DEFAULT_RETAINED_PLAINTEXT_CAPACITY,
+            write_buffer_threshold: 0,
+            unknown_extension_handler: None,
+            server_cert_types: vec![CertificateType::X509],
+            client_cert_types: vec![CertificateType::X509],
+            raw_public_key: None,
+            pinned_client_keys: Vec::new(),
+            time_provider: Arc::new(DefaultTimeProvider),
             provider: PhantomData,
         }
     }
@@ -31,4 +31,14 @@ impl<'a> ActiveCertifiedKey<'a> {
     pub(super) fn get_ocsp(&self) -> Option<&[u8]> {
         self.ocsp
     }
+
+    #[inline]
+    pub(super) fn get_intermediate_ocsp(&self) -> &[Vec<u8>] {
+        &self.key.intermediate_ocsp
+    }
+
+    #[inline]
+    pub(super) fn get_delegated_credential(&self) -> Option<&sign::DelegatedCredential> {
+        self.key.delegated_credential.as_ref()
+    }
 }
@@ -1,4 +1,5 @@
 use crate::dns_name::DnsNameRef;
+use crate::enums::SignatureAlgorithm;
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
@@ -210,9 +211,54 @@ impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
     }
 }
 
+/// Something that resolves to one of several cert chains/keys, based
+/// on the client's supported signature schemes.
+///
+/// This lets a server hold, for example, both an ECDSA and an RSA
+/// certificate and present whichever the client can verify.  ECDSA keys
+/// are preferred; otherwise the first usable key added is chosen.
+pub struct ResolvesServerCertUsingSignatureScheme {
+    keys: Vec<Arc<sign::CertifiedKey>>,
+}
+
+impl ResolvesServerCertUsingSignatureScheme {
+    /// Create a new and empty (i.e., knows no certificates) resolver.
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Add a new `sign::CertifiedKey` to choose from.
+    ///
+    /// This function fails if the certificate chain is empty.
+    pub fn add(&mut self, ck: sign::CertifiedKey) -> Result<(), Error> {
+        ck.end_entity_cert().map_err(|_| {
+            Error::General("No end-entity certificate in certificate chain".to_string())
+        })?;
+        self.keys.push(Arc::new(ck));
+        Ok(())
+    }
+}
+
+impl server::ResolvesServerCert for ResolvesServerCertUsingSignatureScheme {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        let usable = || {
+            self.keys.iter().filter(|ck| {
+                ck.key
+                    .choose_scheme(client_hello.signature_schemes())
+                    .is_some()
+            })
+        };
+        usable()
+            .find(|ck| ck.key.algorithm() == SignatureAlgorithm::ECDSA)
+            .or_else(|| usable().next())
+            .map(Arc::clone)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::enums::SignatureScheme;
     use crate::server::ProducesTickets;
     use crate::server::ResolvesServerCert;
     use crate::server::StoresServerSessions;
@@ -293,7 +339,22 @@ mod test {
     fn test_resolvesservercertusingsni_requires_sni() {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(rscsni
-            .resolve(ClientHello::new(&None, &[], None, &[]))
+            .resolve(ClientHello::new(&None, &[], None, None, &[], ""))
+            .is_none());
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsignaturescheme_requires_keys() {
+        let rscss = ResolvesServerCertUsingSignatureScheme::new();
+        assert!(rscss
+            .resolve(ClientHello::new(
+                &None,
+                &[SignatureScheme::ECDSA_NISTP256_SHA256],
+                None,
+                None,
+                &[],
+                ""
+            ))
             .is_none());
     }
 
@@ -304,7 +365,7 @@ mod test {
             .unwrap()
             .to_owned();
         assert!(rscsni
-            .resolve(ClientHello::new(&Some(name), &[], None, &[]))
+            .resolve(ClientHello::new(&Some(name), &[], None, None, &[], ""))
             .is_none());
     }
 }
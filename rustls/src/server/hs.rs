@@ -9,15 +9,18 @@ use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::enums::{Compression, ExtensionType};
+#[cfg(feature = "tls12")]
+use crate::msgs::enums::ECPointFormat;
+use crate::msgs::enums::{CertificateType, Compression, ExtensionType};
 #[cfg(feature = "tls12")]
 use crate::msgs::handshake::SessionId;
-use crate::msgs::handshake::{ClientHelloPayload, Random, ServerExtension};
+use crate::msgs::handshake::{ClientExtension, ClientHelloPayload, Random, ServerExtension};
 use crate::msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList, HandshakePayload};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
-use crate::server::{ClientHello, ServerConfig};
+use crate::server::{AlpnPolicy, ClientHello, ServerConfig};
 use crate::suites;
+use crate::unknown_extensions::handle_unknown_extensions;
 use crate::SupportedCipherSuite;
 
 use super::server_conn::ServerConnectionData;
@@ -50,6 +53,52 @@ pub(super) fn can_resume(
         && &resumedata.sni == sni
 }
 
+/// Whether [`ServerConfig::with_strict_resumption`] allows `resumedata`
+/// to be resumed by a client for which we chose `alpn_protocol`.
+pub(super) fn resumption_alpn_matches<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    alpn_protocol: Option<&[u8]>,
+    resumedata: &persist::ServerSessionValue,
+) -> bool {
+    !config.strict_resumption
+        || resumedata
+            .alpn
+            .as_ref()
+            .map(|p| p.0.as_slice())
+            == alpn_protocol
+}
+
+/// Choose the ALPN protocol for a client offering `hello`.
+///
+/// This is done once per `ClientHello`, as [`ServerConfig::alpn_selector`]
+/// may be expensive or have side effects.
+fn choose_alpn_protocol<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    hello: &ClientHelloPayload,
+) -> Result<Option<Vec<u8>>, Error> {
+    let their_protocols = match hello.get_alpn_extension() {
+        Some(their_protocols) => their_protocols.to_slices(),
+        None => return Ok(None),
+    };
+
+    if their_protocols
+        .iter()
+        .any(|protocol| protocol.is_empty())
+    {
+        return Err(PeerMisbehaved::OfferedEmptyApplicationProtocol.into());
+    }
+
+    Ok(match &config.alpn_selector {
+        Some(selector) => selector(&their_protocols)
+            .filter(|protocol| their_protocols.contains(&protocol.as_slice())),
+        None => config
+            .alpn_protocols
+            .iter()
+            .find(|protocol| their_protocols.contains(&protocol.as_slice()))
+            .cloned(),
+    })
+}
+
 #[derive(Default)]
 pub(super) struct ExtensionProcessing {
     // extensions to reply with
@@ -71,29 +120,18 @@ impl ExtensionProcessing {
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
         extra_exts: Vec<ServerExtension>,
+        alpn_protocol: Option<Vec<u8>>,
     ) -> Result<(), Error> {
         // ALPN
         let our_protocols = &config.alpn_protocols;
         let maybe_their_protocols = hello.get_alpn_extension();
-        if let Some(their_protocols) = maybe_their_protocols {
-            let their_protocols = their_protocols.to_slices();
-
-            if their_protocols
-                .iter()
-                .any(|protocol| protocol.is_empty())
-            {
-                return Err(PeerMisbehaved::OfferedEmptyApplicationProtocol.into());
-            }
-
-            cx.common.alpn_protocol = our_protocols
-                .iter()
-                .find(|protocol| their_protocols.contains(&protocol.as_slice()))
-                .cloned();
+        if maybe_their_protocols.is_some() {
+            cx.common.alpn_protocol = alpn_protocol;
             if let Some(ref selected_protocol) = cx.common.alpn_protocol {
                 debug!("Chosen ALPN protocol {:?}", selected_protocol);
                 self.exts
                     .push(ServerExtension::make_alpn(&[selected_protocol]));
-            } else if !our_protocols.is_empty() {
+            } else if !our_protocols.is_empty() && config.alpn_policy == AlpnPolicy::Strict {
                 return Err(cx.common.send_fatal_alert(
                     AlertDescription::NoApplicationProtocol,
                     Error::NoApplicationProtocol,
@@ -182,6 +220,15 @@ impl ExtensionProcessing {
                 .push(ServerExtension::make_empty_renegotiation_info());
         }
 
+        // Point formats (RFC8422 section 5.2).  The client must have
+        // offered uncompressed points, the only format we support.
+        if hello.get_ecpoints_extension().is_some() {
+            self.exts
+                .push(ServerExtension::ECPointFormats(
+                    ECPointFormat::SUPPORTED.to_vec(),
+                ));
+        }
+
         // Tickets:
         // If we get any SessionTicket extension and have tickets enabled,
         // we send an ack.
@@ -203,6 +250,63 @@ impl ExtensionProcessing {
     }
 }
 
+/// The certificate types (RFC 7250) each peer authenticates with.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct CertificateTypes {
+    pub(super) server: CertificateType,
+    /// `None` if we won't ask for client authentication.
+    pub(super) client: Option<CertificateType>,
+}
+
+impl CertificateTypes {
+    /// Choose the first type in each of our preference lists which the
+    /// client offered.  Clients which don't send the extensions, and all
+    /// TLS1.2 clients, only offer X509.
+    fn choose<C: CryptoProvider>(
+        config: &ServerConfig<C>,
+        hello: &ClientHelloPayload,
+        version: ProtocolVersion,
+    ) -> Result<Self, PeerIncompatible> {
+        const X509_ONLY: &[CertificateType] = &[CertificateType::X509];
+        let tls13 = version == ProtocolVersion::TLSv1_3;
+        let offered_server = hello
+            .get_server_certificate_type_extension()
+            .filter(|_| tls13)
+            .unwrap_or(X509_ONLY);
+        let offered_client = hello
+            .get_client_certificate_type_extension()
+            .filter(|_| tls13)
+            .unwrap_or(X509_ONLY);
+
+        let server = config
+            .server_cert_types
+            .iter()
+            .copied()
+            .filter(|typ| *typ != CertificateType::RawPublicKey || config.raw_public_key.is_some())
+            .find(|typ| offered_server.contains(typ))
+            .ok_or(PeerIncompatible::NoCertificateTypesInCommon)?;
+
+        let client = match config
+            .client_cert_types
+            .iter()
+            .find(|typ| offered_client.contains(typ))
+        {
+            Some(CertificateType::X509) if !config.verifier.offer_client_auth() => None,
+            Some(typ) => Some(*typ),
+            None if config
+                .client_cert_types
+                .contains(&CertificateType::X509)
+                && !config.verifier.client_auth_mandatory() =>
+            {
+                None
+            }
+            None => return Err(PeerIncompatible::NoCertificateTypesInCommon),
+        };
+
+        Ok(Self { server, client })
+    }
+}
+
 pub(super) struct ExpectClientHello<C: CryptoProvider> {
     pub(super) config: Arc<ServerConfig<C>>,
     pub(super) extra_exts: Vec<ServerExtension>,
@@ -244,6 +348,25 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
         m: &Message,
         cx: &mut ServerContext<'_>,
     ) -> NextStateOrError {
+        handle_unknown_extensions(
+            self.config
+                .unknown_extension_handler
+                .as_ref(),
+            cx.common,
+            client_hello
+                .extensions
+                .iter()
+                .filter_map(|ext| match ext {
+                    ClientExtension::Unknown(unknown) => Some(unknown),
+                    _ => None,
+                }),
+        )?;
+
+        sig_schemes.retain(|scheme| {
+            self.config
+                .allows_signature_scheme(*scheme)
+        });
+
         let tls13_enabled = self
             .config
             .supports_version(ProtocolVersion::TLSv1_3);
@@ -290,6 +413,22 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
 
         cx.common.negotiated_version = Some(version);
 
+        if self.config.require_sni && cx.data.sni.is_none() {
+            let alert = match version {
+                ProtocolVersion::TLSv1_3 => AlertDescription::MissingExtension,
+                _ => AlertDescription::HandshakeFailure,
+            };
+            return Err(cx
+                .common
+                .send_fatal_alert(alert, PeerIncompatible::ServerNameExtensionRequired));
+        }
+
+        let cert_types =
+            CertificateTypes::choose(&self.config, client_hello, version).map_err(|err| {
+                cx.common
+                    .send_fatal_alert(AlertDescription::UnsupportedCertificate, err)
+            })?;
+
         // We communicate to the upper layer what kind of key they should choose
         // via the sigschemes value.  Clients tend to treat this extension
         // orthogonally to offered ciphersuites (even though, in TLS1.2 it is not).
@@ -310,13 +449,24 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
         sig_schemes
             .retain(|scheme| suites::compatible_sigscheme_for_suites(*scheme, &client_suites));
 
+        // An external PSK authenticates a TLS1.3 handshake without a certificate.
+        let external_psk = match version {
+            ProtocolVersion::TLSv1_3 => tls13::offered_external_psk(&self.config, client_hello),
+            _ => None,
+        };
+
         // Choose a certificate.
         let certkey = {
             let client_hello = ClientHello::new(
                 &cx.data.sni,
                 &sig_schemes,
+                client_hello.get_sigalgs_cert_extension(),
                 client_hello.get_alpn_extension(),
                 &client_hello.cipher_suites,
+                cx.data
+                    .client_hello_fingerprint
+                    .as_deref()
+                    .unwrap_or_default(),
             );
 
             let certkey = self
@@ -324,49 +474,121 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
                 .cert_resolver
                 .resolve(client_hello);
 
-            certkey.ok_or_else(|| {
-                cx.common.send_fatal_alert(
-                    AlertDescription::AccessDenied,
-                    Error::General("no server certificate chain resolved".to_owned()),
-                )
-            })?
+            match (certkey, external_psk) {
+                (Some(certkey), _) => Some(certkey),
+                (None, Some(_)) => None,
+                (None, None) => {
+                    return Err(cx.common.send_fatal_alert(
+                        AlertDescription::AccessDenied,
+                        Error::General("no server certificate chain resolved".to_owned()),
+                    ));
+                }
+            }
+        };
+        let certkey = certkey
+            .as_deref()
+            .map(ActiveCertifiedKey::from_certified_key);
+
+        // Reduce our supported ciphersuites by the certificate
+        // (no-op for TLS1.3) and version.  This is usually done in
+        // advance, when the config is built.
+        let sigalg = certkey
+            .as_ref()
+            .map(|certkey| certkey.get_key().algorithm());
+        let computed_suites;
+        let suitable_suites = match self
+            .config
+            .suitable_suites
+            .get(version, sigalg)
+        {
+            Some(suites) => suites,
+            None => {
+                let suites = match sigalg {
+                    Some(sigalg) => suites::reduce_given_sigalg(&self.config.cipher_suites, sigalg),
+                    None => self.config.cipher_suites.clone(),
+                };
+                computed_suites = suites::reduce_given_version(&suites, version);
+                &computed_suites
+            }
         };
-        let certkey = ActiveCertifiedKey::from_certified_key(&certkey);
-
-        // Reduce our supported ciphersuites by the certificate.
-        // (no-op for TLS1.3)
-        let suitable_suites =
-            suites::reduce_given_sigalg(&self.config.cipher_suites, certkey.get_key().algorithm());
 
-        // And version
-        let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
+        let choose_ciphersuite = |suitable_suites: &[SupportedCipherSuite]| {
+            if let Some(selector) = &self.config.cipher_suite_selector {
+                let mutual_suites = match self.config.ignore_client_order {
+                    true => suitable_suites
+                        .iter()
+                        .filter(|scs| {
+                            client_hello
+                                .cipher_suites
+                                .contains(&scs.suite())
+                        })
+                        .copied()
+                        .collect::<Vec<_>>(),
+                    false => client_hello
+                        .cipher_suites
+                        .iter()
+                        .filter_map(|cs| {
+                            suitable_suites
+                                .iter()
+                                .find(|scs| scs.suite() == *cs)
+                                .copied()
+                        })
+                        .collect(),
+                };
+                let hello = ClientHello::new(
+                    &cx.data.sni,
+                    &sig_schemes,
+                    client_hello.get_sigalgs_cert_extension(),
+                    client_hello.get_alpn_extension(),
+                    &client_hello.cipher_suites,
+                    cx.data
+                        .client_hello_fingerprint
+                        .as_deref()
+                        .unwrap_or_default(),
+                );
+                selector(&hello, &mutual_suites).filter(|scs| mutual_suites.contains(scs))
+            } else if self.config.ignore_client_order {
+                suites::choose_ciphersuite_preferring_server(
+                    &client_hello.cipher_suites,
+                    suitable_suites,
+                )
+            } else {
+                suites::choose_ciphersuite_preferring_client(
+                    &client_hello.cipher_suites,
+                    suitable_suites,
+                )
+            }
+        };
 
-        let suite = if self.config.ignore_client_order {
-            suites::choose_ciphersuite_preferring_server(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
-        } else {
-            suites::choose_ciphersuite_preferring_client(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
-        }
-        .ok_or_else(|| {
-            cx.common.send_fatal_alert(
-                AlertDescription::HandshakeFailure,
-                PeerIncompatible::NoCipherSuitesInCommon,
-            )
-        })?;
+        // Prefer a suite we can use with the client's external PSK, if it
+        // offered one.  Without a certificate, we must use one.
+        let suite = external_psk
+            .and_then(|psk| {
+                choose_ciphersuite(&suites::reduce_given_hash(suitable_suites, psk.hash()))
+            })
+            .or_else(|| match certkey {
+                Some(_) => choose_ciphersuite(suitable_suites),
+                None => None,
+            })
+            .ok_or_else(|| {
+                cx.common.send_fatal_alert(
+                    AlertDescription::HandshakeFailure,
+                    PeerIncompatible::NoCipherSuitesInCommon,
+                )
+            })?;
 
         debug!("decided upon suite {:?}", suite);
         cx.common.suite = Some(suite);
 
         // Start handshake hash.
-        let starting_hash = suite.hash_algorithm();
+        let starting_hash = suite.hash_provider();
         let transcript = match self.transcript {
             HandshakeHashOrBuffer::Buffer(inner) => inner.start_hash(starting_hash),
-            HandshakeHashOrBuffer::Hash(inner) if inner.algorithm() == starting_hash => inner,
+            HandshakeHashOrBuffer::Hash(inner)
+                if inner.algorithm() == starting_hash.algorithm() =>
+            {
+                inner
+            }
             _ => {
                 return Err(cx.common.send_fatal_alert(
                     AlertDescription::IllegalParameter,
@@ -377,6 +599,7 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
 
         // Save their Random.
         let randoms = ConnectionRandoms::new(client_hello.random, Random::new::<C>()?);
+        let alpn_protocol = choose_alpn_protocol(&self.config, client_hello)?;
         match suite {
             SupportedCipherSuite::Tls13(suite) => tls13::CompleteClientHelloHandling {
                 config: self.config,
@@ -386,6 +609,8 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
                 done_retry: self.done_retry,
                 send_tickets: self.send_tickets,
                 extra_exts: self.extra_exts,
+                alpn_protocol,
+                cert_types,
             }
             .handle_client_hello(cx, certkey, m, client_hello, sig_schemes),
             #[cfg(feature = "tls12")]
@@ -398,10 +623,12 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
                 randoms,
                 send_ticket: self.send_tickets > 0,
                 extra_exts: self.extra_exts,
+                alpn_protocol,
             }
             .handle_client_hello(
                 cx,
-                certkey,
+                // We only go without a certificate for TLS1.3.
+                certkey.expect("no certificate for TLS1.2"),
                 m,
                 client_hello,
                 sig_schemes,
@@ -413,6 +640,23 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientHello<C> {
     fn handle(self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> NextStateOrError {
+        // A client responding to a stateless HelloRetryRequest sends its
+        // middlebox compatibility CCS before the second `ClientHello`, so
+        // that is the first thing we see.
+        if let MessagePayload::ChangeCipherSpec(_) = m.payload {
+            if self
+                .config
+                .hello_retry_cookie_key
+                .is_some()
+                && !self.done_retry
+                && cx.common.received_middlebox_ccs == 0
+            {
+                cx.common.received_middlebox_ccs += 1;
+                trace!("Dropping CCS before ClientHello");
+                return Ok(self);
+            }
+        }
+
         let (client_hello, sig_schemes) = process_client_hello(&m, self.done_retry, cx)?;
         self.with_certified_key(sig_schemes, client_hello, &m, cx)
     }
@@ -451,6 +695,13 @@ pub(super) fn process_client_hello<'a>(
         ));
     }
 
+    cx.data.client_hello_fingerprint = Some(client_hello.ja3_string());
+    cx.common.peer_extensions = client_hello
+        .extensions
+        .iter()
+        .map(|ext| ext.get_type())
+        .collect();
+
     // No handshake messages should follow this one in this flight.
     cx.common.check_aligned_handshake()?;
 
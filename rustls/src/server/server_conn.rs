@@ -1,22 +1,33 @@
+use crate::buffer_pool::BufferPool;
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
 use crate::common_state::{CommonState, Context, Side, State};
+use crate::compress::CertificateCompressor;
 use crate::conn::{ConnectionCommon, ConnectionCore};
-use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::crypto::{hmac, CryptoProvider, KeyExchange, SupportedGroup};
 use crate::dns_name::DnsName;
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
+use crate::handshake_observer::HandshakeObserver;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::base::Payload;
+use crate::msgs::enums::{CertificateType, NamedGroup};
+use crate::msgs::fragmenter::{MessageFragmenter, PACKET_OVERHEAD};
 use crate::msgs::handshake::{ClientHelloPayload, ProtocolName, ServerExtension};
 use crate::msgs::message::Message;
+use crate::psk::{PresharedKey, PskKeyExchangePolicy};
 use crate::sign;
-use crate::suites::SupportedCipherSuite;
+use crate::suites::{SuitableSuites, SupportedCipherSuite};
+use crate::time::{TimeProvider, UnixTime};
+use crate::tls13::RecordPadding;
+use crate::unknown_extensions::UnknownExtensionHandler;
 use crate::vecbuf::ChunkVecBuffer;
 use crate::verify;
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
 use crate::KeyLog;
+#[cfg(feature = "testing")]
+use crate::{key, RootCertStore};
 
 use super::hs;
 
@@ -110,8 +121,10 @@ pub trait ResolvesServerCert: Send + Sync {
 pub struct ClientHello<'a> {
     server_name: &'a Option<DnsName>,
     signature_schemes: &'a [SignatureScheme],
+    certificate_signature_schemes: Option<&'a [SignatureScheme]>,
     alpn: Option<&'a Vec<ProtocolName>>,
     cipher_suites: &'a [CipherSuite],
+    ja3_fingerprint: &'a str,
 }
 
 impl<'a> ClientHello<'a> {
@@ -119,19 +132,24 @@ impl<'a> ClientHello<'a> {
     pub(super) fn new(
         server_name: &'a Option<DnsName>,
         signature_schemes: &'a [SignatureScheme],
+        certificate_signature_schemes: Option<&'a [SignatureScheme]>,
         alpn: Option<&'a Vec<ProtocolName>>,
         cipher_suites: &'a [CipherSuite],
+        ja3_fingerprint: &'a str,
     ) -> Self {
         trace!("sni {:?}", server_name);
         trace!("sig schemes {:?}", signature_schemes);
+        trace!("cert sig schemes {:?}", certificate_signature_schemes);
         trace!("alpn protocols {:?}", alpn);
         trace!("cipher suites {:?}", cipher_suites);
 
         ClientHello {
             server_name,
             signature_schemes,
+            certificate_signature_schemes,
             alpn,
             cipher_suites,
+            ja3_fingerprint,
         }
     }
 
@@ -151,6 +169,15 @@ impl<'a> ClientHello<'a> {
         self.signature_schemes
     }
 
+    /// Get the signature schemes the client accepts in certificates.
+    ///
+    /// These come from the `signature_algorithms_cert` extension.  If the
+    /// client omitted it, this is the same as [`Self::signature_schemes`].
+    pub fn certificate_signature_schemes(&self) -> &[SignatureScheme] {
+        self.certificate_signature_schemes
+            .unwrap_or(self.signature_schemes)
+    }
+
     /// Get the ALPN protocol identifiers submitted by the client.
     ///
     /// Returns `None` if the client did not include an ALPN extension.
@@ -180,6 +207,13 @@ impl<'a> ClientHello<'a> {
     pub fn cipher_suites(&self) -> &[CipherSuite] {
         self.cipher_suites
     }
+
+    /// Get the JA3 fingerprint string of the ClientHello.
+    ///
+    /// See [`ServerConnection::client_hello_fingerprint`] for its format.
+    pub fn ja3_fingerprint(&self) -> &str {
+        self.ja3_fingerprint
+    }
 }
 
 /// Common configuration for a set of server sessions.
@@ -194,12 +228,40 @@ impl<'a> ClientHello<'a> {
 /// * [`ServerConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
 /// * [`ServerConfig::session_storage`]: the default stores 256 sessions in memory.
 /// * [`ServerConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
+/// * [`ServerConfig::with_alpn_protocols`]: clients offering none of `alpn_protocols` are refused.
+/// * [`ServerConfig::with_alpn_selector`]: the first of `alpn_protocols` the client offers is chosen.
+/// * [`ServerConfig::with_strict_resumption`]: sessions may be resumed with a different ALPN protocol.
+/// * [`ServerConfig::with_signature_schemes`]: all schemes supported by the verifier and our keys.
 /// * [`ServerConfig::key_log`]: key material is not logged.
-/// * [`ServerConfig::send_tls13_tickets`]: 4 tickets are sent.
+/// * [`ServerConfig::handshake_observer`]: handshakes are not observed.
+/// * [`ServerConfig::send_tls13_tickets`]: 2 tickets are sent.
+/// * [`ServerConfig::hello_retry_for_preferred_group`]: the client's key shares are used if possible.
+/// * [`ServerConfig::with_stateless_hello_retry`]: HelloRetryRequest state is kept in the connection.
+/// * [`ServerConfig::with_record_padding`]: records are not padded.
+/// * [`ServerConfig::with_session_id_resumption`]: TLS1.2 sessions can be resumed by session ID.
+/// * [`ServerConfig::with_deferred_tickets`]: TLS1.3 tickets are sent as soon as the handshake completes.
+/// * [`ServerConfig::with_cipher_suite_selector`]: the first suite in preference order is chosen.
+/// * [`ServerConfig::with_require_sni`]: clients need not send a server name.
+/// * [`ServerConfig::with_buffer_pool`]: each record is sent from a newly allocated buffer.
+/// * [`ServerConfig::with_retained_plaintext_capacity`]: 1 KiB.
+/// * [`ServerConfig::with_write_buffering`]: each write is sent in its own record(s).
+/// * [`ServerConfig::with_unknown_extension_handler`]: unknown extensions are ignored.
+/// * [`ServerConfig::with_server_cert_types`]: we authenticate with certificates.
+/// * [`ServerConfig::with_client_cert_types`]: clients authenticate with certificates.
+/// * [`ServerConfig::with_raw_public_key`]: we have no raw public key.
+/// * [`ServerConfig::with_pinned_client_keys`]: no raw public keys are accepted.
+/// * [`ServerConfig::cert_compressors`]: the codecs enabled by crate features.
+/// * [`ServerConfig::preshared_keys`]: the default is empty -- no external PSKs are accepted.
+/// * [`ServerConfig::psk_key_exchange_policy`]: the default is to prefer `psk_dhe_ke`.
+/// * [`ServerConfig::time_provider`]: the system clock.
 pub struct ServerConfig<C: CryptoProvider> {
     /// List of ciphersuites, in preference order.
     pub(super) cipher_suites: Vec<SupportedCipherSuite>,
 
+    /// `cipher_suites`, divided up by the versions and certificate
+    /// key types they are usable with.
+    pub(super) suitable_suites: SuitableSuites,
+
     /// List of supported key exchange groups.
     ///
     /// The first is the highest priority: they will be
@@ -218,6 +280,8 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// Out of range values are reported as errors from ServerConnection::new.
     ///
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
+    ///
+    /// See also [`ServerConfig::with_max_send_fragment`].
     pub max_fragment_size: Option<usize>,
 
     /// How to store client sessions.
@@ -244,6 +308,10 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Observes the outcome of handshakes, for example to collect metrics.
+    /// The default is `None`.
+    pub handshake_observer: Option<Arc<dyn HandshakeObserver>>,
+
     /// Allows traffic secrets to be extracted after the handshake,
     /// e.g. for kTLS setup.
     #[cfg(feature = "secret_extraction")]
@@ -290,12 +358,125 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// Because TLS1.3 tickets are single-use, this allows
     /// a client to perform multiple resumptions.
     ///
-    /// The default is 4.
+    /// The default is 2.  See also [`ServerConfig::with_num_tickets`].
     ///
     /// If this is 0, no tickets are sent and clients will not be able to
     /// do any resumption.
     pub send_tls13_tickets: usize,
 
+    /// Whether to insist on our most preferred key exchange group in TLS1.3.
+    ///
+    /// Normally we choose the most preferred group for which the client sent
+    /// a key share, to avoid a round trip.  If this is true and the client
+    /// supports a group earlier in [`ConfigBuilder::with_kx_groups`] order
+    /// than any it sent a key share for, we instead send a HelloRetryRequest
+    /// asking for a key share in that group.
+    ///
+    /// The default is false.
+    ///
+    /// [`ConfigBuilder::with_kx_groups`]: crate::ConfigBuilder::with_kx_groups
+    pub hello_retry_for_preferred_group: bool,
+
+    /// Codecs we can use to compress our certificate in TLS1.3, as described
+    /// in RFC8879.  We compress with the client's most preferred codec that
+    /// is in this list.  If empty, we never compress it.
+    ///
+    /// The default is [`crate::compress::default_cert_compressors`].
+    pub cert_compressors: Vec<Arc<dyn CertificateCompressor>>,
+
+    /// External pre-shared keys we accept from clients in TLS1.3.  A
+    /// handshake using one of these is authenticated by the key, so we do
+    /// not send a certificate, and need not have one.
+    ///
+    /// The default is empty.
+    pub preshared_keys: Vec<Arc<PresharedKey>>,
+
+    /// Whether handshakes using [`ServerConfig::preshared_keys`] include
+    /// an (EC)DHE key exchange.
+    ///
+    /// The default is [`PskKeyExchangePolicy::PreferDhe`].
+    pub psk_key_exchange_policy: PskKeyExchangePolicy,
+
+    /// Key authenticating HelloRetryRequest cookies, if we send them.
+    /// See [`ServerConfig::with_stateless_hello_retry`].
+    pub(super) hello_retry_cookie_key: Option<Arc<dyn hmac::Key>>,
+
+    /// How to pad TLS1.3 records.  See [`ServerConfig::with_record_padding`].
+    pub(super) record_padding: RecordPadding,
+
+    /// Whether TLS1.2 clients may resume using a session ID.
+    /// See [`ServerConfig::with_session_id_resumption`].
+    pub(super) session_id_resumption: bool,
+
+    /// Whether TLS1.3 tickets wait for the application's first write.
+    /// See [`ServerConfig::with_deferred_tickets`].
+    pub(super) defer_tls13_tickets: bool,
+
+    /// Overrides our choice of cipher suite.
+    /// See [`ServerConfig::with_cipher_suite_selector`].
+    pub(super) cipher_suite_selector: Option<Arc<SelectsCipherSuite>>,
+
+    /// Whether clients must send a server name.
+    /// See [`ServerConfig::with_require_sni`].
+    pub(super) require_sni: bool,
+
+    /// What to do if the client offers none of `alpn_protocols`.
+    /// See [`ServerConfig::with_alpn_protocols`].
+    pub(super) alpn_policy: AlpnPolicy,
+
+    /// Overrides our choice of ALPN protocol.
+    /// See [`ServerConfig::with_alpn_selector`].
+    pub(super) alpn_selector: Option<Arc<SelectsAlpnProtocol>>,
+
+    /// Whether resumption needs the same ALPN protocol as the original session.
+    /// See [`ServerConfig::with_strict_resumption`].
+    pub(super) strict_resumption: bool,
+
+    /// The only signature schemes we offer and accept, if restricted.
+    /// See [`ServerConfig::with_signature_schemes`].
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
+    /// Supplies buffers for the records we send.
+    /// See [`ServerConfig::with_buffer_pool`].
+    pub(super) buffer_pool: Option<Arc<BufferPool>>,
+
+    /// Memory kept by each connection's received plaintext buffer when
+    /// idle.  See [`ServerConfig::with_retained_plaintext_capacity`].
+    pub(super) retained_plaintext_capacity: usize,
+
+    /// How much plaintext to accumulate from small writes before sending it.
+    /// See [`ServerConfig::with_write_buffering`].
+    pub(super) write_buffer_threshold: usize,
+
+    /// Told about extensions in the client's hello that we don't understand.
+    /// See [`ServerConfig::with_unknown_extension_handler`].
+    pub(super) unknown_extension_handler: Option<Arc<dyn UnknownExtensionHandler>>,
+
+    /// The certificate types we can authenticate with, in preference order.
+    /// See [`ServerConfig::with_server_cert_types`].
+    pub(super) server_cert_types: Vec<CertificateType>,
+
+    /// The certificate types we accept from clients, in preference order.
+    /// See [`ServerConfig::with_client_cert_types`].
+    pub(super) client_cert_types: Vec<CertificateType>,
+
+    /// Our raw public key, for clients which accept one.
+    /// See [`ServerConfig::with_raw_public_key`].
+    pub(super) raw_public_key: Option<sign::RawPublicKey>,
+
+    /// The SubjectPublicKeyInfos of the clients we accept.
+    /// See [`ServerConfig::with_pinned_client_keys`].
+    pub(super) pinned_client_keys: Vec<Vec<u8>>,
+
+    /// Where we learn the current time, for verifying client certificates
+    /// and checking the age of resumed sessions.
+    ///
+    /// The [`ServerConfig::ticketer`] keeps its own time: give it the same
+    /// provider, for example with [`crate::Ticketer::new_with_time_provider`].
+    ///
+    /// The default is [`crate::time::DefaultTimeProvider`].
+    pub time_provider: Arc<dyn TimeProvider>,
+
     pub(crate) provider: PhantomData<C>,
 }
 
@@ -305,6 +486,7 @@ impl<C: CryptoProvider> Clone for ServerConfig<C> {
         Self {
             cipher_suites: self.cipher_suites.clone(),
             kx_groups: self.kx_groups.clone(),
+            suitable_suites: self.suitable_suites.clone(),
             ignore_client_order: self.ignore_client_order,
             max_fragment_size: self.max_fragment_size,
             session_storage: Arc::clone(&self.session_storage),
@@ -314,11 +496,35 @@ impl<C: CryptoProvider> Clone for ServerConfig<C> {
             versions: self.versions,
             verifier: Arc::clone(&self.verifier),
             key_log: Arc::clone(&self.key_log),
+            handshake_observer: self.handshake_observer.clone(),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: self.enable_secret_extraction,
             max_early_data_size: self.max_early_data_size,
             send_half_rtt_data: self.send_half_rtt_data,
             send_tls13_tickets: self.send_tls13_tickets,
+            hello_retry_for_preferred_group: self.hello_retry_for_preferred_group,
+            cert_compressors: self.cert_compressors.clone(),
+            preshared_keys: self.preshared_keys.clone(),
+            psk_key_exchange_policy: self.psk_key_exchange_policy,
+            hello_retry_cookie_key: self.hello_retry_cookie_key.clone(),
+            record_padding: self.record_padding.clone(),
+            session_id_resumption: self.session_id_resumption,
+            defer_tls13_tickets: self.defer_tls13_tickets,
+            cipher_suite_selector: self.cipher_suite_selector.clone(),
+            require_sni: self.require_sni,
+            alpn_policy: self.alpn_policy,
+            alpn_selector: self.alpn_selector.clone(),
+            strict_resumption: self.strict_resumption,
+            signature_schemes: self.signature_schemes.clone(),
+            buffer_pool: self.buffer_pool.clone(),
+            retained_plaintext_capacity: self.retained_plaintext_capacity,
+            write_buffer_threshold: self.write_buffer_threshold,
+            unknown_extension_handler: self.unknown_extension_handler.clone(),
+            server_cert_types: self.server_cert_types.clone(),
+            client_cert_types: self.client_cert_types.clone(),
+            raw_public_key: self.raw_public_key.clone(),
+            pinned_client_keys: self.pinned_client_keys.clone(),
+            time_provider: Arc::clone(&self.time_provider),
             provider: PhantomData,
         }
     }
@@ -333,6 +539,10 @@ impl<C: CryptoProvider> fmt::Debug for ServerConfig<C> {
             .field("max_early_data_size", &self.max_early_data_size)
             .field("send_half_rtt_data", &self.send_half_rtt_data)
             .field("send_tls13_tickets", &self.send_tls13_tickets)
+            .field(
+                "hello_retry_for_preferred_group",
+                &self.hello_retry_for_preferred_group,
+            )
             .finish_non_exhaustive()
     }
 }
@@ -348,6 +558,58 @@ impl<C: CryptoProvider> ServerConfig<C> {
         }
     }
 
+    /// Make a configuration with a freshly generated self-signed
+    /// certificate for `names`, and a [`RootCertStore`] trusting it.
+    ///
+    /// `names` are DNS names or IP addresses.  The certificate uses an
+    /// Ed25519 key, and the configuration otherwise has safe defaults
+    /// and no client authentication.  The key and serial number come from
+    /// the provider's [`CryptoProvider::fill_random`]; the certificate is
+    /// signed with *ring*.  Give the `RootCertStore` to
+    /// [`ClientConfig`]s that should connect to it.
+    ///
+    /// This is intended for tests, and needs the `testing` feature.
+    ///
+    /// # Panics
+    ///
+    /// If key generation fails.
+    ///
+    /// [`ClientConfig`]: crate::ClientConfig
+    #[cfg(feature = "testing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+    pub fn self_signed(names: &[&str]) -> (Self, RootCertStore) {
+        let mut serial = [0u8; 16];
+        C::fill_random(&mut serial).expect("cannot make certificate serial number");
+        // positive, and minimally encoded.
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+
+        let mut seed = [0u8; 32];
+        C::fill_random(&mut seed).expect("cannot make certificate key");
+
+        let (cert, key) = crate::x509::self_signed_certificate(names, &serial, &seed)
+            .expect("cannot make self-signed certificate");
+        let cert = key::Certificate(cert);
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&cert)
+            .expect("cannot trust self-signed certificate");
+
+        let config = Self::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key::PrivateKey(key))
+            .expect("cannot use self-signed certificate");
+        (config, roots)
+    }
+
+    /// The current time, according to [`Self::time_provider`].
+    pub(crate) fn current_time(&self) -> Result<UnixTime, Error> {
+        self.time_provider
+            .current_time()
+            .ok_or(Error::FailedToGetCurrentTime)
+    }
+
     /// We support a given TLS version if it's quoted in the configured
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
@@ -358,8 +620,389 @@ impl<C: CryptoProvider> ServerConfig<C> {
                 .iter()
                 .any(|cs| cs.version().version == v)
     }
+
+    /// The names of the configured key exchange groups, in preference
+    /// order.
+    pub fn kx_groups_named(&self) -> Vec<NamedGroup> {
+        self.kx_groups
+            .iter()
+            .map(|group| group.name())
+            .collect()
+    }
+
+    /// The IANA identifiers of the configured cipher suites, in
+    /// preference order.
+    pub fn cipher_suites_named(&self) -> Vec<CipherSuite> {
+        self.cipher_suites
+            .iter()
+            .map(|suite| suite.suite())
+            .collect()
+    }
+
+    /// Make TLS1.3 HelloRetryRequests stateless, using `secret` to
+    /// authenticate the cookies that carry the handshake state.
+    ///
+    /// When we send a HelloRetryRequest, we include a `cookie` extension
+    /// (RFC8446 section 4.2.2) which encodes the transcript of the handshake
+    /// so far, authenticated with HMAC-SHA256 keyed with `secret`.  The client
+    /// echoes it in its second `ClientHello`, which may then be handled by a
+    /// fresh `ServerConnection` -- for example, on another server behind a
+    /// load balancer -- provided that its config has the same cipher suites,
+    /// key exchange groups and `secret`.  Any `ServerConnection` receiving a
+    /// cookie that fails authentication aborts the handshake.
+    ///
+    /// Cookies do not expire and are not bound to the client's address, so
+    /// an attacker can replay one to skip the HelloRetryRequest round trip.
+    /// `secret` should be a uniformly random value of at least 32 bytes.
+    ///
+    /// The default is to keep HelloRetryRequest state in the `ServerConnection`,
+    /// and not send cookies.
+    pub fn with_stateless_hello_retry(mut self, secret: &[u8]) -> Self {
+        self.hello_retry_cookie_key = Some(Arc::from(C::hmac_sha256().with_key(secret)));
+        self
+    }
+
+    /// Set how TLS1.3 records are padded, to obscure the length of
+    /// their contents.  See [`RecordPadding`] for the options.
+    ///
+    /// The default is [`RecordPadding::None`].
+    pub fn with_record_padding(mut self, policy: RecordPadding) -> Self {
+        self.record_padding = policy;
+        self
+    }
+
+    /// Set how many TLS1.3 tickets to send after a successful handshake.
+    ///
+    /// `n` is capped at 8, the number of tickets the default client-side
+    /// storage keeps for each server.  Zero disables TLS1.3 tickets.
+    ///
+    /// This sets [`ServerConfig::send_tls13_tickets`]; the default is 2.
+    pub fn with_num_tickets(mut self, n: u8) -> Self {
+        self.send_tls13_tickets = usize::from(n.min(MAX_TLS13_TICKETS));
+        self
+    }
+
+    /// Set whether TLS1.2 clients may resume a session using its session ID.
+    ///
+    /// If `false`, we send an empty session ID and never look up or store
+    /// sessions in [`ServerConfig::session_storage`] for TLS1.2.  Tickets from
+    /// [`ServerConfig::ticketer`] are still issued and accepted, so this allows
+    /// stateless resumption only.
+    ///
+    /// The default is `true`.
+    pub fn with_session_id_resumption(mut self, enabled: bool) -> Self {
+        self.session_id_resumption = enabled;
+        self
+    }
+
+    /// Set whether to hold back TLS1.3 tickets until the application
+    /// first writes data after the handshake.
+    ///
+    /// Tickets are then sent with the first call to the connection's
+    /// `writer()`, or earlier with [`ServerConnection::send_session_ticket`].
+    /// A connection that closes before either happens never does the work
+    /// of issuing its tickets.  QUIC connections are unaffected.
+    ///
+    /// The default is `false`: tickets are sent as soon as the handshake
+    /// completes.
+    pub fn with_deferred_tickets(mut self, defer: bool) -> Self {
+        self.defer_tls13_tickets = defer;
+        self
+    }
+
+    /// Set a function which chooses the cipher suite for each handshake.
+    ///
+    /// `selector` is given the client's hello and the cipher suites
+    /// both sides support which are usable with the chosen certificate,
+    /// in the order we would otherwise prefer them (see
+    /// [`ServerConfig::ignore_client_order`]).  It returns the suite to use,
+    /// which must be one of those, or `None` to abort the handshake.
+    ///
+    /// When the client offers an external PSK, `selector` is first asked
+    /// to choose among the suites usable with that PSK; if it returns `None`,
+    /// we fall back to certificate authentication and ask again.
+    ///
+    /// The default is to choose the first suite in preference order.
+    pub fn with_cipher_suite_selector(
+        mut self,
+        selector: impl Fn(&ClientHello<'_>, &[SupportedCipherSuite]) -> Option<SupportedCipherSuite>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.cipher_suite_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Set whether clients must send the `server_name` extension.
+    ///
+    /// This suits servers hosting many names, which can't choose a
+    /// certificate without one.  When `true`, a ClientHello without a
+    /// server name is refused with a `missing_extension` alert for
+    /// TLS1.3, or `handshake_failure` for TLS1.2, and the handshake
+    /// fails with [`crate::PeerIncompatible::ServerNameExtensionRequired`].
+    ///
+    /// The default is `false`.
+    pub fn with_require_sni(mut self, required: bool) -> Self {
+        self.require_sni = required;
+        self
+    }
+
+    /// Set the ALPN protocols we support, most preferred first, and what
+    /// to do if a client offers only others.
+    ///
+    /// This sets [`ServerConfig::alpn_protocols`].  Clients which do not
+    /// use ALPN at all are unaffected by `policy`.
+    ///
+    /// The default policy is [`AlpnPolicy::Strict`].
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>, policy: AlpnPolicy) -> Self {
+        self.alpn_protocols = protocols;
+        self.alpn_policy = policy;
+        self
+    }
+
+    /// Set a function which chooses the ALPN protocol for each handshake.
+    ///
+    /// `selector` is given the protocols the client offered, in the
+    /// client's order of preference, and returns the one to use.  This
+    /// replaces choosing the first of [`ServerConfig::alpn_protocols`]
+    /// which the client offered.
+    ///
+    /// If `selector` returns `None`, or a protocol the client did not
+    /// offer, no protocol is chosen: what happens then is as if the
+    /// client had offered none of `alpn_protocols`
+    /// (see [`ServerConfig::with_alpn_protocols`]).  `selector` is not
+    /// called for clients which do not use ALPN.
+    ///
+    /// The default is to choose the first of `alpn_protocols` the client
+    /// offered.
+    pub fn with_alpn_selector(
+        mut self,
+        selector: impl Fn(&[&[u8]]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.alpn_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Only resume sessions with the same ALPN protocol as was negotiated
+    /// when the session was established.
+    ///
+    /// If `strict` is true, a client resuming a session for which (say)
+    /// `h2` was negotiated, but for which we would now choose `http/1.1`
+    /// (or no protocol), gets a full handshake instead.  This applies to
+    /// TLS1.2 and TLS1.3 resumption alike.
+    ///
+    /// Sessions are never resumed with a different server name, whatever
+    /// this setting: RFC6066 forbids it.
+    ///
+    /// The default is `false`: sessions may be resumed whichever ALPN
+    /// protocol is chosen.
+    pub fn with_strict_resumption(mut self, strict: bool) -> Self {
+        self.strict_resumption = strict;
+        self
+    }
+
+    /// Only offer and accept the signature schemes in `schemes`.
+    ///
+    /// We only sign our handshake with one of `schemes`, so a client
+    /// supporting none of them for our certificate's key cannot connect.
+    /// If we ask for client certificates, the `signature_algorithms` we
+    /// send are reduced to those of the verifier's schemes which are in
+    /// `schemes`, and a client signing with any other scheme is rejected.
+    ///
+    /// This fails if `schemes` is empty.  By default, all schemes
+    /// supported by the verifier and our keys are used.
+    pub fn with_signature_schemes(mut self, schemes: &[SignatureScheme]) -> Result<Self, Error> {
+        if schemes.is_empty() {
+            return Err(Error::General("no signature schemes configured".into()));
+        }
+        self.signature_schemes = Some(schemes.to_vec());
+        Ok(self)
+    }
+
+    /// Whether [`Self::with_signature_schemes`] allows `scheme`.
+    pub(crate) fn allows_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+        self.signature_schemes
+            .as_ref()
+            .map_or(true, |schemes| schemes.contains(&scheme))
+    }
+
+    /// Encode the records connections send into buffers taken from
+    /// `pool`, returning them to it once written.
+    ///
+    /// This reduces allocations for servers handling many connections;
+    /// see [`BufferPool`].
+    ///
+    /// The default is to allocate a new buffer for each record.
+    pub fn with_buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Set how many bytes of memory a connection's received plaintext
+    /// buffer may keep once the application has read everything from it.
+    ///
+    /// The buffer is allocated as records are decrypted, and grows to
+    /// hold however much the application has yet to read.  Once that
+    /// has all been read, it shrinks back to at most `bytes`.  A lower
+    /// value saves memory for servers holding many idle connections, at
+    /// the cost of more allocations when a connection becomes busy again.
+    ///
+    /// The default is 1 KiB.
+    pub fn with_retained_plaintext_capacity(mut self, bytes: usize) -> Self {
+        self.retained_plaintext_capacity = bytes;
+        self
+    }
+
+    /// Hold back small writes to a connection's `writer()`, so that they
+    /// can share TLS records.
+    ///
+    /// Written plaintext accumulates until there are `threshold` bytes
+    /// of it, or the application calls `flush()` on the writer; only then
+    /// is it encrypted and queued for [`Connection::write_tls`].  A single
+    /// write of at least `threshold` bytes is sent immediately.  Sending
+    /// close_notify also sends any held-back data first.
+    ///
+    /// This saves the per-record overhead for protocols which make many
+    /// small writes, but applications must remember to flush.
+    ///
+    /// The default is 0: writes are not held back.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    pub fn with_write_buffering(mut self, threshold: usize) -> Self {
+        self.write_buffer_threshold = threshold;
+        self
+    }
+
+    /// Give each extension in the client's hello that rustls does not
+    /// understand to `handler`, which may abort the handshake.
+    ///
+    /// The default is to ignore such extensions.
+    pub fn with_unknown_extension_handler(
+        mut self,
+        handler: Arc<dyn UnknownExtensionHandler>,
+    ) -> Self {
+        self.unknown_extension_handler = Some(handler);
+        self
+    }
+
+    /// Authenticate with the first of `types`, in preference order, that
+    /// the client offers in its `server_certificate_type` extension
+    /// (RFC 7250).
+    ///
+    /// Clients which don't send the extension, and all TLS1.2 clients,
+    /// only accept [`CertificateType::X509`].  Clients which accept none
+    /// of `types` are rejected with [`PeerIncompatible::NoCertificateTypesInCommon`].
+    /// [`CertificateType::RawPublicKey`] is skipped unless one is set with
+    /// [`ServerConfig::with_raw_public_key`].
+    ///
+    /// The default is `[X509]`.
+    ///
+    /// [`CertificateType::X509`]: crate::CertificateType::X509
+    /// [`CertificateType::RawPublicKey`]: crate::CertificateType::RawPublicKey
+    /// [`PeerIncompatible::NoCertificateTypesInCommon`]: crate::PeerIncompatible::NoCertificateTypesInCommon
+    pub fn with_server_cert_types(mut self, types: Vec<CertificateType>) -> Self {
+        self.server_cert_types = types;
+        self
+    }
+
+    /// Ask clients to authenticate with the first of `types`, in
+    /// preference order, that they offer in their `client_certificate_type`
+    /// extension (RFC 7250).
+    ///
+    /// If the client offers none of `types`, it is rejected with
+    /// [`PeerIncompatible::NoCertificateTypesInCommon`], unless `types`
+    /// includes [`CertificateType::X509`] and the verifier makes client
+    /// authentication optional.  Raw public keys are checked against
+    /// [`ServerConfig::with_pinned_client_keys`]; certificates by the
+    /// verifier.
+    ///
+    /// The default is `[X509]`.
+    ///
+    /// [`CertificateType::X509`]: crate::CertificateType::X509
+    /// [`PeerIncompatible::NoCertificateTypesInCommon`]: crate::PeerIncompatible::NoCertificateTypesInCommon
+    pub fn with_client_cert_types(mut self, types: Vec<CertificateType>) -> Self {
+        self.client_cert_types = types;
+        self
+    }
+
+    /// Authenticate with the raw public key (RFC 7250) `key`, instead of
+    /// a certificate chain, to TLS1.3 clients which accept one.
+    ///
+    /// This also calls [`ServerConfig::with_server_cert_types`] preferring
+    /// [`CertificateType::RawPublicKey`], then [`CertificateType::X509`]:
+    /// other clients get the certificate chain from the certificate
+    /// resolver as usual.
+    ///
+    /// The default is `None`.
+    ///
+    /// [`CertificateType::RawPublicKey`]: crate::CertificateType::RawPublicKey
+    /// [`CertificateType::X509`]: crate::CertificateType::X509
+    pub fn with_raw_public_key(mut self, key: sign::RawPublicKey) -> Self {
+        self.raw_public_key = Some(key);
+        self.with_server_cert_types(vec![CertificateType::RawPublicKey, CertificateType::X509])
+    }
+
+    /// Accept clients which authenticate with a raw public key (RFC 7250)
+    /// in `keys`, each a DER-encoded SubjectPublicKeyInfo, in place of a
+    /// certificate chain.  The verifier is not consulted for them; a key
+    /// not in `keys` is rejected with [`CertificateError::UnknownRawPublicKey`].
+    /// Resumed sessions are not authenticated again.
+    ///
+    /// This also calls [`ServerConfig::with_client_cert_types`] with only
+    /// [`CertificateType::RawPublicKey`], so every client must use one:
+    /// call it afterwards to accept certificates too.
+    ///
+    /// The default is empty.
+    ///
+    /// [`CertificateError::UnknownRawPublicKey`]: crate::CertificateError::UnknownRawPublicKey
+    /// [`CertificateType::RawPublicKey`]: crate::CertificateType::RawPublicKey
+    pub fn with_pinned_client_keys(mut self, keys: Vec<Vec<u8>>) -> Self {
+        self.pinned_client_keys = keys;
+        self.with_client_cert_types(vec![CertificateType::RawPublicKey])
+    }
+
+    /// Limit the plaintext carried by each TLS record we send to `size`
+    /// bytes, rather than the protocol maximum of 16384.
+    ///
+    /// This is purely local: nothing is negotiated with the peer, which
+    /// may still send full-size records.  It sets [`Self::max_fragment_size`],
+    /// which counts the 5-byte record header as well.
+    ///
+    /// Returns [`Error::BadMaxFragmentSize`] if `size` is smaller than 27
+    /// or larger than 16384.
+    pub fn with_max_send_fragment(mut self, size: usize) -> Result<Self, Error> {
+        let max_fragment_size = size + PACKET_OVERHEAD;
+        MessageFragmenter::default().set_max_fragment_size(Some(max_fragment_size))?;
+        self.max_fragment_size = Some(max_fragment_size);
+        Ok(self)
+    }
+}
+
+/// What a server does when a client offers ALPN protocols, but none of
+/// those in [`ServerConfig::alpn_protocols`].
+///
+/// See [`ServerConfig::with_alpn_protocols`].  QUIC connections are always
+/// strict, as RFC9001 requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlpnPolicy {
+    /// Fail the handshake with a `no_application_protocol` alert.
+    Strict,
+    /// Continue the handshake without an ALPN protocol: the connection's
+    /// `alpn_protocol()` is `None`.
+    AllowNoProtocol,
 }
 
+/// The function type stored by [`ServerConfig::with_cipher_suite_selector`].
+pub(super) type SelectsCipherSuite =
+    dyn Fn(&ClientHello<'_>, &[SupportedCipherSuite]) -> Option<SupportedCipherSuite> + Send + Sync;
+
+/// The function type stored by [`ServerConfig::with_alpn_selector`].
+pub(super) type SelectsAlpnProtocol = dyn Fn(&[&[u8]]) -> Option<Vec<u8>> + Send + Sync;
+
+/// The most tickets [`ServerConfig::with_num_tickets`] will configure.
+const MAX_TLS13_TICKETS: u8 = 8;
+
 /// Allows reading of early data in resumed TLS1.3 connections.
 ///
 /// "Early data" is also known as "0-RTT data".
@@ -398,12 +1041,6 @@ impl ServerConnection {
     /// Make a new ServerConnection.  `config` controls how
     /// we behave in the TLS protocol.
     pub fn new<C: CryptoProvider>(config: Arc<ServerConfig<C>>) -> Result<Self, Error> {
-        let mut common = CommonState::new(Side::Server);
-        common.set_max_fragment_size(config.max_fragment_size)?;
-        #[cfg(feature = "secret_extraction")]
-        {
-            common.enable_secret_extraction = config.enable_secret_extraction;
-        }
         Ok(Self {
             inner: ConnectionCommon::from(ConnectionCore::for_server(config, Vec::new())?),
         })
@@ -428,6 +1065,31 @@ impl ServerConnection {
         self.inner.core.get_sni_str()
     }
 
+    /// Retrieves the JA3 fingerprint string of the client's ClientHello
+    /// (the second one, if a HelloRetryRequest was needed).
+    ///
+    /// This is made of the decimal values of the legacy version, cipher
+    /// suites, extension types, named groups and point formats the client
+    /// offered, in its order.  Values within each of these fields are
+    /// separated by `-`, and the fields by `,`.  For example:
+    ///
+    /// ```text
+    /// 771,4866-4865-49196,0-10-11,29-23,0
+    /// ```
+    ///
+    /// GREASE values are left out.  The JA3 hash commonly used by bot
+    /// detection tools is the MD5 of this string, which rustls does
+    /// not compute.
+    ///
+    /// Returns `None` until the ClientHello has been received.
+    pub fn client_hello_fingerprint(&self) -> Option<&str> {
+        self.inner
+            .core
+            .data
+            .client_hello_fingerprint
+            .as_deref()
+    }
+
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
     ///
     /// Recovered from the prior session's `set_resumption_data`. Integrity is guaranteed by rustls.
@@ -455,6 +1117,18 @@ impl ServerConnection {
         self.inner.core.data.resumption_data = data.into();
     }
 
+    /// Send any TLS1.3 tickets held back by [`ServerConfig::with_deferred_tickets`].
+    ///
+    /// Otherwise they are sent with the application's first write.  This
+    /// does nothing if there are no such tickets, and fails with
+    /// [`Error::HandshakeNotComplete`] while the handshake is in progress.
+    pub fn send_session_ticket(&mut self) -> Result<(), Error> {
+        if self.inner.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+        self.inner.core.send_deferred_tickets()
+    }
+
     /// Explicitly discard early data, notifying the client
     ///
     /// Useful if invariants encoded in `received_resumption_data()` cannot be respected.
@@ -483,12 +1157,55 @@ impl ServerConnection {
         }
     }
 
+    /// Returns true if the client sent TLS1.3 0RTT/"early" data which
+    /// this server rejected.
+    ///
+    /// This happens if [`ServerConfig::max_early_data_size`] is zero, if
+    /// [`ServerConnection::reject_early_data`] was called, or if the
+    /// early data cannot be safely accepted (for example, the client is
+    /// not resuming).  Rejected early data is discarded, up to
+    /// `max_early_data_size` bytes (or one maximum-sized record, if that is
+    /// zero), and the handshake then completes as usual.
+    pub fn is_early_data_rejected(&self) -> bool {
+        self.inner
+            .core
+            .data
+            .early_data
+            .was_skipped()
+    }
+
+    /// Returns true if application data written now is sent as TLS1.3
+    /// 0.5-RTT data.
+    ///
+    /// That is, our `Finished` has been sent but the client's has not yet
+    /// been received, so the client has not proven it completed the
+    /// handshake -- it is, as yet, unauthenticated.  Data sent in this
+    /// window reaches the client without waiting another round trip, but
+    /// must not depend on anything the client has not yet confirmed.
+    ///
+    /// This only happens if [`ServerConfig::send_half_rtt_data`] is set.
+    pub fn is_sending_half_rtt_data(&self) -> bool {
+        let common = &self.inner.core.common_state;
+        common.may_send_application_data && !common.may_receive_application_data
+    }
+
     /// Extract secrets, so they can be used when configuring kTLS, for example.
     #[cfg(feature = "secret_extraction")]
     #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
         self.inner.extract_secrets()
     }
+
+    /// Extract the TLS1.2 master secret, for decrypting captured traffic
+    /// while debugging.
+    ///
+    /// See [`ConnectionCommon::dangerous_extract_tls12_master_secret`].
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_tls12_master_secret(self) -> Result<[u8; 48], Error> {
+        self.inner
+            .dangerous_extract_tls12_master_secret()
+    }
 }
 
 impl fmt::Debug for ServerConnection {
@@ -651,8 +1368,15 @@ impl Accepted {
         ClientHello::new(
             &self.connection.core.data.sni,
             &self.sig_schemes,
+            payload.get_sigalgs_cert_extension(),
             payload.get_alpn_extension(),
             &payload.cipher_suites,
+            self.connection
+                .core
+                .data
+                .client_hello_fingerprint
+                .as_deref()
+                .unwrap_or_default(),
         )
     }
 
@@ -672,6 +1396,8 @@ impl Accepted {
         {
             self.connection.enable_secret_extraction = config.enable_secret_extraction;
         }
+        self.connection.handshake_observer = config.handshake_observer.clone();
+        self.connection.record_padding = config.record_padding.clone();
 
         let state = hs::ExpectClientHello::new(config, Vec::new());
         let mut cx = hs::ServerContext::from(&mut self.connection);
@@ -717,6 +1443,8 @@ pub(super) enum EarlyDataState {
     New,
     Accepted(ChunkVecBuffer),
     Rejected,
+    /// The client sent early data, which we are skipping.
+    Skipped,
 }
 
 impl Default for EarlyDataState {
@@ -730,6 +1458,10 @@ impl EarlyDataState {
         *self = Self::Rejected;
     }
 
+    pub(super) fn skip(&mut self) {
+        *self = Self::Skipped;
+    }
+
     pub(super) fn accept(&mut self, max_size: usize) {
         *self = Self::Accepted(ChunkVecBuffer::new(Some(max_size)));
     }
@@ -742,6 +1474,10 @@ impl EarlyDataState {
         matches!(self, Self::Rejected)
     }
 
+    fn was_skipped(&self) -> bool {
+        matches!(self, Self::Skipped)
+    }
+
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             Self::Accepted(ref mut received) => received.read(buf),
@@ -802,6 +1538,11 @@ impl ConnectionCore<ServerConnectionData> {
         {
             common.enable_secret_extraction = config.enable_secret_extraction;
         }
+        common.handshake_observer = config.handshake_observer.clone();
+        common.record_padding = config.record_padding.clone();
+        common.set_buffer_pool(config.buffer_pool.clone());
+        common.set_retained_plaintext_capacity(config.retained_plaintext_capacity);
+        common.write_buffer_threshold = config.write_buffer_threshold;
         Ok(Self::new(
             Box::new(hs::ExpectClientHello::new(config, extra_exts)),
             ServerConnectionData::default(),
@@ -826,6 +1567,7 @@ impl ConnectionCore<ServerConnectionData> {
 #[derive(Default)]
 pub struct ServerConnectionData {
     pub(super) sni: Option<DnsName>,
+    pub(super) client_hello_fingerprint: Option<String>,
     pub(super) received_resumption_data: Option<Vec<u8>>,
     pub(super) resumption_data: Vec<u8>,
     pub(super) early_data: EarlyDataState,
@@ -19,8 +19,9 @@ use crate::msgs::persist;
 use crate::rand::GetRandomFailed;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
+use crate::time::UnixTime;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
-use crate::{ticketer, verify};
+use crate::verify;
 
 use super::common::ActiveCertifiedKey;
 use super::hs::{self, ServerContext};
@@ -35,12 +36,15 @@ pub(super) use client_hello::CompleteClientHelloHandling;
 mod client_hello {
     use crate::crypto::{KeyExchange, SupportedGroup};
     use crate::enums::SignatureScheme;
+    use crate::handshake_observer::ResumptionKind;
     use crate::msgs::enums::ECPointFormat;
     use crate::msgs::enums::NamedGroup;
     use crate::msgs::enums::{ClientCertificateType, Compression};
     use crate::msgs::handshake::ServerECDHParams;
     use crate::msgs::handshake::{CertificateRequestPayload, ClientSessionTicket, Random};
-    use crate::msgs::handshake::{CertificateStatus, ECDHEServerKeyExchange};
+    use crate::msgs::handshake::{
+        CertificateStatus, CertificateStatusMulti, ECDHEServerKeyExchange,
+    };
     use crate::msgs::handshake::{ClientExtension, SessionId};
     use crate::msgs::handshake::{ClientHelloPayload, ServerHelloPayload};
     use crate::msgs::handshake::{ServerExtension, ServerKeyExchangePayload};
@@ -58,6 +62,7 @@ mod client_hello {
         pub(in crate::server) randoms: ConnectionRandoms,
         pub(in crate::server) send_ticket: bool,
         pub(in crate::server) extra_exts: Vec<ServerExtension>,
+        pub(in crate::server) alpn_protocol: Option<Vec<u8>>,
     }
 
     impl<C: CryptoProvider> CompleteClientHelloHandling<C> {
@@ -142,7 +147,10 @@ mod client_hello {
                 .or_else(|| {
                     // Perhaps resume?  If we received a ticket, the sessionid
                     // does not correspond to a real session.
-                    if client_hello.session_id.is_empty() || ticket_received {
+                    if client_hello.session_id.is_empty()
+                        || ticket_received
+                        || !self.config.session_id_resumption
+                    {
                         return None;
                     }
 
@@ -153,10 +161,25 @@ mod client_hello {
                 .and_then(|x| persist::ServerSessionValue::read_bytes(&x).ok())
                 .filter(|resumedata| {
                     hs::can_resume(self.suite.into(), &cx.data.sni, self.using_ems, resumedata)
+                        && hs::resumption_alpn_matches(
+                            &self.config,
+                            self.alpn_protocol.as_deref(),
+                            resumedata,
+                        )
                 });
 
             if let Some(data) = resume_data {
-                return self.start_resumption(cx, client_hello, &client_hello.session_id, data);
+                let kind = match ticket_received {
+                    true => ResumptionKind::Tls12Ticket,
+                    false => ResumptionKind::Tls12SessionId,
+                };
+                return self.start_resumption(
+                    cx,
+                    client_hello,
+                    &client_hello.session_id,
+                    data,
+                    kind,
+                );
             }
 
             // Now we have chosen a ciphersuite, we can make kx decisions.
@@ -198,10 +221,24 @@ mod client_hello {
 
             debug_assert_eq!(ecpoint, ECPointFormat::Uncompressed);
 
-            let mut ocsp_response = server_key.get_ocsp();
+            // Staple responses for the whole chain if we have any for intermediates,
+            // and the client supports that.  Otherwise just the end-entity's.
+            let ocsp_multi = client_hello.ocsp_multi_offered()
+                && server_key
+                    .get_intermediate_ocsp()
+                    .iter()
+                    .any(|response| !response.is_empty());
+            let mut ocsp_response = match ocsp_multi {
+                true => None,
+                false => server_key.get_ocsp(),
+            };
+            let mut extra_exts = self.extra_exts;
+            if ocsp_multi {
+                extra_exts.push(ServerExtension::CertificateStatusV2Ack);
+            }
 
             // If we're not offered a ticket or a potential session ID, allocate a session ID.
-            if !self.config.session_storage.can_cache() {
+            if !self.config.session_id_resumption || !self.config.session_storage.can_cache() {
                 self.session_id = SessionId::empty();
             } else if self.session_id.is_empty() && !ticket_received {
                 self.session_id = SessionId::random::<C>()?;
@@ -218,10 +255,18 @@ mod client_hello {
                 client_hello,
                 None,
                 &self.randoms,
-                self.extra_exts,
+                extra_exts,
+                self.alpn_protocol,
             )?;
             emit_certificate(&mut self.transcript, cx.common, server_key.get_cert());
-            if let Some(ocsp_response) = ocsp_response {
+            if ocsp_multi {
+                let mut ocsp_responses = vec![server_key
+                    .get_ocsp()
+                    .unwrap_or_default()
+                    .to_vec()];
+                ocsp_responses.extend_from_slice(server_key.get_intermediate_ocsp());
+                emit_cert_status_multi(&mut self.transcript, cx.common, ocsp_responses);
+            } else if let Some(ocsp_response) = ocsp_response {
                 emit_cert_status(&mut self.transcript, cx.common, ocsp_response);
             }
             let server_kx = emit_server_kx::<C>(
@@ -268,6 +313,7 @@ mod client_hello {
             client_hello: &ClientHelloPayload,
             id: &SessionId,
             resumedata: persist::ServerSessionValue,
+            kind: ResumptionKind,
         ) -> hs::NextStateOrError {
             debug!("Resuming connection");
 
@@ -278,6 +324,7 @@ mod client_hello {
                 ));
             }
 
+            cx.common.note_resumption(kind);
             self.session_id = *id;
             self.send_ticket = emit_server_hello(
                 &self.config,
@@ -291,6 +338,7 @@ mod client_hello {
                 Some(&resumedata),
                 &self.randoms,
                 self.extra_exts,
+                self.alpn_protocol,
             )?;
 
             let secrets = ConnectionSecrets::new_resume(
@@ -314,6 +362,7 @@ mod client_hello {
                     self.using_ems,
                     cx,
                     &*self.config.ticketer,
+                    self.config.current_time()?,
                 )?;
             }
             emit_ccs(cx.common);
@@ -346,9 +395,18 @@ mod client_hello {
         resumedata: Option<&persist::ServerSessionValue>,
         randoms: &ConnectionRandoms,
         extra_exts: Vec<ServerExtension>,
+        alpn_protocol: Option<Vec<u8>>,
     ) -> Result<bool, Error> {
         let mut ep = hs::ExtensionProcessing::new();
-        ep.process_common(config, cx, ocsp_response, hello, resumedata, extra_exts)?;
+        ep.process_common(
+            config,
+            cx,
+            ocsp_response,
+            hello,
+            resumedata,
+            extra_exts,
+            alpn_protocol,
+        )?;
         ep.process_tls12(config, hello, using_ems);
 
         let sh = Message {
@@ -404,6 +462,25 @@ mod client_hello {
         common.send_msg(c, false);
     }
 
+    fn emit_cert_status_multi(
+        transcript: &mut HandshakeHash,
+        common: &mut CommonState,
+        ocsp_responses: Vec<Vec<u8>>,
+    ) {
+        let st = CertificateStatusMulti::new(ocsp_responses);
+
+        let c = Message {
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::handshake(HandshakeMessagePayload {
+                typ: HandshakeType::CertificateStatus,
+                payload: HandshakePayload::CertificateStatusMulti(st),
+            }),
+        };
+
+        transcript.add_message(&c);
+        common.send_msg(c, false);
+    }
+
     fn emit_server_kx<C: CryptoProvider>(
         transcript: &mut HandshakeHash,
         common: &mut CommonState,
@@ -422,6 +499,7 @@ mod client_hello {
                 return Err(GetRandomFailed.into());
             }
         };
+        common.kx_group = Some(selected_group);
         let secdh = ServerECDHParams::new(selected_group, kx.pub_key());
 
         let mut msg = Vec::new();
@@ -464,7 +542,11 @@ mod client_hello {
             return Ok(false);
         }
 
-        let verify_schemes = client_auth.supported_verify_schemes();
+        let verify_schemes = client_auth
+            .supported_verify_schemes()
+            .into_iter()
+            .filter(|scheme| config.allows_signature_scheme(*scheme))
+            .collect();
 
         let names = config
             .verifier
@@ -550,7 +632,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
                 None
             }
             Some((end_entity, intermediates)) => {
-                let now = std::time::SystemTime::now();
+                let now = self.config.current_time()?.into();
                 self.config
                     .verifier
                     .verify_client_cert(end_entity, intermediates, now)
@@ -666,6 +748,16 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificateVerify<
                 HandshakePayload::CertificateVerify
             )?;
 
+            if !self
+                .config
+                .allows_signature_scheme(sig.scheme)
+            {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+                ));
+            }
+
             match self.transcript.take_handshake_buf() {
                 Some(msgs) => {
                     let certs = &self.client_cert;
@@ -756,7 +848,7 @@ fn get_server_connection_value_tls12(
     secrets: &ConnectionSecrets,
     using_ems: bool,
     cx: &ServerContext<'_>,
-    time_now: ticketer::TimeBase,
+    time_now: UnixTime,
 ) -> persist::ServerSessionValue {
     let version = ProtocolVersion::TLSv1_2;
     let secret = secrets.get_master_secret();
@@ -786,8 +878,8 @@ fn emit_ticket(
     using_ems: bool,
     cx: &mut ServerContext<'_>,
     ticketer: &dyn ProducesTickets,
+    time_now: UnixTime,
 ) -> Result<(), Error> {
-    let time_now = ticketer::TimeBase::now()?;
     let plain = get_server_connection_value_tls12(secrets, using_ems, cx, time_now).get_encoding();
 
     // If we can't produce a ticket for some reason, we can't
@@ -829,6 +921,7 @@ fn emit_finished(
 ) {
     let vh = transcript.get_current_hash();
     let verify_data = secrets.server_verify_data(&vh);
+    common.note_tls12_finished(&verify_data);
     let verify_data_payload = Payload::new(verify_data);
 
     let f = Message {
@@ -875,7 +968,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
 
         // Save connection, perhaps
         if !self.resuming && !self.session_id.is_empty() {
-            let time_now = ticketer::TimeBase::now()?;
+            let time_now = self.config.current_time()?;
             let value =
                 get_server_connection_value_tls12(&self.secrets, self.using_ems, cx, time_now);
 
@@ -892,6 +985,8 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
 
         // Send our CCS and Finished.
         self.transcript.add_message(&m);
+        cx.common
+            .note_tls12_finished(&finished.0);
         if !self.resuming {
             if self.send_ticket {
                 emit_ticket(
@@ -900,6 +995,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
                     self.using_ems,
                     cx,
                     &*self.config.ticketer,
+                    self.config.current_time()?,
                 )?;
             }
             emit_ccs(cx.common);
@@ -909,7 +1005,12 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
             emit_finished(&self.secrets, &mut self.transcript, cx.common);
         }
 
-        cx.common.start_traffic();
+        cx.common.note_transcript_hash(
+            self.transcript
+                .get_current_hash()
+                .as_ref(),
+        );
+        cx.common.start_traffic()?;
         Ok(Box::new(ExpectTraffic {
             secrets: self.secrets,
             _fin_verified,
@@ -957,4 +1058,9 @@ impl State<ServerConnectionData> for ExpectTraffic {
         self.secrets
             .extract_secrets(Side::Server)
     }
+
+    #[cfg(feature = "secret_extraction")]
+    fn extract_tls12_master_secret(&self) -> Result<[u8; 48], Error> {
+        Ok(self.secrets.master_secret)
+    }
 }
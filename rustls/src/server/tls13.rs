@@ -10,7 +10,8 @@ use crate::conn::ConnectionRandoms;
 use crate::crypto::CryptoProvider;
 use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
-use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
+use crate::error::{CertificateError, Error, PeerIncompatible, PeerMisbehaved};
+use crate::handshake_observer::ResumptionKind;
 use crate::hash_hs::HandshakeHash;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
@@ -26,7 +27,7 @@ use crate::rand;
 use crate::server::ServerConfig;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
-use crate::ticketer;
+use crate::time::UnixTime;
 use crate::tls13::key_schedule::{KeyScheduleTraffic, KeyScheduleTrafficWithClientFinishedPending};
 use crate::tls13::Tls13CipherSuite;
 use crate::verify;
@@ -34,18 +35,21 @@ use crate::verify;
 use super::hs::{self, HandshakeHashOrBuffer, ServerContext};
 use super::server_conn::ServerConnectionData;
 
+use std::slice;
 use std::sync::Arc;
 
 use subtle::ConstantTimeEq;
 
-pub(super) use client_hello::CompleteClientHelloHandling;
+pub(super) use client_hello::{offered_external_psk, CompleteClientHelloHandling};
 
 mod client_hello {
-    use crate::crypto::{KeyExchange, SupportedGroup};
-    use crate::enums::SignatureScheme;
-    use crate::msgs::base::{Payload, PayloadU8};
+    use crate::compress::{self, CertificateCompressor};
+    use crate::crypto::{hmac, KeyExchange, SupportedGroup};
+    use crate::enums::{CipherSuite, SignatureScheme};
+    use crate::msgs::base::{Payload, PayloadU16, PayloadU8};
     use crate::msgs::ccs::ChangeCipherSpecPayload;
-    use crate::msgs::enums::NamedGroup;
+    use crate::msgs::codec::Reader;
+    use crate::msgs::enums::{CertificateType, NamedGroup};
     use crate::msgs::enums::{Compression, PSKKeyExchangeMode};
     use crate::msgs::handshake::CertReqExtension;
     use crate::msgs::handshake::CertificateEntry;
@@ -54,6 +58,7 @@ mod client_hello {
     use crate::msgs::handshake::CertificateRequestPayloadTLS13;
     use crate::msgs::handshake::CertificateStatus;
     use crate::msgs::handshake::ClientHelloPayload;
+    use crate::msgs::handshake::DelegatedCredentialPayload;
     use crate::msgs::handshake::HelloRetryExtension;
     use crate::msgs::handshake::HelloRetryRequest;
     use crate::msgs::handshake::KeyShareEntry;
@@ -61,7 +66,9 @@ mod client_hello {
     use crate::msgs::handshake::ServerExtension;
     use crate::msgs::handshake::ServerHelloPayload;
     use crate::msgs::handshake::SessionId;
+    use crate::psk::{PresharedKey, PskKeyExchangePolicy};
     use crate::server::common::ActiveCertifiedKey;
+    use crate::server::hs::CertificateTypes;
     use crate::sign;
     use crate::tls13::key_schedule::{
         KeyScheduleEarly, KeyScheduleHandshake, KeySchedulePreHandshake,
@@ -85,6 +92,8 @@ mod client_hello {
         pub(in crate::server) done_retry: bool,
         pub(in crate::server) send_tickets: usize,
         pub(in crate::server) extra_exts: Vec<ServerExtension>,
+        pub(in crate::server) alpn_protocol: Option<Vec<u8>>,
+        pub(in crate::server) cert_types: CertificateTypes,
     }
 
     fn max_early_data_size(configured: u32) -> usize {
@@ -102,12 +111,60 @@ mod client_hello {
         }
     }
 
+    /// The first of our external PSKs offered by the client, if it is
+    /// willing to use one.
+    pub(in crate::server) fn offered_external_psk<'a>(
+        config: &'a ServerConfig<impl CryptoProvider>,
+        client_hello: &ClientHelloPayload,
+    ) -> Option<&'a Arc<PresharedKey>> {
+        external_psk_mode(config.psk_key_exchange_policy, client_hello)?;
+
+        client_hello
+            .get_psk()?
+            .identities
+            .iter()
+            .find_map(|psk_id| find_external_psk(config, &psk_id.identity.0))
+    }
+
+    /// Which PSK key exchange mode to use with an external PSK, if the client
+    /// offers one that `policy` allows.
+    fn external_psk_mode(
+        policy: PskKeyExchangePolicy,
+        client_hello: &ClientHelloPayload,
+    ) -> Option<PSKKeyExchangeMode> {
+        let preference: &[PSKKeyExchangeMode] = match policy {
+            PskKeyExchangePolicy::PreferDhe => {
+                &[PSKKeyExchangeMode::PSK_DHE_KE, PSKKeyExchangeMode::PSK_KE]
+            }
+            PskKeyExchangePolicy::PreferPskOnly => {
+                &[PSKKeyExchangeMode::PSK_KE, PSKKeyExchangeMode::PSK_DHE_KE]
+            }
+            PskKeyExchangePolicy::RequireDhe => &[PSKKeyExchangeMode::PSK_DHE_KE],
+        };
+
+        preference
+            .iter()
+            .copied()
+            .find(|mode| client_hello.psk_mode_offered(*mode))
+    }
+
+    fn find_external_psk<'a>(
+        config: &'a ServerConfig<impl CryptoProvider>,
+        identity: &[u8],
+    ) -> Option<&'a Arc<PresharedKey>> {
+        config
+            .preshared_keys
+            .iter()
+            .find(|psk| psk.identity() == identity)
+    }
+
     impl<C: CryptoProvider> CompleteClientHelloHandling<C> {
         fn check_binder(
             &self,
             suite: &'static Tls13CipherSuite,
             client_hello: &Message,
             psk: &[u8],
+            external_psk: bool,
             binder: &[u8],
         ) -> bool {
             let binder_plaintext = match &client_hello.payload {
@@ -122,8 +179,12 @@ mod client_hello {
                 .get_hash_given(&binder_plaintext);
 
             let key_schedule = KeyScheduleEarly::new(suite, psk);
-            let real_binder =
-                key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
+            let real_binder = match external_psk {
+                true => key_schedule.external_psk_binder_key_and_sign_verify_data(&handshake_hash),
+                false => {
+                    key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash)
+                }
+            };
 
             ConstantTimeEq::ct_eq(real_binder.as_ref(), binder).into()
         }
@@ -145,10 +206,51 @@ mod client_hello {
             }
         }
 
+        /// Treat this `ClientHello` as the response to a HelloRetryRequest
+        /// that we sent statelessly, restoring our transcript from its cookie.
+        fn restore_hello_retry_transcript(
+            &mut self,
+            cx: &mut ServerContext<'_>,
+            key: &dyn hmac::Key,
+            cookie: &[u8],
+        ) -> Result<(), Error> {
+            let (group, client_hello_hash) = match open_hello_retry_cookie(key, cookie) {
+                Some((suite, group, hash))
+                    if suite == self.suite.common.suite
+                        && hash.0.len()
+                            == self
+                                .suite
+                                .common
+                                .hash_provider
+                                .output_len() =>
+                {
+                    (group, hash)
+                }
+                _ => {
+                    return Err(cx.common.send_fatal_alert(
+                        AlertDescription::IllegalParameter,
+                        PeerMisbehaved::InvalidHelloRetryCookie,
+                    ));
+                }
+            };
+
+            debug!("Resuming handshake from HelloRetryRequest cookie");
+            self.transcript
+                .add_rolled_up_hash(&client_hello_hash.0);
+            self.transcript
+                .add_message(&hello_retry_request(
+                    self.suite,
+                    group,
+                    Some(cookie.to_vec()),
+                ));
+            self.done_retry = true;
+            Ok(())
+        }
+
         pub(in crate::server) fn handle_client_hello(
             mut self,
             cx: &mut ServerContext<'_>,
-            server_key: ActiveCertifiedKey,
+            server_key: Option<ActiveCertifiedKey>,
             chm: &Message,
             client_hello: &ClientHelloPayload,
             mut sigschemes_ext: Vec<SignatureScheme>,
@@ -160,6 +262,16 @@ mod client_hello {
                 ));
             }
 
+            if let (Some(key), Some(cookie), false) = (
+                self.config
+                    .hello_retry_cookie_key
+                    .clone(),
+                client_hello.get_cookie_extension(),
+                self.done_retry,
+            ) {
+                self.restore_hello_retry_transcript(cx, key.as_ref(), &cookie.0)?;
+            }
+
             let groups_ext = client_hello
                 .get_namedgroups_extension()
                 .ok_or_else(|| {
@@ -211,6 +323,21 @@ mod client_hello {
                         .find(|share| share.group == group.name())
                 });
 
+            // If asked to, ignore that share if the client supports a group we
+            // prefer: we'll ask for that one instead.
+            let preferred_group = self
+                .config
+                .kx_groups
+                .iter()
+                .find(|group| groups_ext.contains(&group.name()));
+            let chosen_share = chosen_share.filter(|share| {
+                !self
+                    .config
+                    .hello_retry_for_preferred_group
+                    || self.done_retry
+                    || preferred_group.map_or(true, |group| group.name() == share.group)
+            });
+
             let chosen_share = match chosen_share {
                 Some(s) => s,
                 None => {
@@ -238,6 +365,9 @@ mod client_hello {
                             self.suite,
                             cx.common,
                             group.name(),
+                            self.config
+                                .hello_retry_cookie_key
+                                .as_deref(),
                         );
                         emit_fake_ccs(cx.common);
 
@@ -274,7 +404,10 @@ mod client_hello {
 
             let mut chosen_psk_index = None;
             let mut resumedata = None;
-            let time_now = ticketer::TimeBase::now()?;
+            let mut external_psk = None;
+            let external_psk_mode =
+                external_psk_mode(self.config.psk_key_exchange_policy, client_hello);
+            let time_now = self.config.current_time()?;
 
             if let Some(psk_offer) = client_hello.get_psk() {
                 if !client_hello.check_psk_ext_is_last() {
@@ -310,6 +443,35 @@ mod client_hello {
                 }
 
                 for (i, psk_id) in psk_offer.identities.iter().enumerate() {
+                    if let Some(psk) =
+                        find_external_psk(&self.config, &psk_id.identity.0).filter(|psk| {
+                            psk.hash()
+                                == self
+                                    .suite
+                                    .common
+                                    .hash_provider
+                                    .algorithm()
+                                && external_psk_mode.is_some()
+                        })
+                    {
+                        if !self.check_binder(
+                            self.suite,
+                            chm,
+                            psk.key(),
+                            true,
+                            psk_offer.binders[i].as_ref(),
+                        ) {
+                            return Err(cx.common.send_fatal_alert(
+                                AlertDescription::DecryptError,
+                                PeerMisbehaved::IncorrectBinder,
+                            ));
+                        }
+
+                        chosen_psk_index = Some(i);
+                        external_psk = Some(Arc::clone(psk));
+                        break;
+                    }
+
                     let resume = match self
                         .attempt_tls13_ticket_decryption(&psk_id.identity.0)
                         .map(|resumedata| {
@@ -317,6 +479,11 @@ mod client_hello {
                         })
                         .filter(|resumedata| {
                             hs::can_resume(self.suite.into(), &cx.data.sni, false, resumedata)
+                                && hs::resumption_alpn_matches(
+                                    &self.config,
+                                    self.alpn_protocol.as_deref(),
+                                    resumedata,
+                                )
                         }) {
                         Some(resume) => resume,
                         None => continue,
@@ -326,6 +493,7 @@ mod client_hello {
                         self.suite,
                         chm,
                         &resume.master_secret.0,
+                        false,
                         psk_offer.binders[i].as_ref(),
                     ) {
                         return Err(cx.common.send_fatal_alert(
@@ -343,18 +511,31 @@ mod client_hello {
             if !client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE) {
                 debug!("Client unwilling to resume, DHE_KE not offered");
                 self.send_tickets = 0;
-                chosen_psk_index = None;
+                if external_psk.is_none() {
+                    chosen_psk_index = None;
+                }
                 resumedata = None;
             } else {
                 self.send_tickets = self.config.send_tls13_tickets;
             }
 
             if let Some(ref resume) = resumedata {
+                cx.common
+                    .note_resumption(ResumptionKind::Tls13Psk);
                 cx.data.received_resumption_data = Some(resume.application_data.0.clone());
                 cx.common.peer_certificates = resume.client_cert_chain.clone();
             }
 
-            let full_handshake = resumedata.is_none();
+            // Without a key exchange (`psk_ke`), we ignore the client's key share.
+            let chosen_share = match (&external_psk, external_psk_mode) {
+                (Some(_), Some(PSKKeyExchangeMode::PSK_KE)) => None,
+                _ => Some(chosen_share),
+            };
+            if external_psk.is_some() {
+                debug!("Using external PSK with {:?}", external_psk_mode);
+            }
+
+            let full_handshake = resumedata.is_none() && external_psk.is_none();
             self.transcript.add_message(chm);
             let key_schedule = emit_server_hello(
                 &mut self.transcript,
@@ -367,13 +548,42 @@ mod client_hello {
                 resumedata
                     .as_ref()
                     .map(|x| &x.master_secret.0[..]),
+                external_psk.as_deref(),
                 &self.config,
             )?;
             if !self.done_retry {
                 emit_fake_ccs(cx.common);
             }
 
-            let mut ocsp_response = server_key.get_ocsp();
+            // Confirm the certificate types we chose, if the client
+            // offered a choice.
+            let mut raw_public_key = None;
+            if full_handshake {
+                if client_hello
+                    .get_server_certificate_type_extension()
+                    .is_some()
+                {
+                    self.extra_exts
+                        .push(ServerExtension::ServerCertificateType(
+                            self.cert_types.server,
+                        ));
+                }
+                if let (Some(_), Some(typ)) = (
+                    client_hello.get_client_certificate_type_extension(),
+                    self.cert_types.client,
+                ) {
+                    self.extra_exts
+                        .push(ServerExtension::ClientCertificateType(typ));
+                }
+                if self.cert_types.server == CertificateType::RawPublicKey {
+                    raw_public_key = self.config.raw_public_key.as_ref();
+                }
+            }
+
+            let mut ocsp_response = server_key
+                .as_ref()
+                .filter(|_| raw_public_key.is_none())
+                .and_then(|server_key| server_key.get_ocsp());
             let doing_early_data = emit_encrypted_extensions(
                 &mut self.transcript,
                 self.suite,
@@ -382,25 +592,94 @@ mod client_hello {
                 client_hello,
                 resumedata.as_ref(),
                 self.extra_exts,
+                self.alpn_protocol,
                 &self.config,
             )?;
 
-            let doing_client_auth = if full_handshake {
-                let client_auth =
-                    emit_certificate_req_tls13(&mut self.transcript, cx, &self.config)?;
+            // Compress our certificate with the client's favourite codec
+            // that we also support.
+            let cert_compressor = client_hello
+                .get_cert_compression_extension()
+                .and_then(|offered| {
+                    offered.iter().find_map(|algorithm| {
+                        self.config
+                            .cert_compressors
+                            .iter()
+                            .find(|c| c.algorithm() == *algorithm)
+                    })
+                });
+
+            let doing_client_auth = if let Some(raw_public_key) = raw_public_key {
+                let client_auth = emit_certificate_req_tls13(
+                    &mut self.transcript,
+                    cx,
+                    &self.config,
+                    self.cert_types.client,
+                )?;
                 emit_certificate_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_cert(),
-                    ocsp_response,
+                    slice::from_ref(&raw_public_key.spki),
+                    None,
+                    None,
+                    cert_compressor.map(|c| c.as_ref()),
                 );
                 emit_certificate_verify_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_key(),
+                    raw_public_key.key.as_ref(),
                     &sigschemes_ext,
                 )?;
                 client_auth
+            } else if full_handshake {
+                let server_key = match &server_key {
+                    Some(server_key) => server_key,
+                    None => {
+                        return Err(cx.common.send_fatal_alert(
+                            AlertDescription::AccessDenied,
+                            Error::General("no server certificate chain resolved".to_owned()),
+                        ));
+                    }
+                };
+                let client_auth = emit_certificate_req_tls13(
+                    &mut self.transcript,
+                    cx,
+                    &self.config,
+                    self.cert_types.client,
+                )?;
+                // Use our delegated credential, if the client can verify
+                // both its signature and the signature it makes.
+                let delegated_credential = server_key
+                    .get_delegated_credential()
+                    .filter(|dc| {
+                        client_hello
+                            .get_delegated_credential_extension()
+                            .map_or(false, |offered| offered.contains(&dc.payload.algorithm))
+                            && sigschemes_ext.contains(&dc.payload.dc_cert_verify_algorithm)
+                    });
+                emit_certificate_tls13(
+                    &mut self.transcript,
+                    cx.common,
+                    server_key.get_cert(),
+                    ocsp_response,
+                    delegated_credential.map(|dc| &dc.payload),
+                    cert_compressor.map(|c| c.as_ref()),
+                );
+                match delegated_credential {
+                    Some(dc) => emit_certificate_verify_tls13(
+                        &mut self.transcript,
+                        cx.common,
+                        dc.key.as_ref(),
+                        &[dc.payload.dc_cert_verify_algorithm],
+                    )?,
+                    None => emit_certificate_verify_tls13(
+                        &mut self.transcript,
+                        cx.common,
+                        server_key.get_key(),
+                        &sigschemes_ext,
+                    )?,
+                }
+                client_auth
             } else {
                 false
             };
@@ -418,7 +697,7 @@ mod client_hello {
                         Some(max_early_data_size(self.config.max_early_data_size)),
                         cx.common,
                     );
-                    cx.data.early_data.reject();
+                    cx.data.early_data.skip();
                 }
                 EarlyDataDecision::Accepted => {
                     cx.data
@@ -440,11 +719,12 @@ mod client_hello {
                 // Application data can be sent immediately after Finished, in one
                 // flight.  However, if client auth is enabled, we don't want to send
                 // application data to an unauthenticated peer.
-                cx.common.start_outgoing_traffic();
+                cx.common.start_outgoing_traffic()?;
             }
 
             if doing_client_auth {
                 Ok(Box::new(ExpectCertificate {
+                    raw_client_key: self.cert_types.client == Some(CertificateType::RawPublicKey),
                     config: self.config,
                     transcript: self.transcript,
                     suite: self.suite,
@@ -480,22 +760,30 @@ mod client_hello {
         suite: &'static Tls13CipherSuite,
         cx: &mut ServerContext<'_>,
         session_id: &SessionId,
-        share: &KeyShareEntry,
+        share: Option<&KeyShareEntry>,
         chosen_psk_idx: Option<usize>,
         resuming_psk: Option<&[u8]>,
+        external_psk: Option<&PresharedKey>,
         config: &ServerConfig<C>,
     ) -> Result<KeyScheduleHandshake, Error> {
         let mut extensions = Vec::new();
 
         // Prepare key exchange; the caller ascertained that the `share.group` is supported
-        let kx = <<C as CryptoProvider>::KeyExchange as KeyExchange>::start(
-            share.group,
-            &config.kx_groups,
-        )
-        .map_err(|_| Error::FailedToGetRandomBytes)?;
+        let kx = match share {
+            Some(share) => {
+                let kx = <<C as CryptoProvider>::KeyExchange as KeyExchange>::start(
+                    share.group,
+                    &config.kx_groups,
+                )
+                .map_err(|_| Error::FailedToGetRandomBytes)?;
 
-        let kse = KeyShareEntry::new(share.group, kx.pub_key());
-        extensions.push(ServerExtension::KeyShare(kse));
+                let kse = KeyShareEntry::new(share.group, kx.pub_key());
+                extensions.push(ServerExtension::KeyShare(kse));
+                cx.common.kx_group = Some(share.group);
+                Some((share, kx))
+            }
+            None => None,
+        };
         extensions.push(ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_3));
 
         if let Some(psk_idx) = chosen_psk_idx {
@@ -536,14 +824,19 @@ mod client_hello {
             );
 
             KeySchedulePreHandshake::from(early_key_schedule)
+        } else if let Some(psk) = external_psk {
+            KeySchedulePreHandshake::from(KeyScheduleEarly::new(suite, psk.key()))
         } else {
             KeySchedulePreHandshake::new(suite)
         };
 
         // Do key exchange
-        let key_schedule = kx.complete(&share.payload.0, |secret| {
-            Ok(key_schedule_pre_handshake.into_handshake(secret))
-        })?;
+        let key_schedule = match kx {
+            Some((share, kx)) => kx.complete(&share.payload.0, |secret| {
+                Ok(key_schedule_pre_handshake.into_handshake(secret))
+            })?,
+            None => key_schedule_pre_handshake.into_handshake_without_key_exchange(),
+        };
 
         let handshake_hash = transcript.get_current_hash();
         let key_schedule = key_schedule.derive_server_handshake_secrets(
@@ -572,7 +865,26 @@ mod client_hello {
         suite: &'static Tls13CipherSuite,
         common: &mut CommonState,
         group: NamedGroup,
+        cookie_key: Option<&dyn hmac::Key>,
     ) {
+        let cookie = cookie_key.map(|key| {
+            hello_retry_cookie(key, suite, group, transcript.get_current_hash().as_ref())
+        });
+        let m = hello_retry_request(suite, group, cookie);
+
+        trace!("Requesting retry {:?}", m);
+        transcript.rollup_for_hrr();
+        transcript.add_message(&m);
+        common.send_msg(m, false);
+    }
+
+    /// Build a HelloRetryRequest.  This must be deterministic, so a stateless
+    /// server can reproduce it for the transcript from a cookie.
+    fn hello_retry_request(
+        suite: &'static Tls13CipherSuite,
+        group: NamedGroup,
+        cookie: Option<Vec<u8>>,
+    ) -> Message {
         let mut req = HelloRetryRequest {
             legacy_version: ProtocolVersion::TLSv1_2,
             session_id: SessionId::empty(),
@@ -582,23 +894,69 @@ mod client_hello {
 
         req.extensions
             .push(HelloRetryExtension::KeyShare(group));
+        if let Some(cookie) = cookie {
+            req.extensions
+                .push(HelloRetryExtension::Cookie(PayloadU16::new(cookie)));
+        }
         req.extensions
             .push(HelloRetryExtension::SupportedVersions(
                 ProtocolVersion::TLSv1_3,
             ));
 
-        let m = Message {
+        Message {
             version: ProtocolVersion::TLSv1_2,
             payload: MessagePayload::handshake(HandshakeMessagePayload {
                 typ: HandshakeType::HelloRetryRequest,
                 payload: HandshakePayload::HelloRetryRequest(req),
             }),
-        };
+        }
+    }
 
-        trace!("Requesting retry {:?}", m);
-        transcript.rollup_for_hrr();
-        transcript.add_message(&m);
-        common.send_msg(m, false);
+    /// Domain separation for HelloRetryRequest cookie authentication.
+    const HELLO_RETRY_COOKIE_LABEL: &[u8] = b"rustls tls13 hello retry cookie";
+
+    /// Produce a cookie carrying everything needed to continue the handshake
+    /// after a HelloRetryRequest: the chosen suite and group, and the hash of
+    /// the first `ClientHello`.  It is followed by a tag authenticating it.
+    fn hello_retry_cookie(
+        key: &dyn hmac::Key,
+        suite: &'static Tls13CipherSuite,
+        group: NamedGroup,
+        client_hello_hash: &[u8],
+    ) -> Vec<u8> {
+        let mut cookie = Vec::new();
+        suite.common.suite.encode(&mut cookie);
+        group.encode(&mut cookie);
+        PayloadU8::new(client_hello_hash.to_vec()).encode(&mut cookie);
+
+        let tag = key.sign(&[HELLO_RETRY_COOKIE_LABEL, &cookie]);
+        cookie.extend_from_slice(tag.as_ref());
+        cookie
+    }
+
+    /// Authenticate and decode a cookie made by `hello_retry_cookie`.
+    fn open_hello_retry_cookie(
+        key: &dyn hmac::Key,
+        cookie: &[u8],
+    ) -> Option<(CipherSuite, NamedGroup, PayloadU8)> {
+        let (body, tag) = cookie.split_at(
+            cookie
+                .len()
+                .checked_sub(key.tag_len())?,
+        );
+        let expected_tag = key.sign(&[HELLO_RETRY_COOKIE_LABEL, body]);
+        if !bool::from(ConstantTimeEq::ct_eq(expected_tag.as_ref(), tag)) {
+            return None;
+        }
+
+        let mut rd = Reader::init(body);
+        let suite = CipherSuite::read(&mut rd).ok()?;
+        let group = NamedGroup::read(&mut rd).ok()?;
+        let client_hello_hash = PayloadU8::read(&mut rd).ok()?;
+        match rd.any_left() {
+            true => None,
+            false => Some((suite, group, client_hello_hash)),
+        }
     }
 
     #[allow(clippy::needless_pass_by_ref_mut)] // cx only mutated if cfg(feature = "quic")
@@ -670,10 +1028,19 @@ mod client_hello {
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
         extra_exts: Vec<ServerExtension>,
+        alpn_protocol: Option<Vec<u8>>,
         config: &ServerConfig<C>,
     ) -> Result<EarlyDataDecision, Error> {
         let mut ep = hs::ExtensionProcessing::new();
-        ep.process_common(config, cx, ocsp_response, hello, resumedata, extra_exts)?;
+        ep.process_common(
+            config,
+            cx,
+            ocsp_response,
+            hello,
+            resumedata,
+            extra_exts,
+            alpn_protocol,
+        )?;
 
         let early_data = decide_if_early_data_allowed(cx, hello, resumedata, suite, config);
         if early_data == EarlyDataDecision::Accepted {
@@ -694,30 +1061,42 @@ mod client_hello {
         Ok(early_data)
     }
 
+    /// Ask the client to authenticate with `cert_type`, if any.
     fn emit_certificate_req_tls13<C: CryptoProvider>(
         transcript: &mut HandshakeHash,
         cx: &mut ServerContext<'_>,
         config: &ServerConfig<C>,
+        cert_type: Option<CertificateType>,
     ) -> Result<bool, Error> {
-        if !config.verifier.offer_client_auth() {
-            return Ok(false);
-        }
+        let raw_public_keys = match cert_type {
+            Some(typ) => typ == CertificateType::RawPublicKey,
+            None => return Ok(false),
+        };
 
         let mut cr = CertificateRequestPayloadTLS13 {
             context: PayloadU8::empty(),
             extensions: Vec::new(),
         };
 
-        let schemes = config
-            .verifier
-            .supported_verify_schemes();
+        let schemes = match raw_public_keys {
+            true => verify::SPKI_TLS13_SCHEMES.to_vec(),
+            false => config
+                .verifier
+                .supported_verify_schemes(),
+        }
+        .into_iter()
+        .filter(|scheme| config.allows_signature_scheme(*scheme))
+        .collect();
         cr.extensions
-            .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
+            .push(CertReqExtension::SignatureAlgorithms(schemes));
 
-        let names = config
-            .verifier
-            .client_auth_root_subjects()
-            .to_vec();
+        let names = match raw_public_keys {
+            true => Vec::new(),
+            false => config
+                .verifier
+                .client_auth_root_subjects()
+                .to_vec(),
+        };
 
         if !names.is_empty() {
             cr.extensions
@@ -743,6 +1122,8 @@ mod client_hello {
         common: &mut CommonState,
         cert_chain: &[Certificate],
         ocsp_response: Option<&[u8]>,
+        delegated_credential: Option<&DelegatedCredentialPayload>,
+        compressor: Option<&dyn CertificateCompressor>,
     ) {
         let mut cert_entries = vec![];
         for cert in cert_chain {
@@ -763,15 +1144,30 @@ mod client_hello {
                     .exts
                     .push(CertificateExtension::CertificateStatus(cst));
             }
+
+            if let Some(dc) = delegated_credential {
+                end_entity_cert
+                    .exts
+                    .push(CertificateExtension::DelegatedCredential(dc.clone()));
+            }
         }
 
         let cert_body = CertificatePayloadTLS13::new(cert_entries);
-        let c = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::handshake(HandshakeMessagePayload {
+        let payload = match compressor
+            .and_then(|compressor| compress::compress_certificate(compressor, &cert_body))
+        {
+            Some(compressed) => HandshakeMessagePayload {
+                typ: HandshakeType::CompressedCertificate,
+                payload: HandshakePayload::CompressedCertificate(compressed),
+            },
+            None => HandshakeMessagePayload {
                 typ: HandshakeType::Certificate,
                 payload: HandshakePayload::CertificateTLS13(cert_body),
-            }),
+            },
+        };
+        let c = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::handshake(payload),
         };
 
         trace!("sending certificate {:?}", c);
@@ -878,6 +1274,8 @@ struct ExpectCertificate<C: CryptoProvider> {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     send_tickets: usize,
+    /// Whether the client authenticates with a raw public key (RFC 7250).
+    raw_client_key: bool,
 }
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
@@ -897,10 +1295,11 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
 
         let client_cert = certp.convert();
 
-        let mandatory = self
-            .config
-            .verifier
-            .client_auth_mandatory();
+        let mandatory = self.raw_client_key
+            || self
+                .config
+                .verifier
+                .client_auth_mandatory();
 
         let (end_entity, intermediates) = match client_cert.split_first() {
             None => {
@@ -924,14 +1323,27 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             Some(chain) => chain,
         };
 
-        let now = std::time::SystemTime::now();
-        self.config
-            .verifier
-            .verify_client_cert(end_entity, intermediates, now)
-            .map_err(|err| {
-                cx.common
-                    .send_cert_verify_error_alert(err)
-            })?;
+        if self.raw_client_key {
+            // A raw public key is sent alone, in place of the end-entity
+            // certificate.
+            if !intermediates.is_empty() {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::BadCertificate,
+                    CertificateError::BadEncoding,
+                ));
+            }
+            verify::verify_raw_public_key(&self.config.pinned_client_keys, end_entity)
+        } else {
+            let now = self.config.current_time()?.into();
+            self.config
+                .verifier
+                .verify_client_cert(end_entity, intermediates, now)
+                .map(|_| ())
+        }
+        .map_err(|err| {
+            cx.common
+                .send_cert_verify_error_alert(err)
+        })?;
 
         Ok(Box::new(ExpectCertificateVerify {
             config: self.config,
@@ -940,6 +1352,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             key_schedule: self.key_schedule,
             client_cert,
             send_tickets: self.send_tickets,
+            raw_client_key: self.raw_client_key,
         }))
     }
 }
@@ -951,6 +1364,7 @@ struct ExpectCertificateVerify<C: CryptoProvider> {
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     client_cert: Vec<Certificate>,
     send_tickets: usize,
+    raw_client_key: bool,
 }
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificateVerify<C> {
@@ -961,14 +1375,31 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificateVerify<
                 HandshakeType::CertificateVerify,
                 HandshakePayload::CertificateVerify
             )?;
+            if !self
+                .config
+                .allows_signature_scheme(sig.scheme)
+            {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+                ));
+            }
+
             let handshake_hash = self.transcript.get_current_hash();
             self.transcript.abandon_client_auth();
             let certs = &self.client_cert;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
-            self.config
-                .verifier
-                .verify_tls13_signature(&msg, &certs[0], sig)
+            match self.raw_client_key {
+                true => self
+                    .config
+                    .verifier
+                    .verify_tls13_signature_with_spki(&msg, &certs[0].0, sig),
+                false => self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &certs[0], sig),
+            }
         };
 
         if let Err(e) = rc {
@@ -1006,12 +1437,18 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectEarlyData<C> {
     fn handle(mut self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> hs::NextStateOrError {
         match m.payload {
             MessagePayload::ApplicationData(payload) => {
+                let len = payload.0.len() as u64;
                 match cx
                     .data
                     .early_data
                     .take_received_plaintext(payload)
                 {
-                    true => Ok(self),
+                    true => {
+                        cx.common
+                            .stats
+                            .application_bytes_received += len;
+                        Ok(self)
+                    }
                     false => Err(cx.common.send_fatal_alert(
                         AlertDescription::UnexpectedMessage,
                         PeerMisbehaved::TooMuchEarlyDataReceived,
@@ -1053,7 +1490,7 @@ fn get_server_session_value(
     key_schedule: &KeyScheduleTraffic,
     cx: &ServerContext<'_>,
     nonce: &[u8],
-    time_now: ticketer::TimeBase,
+    time_now: UnixTime,
     age_obfuscation_offset: u32,
 ) -> persist::ServerSessionValue {
     let version = ProtocolVersion::TLSv1_3;
@@ -1092,7 +1529,7 @@ impl<C: CryptoProvider> ExpectFinished<C> {
         config: &ServerConfig<C>,
     ) -> Result<(), Error> {
         let nonce = rand::random_vec::<C>(32)?;
-        let now = ticketer::TimeBase::now()?;
+        let now = config.current_time()?;
         let age_add = rand::random_u32::<C>()?;
         let plain =
             get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add)
@@ -1170,21 +1607,37 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
         // nb. future derivations include Client Finished, but not the
         // main application data keying.
         self.transcript.add_message(&m);
+        cx.common.note_transcript_hash(
+            self.transcript
+                .get_current_hash()
+                .as_ref(),
+        );
 
         cx.common.check_aligned_handshake()?;
 
-        for _ in 0..self.send_tickets {
-            Self::emit_ticket(
-                &self.transcript,
-                self.suite,
-                cx,
-                &key_schedule_traffic,
-                &self.config,
-            )?;
-        }
+        let deferred_tickets =
+            if self.config.defer_tls13_tickets && self.send_tickets > 0 && !cx.common.is_quic() {
+                Some(DeferredTickets {
+                    config: self.config,
+                    transcript: self.transcript,
+                    suite: self.suite,
+                    count: self.send_tickets,
+                })
+            } else {
+                for _ in 0..self.send_tickets {
+                    Self::emit_ticket(
+                        &self.transcript,
+                        self.suite,
+                        cx,
+                        &key_schedule_traffic,
+                        &self.config,
+                    )?;
+                }
+                None
+            };
 
         // Application data may now flow, even if we have client auth enabled.
-        cx.common.start_traffic();
+        cx.common.start_traffic()?;
 
         #[cfg(feature = "quic")]
         {
@@ -1198,18 +1651,28 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
 
         Ok(Box::new(ExpectTraffic {
             key_schedule: key_schedule_traffic,
+            deferred_tickets,
             _fin_verified: fin,
         }))
     }
 }
 
+/// Tickets held back until the application's first write.
+struct DeferredTickets<C: CryptoProvider> {
+    config: Arc<ServerConfig<C>>,
+    transcript: HandshakeHash,
+    suite: &'static Tls13CipherSuite,
+    count: usize,
+}
+
 // --- Process traffic ---
-struct ExpectTraffic {
+struct ExpectTraffic<C: CryptoProvider> {
     key_schedule: KeyScheduleTraffic,
+    deferred_tickets: Option<DeferredTickets<C>>,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
-impl ExpectTraffic {
+impl<C: CryptoProvider> ExpectTraffic<C> {
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1229,7 +1692,7 @@ impl ExpectTraffic {
 
         if common.should_update_key(key_update_request)? {
             self.key_schedule
-                .update_encrypter_and_notify(common);
+                .update_encrypter_and_notify(common)?;
         }
 
         // Update our read-side keys.
@@ -1239,7 +1702,7 @@ impl ExpectTraffic {
     }
 }
 
-impl State<ServerConnectionData> for ExpectTraffic {
+impl<C: CryptoProvider> State<ServerConnectionData> for ExpectTraffic<C> {
     fn handle(mut self: Box<Self>, cx: &mut ServerContext, m: Message) -> hs::NextStateOrError {
         match m.payload {
             MessagePayload::ApplicationData(payload) => cx
@@ -1280,6 +1743,31 @@ impl State<ServerConnectionData> for ExpectTraffic {
         self.key_schedule
             .extract_secrets(Side::Server)
     }
+
+    fn send_deferred_tickets(&mut self, cx: &mut ServerContext<'_>) -> Result<(), Error> {
+        let deferred = match self.deferred_tickets.take() {
+            Some(deferred) => deferred,
+            None => return Ok(()),
+        };
+
+        for _ in 0..deferred.count {
+            ExpectFinished::emit_ticket(
+                &deferred.transcript,
+                deferred.suite,
+                cx,
+                &self.key_schedule,
+                &deferred.config,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn send_key_update_request(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        self.key_schedule
+            .update_encrypter_and_notify(common)?;
+        common.perhaps_write_key_update();
+        Ok(())
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -1,7 +1,10 @@
 use crate::enums::{SignatureAlgorithm, SignatureScheme};
-use crate::error::Error;
+use crate::error::{CertificateError, Error};
 use crate::key;
-use crate::x509::{wrap_in_asn1_len, wrap_in_sequence};
+use crate::msgs::base::{PayloadU16, PayloadU24};
+use crate::msgs::handshake::DelegatedCredentialPayload;
+use crate::time::UnixTime;
+use crate::x509::{wrap_in_asn1_len, wrap_in_sequence, CertificateFields};
 
 use ring::io::der;
 use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair};
@@ -44,6 +47,12 @@ pub struct CertifiedKey {
     /// An optional OCSP response from the certificate issuer,
     /// attesting to its continued validity.
     pub ocsp: Option<Vec<u8>>,
+
+    /// See [`CertifiedKey::with_intermediate_ocsp`].
+    pub(crate) intermediate_ocsp: Vec<Vec<u8>>,
+
+    /// See [`CertifiedKey::with_delegated_credential`].
+    pub(crate) delegated_credential: Option<DelegatedCredential>,
 }
 
 impl CertifiedKey {
@@ -56,15 +65,132 @@ impl CertifiedKey {
             cert,
             key,
             ocsp: None,
+            intermediate_ocsp: Vec::new(),
+            delegated_credential: None,
         }
     }
 
+    /// Add OCSP responses for the intermediate certificates in `cert`, in
+    /// the same order: `intermediate_ocsp[0]` is for `cert[1]`.  Use an
+    /// empty response for a certificate which doesn't have one.
+    ///
+    /// These are only sent to TLS1.2 clients which offer the RFC6961
+    /// `status_request_v2` extension.
+    pub fn with_intermediate_ocsp(mut self, intermediate_ocsp: Vec<Vec<u8>>) -> Self {
+        self.intermediate_ocsp = intermediate_ocsp;
+        self
+    }
+
+    /// Add a delegated credential, used in place of `key` with TLS1.3
+    /// clients which support it.
+    pub fn with_delegated_credential(mut self, delegated_credential: DelegatedCredential) -> Self {
+        self.delegated_credential = Some(delegated_credential);
+        self
+    }
+
     /// The end-entity certificate.
     pub fn end_entity_cert(&self) -> Result<&key::Certificate, SignError> {
         self.cert.get(0).ok_or(SignError(()))
     }
 }
 
+/// A delegated credential (RFC 9345): a short-lived key which a server
+/// uses to sign its handshakes instead of its certificate's key, and the
+/// certificate key's signature over it.
+///
+/// The credential is only used with TLS1.3 clients which offer the
+/// `delegated_credential` extension and accept its signature schemes;
+/// other clients see the certificate's key as usual.  Clients must also
+/// find the DelegationUsage extension in the end-entity certificate.
+#[derive(Clone)]
+pub struct DelegatedCredential {
+    pub(crate) payload: DelegatedCredentialPayload,
+    pub(crate) key: Arc<dyn SigningKey>,
+}
+
+impl DelegatedCredential {
+    /// Make a credential delegating to `key`, whose DER-encoded
+    /// SubjectPublicKeyInfo is `spki`.  The credential is signed with
+    /// `cert_key`, the key of the end-entity certificate `end_entity`.
+    ///
+    /// The credential expires at `expiry`, which must be after the
+    /// certificate's notBefore time.  Clients reject credentials which
+    /// are valid for more than seven days after they see them.
+    pub fn new(
+        end_entity: &key::Certificate,
+        cert_key: &dyn SigningKey,
+        key: Arc<dyn SigningKey>,
+        spki: Vec<u8>,
+        expiry: UnixTime,
+    ) -> Result<Self, Error> {
+        // In preference order.
+        const TLS13_SCHEMES: &[SignatureScheme] = &[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+        ];
+
+        let not_before = CertificateFields::parse(&end_entity.0)
+            .and_then(|cert| cert.not_before())
+            .ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let valid_time = expiry
+            .as_secs()
+            .checked_sub(not_before)
+            .and_then(|valid_time| u32::try_from(valid_time).ok())
+            .ok_or_else(|| {
+                Error::General(
+                    "delegated credential must expire after certificate's notBefore".into(),
+                )
+            })?;
+
+        let dc_cert_verify_algorithm = key
+            .choose_scheme(TLS13_SCHEMES)
+            .ok_or_else(|| {
+                Error::General("delegated credential key cannot sign for TLS1.3".into())
+            })?
+            .scheme();
+        let signer = cert_key
+            .choose_scheme(TLS13_SCHEMES)
+            .ok_or_else(|| Error::General("certificate key cannot sign for TLS1.3".into()))?;
+
+        let mut payload = DelegatedCredentialPayload {
+            valid_time,
+            dc_cert_verify_algorithm,
+            spki: PayloadU24::new(spki),
+            algorithm: signer.scheme(),
+            signature: PayloadU16::new(Vec::new()),
+        };
+        payload.signature = PayloadU16::new(signer.sign(&payload.signed_message(end_entity))?);
+
+        Ok(Self { payload, key })
+    }
+}
+
+/// A raw public key (RFC 7250): a key which authenticates a peer by
+/// itself, rather than with a certificate chain.
+///
+/// The other peer must be configured with the key's SubjectPublicKeyInfo
+/// to accept it.  Raw public keys are only used in TLS1.3.
+#[derive(Clone)]
+pub struct RawPublicKey {
+    pub(crate) spki: key::Certificate,
+    pub(crate) key: Arc<dyn SigningKey>,
+}
+
+impl RawPublicKey {
+    /// Authenticate with `key`, whose DER-encoded SubjectPublicKeyInfo
+    /// is `spki`.
+    pub fn new(key: Arc<dyn SigningKey>, spki: Vec<u8>) -> Self {
+        Self {
+            spki: key::Certificate(spki),
+            key,
+        }
+    }
+}
+
 /// Parse `der` as any supported key encoding/type, returning
 /// the first which works.
 pub fn any_supported_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, SignError> {
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use crate::crypto;
+use crate::crypto::hash::HashAlgorithm;
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureAlgorithm, SignatureScheme};
 #[cfg(feature = "tls12")]
 use crate::tls12::Tls12CipherSuite;
@@ -23,7 +25,7 @@ use crate::versions::{SupportedProtocolVersion, TLS13};
 
 /// Bulk symmetric encryption scheme used by a cipher suite.
 #[allow(non_camel_case_types)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BulkAlgorithm {
     /// AES with 128-bit keys in Galois counter mode.
     Aes128Gcm,
@@ -36,7 +38,6 @@ pub enum BulkAlgorithm {
 }
 
 /// Common state for cipher suites (both for TLS 1.2 and TLS 1.3)
-#[derive(Debug)]
 pub struct CipherSuiteCommon {
     /// The TLS enumeration naming this cipher suite.
     pub suite: CipherSuite,
@@ -44,9 +45,22 @@ pub struct CipherSuiteCommon {
     /// How to do bulk encryption.
     pub bulk: BulkAlgorithm,
 
+    /// Which hash function the suite uses.
+    pub(crate) hash_provider: &'static dyn crypto::hash::Hash,
+
     pub(crate) aead_algorithm: &'static ring::aead::Algorithm,
 }
 
+impl fmt::Debug for CipherSuiteCommon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherSuiteCommon")
+            .field("suite", &self.suite)
+            .field("bulk", &self.bulk)
+            .field("hash", &self.hash_provider.algorithm())
+            .finish()
+    }
+}
+
 /// A cipher suite supported by rustls.
 ///
 /// All possible instances of this type are provided by the library in
@@ -66,14 +80,25 @@ impl fmt::Debug for SupportedCipherSuite {
     }
 }
 
+/// Renders the IANA name and code point, eg. `TLS_AES_128_GCM_SHA256 (0x1301)`.
+impl fmt::Display for SupportedCipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.suite(), f)
+    }
+}
+
 impl SupportedCipherSuite {
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        match self {
-            #[cfg(feature = "tls12")]
-            Self::Tls12(inner) => inner.hash_algorithm(),
-            Self::Tls13(inner) => inner.hash_algorithm(),
-        }
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_provider().algorithm()
+    }
+
+    /// The hash function implementation used by this suite.
+    ///
+    /// This is the hash used for the handshake transcript, and (in TLS1.3)
+    /// the key schedule.
+    pub fn hash_provider(&self) -> &'static dyn crypto::hash::Hash {
+        self.common().hash_provider
     }
 
     /// The cipher suite's identifier
@@ -81,6 +106,21 @@ impl SupportedCipherSuite {
         self.common().suite
     }
 
+    /// The name of this cipher suite in the IANA "TLS Cipher Suites" registry,
+    /// eg. `TLS_AES_128_GCM_SHA256`.
+    ///
+    /// This is the reverse of [`lookup`](crate::cipher_suite::lookup).
+    pub fn iana_name(&self) -> &'static str {
+        self.suite()
+            .iana_name()
+            .unwrap_or_default()
+    }
+
+    /// Whether this is a CBC-mode, MAC-then-encrypt suite.
+    pub(crate) fn is_cbc(&self) -> bool {
+        self.suite().is_cbc()
+    }
+
     pub(crate) fn common(&self) -> &CipherSuiteCommon {
         match self {
             #[cfg(feature = "tls12")]
@@ -89,7 +129,6 @@ impl SupportedCipherSuite {
         }
     }
 
-    #[cfg(any(test, feature = "quic"))]
     pub(crate) fn tls13(&self) -> Option<&'static Tls13CipherSuite> {
         match self {
             #[cfg(feature = "tls12")]
@@ -142,12 +181,64 @@ pub static ALL_CIPHER_SUITES: &[SupportedCipherSuite] = &[
     TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
 ];
 
+/// Find the cipher suite with the given IANA name, eg. `TLS_AES_128_GCM_SHA256`
+/// or `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+///
+/// Returns `None` if `name` is not the name of a suite in [`ALL_CIPHER_SUITES`].
+pub fn lookup(name: &str) -> Option<SupportedCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .find(|suite| suite.iana_name() == name)
+        .copied()
+}
+
 /// The cipher suite configuration that an application should use by default.
 ///
 /// This will be [`ALL_CIPHER_SUITES`] sans any supported cipher suites that
 /// shouldn't be enabled by most applications.
 pub static DEFAULT_CIPHER_SUITES: &[SupportedCipherSuite] = ALL_CIPHER_SUITES;
 
+/// [`DEFAULT_CIPHER_SUITES`], reordered to prefer ChaCha20-Poly1305 among
+/// the suites for each protocol version if `aes_hardware` is false.
+///
+/// Without hardware support, AES-GCM is both slower than ChaCha20-Poly1305
+/// and harder to implement in constant time.
+pub(crate) fn default_cipher_suites(aes_hardware: bool) -> Vec<SupportedCipherSuite> {
+    let mut suites = DEFAULT_CIPHER_SUITES.to_vec();
+    if !aes_hardware {
+        // TLS1.3 suites come first in `DEFAULT_CIPHER_SUITES`, and stay first.
+        // nb. this is a stable sort, so otherwise preserves our preferences.
+        suites.sort_by_key(|suite| {
+            (
+                suite.tls13().is_none(),
+                suite.common().bulk != BulkAlgorithm::Chacha20Poly1305,
+            )
+        });
+    }
+    suites
+}
+
+/// Return true if this CPU has instructions accelerating AES-GCM.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) fn aes_hardware_available() -> bool {
+    std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("pclmulqdq")
+}
+
+/// Return true if this CPU has instructions accelerating AES-GCM.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn aes_hardware_available() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+        && std::arch::is_aarch64_feature_detected!("pmull")
+}
+
+/// Return true if this CPU has instructions accelerating AES-GCM.
+///
+/// We don't know how to detect that here, so assume not.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn aes_hardware_available() -> bool {
+    false
+}
+
 // These both O(N^2)!
 pub(crate) fn choose_ciphersuite_preferring_client(
     client_suites: &[CipherSuite],
@@ -203,6 +294,67 @@ pub(crate) fn reduce_given_version(
         .collect()
 }
 
+/// The suites from a list which are usable for each protocol version,
+/// and each kind of certificate key, worked out once in advance.
+#[derive(Clone, Debug)]
+pub(crate) struct SuitableSuites {
+    entries: Vec<(
+        ProtocolVersion,
+        Option<SignatureAlgorithm>,
+        Vec<SupportedCipherSuite>,
+    )>,
+}
+
+impl SuitableSuites {
+    pub(crate) fn new(all: &[SupportedCipherSuite]) -> Self {
+        let mut entries = Vec::new();
+        for version in [ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+            let for_version = reduce_given_version(all, version);
+            for sigalg in [
+                SignatureAlgorithm::RSA,
+                SignatureAlgorithm::ECDSA,
+                SignatureAlgorithm::ED25519,
+                SignatureAlgorithm::ED448,
+            ] {
+                entries.push((
+                    version,
+                    Some(sigalg),
+                    reduce_given_sigalg(&for_version, sigalg),
+                ));
+            }
+            entries.push((version, None, for_version));
+        }
+        Self { entries }
+    }
+
+    /// The suites in the original order which can be used with `version`,
+    /// and with a certificate key of type `sigalg` if there is one.
+    ///
+    /// Returns `None` for versions or key types not worked out in advance.
+    pub(crate) fn get(
+        &self,
+        version: ProtocolVersion,
+        sigalg: Option<SignatureAlgorithm>,
+    ) -> Option<&[SupportedCipherSuite]> {
+        self.entries
+            .iter()
+            .find(|(v, s, _)| *v == version && *s == sigalg)
+            .map(|(_, _, suites)| suites.as_slice())
+    }
+}
+
+/// Return a list of the ciphersuites in `all` which use the hash
+/// function `hash`.
+pub(crate) fn reduce_given_hash(
+    all: &[SupportedCipherSuite],
+    hash: HashAlgorithm,
+) -> Vec<SupportedCipherSuite> {
+    all.iter()
+        .filter(|&&suite| suite.hash_provider().algorithm() == hash)
+        .copied()
+        .collect()
+}
+
 /// Return true if `sigscheme` is usable by any of the given suites.
 pub(crate) fn compatible_sigscheme_for_suites(
     sigscheme: SignatureScheme,
@@ -306,6 +458,58 @@ mod test {
         assert_eq!(chosen.unwrap(), TLS13_AES_256_GCM_SHA384);
     }
 
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            lookup("TLS_AES_128_GCM_SHA256"),
+            Some(TLS13_AES_128_GCM_SHA256)
+        );
+        #[cfg(feature = "tls12")]
+        assert_eq!(
+            lookup("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"),
+            Some(TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384)
+        );
+        assert_eq!(lookup("TLS13_AES_128_GCM_SHA256"), None);
+        assert_eq!(lookup("TLS_NOT_A_SUITE"), None);
+        assert_eq!(lookup(""), None);
+
+        for suite in ALL_CIPHER_SUITES {
+            assert_eq!(lookup(suite.iana_name()), Some(*suite));
+        }
+    }
+
+    #[test]
+    fn test_suitable_suites_match_reductions() {
+        let cache = SuitableSuites::new(ALL_CIPHER_SUITES);
+        for version in [ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+            assert_eq!(
+                cache.get(version, None).unwrap(),
+                reduce_given_version(ALL_CIPHER_SUITES, version)
+            );
+            for sigalg in [
+                SignatureAlgorithm::RSA,
+                SignatureAlgorithm::ECDSA,
+                SignatureAlgorithm::ED25519,
+            ] {
+                let expected =
+                    reduce_given_version(&reduce_given_sigalg(ALL_CIPHER_SUITES, sigalg), version);
+                assert_eq!(
+                    cache
+                        .get(version, Some(sigalg))
+                        .unwrap(),
+                    expected
+                );
+            }
+        }
+
+        assert!(cache
+            .get(ProtocolVersion::TLSv1_1, None)
+            .is_none());
+        assert!(cache
+            .get(ProtocolVersion::TLSv1_3, Some(SignatureAlgorithm::DSA))
+            .is_none());
+    }
+
     #[test]
     fn test_pref_fails() {
         assert!(choose_ciphersuite_preferring_client(
@@ -320,11 +524,106 @@ mod test {
         .is_none());
     }
 
+    #[test]
+    fn test_default_cipher_suites_with_aes_hardware() {
+        assert_eq!(default_cipher_suites(true), DEFAULT_CIPHER_SUITES);
+    }
+
+    #[test]
+    fn test_default_cipher_suites_without_aes_hardware() {
+        let suites = default_cipher_suites(false);
+        assert_eq!(suites.len(), DEFAULT_CIPHER_SUITES.len());
+        assert_eq!(suites[0], TLS13_CHACHA20_POLY1305_SHA256);
+
+        // Versions keep their order: no TLS1.2 suite precedes a TLS1.3 one.
+        let tls13_suites = suites
+            .iter()
+            .take_while(|suite| suite.tls13().is_some())
+            .count();
+        assert!(suites[tls13_suites..]
+            .iter()
+            .all(|suite| suite.tls13().is_none()));
+
+        for version_suites in [&suites[..tls13_suites], &suites[tls13_suites..]] {
+            let chacha_suites = version_suites
+                .iter()
+                .take_while(|suite| suite.common().bulk == BulkAlgorithm::Chacha20Poly1305)
+                .count();
+            assert!(version_suites[chacha_suites..]
+                .iter()
+                .all(|suite| suite.common().bulk != BulkAlgorithm::Chacha20Poly1305));
+
+            // AES suites keep their relative order.
+            let aes_suites: Vec<_> = DEFAULT_CIPHER_SUITES
+                .iter()
+                .filter(|suite| {
+                    suite.common().bulk != BulkAlgorithm::Chacha20Poly1305
+                        && version_suites.contains(suite)
+                })
+                .copied()
+                .collect();
+            assert_eq!(&version_suites[chacha_suites..], &aes_suites[..]);
+        }
+    }
+
     #[test]
     fn test_scs_is_debug() {
         println!("{:?}", ALL_CIPHER_SUITES);
     }
 
+    #[test]
+    fn test_scs_display() {
+        assert_eq!(
+            TLS13_AES_128_GCM_SHA256.to_string(),
+            "TLS_AES_128_GCM_SHA256 (0x1301)"
+        );
+        assert_eq!(
+            format!("{:?}", TLS13_AES_256_GCM_SHA384),
+            "TLS13_AES_256_GCM_SHA384"
+        );
+        #[cfg(feature = "tls12")]
+        assert_eq!(
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256.to_string(),
+            "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256 (0xcca9)"
+        );
+    }
+
+    #[test]
+    fn test_hash_algorithm() {
+        assert_eq!(
+            TLS13_AES_256_GCM_SHA384.hash_algorithm(),
+            HashAlgorithm::SHA384
+        );
+        assert_eq!(
+            TLS13_AES_128_GCM_SHA256.hash_algorithm(),
+            HashAlgorithm::SHA256
+        );
+        assert_eq!(
+            TLS13_AES_256_GCM_SHA384
+                .hash_provider()
+                .output_len(),
+            48
+        );
+        assert_eq!(
+            TLS13_AES_128_GCM_SHA256
+                .hash_provider()
+                .output_len(),
+            32
+        );
+
+        #[cfg(feature = "tls12")]
+        {
+            assert_eq!(
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384.hash_algorithm(),
+                HashAlgorithm::SHA384
+            );
+            assert_eq!(
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256.hash_algorithm(),
+                HashAlgorithm::SHA256
+            );
+        }
+    }
+
     #[test]
     fn test_can_resume_to() {
         assert!(TLS13_AES_128_GCM_SHA256
@@ -0,0 +1,139 @@
+use crate::crypto::{CryptoProvider, KeyExchange, SupportedGroup};
+use crate::enums::ProtocolVersion;
+use crate::error::Error;
+use crate::suites::{self, SupportedCipherSuite};
+use crate::versions::SupportedProtocolVersion;
+use crate::ALL_VERSIONS;
+
+/// The parameters a configuration negotiates, described by name.
+///
+/// This is intended for configuration-as-data: with the `serde` feature
+/// this can be serialized and deserialized, and then given to
+/// [`ClientConfig::from_template`] to make a configuration.
+///
+/// Names are:
+///
+/// - cipher suites: the names in the IANA "TLS Cipher Suites" registry,
+///   for example `TLS_AES_128_GCM_SHA256` or `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+///   See [`SupportedCipherSuite::iana_name`].
+/// - key exchange groups: the names in the IANA "TLS Supported Groups"
+///   registry, for example `x25519` or `secp256r1`.  See [`NamedGroup::name`].
+/// - protocol versions: `TLSv1.2` or `TLSv1.3`.
+///
+/// An empty list of cipher suites, key exchange groups or protocol
+/// versions means the safe defaults for that list.
+///
+/// [`ClientConfig::from_template`]: crate::ClientConfig::from_template
+/// [`NamedGroup::name`]: crate::NamedGroup::name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ConfigTemplate {
+    /// Cipher suites, in preference order.
+    pub cipher_suites: Vec<String>,
+
+    /// Key exchange groups, in preference order.
+    pub kx_groups: Vec<String>,
+
+    /// Protocol versions.
+    pub versions: Vec<String>,
+
+    /// ALPN protocols, in preference order.
+    pub alpn_protocols: Vec<String>,
+}
+
+impl ConfigTemplate {
+    pub(crate) fn resolve_cipher_suites(&self) -> Result<Vec<SupportedCipherSuite>, Error> {
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                suites::lookup(name)
+                    .ok_or_else(|| Error::General(format!("unknown cipher suite {:?}", name)))
+            })
+            .collect()
+    }
+
+    pub(crate) fn resolve_kx_groups<C: CryptoProvider>(
+        &self,
+    ) -> Result<Vec<&'static <C::KeyExchange as KeyExchange>::SupportedGroup>, Error> {
+        self.kx_groups
+            .iter()
+            .map(|name| {
+                C::KeyExchange::all_kx_groups()
+                    .iter()
+                    .find(|group| group.name().name() == Some(name.as_str()))
+                    .copied()
+                    .ok_or_else(|| Error::General(format!("unknown key exchange group {:?}", name)))
+            })
+            .collect()
+    }
+
+    pub(crate) fn resolve_versions(&self) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+        self.versions
+            .iter()
+            .map(|name| {
+                ALL_VERSIONS
+                    .iter()
+                    .find(|version| version_name(version.version) == Some(name.as_str()))
+                    .copied()
+                    .ok_or_else(|| Error::General(format!("unknown protocol version {:?}", name)))
+            })
+            .collect()
+    }
+
+    pub(crate) fn resolve_alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect()
+    }
+}
+
+fn version_name(version: ProtocolVersion) -> Option<&'static str> {
+    VERSION_NAMES
+        .iter()
+        .find(|(v, _)| *v == version)
+        .map(|(_, name)| *name)
+}
+
+static VERSION_NAMES: &[(ProtocolVersion, &str)] = &[
+    (ProtocolVersion::TLSv1_2, "TLSv1.2"),
+    (ProtocolVersion::TLSv1_3, "TLSv1.3"),
+];
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::ConfigTemplate;
+    use crate::crypto::ring::Ring;
+    use crate::{ClientConfig, RootCertStore};
+
+    #[test]
+    fn template_round_trips_through_json() {
+        let template = ConfigTemplate {
+            cipher_suites: vec!["TLS_AES_128_GCM_SHA256".to_string()],
+            kx_groups: vec!["x25519".to_string()],
+            versions: vec!["TLSv1.3".to_string()],
+            alpn_protocols: vec!["h2".to_string()],
+        };
+
+        let json = serde_json::to_string(&template).unwrap();
+        assert_eq!(
+            json,
+            r#"{"cipher_suites":["TLS_AES_128_GCM_SHA256"],"kx_groups":["x25519"],"versions":["TLSv1.3"],"alpn_protocols":["h2"]}"#
+        );
+
+        let parsed: ConfigTemplate = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, template);
+        assert!(ClientConfig::<Ring>::from_template(&parsed, RootCertStore::empty()).is_ok());
+
+        // Missing fields take their defaults.
+        let parsed: ConfigTemplate = serde_json::from_str(r#"{"alpn_protocols":["h2"]}"#).unwrap();
+        assert_eq!(
+            parsed,
+            ConfigTemplate {
+                alpn_protocols: vec!["h2".to_string()],
+                ..Default::default()
+            }
+        );
+    }
+}
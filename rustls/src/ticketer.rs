@@ -2,30 +2,10 @@ use crate::rand;
 use crate::server::ProducesTickets;
 use crate::Error;
 
-use std::mem;
-use std::sync::{Mutex, MutexGuard};
-use std::time;
-
-/// The timebase for expiring and rolling tickets and ticketing
-/// keys.  This is UNIX wall time in seconds.
-///
-/// This is guaranteed to be on or after the UNIX epoch.
-#[derive(Clone, Copy, Debug)]
-pub struct TimeBase(pub(crate) time::Duration);
-
-impl TimeBase {
-    #[inline]
-    pub fn now() -> Result<Self, time::SystemTimeError> {
-        Ok(Self(
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH)?,
-        ))
-    }
+use crate::time::{DefaultTimeProvider, TimeProvider, UnixTime};
 
-    #[inline]
-    pub fn as_secs(&self) -> u64 {
-        self.0.as_secs()
-    }
-}
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub(crate) struct TicketSwitcherState {
     next: Option<Box<dyn ProducesTickets>>,
@@ -41,6 +21,7 @@ pub struct TicketSwitcher {
     pub(crate) generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
     lifetime: u32,
     state: Mutex<TicketSwitcherState>,
+    time_provider: Arc<dyn TimeProvider>,
 }
 
 impl TicketSwitcher {
@@ -51,14 +32,32 @@ impl TicketSwitcher {
     /// is used to generate new tickets.  Tickets are accepted for no
     /// longer than twice this duration.  `generator` produces a new
     /// `ProducesTickets` implementation.
+    ///
+    /// The passage of time is measured with the system clock.
     pub fn new(
         lifetime: u32,
         generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
     ) -> Result<Self, Error> {
-        let now = TimeBase::now()?;
+        Self::new_with_time_provider(lifetime, generator, Arc::new(DefaultTimeProvider))
+    }
+
+    /// Like [`TicketSwitcher::new`], but measures the passage of time
+    /// with `time_provider`.
+    ///
+    /// This should usually be the same provider as
+    /// [`ServerConfig::time_provider`](crate::ServerConfig::time_provider).
+    pub fn new_with_time_provider(
+        lifetime: u32,
+        generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Result<Self, Error> {
+        let now = time_provider
+            .current_time()
+            .ok_or(Error::FailedToGetCurrentTime)?;
         Ok(Self {
             generator,
             lifetime,
+            time_provider,
             state: Mutex::new(TicketSwitcherState {
                 next: Some(generator()?),
                 current: generator()?,
@@ -79,7 +78,7 @@ impl TicketSwitcher {
     ///
     /// For efficiency, this is also responsible for locking the state mutex
     /// and returning the mutexguard.
-    pub(crate) fn maybe_roll(&self, now: TimeBase) -> Option<MutexGuard<TicketSwitcherState>> {
+    pub(crate) fn maybe_roll(&self, now: UnixTime) -> Option<MutexGuard<'_, TicketSwitcherState>> {
         // The code below aims to make switching as efficient as possible
         // in the common case that the generator never fails. To achieve this
         // we run the following steps:
@@ -161,13 +160,13 @@ impl ProducesTickets for TicketSwitcher {
     }
 
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
-        let state = self.maybe_roll(TimeBase::now().ok()?)?;
+        let state = self.maybe_roll(self.time_provider.current_time()?)?;
 
         state.current.encrypt(message)
     }
 
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-        let state = self.maybe_roll(TimeBase::now().ok()?)?;
+        let state = self.maybe_roll(self.time_provider.current_time()?)?;
 
         // Decrypt with the current key; if that fails, try with the previous.
         state
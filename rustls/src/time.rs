@@ -0,0 +1,100 @@
+use core::fmt::Debug;
+use core::time::Duration;
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A point in time, as a whole number of seconds since the UNIX epoch.
+///
+/// This is the precision rustls needs: for checking certificate
+/// validity periods, and for expiring tickets and sessions.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct UnixTime(u64);
+
+impl UnixTime {
+    /// Make a `UnixTime` for a time `duration` after the UNIX epoch.
+    ///
+    /// Fractions of a second are discarded.
+    pub fn since_unix_epoch(duration: Duration) -> Self {
+        Self(duration.as_secs())
+    }
+
+    /// The number of whole seconds since the UNIX epoch.
+    pub fn as_secs(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<UnixTime> for SystemTime {
+    fn from(time: UnixTime) -> Self {
+        UNIX_EPOCH + Duration::from_secs(time.0)
+    }
+}
+
+/// Where rustls learns the current time.
+///
+/// The default, [`DefaultTimeProvider`], asks the operating system.
+/// Supply another to run where there is no system clock, or to make
+/// time-dependent behaviour deterministic in tests.
+pub trait TimeProvider: Debug + Send + Sync {
+    /// Return the current wall time, or `None` if it isn't available.
+    ///
+    /// rustls fails handshakes which need the time with
+    /// [`Error::FailedToGetCurrentTime`] if this returns `None`.
+    ///
+    /// [`Error::FailedToGetCurrentTime`]: crate::Error::FailedToGetCurrentTime
+    fn current_time(&self) -> Option<UnixTime>;
+}
+
+/// A [`TimeProvider`] which uses the system clock.
+#[derive(Debug)]
+pub struct DefaultTimeProvider;
+
+impl TimeProvider for DefaultTimeProvider {
+    fn current_time(&self) -> Option<UnixTime> {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(UnixTime::since_unix_epoch)
+    }
+}
+
+/// Where rustls learns the current monotonic time.
+///
+/// This is used to enforce
+/// [`CommonState::set_handshake_deadline`](crate::CommonState::set_handshake_deadline).
+/// The default, [`DefaultMonotonicClock`], is [`Instant::now`].  Supply
+/// another to make deadlines deterministic in tests.
+pub trait MonotonicClock: Debug + Send + Sync {
+    /// Return the current monotonic time.
+    fn now(&self) -> Instant;
+}
+
+/// A [`MonotonicClock`] which uses [`Instant::now`].
+#[derive(Debug)]
+pub struct DefaultMonotonicClock;
+
+impl MonotonicClock for DefaultMonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_time_discards_fractional_seconds() {
+        let time = UnixTime::since_unix_epoch(Duration::from_millis(1_500));
+        assert_eq!(time.as_secs(), 1);
+        assert_eq!(SystemTime::from(time), UNIX_EPOCH + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn default_time_provider_is_after_epoch() {
+        let now = DefaultTimeProvider
+            .current_time()
+            .unwrap();
+        assert!(now.as_secs() > 0);
+    }
+}
@@ -1,4 +1,5 @@
-use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::cipher::{make_nonce, MessageDecrypter, MessageEncrypter};
+use crate::crypto::cipher::Iv;
 use crate::enums::ContentType;
 use crate::enums::ProtocolVersion;
 use crate::error::Error;
@@ -2,6 +2,7 @@ use crate::cipher::{MessageDecrypter, MessageEncrypter};
 use crate::common_state::{CommonState, Side};
 use crate::conn::ConnectionRandoms;
 use crate::crypto;
+use crate::crypto::hash::{self, HashAlgorithm};
 use crate::enums::{AlertDescription, CipherSuite, SignatureScheme};
 use crate::error::{Error, InvalidMessage};
 use crate::msgs::codec::{Codec, Reader};
@@ -11,7 +12,6 @@ use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 
 use ring::aead;
-use ring::digest::Digest;
 
 use std::fmt;
 
@@ -26,6 +26,7 @@ pub static TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
             bulk: BulkAlgorithm::Chacha20Poly1305,
+            hash_provider: &crypto::ring::hash::SHA256,
             aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -42,6 +43,7 @@ pub static TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
             bulk: BulkAlgorithm::Chacha20Poly1305,
+            hash_provider: &crypto::ring::hash::SHA256,
             aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -58,6 +60,7 @@ pub static TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
             bulk: BulkAlgorithm::Aes128Gcm,
+            hash_provider: &crypto::ring::hash::SHA256,
             aead_algorithm: &ring::aead::AES_128_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -74,6 +77,7 @@ pub static TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
+            hash_provider: &crypto::ring::hash::SHA384,
             aead_algorithm: &ring::aead::AES_256_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -90,6 +94,7 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
             bulk: BulkAlgorithm::Aes128Gcm,
+            hash_provider: &crypto::ring::hash::SHA256,
             aead_algorithm: &ring::aead::AES_128_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -106,6 +111,7 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
+            hash_provider: &crypto::ring::hash::SHA384,
             aead_algorithm: &ring::aead::AES_256_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
@@ -171,8 +177,8 @@ impl Tls12CipherSuite {
     }
 
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hmac_algorithm.digest_algorithm()
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.common.hash_provider.algorithm()
     }
 }
 
@@ -208,7 +214,7 @@ impl ConnectionSecrets {
     pub(crate) fn from_key_exchange(
         kx: impl crypto::KeyExchange,
         peer_pub_key: &[u8],
-        ems_seed: Option<Digest>,
+        ems_seed: Option<hash::Output>,
         randoms: ConnectionRandoms,
         suite: &'static Tls12CipherSuite,
     ) -> Result<Self, Error> {
@@ -340,7 +346,7 @@ impl ConnectionSecrets {
         ret
     }
 
-    fn make_verify_data(&self, handshake_hash: &Digest, label: &[u8]) -> Vec<u8> {
+    fn make_verify_data(&self, handshake_hash: &hash::Output, label: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
         out.resize(12, 0u8);
 
@@ -354,11 +360,11 @@ impl ConnectionSecrets {
         out
     }
 
-    pub(crate) fn client_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn client_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"client finished")
     }
 
-    pub(crate) fn server_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn server_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"server finished")
     }
 
@@ -472,7 +478,7 @@ impl ConnectionSecrets {
 }
 
 enum Seed {
-    Ems(Digest),
+    Ems(hash::Output),
     Randoms([u8; 64]),
 }
 
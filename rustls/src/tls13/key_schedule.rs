@@ -1,7 +1,10 @@
-use crate::cipher::{Iv, IvLen, MessageDecrypter};
+use crate::cipher::MessageDecrypter;
 use crate::common_state::{CommonState, Side};
+use crate::crypto::cipher;
+use crate::crypto::hash;
+use crate::crypto::hkdf::{Hkdf, Prk};
+use crate::crypto::hmac;
 use crate::error::Error;
-use crate::msgs::base::PayloadU8;
 #[cfg(feature = "quic")]
 use crate::quic;
 #[cfg(feature = "secret_extraction")]
@@ -9,18 +12,14 @@ use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 use crate::{KeyLog, Tls13CipherSuite};
 
 /// Key schedule maintenance for TLS1.3
-use ring::{
-    aead,
-    digest::{self, Digest},
-    hkdf::{self, KeyType as _},
-    hmac,
-};
+use ring::aead;
 
 use super::{Tls13MessageDecrypter, Tls13MessageEncrypter};
 
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum SecretKind {
+    ExternalPskBinderKey,
     ResumptionPskBinderKey,
     ClientEarlyTrafficSecret,
     ClientHandshakeTrafficSecret,
@@ -36,6 +35,7 @@ impl SecretKind {
     fn to_bytes(self) -> &'static [u8] {
         use self::SecretKind::*;
         match self {
+            ExternalPskBinderKey => b"ext binder",
             ResumptionPskBinderKey => b"res binder",
             ClientEarlyTrafficSecret => b"c e traffic",
             ClientHandshakeTrafficSecret => b"c hs traffic",
@@ -68,7 +68,7 @@ impl SecretKind {
 /// the type of hash.  This isn't used directly; but only through the
 /// typestates.
 struct KeySchedule {
-    current: hkdf::Prk,
+    current: Prk,
     suite: &'static Tls13CipherSuite,
 }
 
@@ -92,7 +92,7 @@ impl KeyScheduleEarly {
 
     pub(crate) fn client_early_traffic_secret(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -123,7 +123,7 @@ impl KeyScheduleEarly {
 
     pub(crate) fn resumption_psk_binder_key_and_sign_verify_data(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
     ) -> hmac::Tag {
         let resumption_psk_binder_key = self
             .ks
@@ -131,6 +131,17 @@ impl KeyScheduleEarly {
         self.ks
             .sign_verify_data(&resumption_psk_binder_key, hs_hash)
     }
+
+    pub(crate) fn external_psk_binder_key_and_sign_verify_data(
+        &self,
+        hs_hash: &hash::Output,
+    ) -> hmac::Tag {
+        let external_psk_binder_key = self
+            .ks
+            .derive_for_empty_hash(SecretKind::ExternalPskBinderKey);
+        self.ks
+            .sign_verify_data(&external_psk_binder_key, hs_hash)
+    }
 }
 
 /// Pre-handshake key schedule
@@ -152,6 +163,13 @@ impl KeySchedulePreHandshake {
         self.ks.input_secret(secret);
         KeyScheduleHandshakeStart { ks: self.ks }
     }
+
+    /// Like `into_handshake`, but for a PSK-only (`psk_ke`) handshake which
+    /// has no key exchange: the empty secret is used instead.
+    pub(crate) fn into_handshake_without_key_exchange(mut self) -> KeyScheduleHandshakeStart {
+        self.ks.input_empty();
+        KeyScheduleHandshakeStart { ks: self.ks }
+    }
 }
 
 impl From<KeyScheduleEarly> for KeySchedulePreHandshake {
@@ -169,7 +187,7 @@ impl KeyScheduleHandshakeStart {
     pub(crate) fn derive_client_handshake_secrets(
         mut self,
         early_data_enabled: bool,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         suite: &'static Tls13CipherSuite,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
@@ -195,7 +213,7 @@ impl KeyScheduleHandshakeStart {
 
     pub(crate) fn derive_server_handshake_secrets(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -213,7 +231,7 @@ impl KeyScheduleHandshakeStart {
 
     fn into_handshake(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         _common: &mut CommonState,
@@ -254,12 +272,12 @@ impl KeyScheduleHandshakeStart {
 
 pub(crate) struct KeyScheduleHandshake {
     ks: KeySchedule,
-    client_handshake_traffic_secret: hkdf::Prk,
-    server_handshake_traffic_secret: hkdf::Prk,
+    client_handshake_traffic_secret: Prk,
+    server_handshake_traffic_secret: Prk,
 }
 
 impl KeyScheduleHandshake {
-    pub(crate) fn sign_server_finish(&self, hs_hash: &Digest) -> hmac::Tag {
+    pub(crate) fn sign_server_finish(&self, hs_hash: &hash::Output) -> hmac::Tag {
         self.ks
             .sign_finish(&self.server_handshake_traffic_secret, hs_hash)
     }
@@ -285,13 +303,14 @@ impl KeyScheduleHandshake {
                     self.ks
                         .derive_decrypter(&self.client_handshake_traffic_secret),
                     max_early_data_size,
+                    self.ks.suite.integrity_limit,
                 ),
         }
     }
 
     pub(crate) fn into_traffic_with_client_finished_pending(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -327,8 +346,8 @@ impl KeyScheduleHandshake {
 
     pub(crate) fn into_pre_finished_client_traffic(
         self,
-        pre_finished_hash: Digest,
-        handshake_hash: Digest,
+        pre_finished_hash: hash::Output,
+        handshake_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> (KeyScheduleClientBeforeFinished, hmac::Tag) {
@@ -382,7 +401,7 @@ impl KeyScheduleClientBeforeFinished {
 /// finished verify_data. The traffic stage key schedule can be extracted from it
 /// through signing the client finished hash.
 pub(crate) struct KeyScheduleTrafficWithClientFinishedPending {
-    handshake_client_traffic_secret: hkdf::Prk,
+    handshake_client_traffic_secret: Prk,
     traffic: KeyScheduleTraffic,
 }
 
@@ -396,7 +415,7 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 
     pub(crate) fn sign_client_finish(
         self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         common: &mut CommonState,
     ) -> (KeyScheduleTraffic, hmac::Tag) {
         debug_assert_eq!(common.side, Side::Server);
@@ -421,15 +440,15 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 /// to be available.
 pub(crate) struct KeyScheduleTraffic {
     ks: KeySchedule,
-    current_client_traffic_secret: hkdf::Prk,
-    current_server_traffic_secret: hkdf::Prk,
-    current_exporter_secret: hkdf::Prk,
+    current_client_traffic_secret: Prk,
+    current_server_traffic_secret: Prk,
+    current_exporter_secret: Prk,
 }
 
 impl KeyScheduleTraffic {
     fn new(
         mut ks: KeySchedule,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> Self {
@@ -464,18 +483,23 @@ impl KeyScheduleTraffic {
         }
     }
 
-    pub(crate) fn update_encrypter_and_notify(&mut self, common: &mut CommonState) {
+    pub(crate) fn update_encrypter_and_notify(
+        &mut self,
+        common: &mut CommonState,
+    ) -> Result<(), Error> {
+        common.enqueue_key_update_notification()?;
         let secret = self.next_application_traffic_secret(common.side);
-        common.enqueue_key_update_notification();
         self.ks.set_encrypter(&secret, common);
+        Ok(())
     }
 
     pub(crate) fn update_decrypter(&mut self, common: &mut CommonState) {
+        common.stats.key_updated = true;
         let secret = self.next_application_traffic_secret(common.side.peer());
         self.ks.set_decrypter(&secret, common);
     }
 
-    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> hkdf::Prk {
+    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> Prk {
         let current = match side {
             Side::Client => &mut self.current_client_traffic_secret,
             Side::Server => &mut self.current_server_traffic_secret,
@@ -488,14 +512,12 @@ impl KeyScheduleTraffic {
 
     pub(crate) fn resumption_master_secret_and_derive_ticket_psk(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         nonce: &[u8],
     ) -> Vec<u8> {
-        let resumption_master_secret = self.ks.derive(
-            self.ks.algorithm(),
-            SecretKind::ResumptionMasterSecret,
-            hs_hash.as_ref(),
-        );
+        let resumption_master_secret = self
+            .ks
+            .derive(SecretKind::ResumptionMasterSecret, hs_hash.as_ref());
         self.ks
             .derive_ticket_psk(&resumption_master_secret, nonce)
     }
@@ -513,31 +535,29 @@ impl KeyScheduleTraffic {
     #[cfg(feature = "secret_extraction")]
     pub(crate) fn extract_secrets(&self, side: Side) -> Result<PartiallyExtractedSecrets, Error> {
         fn expand<const KEY_LEN: usize, const IV_LEN: usize>(
-            secret: &hkdf::Prk,
+            hkdf: &dyn Hkdf,
+            secret: &Prk,
         ) -> Result<([u8; KEY_LEN], [u8; IV_LEN]), Error> {
             let mut key = [0u8; KEY_LEN];
             let mut iv = [0u8; IV_LEN];
 
-            hkdf_expand_info(secret, PayloadU8Len(key.len()), b"key", &[], |okm| {
-                okm.fill(&mut key)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf.expand_label(secret.as_ref(), b"key", &[], &mut key)
+                .map_err(|_| Error::General("hkdf_expand_label failed".to_string()))?;
 
-            hkdf_expand_info(secret, PayloadU8Len(iv.len()), b"iv", &[], |okm| {
-                okm.fill(&mut iv)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf.expand_label(secret.as_ref(), b"iv", &[], &mut iv)
+                .map_err(|_| Error::General("hkdf_expand_label failed".to_string()))?;
 
             Ok((key, iv))
         }
 
+        let hkdf = self.ks.hkdf();
         let client_secrets;
         let server_secrets;
 
         let algo = self.ks.suite.common.aead_algorithm;
         if algo == &ring::aead::AES_128_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv_in) = expand::<16, 12>(secret)?;
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
+                let (key, iv_in) = expand::<16, 12>(hkdf, secret)?;
 
                 let mut salt = [0u8; 4];
                 salt.copy_from_slice(&iv_in[..4]);
@@ -551,8 +571,8 @@ impl KeyScheduleTraffic {
             client_secrets = extract(&self.current_client_traffic_secret)?;
             server_secrets = extract(&self.current_server_traffic_secret)?;
         } else if algo == &ring::aead::AES_256_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv_in) = expand::<32, 12>(secret)?;
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
+                let (key, iv_in) = expand::<32, 12>(hkdf, secret)?;
 
                 let mut salt = [0u8; 4];
                 salt.copy_from_slice(&iv_in[..4]);
@@ -566,8 +586,8 @@ impl KeyScheduleTraffic {
             client_secrets = extract(&self.current_client_traffic_secret)?;
             server_secrets = extract(&self.current_server_traffic_secret)?;
         } else if algo == &ring::aead::CHACHA20_POLY1305 {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv) = expand::<32, 12>(secret)?;
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
+                let (key, iv) = expand::<32, 12>(hkdf, secret)?;
                 Ok(ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv })
             };
 
@@ -590,35 +610,41 @@ impl KeyScheduleTraffic {
 
 impl KeySchedule {
     fn new(suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        let salt = hkdf::Salt::new(suite.hkdf_algorithm, &zeroes[..suite.hkdf_algorithm.len()]);
+        let zeroes = [0u8; Prk::MAX_LEN];
+        let salt = &zeroes[..suite.hkdf_provider.hash_output_len()];
         Self {
-            current: salt.extract(secret),
+            current: suite
+                .hkdf_provider
+                .extract(salt, secret),
             suite,
         }
     }
 
-    fn set_encrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
-        let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
-        let iv = derive_traffic_iv(secret);
+    fn set_encrypter(&self, secret: &Prk, common: &mut CommonState) {
+        let key = derive_traffic_key(self.suite, secret);
+        let iv = cipher::derive_traffic_iv(self.hkdf(), secret.as_ref());
 
         common
             .record_layer
-            .set_message_encrypter(Box::new(Tls13MessageEncrypter {
-                enc_key: aead::LessSafeKey::new(key),
-                iv,
-            }));
+            .set_message_encrypter(
+                Box::new(Tls13MessageEncrypter {
+                    enc_key: aead::LessSafeKey::new(key),
+                    iv,
+                    padding: common.record_padding.clone(),
+                }),
+                self.suite.confidentiality_limit,
+            );
     }
 
-    fn set_decrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
+    fn set_decrypter(&self, secret: &Prk, common: &mut CommonState) {
         common
             .record_layer
             .set_message_decrypter(self.derive_decrypter(secret));
     }
 
-    fn derive_decrypter(&self, secret: &hkdf::Prk) -> Box<dyn MessageDecrypter> {
-        let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
-        let iv = derive_traffic_iv(secret);
+    fn derive_decrypter(&self, secret: &Prk) -> Box<dyn MessageDecrypter> {
+        let key = derive_traffic_key(self.suite, secret);
+        let iv = cipher::derive_traffic_iv(self.hkdf(), secret.as_ref());
         Box::new(Tls13MessageDecrypter {
             dec_key: aead::LessSafeKey::new(key),
             iv,
@@ -626,34 +652,32 @@ impl KeySchedule {
     }
 
     #[inline]
-    fn algorithm(&self) -> hkdf::Algorithm {
-        self.suite.hkdf_algorithm
+    fn hkdf(&self) -> &'static dyn Hkdf {
+        self.suite.hkdf_provider
     }
 
     fn new_with_empty_secret(suite: &'static Tls13CipherSuite) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        Self::new(suite, &zeroes[..suite.hkdf_algorithm.len()])
+        let zeroes = [0u8; Prk::MAX_LEN];
+        Self::new(suite, &zeroes[..suite.hkdf_provider.hash_output_len()])
     }
 
     /// Input the empty secret.
     fn input_empty(&mut self) {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        self.input_secret(&zeroes[..self.suite.hkdf_algorithm.len()]);
+        let zeroes = [0u8; Prk::MAX_LEN];
+        self.input_secret(&zeroes[..self.hkdf().hash_output_len()]);
     }
 
     /// Input the given secret.
     fn input_secret(&mut self, secret: &[u8]) {
-        let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
-        self.current = salt.extract(secret);
+        let salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
+        self.current = self
+            .hkdf()
+            .extract(salt.as_ref(), secret);
     }
 
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
-    fn derive<T, L>(&self, key_type: L, kind: SecretKind, hs_hash: &[u8]) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, L>>,
-        L: hkdf::KeyType,
-    {
-        hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash)
+    fn derive(&self, kind: SecretKind, hs_hash: &[u8]) -> Prk {
+        hkdf_expand_label_prk(self.hkdf(), &self.current, kind.to_bytes(), hs_hash)
     }
 
     fn derive_logged_secret(
@@ -662,177 +686,360 @@ impl KeySchedule {
         hs_hash: &[u8],
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> hkdf::Prk {
+    ) -> Prk {
         let log_label = kind
             .log_label()
             .expect("not a loggable secret");
+        let secret = self.derive(kind, hs_hash);
         if key_log.will_log(log_label) {
-            let secret = self
-                .derive::<PayloadU8, _>(
-                    PayloadU8Len(self.suite.hkdf_algorithm.len()),
-                    kind,
-                    hs_hash,
-                )
-                .into_inner();
-            key_log.log(log_label, client_random, &secret);
+            key_log.log(log_label, client_random, secret.as_ref());
         }
-        self.derive(self.suite.hkdf_algorithm, kind, hs_hash)
+        secret
     }
 
     /// Derive a secret of given `kind` using the hash of the empty string
     /// for the handshake hash.  Useful only for
-    /// `SecretKind::ResumptionPSKBinderKey` and
+    /// `SecretKind::ResumptionPSKBinderKey`,
+    /// `SecretKind::ExternalPskBinderKey` and
     /// `SecretKind::DerivedSecret`.
-    fn derive_for_empty_hash<T>(&self, kind: SecretKind) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, hkdf::Algorithm>>,
-    {
-        let digest_alg = self
+    fn derive_for_empty_hash(&self, kind: SecretKind) -> Prk {
+        let empty_hash = self
             .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-        let empty_hash = digest::digest(digest_alg, &[]);
-        self.derive(self.suite.hkdf_algorithm, kind, empty_hash.as_ref())
+            .common
+            .hash_provider
+            .compute_empty();
+        self.derive(kind, empty_hash.as_ref())
     }
 
     /// Sign the finished message consisting of `hs_hash` using a current
     /// traffic secret.
-    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_finish(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
         self.sign_verify_data(base_key, hs_hash)
     }
 
     /// Sign the finished message consisting of `hs_hash` using the key material
     /// `base_key`.
-    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
-        let hmac_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm();
-        let hmac_key = hkdf_expand(base_key, hmac_alg, b"finished", &[]);
-        hmac::sign(&hmac_key, hs_hash.as_ref())
+    fn sign_verify_data(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
+        let hmac_key = hkdf_expand_label_prk(self.hkdf(), base_key, b"finished", &[]);
+        self.hkdf()
+            .hmac_sign(hmac_key.as_ref(), hs_hash.as_ref())
     }
 
     /// Derive the next application traffic secret, returning it.
-    fn derive_next(&self, base_key: &hkdf::Prk) -> hkdf::Prk {
-        hkdf_expand(base_key, self.suite.hkdf_algorithm, b"traffic upd", &[])
+    fn derive_next(&self, base_key: &Prk) -> Prk {
+        hkdf_expand_label_prk(self.hkdf(), base_key, b"traffic upd", &[])
     }
 
     /// Derive the PSK to use given a resumption_master_secret and
     /// ticket_nonce.
-    fn derive_ticket_psk(&self, rms: &hkdf::Prk, nonce: &[u8]) -> Vec<u8> {
-        let payload: PayloadU8 = hkdf_expand(
-            rms,
-            PayloadU8Len(self.suite.hkdf_algorithm.len()),
-            b"resumption",
-            nonce,
-        );
-        payload.into_inner()
+    fn derive_ticket_psk(&self, rms: &Prk, nonce: &[u8]) -> Vec<u8> {
+        hkdf_expand_label_prk(self.hkdf(), rms, b"resumption", nonce)
+            .as_ref()
+            .to_vec()
     }
 
     fn export_keying_material(
         &self,
-        current_exporter_secret: &hkdf::Prk,
+        current_exporter_secret: &Prk,
         out: &mut [u8],
         label: &[u8],
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
-        let digest_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
+        let hash_provider = self.suite.common.hash_provider;
 
-        let h_empty = digest::digest(digest_alg, &[]);
-        let secret: hkdf::Prk = hkdf_expand(
+        let h_empty = hash_provider.compute_empty();
+        let secret = hkdf_expand_label_prk(
+            self.hkdf(),
             current_exporter_secret,
-            self.suite.hkdf_algorithm,
             label,
             h_empty.as_ref(),
         );
 
-        let h_context = digest::digest(digest_alg, context.unwrap_or(&[]));
+        let h_context = hash_provider.hash(context.unwrap_or(&[]));
 
         // TODO: Test what happens when this fails
-        hkdf_expand_info(
-            &secret,
-            PayloadU8Len(out.len()),
-            b"exporter",
-            h_context.as_ref(),
-            |okm| okm.fill(out),
-        )
-        .map_err(|_| Error::General("exporting too much".to_string()))
+        self.hkdf()
+            .expand_label(secret.as_ref(), b"exporter", h_context.as_ref(), out)
+            .map_err(|_| Error::General("exporting too much".to_string()))
     }
 }
 
-pub(crate) fn hkdf_expand<T, L>(secret: &hkdf::Prk, key_type: L, label: &[u8], context: &[u8]) -> T
-where
-    T: for<'a> From<hkdf::Okm<'a, L>>,
-    L: hkdf::KeyType,
-{
-    hkdf_expand_info(secret, key_type, label, context, |okm| okm.into())
+/// [HKDF-Expand-Label] where the output is a secret of the hash's output length.
+///
+/// [HKDF-Expand-Label]: <https://www.rfc-editor.org/rfc/rfc8446#section-7.1>
+pub(crate) fn hkdf_expand_label_prk(
+    hkdf: &dyn Hkdf,
+    secret: &Prk,
+    label: &[u8],
+    context: &[u8],
+) -> Prk {
+    let mut buf = [0u8; Prk::MAX_LEN];
+    let out = &mut buf[..hkdf.hash_output_len()];
+    hkdf.expand_label(secret.as_ref(), label, context, out)
+        .unwrap();
+    Prk::new(out)
 }
 
-fn hkdf_expand_info<F, T, L>(
-    secret: &hkdf::Prk,
-    key_type: L,
+/// [HKDF-Expand-Label] where the output is a key for `aead_algorithm`.
+///
+/// [HKDF-Expand-Label]: <https://www.rfc-editor.org/rfc/rfc8446#section-7.1>
+pub(crate) fn hkdf_expand_label_aead_key(
+    hkdf: &dyn Hkdf,
+    aead_algorithm: &'static aead::Algorithm,
+    secret: &Prk,
     label: &[u8],
-    context: &[u8],
-    f: F,
-) -> T
-where
-    F: for<'b> FnOnce(hkdf::Okm<'b, L>) -> T,
-    L: hkdf::KeyType,
-{
-    const LABEL_PREFIX: &[u8] = b"tls13 ";
-
-    let output_len = u16::to_be_bytes(key_type.len() as u16);
-    let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
-    let context_len = u8::to_be_bytes(context.len() as u8);
-
-    let info = &[
-        &output_len[..],
-        &label_len[..],
-        LABEL_PREFIX,
-        label,
-        &context_len[..],
-        context,
-    ];
-    let okm = secret.expand(info, key_type).unwrap();
-
-    f(okm)
+) -> aead::UnboundKey {
+    let key = cipher::expand_key(hkdf, secret.as_ref(), label, aead_algorithm.key_len());
+    aead::UnboundKey::new(aead_algorithm, key.as_ref()).unwrap()
 }
 
-pub(crate) struct PayloadU8Len(pub(crate) usize);
-impl hkdf::KeyType for PayloadU8Len {
-    fn len(&self) -> usize {
-        self.0
-    }
+pub(crate) fn derive_traffic_key(suite: &Tls13CipherSuite, secret: &Prk) -> aead::UnboundKey {
+    hkdf_expand_label_aead_key(
+        suite.hkdf_provider,
+        suite.common.aead_algorithm,
+        secret,
+        b"key",
+    )
 }
 
-impl From<hkdf::Okm<'_, PayloadU8Len>> for PayloadU8 {
-    fn from(okm: hkdf::Okm<PayloadU8Len>) -> Self {
-        let mut r = vec![0u8; okm.len().0];
-        okm.fill(&mut r[..]).unwrap();
-        Self::new(r)
+/// The individual steps of the TLS1.3 key schedule (RFC8446 section 7.1),
+/// operating on plain byte strings.
+///
+/// These exist so the key schedule can be checked against fixed test vectors
+/// (such as those in RFC8448) independently of a handshake.  They are not part
+/// of the stable API.
+#[cfg(any(test, feature = "key_schedule_steps"))]
+#[cfg_attr(not(feature = "key_schedule_steps"), allow(dead_code))]
+pub mod steps {
+    use super::SecretKind;
+    use crate::crypto::cipher;
+    use crate::Tls13CipherSuite;
+
+    /// Compute the Early Secret from `psk`, or from a zero-filled secret if
+    /// there is no PSK.
+    pub fn early_secret(suite: &Tls13CipherSuite, psk: Option<&[u8]>) -> Vec<u8> {
+        let zeroes = zeroes(suite);
+        extract(suite, &zeroes, psk.unwrap_or(&zeroes))
     }
-}
 
-pub(crate) fn derive_traffic_key(
-    secret: &hkdf::Prk,
-    aead_algorithm: &'static aead::Algorithm,
-) -> aead::UnboundKey {
-    hkdf_expand(secret, aead_algorithm, b"key", &[])
-}
+    /// Compute the Handshake Secret from `early_secret` and the (EC)DHE
+    /// `shared_secret`, or a zero-filled secret for `psk_ke`.
+    pub fn handshake_secret(
+        suite: &Tls13CipherSuite,
+        early_secret: &[u8],
+        shared_secret: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let salt = derived(suite, early_secret);
+        extract(suite, &salt, shared_secret.unwrap_or(&zeroes(suite)))
+    }
+
+    /// Compute the Master Secret from `handshake_secret`.
+    pub fn master_secret(suite: &Tls13CipherSuite, handshake_secret: &[u8]) -> Vec<u8> {
+        let salt = derived(suite, handshake_secret);
+        extract(suite, &salt, &zeroes(suite))
+    }
+
+    /// Compute `binder_key` for an external PSK from `early_secret`.
+    pub fn external_psk_binder_key(suite: &Tls13CipherSuite, early_secret: &[u8]) -> Vec<u8> {
+        let empty_hash = empty_hash(suite);
+        derive_secret(
+            suite,
+            early_secret,
+            SecretKind::ExternalPskBinderKey,
+            &empty_hash,
+        )
+    }
+
+    /// Compute `binder_key` for a resumption PSK from `early_secret`.
+    pub fn resumption_psk_binder_key(suite: &Tls13CipherSuite, early_secret: &[u8]) -> Vec<u8> {
+        let empty_hash = empty_hash(suite);
+        derive_secret(
+            suite,
+            early_secret,
+            SecretKind::ResumptionPskBinderKey,
+            &empty_hash,
+        )
+    }
+
+    /// Compute `client_early_traffic_secret` from `early_secret` and the
+    /// hash of the ClientHello.
+    pub fn client_early_traffic_secret(
+        suite: &Tls13CipherSuite,
+        early_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            early_secret,
+            SecretKind::ClientEarlyTrafficSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `client_handshake_traffic_secret` from `handshake_secret` and
+    /// the hash of ClientHello...ServerHello.
+    pub fn client_handshake_traffic_secret(
+        suite: &Tls13CipherSuite,
+        handshake_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            handshake_secret,
+            SecretKind::ClientHandshakeTrafficSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `server_handshake_traffic_secret` from `handshake_secret` and
+    /// the hash of ClientHello...ServerHello.
+    pub fn server_handshake_traffic_secret(
+        suite: &Tls13CipherSuite,
+        handshake_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            handshake_secret,
+            SecretKind::ServerHandshakeTrafficSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `client_application_traffic_secret_0` from `master_secret` and
+    /// the hash of ClientHello...server Finished.
+    pub fn client_application_traffic_secret(
+        suite: &Tls13CipherSuite,
+        master_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            master_secret,
+            SecretKind::ClientApplicationTrafficSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `server_application_traffic_secret_0` from `master_secret` and
+    /// the hash of ClientHello...server Finished.
+    pub fn server_application_traffic_secret(
+        suite: &Tls13CipherSuite,
+        master_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            master_secret,
+            SecretKind::ServerApplicationTrafficSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `exporter_master_secret` from `master_secret` and the hash of
+    /// ClientHello...server Finished.
+    pub fn exporter_master_secret(
+        suite: &Tls13CipherSuite,
+        master_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            master_secret,
+            SecretKind::ExporterMasterSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute `resumption_master_secret` from `master_secret` and the hash
+    /// of ClientHello...client Finished.
+    pub fn resumption_master_secret(
+        suite: &Tls13CipherSuite,
+        master_secret: &[u8],
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        derive_secret(
+            suite,
+            master_secret,
+            SecretKind::ResumptionMasterSecret,
+            hs_hash,
+        )
+    }
+
+    /// Compute the record protection key for `traffic_secret`.
+    pub fn traffic_key(suite: &Tls13CipherSuite, traffic_secret: &[u8]) -> Vec<u8> {
+        let key_len = suite.common.aead_algorithm.key_len();
+        cipher::derive_traffic_key(suite.hkdf_provider, traffic_secret, key_len)
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Compute the record protection IV for `traffic_secret`.
+    pub fn traffic_iv(suite: &Tls13CipherSuite, traffic_secret: &[u8]) -> Vec<u8> {
+        cipher::derive_traffic_iv(suite.hkdf_provider, traffic_secret)
+            .as_ref()
+            .to_vec()
+    }
+
+    fn derive_secret(
+        suite: &Tls13CipherSuite,
+        secret: &[u8],
+        kind: SecretKind,
+        hs_hash: &[u8],
+    ) -> Vec<u8> {
+        let len = suite.hkdf_provider.hash_output_len();
+        expand_label(suite, secret, kind.to_bytes(), hs_hash, len)
+    }
+
+    fn derived(suite: &Tls13CipherSuite, secret: &[u8]) -> Vec<u8> {
+        let empty_hash = empty_hash(suite);
+        derive_secret(suite, secret, SecretKind::DerivedSecret, &empty_hash)
+    }
+
+    fn expand_label(
+        suite: &Tls13CipherSuite,
+        secret: &[u8],
+        label: &[u8],
+        context: &[u8],
+        len: usize,
+    ) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        suite
+            .hkdf_provider
+            .expand_label(secret, label, context, &mut out)
+            .unwrap();
+        out
+    }
+
+    fn extract(suite: &Tls13CipherSuite, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+        suite
+            .hkdf_provider
+            .extract(salt, ikm)
+            .as_ref()
+            .to_vec()
+    }
 
-pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
-    hkdf_expand(secret, IvLen, b"iv", &[])
+    fn empty_hash(suite: &Tls13CipherSuite) -> Vec<u8> {
+        suite
+            .common
+            .hash_provider
+            .hash(&[])
+            .as_ref()
+            .to_vec()
+    }
+
+    fn zeroes(suite: &Tls13CipherSuite) -> Vec<u8> {
+        vec![0u8; suite.hkdf_provider.hash_output_len()]
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
-    use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
+    use super::{hkdf_expand_label_aead_key, steps, KeySchedule, SecretKind};
+    use crate::crypto::cipher::derive_traffic_iv;
+    use crate::tls13::{
+        TLS13_AES_128_GCM_SHA256_INTERNAL, TLS13_CHACHA20_POLY1305_SHA256_INTERNAL,
+    };
     use crate::KeyLog;
     use ring::aead;
 
@@ -957,6 +1164,136 @@ mod test {
             &server_ats_key,
             &server_ats_iv,
         );
+
+        // The individual steps must agree with the key schedule.
+        let suite = TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
+        let early = steps::early_secret(suite, None);
+        let handshake = steps::handshake_secret(suite, &early, Some(&ecdhe_secret));
+        assert_eq!(
+            steps::client_handshake_traffic_secret(suite, &handshake, &hs_start_hash),
+            client_hts
+        );
+        assert_eq!(
+            steps::server_handshake_traffic_secret(suite, &handshake, &hs_start_hash),
+            server_hts
+        );
+
+        let master = steps::master_secret(suite, &handshake);
+        assert_eq!(
+            steps::client_application_traffic_secret(suite, &master, &hs_full_hash),
+            client_ats
+        );
+        assert_eq!(
+            steps::server_application_traffic_secret(suite, &master, &hs_full_hash),
+            server_ats
+        );
+    }
+
+    #[test]
+    fn test_rfc8448_simple_1rtt() {
+        /* These test vectors are from RFC8448 section 3, "Simple 1-RTT Handshake". */
+        let ecdhe = [
+            0x8b, 0xd4, 0x05, 0x4f, 0xb5, 0x5b, 0x9d, 0x63, 0xfd, 0xfb, 0xac, 0xf9, 0xf0, 0x4b,
+            0x9f, 0x0d, 0x35, 0xe6, 0xd6, 0x3f, 0x53, 0x75, 0x63, 0xef, 0xd4, 0x62, 0x72, 0x90,
+            0x0f, 0x89, 0x49, 0x2d,
+        ];
+
+        let hs_start_hash = [
+            0x86, 0x0c, 0x06, 0xed, 0xc0, 0x78, 0x58, 0xee, 0x8e, 0x78, 0xf0, 0xe7, 0x42, 0x8c,
+            0x58, 0xed, 0xd6, 0xb4, 0x3f, 0x2c, 0xa3, 0xe6, 0xe9, 0x5f, 0x02, 0xed, 0x06, 0x3c,
+            0xf0, 0xe1, 0xca, 0xd8,
+        ];
+
+        let early_secret = [
+            0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93, 0x68,
+            0x0c, 0xe2, 0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2, 0x2e, 0x10,
+            0xf1, 0x70, 0xf9, 0x2a,
+        ];
+
+        let handshake_secret = [
+            0x1d, 0xc8, 0x26, 0xe9, 0x36, 0x06, 0xaa, 0x6f, 0xdc, 0x0a, 0xad, 0xc1, 0x2f, 0x74,
+            0x1b, 0x01, 0x04, 0x6a, 0xa6, 0xb9, 0x9f, 0x69, 0x1e, 0xd2, 0x21, 0xa9, 0xf0, 0xca,
+            0x04, 0x3f, 0xbe, 0xac,
+        ];
+
+        let client_hts = [
+            0xb3, 0xed, 0xdb, 0x12, 0x6e, 0x06, 0x7f, 0x35, 0xa7, 0x80, 0xb3, 0xab, 0xf4, 0x5e,
+            0x2d, 0x8f, 0x3b, 0x1a, 0x95, 0x07, 0x38, 0xf5, 0x2e, 0x96, 0x00, 0x74, 0x6a, 0x0e,
+            0x27, 0xa5, 0x5a, 0x21,
+        ];
+
+        let server_hts = [
+            0xb6, 0x7b, 0x7d, 0x69, 0x0c, 0xc1, 0x6c, 0x4e, 0x75, 0xe5, 0x42, 0x13, 0xcb, 0x2d,
+            0x37, 0xb4, 0xe9, 0xc9, 0x12, 0xbc, 0xde, 0xd9, 0x10, 0x5d, 0x42, 0xbe, 0xfd, 0x59,
+            0xd3, 0x91, 0xad, 0x38,
+        ];
+
+        let master_secret = [
+            0x18, 0xdf, 0x06, 0x84, 0x3d, 0x13, 0xa0, 0x8b, 0xf2, 0xa4, 0x49, 0x84, 0x4c, 0x5f,
+            0x8a, 0x47, 0x80, 0x01, 0xbc, 0x4d, 0x4c, 0x62, 0x79, 0x84, 0xd5, 0xa4, 0x1d, 0xa8,
+            0xd0, 0x40, 0x29, 0x19,
+        ];
+
+        let server_hts_key = [
+            0x3f, 0xce, 0x51, 0x60, 0x09, 0xc2, 0x17, 0x27, 0xd0, 0xf2, 0xe4, 0xe8, 0x6e, 0xe4,
+            0x03, 0xbc,
+        ];
+
+        let server_hts_iv = [
+            0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x30,
+        ];
+
+        let client_hts_key = [
+            0xdb, 0xfa, 0xa6, 0x93, 0xd1, 0x76, 0x2c, 0x5b, 0x66, 0x6a, 0xf5, 0xd9, 0x50, 0x25,
+            0x8d, 0x01,
+        ];
+
+        let client_hts_iv = [
+            0x5b, 0xd3, 0xc7, 0x1b, 0x83, 0x6e, 0x0b, 0x76, 0xbb, 0x73, 0x26, 0x5f,
+        ];
+
+        let suite = TLS13_AES_128_GCM_SHA256_INTERNAL;
+
+        let early = steps::early_secret(suite, None);
+        assert_eq!(early, early_secret);
+
+        let handshake = steps::handshake_secret(suite, &early, Some(&ecdhe));
+        assert_eq!(handshake, handshake_secret);
+
+        let chts = steps::client_handshake_traffic_secret(suite, &handshake, &hs_start_hash);
+        assert_eq!(chts, client_hts);
+        assert_eq!(steps::traffic_key(suite, &chts), client_hts_key);
+        assert_eq!(steps::traffic_iv(suite, &chts), client_hts_iv);
+
+        let shts = steps::server_handshake_traffic_secret(suite, &handshake, &hs_start_hash);
+        assert_eq!(shts, server_hts);
+        assert_eq!(steps::traffic_key(suite, &shts), server_hts_key);
+        assert_eq!(steps::traffic_iv(suite, &shts), server_hts_iv);
+
+        let master = steps::master_secret(suite, &handshake);
+        assert_eq!(master, master_secret);
+
+        // The same values must come out of the real key schedule.
+        let mut ks = KeySchedule::new_with_empty_secret(suite);
+        ks.input_secret(&ecdhe);
+
+        assert_traffic_secret(
+            &ks,
+            SecretKind::ClientHandshakeTrafficSecret,
+            &hs_start_hash,
+            &client_hts,
+            &client_hts_key,
+            &client_hts_iv,
+        );
+
+        assert_traffic_secret(
+            &ks,
+            SecretKind::ServerHandshakeTrafficSecret,
+            &hs_start_hash,
+            &server_hts,
+            &server_hts_key,
+            &server_hts_iv,
+        );
     }
 
     fn assert_traffic_secret(
@@ -978,15 +1315,15 @@ mod test {
 
         // Since we can't test key equality, we test the output of sealing with the key instead.
         let aead_alg = &aead::AES_128_GCM;
-        let key = derive_traffic_key(&traffic_secret, aead_alg);
+        let key = hkdf_expand_label_aead_key(ks.hkdf(), aead_alg, &traffic_secret, b"key");
         let seal_output = seal_zeroes(key);
         let expected_key = aead::UnboundKey::new(aead_alg, expected_key).unwrap();
         let expected_seal_output = seal_zeroes(expected_key);
         assert_eq!(seal_output, expected_seal_output);
         assert!(seal_output.len() >= 48); // Sanity check.
 
-        let iv = derive_traffic_iv(&traffic_secret);
-        assert_eq!(iv.value(), expected_iv);
+        let iv = derive_traffic_iv(ks.hkdf(), traffic_secret.as_ref());
+        assert_eq!(iv.as_ref(), expected_iv);
     }
 
     fn seal_zeroes(key: aead::UnboundKey) -> Vec<u8> {
@@ -1006,7 +1343,8 @@ mod test {
 mod benchmarks {
     #[bench]
     fn bench_sha256(b: &mut test::Bencher) {
-        use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
+        use super::{hkdf_expand_label_aead_key, KeySchedule, SecretKind};
+        use crate::crypto::cipher::derive_traffic_iv;
         use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
         use crate::KeyLog;
         use ring::aead;
@@ -1021,8 +1359,13 @@ mod benchmarks {
             let aead_alg = &aead::CHACHA20_POLY1305;
             let hash = [0u8; 32];
             let traffic_secret = ks.derive_logged_secret(kind, &hash, &Log, &[0u8; 32]);
-            test::black_box(derive_traffic_key(&traffic_secret, aead_alg));
-            test::black_box(derive_traffic_iv(&traffic_secret));
+            test::black_box(hkdf_expand_label_aead_key(
+                ks.hkdf(),
+                aead_alg,
+                &traffic_secret,
+                b"key",
+            ));
+            test::black_box(derive_traffic_iv(ks.hkdf(), traffic_secret.as_ref()));
         }
 
         b.iter(|| {
@@ -1,4 +1,7 @@
-use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::cipher::{make_nonce, MessageDecrypter, MessageEncrypter};
+use crate::crypto;
+use crate::crypto::cipher::Iv;
+use crate::crypto::hash::HashAlgorithm;
 use crate::enums::ContentType;
 use crate::enums::{CipherSuite, ProtocolVersion};
 use crate::error::{Error, PeerMisbehaved};
@@ -11,6 +14,7 @@ use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 use ring::aead;
 
 use std::fmt;
+use std::sync::Arc;
 
 pub(crate) mod key_schedule;
 
@@ -22,12 +26,11 @@ pub(crate) static TLS13_CHACHA20_POLY1305_SHA256_INTERNAL: &Tls13CipherSuite = &
     common: CipherSuiteCommon {
         suite: CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
         bulk: BulkAlgorithm::Chacha20Poly1305,
+        hash_provider: &crypto::ring::hash::SHA256,
         aead_algorithm: &ring::aead::CHACHA20_POLY1305,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
+    hkdf_provider: &crypto::ring::hkdf::HKDF_SHA256,
     confidentiality_limit: u64::MAX,
-    #[cfg(feature = "quic")]
     integrity_limit: 1 << 36,
 };
 
@@ -37,12 +40,11 @@ pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS13_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
+            hash_provider: &crypto::ring::hash::SHA384,
             aead_algorithm: &ring::aead::AES_256_GCM,
         },
-        hkdf_algorithm: ring::hkdf::HKDF_SHA384,
-        #[cfg(feature = "quic")]
+        hkdf_provider: &crypto::ring::hkdf::HKDF_SHA384,
         confidentiality_limit: 1 << 23,
-        #[cfg(feature = "quic")]
         integrity_limit: 1 << 52,
     });
 
@@ -54,12 +56,11 @@ pub(crate) static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13C
     common: CipherSuiteCommon {
         suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
         bulk: BulkAlgorithm::Aes128Gcm,
+        hash_provider: &crypto::ring::hash::SHA256,
         aead_algorithm: &ring::aead::AES_128_GCM,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
+    hkdf_provider: &crypto::ring::hkdf::HKDF_SHA256,
     confidentiality_limit: 1 << 23,
-    #[cfg(feature = "quic")]
     integrity_limit: 1 << 52,
 };
 
@@ -67,19 +68,45 @@ pub(crate) static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13C
 pub struct Tls13CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
-    pub(crate) hkdf_algorithm: ring::hkdf::Algorithm,
-    #[cfg(feature = "quic")]
+    pub(crate) hkdf_provider: &'static dyn crypto::hkdf::Hkdf,
+    /// How many records may be encrypted under one key before it must
+    /// be replaced with a KeyUpdate (RFC8446 section 5.5).
     pub(crate) confidentiality_limit: u64,
-    #[cfg(feature = "quic")]
+    /// How many records may fail to decrypt, where that is tolerated at
+    /// all, before the connection must be closed.
     pub(crate) integrity_limit: u64,
 }
 
 impl Tls13CipherSuite {
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm()
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.common.hash_provider.algorithm()
+    }
+
+    /// Which HKDF implementation the key schedule uses with this suite.
+    pub fn hkdf_provider(&self) -> &'static dyn crypto::hkdf::Hkdf {
+        self.hkdf_provider
+    }
+
+    /// Return a copy of this suite which replaces its traffic keys after
+    /// encrypting `limit` records, if that is sooner than it otherwise would.
+    ///
+    /// This is for exercising key updates in tests, and needs the `testing`
+    /// feature.
+    #[cfg(feature = "testing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+    pub fn with_confidentiality_limit(&self, limit: u64) -> Self {
+        Self {
+            common: CipherSuiteCommon {
+                suite: self.common.suite,
+                bulk: self.common.bulk,
+                hash_provider: self.common.hash_provider,
+                aead_algorithm: self.common.aead_algorithm,
+            },
+            hkdf_provider: self.hkdf_provider,
+            confidentiality_limit: self.confidentiality_limit.min(limit),
+            integrity_limit: self.integrity_limit,
+        }
     }
 
     /// Can a session using suite self resume from suite prev?
@@ -109,9 +136,54 @@ impl fmt::Debug for Tls13CipherSuite {
     }
 }
 
+/// How to pad TLS1.3 records, to obscure the length of their contents
+/// from an observer of the encrypted traffic.
+///
+/// Padding is added inside the encryption, so the peer discards it.  It
+/// is capped so that records never exceed the maximum allowed size.
+/// TLS1.2 records are never padded.
+#[derive(Clone)]
+pub enum RecordPadding {
+    /// Do not pad records.
+    None,
+
+    /// Pad the contents of each record to a multiple of this many bytes.
+    /// Zero means no padding.
+    BlockSize(usize),
+
+    /// Call this function with the length of each record's contents.  It
+    /// returns how many bytes of padding to add.
+    Custom(Arc<dyn Fn(usize) -> usize + Send + Sync>),
+}
+
+impl RecordPadding {
+    fn padding_len(&self, content_len: usize) -> usize {
+        let padding = match self {
+            Self::None | Self::BlockSize(0) => 0,
+            Self::BlockSize(block) => (block - content_len % block) % block,
+            Self::Custom(policy) => policy(content_len),
+        };
+        padding.min(MAX_FRAGMENT_LEN.saturating_sub(content_len))
+    }
+}
+
+impl fmt::Debug for RecordPadding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::BlockSize(block) => f
+                .debug_tuple("BlockSize")
+                .field(block)
+                .finish(),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
 struct Tls13MessageEncrypter {
     enc_key: aead::LessSafeKey,
     iv: Iv,
+    padding: RecordPadding,
 }
 
 struct Tls13MessageDecrypter {
@@ -144,10 +216,14 @@ const TLS13_AAD_SIZE: usize = 1 + 2 + 2;
 
 impl MessageEncrypter for Tls13MessageEncrypter {
     fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
-        let total_len = msg.payload.len() + 1 + self.enc_key.algorithm().tag_len();
+        let padding_len = self
+            .padding
+            .padding_len(msg.payload.len());
+        let total_len = msg.payload.len() + 1 + padding_len + self.enc_key.algorithm().tag_len();
         let mut payload = Vec::with_capacity(total_len);
         payload.extend_from_slice(msg.payload);
         msg.typ.encode(&mut payload);
+        payload.resize(payload.len() + padding_len, 0);
 
         let nonce = make_nonce(&self.iv, seq);
         let aad = make_tls13_aad(total_len);
@@ -0,0 +1,66 @@
+use crate::common_state::CommonState;
+use crate::enums::AlertDescription;
+use crate::error::{Error, PeerIncompatible};
+use crate::msgs::enums::ExtensionType;
+use crate::msgs::handshake::UnknownExtension;
+
+use std::sync::Arc;
+
+/// This trait allows inspecting the extensions in a peer's hello which
+/// rustls does not understand, for example to log them.
+///
+/// rustls otherwise ignores such extensions, as the protocol requires.
+///
+/// For servers, these are the extensions of the ClientHello (each
+/// ClientHello, if a HelloRetryRequest was needed).  For clients, these
+/// are the extensions of the ServerHello and, in TLS1.3, the
+/// EncryptedExtensions.
+///
+/// Configure one using [`ClientConfig::with_unknown_extension_handler`] or
+/// [`ServerConfig::with_unknown_extension_handler`].  Callbacks are made
+/// synchronously from within [`Connection::process_new_packets()`], so
+/// should be cheap.
+///
+/// [`ClientConfig::with_unknown_extension_handler`]: crate::ClientConfig::with_unknown_extension_handler
+/// [`ServerConfig::with_unknown_extension_handler`]: crate::ServerConfig::with_unknown_extension_handler
+/// [`Connection::process_new_packets()`]: crate::Connection::process_new_packets
+pub trait UnknownExtensionHandler: Send + Sync {
+    /// Called for each unrecognised extension, in the order the peer sent
+    /// them.  `payload` is the extension's body, without its type and
+    /// length.
+    fn handle(&self, typ: ExtensionType, payload: &[u8]) -> UnknownExtensionAction;
+}
+
+/// What to do about an unrecognised extension, as returned by
+/// [`UnknownExtensionHandler::handle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownExtensionAction {
+    /// Carry on with the handshake, ignoring the extension.
+    Ignore,
+    /// Abort the handshake with an `unsupported_extension` alert.
+    Abort,
+}
+
+/// Give each of `extensions` to `handler`, aborting the handshake if it
+/// asks us to.
+pub(crate) fn handle_unknown_extensions<'a>(
+    handler: Option<&Arc<dyn UnknownExtensionHandler>>,
+    common: &mut CommonState,
+    extensions: impl Iterator<Item = &'a UnknownExtension>,
+) -> Result<(), Error> {
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return Ok(()),
+    };
+
+    for ext in extensions {
+        if handler.handle(ext.typ, &ext.payload.0) == UnknownExtensionAction::Abort {
+            return Err(common.send_fatal_alert(
+                AlertDescription::UnsupportedExtension,
+                PeerIncompatible::UnknownExtensionRejected(ext.typ),
+            ));
+        }
+    }
+
+    Ok(())
+}
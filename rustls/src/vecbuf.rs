@@ -1,15 +1,25 @@
+use crate::buffer_pool::BufferPool;
+
 use std::cmp;
 use std::collections::VecDeque;
 use std::io;
 use std::io::Read;
+use std::mem::size_of;
+use std::sync::Arc;
 
 /// This is a byte buffer that is built from a vector
 /// of byte vectors.  This avoids extra copies when
 /// appending a new byte vector, at the expense of
 /// more complexity when reading out.
+///
+/// Nothing is allocated until the first append.
 pub(crate) struct ChunkVecBuffer {
     chunks: VecDeque<Vec<u8>>,
     limit: Option<usize>,
+    /// Where chunks go once they are completely consumed.
+    pool: Option<Arc<BufferPool>>,
+    /// How many bytes of memory to keep once emptied.
+    retained_capacity: Option<usize>,
 }
 
 impl ChunkVecBuffer {
@@ -17,9 +27,44 @@ impl ChunkVecBuffer {
         Self {
             chunks: VecDeque::new(),
             limit,
+            pool: None,
+            retained_capacity: None,
         }
     }
 
+    /// Sets how many bytes of memory this object may keep
+    /// allocated once everything in it has been read out.
+    ///
+    /// A [`None`] limit keeps whatever was needed at the
+    /// busiest time.
+    pub(crate) fn set_retained_capacity(&mut self, retained: Option<usize>) {
+        self.retained_capacity = retained;
+        self.shrink_if_empty();
+    }
+
+    /// How many bytes of memory this object is holding.
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.chunks.capacity() * size_of::<Vec<u8>>()
+            + self
+                .chunks
+                .iter()
+                .map(Vec::capacity)
+                .sum::<usize>()
+    }
+
+    fn shrink_if_empty(&mut self) {
+        if let (true, Some(retained)) = (self.chunks.is_empty(), self.retained_capacity) {
+            self.chunks
+                .shrink_to(retained / size_of::<Vec<u8>>());
+        }
+    }
+
+    /// Return chunks to `pool` once they have been read out.
+    pub(crate) fn set_pool(&mut self, pool: Option<Arc<BufferPool>>) {
+        self.pool = pool;
+    }
+
     /// Sets the upper limit on how many bytes this
     /// object can store.
     ///
@@ -82,10 +127,20 @@ impl ChunkVecBuffer {
         len
     }
 
+    /// Put `bytes` back at the front of this object, to be read
+    /// before anything already here.
+    pub(crate) fn prepend(&mut self, bytes: Vec<u8>) {
+        if !bytes.is_empty() {
+            self.chunks.push_front(bytes);
+        }
+    }
+
     /// Take one of the chunks from this object.  This
     /// function panics if the object `is_empty`.
     pub(crate) fn pop(&mut self) -> Option<Vec<u8>> {
-        self.chunks.pop_front()
+        let chunk = self.chunks.pop_front();
+        self.shrink_if_empty();
+        chunk
     }
 
     /// Read data out of this object, writing it into `buf`
@@ -121,13 +176,21 @@ impl ChunkVecBuffer {
     fn consume(&mut self, mut used: usize) {
         while let Some(mut buf) = self.chunks.pop_front() {
             if used < buf.len() {
-                self.chunks
-                    .push_front(buf.split_off(used));
+                // Move the rest to the front, rather than `split_off()` it
+                // into a new allocation, so that this chunk's buffer is
+                // the one that goes back to the pool.
+                buf.drain(..used);
+                self.chunks.push_front(buf);
                 break;
             } else {
                 used -= buf.len();
+                if let Some(pool) = &self.pool {
+                    pool.put(buf);
+                }
             }
         }
+
+        self.shrink_if_empty();
     }
 
     /// Read data out of this object, passing it `wr`
@@ -164,6 +227,49 @@ mod test {
         assert_eq!(buf.to_vec(), b"helloworldhe".to_vec());
     }
 
+    #[test]
+    fn retained_capacity_is_bounded_once_emptied() {
+        let mut cvb = ChunkVecBuffer::new(None);
+        assert_eq!(cvb.capacity(), 0);
+        cvb.set_retained_capacity(Some(256));
+
+        for _ in 0..1000 {
+            cvb.append(vec![0u8; 16]);
+        }
+        assert!(cvb.capacity() > 1000 * 16);
+
+        let mut buf = [0u8; 1000];
+        while cvb.read(&mut buf).unwrap() > 0 {}
+        assert!(cvb.is_empty());
+        assert!(cvb.capacity() <= 256);
+
+        cvb.set_retained_capacity(None);
+        for _ in 0..1000 {
+            cvb.append(vec![0u8; 16]);
+        }
+        while cvb.read(&mut buf).unwrap() > 0 {}
+        assert!(cvb.capacity() > 256);
+    }
+
+    #[test]
+    fn consumed_chunks_return_to_pool() {
+        use crate::buffer_pool::BufferPool;
+        use std::sync::Arc;
+
+        let pool = Arc::new(BufferPool::new(4));
+        let mut cvb = ChunkVecBuffer::new(None);
+        cvb.set_pool(Some(Arc::clone(&pool)));
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        let mut buf = [0u8; 7];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 7);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(cvb.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"rld");
+        assert_eq!(pool.len(), 2);
+    }
+
     #[cfg(read_buf)]
     #[test]
     fn read_buf() {
@@ -2,6 +2,7 @@ use std::fmt;
 
 use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 use crate::client::ServerName;
+use crate::crypto::hash;
 use crate::enums::SignatureScheme;
 use crate::error::{
     CertRevocationListError, CertificateError, Error, InvalidMessage, PeerMisbehaved,
@@ -12,9 +13,10 @@ use crate::log::trace;
 use crate::msgs::base::PayloadU16;
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::handshake::DistinguishedName;
+use crate::ocsp;
+use crate::x509::{self, PublicKeyKind};
 
-use ring::digest::Digest;
-
+use std::borrow::Cow;
 use std::sync::Arc;
 use std::time::SystemTime;
 
@@ -120,6 +122,42 @@ pub trait ServerCertVerifier: Send + Sync {
         now: SystemTime,
     ) -> Result<ServerCertVerified, Error>;
 
+    /// Check an OCSP response stapled by the server for the intermediate
+    /// certificate `cert`.
+    ///
+    /// This is only called for TLS1.2 handshakes where the server stapled
+    /// responses for its whole chain using the `status_request_v2`
+    /// extension ([RFC6961]), once for each intermediate with a non-empty
+    /// response, after [`ServerCertVerifier::verify_server_cert`] succeeded.
+    ///
+    /// The default implementation ignores the response, just like
+    /// `verify_server_cert` does for the end-entity's.  Implementations
+    /// which check these responses must also override
+    /// [`ServerCertVerifier::requests_intermediate_ocsp_responses`].
+    ///
+    /// [RFC6961]: https://datatracker.ietf.org/doc/html/rfc6961
+    fn verify_intermediate_ocsp_response(
+        &self,
+        cert: &Certificate,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let _ = (cert, ocsp_response, now);
+        Ok(())
+    }
+
+    /// Whether to ask TLS1.2 servers for OCSP responses for their
+    /// intermediate certificates, using the `status_request_v2` extension.
+    ///
+    /// The responses are passed to
+    /// [`ServerCertVerifier::verify_intermediate_ocsp_response`], so this
+    /// should only return true if that checks them.
+    ///
+    /// The default implementation returns false.
+    fn requests_intermediate_ocsp_responses(&self) -> bool {
+        false
+    }
+
     /// Verify a signature allegedly by the given server certificate.
     ///
     /// `message` is not hashed, and needs hashing during the verification.
@@ -173,6 +211,31 @@ pub trait ServerCertVerifier: Send + Sync {
         verify_tls13(message, cert, dss)
     }
 
+    /// Verify a TLS1.3 signature allegedly by the key with the DER-encoded
+    /// SubjectPublicKeyInfo `spki`.
+    ///
+    /// This is called instead of `verify_tls13_signature` when the server
+    /// authenticates with a delegated credential ([RFC9345]), which has
+    /// already been checked against the server's certificate, or with a raw
+    /// public key ([RFC7250]) that has been checked against the pinned keys.
+    ///
+    /// As well as checking the signature, implementations must check that
+    /// the key's algorithm in `spki` matches the signature scheme in `dss`.
+    ///
+    /// This trait method has a default implementation that uses *ring* to
+    /// verify the signature.
+    ///
+    /// [RFC9345]: https://datatracker.ietf.org/doc/html/rfc9345
+    /// [RFC7250]: https://datatracker.ietf.org/doc/html/rfc7250
+    fn verify_tls13_signature_with_spki(
+        &self,
+        message: &[u8],
+        spki: &[u8],
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_with_spki(message, spki, dss)
+    }
+
     /// Return the list of SignatureSchemes that this verifier will handle,
     /// in `verify_tls12_signature` and `verify_tls13_signature` calls.
     ///
@@ -291,6 +354,29 @@ pub trait ClientCertVerifier: Send + Sync {
         verify_tls13(message, cert, dss)
     }
 
+    /// Verify a TLS1.3 signature allegedly by the key with the DER-encoded
+    /// SubjectPublicKeyInfo `spki`.
+    ///
+    /// This is called instead of `verify_tls13_signature` when the client
+    /// authenticates with a raw public key ([RFC7250]) that has
+    /// been checked against the pinned keys.
+    ///
+    /// As well as checking the signature, implementations must check that
+    /// the key's algorithm in `spki` matches the signature scheme in `dss`.
+    ///
+    /// This trait method has a default implementation that uses *ring* to
+    /// verify the signature.
+    ///
+    /// [RFC7250]: https://datatracker.ietf.org/doc/html/rfc7250
+    fn verify_tls13_signature_with_spki(
+        &self,
+        message: &[u8],
+        spki: &[u8],
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_with_spki(message, spki, dss)
+    }
+
     /// Return the list of SignatureSchemes that this verifier will handle,
     /// in `verify_tls12_signature` and `verify_tls13_signature` calls.
     ///
@@ -368,11 +454,34 @@ pub fn verify_server_name(cert: &ParsedCertificate, server_name: &ServerName) ->
     Ok(())
 }
 
+/// Verify that `end_entity` is a valid server certificate for `server_name`
+/// at the time `now`, and chains to a trust anchor in `roots`.
+///
+/// This is the certificate chain validation done by [`WebPkiVerifier`],
+/// without a connection: for example, to re-check certificate chains that
+/// were recorded at some earlier time.
+///
+/// `intermediates` contains all certificates other than `end_entity` that
+/// were sent as part of the server's [Certificate] message.
+pub fn verify_chain(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    roots: &RootCertStore,
+    server_name: &ServerName,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let cert = ParsedCertificate::try_from(end_entity)?;
+    verify_server_cert_signed_by_trust_anchor(&cert, roots, intermediates, now)?;
+    verify_server_name(&cert, server_name)
+}
+
 impl ServerCertVerifier for WebPkiVerifier {
     /// Will verify the certificate is valid in the following ways:
     /// - Signed by a  trusted `RootCertStore` CA
     /// - Not Expired
     /// - Valid for DNS entry
+    /// - Accompanied by a valid OCSP response, if the certificate requires
+    ///   one (has the "must-staple" TLS feature)
     fn verify_server_cert(
         &self,
         end_entity: &Certificate,
@@ -381,24 +490,77 @@ impl ServerCertVerifier for WebPkiVerifier {
         ocsp_response: &[u8],
         now: SystemTime,
     ) -> Result<ServerCertVerified, Error> {
-        let cert = ParsedCertificate::try_from(end_entity)?;
-
-        verify_server_cert_signed_by_trust_anchor(&cert, &self.roots, intermediates, now)?;
-
-        if !ocsp_response.is_empty() {
+        check_chain_depth(intermediates, self.max_chain_depth)?;
+        let strong = strong_intermediates(end_entity, intermediates, self.minimum_key_size)?;
+        let fetched;
+        let intermediates = match verify_chain(end_entity, &strong, &self.roots, server_name, now) {
+            Err(Error::InvalidCertificate(CertificateError::UnknownIssuer)) => {
+                match &self.intermediate_fetcher {
+                    Some(fetcher) => {
+                        fetched = self
+                            .verify_with_fetched_intermediates(
+                                fetcher.as_ref(),
+                                end_entity,
+                                &strong,
+                                server_name,
+                                now,
+                            )
+                            .map_err(|err| blame_weak_intermediates(err, &strong))?;
+                        &fetched[..]
+                    }
+                    None => {
+                        return Err(blame_weak_intermediates(
+                            CertificateError::UnknownIssuer.into(),
+                            &strong,
+                        ))
+                    }
+                }
+            }
+            result => {
+                result?;
+                &strong[..]
+            }
+        };
+
+        if x509::requires_ocsp_staple(&end_entity.0) {
+            ocsp::verify_response(ocsp_response, end_entity, intermediates, &self.roots, now)?;
+        } else if !ocsp_response.is_empty() {
             trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        verify_server_name(&cert, server_name)?;
         Ok(ServerCertVerified::assertion())
     }
 }
 
+/// Supplies intermediate certificates which a server left out of its
+/// certificate chain, for example by downloading them from the URLs in
+/// the certificates' Authority Information Access extension.
+///
+/// This is called synchronously during the handshake, so blocks it until
+/// the certificates are found.  Configure one using
+/// [`WebPkiVerifier::with_intermediate_fetcher`].
+#[allow(unreachable_pub)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub trait IntermediateFetcher: Send + Sync {
+    /// Find the certificate(s) which issued `cert`.
+    ///
+    /// `ca_issuers` are the caIssuers URLs from `cert`'s Authority
+    /// Information Access extension, and may be empty.  Return an empty
+    /// list if nothing can be found.
+    ///
+    /// The certificates returned are only used as intermediates, and are
+    /// verified exactly like those sent by the server.
+    fn fetch(&self, cert: &Certificate, ca_issuers: &[&str]) -> Vec<Certificate>;
+}
+
 /// Default `ServerCertVerifier`, see the trait impl for more information.
 #[allow(unreachable_pub)]
 #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
 pub struct WebPkiVerifier {
     roots: RootCertStore,
+    max_chain_depth: usize,
+    minimum_key_size: MinimumKeySize,
+    intermediate_fetcher: Option<Arc<dyn IntermediateFetcher>>,
 }
 
 #[allow(unreachable_pub)]
@@ -407,7 +569,84 @@ impl WebPkiVerifier {
     ///
     /// `roots` is the set of trust anchors to trust for issuing server certs.
     pub fn new(roots: RootCertStore) -> Self {
-        Self { roots }
+        Self {
+            roots,
+            max_chain_depth: DEFAULT_MAX_CHAIN_DEPTH,
+            minimum_key_size: MinimumKeySize::default(),
+            intermediate_fetcher: None,
+        }
+    }
+
+    /// Reject certificate chains with more than `max_chain_depth`
+    /// intermediate certificates, before doing any signature verification.
+    ///
+    /// The default is 6.
+    pub fn with_max_chain_depth(self, max_chain_depth: usize) -> Self {
+        Self {
+            max_chain_depth,
+            ..self
+        }
+    }
+
+    /// Reject certificate chains where the end-entity certificate, or
+    /// any intermediate certificate, has a public key smaller than
+    /// `minimum_key_size`.
+    ///
+    /// The default accepts keys of any size.
+    pub fn with_minimum_key_size(self, minimum_key_size: MinimumKeySize) -> Self {
+        Self {
+            minimum_key_size,
+            ..self
+        }
+    }
+
+    /// When a server's certificate chain does not lead to a trusted root,
+    /// ask `fetcher` for the missing intermediate certificates.
+    ///
+    /// The fetcher is asked for the issuer of the last certificate the
+    /// server sent, then the issuer of that, and so on, until the chain
+    /// verifies or the fetcher finds nothing.  Fetched certificates count
+    /// towards the limit set by [`WebPkiVerifier::with_max_chain_depth`].
+    ///
+    /// The default is to fetch nothing: a server which sends an incomplete
+    /// chain is rejected.  Fetching certificates lets a server make us
+    /// contact arbitrary URLs, so think carefully before enabling this.
+    pub fn with_intermediate_fetcher(self, fetcher: Arc<dyn IntermediateFetcher>) -> Self {
+        Self {
+            intermediate_fetcher: Some(fetcher),
+            ..self
+        }
+    }
+
+    /// Verify the chain, adding intermediates from `fetcher` until it
+    /// leads to a trusted root.  Returns the intermediates used.
+    fn verify_with_fetched_intermediates(
+        &self,
+        fetcher: &dyn IntermediateFetcher,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        now: SystemTime,
+    ) -> Result<Vec<Certificate>, Error> {
+        let mut chain = intermediates.to_vec();
+        loop {
+            let last = chain.last().unwrap_or(end_entity);
+            let fetched = fetcher
+                .fetch(last, &x509::ca_issuers(&last.0))
+                .into_iter()
+                .filter(|cert| key_size_meets(cert, self.minimum_key_size))
+                .collect::<Vec<_>>();
+            if fetched.is_empty() {
+                return Err(CertificateError::UnknownIssuer.into());
+            }
+
+            chain.extend(fetched);
+            check_chain_depth(&chain, self.max_chain_depth)?;
+            match verify_chain(end_entity, &chain, &self.roots, server_name, now) {
+                Err(Error::InvalidCertificate(CertificateError::UnknownIssuer)) => continue,
+                result => return result.map(|_| chain),
+            }
+        }
     }
 
     /// Returns the signature verification methods supported by
@@ -427,6 +666,89 @@ impl WebPkiVerifier {
     }
 }
 
+/// The most intermediate certificates a verifier accepts by default.
+const DEFAULT_MAX_CHAIN_DEPTH: usize = 6;
+
+fn check_chain_depth(intermediates: &[Certificate], max_chain_depth: usize) -> Result<(), Error> {
+    match intermediates.len() > max_chain_depth {
+        true => Err(CertificateError::ChainTooLong.into()),
+        false => Ok(()),
+    }
+}
+
+/// The smallest public keys a verifier accepts in a certificate chain.
+///
+/// Sizes are in bits: the modulus size for RSA keys, and the curve size
+/// for ECDSA and EdDSA keys (256 for P-256 and Ed25519).  The default, zero,
+/// accepts keys of any size.
+///
+/// Once either minimum is non-zero, keys whose size cannot be determined
+/// (for example, on an unknown curve) are rejected.
+///
+/// The end-entity certificate and the intermediates on the verified path
+/// are checked.  Other intermediates the peer sent are ignored, and trust
+/// anchors are not checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MinimumKeySize {
+    /// The smallest RSA key accepted.
+    pub rsa: usize,
+
+    /// The smallest elliptic curve key accepted.
+    pub ec: usize,
+}
+
+fn key_size_meets(cert: &Certificate, minimum: MinimumKeySize) -> bool {
+    if minimum == MinimumKeySize::default() {
+        return true;
+    }
+
+    match x509::public_key_size(&cert.0) {
+        Some((PublicKeyKind::Rsa, bits)) => bits >= minimum.rsa,
+        Some((PublicKeyKind::Ec, bits)) => bits >= minimum.ec,
+        None => false,
+    }
+}
+
+/// Check the key of `end_entity` against `minimum`, and return the
+/// `intermediates` whose keys meet it.
+///
+/// Verifying the chain with only these intermediates means that every
+/// intermediate on the verified path has a strong enough key.
+fn strong_intermediates<'a>(
+    end_entity: &Certificate,
+    intermediates: &'a [Certificate],
+    minimum: MinimumKeySize,
+) -> Result<Cow<'a, [Certificate]>, Error> {
+    if !key_size_meets(end_entity, minimum) {
+        return Err(CertificateError::WeakKey.into());
+    }
+
+    match intermediates
+        .iter()
+        .all(|cert| key_size_meets(cert, minimum))
+    {
+        true => Ok(Cow::Borrowed(intermediates)),
+        false => Ok(Cow::Owned(
+            intermediates
+                .iter()
+                .filter(|cert| key_size_meets(cert, minimum))
+                .cloned()
+                .collect(),
+        )),
+    }
+}
+
+/// If no issuer was found once [`strong_intermediates`] removed some weak
+/// intermediates, report those as the reason.
+fn blame_weak_intermediates(err: Error, strong: &Cow<'_, [Certificate]>) -> Error {
+    match (err, strong) {
+        (Error::InvalidCertificate(CertificateError::UnknownIssuer), Cow::Owned(_)) => {
+            CertificateError::WeakKey.into()
+        }
+        (err, _) => err,
+    }
+}
+
 fn intermediate_chain(intermediates: &[Certificate]) -> Vec<&[u8]> {
     intermediates
         .iter()
@@ -462,6 +784,8 @@ pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
     subjects: Vec<DistinguishedName>,
     crls: Vec<webpki::OwnedCertRevocationList>,
+    max_chain_depth: usize,
+    minimum_key_size: MinimumKeySize,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -477,6 +801,8 @@ impl AllowAnyAuthenticatedClient {
                 .collect(),
             crls: Vec::new(),
             roots,
+            max_chain_depth: DEFAULT_MAX_CHAIN_DEPTH,
+            minimum_key_size: MinimumKeySize::default(),
         }
     }
 
@@ -495,6 +821,29 @@ impl AllowAnyAuthenticatedClient {
         })
     }
 
+    /// Reject certificate chains with more than `max_chain_depth`
+    /// intermediate certificates, before doing any signature verification.
+    ///
+    /// The default is 6.
+    pub fn with_max_chain_depth(self, max_chain_depth: usize) -> Self {
+        Self {
+            max_chain_depth,
+            ..self
+        }
+    }
+
+    /// Reject certificate chains where the end-entity certificate, or
+    /// any intermediate certificate, has a public key smaller than
+    /// `minimum_key_size`.
+    ///
+    /// The default accepts keys of any size.
+    pub fn with_minimum_key_size(self, minimum_key_size: MinimumKeySize) -> Self {
+        Self {
+            minimum_key_size,
+            ..self
+        }
+    }
+
     /// Wrap this verifier in an [`Arc`] and coerce it to `dyn ClientCertVerifier`
     #[inline(always)]
     pub fn boxed(self) -> Arc<dyn ClientCertVerifier> {
@@ -519,8 +868,10 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         intermediates: &[Certificate],
         now: SystemTime,
     ) -> Result<ClientCertVerified, Error> {
+        check_chain_depth(intermediates, self.max_chain_depth)?;
+        let strong = strong_intermediates(end_entity, intermediates, self.minimum_key_size)?;
         let cert = ParsedCertificate::try_from(end_entity)?;
-        let chain = intermediate_chain(intermediates);
+        let chain = intermediate_chain(&strong);
         let trust_roots = trust_roots(&self.roots);
         let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
 
@@ -540,7 +891,9 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
                 crls.as_slice(),
             )
             .map_err(pki_error)
-            .map(|_| ClientCertVerified::assertion())
+            .map_err(|err| blame_weak_intermediates(err, &strong))?;
+
+        Ok(ClientCertVerified::assertion())
     }
 }
 
@@ -575,6 +928,31 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
         })
     }
 
+    /// Reject certificate chains with more than `max_chain_depth`
+    /// intermediate certificates, before doing any signature verification.
+    ///
+    /// The default is 6.
+    pub fn with_max_chain_depth(self, max_chain_depth: usize) -> Self {
+        Self {
+            inner: self
+                .inner
+                .with_max_chain_depth(max_chain_depth),
+        }
+    }
+
+    /// Reject certificate chains where the end-entity certificate, or
+    /// any intermediate certificate, has a public key smaller than
+    /// `minimum_key_size`.
+    ///
+    /// The default accepts keys of any size.
+    pub fn with_minimum_key_size(self, minimum_key_size: MinimumKeySize) -> Self {
+        Self {
+            inner: self
+                .inner
+                .with_minimum_key_size(minimum_key_size),
+        }
+    }
+
     /// Wrap this verifier in an [`Arc`] and coerce it to `dyn ClientCertVerifier`
     #[inline(always)]
     pub fn boxed(self) -> Arc<dyn ClientCertVerifier> {
@@ -785,17 +1163,17 @@ fn convert_alg_tls13(
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, client CertificateVerify\x00")
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, server CertificateVerify\x00")
 }
 
 fn construct_tls13_verify_message(
-    handshake_hash: &Digest,
+    handshake_hash: &hash::Output,
     context_string_with_0: &[u8],
 ) -> Vec<u8> {
     let mut msg = Vec::new();
@@ -819,9 +1197,409 @@ fn verify_tls13(
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
+/// The signature schemes supported by [`verify_tls13_with_spki`].
+pub(crate) const SPKI_TLS13_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// Verify a TLS1.3 signature made by the key with the DER-encoded
+/// SubjectPublicKeyInfo `spki`, rather than by a certificate's key.
+/// The key's algorithm must match the signature scheme.
+///
+/// This is the default implementation of
+/// [`ServerCertVerifier::verify_tls13_signature_with_spki`] and
+/// [`ClientCertVerifier::verify_tls13_signature_with_spki`].
+pub(crate) fn verify_tls13_with_spki(
+    msg: &[u8],
+    spki: &[u8],
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, Error> {
+    use crate::enums::SignatureScheme::*;
+    use ring::signature;
+
+    let alg: &'static dyn signature::VerificationAlgorithm = match dss.scheme {
+        ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        ED25519 => &signature::ED25519,
+        RSA_PSS_SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+        RSA_PSS_SHA384 => &signature::RSA_PSS_2048_8192_SHA384,
+        RSA_PSS_SHA512 => &signature::RSA_PSS_2048_8192_SHA512,
+        _ => return Err(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme.into()),
+    };
+
+    let (oid, params, public_key) =
+        x509::spki_parts(spki).ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    if !x509::key_algorithm_matches_scheme(oid, params, dss.scheme) {
+        return Err(Error::InvalidCertificate(CertificateError::BadSignature));
+    }
+
+    signature::UnparsedPublicKey::new(alg, public_key)
+        .verify(msg, dss.signature())
+        .map_err(|_| Error::InvalidCertificate(CertificateError::BadSignature))
+        .map(|_| HandshakeSignatureValid::assertion())
+}
+
+/// Check that `spki`, the raw public key (RFC 7250) a peer sent instead
+/// of a certificate chain, is one of the DER-encoded SubjectPublicKeyInfos
+/// in `pinned`.
+pub(crate) fn verify_raw_public_key(pinned: &[Vec<u8>], spki: &Certificate) -> Result<(), Error> {
+    match pinned.contains(&spki.0) {
+        true => Ok(()),
+        false => Err(CertificateError::UnknownRawPublicKey.into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn long_chain_roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        let ca = rustls_pemfile::certs(&mut io::BufReader::new(
+            &include_bytes!("../../test-ca/ecdsa/ca.cert")[..],
+        ))
+        .unwrap();
+        roots
+            .add(&Certificate(ca[0].clone()))
+            .unwrap();
+        roots
+    }
+
+    /// An end-entity certificate, followed by three intermediates.
+    fn long_chain() -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            &include_bytes!("../../test-ca/ecdsa/end-long-chain.fullchain")[..],
+        ))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect()
+    }
+
+    /// A P-384 client certificate, followed by its P-256 intermediate and
+    /// the P-384 CA.
+    fn client_chain() -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            &include_bytes!("../../test-ca/ecdsa/client.fullchain")[..],
+        ))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect()
+    }
+
+    #[test]
+    fn server_verifier_limits_chain_depth() {
+        let chain = long_chain();
+        let server_name = ServerName::try_from("testserver.com").unwrap();
+        let verify = |verifier: WebPkiVerifier| {
+            verifier.verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &server_name,
+                &[],
+                SystemTime::now(),
+            )
+        };
+
+        assert!(verify(WebPkiVerifier::new(long_chain_roots())).is_ok());
+        assert!(verify(WebPkiVerifier::new(long_chain_roots()).with_max_chain_depth(3)).is_ok());
+        assert_eq!(
+            verify(WebPkiVerifier::new(long_chain_roots()).with_max_chain_depth(2)).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::ChainTooLong)
+        );
+    }
+
+    /// Supplies certificates from a chain, by issuer, counting requests.
+    struct ChainFetcher {
+        chain: Vec<Certificate>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl IntermediateFetcher for ChainFetcher {
+        fn fetch(&self, cert: &Certificate, _ca_issuers: &[&str]) -> Vec<Certificate> {
+            self.requests
+                .fetch_add(1, Ordering::SeqCst);
+            let issuer = x509::CertificateFields::parse(&cert.0)
+                .unwrap()
+                .issuer;
+            self.chain
+                .iter()
+                .filter(|candidate| {
+                    x509::CertificateFields::parse(&candidate.0)
+                        .unwrap()
+                        .subject
+                        == issuer
+                })
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[test]
+    fn server_verifier_fetches_missing_intermediates() {
+        let chain = long_chain();
+        let server_name = ServerName::try_from("testserver.com").unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let fetcher = || {
+            Arc::new(ChainFetcher {
+                chain: chain.clone(),
+                requests: Arc::clone(&requests),
+            })
+        };
+        // The server only sends the first of three intermediates.
+        let verify = |verifier: WebPkiVerifier| {
+            verifier.verify_server_cert(
+                &chain[0],
+                &chain[1..2],
+                &server_name,
+                &[],
+                SystemTime::now(),
+            )
+        };
+
+        assert_eq!(
+            verify(WebPkiVerifier::new(long_chain_roots())).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::UnknownIssuer)
+        );
+
+        assert!(verify(
+            WebPkiVerifier::new(long_chain_roots()).with_intermediate_fetcher(fetcher())
+        )
+        .is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Fetched intermediates count towards the chain depth.
+        assert_eq!(
+            verify(
+                WebPkiVerifier::new(long_chain_roots())
+                    .with_max_chain_depth(2)
+                    .with_intermediate_fetcher(fetcher())
+            )
+            .unwrap_err(),
+            Error::InvalidCertificate(CertificateError::ChainTooLong)
+        );
+    }
+
+    #[test]
+    fn client_verifier_limits_chain_depth() {
+        let chain = long_chain();
+        let verify = |verifier: AllowAnyAuthenticatedClient| {
+            verifier.verify_client_cert(&chain[0], &chain[1..], SystemTime::now())
+        };
+
+        assert!(verify(AllowAnyAuthenticatedClient::new(long_chain_roots())).is_ok());
+        assert_eq!(
+            verify(AllowAnyAuthenticatedClient::new(long_chain_roots()).with_max_chain_depth(2))
+                .unwrap_err(),
+            Error::InvalidCertificate(CertificateError::ChainTooLong)
+        );
+    }
+
+    fn rsa_roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        let ca = rustls_pemfile::certs(&mut io::BufReader::new(
+            &include_bytes!("../../test-ca/rsa/ca.cert")[..],
+        ))
+        .unwrap();
+        roots
+            .add(&Certificate(ca[0].clone()))
+            .unwrap();
+        roots
+    }
+
+    fn rsa_chain(fullchain: &[u8]) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(fullchain))
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect()
+    }
+
+    #[test]
+    fn server_verifier_enforces_minimum_key_size() {
+        let server_name = ServerName::try_from("testserver.com").unwrap();
+        let verify = |chain: &[Certificate], minimum_key_size| {
+            WebPkiVerifier::new(rsa_roots())
+                .with_minimum_key_size(minimum_key_size)
+                .verify_server_cert(&chain[0], &chain[1..], &server_name, &[], SystemTime::now())
+        };
+        let rsa_2048 = MinimumKeySize {
+            rsa: 2048,
+            ..Default::default()
+        };
+
+        // A 1024-bit end-entity key.
+        let weak = rsa_chain(include_bytes!("../../test-ca/rsa/end-weak.fullchain"));
+        assert!(verify(&weak, MinimumKeySize::default()).is_ok());
+        assert_eq!(
+            verify(&weak, rsa_2048).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::WeakKey)
+        );
+
+        // A 2048-bit end-entity key.
+        let chain = rsa_chain(include_bytes!("../../test-ca/rsa/end.fullchain"));
+        assert!(verify(&chain, rsa_2048).is_ok());
+        assert_eq!(
+            verify(
+                &chain,
+                MinimumKeySize {
+                    rsa: 3072,
+                    ..Default::default()
+                }
+            )
+            .unwrap_err(),
+            Error::InvalidCertificate(CertificateError::WeakKey)
+        );
+
+        // ... but a weak certificate sent alongside, and not on the
+        // verified path, is ignored.
+        let mut extra = chain.clone();
+        extra.push(weak[0].clone());
+        assert!(verify(&extra, rsa_2048).is_ok());
+    }
+
+    #[test]
+    fn client_verifier_enforces_minimum_key_size() {
+        let chain = long_chain();
+        let verify = |minimum_key_size| {
+            AllowAnyAuthenticatedClient::new(long_chain_roots())
+                .with_minimum_key_size(minimum_key_size)
+                .verify_client_cert(&chain[0], &chain[1..], SystemTime::now())
+        };
+
+        assert!(verify(MinimumKeySize { rsa: 4096, ec: 256 }).is_ok());
+        assert_eq!(
+            verify(MinimumKeySize { rsa: 0, ec: 384 }).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::WeakKey)
+        );
+
+        // A P-384 end-entity key, issued by a P-256 intermediate.
+        let chain = client_chain();
+        let verify = |minimum_key_size| {
+            AllowAnyAuthenticatedClient::new(long_chain_roots())
+                .with_minimum_key_size(minimum_key_size)
+                .verify_client_cert(&chain[0], &chain[1..], SystemTime::now())
+        };
+        assert!(verify(MinimumKeySize { rsa: 0, ec: 256 }).is_ok());
+        assert_eq!(
+            verify(MinimumKeySize { rsa: 0, ec: 384 }).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::WeakKey)
+        );
+    }
+
+    #[test]
+    fn unknown_key_sizes_fail_minimum() {
+        // A certificate with no key we can parse.
+        let unknown = Certificate(b"not a certificate".to_vec());
+        let rsa_2048 = MinimumKeySize {
+            rsa: 2048,
+            ..Default::default()
+        };
+        assert!(key_size_meets(&unknown, MinimumKeySize::default()));
+        assert!(!key_size_meets(&unknown, rsa_2048));
+        assert_eq!(
+            strong_intermediates(&unknown, &[], rsa_2048).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::WeakKey)
+        );
+    }
+
+    #[test]
+    fn verify_chain_at_fixed_times() {
+        let chain = rsa_chain(include_bytes!("../../test-ca/rsa/end.fullchain"));
+        let server_name = ServerName::try_from("testserver.com").unwrap();
+        let verify_at = |secs| {
+            verify_chain(
+                &chain[0],
+                &chain[1..],
+                &rsa_roots(),
+                &server_name,
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs),
+            )
+        };
+
+        // 2024-01-01: the chain is valid.
+        assert_eq!(verify_at(1_704_067_200), Ok(()));
+
+        // 2030-01-01: the end-entity certificate has expired.
+        assert_eq!(
+            verify_at(1_893_456_000),
+            Err(Error::InvalidCertificate(CertificateError::Expired))
+        );
+
+        // 2020-01-01: and it was not yet valid.
+        assert_eq!(
+            verify_at(1_577_836_800),
+            Err(Error::InvalidCertificate(CertificateError::NotValidYet))
+        );
+
+        assert_eq!(
+            verify_chain(
+                &chain[0],
+                &chain[1..],
+                &rsa_roots(),
+                &ServerName::try_from("example.com").unwrap(),
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_704_067_200),
+            ),
+            Err(Error::InvalidCertificate(CertificateError::NotValidForName))
+        );
+    }
+
+    #[test]
+    fn server_verifier_enforces_must_staple() {
+        let server_name = ServerName::try_from("testserver.com").unwrap();
+        let ocsp = include_bytes!("../../test-ca/rsa/end-must-staple.ocsp");
+        // At 2027-01-01.
+        let verify = |chain: &[Certificate], ocsp: &[u8]| {
+            WebPkiVerifier::new(rsa_roots()).verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &server_name,
+                ocsp,
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_798_761_600),
+            )
+        };
+
+        let must_staple = rsa_chain(include_bytes!(
+            "../../test-ca/rsa/end-must-staple.fullchain"
+        ));
+        assert!(verify(&must_staple, ocsp).is_ok());
+        assert_eq!(
+            verify(&must_staple, &[]).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::MissingOcspResponse)
+        );
+
+        let mut bad_signature = ocsp.to_vec();
+        *bad_signature.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            verify(&must_staple, &bad_signature).unwrap_err(),
+            Error::InvalidCertificate(CertificateError::InvalidOcspResponse)
+        );
+
+        // A certificate without must-staple needs no response.
+        let chain = rsa_chain(include_bytes!("../../test-ca/rsa/end.fullchain"));
+        assert!(verify(&chain, &[]).is_ok());
+        // The issuer may be a trust anchor, rather than an intermediate.
+        let inter = rsa_chain(include_bytes!("../../test-ca/rsa/inter.cert"));
+        let mut inter_root = RootCertStore::empty();
+        inter_root.add(&inter[0]).unwrap();
+        assert!(WebPkiVerifier::new(inter_root)
+            .verify_server_cert(
+                &must_staple[0],
+                &[],
+                &server_name,
+                ocsp,
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_798_761_600),
+            )
+            .is_ok());
+    }
 
     #[test]
     fn assertions_are_debug() {
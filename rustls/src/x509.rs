@@ -1,5 +1,7 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
+use crate::enums::SignatureScheme;
+
 pub(crate) fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
 
@@ -25,6 +27,443 @@ pub(crate) fn wrap_in_sequence(bytes: &mut Vec<u8>) {
 
 const DER_SEQUENCE_TAG: u8 = 0x30;
 
+/// The kinds of public key whose size [`public_key_size`] reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PublicKeyKind {
+    Rsa,
+    /// ECDSA or EdDSA.
+    Ec,
+}
+
+/// The parts of a DER-encoded X.509 certificate needed outside webpki.
+pub(crate) struct CertificateFields<'a> {
+    /// The contents of the serialNumber INTEGER.
+    pub(crate) serial: &'a [u8],
+    /// The issuer Name, including its tag and length.
+    pub(crate) issuer: &'a [u8],
+    /// The subject Name, including its tag and length.
+    pub(crate) subject: &'a [u8],
+    /// The contents of the validity SEQUENCE.
+    pub(crate) validity: &'a [u8],
+    /// The contents of the subjectPublicKeyInfo SEQUENCE.
+    pub(crate) spki: &'a [u8],
+    /// The contents of the extensions SEQUENCE; empty if there are none.
+    pub(crate) extensions: &'a [u8],
+}
+
+impl<'a> CertificateFields<'a> {
+    /// Parse the fields of the DER-encoded certificate `cert`.
+    ///
+    /// Returns `None` if the certificate cannot be parsed.
+    pub(crate) fn parse(cert: &'a [u8]) -> Option<Self> {
+        let (cert, _) = der_expect(cert, DER_SEQUENCE_TAG)?;
+        let (mut tbs, _) = der_expect(cert, DER_SEQUENCE_TAG)?;
+
+        if tbs.first() == Some(&0xa0) {
+            tbs = der_next(tbs)?.2;
+        }
+        let (serial, rest) = der_expect(tbs, 0x02)?;
+        let (_signature, rest) = der_expect(rest, DER_SEQUENCE_TAG)?;
+        let (issuer, rest) = der_raw(rest)?;
+        let (validity, rest) = der_expect(rest, DER_SEQUENCE_TAG)?;
+        let (subject, rest) = der_raw(rest)?;
+        let (spki, mut rest) = der_expect(rest, DER_SEQUENCE_TAG)?;
+
+        // Skip the optional issuerUniqueID and subjectUniqueID.
+        let mut extensions: &[u8] = &[];
+        while let Some((tag, contents, after)) = der_next(rest) {
+            if tag == 0xa3 {
+                extensions = der_expect(contents, DER_SEQUENCE_TAG)?.0;
+            }
+            rest = after;
+        }
+
+        Some(Self {
+            serial,
+            issuer,
+            subject,
+            validity,
+            spki,
+            extensions,
+        })
+    }
+
+    /// The contents of the extension with the DER-encoded object identifier
+    /// `oid`, if present.
+    pub(crate) fn extension(&self, oid: &[u8]) -> Option<&'a [u8]> {
+        let mut rest = self.extensions;
+        while !rest.is_empty() {
+            let (extension, after) = der_expect(rest, DER_SEQUENCE_TAG)?;
+            rest = after;
+
+            let (id, extension) = der_expect(extension, 0x06)?;
+            if id != oid {
+                continue;
+            }
+
+            // Skip the optional critical BOOLEAN.
+            let extension = match der_next(extension)? {
+                (0x01, _, after) => after,
+                _ => extension,
+            };
+            return Some(der_expect(extension, 0x04)?.0);
+        }
+        None
+    }
+
+    /// The notBefore time, in seconds since the Unix epoch.
+    pub(crate) fn not_before(&self) -> Option<u64> {
+        let (tag, time, _) = der_next(self.validity)?;
+        der_time(tag, time)
+    }
+
+    /// The subjectPublicKey BIT STRING, without its count of unused bits.
+    pub(crate) fn public_key(&self) -> Option<&'a [u8]> {
+        let (_algorithm, rest) = der_expect(self.spki, DER_SEQUENCE_TAG)?;
+        let (key, _) = der_expect(rest, 0x03)?;
+        key.get(1..)
+    }
+}
+
+/// Whether the DER-encoded certificate `cert` has the TLS feature
+/// extension requiring a stapled OCSP response ("must-staple", RFC 7633).
+pub(crate) fn requires_ocsp_staple(cert: &[u8]) -> bool {
+    // id-pe-tlsfeature
+    const TLS_FEATURE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+    // The status_request extension type.
+    const STATUS_REQUEST: &[u8] = &[0x05];
+
+    let features = match CertificateFields::parse(cert).and_then(|cert| cert.extension(TLS_FEATURE))
+    {
+        Some(features) => features,
+        None => return false,
+    };
+
+    let mut features = match der_expect(features, DER_SEQUENCE_TAG) {
+        Some((features, _)) => features,
+        None => return false,
+    };
+    while let Some((feature, rest)) = der_expect(features, 0x02) {
+        if feature == STATUS_REQUEST {
+            return true;
+        }
+        features = rest;
+    }
+    false
+}
+
+/// Whether the DER-encoded certificate `cert` has the DelegationUsage
+/// extension, allowing it to sign delegated credentials (RFC 9345).
+pub(crate) fn allows_delegation(cert: &[u8]) -> bool {
+    // id-ce-delegationUsage
+    const DELEGATION_USAGE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xda, 0x4b, 0x2c];
+
+    CertificateFields::parse(cert)
+        .and_then(|cert| cert.extension(DELEGATION_USAGE))
+        .is_some()
+}
+
+/// The caIssuers URLs in the Authority Information Access extension of the
+/// DER-encoded certificate `cert`, which say where to find its issuer's
+/// certificate (RFC 5280 section 4.2.2.1).
+pub(crate) fn ca_issuers(cert: &[u8]) -> Vec<&str> {
+    // id-pe-authorityInfoAccess
+    const AUTHORITY_INFO_ACCESS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+    // id-ad-caIssuers
+    const CA_ISSUERS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x02];
+    // The uniformResourceIdentifier GeneralName.
+    const URI_TAG: u8 = 0x86;
+
+    let mut urls = Vec::new();
+    let mut descriptions = match CertificateFields::parse(cert)
+        .and_then(|cert| cert.extension(AUTHORITY_INFO_ACCESS))
+        .and_then(|aia| der_expect(aia, DER_SEQUENCE_TAG))
+    {
+        Some((descriptions, _)) => descriptions,
+        None => return urls,
+    };
+
+    while let Some((description, rest)) = der_expect(descriptions, DER_SEQUENCE_TAG) {
+        descriptions = rest;
+        let (method, location) = match der_expect(description, 0x06) {
+            Some(found) => found,
+            None => continue,
+        };
+        if method != CA_ISSUERS {
+            continue;
+        }
+        if let Some(url) =
+            der_expect(location, URI_TAG).and_then(|(url, _)| core::str::from_utf8(url).ok())
+        {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+// Public key algorithm and named curve OIDs.
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const RSASSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const SECP521R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const ED448: &[u8] = &[0x2b, 0x65, 0x71];
+
+/// Split the DER-encoded SubjectPublicKeyInfo `spki` into its algorithm
+/// OID and parameters, and its public key without the count of unused bits.
+pub(crate) fn spki_parts(spki: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (spki, _) = der_expect(spki, DER_SEQUENCE_TAG)?;
+    let (algorithm, rest) = der_expect(spki, DER_SEQUENCE_TAG)?;
+    let (oid, params) = der_expect(algorithm, 0x06)?;
+    let (key, _) = der_expect(rest, 0x03)?;
+    Some((oid, params, key.get(1..)?))
+}
+
+/// Whether a public key with the algorithm `oid` and `params`, as found
+/// in a SubjectPublicKeyInfo, can make TLS1.3 signatures with `scheme`.
+pub(crate) fn key_algorithm_matches_scheme(
+    oid: &[u8],
+    params: &[u8],
+    scheme: SignatureScheme,
+) -> bool {
+    let curve = || der_expect(params, 0x06).map(|(curve, _)| curve);
+
+    match scheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => {
+            oid == EC_PUBLIC_KEY && curve() == Some(SECP256R1)
+        }
+        SignatureScheme::ECDSA_NISTP384_SHA384 => {
+            oid == EC_PUBLIC_KEY && curve() == Some(SECP384R1)
+        }
+        SignatureScheme::ECDSA_NISTP521_SHA512 => {
+            oid == EC_PUBLIC_KEY && curve() == Some(SECP521R1)
+        }
+        SignatureScheme::ED25519 => oid == ED25519,
+        SignatureScheme::ED448 => oid == ED448,
+        SignatureScheme::RSA_PSS_SHA256
+        | SignatureScheme::RSA_PSS_SHA384
+        | SignatureScheme::RSA_PSS_SHA512 => oid == RSA_ENCRYPTION,
+        _ => false,
+    }
+}
+
+/// Find the kind and size, in bits, of the public key in the DER-encoded
+/// certificate `cert`.
+///
+/// The size is the modulus size for RSA keys, and the curve size for
+/// elliptic curve keys.  Returns `None` if the certificate cannot be
+/// parsed, or its key algorithm is not recognised.
+pub(crate) fn public_key_size(cert: &[u8]) -> Option<(PublicKeyKind, usize)> {
+    let cert = CertificateFields::parse(cert)?;
+    let (algorithm, _) = der_expect(cert.spki, DER_SEQUENCE_TAG)?;
+    let (oid, params) = der_expect(algorithm, 0x06)?;
+
+    match oid {
+        RSA_ENCRYPTION | RSASSA_PSS => {
+            // Find the modulus in the RSAPublicKey.
+            let (key, _) = der_expect(cert.public_key()?, DER_SEQUENCE_TAG)?;
+            let (modulus, _) = der_expect(key, 0x02)?;
+            let modulus = &modulus[modulus.iter().position(|b| *b != 0)?..];
+            let bits = modulus.len() * 8 - modulus[0].leading_zeros() as usize;
+            Some((PublicKeyKind::Rsa, bits))
+        }
+        EC_PUBLIC_KEY => {
+            let (curve, _) = der_expect(params, 0x06)?;
+            let bits = match curve {
+                SECP256R1 => 256,
+                SECP384R1 => 384,
+                SECP521R1 => 521,
+                _ => return None,
+            };
+            Some((PublicKeyKind::Ec, bits))
+        }
+        ED25519 => Some((PublicKeyKind::Ec, 256)),
+        ED448 => Some((PublicKeyKind::Ec, 448)),
+        _ => None,
+    }
+}
+
+/// Convert a DER UTCTime (tag 0x17) or GeneralizedTime (tag 0x18) to
+/// seconds since the Unix epoch.
+pub(crate) fn der_time(tag: u8, time: &[u8]) -> Option<u64> {
+    match tag {
+        0x17 => {
+            // RFC 5280: two-digit years from 50 are in the twentieth century.
+            let mut full = match time.first()? {
+                b'5'..=b'9' => b"19".to_vec(),
+                _ => b"20".to_vec(),
+            };
+            full.extend_from_slice(time);
+            generalized_time(&full)
+        }
+        0x18 => generalized_time(time),
+        _ => None,
+    }
+}
+
+/// Convert a GeneralizedTime of the form `YYYYMMDDHHMMSSZ` to seconds
+/// since the Unix epoch.
+pub(crate) fn generalized_time(time: &[u8]) -> Option<u64> {
+    if time.len() != 15 || time[14] != b'Z' {
+        return None;
+    }
+
+    let mut fields = [0u64; 6];
+    let widths = [4, 2, 2, 2, 2, 2];
+    let mut digits = time[..14].iter();
+    for (field, width) in fields.iter_mut().zip(widths) {
+        for _ in 0..width {
+            let digit = *digits.next()?;
+            if !digit.is_ascii_digit() {
+                return None;
+            }
+            *field = *field * 10 + u64::from(digit - b'0');
+        }
+    }
+
+    let [year, month, day, hour, minute, second] = fields;
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch, counting years from March so that leap days
+    // fall at the end of a year.
+    let (year, month) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let days =
+        year * 365 + year / 4 - year / 100 + year / 400 + (153 * month + 2) / 5 + day - 1 - 719_468;
+
+    Some(((days * 24 + hour) * 60 + minute) * 60 + second)
+}
+
+/// Split the first DER value from `input`, returning its tag, contents,
+/// and the remainder of `input`.
+pub(crate) fn der_next(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+
+    let len = match first {
+        0..=0x7f => first as usize,
+        0x81..=0x84 => {
+            let count = (first & 0x7f) as usize;
+            if rest.len() < count {
+                return None;
+            }
+            let (len, after) = rest.split_at(count);
+            rest = after;
+            len.iter()
+                .fold(0usize, |acc, byte| (acc << 8) | *byte as usize)
+        }
+        _ => return None,
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Like [`der_next`], but returns the whole first value, including its
+/// tag and length, and the remainder of `input`.
+pub(crate) fn der_raw(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, _, rest) = der_next(input)?;
+    Some(input.split_at(input.len() - rest.len()))
+}
+
+/// Like [`der_next`], but only if the first value has tag `tag`.
+pub(crate) fn der_expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_next(input)? {
+        (found, contents, rest) if found == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Make a self-signed Ed25519 certificate valid for `names`, which
+/// are DNS names or IP addresses.
+///
+/// The key is derived from `seed`, and Ed25519 signing is deterministic,
+/// so the certificate depends only on the arguments.
+///
+/// Returns the DER-encoded certificate and its PKCS#8 private key.
+#[cfg(feature = "testing")]
+pub(crate) fn self_signed_certificate(
+    names: &[&str],
+    serial: &[u8],
+    seed: &[u8; 32],
+) -> Result<(Vec<u8>, Vec<u8>), ring::error::KeyRejected> {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    // id-Ed25519, for both the signature and public key algorithms
+    const ALGORITHM: &[u8] = &[0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70];
+    // commonName
+    const COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+    // subjectAltName
+    const SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(seed)?;
+
+    // A version 1 PKCS#8 document: the private key is the seed.
+    let mut pkcs8 = asn1(0x02, vec![0x00]);
+    pkcs8.extend_from_slice(ALGORITHM);
+    pkcs8.extend(asn1(0x04, asn1(0x04, seed.to_vec())));
+    let pkcs8 = asn1(0x30, pkcs8);
+
+    let mut name = COMMON_NAME.to_vec();
+    name.extend(asn1(0x0c, b"rustls self-signed".to_vec()));
+    let name = asn1(0x30, asn1(0x31, asn1(0x30, name)));
+
+    let mut validity = asn1(0x17, b"750101000000Z".to_vec());
+    validity.extend(asn1(0x18, b"99991231235959Z".to_vec()));
+
+    let mut public_key = ALGORITHM.to_vec();
+    let mut bits = vec![0u8];
+    bits.extend_from_slice(key_pair.public_key().as_ref());
+    public_key.extend(asn1(0x03, bits));
+
+    let mut alt_names = Vec::new();
+    for name in names {
+        alt_names.extend(match name.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => asn1(0x87, ip.octets().to_vec()),
+            Ok(std::net::IpAddr::V6(ip)) => asn1(0x87, ip.octets().to_vec()),
+            Err(_) => asn1(0x82, name.as_bytes().to_vec()),
+        });
+    }
+    let mut extension = SUBJECT_ALT_NAME.to_vec();
+    extension.extend(asn1(0x04, asn1(0x30, alt_names)));
+
+    let mut tbs = asn1(0xa0, asn1(0x02, vec![0x02]));
+    tbs.extend(asn1(0x02, serial.to_vec()));
+    tbs.extend_from_slice(ALGORITHM);
+    tbs.extend_from_slice(&name);
+    tbs.extend(asn1(0x30, validity));
+    tbs.extend_from_slice(&name);
+    tbs.extend(asn1(0x30, public_key));
+    tbs.extend(asn1(0xa3, asn1(0x30, asn1(0x30, extension))));
+    let tbs = asn1(0x30, tbs);
+
+    let signature = key_pair.sign(&tbs);
+    let mut bits = vec![0u8];
+    bits.extend_from_slice(signature.as_ref());
+
+    let mut cert = tbs;
+    cert.extend_from_slice(ALGORITHM);
+    cert.extend(asn1(0x03, bits));
+
+    Ok((asn1(0x30, cert), pkcs8))
+}
+
+#[cfg(feature = "testing")]
+fn asn1(tag: u8, mut contents: Vec<u8>) -> Vec<u8> {
+    wrap_in_asn1_len(&mut contents);
+    contents.insert(0, tag);
+    contents
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
@@ -91,3 +530,103 @@ fn test_ludicrous() {
     );
     assert_eq!(val.len(), 0x1000000 + 6);
 }
+
+#[test]
+fn test_public_key_size() {
+    fn first_cert(pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut std::io::BufReader::new(pem))
+            .unwrap()
+            .remove(0)
+    }
+
+    let cases: &[(&[u8], PublicKeyKind, usize)] = &[
+        (
+            include_bytes!("../../test-ca/rsa/ca.cert"),
+            PublicKeyKind::Rsa,
+            4096,
+        ),
+        (
+            include_bytes!("../../test-ca/rsa/inter.cert"),
+            PublicKeyKind::Rsa,
+            3072,
+        ),
+        (
+            include_bytes!("../../test-ca/rsa/end.cert"),
+            PublicKeyKind::Rsa,
+            2048,
+        ),
+        (
+            include_bytes!("../../test-ca/rsa/end-weak.cert"),
+            PublicKeyKind::Rsa,
+            1024,
+        ),
+        (
+            include_bytes!("../../test-ca/ecdsa/ca.cert"),
+            PublicKeyKind::Ec,
+            384,
+        ),
+        (
+            include_bytes!("../../test-ca/ecdsa/end.cert"),
+            PublicKeyKind::Ec,
+            256,
+        ),
+        (
+            include_bytes!("../../test-ca/eddsa/end.cert"),
+            PublicKeyKind::Ec,
+            256,
+        ),
+    ];
+    for (pem, kind, bits) in cases {
+        assert_eq!(public_key_size(&first_cert(pem)), Some((*kind, *bits)));
+    }
+
+    assert_eq!(public_key_size(&[]), None);
+    assert_eq!(public_key_size(&[0x30, 0x03, 0x30, 0x01]), None);
+}
+
+#[test]
+fn test_ca_issuers() {
+    assert_eq!(
+        ca_issuers(include_bytes!("testdata/cert-github.0.der")),
+        vec!["http://cacerts.digicert.com/DigiCertHighAssuranceTLSHybridECCSHA2562020CA1.crt"]
+    );
+
+    let test_ca_cert = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        &include_bytes!("../../test-ca/rsa/end.cert")[..],
+    ))
+    .unwrap()
+    .remove(0);
+    assert!(ca_issuers(&test_ca_cert).is_empty());
+    assert!(ca_issuers(&[]).is_empty());
+}
+
+#[test]
+fn test_generalized_time() {
+    assert_eq!(generalized_time(b"19700101000000Z"), Some(0));
+    assert_eq!(generalized_time(b"20000301000000Z"), Some(951_868_800));
+    assert_eq!(generalized_time(b"20261015033636Z"), Some(1_792_035_396));
+    assert_eq!(generalized_time(b"20261015033636"), None);
+    assert_eq!(generalized_time(b"2026101503363aZ"), None);
+    assert_eq!(generalized_time(b"19691231235959Z"), None);
+}
+
+#[test]
+fn test_der_time() {
+    assert_eq!(der_time(0x17, b"700101000000Z"), Some(0));
+    assert_eq!(der_time(0x17, b"261015033636Z"), Some(1_792_035_396));
+    assert_eq!(der_time(0x18, b"20261015033636Z"), Some(1_792_035_396));
+    assert_eq!(der_time(0x17, b"20261015033636Z"), None);
+    assert_eq!(der_time(0x04, b"261015033636Z"), None);
+
+    let cert = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        &include_bytes!("../../test-ca/rsa/end-delegation.cert")[..],
+    ))
+    .unwrap()
+    .remove(0);
+    assert_eq!(
+        CertificateFields::parse(&cert)
+            .unwrap()
+            .not_before(),
+        Some(1_767_225_600)
+    );
+}
@@ -8,20 +8,32 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use rustls::client::{ResolvesClientCert, Resumption};
+use rustls::client::{ResolvesClientCert, Resumption, Tls12Resumption};
+use rustls::compress::{
+    CertificateCompressionAlgorithm, CertificateCompressor, CompressionFailed, DecompressionFailed,
+};
+use rustls::crypto::hash::HashAlgorithm;
 use rustls::crypto::ring::Ring;
-use rustls::crypto::CryptoProvider;
+use rustls::crypto::{CompositeProvider, CryptoProvider, KeyExchangeError, SupportedGroup};
 use rustls::internal::msgs::base::Payload;
 use rustls::internal::msgs::codec::Codec;
-use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AlpnPolicy, ClientHello, ResolvesServerCert,
+};
+use rustls::time::{DefaultTimeProvider, MonotonicClock, TimeProvider, UnixTime};
 #[cfg(feature = "secret_extraction")]
 use rustls::ConnectionTrafficSecrets;
 use rustls::{
-    sign, CertificateError, ConnectionCommon, Error, KeyLog, PeerIncompatible, PeerMisbehaved,
+    sign, CertificateError, CertificateType, ConnectionCommon, ConnectionState, Error,
+    HandshakeObserver, HandshakeParams, KeyLog, PeerIncompatible, PeerMisbehaved, ResumptionKind,
     SideData,
 };
-use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
+use rustls::{
+    CipherSuite, ExtensionType, PresharedKey, ProtocolVersion, PskKeyExchangePolicy, RecordPadding,
+    SignatureScheme,
+};
 use rustls::{ClientConfig, ClientConnection};
 use rustls::{ServerConfig, ServerConnection};
 use rustls::{Stream, StreamOwned};
@@ -96,6 +108,213 @@ fn alpn() {
     );
 }
 
+#[test]
+fn alpn_policy() {
+    for (policy, expected_error) in [
+        (
+            AlpnPolicy::Strict,
+            Some(ErrorFromPeer::Server(Error::NoApplicationProtocol)),
+        ),
+        (AlpnPolicy::AllowNoProtocol, None),
+    ] {
+        let server_config = Arc::new(
+            make_server_config(KeyType::Rsa)
+                .with_alpn_protocols(vec![b"server-proto".to_vec()], policy),
+        );
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.alpn_protocols = vec![b"client-proto".to_vec()];
+            let client_config = Arc::new(client_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            let error = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(error.err(), expected_error);
+            assert_eq!(client.alpn_protocol(), None);
+            assert_eq!(server.alpn_protocol(), None);
+
+            if policy == AlpnPolicy::Strict {
+                transfer(&mut server, &mut client);
+                assert_eq!(
+                    client.process_new_packets().err(),
+                    Some(Error::AlertReceived(
+                        rustls::AlertDescription::NoApplicationProtocol
+                    ))
+                );
+            } else {
+                assert!(!client.is_handshaking());
+                assert!(!server.is_handshaking());
+            }
+
+            // A common protocol is still chosen, whatever the policy.
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.alpn_protocols = vec![b"client-proto".to_vec(), b"server-proto".to_vec()];
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.alpn_protocol(), Some(&b"server-proto"[..]));
+            assert_eq!(server.alpn_protocol(), Some(&b"server-proto"[..]));
+        }
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    let server_config = Arc::new(
+        make_server_config(KeyType::Rsa).with_alpn_selector(|offered| {
+            offered
+                .iter()
+                .find(|protocol| **protocol == b"http/1.1")
+                .map(|protocol| protocol.to_vec())
+        }),
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(server.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+}
+
+#[test]
+fn alpn_from_derived_client_config() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa).with_alpn_protocols(
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        AlpnPolicy::AllowNoProtocol,
+    ));
+
+    let mut base = make_client_config(KeyType::Rsa);
+    base.alpn_protocols = vec![b"h2".to_vec()];
+    let base = Arc::new(base);
+
+    let mut derived = ClientConfig::clone(&base);
+    derived.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let derived = Arc::new(derived);
+
+    for (config, agreed) in [(&base, &b"h2"[..]), (&derived, &b"http/1.1"[..])] {
+        let (mut client, mut server) = make_pair_for_arc_configs(config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), Some(agreed));
+        assert_eq!(server.alpn_protocol(), Some(agreed));
+    }
+
+    // Everything else is inherited.
+    assert_eq!(derived.cipher_suites_named(), base.cipher_suites_named());
+    assert_eq!(derived.kx_groups_named(), base.kx_groups_named());
+}
+
+#[test]
+fn alpn_selector_cannot_choose_unoffered_protocol() {
+    let server_config = make_server_config(KeyType::Rsa)
+        .with_alpn_protocols(vec![b"h2".to_vec()], AlpnPolicy::Strict)
+        .with_alpn_selector(|_| Some(b"http/1.1".to_vec()));
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server).err(),
+        Some(ErrorFromPeer::Server(Error::NoApplicationProtocol))
+    );
+}
+
+#[test]
+fn handshake_deadline() {
+    let now = Instant::now();
+
+    // a distant deadline is no obstacle
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    server.set_handshake_deadline(now + Duration::from_secs(3600));
+    assert_eq!(
+        server.handshake_deadline(),
+        Some(now + Duration::from_secs(3600))
+    );
+    do_handshake(&mut client, &mut server);
+
+    // ... nor one passing after the handshake
+    server.set_handshake_deadline(now);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    // but processing a handshake step after the deadline fails
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.set_handshake_deadline(now);
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+    assert!(client.is_handshaking());
+    assert!(!client.wants_write());
+
+    // ... as do later attempts
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+}
+
+#[derive(Debug)]
+struct SteppedClock(Mutex<Instant>);
+
+impl MonotonicClock for SteppedClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn handshake_deadline_uses_handshake_clock() {
+    let start = Instant::now();
+    let clock = Arc::new(SteppedClock(Mutex::new(start)));
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    client.set_handshake_clock(Arc::clone(&clock) as Arc<dyn MonotonicClock>);
+    client.set_handshake_deadline(start + Duration::from_secs(10));
+
+    // before the deadline, by the clock given
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    client.process_new_packets().unwrap();
+
+    // at the deadline, by the clock given, however little real time passed
+    *clock.0.lock().unwrap() = start + Duration::from_secs(10);
+    transfer(&mut server, &mut client);
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+}
+
+#[test]
+fn peer_extensions_are_reported() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"proto".to_vec()];
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.alpn_protocols = vec![b"proto".to_vec()];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert!(client.peer_extensions().is_empty());
+        assert!(server.peer_extensions().is_empty());
+        do_handshake(&mut client, &mut server);
+
+        let client_sent = server.peer_extensions();
+        assert!(client_sent.contains(&ExtensionType::ServerName));
+        assert!(client_sent.contains(&ExtensionType::ALProtocolNegotiation));
+
+        let server_sent = client.peer_extensions();
+        assert!(server_sent.contains(&ExtensionType::ALProtocolNegotiation));
+    }
+}
+
 fn version_test(
     client_versions: &[&'static rustls::SupportedProtocolVersion],
     server_versions: &[&'static rustls::SupportedProtocolVersion],
@@ -251,6 +470,98 @@ fn config_builder_for_client_rejects_incompatible_cipher_suites() {
     );
 }
 
+#[test]
+fn config_reports_default_kx_groups_in_order() {
+    let expected = vec![
+        rustls::NamedGroup::X25519,
+        rustls::NamedGroup::secp256r1,
+        rustls::NamedGroup::secp384r1,
+    ];
+    assert_eq!(make_client_config(KeyType::Rsa).kx_groups_named(), expected);
+    assert_eq!(make_server_config(KeyType::Rsa).kx_groups_named(), expected);
+
+    let client_config = ClientConfig::<Ring>::builder()
+        .with_safe_default_cipher_suites()
+        .with_kx_groups(&[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519])
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    assert_eq!(
+        client_config.kx_groups_named(),
+        vec![rustls::NamedGroup::secp384r1, rustls::NamedGroup::X25519]
+    );
+}
+
+#[test]
+fn config_reports_default_cipher_suites_in_order() {
+    let expected = rustls::DEFAULT_CIPHER_SUITES
+        .iter()
+        .map(|suite| suite.suite())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        make_client_config(KeyType::Rsa).cipher_suites_named(),
+        expected
+    );
+    assert_eq!(
+        make_server_config(KeyType::Rsa).cipher_suites_named(),
+        expected
+    );
+
+    let server_config = finish_server_config(
+        KeyType::Rsa,
+        ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[
+                rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+                rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+            ])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    assert_eq!(
+        server_config.cipher_suites_named(),
+        vec![
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+            CipherSuite::TLS13_AES_128_GCM_SHA256
+        ]
+    );
+}
+
+#[test]
+fn config_builder_without_cbc_suites_offers_none() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = finish_client_config(
+            *kt,
+            ClientConfig::<Ring>::builder()
+                .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
+                .without_cbc_suites()
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap(),
+        );
+
+        let mut offered = rustls::ALL_CIPHER_SUITES
+            .iter()
+            .map(|suite| suite.suite())
+            .collect::<Vec<_>>();
+        offered.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+        assert!(!offered.iter().any(|suite| suite
+            .iana_name()
+            .unwrap()
+            .contains("_CBC_")));
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_cipher_suites: Some(offered),
+            ..Default::default()
+        });
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    }
+}
+
 #[test]
 fn config_builder_for_server_rejects_empty_kx_groups() {
     assert_eq!(
@@ -590,3159 +901,5159 @@ fn client_close_notify() {
 }
 
 #[test]
-fn server_closes_uncleanly() {
-    let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config(kt));
-
+fn connection_state_transitions() {
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
-
-        // check that unclean EOF reporting does not overtake appdata
-        assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
-        assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
-        );
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(client.state(), ConnectionState::Handshaking);
+        assert_eq!(server.state(), ConnectionState::Handshaking);
 
-        transfer(&mut server, &mut client);
-        transfer_eof(&mut client);
-        let io_state = client.process_new_packets().unwrap();
-        assert!(!io_state.peer_has_closed());
-        check_read(&mut client.reader(), b"from-server!");
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.state(), ConnectionState::Established);
+        assert_eq!(server.state(), ConnectionState::Established);
 
-        check_read_err(
-            &mut client.reader() as &mut dyn io::Read,
-            io::ErrorKind::UnexpectedEof,
-        );
+        client.send_close_notify();
+        assert_eq!(client.state(), ConnectionState::Closing);
+        assert_eq!(server.state(), ConnectionState::Established);
 
-        // may still transmit pending frames
         transfer(&mut client, &mut server);
         server.process_new_packets().unwrap();
-        check_read(&mut server.reader(), b"from-client!");
+        assert_eq!(server.state(), ConnectionState::Closing);
+
+        server.send_close_notify();
+        assert_eq!(server.state(), ConnectionState::Closed);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(client.state(), ConnectionState::Closed);
     }
 }
 
 #[test]
-fn client_closes_uncleanly() {
-    let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config(kt));
-
+fn pending_write_bytes_tracks_queued_tls() {
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
         let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert!(client.pending_write_bytes() > 0);
         do_handshake(&mut client, &mut server);
+        assert_eq!(client.pending_write_bytes(), 0);
+        assert!(!client.wants_write());
 
-        // check that unclean EOF reporting does not overtake appdata
-        assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
-        assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
-        );
-
-        transfer(&mut client, &mut server);
-        transfer_eof(&mut server);
-        let io_state = server.process_new_packets().unwrap();
-        assert!(!io_state.peer_has_closed());
-        check_read(&mut server.reader(), b"from-client!");
-
-        check_read_err(
-            &mut server.reader() as &mut dyn io::Read,
-            io::ErrorKind::UnexpectedEof,
-        );
+        client
+            .writer()
+            .write_all(&[0u8; 100])
+            .unwrap();
+        let pending = client.pending_write_bytes();
+        assert!(client.wants_write());
+        // one record: header, plus (for TLS1.2) explicit nonce or
+        // (for TLS1.3) inner content type, and AEAD tag
+        assert!((100..=100 + 64).contains(&pending));
 
-        // may still transmit pending frames
-        transfer(&mut server, &mut client);
-        client.process_new_packets().unwrap();
-        check_read(&mut client.reader(), b"from-server!");
+        let mut buf = Vec::new();
+        assert_eq!(client.write_tls(&mut buf).unwrap(), pending);
+        assert_eq!(client.pending_write_bytes(), 0);
+        assert!(!client.wants_write());
     }
 }
 
-#[derive(Default)]
-struct ServerCheckCertResolve {
-    expected_sni: Option<String>,
-    expected_sigalgs: Option<Vec<SignatureScheme>>,
-    expected_alpn: Option<Vec<Vec<u8>>>,
-    expected_cipher_suites: Option<Vec<CipherSuite>>,
-}
+#[test]
+fn tls13_record_padding() {
+    let send_padded = |policy: RecordPadding, len: usize| -> usize {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13])
+                .with_record_padding(policy.clone());
+        let server_config = make_server_config(KeyType::Rsa).with_record_padding(policy);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-impl ResolvesServerCert for ServerCheckCertResolve {
-    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
-        if client_hello
-            .signature_schemes()
-            .is_empty()
-        {
-            panic!("no signature schemes shared by client");
-        }
+        let data = vec![0x42u8; len];
+        client
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
 
-        if client_hello.cipher_suites().is_empty() {
-            panic!("no cipher suites shared by client");
+        let mut rd = &buf[..];
+        while !rd.is_empty() {
+            server.read_tls(&mut rd).unwrap();
+            server.process_new_packets().unwrap();
         }
+        check_read(&mut server.reader(), &data);
 
-        if let Some(expected_sni) = &self.expected_sni {
-            let sni: &str = client_hello
-                .server_name()
-                .expect("sni unexpectedly absent");
-            assert_eq!(expected_sni, sni);
-        }
+        // header, then content and padding, inner content type and AEAD tag
+        buf.len() - 5 - 1 - 16
+    };
 
-        if let Some(expected_sigalgs) = &self.expected_sigalgs {
-            assert_eq!(
-                expected_sigalgs,
-                client_hello.signature_schemes(),
-                "unexpected signature schemes"
-            );
-        }
+    assert_eq!(send_padded(RecordPadding::None, 3), 3);
+    assert_eq!(send_padded(RecordPadding::BlockSize(64), 3), 64);
+    assert_eq!(send_padded(RecordPadding::BlockSize(64), 64), 64);
+    assert_eq!(send_padded(RecordPadding::BlockSize(64), 65), 128);
+    assert_eq!(
+        send_padded(RecordPadding::Custom(Arc::new(|len| len)), 10),
+        20
+    );
 
-        if let Some(expected_alpn) = &self.expected_alpn {
-            let alpn = client_hello
-                .alpn()
-                .expect("alpn unexpectedly absent")
-                .collect::<Vec<_>>();
-            assert_eq!(alpn.len(), expected_alpn.len());
+    // padding never makes a record oversized
+    assert_eq!(
+        send_padded(RecordPadding::Custom(Arc::new(|_| 1000)), 16_000),
+        16_384
+    );
+}
 
-            for (got, wanted) in alpn.iter().zip(expected_alpn.iter()) {
-                assert_eq!(got, &wanted.as_slice());
-            }
-        }
+/// A certificate codec which reverses its input, and remembers what it saw.
+#[derive(Debug)]
+struct ReversingCompressor {
+    algorithm: u16,
+    compressed: Mutex<Vec<Vec<u8>>>,
+    decompressed: Mutex<Vec<Vec<u8>>>,
+}
 
-        if let Some(expected_cipher_suites) = &self.expected_cipher_suites {
-            assert_eq!(
-                expected_cipher_suites,
-                client_hello.cipher_suites(),
-                "unexpected cipher suites"
-            );
+impl ReversingCompressor {
+    fn with_algorithm(algorithm: u16) -> Self {
+        Self {
+            algorithm,
+            compressed: Mutex::default(),
+            decompressed: Mutex::default(),
         }
-
-        None
     }
 }
 
-#[test]
-fn server_cert_resolve_with_sni() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config(*kt);
-        let mut server_config = make_server_config(*kt);
+impl Default for ReversingCompressor {
+    fn default() -> Self {
+        Self::with_algorithm(0xff00)
+    }
+}
 
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_sni: Some("the-value-from-sni".into()),
-            ..Default::default()
-        });
+impl CertificateCompressor for ReversingCompressor {
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Unknown(self.algorithm)
+    }
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("the-value-from-sni"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, CompressionFailed> {
+        self.compressed
+            .lock()
+            .unwrap()
+            .push(input.to_vec());
+        Ok(input.iter().rev().copied().collect())
+    }
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+        if input.len() != output.len() {
+            return Err(DecompressionFailed);
+        }
+        for (out, byte) in output
+            .iter_mut()
+            .zip(input.iter().rev())
+        {
+            *out = *byte;
+        }
+        self.decompressed
+            .lock()
+            .unwrap()
+            .push(output.to_vec());
+        Ok(())
     }
 }
 
 #[test]
-fn server_cert_resolve_with_alpn() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.alpn_protocols = vec!["foo".into(), "bar".into()];
+fn tls13_cert_compression_with_custom_codec() {
+    let codec = Arc::new(ReversingCompressor::default());
 
-        let mut server_config = make_server_config(*kt);
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_alpn: Some(vec![b"foo".to_vec(), b"bar".to_vec()]),
-            ..Default::default()
-        });
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_compressors = vec![codec.clone()];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = vec![codec.clone()];
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("sni-value")).unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
-    }
+    let compressed = codec.compressed.lock().unwrap();
+    let decompressed = codec.decompressed.lock().unwrap();
+    assert_eq!(compressed.len(), 1);
+    assert_eq!(*compressed, *decompressed);
+    assert_eq!(
+        client.peer_certificates(),
+        Some(&KeyType::Rsa.get_chain()[..])
+    );
 }
 
 #[test]
-fn client_trims_terminating_dot() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config(*kt);
-        let mut server_config = make_server_config(*kt);
-
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_sni: Some("some-host.com".into()),
-            ..Default::default()
-        });
+fn tls13_cert_compression_needs_both_peers() {
+    for (client_codecs, server_codecs) in [(true, false), (false, true)] {
+        let codec = Arc::new(ReversingCompressor::default());
+        let codecs = |enabled: bool| -> Vec<Arc<dyn CertificateCompressor>> {
+            match enabled {
+                true => vec![codec.clone()],
+                false => vec![],
+            }
+        };
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("some-host.com.")).unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        client_config.cert_compressors = codecs(client_codecs);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.cert_compressors = codecs(server_codecs);
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(codec
+            .compressed
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert!(codec
+            .decompressed
+            .lock()
+            .unwrap()
+            .is_empty());
     }
 }
 
-#[cfg(feature = "tls12")]
-fn check_sigalgs_reduced_by_ciphersuite(
-    kt: KeyType,
-    suite: CipherSuite,
-    expected_sigalgs: Vec<SignatureScheme>,
-) {
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::<Ring>::builder()
-            .with_cipher_suites(&[find_suite(suite)])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
-
-    let mut server_config = make_server_config(kt);
+#[test]
+fn tls13_cert_compression_follows_client_preference() {
+    let first = Arc::new(ReversingCompressor::with_algorithm(0xff00));
+    let second = Arc::new(ReversingCompressor::with_algorithm(0xff01));
 
-    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-        expected_sigalgs: Some(expected_sigalgs),
-        expected_cipher_suites: Some(vec![suite, CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV]),
-        ..Default::default()
-    });
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_compressors = vec![first.clone(), second.clone()];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = vec![second.clone(), first.clone()];
 
-    let mut client =
-        ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
-    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert!(err.is_err());
+    assert_eq!(first.compressed.lock().unwrap().len(), 1);
+    assert!(second
+        .compressed
+        .lock()
+        .unwrap()
+        .is_empty());
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
-    check_sigalgs_reduced_by_ciphersuite(
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-        vec![
-            SignatureScheme::RSA_PSS_SHA512,
-            SignatureScheme::RSA_PSS_SHA384,
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA512,
-            SignatureScheme::RSA_PKCS1_SHA384,
-            SignatureScheme::RSA_PKCS1_SHA256,
-        ],
+fn tls13_cert_decompression_limit() {
+    let codec = Arc::new(ReversingCompressor::default());
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_compressors = vec![codec.clone()];
+    client_config.max_decompressed_certificate_len = 1024;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = vec![codec.clone()];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::PeerMisbehaved(
+            PeerMisbehaved::DecompressedCertificateTooLarge
+        )))
     );
+
+    // the server compressed its certificate, but the client rejected it
+    // without attempting to decompress it.
+    assert_eq!(codec.compressed.lock().unwrap().len(), 1);
+    assert!(codec
+        .decompressed
+        .lock()
+        .unwrap()
+        .is_empty());
 }
 
-#[cfg(feature = "tls12")]
+#[cfg(any(feature = "brotli", feature = "zlib"))]
 #[test]
-fn server_cert_resolve_reduces_sigalgs_for_ecdsa_ciphersuite() {
-    check_sigalgs_reduced_by_ciphersuite(
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-        vec![
-            SignatureScheme::ECDSA_NISTP384_SHA384,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::ED25519,
-        ],
-    );
-}
+fn tls13_cert_compression_shrinks_server_flight() {
+    let server_flight_len = |compressor: Option<Arc<dyn CertificateCompressor>>| -> usize {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        client_config.cert_compressors = compressor.iter().cloned().collect();
+        server_config.cert_compressors = compressor.iter().cloned().collect();
 
-struct ServerCheckNoSNI {}
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let len = transfer(&mut server, &mut client);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&KeyType::Rsa.get_chain()[..])
+        );
+        len
+    };
 
-impl ResolvesServerCert for ServerCheckNoSNI {
-    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
-        assert!(client_hello.server_name().is_none());
+    let uncompressed = server_flight_len(None);
+    for compressor in rustls::compress::default_cert_compressors() {
+        let compressed = server_flight_len(Some(compressor.clone()));
+        println!("{:?}: {} -> {} bytes", compressor, uncompressed, compressed);
+        assert!(compressed < uncompressed);
+    }
+}
 
-        None
+fn external_psk(key: &[u8]) -> Arc<PresharedKey> {
+    Arc::new(PresharedKey::new(b"client-1".to_vec(), key.to_vec(), HashAlgorithm::SHA256).unwrap())
+}
+
+#[test]
+fn preshared_key_identity_length() {
+    for len in [0, 0x10000] {
+        assert!(
+            PresharedKey::new(vec![b'a'; len], b"key".to_vec(), HashAlgorithm::SHA256).is_err()
+        );
+    }
+    for len in [1, 0xffff] {
+        assert!(PresharedKey::new(vec![b'a'; len], b"key".to_vec(), HashAlgorithm::SHA256).is_ok());
     }
 }
 
 #[test]
-fn client_with_sni_disabled_does_not_send_sni() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut server_config = make_server_config(*kt);
-        server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
-        let server_config = Arc::new(server_config);
+fn tls13_external_psk_handshake_without_certificate() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
 
-        for version in rustls::ALL_VERSIONS {
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.enable_sni = false;
+    // The server has no certificate at all.
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(rustls::server::ResolvesServerCertUsingSni::new());
+    server_config.preshared_keys = vec![
+        Arc::new(
+            PresharedKey::new(
+                b"client-2".to_vec(),
+                b"another secret".to_vec(),
+                HashAlgorithm::SHA256,
+            )
+            .unwrap(),
+        ),
+        external_psk(b"shared secret"),
+    ];
 
-            let mut client =
-                ClientConnection::new(Arc::new(client_config), server_name("value-not-sent"))
-                    .unwrap();
-            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
 
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert!(err.is_err());
-        }
-    }
+    assert_eq!(client.peer_certificates(), None);
+    // A suite with the PSK's hash function is chosen, even though we
+    // prefer TLS13_AES_256_GCM_SHA384.
+    assert_eq!(
+        client
+            .negotiated_cipher_suite()
+            .unwrap()
+            .suite(),
+        CipherSuite::TLS13_AES_128_GCM_SHA256
+    );
+
+    server
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"hello");
 }
 
-#[test]
-fn client_checks_server_certificate_with_given_name() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config(*kt));
+/// Complete a handshake using an external PSK, returning the key share the
+/// server sent in its ServerHello, if any.
+fn external_psk_server_key_share(policy: PskKeyExchangePolicy) -> Option<Vec<u8>> {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(rustls::server::ResolvesServerCertUsingSni::new());
+    server_config.preshared_keys = vec![external_psk(b"shared secret")];
+    server_config.psk_key_exchange_policy = policy;
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let mut client = ClientConnection::new(
-                Arc::new(client_config),
-                server_name("not-the-right-hostname.com"),
-            )
-            .unwrap();
-            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
 
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert_eq!(
-                err,
-                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
-                    CertificateError::NotValidForName
-                )))
-            );
+    let key_share = Mutex::new(None);
+    let find_key_share = |msg: &mut Message| {
+        if let MessagePayload::Handshake { parsed, .. } = &msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &parsed.payload {
+                *key_share.lock().unwrap() = sh
+                    .get_key_share()
+                    .map(|kse| kse.payload.0.clone());
+            }
         }
-    }
-}
+        Altered::InPlace
+    };
 
-#[test]
-fn client_checks_server_certificate_with_given_ip_address() {
-    fn check_server_name(
-        client_config: Arc<ClientConfig<Ring>>,
-        server_config: Arc<ServerConfig<Ring>>,
-        name: &'static str,
-    ) -> Result<(), ErrorFromPeer> {
-        let mut client = ClientConnection::new(client_config, server_name(name)).unwrap();
-        let mut server = ServerConnection::new(server_config).unwrap();
-        do_handshake_until_error(&mut client, &mut server)
-    }
-
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config(*kt));
-
-        for version in rustls::ALL_VERSIONS {
-            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+    transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, find_key_share, &mut client);
+    client.process_new_packets().unwrap();
+    transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+    assert_eq!(client.peer_certificates(), None);
 
-            // positive ipv4 case
-            assert_eq!(
-                check_server_name(client_config.clone(), server_config.clone(), "198.51.100.1"),
-                Ok(()),
-            );
+    key_share.into_inner().unwrap()
+}
 
-            // negative ipv4 case
-            assert_eq!(
-                check_server_name(client_config.clone(), server_config.clone(), "198.51.100.2"),
-                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
-                    CertificateError::NotValidForName
-                )))
-            );
+#[test]
+fn tls13_external_psk_key_exchange_policy() {
+    // with psk_dhe_ke, every handshake uses a fresh ephemeral key
+    let first = external_psk_server_key_share(PskKeyExchangePolicy::PreferDhe).unwrap();
+    let second = external_psk_server_key_share(PskKeyExchangePolicy::PreferDhe).unwrap();
+    assert_ne!(first, second);
+    assert!(external_psk_server_key_share(PskKeyExchangePolicy::RequireDhe).is_some());
+
+    // with psk_ke, there is no key exchange
+    assert_eq!(
+        external_psk_server_key_share(PskKeyExchangePolicy::PreferPskOnly),
+        None
+    );
+}
 
-            // positive ipv6 case
-            assert_eq!(
-                check_server_name(client_config.clone(), server_config.clone(), "2001:db8::1"),
-                Ok(()),
-            );
+#[test]
+fn negotiated_key_exchange_group_is_reported() {
+    for version in rustls::ALL_VERSIONS {
+        let (mut client, mut server) = make_pair_for_configs(
+            make_client_config_with_versions(KeyType::Rsa, &[version]),
+            make_server_config(KeyType::Rsa),
+        );
+        assert_eq!(client.negotiated_key_exchange_group(), None);
+        assert_eq!(server.negotiated_key_exchange_group(), None);
 
-            // negative ipv6 case
-            assert_eq!(
-                check_server_name(client_config.clone(), server_config.clone(), "2001:db8::2"),
-                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
-                    CertificateError::NotValidForName
-                )))
-            );
-        }
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            client.negotiated_key_exchange_group(),
+            Some(rustls::NamedGroup::X25519)
+        );
+        assert_eq!(
+            server.negotiated_key_exchange_group(),
+            Some(rustls::NamedGroup::X25519)
+        );
     }
 }
 
-struct ClientCheckCertResolve {
-    query_count: AtomicUsize,
-    expect_queries: usize,
-    expect_issuers: Vec<Vec<u8>>,
-    expect_sigschemes: Vec<SignatureScheme>,
-}
+#[test]
+fn negotiated_key_exchange_group_is_none_for_psk_only() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.preshared_keys = vec![external_psk(b"shared secret")];
+    server_config.psk_key_exchange_policy = PskKeyExchangePolicy::PreferPskOnly;
 
-impl ClientCheckCertResolve {
-    fn new(
-        expect_queries: usize,
-        expect_issuers: Vec<Vec<u8>>,
-        expect_sigschemes: Vec<SignatureScheme>,
-    ) -> Self {
-        Self {
-            query_count: AtomicUsize::new(0),
-            expect_queries,
-            expect_issuers,
-            expect_sigschemes,
-        }
-    }
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_certificates(), None);
+    assert_eq!(client.negotiated_key_exchange_group(), None);
+    assert_eq!(server.negotiated_key_exchange_group(), None);
 }
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            let count = self.query_count.load(Ordering::SeqCst);
-            assert_eq!(count, self.expect_queries);
-        }
-    }
+#[test]
+fn tls13_external_psk_with_wrong_key_fails() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.preshared_keys = vec![external_psk(b"different secret")];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerMisbehaved(
+            PeerMisbehaved::IncorrectBinder
+        )))
+    );
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(
-        &self,
-        acceptable_issuers: &[&[u8]],
-        sigschemes: &[SignatureScheme],
-    ) -> Option<Arc<sign::CertifiedKey>> {
-        self.query_count
-            .fetch_add(1, Ordering::SeqCst);
+#[test]
+fn tls13_unknown_external_psk_falls_back_to_certificate() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
+    let server_config = make_server_config(KeyType::Rsa);
 
-        if acceptable_issuers.is_empty() {
-            panic!("no issuers offered by server");
-        }
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.peer_certificates(),
+        Some(&KeyType::Rsa.get_chain()[..])
+    );
+}
 
-        if sigschemes.is_empty() {
-            panic!("no signature schemes shared by server");
-        }
+#[test]
+fn client_half_close() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
 
-        assert_eq!(acceptable_issuers, self.expect_issuers);
-        assert_eq!(sigschemes, self.expect_sigschemes);
+        client
+            .writer()
+            .write_all(b"request")
+            .unwrap();
+        client.send_close_notify();
+        let err = client
+            .writer()
+            .write(b"more")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
 
-        None
-    }
+        transfer(&mut client, &mut server);
+        let io_state = server.process_new_packets().unwrap();
+        assert!(io_state.peer_has_closed());
+        check_read_and_close(&mut server.reader(), b"request");
 
-    fn has_certs(&self) -> bool {
-        true
+        // the server can still respond, and the client can still read it
+        server
+            .writer()
+            .write_all(b"response")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        check_read(&mut client.reader(), b"response");
+        assert_eq!(client.state(), ConnectionState::Closing);
+
+        server.send_close_notify();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert!(matches!(client.reader().read(&mut [0u8; 5]), Ok(0)));
+        assert_eq!(client.state(), ConnectionState::Closed);
+        assert_eq!(server.state(), ConnectionState::Closed);
     }
 }
 
 #[test]
-fn client_cert_resolve() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
-
-        let expected_issuers = match *kt {
-            KeyType::Rsa => vec![
-                b"0,1*0(\x06\x03U\x04\x03\x0c!ponytown RSA level 2 intermediate".to_vec(),
-                b"0\x1a1\x180\x16\x06\x03U\x04\x03\x0c\x0fponytown RSA CA".to_vec(),
-            ],
-            KeyType::Ecdsa => vec![
-                b"0.1,0*\x06\x03U\x04\x03\x0c#ponytown ECDSA level 2 intermediate".to_vec(),
-                b"0\x1c1\x1a0\x18\x06\x03U\x04\x03\x0c\x11ponytown ECDSA CA".to_vec(),
-            ],
-            KeyType::Ed25519 => vec![
-                b"0.1,0*\x06\x03U\x04\x03\x0c#ponytown EdDSA level 2 intermediate".to_vec(),
-                b"0\x1c1\x1a0\x18\x06\x03U\x04\x03\x0c\x11ponytown EdDSA CA".to_vec(),
-            ],
-        };
+fn server_closes_uncleanly() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
 
-        for version in rustls::ALL_VERSIONS {
-            let expected_sigschemes = match version.version {
-                ProtocolVersion::TLSv1_2 => vec![
-                    SignatureScheme::ECDSA_NISTP384_SHA384,
-                    SignatureScheme::ECDSA_NISTP256_SHA256,
-                    SignatureScheme::ED25519,
-                    SignatureScheme::RSA_PSS_SHA512,
-                    SignatureScheme::RSA_PSS_SHA384,
-                    SignatureScheme::RSA_PSS_SHA256,
-                    SignatureScheme::RSA_PKCS1_SHA512,
-                    SignatureScheme::RSA_PKCS1_SHA384,
-                    SignatureScheme::RSA_PKCS1_SHA256,
-                ],
-                ProtocolVersion::TLSv1_3 => vec![
-                    SignatureScheme::ECDSA_NISTP384_SHA384,
-                    SignatureScheme::ECDSA_NISTP256_SHA256,
-                    SignatureScheme::ED25519,
-                    SignatureScheme::RSA_PSS_SHA512,
-                    SignatureScheme::RSA_PSS_SHA384,
-                    SignatureScheme::RSA_PSS_SHA256,
-                ],
-                _ => unreachable!(),
-            };
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-            println!("{:?} {:?}:", version.version, *kt);
+        // check that unclean EOF reporting does not overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
+        );
 
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(
-                1,
-                expected_issuers.clone(),
-                expected_sigschemes,
-            ));
+        transfer(&mut server, &mut client);
+        transfer_eof(&mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        check_read(&mut client.reader(), b"from-server!");
 
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        check_read_err(
+            &mut client.reader() as &mut dyn io::Read,
+            io::ErrorKind::UnexpectedEof,
+        );
 
-            assert_eq!(
-                do_handshake_until_error(&mut client, &mut server),
-                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
-            );
-        }
+        // may still transmit pending frames
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"from-client!");
     }
 }
 
 #[test]
-fn client_auth_works() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn client_closes_uncleanly() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
-        }
-    }
-}
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-#[test]
-fn client_mandatory_auth_revocation_works() {
-    for kt in ALL_KEY_TYPES.iter() {
-        // Create a server configuration that includes a CRL that specifies the client certificate
-        // is revoked.
-        let crls = vec![kt.client_crl()];
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth_crls(
-            *kt, crls,
-        ));
+        // check that unclean EOF reporting does not overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
+        );
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            // Because the client certificate is revoked, the handshake should fail.
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert_eq!(
-                err,
-                Err(ErrorFromPeer::Server(Error::InvalidCertificate(
-                    CertificateError::Revoked
-                )))
-            );
-        }
-    }
-}
+        transfer(&mut client, &mut server);
+        transfer_eof(&mut server);
+        let io_state = server.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        check_read(&mut server.reader(), b"from-client!");
 
-#[test]
-fn client_optional_auth_revocation_works() {
-    for kt in ALL_KEY_TYPES.iter() {
-        // Create a server configuration that includes a CRL that specifies the client certificate
-        // is revoked.
-        let crls = vec![kt.client_crl()];
-        let server_config = Arc::new(make_server_config_with_optional_client_auth(*kt, crls));
+        check_read_err(
+            &mut server.reader() as &mut dyn io::Read,
+            io::ErrorKind::UnexpectedEof,
+        );
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            // Because the client certificate is revoked, the handshake should fail.
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert_eq!(
-                err,
-                Err(ErrorFromPeer::Server(Error::InvalidCertificate(
-                    CertificateError::Revoked
-                )))
-            );
-        }
+        // may still transmit pending frames
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"from-server!");
     }
 }
 
 #[test]
-fn client_error_is_sticky() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    client
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = client.process_new_packets();
-    assert!(err.is_err());
-    err = client.process_new_packets();
-    assert!(err.is_err());
-}
+fn clean_and_truncated_closes_are_distinguishable() {
+    for version in rustls::ALL_VERSIONS {
+        // close_notify followed by TCP EOF: a clean close.
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+        server
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        server.send_close_notify();
+        transfer(&mut server, &mut client);
+        transfer_eof(&mut client);
+        client.process_new_packets().unwrap();
+        check_read_and_close(&mut client.reader(), b"hello");
+        assert!(matches!(client.reader().read(&mut [0u8; 5]), Ok(0)));
 
-#[test]
-fn server_error_is_sticky() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    server
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = server.process_new_packets();
-    assert!(err.is_err());
-    err = server.process_new_packets();
-    assert!(err.is_err());
+        // TCP EOF alone: a possible truncation.
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+        server
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        transfer_eof(&mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"hello");
+        let err = client
+            .reader()
+            .read(&mut [0u8; 5])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("close_notify"));
+    }
 }
 
-#[test]
-fn server_flush_does_nothing() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.writer().flush(), Ok(())));
+#[derive(Default)]
+struct ServerCheckCertResolve {
+    expected_sni: Option<String>,
+    expected_sigalgs: Option<Vec<SignatureScheme>>,
+    expected_alpn: Option<Vec<Vec<u8>>>,
+    expected_cipher_suites: Option<Vec<CipherSuite>>,
 }
 
-#[test]
-fn client_flush_does_nothing() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.writer().flush(), Ok(())));
-}
+impl ResolvesServerCert for ServerCheckCertResolve {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        if client_hello
+            .signature_schemes()
+            .is_empty()
+        {
+            panic!("no signature schemes shared by client");
+        }
 
-#[test]
-fn server_is_send_and_sync() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    &server as &dyn Send;
-    &server as &dyn Sync;
-}
+        if client_hello.cipher_suites().is_empty() {
+            panic!("no cipher suites shared by client");
+        }
 
-#[test]
-fn client_is_send_and_sync() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    &client as &dyn Send;
-    &client as &dyn Sync;
-}
+        if let Some(expected_sni) = &self.expected_sni {
+            let sni: &str = client_hello
+                .server_name()
+                .expect("sni unexpectedly absent");
+            assert_eq!(expected_sni, sni);
+        }
 
-#[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+        if let Some(expected_sigalgs) = &self.expected_sigalgs {
+            assert_eq!(
+                expected_sigalgs,
+                client_hello.signature_schemes(),
+                "unexpected signature schemes"
+            );
+        }
 
-    server.set_buffer_limit(Some(32));
+        if let Some(expected_alpn) = &self.expected_alpn {
+            let alpn = client_hello
+                .alpn()
+                .expect("alpn unexpectedly absent")
+                .collect::<Vec<_>>();
+            assert_eq!(alpn.len(), expected_alpn.len());
 
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
-    );
+            for (got, wanted) in alpn.iter().zip(expected_alpn.iter()) {
+                assert_eq!(got, &wanted.as_slice());
+            }
+        }
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        if let Some(expected_cipher_suites) = &self.expected_cipher_suites {
+            assert_eq!(
+                expected_cipher_suites,
+                client_hello.cipher_suites(),
+                "unexpected cipher suites"
+            );
+        }
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+        None
+    }
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    server.set_buffer_limit(Some(32));
+fn server_cert_resolve_with_sni() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config(*kt);
+        let mut server_config = make_server_config(*kt);
 
-    assert_eq!(
-        server
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_sni: Some("the-value-from-sni".into()),
+            ..Default::default()
+        });
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("the-value-from-sni"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
+    }
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn server_cert_resolve_with_alpn() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.alpn_protocols = vec!["foo".into(), "bar".into()];
 
-    // this test will vary in behaviour depending on the default suites
-    do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(Some(48));
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_alpn: Some(vec![b"foo".to_vec(), b"bar".to_vec()]),
+            ..Default::default()
+        });
 
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
-    );
-
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("sni-value")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-    check_read(&mut client.reader(), b"01234567890123456789012345");
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
+    }
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    client.set_buffer_limit(Some(32));
+fn client_trims_terminating_dot() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config(*kt);
+        let mut server_config = make_server_config(*kt);
 
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
-    );
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_sni: Some("some-host.com".into()),
+            ..Default::default()
+        });
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("some-host.com.")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
+    }
 }
 
-#[test]
-fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    client.set_buffer_limit(Some(32));
-
-    assert_eq!(
-        client
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
+#[cfg(feature = "tls12")]
+fn check_sigalgs_reduced_by_ciphersuite(
+    kt: KeyType,
+    suite: CipherSuite,
+    expected_sigalgs: Vec<SignatureScheme>,
+) {
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::<Ring>::builder()
+            .with_cipher_suites(&[find_suite(suite)])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
             .unwrap(),
-        32
     );
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    let mut server_config = make_server_config(kt);
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
-}
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_sigalgs: Some(expected_sigalgs),
+        expected_cipher_suites: Some(vec![suite, CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV]),
+        ..Default::default()
+    });
 
-#[test]
-fn client_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-    do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(Some(48));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert!(err.is_err());
+}
 
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
+#[cfg(feature = "tls12")]
+#[test]
+fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
+    check_sigalgs_reduced_by_ciphersuite(
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        vec![
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA512,
+            SignatureScheme::RSA_PKCS1_SHA384,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ],
     );
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server.reader(), b"01234567890123456789012345");
 }
 
-struct OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    sess: &'a mut C,
-    pub reads: usize,
-    pub writevs: Vec<Vec<usize>>,
-    fail_ok: bool,
-    pub short_writes: bool,
-    pub last_error: Option<rustls::Error>,
+#[cfg(feature = "tls12")]
+#[test]
+fn server_cert_resolve_reduces_sigalgs_for_ecdsa_ciphersuite() {
+    check_sigalgs_reduced_by_ciphersuite(
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        vec![
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+        ],
+    );
 }
 
-impl<'a, C, S> OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        OtherSession {
-            sess,
-            reads: 0,
-            writevs: vec![],
-            fail_ok: false,
-            short_writes: false,
-            last_error: None,
-        }
+#[test]
+fn server_cert_resolve_by_signature_scheme() {
+    let mut resolver = rustls::server::ResolvesServerCertUsingSignatureScheme::new();
+    for kt in [KeyType::Rsa, KeyType::Ecdsa] {
+        resolver
+            .add(sign::CertifiedKey::new(
+                kt.get_chain(),
+                sign::any_supported_type(&kt.get_key()).unwrap(),
+            ))
+            .unwrap();
     }
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
 
-    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        let mut os = OtherSession::new(sess);
-        os.fail_ok = true;
-        os
+    let rsa_schemes = [
+        SignatureScheme::RSA_PSS_SHA256,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ];
+    let ecdsa_schemes = [
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+    ];
+    let both_schemes = [rsa_schemes, ecdsa_schemes].concat();
+
+    for version in rustls::ALL_VERSIONS {
+        for (schemes, expected) in [
+            (&rsa_schemes[..], KeyType::Rsa),
+            (&ecdsa_schemes[..], KeyType::Ecdsa),
+            (&both_schemes[..], KeyType::Ecdsa),
+        ] {
+            let client_config = make_client_config_with_versions(expected, &[version])
+                .with_signature_schemes(schemes)
+                .unwrap();
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.peer_certificates(), Some(&expected.get_chain()[..]));
+        }
     }
 }
 
-impl<'a, C, S> io::Read for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
+struct ServerCheckNoSNI {}
+
+impl ResolvesServerCert for ServerCheckNoSNI {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        assert!(client_hello.server_name().is_none());
+
+        None
     }
 }
 
-impl<'a, C, S> io::Write for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        unreachable!()
-    }
+#[test]
+fn client_with_sni_disabled_does_not_send_sni() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+        let server_config = Arc::new(server_config);
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.enable_sni = false;
 
-    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-        let mut total = 0;
-        let mut lengths = vec![];
-        for bytes in b {
-            let write_len = if self.short_writes {
-                if bytes.len() > 5 {
-                    bytes.len() / 2
-                } else {
-                    bytes.len()
-                }
-            } else {
-                bytes.len()
-            };
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), server_name("value-not-sent"))
+                    .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-            let l = self
-                .sess
-                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
-            lengths.push(l);
-            total += l;
-            if bytes.len() != l {
-                break;
-            }
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert!(err.is_err());
         }
+    }
+}
 
-        let rc = self.sess.process_new_packets();
-        if !self.fail_ok {
-            rc.unwrap();
-        } else if rc.is_err() {
-            self.last_error = rc.err();
-        }
+#[test]
+fn client_checks_server_certificate_with_given_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-        self.writevs.push(lengths);
-        Ok(total)
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut client = ClientConnection::new(
+                Arc::new(client_config),
+                server_name("not-the-right-hostname.com"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                )))
+            );
+        }
     }
 }
 
 #[test]
-fn server_read_returns_wouldblock_when_no_data() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
-}
+fn client_verifies_server_against_verification_name() {
+    // The test certificates stand in for "app.example": they are valid
+    // for testserver.com, but not for the CDN edge name sent in SNI.
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-#[test]
-fn client_read_returns_wouldblock_when_no_data() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
-}
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+            let mut client = ClientConnection::new_with_verification_name(
+                Arc::clone(&client_config),
+                server_name("edge.cdn.example"),
+                server_name("testserver.com"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.server_name(), Some("edge.cdn.example"));
 
-#[test]
-fn new_server_returns_initial_io_state() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let io_state = server.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert_eq!(io_state.tls_bytes_to_write(), 0);
+            // The SNI name alone is not enough.
+            let mut client = ClientConnection::new_with_verification_name(
+                client_config,
+                server_name("testserver.com"),
+                server_name("edge.cdn.example"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                )))
+            );
+            assert_eq!(server.server_name(), Some("testserver.com"));
+        }
+    }
 }
 
 #[test]
-fn new_client_returns_initial_io_state() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let io_state = client.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert!(io_state.tls_bytes_to_write() > 200);
-}
+fn client_checks_server_certificate_with_given_ip_address() {
+    fn check_server_name(
+        client_config: Arc<ClientConfig<Ring>>,
+        server_config: Arc<ServerConfig<Ring>>,
+        name: &'static str,
+    ) -> Result<(), ErrorFromPeer> {
+        let mut client = ClientConnection::new(client_config, server_name(name)).unwrap();
+        let mut server = ServerConnection::new(server_config).unwrap();
+        do_handshake_until_error(&mut client, &mut server)
+    }
 
-#[test]
-fn client_complete_io_for_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-    assert!(client.is_handshaking());
-    let (rdlen, wrlen) = client
-        .complete_io(&mut OtherSession::new(&mut server))
-        .unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert!(!client.is_handshaking());
-    assert!(!client.wants_write());
-}
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
 
-#[test]
-fn client_complete_io_for_handshake_eof() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
+            // positive ipv4 case
+            assert_eq!(
+                check_server_name(client_config.clone(), server_config.clone(), "198.51.100.1"),
+                Ok(()),
+            );
 
-    assert!(client.is_handshaking());
-    let err = client
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            // negative ipv4 case
+            assert_eq!(
+                check_server_name(client_config.clone(), server_config.clone(), "198.51.100.2"),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                )))
+            );
+
+            // positive ipv6 case
+            assert_eq!(
+                check_server_name(client_config.clone(), server_config.clone(), "2001:db8::1"),
+                Ok(()),
+            );
+
+            // negative ipv6 case
+            assert_eq!(
+                check_server_name(client_config.clone(), server_config.clone(), "2001:db8::2"),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                )))
+            );
+        }
+    }
 }
 
 #[test]
-fn client_complete_io_for_write() {
+fn client_omits_sni_for_ip_address() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
-
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            println!("{:?}", pipe.writevs);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+            let mut client =
+                ClientConnection::new(client_config, server_name("198.51.100.1")).unwrap();
+            let mut server = ServerConnection::new(Arc::new(make_server_config(*kt))).unwrap();
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.server_name(), None);
         }
-        check_read(
-            &mut server.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
     }
 }
 
 #[test]
-fn client_complete_io_for_read() {
+fn client_rejects_dns_only_certificate_for_ip_address() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let server_config = Arc::new(
+            ServerConfig::<Ring>::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(kt.get_dns_only_chain(), kt.get_key())
+                .unwrap(),
+        );
 
-        do_handshake(&mut client, &mut server);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
+            let mut client =
+                ClientConnection::new(client_config.clone(), server_name("testserver.com"))
+                    .unwrap();
+            let mut server = ServerConnection::new(server_config.clone()).unwrap();
+            assert_eq!(do_handshake_until_error(&mut client, &mut server), Ok(()));
+
+            let mut client =
+                ClientConnection::new(client_config, server_name("198.51.100.1")).unwrap();
+            let mut server = ServerConnection::new(server_config.clone()).unwrap();
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                )))
+            );
         }
-        check_read(&mut client.reader(), b"01234567890123456789");
     }
 }
 
-#[test]
-fn server_complete_io_for_handshake() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+#[derive(Debug)]
+struct FixedTime(UnixTime);
 
-        assert!(server.is_handshaking());
-        let (rdlen, wrlen) = server
-            .complete_io(&mut OtherSession::new(&mut client))
-            .unwrap();
-        assert!(rdlen > 0 && wrlen > 0);
-        assert!(!server.is_handshaking());
-        assert!(!server.wants_write());
+impl FixedTime {
+    fn at(secs: u64) -> Arc<Self> {
+        Arc::new(Self(UnixTime::since_unix_epoch(Duration::from_secs(secs))))
     }
 }
 
-#[test]
-fn server_complete_io_for_handshake_eof() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
-
-    assert!(server.is_handshaking());
-    let err = server
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+impl TimeProvider for FixedTime {
+    fn current_time(&self) -> Option<UnixTime> {
+        Some(self.0)
+    }
 }
 
 #[test]
-fn server_complete_io_for_write() {
+fn client_checks_server_certificate_validity_using_time_provider() {
+    // 1970, and the year 3000.
+    let cases = [
+        (0, CertificateError::NotValidYet),
+        (32_503_680_000, CertificateError::Expired),
+    ];
+
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let server_config = Arc::new(make_server_config(*kt));
 
-        do_handshake(&mut client, &mut server);
+        for (secs, expected) in cases.iter() {
+            let mut client_config = make_client_config(*kt);
+            client_config.time_provider = FixedTime::at(*secs);
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    expected.clone()
+                )))
+            );
         }
-        check_read(
-            &mut client.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
     }
 }
 
 #[test]
-fn server_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn client_expires_sessions_using_time_provider() {
+    let kt = KeyType::Rsa;
+    let now = DefaultTimeProvider
+        .current_time()
+        .unwrap()
+        .as_secs();
 
-        do_handshake(&mut client, &mut server);
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.time_provider = FixedTime::at(now);
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
-        }
-        check_read(&mut server.reader(), b"01234567890123456789");
-    }
-}
+    let mut server_config = make_server_config(kt);
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
 
-#[test]
-fn client_stream_write() {
-    test_client_stream_write(StreamKind::Ref);
-    test_client_stream_write(StreamKind::Owned);
-}
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 2);
 
-#[test]
-fn server_stream_write() {
-    test_server_stream_write(StreamKind::Ref);
-    test_server_stream_write(StreamKind::Owned);
+    // A week later, the server's tickets (valid for a day) are not offered.
+    client_config.time_provider = FixedTime::at(now + 7 * 24 * 60 * 60);
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(storage.puts(), 4);
 }
 
-#[derive(Debug, Copy, Clone)]
-enum StreamKind {
-    Owned,
-    Ref,
+struct ClientCheckCertResolve {
+    query_count: AtomicUsize,
+    expect_queries: usize,
+    expect_issuers: Vec<Vec<u8>>,
+    expect_sigschemes: Vec<SignatureScheme>,
 }
 
-fn test_client_stream_write(stream_kind: StreamKind) {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-        let data = b"hello";
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream: Box<dyn Write> = match stream_kind {
-                StreamKind::Ref => Box::new(Stream::new(&mut client, &mut pipe)),
-                StreamKind::Owned => Box::new(StreamOwned::new(client, pipe)),
-            };
-            assert_eq!(stream.write(data).unwrap(), 5);
+impl ClientCheckCertResolve {
+    fn new(
+        expect_queries: usize,
+        expect_issuers: Vec<Vec<u8>>,
+        expect_sigschemes: Vec<SignatureScheme>,
+    ) -> Self {
+        Self {
+            query_count: AtomicUsize::new(0),
+            expect_queries,
+            expect_issuers,
+            expect_sigschemes,
         }
-        check_read(&mut server.reader(), data);
     }
 }
 
-fn test_server_stream_write(stream_kind: StreamKind) {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-        let data = b"hello";
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream: Box<dyn Write> = match stream_kind {
-                StreamKind::Ref => Box::new(Stream::new(&mut server, &mut pipe)),
-                StreamKind::Owned => Box::new(StreamOwned::new(server, pipe)),
-            };
-            assert_eq!(stream.write(data).unwrap(), 5);
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let count = self.query_count.load(Ordering::SeqCst);
+            assert_eq!(count, self.expect_queries);
         }
-        check_read(&mut client.reader(), data);
-    }
-}
-
-#[test]
-fn client_stream_read() {
-    test_client_stream_read(StreamKind::Ref, ReadKind::Buf);
-    test_client_stream_read(StreamKind::Owned, ReadKind::Buf);
-    #[cfg(read_buf)]
-    {
-        test_client_stream_read(StreamKind::Ref, ReadKind::BorrowedBuf);
-        test_client_stream_read(StreamKind::Owned, ReadKind::BorrowedBuf);
-    }
-}
-
-#[test]
-fn server_stream_read() {
-    test_server_stream_read(StreamKind::Ref, ReadKind::Buf);
-    test_server_stream_read(StreamKind::Owned, ReadKind::Buf);
-    #[cfg(read_buf)]
-    {
-        test_server_stream_read(StreamKind::Ref, ReadKind::BorrowedBuf);
-        test_server_stream_read(StreamKind::Owned, ReadKind::BorrowedBuf);
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-enum ReadKind {
-    Buf,
-    #[cfg(read_buf)]
-    BorrowedBuf,
-}
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        self.query_count
+            .fetch_add(1, Ordering::SeqCst);
 
-fn test_stream_read(read_kind: ReadKind, mut stream: impl Read, data: &[u8]) {
-    match read_kind {
-        ReadKind::Buf => {
-            check_read(&mut stream, data);
-            check_read_err(&mut stream, io::ErrorKind::UnexpectedEof)
-        }
-        #[cfg(read_buf)]
-        ReadKind::BorrowedBuf => {
-            check_read_buf(&mut stream, data);
-            check_read_buf_err(&mut stream, io::ErrorKind::UnexpectedEof)
+        if acceptable_issuers.is_empty() {
+            panic!("no issuers offered by server");
         }
-    }
-}
 
-fn test_client_stream_read(stream_kind: StreamKind, read_kind: ReadKind) {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-        let data = b"world";
-        server.writer().write_all(data).unwrap();
+        if sigschemes.is_empty() {
+            panic!("no signature schemes shared by server");
+        }
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            transfer_eof(&mut client);
+        assert_eq!(acceptable_issuers, self.expect_issuers);
+        assert_eq!(sigschemes, self.expect_sigschemes);
 
-            let stream: Box<dyn Read> = match stream_kind {
-                StreamKind::Ref => Box::new(Stream::new(&mut client, &mut pipe)),
-                StreamKind::Owned => Box::new(StreamOwned::new(client, pipe)),
-            };
+        None
+    }
 
-            test_stream_read(read_kind, stream, data)
-        }
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
-fn test_server_stream_read(stream_kind: StreamKind, read_kind: ReadKind) {
+#[test]
+fn client_cert_resolve() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-        let data = b"world";
-        client.writer().write_all(data).unwrap();
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            transfer_eof(&mut server);
+        let expected_issuers = match *kt {
+            KeyType::Rsa => vec![
+                b"0,1*0(\x06\x03U\x04\x03\x0c!ponytown RSA level 2 intermediate".to_vec(),
+                b"0\x1a1\x180\x16\x06\x03U\x04\x03\x0c\x0fponytown RSA CA".to_vec(),
+            ],
+            KeyType::Ecdsa => vec![
+                b"0.1,0*\x06\x03U\x04\x03\x0c#ponytown ECDSA level 2 intermediate".to_vec(),
+                b"0\x1c1\x1a0\x18\x06\x03U\x04\x03\x0c\x11ponytown ECDSA CA".to_vec(),
+            ],
+            KeyType::Ed25519 => vec![
+                b"0.1,0*\x06\x03U\x04\x03\x0c#ponytown EdDSA level 2 intermediate".to_vec(),
+                b"0\x1c1\x1a0\x18\x06\x03U\x04\x03\x0c\x11ponytown EdDSA CA".to_vec(),
+            ],
+        };
 
-            let stream: Box<dyn Read> = match stream_kind {
-                StreamKind::Ref => Box::new(Stream::new(&mut server, &mut pipe)),
-                StreamKind::Owned => Box::new(StreamOwned::new(server, pipe)),
+        for version in rustls::ALL_VERSIONS {
+            let expected_sigschemes = match version.version {
+                ProtocolVersion::TLSv1_2 => vec![
+                    SignatureScheme::ECDSA_NISTP384_SHA384,
+                    SignatureScheme::ECDSA_NISTP256_SHA256,
+                    SignatureScheme::ED25519,
+                    SignatureScheme::RSA_PSS_SHA512,
+                    SignatureScheme::RSA_PSS_SHA384,
+                    SignatureScheme::RSA_PSS_SHA256,
+                    SignatureScheme::RSA_PKCS1_SHA512,
+                    SignatureScheme::RSA_PKCS1_SHA384,
+                    SignatureScheme::RSA_PKCS1_SHA256,
+                ],
+                ProtocolVersion::TLSv1_3 => vec![
+                    SignatureScheme::ECDSA_NISTP384_SHA384,
+                    SignatureScheme::ECDSA_NISTP256_SHA256,
+                    SignatureScheme::ED25519,
+                    SignatureScheme::RSA_PSS_SHA512,
+                    SignatureScheme::RSA_PSS_SHA384,
+                    SignatureScheme::RSA_PSS_SHA256,
+                ],
+                _ => unreachable!(),
             };
 
-            test_stream_read(read_kind, stream, data)
-        }
-    }
-}
+            println!("{:?} {:?}:", version.version, *kt);
 
-struct FailsWrites {
-    errkind: io::ErrorKind,
-    after: usize,
-}
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(
+                1,
+                expected_issuers.clone(),
+                expected_sigschemes,
+            ));
 
-impl io::Read for FailsWrites {
-    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
-        Ok(0)
-    }
-}
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
 
-impl io::Write for FailsWrites {
-    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
-        if self.after > 0 {
-            self.after -= 1;
-            Ok(b.len())
-        } else {
-            Err(io::Error::new(self.errkind, "oops"))
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+            );
         }
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+#[test]
+fn client_auth_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
     }
 }
 
 #[test]
-fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
+fn client_mandatory_auth_revocation_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // Create a server configuration that includes a CRL that specifies the client certificate
+        // is revoked.
+        let crls = vec![kt.client_crl()];
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth_crls(
+            *kt, crls,
+        ));
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 0,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
-        .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert!(rc.is_err());
-    let err = rc.err().unwrap();
-    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            // Because the client certificate is revoked, the handshake should fail.
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                    CertificateError::Revoked
+                )))
+            );
+        }
+    }
 }
 
 #[test]
-fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
+fn client_optional_auth_revocation_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // Create a server configuration that includes a CRL that specifies the client certificate
+        // is revoked.
+        let crls = vec![kt.client_crl()];
+        let server_config = Arc::new(make_server_config_with_optional_client_auth(*kt, crls));
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 1,
-    };
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            // Because the client certificate is revoked, the handshake should fail.
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                    CertificateError::Revoked
+                )))
+            );
+        }
+    }
+}
+
+#[test]
+fn client_error_is_sticky() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
     client
-        .writer()
-        .write_all(b"hello")
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
         .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert_eq!(format!("{:?}", rc), "Ok(5)");
+    let mut err = client.process_new_packets();
+    assert!(err.is_err());
+    err = client.process_new_packets();
+    assert!(err.is_err());
 }
 
-fn make_disjoint_suite_configs() -> (ClientConfig<Ring>, ServerConfig<Ring>) {
-    let kt = KeyType::Rsa;
-    let server_config = finish_server_config(
-        kt,
-        ServerConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
+#[test]
+fn server_error_is_sticky() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    server
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .unwrap();
+    let mut err = server.process_new_packets();
+    assert!(err.is_err());
+    err = server.process_new_packets();
+    assert!(err.is_err());
+}
 
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
+#[test]
+fn server_flush_does_nothing() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.writer().flush(), Ok(())));
+}
 
-    (client_config, server_config)
+#[test]
+fn client_flush_does_nothing() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.writer().flush(), Ok(())));
 }
 
 #[test]
-fn client_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+fn server_is_send_and_sync() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    &server as &dyn Send;
+    &server as &dyn Sync;
+}
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut server);
-        let mut client_stream = Stream::new(&mut client, &mut pipe);
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
-    }
+#[test]
+fn client_is_send_and_sync() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    &client as &dyn Send;
+    &client as &dyn Sync;
 }
 
 #[test]
-fn client_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+fn server_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    server.set_buffer_limit(Some(32));
 
-    let pipe = OtherSession::new_fails(&mut server);
-    let mut client_stream = StreamOwned::new(client, pipe);
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
     assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
     );
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
     assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
     );
-}
-
-#[test]
-fn server_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut client);
-        let mut server_stream = Stream::new(&mut server, &mut pipe);
-        let mut bytes = [0u8; 5];
-        let rc = server_stream.read(&mut bytes);
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: PeerIncompatible(NoCipherSuitesInCommon) })"
-        );
-    }
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn server_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, server) = make_pair_for_configs(client_config, server_config);
+fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+    server.set_buffer_limit(Some(32));
 
-    let pipe = OtherSession::new_fails(&mut client);
-    let mut server_stream = StreamOwned::new(server, pipe);
-    let mut bytes = [0u8; 5];
-    let rc = server_stream.read(&mut bytes);
-    assert!(rc.is_err());
     assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: PeerIncompatible(NoCipherSuitesInCommon) })"
+        server
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
     );
-}
-
-#[test]
-fn server_config_is_clone() {
-    let _ = make_server_config(KeyType::Rsa);
-}
-
-#[test]
-fn client_config_is_clone() {
-    let _ = make_client_config(KeyType::Rsa);
-}
 
-#[test]
-fn client_connection_is_debug() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    println!("{:?}", client);
-}
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-#[test]
-fn server_connection_is_debug() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    println!("{:?}", server);
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn server_complete_io_for_handshake_ending_with_alert() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+fn server_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    assert!(server.is_handshaking());
+    // this test will vary in behaviour depending on the default suites
+    do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(Some(48));
 
-    let mut pipe = OtherSession::new_fails(&mut client);
-    let rc = server.complete_io(&mut pipe);
-    assert!(rc.is_err(), "server io failed due to handshake failure");
-    assert!(!server.wants_write(), "but server did send its alert");
     assert_eq!(
-        format!("{:?}", pipe.last_error),
-        "Some(AlertReceived(HandshakeFailure))",
-        "which was received by client"
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
     );
-}
 
-#[test]
-fn server_exposes_offered_sni() {
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client = ClientConnection::new(
-            Arc::new(client_config),
-            server_name("second.testserver.com"),
-        )
-        .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-        assert_eq!(None, server.server_name());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.server_name());
-    }
+    check_read(&mut client.reader(), b"01234567890123456789012345");
 }
 
 #[test]
-fn server_exposes_offered_sni_smashed_to_lowercase() {
-    // webpki actually does this for us in its DnsName type
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client = ClientConnection::new(
-            Arc::new(client_config),
-            server_name("SECOND.TESTServer.com"),
-        )
-        .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
-
-        assert_eq!(None, server.server_name());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.server_name());
-    }
-}
+fn client_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-#[test]
-fn server_exposes_offered_sni_even_if_resolver_fails() {
-    let kt = KeyType::Rsa;
-    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    client.set_buffer_limit(Some(32));
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
+    );
 
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("thisdoesNOTexist.com"))
-                .unwrap();
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-        assert_eq!(None, server.server_name());
-        transfer(&mut client, &mut server);
-        assert_eq!(
-            server.process_new_packets(),
-            Err(Error::General(
-                "no server certificate chain resolved".to_string()
-            ))
-        );
-        assert_eq!(Some("thisdoesnotexist.com"), server.server_name());
-    }
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn sni_resolver_works() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-    resolver
-        .add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
-        )
-        .unwrap();
-
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+    client.set_buffer_limit(Some(32));
 
-    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client2 = ClientConnection::new(
-        Arc::new(make_client_config(kt)),
-        server_name("notlocalhost"),
-    )
-    .unwrap();
-    let err = do_handshake_until_error(&mut client2, &mut server2);
     assert_eq!(
-        err,
-        Err(ErrorFromPeer::Server(Error::General(
-            "no server certificate chain resolved".into()
-        )))
+        client
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
     );
-}
 
-#[test]
-fn sni_resolver_rejects_wrong_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General(
-            "The server certificate is not valid for the given name".into()
-        )),
-        resolver.add(
-            "not-localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General("Bad DNS name".into())),
-        resolver.add(
-            "not ascii 🦀",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn sni_resolver_lower_cases_configured_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(48));
 
     assert_eq!(
-        Ok(()),
-        resolver.add(
-            "LOCALHOST",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
     );
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+    check_read(&mut server.reader(), b"01234567890123456789012345");
 }
 
-#[test]
-fn sni_resolver_lower_cases_queried_names() {
-    // actually, the handshake parser does this, but the effect is the same.
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
+struct OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    sess: &'a mut C,
+    pub reads: usize,
+    pub writevs: Vec<Vec<usize>>,
+    fail_ok: bool,
+    pub short_writes: bool,
+    pub last_error: Option<rustls::Error>,
+}
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+impl<'a, C, S> OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        OtherSession {
+            sess,
+            reads: 0,
+            writevs: vec![],
+            fail_ok: false,
+            short_writes: false,
+            last_error: None,
+        }
+    }
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("LOCALHOST")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        let mut os = OtherSession::new(sess);
+        os.fail_ok = true;
+        os
+    }
 }
 
-#[test]
-fn sni_resolver_rejects_bad_certs() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+impl<'a, C, S> io::Read for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
+    }
+}
 
-    assert_eq!(
-        Err(Error::General(
-            "No end-entity certificate in certificate chain".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(vec![], signing_key.clone())
-        )
-    );
+impl<'a, C, S> io::Write for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        unreachable!()
+    }
 
-    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
-    assert_eq!(
-        Err(Error::General(
-            "End-entity certificate in certificate chain is syntactically invalid".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(bad_chain, signing_key.clone())
-        )
-    );
-}
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 
-fn do_exporter_test(
-    client_config: ClientConfig<impl CryptoProvider>,
-    server_config: ServerConfig<impl CryptoProvider>,
-) {
-    let mut client_secret = [0u8; 64];
-    let mut server_secret = [0u8; 64];
+    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+        let mut total = 0;
+        let mut lengths = vec![];
+        for bytes in b {
+            let write_len = if self.short_writes {
+                if bytes.len() > 5 {
+                    bytes.len() / 2
+                } else {
+                    bytes.len()
+                }
+            } else {
+                bytes.len()
+            };
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            let l = self
+                .sess
+                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
+            lengths.push(l);
+            total += l;
+            if bytes.len() != l {
+                break;
+            }
+        }
 
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
-    );
-    do_handshake(&mut client, &mut server);
+        let rc = self.sess.process_new_packets();
+        if !self.fail_ok {
+            rc.unwrap();
+        } else if rc.is_err() {
+            self.last_error = rc.err();
+        }
 
-    assert!(client
-        .export_keying_material(&mut client_secret, b"label", Some(b"context"))
-        .is_ok());
-    assert!(server
-        .export_keying_material(&mut server_secret, b"label", Some(b"context"))
-        .is_ok());
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+        self.writevs.push(lengths);
+        Ok(total)
+    }
+}
 
-    assert!(client
-        .export_keying_material(&mut client_secret, b"label", None)
-        .is_ok());
-    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
-    assert!(server
-        .export_keying_material(&mut server_secret, b"label", None)
-        .is_ok());
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+#[test]
+fn server_read_returns_wouldblock_when_no_data() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn test_tls12_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
-        let server_config = make_server_config(*kt);
+fn client_read_returns_wouldblock_when_no_data() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+}
 
-        do_exporter_test(client_config, server_config);
-    }
+#[test]
+fn new_server_returns_initial_io_state() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let io_state = server.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert_eq!(io_state.tls_bytes_to_write(), 0);
+    assert_eq!(io_state.needs_more(), None);
 }
 
 #[test]
-fn test_tls13_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
-        let server_config = make_server_config(*kt);
+fn new_client_returns_initial_io_state() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let io_state = client.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert!(io_state.tls_bytes_to_write() > 200);
+}
 
-        do_exporter_test(client_config, server_config);
+#[test]
+fn server_needs_more_for_client_hello_fed_one_byte_at_a_time() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let mut client_hello = Vec::new();
+    client
+        .write_tls(&mut client_hello)
+        .unwrap();
+
+    let (last, partial) = client_hello.split_last().unwrap();
+    for byte in partial {
+        assert_eq!(
+            server
+                .read_tls(&mut &[*byte][..])
+                .unwrap(),
+            1
+        );
+        let io_state = server.process_new_packets().unwrap();
+        assert!(io_state.needs_more().is_some());
+        assert_eq!(io_state.tls_bytes_to_write(), 0);
     }
-}
 
-fn do_suite_test(
-    client_config: ClientConfig<impl CryptoProvider>,
-    server_config: ServerConfig<impl CryptoProvider>,
-    expect_suite: SupportedCipherSuite,
-    expect_version: ProtocolVersion,
-) {
-    println!(
-        "do_suite_test {:?} {:?}",
-        expect_version,
-        expect_suite.suite()
+    assert_eq!(
+        server
+            .read_tls(&mut &[*last][..])
+            .unwrap(),
+        1
     );
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(None, server.negotiated_cipher_suite());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(None, server.protocol_version());
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
+    let io_state = server.process_new_packets().unwrap();
+    assert_eq!(io_state.needs_more(), None);
+    assert!(io_state.tls_bytes_to_write() > 0);
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+#[test]
+fn client_complete_io_for_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
     assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+    let (rdlen, wrlen) = client
+        .complete_io(&mut OtherSession::new(&mut server))
+        .unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert!(!client.is_handshaking());
+    assert!(!client.wants_write());
+}
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+#[test]
+fn client_complete_io_for_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
 
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+    assert!(client.is_handshaking());
+    let err = client
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+#[test]
+fn client_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-    assert!(!client.is_handshaking());
-    assert!(!server.is_handshaking());
-    assert_eq!(Some(expect_version), client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
-}
+        do_handshake(&mut client, &mut server);
 
-fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
-    for scs in ALL_CIPHER_SUITES.iter().copied() {
-        if scs.suite() == suite {
-            return scs;
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            println!("{:?}", pipe.writevs);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
         }
+        check_read(
+            &mut server.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
     }
-
-    panic!("find_suite given unsupported suite");
 }
 
-static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_256_GCM_SHA384,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-    ),
-];
+#[test]
+fn client_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut client.reader(), b"01234567890123456789");
+    }
+}
 
 #[test]
-fn negotiated_ciphersuite_default() {
+fn server_complete_io_for_handshake() {
     for kt in ALL_KEY_TYPES.iter() {
-        do_suite_test(
-            make_client_config(*kt),
-            make_server_config(*kt),
-            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
-            ProtocolVersion::TLSv1_3,
-        );
+        let (mut client, mut server) = make_pair(*kt);
+
+        assert!(server.is_handshaking());
+        let (rdlen, wrlen) = server
+            .complete_io(&mut OtherSession::new(&mut client))
+            .unwrap();
+        assert!(rdlen > 0 && wrlen > 0);
+        assert!(!server.is_handshaking());
+        assert!(!server.wants_write());
     }
 }
 
 #[test]
-fn all_suites_covered() {
-    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+fn server_complete_io_for_handshake_eof() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
+
+    assert!(server.is_handshaking());
+    let err = server
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
 }
 
 #[test]
-fn negotiated_ciphersuite_client() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let client_config = finish_client_config(
-            kt,
-            ClientConfig::<Ring>::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+fn server_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut client.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
     }
 }
 
 #[test]
-fn negotiated_ciphersuite_server() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let server_config = finish_server_config(
-            kt,
-            ServerConfig::<Ring>::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+fn server_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+        do_handshake(&mut client, &mut server);
+
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut server.reader(), b"01234567890123456789");
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct KeyLogItem {
-    label: String,
-    client_random: Vec<u8>,
-    secret: Vec<u8>,
+#[test]
+fn client_stream_write() {
+    test_client_stream_write(StreamKind::Ref);
+    test_client_stream_write(StreamKind::Owned);
 }
 
-struct KeyLogToVec {
-    label: &'static str,
-    items: Mutex<Vec<KeyLogItem>>,
+#[test]
+fn server_stream_write() {
+    test_server_stream_write(StreamKind::Ref);
+    test_server_stream_write(StreamKind::Owned);
 }
 
-impl KeyLogToVec {
-    fn new(who: &'static str) -> Self {
-        Self {
-            label: who,
-            items: Mutex::new(vec![]),
+#[derive(Debug, Copy, Clone)]
+enum StreamKind {
+    Owned,
+    Ref,
+}
+
+fn test_client_stream_write(stream_kind: StreamKind) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        let data = b"hello";
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream: Box<dyn Write> = match stream_kind {
+                StreamKind::Ref => Box::new(Stream::new(&mut client, &mut pipe)),
+                StreamKind::Owned => Box::new(StreamOwned::new(client, pipe)),
+            };
+            assert_eq!(stream.write(data).unwrap(), 5);
         }
+        check_read(&mut server.reader(), data);
     }
+}
 
-    fn take(&self) -> Vec<KeyLogItem> {
-        std::mem::take(&mut self.items.lock().unwrap())
+fn test_server_stream_write(stream_kind: StreamKind) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        let data = b"hello";
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream: Box<dyn Write> = match stream_kind {
+                StreamKind::Ref => Box::new(Stream::new(&mut server, &mut pipe)),
+                StreamKind::Owned => Box::new(StreamOwned::new(server, pipe)),
+            };
+            assert_eq!(stream.write(data).unwrap(), 5);
+        }
+        check_read(&mut client.reader(), data);
     }
 }
 
-impl KeyLog for KeyLogToVec {
-    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
-        let value = KeyLogItem {
-            label: label.into(),
-            client_random: client.into(),
-            secret: secret.into(),
-        };
-
-        println!("key log {:?}: {:?}", self.label, value);
-
-        self.items.lock().unwrap().push(value);
+#[test]
+fn client_stream_read() {
+    test_client_stream_read(StreamKind::Ref, ReadKind::Buf);
+    test_client_stream_read(StreamKind::Owned, ReadKind::Buf);
+    #[cfg(read_buf)]
+    {
+        test_client_stream_read(StreamKind::Ref, ReadKind::BorrowedBuf);
+        test_client_stream_read(StreamKind::Owned, ReadKind::BorrowedBuf);
     }
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn key_log_for_tls12() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
-
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
+fn server_stream_read() {
+    test_server_stream_read(StreamKind::Ref, ReadKind::Buf);
+    test_server_stream_read(StreamKind::Owned, ReadKind::Buf);
+    #[cfg(read_buf)]
+    {
+        test_server_stream_read(StreamKind::Ref, ReadKind::BorrowedBuf);
+        test_server_stream_read(StreamKind::Owned, ReadKind::BorrowedBuf);
+    }
+}
 
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
-    let server_config = Arc::new(server_config);
-
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
-
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
-    assert_eq!(client_full_log, server_full_log);
-    assert_eq!(1, client_full_log.len());
-    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
-
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[derive(Debug, Copy, Clone)]
+enum ReadKind {
+    Buf,
+    #[cfg(read_buf)]
+    BorrowedBuf,
+}
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
-    assert_eq!(client_resume_log, server_resume_log);
-    assert_eq!(1, client_resume_log.len());
-    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
-    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+fn test_stream_read(read_kind: ReadKind, mut stream: impl Read, data: &[u8]) {
+    match read_kind {
+        ReadKind::Buf => {
+            check_read(&mut stream, data);
+            check_read_err(&mut stream, io::ErrorKind::UnexpectedEof)
+        }
+        #[cfg(read_buf)]
+        ReadKind::BorrowedBuf => {
+            check_read_buf(&mut stream, data);
+            check_read_buf_err(&mut stream, io::ErrorKind::UnexpectedEof)
+        }
+    }
 }
 
-#[test]
-fn key_log_for_tls13() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+fn test_client_stream_read(stream_kind: StreamKind, read_kind: ReadKind) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        let data = b"world";
+        server.writer().write_all(data).unwrap();
 
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            transfer_eof(&mut client);
 
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
-    let server_config = Arc::new(server_config);
+            let stream: Box<dyn Read> = match stream_kind {
+                StreamKind::Ref => Box::new(Stream::new(&mut client, &mut pipe)),
+                StreamKind::Owned => Box::new(StreamOwned::new(client, pipe)),
+            };
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+            test_stream_read(read_kind, stream, data)
+        }
+    }
+}
 
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
+fn test_server_stream_read(stream_kind: StreamKind, read_kind: ReadKind) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        let data = b"world";
+        client.writer().write_all(data).unwrap();
 
-    assert_eq!(5, client_full_log.len());
-    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
-    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            transfer_eof(&mut server);
 
-    assert_eq!(client_full_log[0], server_full_log[0]);
-    assert_eq!(client_full_log[1], server_full_log[1]);
-    assert_eq!(client_full_log[2], server_full_log[2]);
-    assert_eq!(client_full_log[3], server_full_log[3]);
-    assert_eq!(client_full_log[4], server_full_log[4]);
+            let stream: Box<dyn Read> = match stream_kind {
+                StreamKind::Ref => Box::new(Stream::new(&mut server, &mut pipe)),
+                StreamKind::Owned => Box::new(StreamOwned::new(server, pipe)),
+            };
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+            test_stream_read(read_kind, stream, data)
+        }
+    }
+}
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
+struct FailsWrites {
+    errkind: io::ErrorKind,
+    after: usize,
+}
 
-    assert_eq!(5, client_resume_log.len());
-    assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[0].label
-    );
-    assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[1].label
-    );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+impl io::Read for FailsWrites {
+    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
 
-    assert_eq!(6, server_resume_log.len());
-    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
-    assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[1].label
-    );
-    assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[2].label
-    );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
-    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
+impl io::Write for FailsWrites {
+    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+        if self.after > 0 {
+            self.after -= 1;
+            Ok(b.len())
+        } else {
+            Err(io::Error::new(self.errkind, "oops"))
+        }
+    }
 
-    assert_eq!(client_resume_log[0], server_resume_log[1]);
-    assert_eq!(client_resume_log[1], server_resume_log[2]);
-    assert_eq!(client_resume_log[2], server_resume_log[3]);
-    assert_eq!(client_resume_log[3], server_resume_log[4]);
-    assert_eq!(client_resume_log[4], server_resume_log[5]);
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 #[test]
-fn vectored_write_for_server_appdata() {
+fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 0,
+    };
+    client
         .writer()
-        .write_all(b"01234567890123456789")
+        .write_all(b"hello")
         .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut client.reader(),
-        b"0123456789012345678901234567890123456789",
-    );
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert!(rc.is_err());
+    let err = rc.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
 }
 
 #[test]
-fn vectored_write_for_client_appdata() {
+fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
 
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 1,
+    };
     client
         .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
+        .write_all(b"hello")
         .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut server.reader(),
-        b"0123456789012345678901234567890123456789",
-    );
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert_eq!(format!("{:?}", rc), "Ok(5)");
 }
 
-#[test]
-fn vectored_write_for_server_handshake_with_half_rtt_data() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.send_half_rtt_data = true;
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+fn make_disjoint_suite_configs() -> (ClientConfig<Ring>, ServerConfig<Ring>) {
+    let kt = KeyType::Rsa;
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
-    }
+    (client_config, server_config)
+}
 
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // 4 tickets
-        assert_eq!(wrlen, 103 * 4);
-        assert_eq!(pipe.writevs, vec![vec![103, 103, 103, 103]]);
-    }
-
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
-}
+#[test]
+fn no_cipher_suites_in_common() {
+    fn check(client_suites: &[SupportedCipherSuite], server_suites: &[SupportedCipherSuite]) {
+        let client_config: ClientConfig<Ring> = finish_client_config(
+            KeyType::Rsa,
+            ClientConfig::builder()
+                .with_cipher_suites(client_suites)
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap(),
+        );
+        let server_config: ServerConfig<Ring> = finish_server_config(
+            KeyType::Rsa,
+            ServerConfig::builder()
+                .with_cipher_suites(server_suites)
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap(),
+        );
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-fn check_half_rtt_does_not_work(server_config: ServerConfig<impl CryptoProvider>) {
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerIncompatible(
+                PeerIncompatible::NoCipherSuitesInCommon
+            ))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            ))
+        );
+    }
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    check(
+        &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384],
+        &[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256],
+    );
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    #[cfg(feature = "tls12")]
     {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
+        check(
+            &[rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384],
+            &[rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256],
+        );
+
+        // A suite in common, but the server's RSA certificate cannot be
+        // used with it.
+        check(
+            &[rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384],
+            &[rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384],
+        );
     }
+}
 
-    // client second flight
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
+#[test]
+fn client_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
-    // it to an unauthenticated peer. so it happens here, in the server's second
-    // flight (42 and 32 are lengths of appdata sent above).
-    server.process_new_packets().unwrap();
     {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 486);
-        assert_eq!(pipe.writevs, vec![vec![103, 103, 103, 103, 42, 32]]);
+        let mut pipe = OtherSession::new_fails(&mut server);
+        let mut client_stream = Stream::new(&mut client, &mut pipe);
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
     }
-
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
 }
 
 #[test]
-fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
-    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
-    server_config.send_half_rtt_data = true; // ask even though it will be ignored
-    check_half_rtt_does_not_work(server_config);
-}
+fn client_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (client, mut server) = make_pair_for_configs(client_config, server_config);
 
-#[test]
-fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
-    let server_config = make_server_config(KeyType::Rsa);
-    assert!(!server_config.send_half_rtt_data);
-    check_half_rtt_does_not_work(server_config);
+    let pipe = OtherSession::new_fails(&mut server);
+    let mut client_stream = StreamOwned::new(client, pipe);
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
 }
 
 #[test]
-fn vectored_write_for_client_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn server_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
     client
         .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"0123456789")
+        .write_all(b"world")
         .unwrap();
+
     {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 200); // just the client hello
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 1); // only a client hello
+        let mut pipe = OtherSession::new_fails(&mut client);
+        let mut server_stream = Stream::new(&mut server, &mut pipe);
+        let mut bytes = [0u8; 5];
+        let rc = server_stream.read(&mut bytes);
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: PeerIncompatible(NoCipherSuitesInCommon) })"
+        );
     }
+}
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+#[test]
+fn server_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, server) = make_pair_for_configs(client_config, server_config);
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 154);
-        // CCS, finished, then two application datas
-        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
-    }
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut server.reader(), b"012345678901234567890123456789");
+    let pipe = OtherSession::new_fails(&mut client);
+    let mut server_stream = StreamOwned::new(server, pipe);
+    let mut bytes = [0u8; 5];
+    let rc = server_stream.read(&mut bytes);
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: PeerIncompatible(NoCipherSuitesInCommon) })"
+    );
 }
 
 #[test]
-fn vectored_write_with_slow_client() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn handshake_error_source_is_certificate_error() {
+    use std::error::Error as StdError;
 
-    client.set_buffer_limit(Some(32));
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config(KeyType::Ecdsa),
+        make_server_config(KeyType::Rsa),
+    );
+    let err = match do_handshake_until_error(&mut client, &mut server) {
+        Err(ErrorFromPeer::Client(err)) => err,
+        other => panic!("unexpected result {:?}", other),
+    };
 
-    do_handshake(&mut client, &mut server);
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
+    // e.g. as an application would see it, through `Stream`.
+    let err = io::Error::new(io::ErrorKind::InvalidData, err);
+    let err = err
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<Error>()
         .unwrap();
+    assert_eq!(
+        err.source()
+            .unwrap()
+            .downcast_ref::<CertificateError>(),
+        Some(&CertificateError::UnknownIssuer)
+    );
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        pipe.short_writes = true;
-        let wrlen = server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap();
-        assert_eq!(42, wrlen);
+    assert!(Error::FailedToGetRandomBytes
+        .source()
+        .unwrap()
+        .is::<rustls::crypto::GetRandomFailed>());
+}
+
+#[test]
+fn client_config_from_template() {
+    fn roots() -> rustls::RootCertStore {
+        let mut roots = rustls::RootCertStore::empty();
+        let mut rootbuf = io::BufReader::new(KeyType::Rsa.bytes_for("ca.cert"));
+        roots.add_parsable_certificates(rustls_pemfile::certs(&mut rootbuf).unwrap());
+        roots
+    }
+
+    let template = rustls::ConfigTemplate {
+        cipher_suites: vec!["TLS_CHACHA20_POLY1305_SHA256".to_string()],
+        kx_groups: vec!["secp256r1".to_string()],
+        versions: vec!["TLSv1.3".to_string()],
+        alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+    };
+    let client_config = ClientConfig::<Ring>::from_template(&template, roots()).unwrap();
+    assert_eq!(
+        client_config.alpn_protocols,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.negotiated_cipher_suite(),
+        Some(rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256)
+    );
+    assert_eq!(
+        client.negotiated_key_exchange_group(),
+        Some(rustls::NamedGroup::secp256r1)
+    );
+    assert_eq!(client.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    // empty lists mean the defaults.
+    let client_config =
+        ClientConfig::<Ring>::from_template(&rustls::ConfigTemplate::default(), roots()).unwrap();
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    for (template, expected) in [
+        (
+            rustls::ConfigTemplate {
+                cipher_suites: vec!["TLS13_CHACHA20_POLY1305_SHA256".to_string()],
+                ..Default::default()
+            },
+            "unknown cipher suite \"TLS13_CHACHA20_POLY1305_SHA256\"",
+        ),
+        (
+            rustls::ConfigTemplate {
+                kx_groups: vec!["X25519".to_string()],
+                ..Default::default()
+            },
+            "unknown key exchange group \"X25519\"",
+        ),
+        (
+            rustls::ConfigTemplate {
+                versions: vec!["TLSv1_3".to_string()],
+                ..Default::default()
+            },
+            "unknown protocol version \"TLSv1_3\"",
+        ),
+    ] {
         assert_eq!(
-            pipe.writevs,
-            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
+            ClientConfig::<Ring>::from_template(&template, roots()).err(),
+            Some(Error::General(expected.to_string()))
         );
     }
-    check_read(&mut client.reader(), b"01234567890123456789");
 }
 
-struct ServerStorage {
-    storage: Arc<dyn rustls::server::StoresServerSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    take_count: AtomicUsize,
+#[test]
+fn server_config_is_clone() {
+    let _ = make_server_config(KeyType::Rsa);
 }
 
-impl ServerStorage {
-    fn new() -> Self {
-        Self {
-            storage: rustls::server::ServerSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            take_count: AtomicUsize::new(0),
-        }
-    }
-
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
-    }
-    fn takes(&self) -> usize {
-        self.take_count.load(Ordering::SeqCst)
-    }
+#[test]
+fn client_config_is_clone() {
+    let _ = make_client_config(KeyType::Rsa);
 }
 
-impl fmt::Debug for ServerStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(put: {:?}, get: {:?}, take: {:?})",
-            self.put_count, self.get_count, self.take_count
-        )
-    }
+#[test]
+fn client_connection_is_debug() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    println!("{:?}", client);
 }
 
-impl rustls::server::StoresServerSessions for ServerStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.put(key, value)
-    }
-
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
-    }
-
-    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.take_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.take(key)
-    }
-
-    fn can_cache(&self) -> bool {
-        true
-    }
-}
-
-#[derive(Debug, Clone)]
-enum ClientStorageOp {
-    SetKxHint(rustls::ServerName, rustls::NamedGroup),
-    GetKxHint(rustls::ServerName, Option<rustls::NamedGroup>),
-    SetTls12Session(rustls::ServerName),
-    GetTls12Session(rustls::ServerName, bool),
-    RemoveTls12Session(rustls::ServerName),
-    InsertTls13Ticket(rustls::ServerName),
-    TakeTls13Ticket(rustls::ServerName, bool),
-}
-
-struct ClientStorage {
-    storage: Arc<dyn rustls::client::ClientSessionStore>,
-    ops: Mutex<Vec<ClientStorageOp>>,
+#[test]
+fn server_connection_is_debug() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    println!("{:?}", server);
 }
 
-impl ClientStorage {
-    fn new() -> Self {
-        Self {
-            storage: Arc::new(rustls::client::ClientSessionMemoryCache::new(1024)),
-            ops: Mutex::new(Vec::new()),
-        }
-    }
+#[test]
+fn server_complete_io_for_handshake_ending_with_alert() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    #[cfg(feature = "tls12")]
-    fn ops(&self) -> Vec<ClientStorageOp> {
-        self.ops.lock().unwrap().clone()
-    }
+    assert!(server.is_handshaking());
 
-    #[cfg(feature = "tls12")]
-    fn ops_and_reset(&self) -> Vec<ClientStorageOp> {
-        std::mem::take(&mut self.ops.lock().unwrap())
-    }
+    let mut pipe = OtherSession::new_fails(&mut client);
+    let rc = server.complete_io(&mut pipe);
+    assert!(rc.is_err(), "server io failed due to handshake failure");
+    assert!(!server.wants_write(), "but server did send its alert");
+    assert_eq!(
+        format!("{:?}", pipe.last_error),
+        "Some(AlertReceived(HandshakeFailure))",
+        "which was received by client"
+    );
 }
 
-impl fmt::Debug for ClientStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "(ops: {:?})", self.ops.lock().unwrap())
-    }
-}
+#[test]
+fn server_exposes_offered_sni() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client = ClientConnection::new(
+            Arc::new(client_config),
+            server_name("second.testserver.com"),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
 
-impl rustls::client::ClientSessionStore for ClientStorage {
-    fn set_kx_hint(&self, server_name: &rustls::ServerName, group: rustls::NamedGroup) {
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::SetKxHint(server_name.clone(), group));
-        self.storage
-            .set_kx_hint(server_name, group)
+        assert_eq!(None, server.server_name());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.server_name());
     }
+}
 
-    fn kx_hint(&self, server_name: &rustls::ServerName) -> Option<rustls::NamedGroup> {
-        let rc = self.storage.kx_hint(server_name);
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::GetKxHint(server_name.clone(), rc));
-        rc
-    }
+#[test]
+fn server_exposes_offered_sni_smashed_to_lowercase() {
+    // webpki actually does this for us in its DnsName type
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client = ClientConnection::new(
+            Arc::new(client_config),
+            server_name("SECOND.TESTServer.com"),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
 
-    fn set_tls12_session(
-        &self,
-        server_name: &rustls::ServerName,
-        value: rustls::client::Tls12ClientSessionValue,
-    ) {
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::SetTls12Session(server_name.clone()));
-        self.storage
-            .set_tls12_session(server_name, value)
+        assert_eq!(None, server.server_name());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.server_name());
     }
+}
 
-    fn tls12_session(
-        &self,
-        server_name: &rustls::ServerName,
-    ) -> Option<rustls::client::Tls12ClientSessionValue> {
-        let rc = self.storage.tls12_session(server_name);
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::GetTls12Session(
-                server_name.clone(),
-                rc.is_some(),
-            ));
-        rc
-    }
+#[test]
+fn server_exposes_offered_sni_even_if_resolver_fails() {
+    let kt = KeyType::Rsa;
+    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
 
-    fn remove_tls12_session(&self, server_name: &rustls::ServerName) {
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::RemoveTls12Session(server_name.clone()));
-        self.storage
-            .remove_tls12_session(server_name);
-    }
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
 
-    fn insert_tls13_ticket(
-        &self,
-        server_name: &rustls::ServerName,
-        value: rustls::client::Tls13ClientSessionValue,
-    ) {
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::InsertTls13Ticket(server_name.clone()));
-        self.storage
-            .insert_tls13_ticket(server_name, value);
-    }
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("thisdoesNOTexist.com"))
+                .unwrap();
 
-    fn take_tls13_ticket(
-        &self,
-        server_name: &rustls::ServerName,
-    ) -> Option<rustls::client::Tls13ClientSessionValue> {
-        let rc = self
-            .storage
-            .take_tls13_ticket(server_name);
-        self.ops
-            .lock()
-            .unwrap()
-            .push(ClientStorageOp::TakeTls13Ticket(
-                server_name.clone(),
-                rc.is_some(),
-            ));
-        rc
+        assert_eq!(None, server.server_name());
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets(),
+            Err(Error::General(
+                "no server certificate chain resolved".to_string()
+            ))
+        );
+        assert_eq!(Some("thisdoesnotexist.com"), server.server_name());
     }
 }
 
 #[test]
-fn tls13_stateful_resumption() {
+fn sni_resolver_works() {
     let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    resolver
+        .add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
 
     let mut server_config = make_server_config(kt);
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
+    server_config.cert_resolver = Arc::new(resolver);
     let server_config = Arc::new(server_config);
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 4);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+
+    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client2 = ClientConnection::new(
+        Arc::new(make_client_config(kt)),
+        server_name("notlocalhost"),
+    )
+    .unwrap();
+    let err = do_handshake_until_error(&mut client2, &mut server2);
     assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
+        err,
+        Err(ErrorFromPeer::Server(Error::General(
+            "no server certificate chain resolved".into()
+        )))
     );
+}
+
+#[test]
+fn sni_resolver_rejects_wrong_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 8);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 1);
     assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
     );
-
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 12);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 2);
     assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
+        Err(Error::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        resolver.add(
+            "not-localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General("Bad DNS name".into())),
+        resolver.add(
+            "not ascii 🦀",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
     );
 }
 
 #[test]
-fn tls13_stateless_resumption() {
+fn sni_resolver_lower_cases_configured_names() {
     let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
-
-    let mut server_config = make_server_config(kt);
-    server_config.ticketer = rustls::Ticketer::new().unwrap();
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
     assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
+        Ok(()),
+        resolver.add(
+            "LOCALHOST",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
     );
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
 }
 
 #[test]
-fn early_data_not_available() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(client.early_data().is_none());
-}
-
-fn early_data_configs() -> (Arc<ClientConfig<Ring>>, Arc<ServerConfig<Ring>>) {
+fn sni_resolver_lower_cases_queried_names() {
+    // actually, the handshake parser does this, but the effect is the same.
     let kt = KeyType::Rsa;
-    let mut client_config = make_client_config(kt);
-    client_config.enable_early_data = true;
-    client_config.resumption = Resumption::store(Arc::new(ClientStorage::new()));
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
 
     let mut server_config = make_server_config(kt);
-    server_config.max_early_data_size = 1234;
-    (Arc::new(client_config), Arc::new(server_config))
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), server_name("LOCALHOST")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
 }
 
 #[test]
-fn early_data_is_available_on_resumption() {
-    let (client_config, server_config) = early_data_configs();
-
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+fn sni_resolver_rejects_bad_certs() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
-    client
-        .early_data()
-        .unwrap()
-        .flush()
-        .unwrap();
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
+        Err(Error::General(
+            "No end-entity certificate in certificate chain".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(vec![], signing_key.clone())
+        )
     );
-    do_handshake(&mut client, &mut server);
 
-    let mut received_early_data = [0u8; 5];
+    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
     assert_eq!(
-        server
-            .early_data()
-            .expect("early_data didn't happen")
-            .read(&mut received_early_data)
-            .expect("early_data failed unexpectedly"),
-        5
+        Err(Error::General(
+            "End-entity certificate in certificate chain is syntactically invalid".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(bad_chain, signing_key.clone())
+        )
     );
-    assert_eq!(&received_early_data[..], b"hello");
 }
 
-#[test]
-fn early_data_not_available_on_server_before_client_hello() {
-    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
-    assert!(server.early_data().is_none());
-}
-
-#[test]
-fn early_data_can_be_rejected_by_server() {
-    let (client_config, server_config) = early_data_configs();
+fn do_exporter_test(
+    client_config: ClientConfig<impl CryptoProvider>,
+    server_config: ServerConfig<impl CryptoProvider>,
+) {
+    let mut client_secret = [0u8; 64];
+    let mut server_secret = [0u8; 64];
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
+        Err(Error::HandshakeNotComplete),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
     );
-    client
-        .early_data()
-        .unwrap()
-        .flush()
-        .unwrap();
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
+        Err(Error::HandshakeNotComplete),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
     );
-    server.reject_early_data();
     do_handshake(&mut client, &mut server);
 
-    assert!(!client.is_early_data_accepted());
-}
+    assert!(client
+        .export_keying_material(&mut client_secret, b"label", Some(b"context"))
+        .is_ok());
+    assert!(server
+        .export_keying_material(&mut server_secret, b"label", Some(b"context"))
+        .is_ok());
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
 
-#[cfg(feature = "quic")]
-mod test_quic {
-    use super::*;
-    use rustls::quic::{self, ConnectionCommon};
+    assert!(client
+        .export_keying_material(&mut client_secret, b"label", None)
+        .is_ok());
+    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
+    assert!(server
+        .export_keying_material(&mut server_secret, b"label", None)
+        .is_ok());
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+}
 
-    // Returns the sender's next secrets to use, or the receiver's error.
-    fn step<L: SideData, R: SideData>(
-        send: &mut ConnectionCommon<L>,
-        recv: &mut ConnectionCommon<R>,
-    ) -> Result<Option<quic::KeyChange>, Error> {
-        let mut buf = Vec::new();
-        let change = loop {
-            let prev = buf.len();
-            if let Some(x) = send.write_hs(&mut buf) {
-                break Some(x);
-            }
-            if prev == buf.len() {
-                break None;
-            }
-        };
-        if let Err(e) = recv.read_hs(&buf) {
-            return Err(e);
-        } else {
-            assert_eq!(recv.alert(), None);
-        }
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        let server_config = make_server_config(*kt);
 
-        Ok(change)
+        do_exporter_test(client_config, server_config);
     }
+}
 
-    #[test]
-    fn test_quic_handshake() {
-        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
-            // Check that these two sets of keys are equal.
-            let mut buf = vec![0; 32];
-            let (header, payload_tag) = buf.split_at_mut(8);
-            let (payload, tag_buf) = payload_tag.split_at_mut(8);
-            let tag = x
-                .encrypt_in_place(42, &*header, payload)
-                .unwrap();
-            tag_buf.copy_from_slice(tag.as_ref());
-
-            let result = y.decrypt_in_place(42, &*header, payload_tag);
-            match result {
-                Ok(payload) => payload == &[0; 8],
-                Err(_) => false,
-            }
-        }
-
-        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
-            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
-                match kc {
-                    quic::KeyChange::Handshake { keys } => keys,
-                    quic::KeyChange::OneRtt { keys, .. } => keys,
-                }
-            }
-
-            let (x, y) = (keys(x), keys(y));
-            equal_packet_keys(&x.local.packet, &y.remote.packet)
-                && equal_packet_keys(&x.remote.packet, &y.local.packet)
-        }
+#[test]
+fn test_tls13_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        let server_config = make_server_config(*kt);
 
-        let kt = KeyType::Rsa;
-        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-        client_config.enable_early_data = true;
-        let client_config = Arc::new(client_config);
-        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-        server_config.max_early_data_size = 0xffffffff;
-        let server_config = Arc::new(server_config);
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+        do_exporter_test(client_config, server_config);
+    }
+}
 
-        // full handshake
-        let mut client = quic::ClientConnection::new(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            server_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
+fn do_suite_test(
+    client_config: ClientConfig<impl CryptoProvider>,
+    server_config: ServerConfig<impl CryptoProvider>,
+    expect_suite: SupportedCipherSuite,
+    expect_version: ProtocolVersion,
+) {
+    println!(
+        "do_suite_test {:?} {:?}",
+        expect_version,
+        expect_suite.suite()
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-        let mut server = quic::ServerConnection::new(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(None, server.negotiated_cipher_suite());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(None, server.protocol_version());
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
 
-        let client_initial = step(&mut client, &mut server).unwrap();
-        assert!(client_initial.is_none());
-        assert!(client.zero_rtt_keys().is_none());
-        assert_eq!(server.quic_transport_parameters(), Some(client_params));
-        let server_hs = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(server.zero_rtt_keys().is_none());
-        let client_hs = step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        assert!(compatible_keys(&server_hs, &client_hs));
-        assert!(client.is_handshaking());
-        let server_1rtt = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(!client.is_handshaking());
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        assert!(server.is_handshaking());
-        let client_1rtt = step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        assert!(!server.is_handshaking());
-        assert!(compatible_keys(&server_1rtt, &client_1rtt));
-        assert!(!compatible_keys(&server_hs, &server_1rtt));
-        assert!(step(&mut client, &mut server)
-            .unwrap()
-            .is_none());
-        assert!(step(&mut server, &mut client)
-            .unwrap()
-            .is_none());
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-        // 0-RTT handshake
-        let mut client = quic::ClientConnection::new(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            server_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
-        assert!(client
-            .negotiated_cipher_suite()
-            .is_some());
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
 
-        let mut server = quic::ServerConnection::new(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-        step(&mut client, &mut server).unwrap();
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        {
-            let client_early = client.zero_rtt_keys().unwrap();
-            let server_early = server.zero_rtt_keys().unwrap();
-            assert!(equal_packet_keys(
-                &client_early.packet,
-                &server_early.packet
-            ));
-        }
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(client.is_early_data_accepted());
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
 
-        // 0-RTT rejection
-        {
-            let client_config = (*client_config).clone();
-            let mut client = quic::ClientConnection::new(
-                Arc::new(client_config),
-                quic::Version::V1,
-                server_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-            let mut server = quic::ServerConnection::new(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
-            .unwrap();
-            server.reject_early_data();
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+    assert_eq!(Some(expect_version), client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+}
 
-            step(&mut client, &mut server).unwrap();
-            assert_eq!(client.quic_transport_parameters(), Some(server_params));
-            assert!(client.zero_rtt_keys().is_some());
-            assert!(server.zero_rtt_keys().is_none());
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            step(&mut client, &mut server)
-                .unwrap()
-                .unwrap();
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            assert!(!client.is_early_data_accepted());
+fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
+    for scs in ALL_CIPHER_SUITES.iter().copied() {
+        if scs.suite() == suite {
+            return scs;
         }
+    }
 
-        // failed handshake
-        let mut client = quic::ClientConnection::new(
-            client_config,
-            quic::Version::V1,
-            server_name("example.com"),
-            client_params.into(),
-        )
-        .unwrap();
-
-        let mut server =
-            quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
-                .unwrap();
+    panic!("find_suite given unsupported suite");
+}
 
-        step(&mut client, &mut server).unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(step(&mut server, &mut client).is_err());
-        assert_eq!(
-            client.alert(),
-            Some(rustls::AlertDescription::BadCertificate)
-        );
+static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+];
 
-        // Key updates
+#[test]
+fn negotiated_ciphersuite_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        do_suite_test(
+            make_client_config(*kt),
+            make_server_config(*kt),
+            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+            ProtocolVersion::TLSv1_3,
+        );
+    }
+}
 
-        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
-            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
-                (c, s)
-            }
-            _ => unreachable!(),
-        };
+#[test]
+fn all_suites_covered() {
+    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+}
 
-        let mut client_next = client_secrets.next_packet_keys();
-        let mut server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+#[test]
+fn negotiated_ciphersuite_client() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let client_config = finish_client_config(
+            kt,
+            ClientConfig::<Ring>::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
 
-        client_next = client_secrets.next_packet_keys();
-        server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+        do_suite_test(client_config, make_server_config(kt), scs, version.version);
     }
+}
 
-    #[test]
-    fn test_quic_rejects_missing_alpn() {
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
-
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let client_config = Arc::new(client_config);
-
-            let mut server_config =
-                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-            server_config.alpn_protocols = vec!["foo".into()];
-            let server_config = Arc::new(server_config);
+#[test]
+fn negotiated_ciphersuite_server() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let server_config = finish_server_config(
+            kt,
+            ServerConfig::<Ring>::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
 
-            let mut client = quic::ClientConnection::new(
-                client_config,
-                quic::Version::V1,
-                server_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
-            let mut server =
-                quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
-                    .unwrap();
+        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    }
+}
 
-            assert_eq!(
-                step(&mut client, &mut server)
-                    .err()
-                    .unwrap(),
-                Error::NoApplicationProtocol
-            );
+#[test]
+fn negotiated_ciphersuite_shared_server_config() {
+    // One server config, reused (and hence warmed) across connections
+    // negotiating every suite it supports.
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-            assert_eq!(
-                server.alert(),
-                Some(rustls::AlertDescription::NoApplicationProtocol)
-            );
+        for _ in 0..2 {
+            for item in TEST_CIPHERSUITES
+                .iter()
+                .filter(|item| item.1 == *kt)
+            {
+                let (version, _, suite) = *item;
+                let scs = find_suite(suite);
+                let client_config = Arc::new(finish_client_config(
+                    *kt,
+                    ClientConfig::<Ring>::builder()
+                        .with_cipher_suites(&[scs])
+                        .with_safe_default_kx_groups()
+                        .with_protocol_versions(&[version])
+                        .unwrap(),
+                ));
+
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&client_config, &server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(Some(scs), client.negotiated_cipher_suite());
+                assert_eq!(Some(scs), server.negotiated_cipher_suite());
+                assert_eq!(Some(version.version), server.protocol_version());
+            }
         }
     }
+}
 
-    #[cfg(feature = "tls12")]
-    #[test]
-    fn test_quic_no_tls13_error() {
-        let mut client_config =
-            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        client_config.alpn_protocols = vec!["foo".into()];
-        let client_config = Arc::new(client_config);
-
-        assert!(quic::ClientConnection::new(
-            client_config,
-            quic::Version::V1,
-            server_name("localhost"),
-            b"client params".to_vec(),
-        )
-        .is_err());
+#[derive(Debug, PartialEq)]
+struct KeyLogItem {
+    label: String,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
 
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+struct KeyLogToVec {
+    label: &'static str,
+    items: Mutex<Vec<KeyLogItem>>,
+}
 
-        assert!(quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .is_err());
+impl KeyLogToVec {
+    fn new(who: &'static str) -> Self {
+        Self {
+            label: who,
+            items: Mutex::new(vec![]),
+        }
     }
 
-    #[test]
-    fn test_quic_invalid_early_data_size() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
+    fn take(&self) -> Vec<KeyLogItem> {
+        std::mem::take(&mut self.items.lock().unwrap())
+    }
+}
 
-        let cases = [
-            (None, true),
-            (Some(0u32), true),
-            (Some(5), false),
-            (Some(0xffff_ffff), true),
-        ];
+impl KeyLog for KeyLogToVec {
+    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
+        let value = KeyLogItem {
+            label: label.into(),
+            client_random: client.into(),
+            secret: secret.into(),
+        };
 
-        for &(size, ok) in cases.iter() {
-            println!("early data size case: {:?}", size);
-            if let Some(new) = size {
-                server_config.max_early_data_size = new;
-            }
+        println!("key log {:?}: {:?}", self.label, value);
 
-            let wrapped = Arc::new(server_config.clone());
-            assert_eq!(
-                quic::ServerConnection::new(wrapped, quic::Version::V1, b"server params".to_vec(),)
-                    .is_ok(),
-                ok
-            );
-        }
+        self.items.lock().unwrap().push(value);
     }
+}
 
-    #[test]
-    fn test_quic_server_no_params_received() {
-        let server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        let server_config = Arc::new(server_config);
+#[cfg(feature = "tls12")]
+#[test]
+fn key_log_for_tls12() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
 
-        let mut server = quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .unwrap();
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{Compression, NamedGroup};
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
-        };
-        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+    assert_eq!(client_full_log, server_full_log);
+    assert_eq!(1, client_full_log.len());
+    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
 
-        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
-            typ: HandshakeType::ClientHello,
-            payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                client_version: ProtocolVersion::TLSv1_3,
-                random,
-                session_id: SessionId::random::<Ring>().unwrap(),
-                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                compression_methods: vec![Compression::Null],
-                extensions: vec![
-                    ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
-                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                    ClientExtension::KeyShare(vec![KeyShareEntry {
-                        group: NamedGroup::X25519,
-                        payload: PayloadU16::new(kx.as_ref().to_vec()),
-                    }]),
-                ],
-            }),
-        });
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-        let mut buf = Vec::with_capacity(512);
-        client_hello.encode(&mut buf);
-        assert_eq!(
-            server
-                .read_hs(&mut buf.as_slice())
-                .err(),
-            Some(Error::PeerMisbehaved(
-                PeerMisbehaved::MissingQuicTransportParameters
-            ))
-        );
-    }
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+    assert_eq!(client_resume_log, server_resume_log);
+    assert_eq!(1, client_resume_log.len());
+    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
+    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+}
 
-    #[test]
-    fn test_quic_server_no_tls12() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+#[test]
+fn key_log_for_tls13() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{Compression, NamedGroup};
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
-        };
-        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-        let mut server = quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .unwrap();
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
 
-        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
-            typ: HandshakeType::ClientHello,
-            payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                client_version: ProtocolVersion::TLSv1_2,
-                random: random.clone(),
-                session_id: SessionId::random::<Ring>().unwrap(),
-                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                compression_methods: vec![Compression::Null],
-                extensions: vec![
-                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                    ClientExtension::KeyShare(vec![KeyShareEntry {
-                        group: NamedGroup::X25519,
-                        payload: PayloadU16::new(kx.as_ref().to_vec()),
-                    }]),
-                ],
-            }),
-        });
+    assert_eq!(5, client_full_log.len());
+    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
+    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
 
-        let mut buf = Vec::with_capacity(512);
-        client_hello.encode(&mut buf);
-        assert_eq!(
-            server
-                .read_hs(&mut buf.as_slice())
-                .err(),
-            Some(Error::PeerIncompatible(
-                PeerIncompatible::SupportedVersionsExtensionRequired
-            )),
-        );
-    }
+    assert_eq!(client_full_log[0], server_full_log[0]);
+    assert_eq!(client_full_log[1], server_full_log[1]);
+    assert_eq!(client_full_log[2], server_full_log[2]);
+    assert_eq!(client_full_log[3], server_full_log[3]);
+    assert_eq!(client_full_log[4], server_full_log[4]);
 
-    #[test]
-    fn packet_key_api() {
-        use rustls::quic::{Keys, Version};
-        use rustls::Side;
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
-        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
-        const PACKET_NUMBER: u64 = 2;
-        const PLAIN_HEADER: &[u8] = &[
-            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
-        ];
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
 
-        const PAYLOAD: &[u8] = &[
-            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
-            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
-            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
-            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
-            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
-            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
-            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
-            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
-            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
-            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
-            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
-            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
-            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
-            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
-            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
-            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
-            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
-            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
-        ];
+    assert_eq!(5, client_resume_log.len());
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[0].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[1].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
 
-        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Client);
-        assert_eq!(
-            client_keys
-                .local
-                .packet
-                .confidentiality_limit(),
-            2u64.pow(23)
-        );
-        assert_eq!(
-            client_keys
-                .local
-                .packet
-                .integrity_limit(),
-            2u64.pow(52)
-        );
-        assert_eq!(client_keys.local.packet.tag_len(), 16);
+    assert_eq!(6, server_resume_log.len());
+    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[1].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[2].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
+    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
 
-        let mut buf = Vec::new();
-        buf.extend(PLAIN_HEADER);
-        buf.extend(PAYLOAD);
-        let header_len = PLAIN_HEADER.len();
-        let tag_len = client_keys.local.packet.tag_len();
-        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
-        buf.extend(std::iter::repeat(0).take(padding_len));
-        let (header, payload) = buf.split_at_mut(header_len);
-        let tag = client_keys
-            .local
-            .packet
-            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+    assert_eq!(client_resume_log[0], server_resume_log[1]);
+    assert_eq!(client_resume_log[1], server_resume_log[2]);
+    assert_eq!(client_resume_log[2], server_resume_log[3]);
+    assert_eq!(client_resume_log[3], server_resume_log[4]);
+    assert_eq!(client_resume_log[4], server_resume_log[5]);
+}
 
-        let sample_len = client_keys.local.header.sample_len();
-        let sample = &payload[..sample_len];
-        let (first, rest) = header.split_at_mut(1);
-        client_keys
-            .local
-            .header
-            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
-        buf.extend_from_slice(tag.as_ref());
+#[test]
+fn vectored_write_for_server_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-        const PROTECTED: &[u8] = &[
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut client.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_client_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut server.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn coalesced_write_for_client_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all_vectored(&[b"0123456789", b"0123456789", b"0123456789"])
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(52, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![52]]);
+    }
+    check_read(&mut server.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn coalesced_write_respects_max_fragment_size() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.max_fragment_size = Some(64);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    let data = [b'x'; 50];
+    client
+        .writer()
+        .write_all_vectored(&[&data, &data, &data])
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(150 + 3 * 22, wrlen);
+        // Each record holds as much plaintext as `max_fragment_size` allows
+        // (59 bytes, after the record header), rather than one buffer.
+        assert_eq!(pipe.writevs, vec![vec![59 + 22, 59 + 22, 32 + 22]]);
+    }
+    check_read(&mut server.reader(), &[b'x'; 150]);
+}
+
+#[test]
+fn connection_stats_count_traffic() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa)
+            .with_max_send_fragment(4096)
+            .unwrap();
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(server.stats(), rustls::ConnectionStats::default());
+        do_handshake(&mut client, &mut server);
+
+        let client_stats = client.stats();
+        let server_stats = server.stats();
+        let expected_round_trips = match version.version {
+            ProtocolVersion::TLSv1_3 => 1,
+            _ => 2,
+        };
+        assert_eq!(client_stats.handshake_round_trips, expected_round_trips);
+        assert_eq!(server_stats.handshake_round_trips, 1);
+        assert_eq!(client_stats.application_bytes_sent, 0);
+        assert_eq!(server_stats.application_bytes_received, 0);
+        assert!(client_stats.records_sent > 0);
+        assert_eq!(client_stats.records_sent, server_stats.records_received);
+        assert_eq!(server_stats.records_sent, client_stats.records_received);
+
+        // 10000 bytes fit in one of the client's records, but need three
+        // of the server's.
+        client
+            .writer()
+            .write_all(&[0x5a; 10_000])
+            .unwrap();
+        server
+            .writer()
+            .write_all(&[0xa5; 10_000])
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        let client_after = client.stats();
+        let server_after = server.stats();
+        assert_eq!(client_after.application_bytes_sent, 10_000);
+        assert_eq!(client_after.application_bytes_received, 10_000);
+        assert_eq!(server_after.application_bytes_sent, 10_000);
+        assert_eq!(server_after.application_bytes_received, 10_000);
+        assert_eq!(client_after.records_sent - client_stats.records_sent, 1);
+        assert_eq!(server_after.records_sent - server_stats.records_sent, 3);
+        assert_eq!(client_after.records_sent, server_after.records_received);
+        assert_eq!(server_after.records_sent, client_after.records_received);
+        assert_eq!(client_after.handshake_round_trips, expected_round_trips);
+        assert!(!client_after.key_updated);
+        assert!(!server_after.key_updated);
+    }
+}
+
+#[test]
+fn max_send_fragment_limits_record_plaintext() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version])
+            .with_max_send_fragment(1000)
+            .unwrap();
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        let data: Vec<u8> = (0..10_500u32)
+            .map(|i| i as u8)
+            .collect();
+        client
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            client.write_tls(&mut pipe).unwrap();
+
+            // Ten full records, then the remaining 500 bytes.  The per-record
+            // overhead depends on the protocol version.
+            let overhead = pipe.writevs[0][0] - 1000;
+            let mut expected = vec![1000 + overhead; 10];
+            expected.push(500 + overhead);
+            assert_eq!(pipe.writevs, vec![expected]);
+        }
+        check_read(&mut server.reader(), &data);
+    }
+
+    let client_config = make_client_config(KeyType::Rsa);
+    assert!(client_config
+        .clone()
+        .with_max_send_fragment(26)
+        .is_err());
+    assert_eq!(
+        client_config
+            .clone()
+            .with_max_send_fragment(27)
+            .unwrap()
+            .max_fragment_size,
+        Some(32)
+    );
+    assert!(client_config
+        .clone()
+        .with_max_send_fragment(16384)
+        .is_ok());
+    assert_eq!(
+        make_server_config(KeyType::Rsa)
+            .with_max_send_fragment(16385)
+            .err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+#[test]
+fn coalesced_write_fails_if_not_all_accepted() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(32));
+
+    let err = client
+        .writer()
+        .write_all_vectored(&[b"01234567890123456789", b"01234567890123456789"])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+    // Nothing was accepted, so the write can simply be retried.
+    assert!(!client.wants_write());
+    client.set_buffer_limit(Some(64));
+    client
+        .writer()
+        .write_all_vectored(&[b"01234567890123456789", b"01234567890123456789"])
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(
+        &mut server.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn plaintext_records_can_be_taken_individually() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.next_plaintext_record(), None);
+
+    server
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"world!")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(client.next_plaintext_record(), Some(&b"hello"[..]));
+    assert_eq!(client.next_plaintext_record(), Some(&b"world!"[..]));
+    assert_eq!(client.next_plaintext_record(), None);
+
+    // ... and after a partial read, the rest of the record
+    server
+        .writer()
+        .write_all(b"goodbye")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    let mut buf = [0u8; 4];
+    client
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(&buf, b"good");
+    assert_eq!(client.next_plaintext_record(), Some(&b"bye"[..]));
+}
+
+#[test]
+fn buffered_writes_for_client_appdata() {
+    for buffering in [0, 1024] {
+        let client_config = make_client_config(KeyType::Rsa).with_write_buffering(buffering);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        for _ in 0..3 {
+            client
+                .writer()
+                .write_all(b"0123456789")
+                .unwrap();
+        }
+        // Held-back writes wait for a flush.
+        assert_eq!(client.wants_write(), buffering == 0);
+        client.writer().flush().unwrap();
+        assert!(client.wants_write());
+
+        let mut pipe = OtherSession::new(&mut server);
+        client.write_tls(&mut pipe).unwrap();
+        match buffering {
+            0 => assert_eq!(pipe.writevs, vec![vec![32, 32, 32]]),
+            _ => assert_eq!(pipe.writevs, vec![vec![52]]),
+        }
+        check_read(&mut server.reader(), b"012345678901234567890123456789");
+    }
+}
+
+#[test]
+fn buffered_writes_are_sent_at_threshold() {
+    let server_config = make_server_config(KeyType::Rsa).with_write_buffering(25);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    assert!(!server.wants_write());
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    assert!(server.wants_write());
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+        assert_eq!(pipe.writevs, vec![vec![25 + 22]]);
+    }
+    check_read(&mut client.reader(), b"0123456789012345678901234");
+
+    // close_notify follows any held-back data
+    server.send_close_notify();
+    transfer(&mut server, &mut client);
+    assert!(client
+        .process_new_packets()
+        .unwrap()
+        .peer_has_closed());
+    check_read_and_close(&mut client.reader(), b"56789");
+}
+
+#[test]
+fn buffered_vectored_write_stops_at_threshold() {
+    let server_config = make_server_config(KeyType::Rsa).with_write_buffering(10);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+    do_handshake(&mut client, &mut server);
+
+    let bufs = [
+        IoSlice::new(b"01234567"),
+        IoSlice::new(b"89abcde"),
+        IoSlice::new(b"fgh"),
+    ];
+    let written = server
+        .writer()
+        .write_vectored(&bufs)
+        .unwrap();
+    assert_eq!(written, 10);
+    server.writer().flush().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"0123456789");
+}
+
+#[test]
+fn vectored_write_for_server_handshake_with_half_rtt_data() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_half_rtt_data = true;
+    // the sizes below assume an uncompressed certificate chain
+    server_config.cert_compressors.clear();
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // 2 tickets
+        assert_eq!(wrlen, 103 * 2);
+        assert_eq!(pipe.writevs, vec![vec![103, 103]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn half_rtt_data_is_received_after_handshake() {
+    for send_half_rtt_data in [false, true] {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.send_half_rtt_data = send_half_rtt_data;
+        let (mut client, mut server) =
+            make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        assert!(!server.is_sending_half_rtt_data());
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert!(server.is_handshaking());
+        assert_eq!(server.is_sending_half_rtt_data(), send_half_rtt_data);
+
+        server
+            .writer()
+            .write_all(b"half-rtt")
+            .unwrap();
+        let sent = transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert!(!client.is_handshaking());
+        if send_half_rtt_data {
+            // sent with the server's first flight, so it is waiting for
+            // the client as soon as the client completes the handshake.
+            check_read(&mut client.reader(), b"half-rtt");
+        } else {
+            assert!(sent > 0);
+            assert_eq!(
+                client
+                    .reader()
+                    .read(&mut [0u8; 8])
+                    .err()
+                    .unwrap()
+                    .kind(),
+                io::ErrorKind::WouldBlock
+            );
+        }
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert!(!server.is_handshaking());
+        assert!(!server.is_sending_half_rtt_data());
+
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        if !send_half_rtt_data {
+            check_read(&mut client.reader(), b"half-rtt");
+        }
+    }
+}
+
+fn check_half_rtt_does_not_work(mut server_config: ServerConfig<impl CryptoProvider>) {
+    // the sizes below assume an uncompressed certificate chain
+    server_config.cert_compressors.clear();
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
+    }
+
+    // client second flight
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+
+    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
+    // it to an unauthenticated peer. so it happens here, in the server's second
+    // flight (42 and 32 are lengths of appdata sent above).
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 280);
+        assert_eq!(pipe.writevs, vec![vec![103, 103, 42, 32]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
+    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+    server_config.send_half_rtt_data = true; // ask even though it will be ignored
+    check_half_rtt_does_not_work(server_config);
+}
+
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
+    let server_config = make_server_config(KeyType::Rsa);
+    assert!(!server_config.send_half_rtt_data);
+    check_half_rtt_does_not_work(server_config);
+}
+
+#[test]
+fn vectored_write_for_client_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 200); // just the client hello
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 1); // only a client hello
+    }
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 154);
+        // CCS, finished, then two application datas
+        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut server.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_with_slow_client() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client.set_buffer_limit(Some(32));
+
+    do_handshake(&mut client, &mut server);
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        pipe.short_writes = true;
+        let wrlen = server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap();
+        assert_eq!(42, wrlen);
+        assert_eq!(
+            pipe.writevs,
+            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
+        );
+    }
+    check_read(&mut client.reader(), b"01234567890123456789");
+}
+
+struct ServerStorage {
+    storage: Arc<dyn rustls::server::StoresServerSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    take_count: AtomicUsize,
+}
+
+impl ServerStorage {
+    fn new() -> Self {
+        Self {
+            storage: rustls::server::ServerSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            take_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+    fn takes(&self) -> usize {
+        self.take_count.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ServerStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(put: {:?}, get: {:?}, take: {:?})",
+            self.put_count, self.get_count, self.take_count
+        )
+    }
+}
+
+impl rustls::server::StoresServerSessions for ServerStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.take_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.take(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ClientStorageOp {
+    SetKxHint(rustls::ServerName, rustls::NamedGroup),
+    GetKxHint(rustls::ServerName, Option<rustls::NamedGroup>),
+    SetTls12Session(rustls::ServerName),
+    GetTls12Session(rustls::ServerName, bool),
+    RemoveTls12Session(rustls::ServerName),
+    InsertTls13Ticket(rustls::ServerName),
+    TakeTls13Ticket(rustls::ServerName, bool),
+}
+
+struct ClientStorage {
+    storage: Arc<dyn rustls::client::ClientSessionStore>,
+    ops: Mutex<Vec<ClientStorageOp>>,
+}
+
+impl ClientStorage {
+    fn new() -> Self {
+        Self {
+            storage: Arc::new(rustls::client::ClientSessionMemoryCache::new(1024)),
+            ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn ops(&self) -> Vec<ClientStorageOp> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    fn ops_and_reset(&self) -> Vec<ClientStorageOp> {
+        std::mem::take(&mut self.ops.lock().unwrap())
+    }
+}
+
+impl fmt::Debug for ClientStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(ops: {:?})", self.ops.lock().unwrap())
+    }
+}
+
+impl rustls::client::ClientSessionStore for ClientStorage {
+    fn set_kx_hint(&self, server_name: &rustls::ServerName, group: rustls::NamedGroup) {
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::SetKxHint(server_name.clone(), group));
+        self.storage
+            .set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &rustls::ServerName) -> Option<rustls::NamedGroup> {
+        let rc = self.storage.kx_hint(server_name);
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::GetKxHint(server_name.clone(), rc));
+        rc
+    }
+
+    fn set_tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+        value: rustls::client::Tls12ClientSessionValue,
+    ) {
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::SetTls12Session(server_name.clone()));
+        self.storage
+            .set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls12ClientSessionValue> {
+        let rc = self.storage.tls12_session(server_name);
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::GetTls12Session(
+                server_name.clone(),
+                rc.is_some(),
+            ));
+        rc
+    }
+
+    fn remove_tls12_session(&self, server_name: &rustls::ServerName) {
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::RemoveTls12Session(server_name.clone()));
+        self.storage
+            .remove_tls12_session(server_name);
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+        value: rustls::client::Tls13ClientSessionValue,
+    ) {
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::InsertTls13Ticket(server_name.clone()));
+        self.storage
+            .insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls13ClientSessionValue> {
+        let rc = self
+            .storage
+            .take_tls13_ticket(server_name);
+        self.ops
+            .lock()
+            .unwrap()
+            .push(ClientStorageOp::TakeTls13Ticket(
+                server_name.clone(),
+                rc.is_some(),
+            ));
+        rc
+    }
+}
+
+#[test]
+fn tls13_stateful_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 2);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 4);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 1);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 6);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 2);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+#[test]
+fn tls13_stateless_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+#[test]
+fn peers_agree_on_transcript_hash_and_tls_unique() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let server_config = Arc::new(make_server_config(kt));
+
+        let mut seen = Vec::new();
+        // full handshake, then resumed
+        for _ in 0..2 {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            assert_eq!(client.transcript_hash(), None);
+            assert_eq!(server.transcript_hash(), None);
+
+            do_handshake(&mut client, &mut server);
+            let hash = client.transcript_hash().unwrap();
+            assert_eq!(server.transcript_hash(), Some(hash.clone()));
+            let hash_len = match client
+                .negotiated_cipher_suite()
+                .unwrap()
+                .hash_algorithm()
+            {
+                HashAlgorithm::SHA256 => 32,
+                HashAlgorithm::SHA384 => 48,
+                _ => unreachable!(),
+            };
+            assert_eq!(hash.len(), hash_len);
+
+            let unique = client.tls_unique();
+            assert_eq!(server.tls_unique(), unique);
+            match version.version {
+                ProtocolVersion::TLSv1_3 => assert_eq!(unique, None),
+                _ => assert_eq!(unique.unwrap().len(), 12),
+            }
+
+            seen.push(hash);
+        }
+        assert_ne!(seen[0], seen[1]);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ObservedEvent {
+    Complete(ProtocolVersion, bool),
+    Failed,
+    Resumption(ResumptionKind),
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    events: Mutex<Vec<ObservedEvent>>,
+}
+
+impl RecordingObserver {
+    fn take(&self) -> Vec<ObservedEvent> {
+        mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl HandshakeObserver for RecordingObserver {
+    fn on_handshake_complete(&self, params: &HandshakeParams) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(ObservedEvent::Complete(
+                params.protocol_version,
+                params.resumed,
+            ));
+    }
+
+    fn on_handshake_failed(&self, _error: &Error) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(ObservedEvent::Failed);
+    }
+
+    fn on_resumption(&self, kind: ResumptionKind) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(ObservedEvent::Resumption(kind));
+    }
+}
+
+#[test]
+fn handshake_observer_sees_full_and_resumed_handshakes() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let kind = match version.version {
+            ProtocolVersion::TLSv1_3 => ResumptionKind::Tls13Psk,
+            _ => ResumptionKind::Tls12SessionId,
+        };
+        let client_observer = Arc::new(RecordingObserver::default());
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.resumption =
+            Resumption::default().tls12_resumption(Tls12Resumption::SessionIdOnly);
+        client_config.handshake_observer = Some(client_observer.clone());
+        let client_config = Arc::new(client_config);
+
+        let server_observer = Arc::new(RecordingObserver::default());
+        let mut server_config = make_server_config(kt);
+        server_config.handshake_observer = Some(server_observer.clone());
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        let expected = vec![ObservedEvent::Complete(version.version, false)];
+        assert_eq!(client_observer.take(), expected);
+        assert_eq!(server_observer.take(), expected);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            client_observer.take(),
+            vec![
+                ObservedEvent::Resumption(kind),
+                ObservedEvent::Complete(version.version, true)
+            ]
+        );
+        assert_eq!(
+            server_observer.take(),
+            vec![
+                ObservedEvent::Resumption(kind),
+                ObservedEvent::Complete(version.version, true)
+            ]
+        );
+    }
+}
+
+#[test]
+fn connections_report_resumption() {
+    for version in rustls::ALL_VERSIONS {
+        for tls12_tickets in [false, true] {
+            let client_config =
+                Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+            let mut server_config = make_server_config(KeyType::Rsa);
+            if tls12_tickets {
+                server_config.ticketer = rustls::Ticketer::new().unwrap();
+            }
+            let server_config = Arc::new(server_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert!(!client.is_resumed());
+            assert!(!server.is_resumed());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+            assert!(!server.is_resumed());
+            do_handshake(&mut client, &mut server);
+            assert!(client.is_resumed());
+            assert!(server.is_resumed());
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn self_signed_server_config() {
+    let (server_config, roots) = ServerConfig::<Ring>::self_signed(&["example.com", "127.0.0.1"]);
+    assert_eq!(roots.len(), 1);
+    let server_config = Arc::new(server_config);
+
+    for name in ["example.com", "127.0.0.1"] {
+        let client_config = ClientConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        let mut client = ClientConnection::new(Arc::new(client_config), server_name(name)).unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            server.server_name(),
+            Some(name).filter(|_| name != "127.0.0.1")
+        );
+    }
+
+    // the certificate is not valid for other names.
+    let client_config = ClientConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("example.org")).unwrap();
+    let mut server = ServerConnection::new(server_config).unwrap();
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+            CertificateError::NotValidForName
+        )))
+    );
+}
+
+/// *ring*, but with a constant source of "randomness".
+#[cfg(feature = "testing")]
+struct ConstantRandom;
+
+#[cfg(feature = "testing")]
+impl CryptoProvider for ConstantRandom {
+    type KeyExchange = <Ring as CryptoProvider>::KeyExchange;
+
+    fn fill_random(buf: &mut [u8]) -> Result<(), rustls::crypto::GetRandomFailed> {
+        buf.fill(0x42);
+        Ok(())
+    }
+
+    fn hmac_sha256() -> &'static dyn rustls::crypto::hmac::Hmac {
+        Ring::hmac_sha256()
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn self_signed_server_config_uses_provider_randomness() {
+    let peer_certificates = || {
+        let (server_config, roots) = ServerConfig::<ConstantRandom>::self_signed(&["example.com"]);
+        let client_config = ClientConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("example.com")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        client
+            .peer_certificates()
+            .unwrap()
+            .to_vec()
+    };
+
+    // The key and serial number come only from the provider.
+    assert_eq!(peer_certificates(), peer_certificates());
+}
+
+#[test]
+fn strict_resumption_requires_same_alpn_protocol() {
+    for version in rustls::ALL_VERSIONS {
+        for strict in [false, true] {
+            let mut h2_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            h2_config.alpn_protocols = vec![b"h2".to_vec()];
+            let mut http11_config = h2_config.clone();
+            http11_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let h2_config = Arc::new(h2_config);
+            let http11_config = Arc::new(http11_config);
+
+            let mut server_config = make_server_config(KeyType::Rsa);
+            server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            let server_config = Arc::new(server_config.with_strict_resumption(strict));
+
+            let (mut client, mut server) = make_pair_for_arc_configs(&h2_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert!(!server.is_resumed());
+
+            let (mut client, mut server) = make_pair_for_arc_configs(&h2_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert!(server.is_resumed());
+            assert_eq!(server.alpn_protocol(), Some(&b"h2"[..]));
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&http11_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.is_resumed(), !strict);
+            assert_eq!(client.is_resumed(), !strict);
+            assert_eq!(server.alpn_protocol(), Some(&b"http/1.1"[..]));
+        }
+    }
+}
+
+#[test]
+fn strict_resumption_runs_alpn_selector_once() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let client_config = Arc::new(client_config);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let selector_calls = Arc::clone(&calls);
+        let server_config = Arc::new(
+            make_server_config(KeyType::Rsa)
+                .with_alpn_selector(move |_| {
+                    selector_calls.fetch_add(1, Ordering::SeqCst);
+                    Some(b"h2".to_vec())
+                })
+                .with_strict_resumption(true),
+        );
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.is_resumed());
+        assert_eq!(server.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
+
+/// Stores every session as if it were for the same server name.
+struct SingleNameStorage {
+    storage: rustls::client::ClientSessionMemoryCache,
+    name: rustls::ServerName,
+}
+
+impl fmt::Debug for SingleNameStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SingleNameStorage({:?})", self.name)
+    }
+}
+
+impl rustls::client::ClientSessionStore for SingleNameStorage {
+    fn set_kx_hint(&self, _: &rustls::ServerName, group: rustls::NamedGroup) {
+        self.storage
+            .set_kx_hint(&self.name, group)
+    }
+
+    fn kx_hint(&self, _: &rustls::ServerName) -> Option<rustls::NamedGroup> {
+        self.storage.kx_hint(&self.name)
+    }
+
+    fn set_tls12_session(
+        &self,
+        _: &rustls::ServerName,
+        value: rustls::client::Tls12ClientSessionValue,
+    ) {
+        self.storage
+            .set_tls12_session(&self.name, value)
+    }
+
+    fn tls12_session(
+        &self,
+        _: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls12ClientSessionValue> {
+        self.storage.tls12_session(&self.name)
+    }
+
+    fn remove_tls12_session(&self, _: &rustls::ServerName) {
+        self.storage
+            .remove_tls12_session(&self.name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        _: &rustls::ServerName,
+        value: rustls::client::Tls13ClientSessionValue,
+    ) {
+        self.storage
+            .insert_tls13_ticket(&self.name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        _: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls13ClientSessionValue> {
+        self.storage
+            .take_tls13_ticket(&self.name)
+    }
+}
+
+#[test]
+fn resumption_requires_same_server_name() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.resumption = Resumption::store(Arc::new(SingleNameStorage {
+            storage: rustls::client::ClientSessionMemoryCache::new(32),
+            name: server_name("any.name"),
+        }));
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(KeyType::Rsa).with_strict_resumption(true));
+
+        let connect = |name: &'static str| {
+            let mut client =
+                ClientConnection::new(Arc::clone(&client_config), server_name(name)).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(&mut client, &mut server);
+            server.is_resumed()
+        };
+
+        assert!(!connect("testserver.com"));
+        assert!(connect("testserver.com"));
+        assert!(!connect("localhost"));
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn handshake_observer_sees_tls12_ticket_resumption() {
+    let kt = KeyType::Rsa;
+    let client_observer = Arc::new(RecordingObserver::default());
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.handshake_observer = Some(client_observer.clone());
+    let client_config = Arc::new(client_config);
+
+    let server_observer = Arc::new(RecordingObserver::default());
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    server_config.handshake_observer = Some(server_observer.clone());
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    client_observer.take();
+    server_observer.take();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    let expected = vec![
+        ObservedEvent::Resumption(ResumptionKind::Tls12Ticket),
+        ObservedEvent::Complete(ProtocolVersion::TLSv1_2, true),
+    ];
+    assert_eq!(client_observer.take(), expected);
+    assert_eq!(server_observer.take(), expected);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_session_id_resumption_can_be_disabled() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.resumption =
+        Resumption::default().tls12_resumption(Tls12Resumption::SessionIdOnly);
+    let client_config = Arc::new(client_config);
+
+    // the client caches a session ID from a server that allows its use...
+    let server_config = make_server_config(kt);
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(server_config.clone()));
+    do_handshake(&mut client, &mut server);
+
+    // ...but a server sharing its session storage now refuses it
+    let server_observer = Arc::new(RecordingObserver::default());
+    let mut server_config = server_config.with_session_id_resumption(false);
+    server_config.handshake_observer = Some(server_observer.clone());
+    let server_config = Arc::new(server_config);
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            server_observer.take(),
+            vec![ObservedEvent::Complete(ProtocolVersion::TLSv1_2, false)]
+        );
+    }
+
+    // tickets still work
+    let client_config = Arc::new(make_client_config_with_versions(
+        kt,
+        &[&rustls::version::TLS12],
+    ));
+    let mut server_config = make_server_config(kt).with_session_id_resumption(false);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    server_config.handshake_observer = Some(server_observer.clone());
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    server_observer.take();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server_observer.take(),
+        vec![
+            ObservedEvent::Resumption(ResumptionKind::Tls12Ticket),
+            ObservedEvent::Complete(ProtocolVersion::TLSv1_2, true),
+        ]
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn handshake_observer_sees_failure() {
+    let kt = KeyType::Rsa;
+    let client_observer = Arc::new(RecordingObserver::default());
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.handshake_observer = Some(client_observer.clone());
+
+    let server_observer = Arc::new(RecordingObserver::default());
+    let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS12]);
+    server_config.handshake_observer = Some(server_observer.clone());
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_both_error(&mut client, &mut server).is_err());
+    assert_eq!(client_observer.take(), vec![ObservedEvent::Failed]);
+    assert_eq!(server_observer.take(), vec![ObservedEvent::Failed]);
+
+    // errors are sticky, and are reported only once
+    assert!(server.process_new_packets().is_err());
+    assert_eq!(server_observer.take(), vec![]);
+}
+
+#[cfg(feature = "tracing")]
+mod handshake_tracing {
+    use std::sync::atomic::AtomicU64;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span;
+
+    use super::*;
+
+    /// Records `rustls::handshake` spans and events, formatted as
+    /// `message field=value ...`.
+    #[derive(Default)]
+    struct Capture {
+        next_id: AtomicU64,
+        spans: Mutex<Vec<String>>,
+        events: Mutex<Vec<String>>,
+    }
+
+    struct Formatter(String);
+
+    impl Visit for Formatter {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            match field.name() {
+                "message" => self
+                    .0
+                    .insert_str(0, &format!("{:?}", value)),
+                name => self.0 += &format!(" {}={:?}", name, value),
+            }
+        }
+    }
+
+    impl tracing::Subscriber for Capture {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            metadata.target() == "rustls::handshake"
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            let mut fmt = Formatter(attrs.metadata().name().to_string());
+            attrs.record(&mut fmt);
+            self.spans.lock().unwrap().push(fmt.0);
+            span::Id::from_u64(
+                self.next_id
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1,
+            )
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fmt = Formatter(String::new());
+            event.record(&mut fmt);
+            self.events.lock().unwrap().push(fmt.0);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn client_handshake_events() {
+        for version in rustls::ALL_VERSIONS {
+            let capture = Arc::new(Capture::default());
+            let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            let server_config = make_server_config(KeyType::Rsa);
+
+            let suite = tracing::subscriber::with_default(capture.clone(), || {
+                let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+                do_handshake(&mut client, &mut server);
+                client
+                    .negotiated_cipher_suite()
+                    .unwrap()
+            });
+
+            assert_eq!(
+                *capture.spans.lock().unwrap(),
+                vec!["handshake side=Client", "handshake side=Server"]
+            );
+
+            let finished = |side| {
+                format!(
+                    "handshake finished side={} version={:?} suite={:?} resumed=false",
+                    side, version.version, suite
+                )
+            };
+            let mut expected = vec![
+                "ClientHello sent retry=false".to_string(),
+                format!(
+                    "ServerHello received version={:?} suite={:?}",
+                    version.version, suite
+                ),
+                "server certificate verified".to_string(),
+            ];
+            match version.version {
+                // In TLS1.3 the client completes first; in TLS1.2 the server does.
+                ProtocolVersion::TLSv1_3 => {
+                    expected.extend([finished("Client"), finished("Server")])
+                }
+                _ => expected.extend([finished("Server"), finished("Client")]),
+            }
+            assert_eq!(*capture.events.lock().unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+fn early_data_not_available() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(client.early_data().is_none());
+}
+
+fn early_data_configs() -> (Arc<ClientConfig<Ring>>, Arc<ServerConfig<Ring>>) {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    client_config.resumption = Resumption::store(Arc::new(ClientStorage::new()));
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+#[test]
+fn early_data_is_available_on_resumption() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+}
+
+#[test]
+fn early_data_not_available_on_server_before_client_hello() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    assert!(server.early_data().is_none());
+}
+
+#[test]
+fn early_data_can_be_rejected_by_server() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.is_early_data_accepted());
+    assert!(server.is_early_data_rejected());
+}
+
+#[test]
+fn early_data_rejected_by_server_is_skipped() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!server.is_early_data_rejected());
+
+    // This server shares the session storage, so resumes the session, but
+    // no longer accepts early data.
+    let mut rejecting_config = (*server_config).clone();
+    rejecting_config.max_early_data_size = 0;
+    let rejecting_config = Arc::new(rejecting_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &rejecting_config);
+    let early_data = [0x5a; 1234];
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(&early_data)
+            .unwrap(),
+        early_data.len()
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.is_early_data_accepted());
+    assert!(server.is_early_data_rejected());
+    assert!(server.early_data().is_none());
+    assert!(server
+        .received_resumption_data()
+        .is_some());
+
+    // The skipped early data is not delivered with the 1-RTT data.
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[cfg(feature = "quic")]
+mod test_quic {
+    use super::*;
+    use rustls::quic::{self, ConnectionCommon};
+
+    // Returns the sender's next secrets to use, or the receiver's error.
+    fn step<L: SideData, R: SideData>(
+        send: &mut ConnectionCommon<L>,
+        recv: &mut ConnectionCommon<R>,
+    ) -> Result<Option<quic::KeyChange>, Error> {
+        let mut buf = Vec::new();
+        let change = loop {
+            let prev = buf.len();
+            if let Some(x) = send.write_hs(&mut buf) {
+                break Some(x);
+            }
+            if prev == buf.len() {
+                break None;
+            }
+        };
+        if let Err(e) = recv.read_hs(&buf) {
+            return Err(e);
+        } else {
+            assert_eq!(recv.alert(), None);
+        }
+
+        Ok(change)
+    }
+
+    #[test]
+    fn test_quic_handshake() {
+        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
+            // Check that these two sets of keys are equal.
+            let mut buf = vec![0; 32];
+            let (header, payload_tag) = buf.split_at_mut(8);
+            let (payload, tag_buf) = payload_tag.split_at_mut(8);
+            let tag = x
+                .encrypt_in_place(42, &*header, payload)
+                .unwrap();
+            tag_buf.copy_from_slice(tag.as_ref());
+
+            let result = y.decrypt_in_place(42, &*header, payload_tag);
+            match result {
+                Ok(payload) => payload == &[0; 8],
+                Err(_) => false,
+            }
+        }
+
+        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
+            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
+                match kc {
+                    quic::KeyChange::Handshake { keys } => keys,
+                    quic::KeyChange::OneRtt { keys, .. } => keys,
+                }
+            }
+
+            let (x, y) = (keys(x), keys(y));
+            equal_packet_keys(&x.local.packet, &y.remote.packet)
+                && equal_packet_keys(&x.remote.packet, &y.local.packet)
+        }
+
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        // full handshake
+        let mut client = quic::ClientConnection::new(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            server_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server = quic::ServerConnection::new(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        let client_initial = step(&mut client, &mut server).unwrap();
+        assert!(client_initial.is_none());
+        assert!(client.zero_rtt_keys().is_none());
+        assert_eq!(server.quic_transport_parameters(), Some(client_params));
+        let server_hs = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(server.zero_rtt_keys().is_none());
+        let client_hs = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(compatible_keys(&server_hs, &client_hs));
+        assert!(client.is_handshaking());
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        assert!(server.is_handshaking());
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(!server.is_handshaking());
+        assert!(compatible_keys(&server_1rtt, &client_1rtt));
+        assert!(!compatible_keys(&server_hs, &server_1rtt));
+        assert!(step(&mut client, &mut server)
+            .unwrap()
+            .is_none());
+        assert!(step(&mut server, &mut client)
+            .unwrap()
+            .is_none());
+
+        // 0-RTT handshake
+        let mut client = quic::ClientConnection::new(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            server_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+        assert!(client
+            .negotiated_cipher_suite()
+            .is_some());
+
+        let mut server = quic::ServerConnection::new(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        {
+            let client_early = client.zero_rtt_keys().unwrap();
+            let server_early = server.zero_rtt_keys().unwrap();
+            assert!(equal_packet_keys(
+                &client_early.packet,
+                &server_early.packet
+            ));
+        }
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(client.is_early_data_accepted());
+
+        // 0-RTT rejection
+        {
+            let client_config = (*client_config).clone();
+            let mut client = quic::ClientConnection::new(
+                Arc::new(client_config),
+                quic::Version::V1,
+                server_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+
+            let mut server = quic::ServerConnection::new(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap();
+            server.reject_early_data();
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(client.quic_transport_parameters(), Some(server_params));
+            assert!(client.zero_rtt_keys().is_some());
+            assert!(server.zero_rtt_keys().is_none());
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            step(&mut client, &mut server)
+                .unwrap()
+                .unwrap();
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            assert!(!client.is_early_data_accepted());
+        }
+
+        // failed handshake
+        let mut client = quic::ClientConnection::new(
+            client_config,
+            quic::Version::V1,
+            server_name("example.com"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server =
+            quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
+                .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(step(&mut server, &mut client).is_err());
+        assert_eq!(
+            client.alert(),
+            Some(rustls::AlertDescription::BadCertificate)
+        );
+
+        // Key updates
+
+        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
+                (c, s)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut client_next = client_secrets.next_packet_keys();
+        let mut server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+
+        client_next = client_secrets.next_packet_keys();
+        server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+    }
+
+    #[test]
+    fn test_quic_rejects_missing_alpn() {
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config =
+                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+            server_config.alpn_protocols = vec!["foo".into()];
+            let server_config = Arc::new(server_config);
+
+            let mut client = quic::ClientConnection::new(
+                client_config,
+                quic::Version::V1,
+                server_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+            let mut server =
+                quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
+                    .unwrap();
+
+            assert_eq!(
+                step(&mut client, &mut server)
+                    .err()
+                    .unwrap(),
+                Error::NoApplicationProtocol
+            );
+
+            assert_eq!(
+                server.alert(),
+                Some(rustls::AlertDescription::NoApplicationProtocol)
+            );
+        }
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_quic_no_tls13_error() {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        client_config.alpn_protocols = vec!["foo".into()];
+        let client_config = Arc::new(client_config);
+
+        assert!(quic::ClientConnection::new(
+            client_config,
+            quic::Version::V1,
+            server_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .is_err());
+
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        assert!(quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quic_invalid_early_data_size() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+
+        let cases = [
+            (None, true),
+            (Some(0u32), true),
+            (Some(5), false),
+            (Some(0xffff_ffff), true),
+        ];
+
+        for &(size, ok) in cases.iter() {
+            println!("early data size case: {:?}", size);
+            if let Some(new) = size {
+                server_config.max_early_data_size = new;
+            }
+
+            let wrapped = Arc::new(server_config.clone());
+            assert_eq!(
+                quic::ServerConnection::new(wrapped, quic::Version::V1, b"server params".to_vec(),)
+                    .is_ok(),
+                ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_server_no_params_received() {
+        let server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        let server_config = Arc::new(server_config);
+
+        let mut server = quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .unwrap();
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{Compression, NamedGroup};
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
+        };
+        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
+            payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                client_version: ProtocolVersion::TLSv1_3,
+                random,
+                session_id: SessionId::random::<Ring>().unwrap(),
+                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                compression_methods: vec![Compression::Null],
+                extensions: vec![
+                    ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
+                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                    ClientExtension::KeyShare(vec![KeyShareEntry {
+                        group: NamedGroup::X25519,
+                        payload: PayloadU16::new(kx.as_ref().to_vec()),
+                    }]),
+                ],
+            }),
+        });
+
+        let mut buf = Vec::with_capacity(512);
+        client_hello.encode(&mut buf);
+        assert_eq!(
+            server
+                .read_hs(&mut buf.as_slice())
+                .err(),
+            Some(Error::PeerMisbehaved(
+                PeerMisbehaved::MissingQuicTransportParameters
+            ))
+        );
+    }
+
+    #[test]
+    fn test_quic_server_no_tls12() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{Compression, NamedGroup};
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
+        };
+        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let mut server = quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .unwrap();
+
+        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
+            payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                client_version: ProtocolVersion::TLSv1_2,
+                random: random.clone(),
+                session_id: SessionId::random::<Ring>().unwrap(),
+                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                compression_methods: vec![Compression::Null],
+                extensions: vec![
+                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                    ClientExtension::KeyShare(vec![KeyShareEntry {
+                        group: NamedGroup::X25519,
+                        payload: PayloadU16::new(kx.as_ref().to_vec()),
+                    }]),
+                ],
+            }),
+        });
+
+        let mut buf = Vec::with_capacity(512);
+        client_hello.encode(&mut buf);
+        assert_eq!(
+            server
+                .read_hs(&mut buf.as_slice())
+                .err(),
+            Some(Error::PeerIncompatible(
+                PeerIncompatible::SupportedVersionsExtensionRequired
+            )),
+        );
+    }
+
+    #[test]
+    fn packet_key_api() {
+        use rustls::quic::{Keys, Version};
+        use rustls::Side;
+
+        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
+        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        const PACKET_NUMBER: u64 = 2;
+        const PLAIN_HEADER: &[u8] = &[
+            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
+        ];
+
+        const PAYLOAD: &[u8] = &[
+            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
+            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
+            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
+            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
+            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
+            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
+            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
+            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
+            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
+            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
+            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
+            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
+            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
+            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
+            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
+            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
+        ];
+
+        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Client);
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .confidentiality_limit(),
+            2u64.pow(23)
+        );
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .integrity_limit(),
+            2u64.pow(52)
+        );
+        assert_eq!(client_keys.local.packet.tag_len(), 16);
+
+        let mut buf = Vec::new();
+        buf.extend(PLAIN_HEADER);
+        buf.extend(PAYLOAD);
+        let header_len = PLAIN_HEADER.len();
+        let tag_len = client_keys.local.packet.tag_len();
+        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
+        buf.extend(std::iter::repeat(0).take(padding_len));
+        let (header, payload) = buf.split_at_mut(header_len);
+        let tag = client_keys
+            .local
+            .packet
+            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        let sample_len = client_keys.local.header.sample_len();
+        let sample = &payload[..sample_len];
+        let (first, rest) = header.split_at_mut(1);
+        client_keys
+            .local
+            .header
+            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        buf.extend_from_slice(tag.as_ref());
+
+        const PROTECTED: &[u8] = &[
             0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
             0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
             0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
@@ -3831,988 +6142,3069 @@ mod test_quic {
             0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
         ];
 
-        assert_eq!(&buf, PROTECTED);
+        assert_eq!(&buf, PROTECTED);
+
+        let (header, payload) = buf.split_at_mut(header_len);
+        let (first, rest) = header.split_at_mut(1);
+        let sample = &payload[..sample_len];
+
+        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Server);
+        server_keys
+            .remote
+            .header
+            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        let payload = server_keys
+            .remote
+            .packet
+            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
+        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+    }
+
+    #[test]
+    fn test_quic_exporter() {
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+
+            do_exporter_test(client_config, server_config);
+        }
+    }
+} // mod test_quic
+
+#[test]
+fn test_client_does_not_offer_sha1() {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+    use rustls::HandshakeType;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+
+            assert!(client.wants_write());
+            let mut buf = [0u8; 262144];
+            let sz = client
+                .write_tls(&mut buf.as_mut())
+                .unwrap();
+            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+            let msg = Message::try_from(msg.into_plain_message()).unwrap();
+            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake { parsed, .. } => match parsed.payload {
+                    HandshakePayload::ClientHello(ch) => ch,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+
+            let sigalgs = client_hello
+                .get_sigalgs_extension()
+                .unwrap();
+            assert!(
+                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
+                "sha1 unexpectedly offered"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn test_client_follows_helloretryrequest_for_p256() {
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP256R1]);
+
+    for max_hello_retry in [1, 0] {
+        // client offers an x25519 key share, but server only accepts secp256r1
+        let client_config = make_client_config_with_kx_groups(
+            KeyType::Rsa,
+            &[&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1],
+        )
+        .with_max_hello_retry(max_hello_retry);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config.clone());
+
+        // first ClientHello, answered with HelloRetryRequest
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert!(server.is_handshaking());
+        transfer(&mut server, &mut client);
+
+        if max_hello_retry == 0 {
+            assert!(client.process_new_packets().is_err());
+            continue;
+        }
+
+        // second ClientHello, with a secp256r1 key share
+        client.process_new_packets().unwrap();
+        assert!(client.wants_write());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+    }
+}
+
+#[test]
+fn test_client_rejects_second_helloretryrequest() {
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP256R1]);
+
+    for max_hello_retry in [1, 2] {
+        let client_config = make_client_config_with_kx_groups(
+            KeyType::Rsa,
+            &[&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1],
+        )
+        .with_max_hello_retry(max_hello_retry);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config.clone());
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let mut retry = Vec::new();
+        server.write_tls(&mut retry).unwrap();
+
+        client
+            .read_tls(&mut io::Cursor::new(&retry))
+            .unwrap();
+        client.process_new_packets().unwrap();
+        client
+            .write_tls(&mut io::sink())
+            .unwrap();
+
+        // replay the HelloRetryRequest in answer to the second ClientHello
+        client
+            .read_tls(&mut io::Cursor::new(&retry))
+            .unwrap();
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::InappropriateHandshakeMessage {
+                expect_types: vec![rustls::HandshakeType::ServerHello],
+                got_type: rustls::HandshakeType::HelloRetryRequest,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_client_key_shares_for_two_groups_avoid_helloretryrequest() {
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP256R1]);
+
+    for key_shares in [1, 2] {
+        let client_config = make_client_config_with_kx_groups(
+            KeyType::Rsa,
+            &[&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1],
+        )
+        .with_key_shares(key_shares);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config.clone());
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let server_flight = transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        if key_shares == 1 {
+            // just the HelloRetryRequest and a fake CCS
+            assert!(server_flight < 100);
+        } else {
+            assert!(server_flight > 1000);
+        }
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            client.negotiated_key_exchange_group(),
+            Some(rustls::NamedGroup::secp256r1)
+        );
+    }
+}
+
+#[test]
+fn test_server_hello_retry_for_preferred_group() {
+    for hello_retry_for_preferred_group in [false, true] {
+        // server prefers secp256r1, but would accept x25519
+        let mut server_config = make_server_config_with_kx_groups(
+            KeyType::Rsa,
+            &[&rustls::kx_group::SECP256R1, &rustls::kx_group::X25519],
+        );
+        server_config.hello_retry_for_preferred_group = hello_retry_for_preferred_group;
+
+        // client supports both, but only sends an x25519 key share
+        let client_config = make_client_config_with_kx_groups(
+            KeyType::Rsa,
+            &[&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1],
+        );
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let server_flight = transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        if hello_retry_for_preferred_group {
+            // just the HelloRetryRequest and a fake CCS
+            assert!(server_flight < 100);
+            assert!(client.is_handshaking());
+        } else {
+            assert!(server_flight > 1000);
+        }
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+        assert!(!server.is_handshaking());
+    }
+}
+
+fn make_stateless_hello_retry_configs() -> (ClientConfig<Ring>, Arc<ServerConfig<Ring>>) {
+    // client sends an x25519 key share, but server only accepts secp384r1
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1])
+            .with_stateless_hello_retry(b"0123456789abcdef0123456789abcdef");
+    (client_config, Arc::new(server_config))
+}
+
+#[test]
+fn test_server_stateless_hello_retry() {
+    let (client_config, server_config) = make_stateless_hello_retry_configs();
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+
+    // one server sends the HelloRetryRequest...
+    let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+
+    // ...and another completes the handshake, with no state shared between them
+    let mut server = ServerConnection::new(server_config).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert!(!server.is_handshaking());
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn test_server_rejects_tampered_hello_retry_cookie() {
+    fn tamper_cookie(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::HelloRetryRequest(hrr) = &mut parsed.payload {
+                for ext in hrr.extensions.iter_mut() {
+                    if let HelloRetryExtension::Cookie(cookie) = ext {
+                        cookie.0[0] ^= 0x01;
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let (client_config, server_config) = make_stateless_hello_retry_configs();
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut server, tamper_cookie, &mut client);
+    client.process_new_packets().unwrap();
+
+    let mut server = ServerConnection::new(server_config)
+        .unwrap()
+        .into();
+    transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::InvalidHelloRetryCookie
+        ))
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_sends_helloretryrequest() {
+    // client sends a secp384r1 key share
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let storage = Arc::new(ClientStorage::new());
+    client_config.resumption = Resumption::store(storage.clone());
+
+    // but server only accepts x25519, so a HRR is required
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 1);
+    }
+
+    // server sends HRR
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    }
+
+    // client sends fixed hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200); // just the client hello retry
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    }
+
+    // server completes handshake
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    }
+
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    // client only did following storage queries:
+    println!("storage {:#?}", storage.ops());
+    assert_eq!(storage.ops().len(), 7);
+    assert!(matches!(
+        storage.ops()[0],
+        ClientStorageOp::TakeTls13Ticket(_, false)
+    ));
+    assert!(matches!(
+        storage.ops()[1],
+        ClientStorageOp::GetTls12Session(_, false)
+    ));
+    assert!(matches!(
+        storage.ops()[2],
+        ClientStorageOp::GetKxHint(_, None)
+    ));
+    assert!(matches!(
+        storage.ops()[3],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
+    ));
+    assert!(matches!(
+        storage.ops()[4],
+        ClientStorageOp::RemoveTls12Session(_)
+    ));
+    // server sends 2 tickets by default
+    assert!(matches!(
+        storage.ops()[5],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+    assert!(matches!(
+        storage.ops()[6],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_attempts_to_use_unsupported_kx_group() {
+    // common to both client configs
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    // first, client sends a x25519 and server agrees. x25519 is inserted
+    //   into kx group cache.
+    let mut client_config_1 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    client_config_1.resumption = Resumption::store(shared_storage.clone());
+
+    // second, client only supports secp-384 and so kx group cache
+    //   contains an unusable value.
+    let mut client_config_2 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    client_config_2.resumption = Resumption::store(shared_storage.clone());
+
+    let server_config = make_server_config(KeyType::Rsa);
+
+    // first handshake
+    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
+    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+
+    let ops = shared_storage.ops();
+    println!("storage {:#?}", ops);
+    assert_eq!(ops.len(), 7);
+    assert!(matches!(
+        ops[3],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
+    ));
+
+    // second handshake
+    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
+    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+
+    let ops = shared_storage.ops();
+    println!("storage {:?} {:#?}", ops.len(), ops);
+    assert_eq!(ops.len(), 13);
+    assert!(matches!(ops[7], ClientStorageOp::TakeTls13Ticket(_, true)));
+    assert!(matches!(
+        ops[8],
+        ClientStorageOp::GetKxHint(_, Some(rustls::NamedGroup::X25519))
+    ));
+    assert!(matches!(
+        ops[9],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::secp384r1)
+    ));
+}
+
+#[test]
+fn test_tls13_server_sends_configured_number_of_tickets() {
+    for (configured, expected) in [(0, 0), (1, 1), (2, 2), (3, 3), (255, 8)] {
+        let storage = Arc::new(ClientStorage::new());
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.resumption = Resumption::store(storage.clone());
+
+        let server_config = make_server_config(KeyType::Rsa).with_num_tickets(configured);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let tickets = storage
+            .ops()
+            .iter()
+            .filter(|op| matches!(op, ClientStorageOp::InsertTls13Ticket(_)))
+            .count();
+        assert_eq!(tickets, expected);
+    }
+}
+
+#[test]
+fn test_tls13_server_can_defer_tickets_until_first_write() {
+    fn count_tickets(storage: &ClientStorage) -> usize {
+        storage
+            .ops_and_reset()
+            .iter()
+            .filter(|op| matches!(op, ClientStorageOp::InsertTls13Ticket(_)))
+            .count()
+    }
+
+    let storage = Arc::new(ClientStorage::new());
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.resumption = Resumption::store(storage.clone());
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::Rsa).with_deferred_tickets(true));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!server.wants_write());
+    assert_eq!(count_tickets(&storage), 0);
+
+    server
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(count_tickets(&storage), 2);
+    check_read(&mut client.reader(), b"hello");
+
+    // only the first write sends them
+    server
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(count_tickets(&storage), 0);
+
+    // or they can be sent explicitly
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        server.send_session_ticket(),
+        Err(Error::HandshakeNotComplete)
+    );
+    do_handshake(&mut client, &mut server);
+    storage.ops_and_reset();
+    server.send_session_ticket().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(count_tickets(&storage), 2);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls13_client_resumption_does_not_reuse_tickets() {
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.resumption = Resumption::store(shared_storage.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_tls13_tickets = 5;
+    let server_config = Arc::new(server_config);
+
+    // first handshake: client obtains 5 tickets from server.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    let ops = shared_storage.ops_and_reset();
+    println!("storage {:#?}", ops);
+    assert_eq!(ops.len(), 10);
+    assert!(matches!(ops[5], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[6], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[7], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[8], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[9], ClientStorageOp::InsertTls13Ticket(_)));
+
+    // 5 subsequent handshakes: all are resumptions
+
+    // Note: we don't do complete the handshakes, because that means
+    // we get five additional tickets per connection which is unhelpful
+    // in this test.  It also acts to record a "Happy Eyeballs"-type use
+    // case, where a client speculatively makes many connection attempts
+    // in parallel without knowledge of which will work due to underlying
+    // connectivity uncertainty.
+    for _ in 0..5 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let ops = shared_storage.ops_and_reset();
+        assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, true)));
+    }
+
+    // 6th subsequent handshake: cannot be resumed; we ran out of tickets
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let ops = shared_storage.ops_and_reset();
+    println!("last {:?}", ops);
+    assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, false)));
+}
+
+#[test]
+fn test_client_mtu_reduction() {
+    struct CollectWrites {
+        writevs: Vec<Vec<usize>>,
+    }
+
+    impl io::Write for CollectWrites {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            panic!()
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            panic!()
+        }
+        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+            let writes = b
+                .iter()
+                .map(|slice| slice.len())
+                .collect::<Vec<usize>>();
+            let len = writes.iter().sum();
+            self.writevs.push(writes);
+            Ok(len)
+        }
+    }
+
+    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
+        let mut collector = CollectWrites { writevs: vec![] };
+
+        client
+            .write_tls(&mut collector)
+            .unwrap();
+        assert_eq!(collector.writevs.len(), 1);
+        collector.writevs[0].clone()
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_fragment_size = Some(64);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+        let writes = collect_write_lengths(&mut client);
+        println!("writes at mtu=64: {:?}", writes);
+        assert!(writes.iter().all(|x| *x <= 64));
+        assert!(writes.len() > 1);
+    }
+}
+
+#[test]
+fn test_server_mtu_reduction() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.max_fragment_size = Some(64);
+    server_config.send_half_rtt_data = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    let big_data = [0u8; 2048];
+    server
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
+
+    let encryption_overhead = 20; // FIXME: see issue #991
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), &big_data);
+}
+
+fn check_client_max_fragment_size(size: usize) -> Option<Error> {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.max_fragment_size = Some(size);
+    ClientConnection::new(Arc::new(client_config), server_name("localhost")).err()
+}
+
+#[test]
+fn bad_client_max_fragment_sizes() {
+    assert_eq!(
+        check_client_max_fragment_size(31),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(check_client_max_fragment_size(32), None);
+    assert_eq!(check_client_max_fragment_size(64), None);
+    assert_eq!(check_client_max_fragment_size(1460), None);
+    assert_eq!(check_client_max_fragment_size(0x4000), None);
+    assert_eq!(check_client_max_fragment_size(0x4005), None);
+    assert_eq!(
+        check_client_max_fragment_size(0x4006),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(
+        check_client_max_fragment_size(0xffff),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+fn assert_lt(left: usize, right: usize) {
+    if left >= right {
+        panic!("expected {} < {}", left, right);
+    }
+}
+
+#[test]
+fn connection_types_are_not_huge() {
+    // Arbitrary sizes
+    assert_lt(mem::size_of::<ServerConnection>(), 1600);
+    assert_lt(mem::size_of::<ClientConnection>(), 1600);
+}
+
+#[cfg(feature = "tls12")]
+use rustls::internal::msgs::handshake::ServerExtension;
+use rustls::internal::msgs::{
+    handshake::ClientExtension, handshake::ClientHelloPayload, handshake::HandshakePayload,
+    handshake::HelloRetryExtension, handshake::UnknownExtension, message::Message,
+    message::MessagePayload,
+};
+
+#[test]
+fn test_server_rejects_duplicate_sni_names() {
+    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.push(snr[0].clone());
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::DuplicateServerNameTypes
+        ))
+    );
+}
+
+#[test]
+fn test_server_rejects_empty_sni_extension() {
+    fn empty_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.clear();
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, empty_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::ServerNameMustContainOneHostName
+        ))
+    );
+}
+
+#[test]
+fn test_server_rejects_clients_without_any_kx_group_overlap() {
+    fn different_kx_group(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
+                        ngs.clear();
+                    }
+                    if let ClientExtension::KeyShare(ks) = &mut ext {
+                        ks.clear();
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, different_kx_group, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerIncompatible(
+            PeerIncompatible::NoKxGroupsInCommon
+        ))
+    );
+}
+
+#[test]
+fn no_kx_groups_in_common() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = finish_client_config(
+            KeyType::Rsa,
+            ClientConfig::<Ring>::builder()
+                .with_safe_default_cipher_suites()
+                .with_kx_groups(&[&rustls::kx_group::X25519])
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+        let server_config =
+            make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP256R1]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        // No HelloRetryRequest can help: the client supports no group
+        // the server does.
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerIncompatible(
+                PeerIncompatible::NoKxGroupsInCommon
+            ))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_client_rejects_illegal_tls13_ccs() {
+    fn corrupt_ccs(msg: &mut Message) -> Altered {
+        if let MessagePayload::ChangeCipherSpec(_) = &mut msg.payload {
+            println!("seen CCS {:?}", msg);
+            return Altered::Raw(vec![0x14, 0x03, 0x03, 0x00, 0x02, 0x01, 0x02]);
+        }
+        Altered::InPlace
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let (mut server, mut client) = (server.into(), client.into());
+
+    transfer_altered(&mut server, corrupt_ccs, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::IllegalMiddleboxChangeCipherSpec
+        ))
+    );
+}
+
+/// https://github.com/rustls/rustls/issues/797
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_tls12_no_resume_after_server_downgrade() {
+    let mut client_config = common::make_client_config(KeyType::Ed25519);
+    let client_storage = Arc::new(ClientStorage::new());
+    client_config.resumption = Resumption::store(client_storage.clone());
+    let client_config = Arc::new(client_config);
+
+    let server_config_1 = Arc::new(common::finish_server_config(
+        KeyType::Ed25519,
+        ServerConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap(),
+    ));
+
+    let mut server_config_2 = common::finish_server_config(
+        KeyType::Ed25519,
+        ServerConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    server_config_2.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+
+    dbg!("handshake 1");
+    let mut client_1 =
+        ClientConnection::new(client_config.clone(), "localhost".try_into().unwrap()).unwrap();
+    let mut server_1 = ServerConnection::new(server_config_1).unwrap();
+    common::do_handshake(&mut client_1, &mut server_1);
+
+    assert_eq!(client_storage.ops().len(), 7);
+    println!("hs1 storage ops: {:#?}", client_storage.ops());
+    assert!(matches!(
+        client_storage.ops()[3],
+        ClientStorageOp::SetKxHint(_, _)
+    ));
+    assert!(matches!(
+        client_storage.ops()[4],
+        ClientStorageOp::RemoveTls12Session(_)
+    ));
+    assert!(matches!(
+        client_storage.ops()[5],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+
+    dbg!("handshake 2");
+    let mut client_2 =
+        ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+    let mut server_2 = ServerConnection::new(Arc::new(server_config_2)).unwrap();
+    common::do_handshake(&mut client_2, &mut server_2);
+    println!("hs2 storage ops: {:#?}", client_storage.ops());
+    assert_eq!(client_storage.ops().len(), 9);
+
+    // attempt consumes a TLS1.3 ticket
+    assert!(matches!(
+        client_storage.ops()[7],
+        ClientStorageOp::TakeTls13Ticket(_, true)
+    ));
+
+    // but ends up with TLS1.2
+    assert_eq!(
+        client_2.protocol_version(),
+        Some(rustls::ProtocolVersion::TLSv1_2)
+    );
+}
+
+#[test]
+fn test_acceptor() {
+    use rustls::server::Acceptor;
+
+    let client_config = Arc::new(make_client_config(KeyType::Ed25519));
+    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    let server_config = Arc::new(make_server_config(KeyType::Ed25519));
+    let mut acceptor = Acceptor::default();
+    acceptor
+        .read_tls(&mut buf.as_slice())
+        .unwrap();
+    let accepted = acceptor.accept().unwrap().unwrap();
+    let ch = accepted.client_hello();
+    assert_eq!(ch.server_name(), Some("localhost"));
+
+    let server = accepted
+        .into_connection(server_config)
+        .unwrap();
+    assert!(server.wants_write());
+
+    // Reusing an acceptor is not allowed
+    assert_eq!(
+        acceptor
+            .read_tls(&mut [0u8].as_ref())
+            .err()
+            .unwrap()
+            .kind(),
+        io::ErrorKind::Other,
+    );
+    assert_eq!(
+        acceptor.accept().err(),
+        Some(Error::General("Acceptor polled after completion".into()))
+    );
+
+    let mut acceptor = Acceptor::default();
+    assert!(acceptor.accept().unwrap().is_none());
+    acceptor
+        .read_tls(&mut &buf[..3])
+        .unwrap(); // incomplete message
+    assert!(acceptor.accept().unwrap().is_none());
+    acceptor
+        .read_tls(&mut [0x80, 0x00].as_ref())
+        .unwrap(); // invalid message (len = 32k bytes)
+    assert!(acceptor.accept().is_err());
+
+    let mut acceptor = Acceptor::default();
+    // Minimal valid 1-byte application data message is not a handshake message
+    acceptor
+        .read_tls(&mut [0x17, 0x03, 0x03, 0x00, 0x01, 0x00].as_ref())
+        .unwrap();
+    assert!(acceptor.accept().is_err());
+
+    let mut acceptor = Acceptor::default();
+    // Minimal 1-byte ClientHello message is not a legal handshake message
+    acceptor
+        .read_tls(&mut [0x16, 0x03, 0x03, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00].as_ref())
+        .unwrap();
+    assert!(acceptor.accept().is_err());
+}
+
+#[derive(Default, Debug)]
+struct LogCounts {
+    trace: usize,
+    debug: usize,
+    info: usize,
+    warn: usize,
+    error: usize,
+}
+
+impl LogCounts {
+    fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn add(&mut self, level: log::Level) {
+        match level {
+            log::Level::Trace => self.trace += 1,
+            log::Level::Debug => self.debug += 1,
+            log::Level::Info => self.info += 1,
+            log::Level::Warn => self.warn += 1,
+            log::Level::Error => self.error += 1,
+        }
+    }
+}
+
+thread_local!(static COUNTS: RefCell<LogCounts> = RefCell::new(LogCounts::new()));
+
+struct CountingLogger;
+
+static LOGGER: CountingLogger = CountingLogger;
+
+impl CountingLogger {
+    fn install() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+
+    fn reset() {
+        COUNTS.with(|c| {
+            c.borrow_mut().reset();
+        });
+    }
+}
+
+impl log::Log for CountingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        println!("logging at {:?}: {:?}", record.level(), record.args());
+
+        COUNTS.with(|c| {
+            c.borrow_mut().add(record.level());
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn test_no_warning_logging_during_successful_sessions() {
+    CountingLogger::install();
+    CountingLogger::reset();
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
+        }
+    }
+
+    if cfg!(feature = "logging") {
+        COUNTS.with(|c| {
+            println!("After tests: {:?}", c.borrow());
+            assert_eq!(c.borrow().warn, 0);
+            assert_eq!(c.borrow().error, 0);
+            assert_eq!(c.borrow().info, 0);
+            assert!(c.borrow().trace > 0);
+            assert!(c.borrow().debug > 0);
+        });
+    } else {
+        COUNTS.with(|c| {
+            println!("After tests: {:?}", c.borrow());
+            assert_eq!(c.borrow().warn, 0);
+            assert_eq!(c.borrow().error, 0);
+            assert_eq!(c.borrow().info, 0);
+            assert_eq!(c.borrow().trace, 0);
+            assert_eq!(c.borrow().debug, 0);
+        });
+    }
+}
 
-        let (header, payload) = buf.split_at_mut(header_len);
-        let (first, rest) = header.split_at_mut(1);
-        let sample = &payload[..sample_len];
+/// Test that secrets can be extracted and used for encryption/decryption.
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction_enabled() {
+    // Normally, secret extraction would be used to configure kTLS (TLS offload
+    // to the kernel). We want this test to run on any platform, though, so
+    // instead we just compare secrets for equality.
 
-        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Server);
-        server_keys
-            .remote
-            .header
-            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+    // TLS 1.2 and 1.3 have different mechanisms for key exchange and handshake,
+    // and secrets are stored/extracted differently, so we want to test them both.
+    // We support 3 different AEAD algorithms (AES-128-GCM mode, AES-256-GCM, and
+    // Chacha20Poly1305), so that's 2*3 = 6 combinations to test.
+    let kt = KeyType::Rsa;
+    for suite in [
+        rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ] {
+        let version = suite.version();
+        println!("Testing suite {:?}", suite.suite().as_str());
+
+        // Only offer the cipher suite (and protocol version) that we're testing
+        let mut server_config = ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
             .unwrap();
-        let payload = server_keys
-            .remote
-            .packet
-            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
+        // Opt into secret extraction from both sides
+        server_config.enable_secret_extraction = true;
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(kt);
+        client_config.enable_secret_extraction = true;
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+        do_handshake(&mut client, &mut server);
+
+        // The handshake is finished, we're now able to extract traffic secrets
+        let client_secrets = client.extract_secrets().unwrap();
+        let server_secrets = server.extract_secrets().unwrap();
+
+        // Comparing secrets for equality is something you should never have to
+        // do in production code, so ConnectionTrafficSecrets doesn't implement
+        // PartialEq/Eq on purpose. Instead, we have to get creative.
+        fn explode_secrets(s: &ConnectionTrafficSecrets) -> (&[u8], &[u8], &[u8]) {
+            match s {
+                ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => (key, salt, iv),
+                ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => (key, salt, iv),
+                ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => (key, &[], iv),
+                _ => panic!("unexpected secret type"),
+            }
+        }
+
+        fn assert_secrets_equal(
+            (l_seq, l_sec): (u64, ConnectionTrafficSecrets),
+            (r_seq, r_sec): (u64, ConnectionTrafficSecrets),
+        ) {
+            assert_eq!(l_seq, r_seq);
+            assert_eq!(explode_secrets(&l_sec), explode_secrets(&r_sec));
+        }
+
+        assert_secrets_equal(client_secrets.tx, server_secrets.rx);
+        assert_secrets_equal(client_secrets.rx, server_secrets.tx);
+    }
+}
+
+/// Test that secrets cannot be extracted unless explicitly enabled, and until
+/// the handshake is done.
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction_disabled_or_too_early() {
+    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    let kt = KeyType::Rsa;
+
+    for (server_enable, client_enable) in [(true, false), (false, true)] {
+        let mut server_config = ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
             .unwrap();
+        server_config.enable_secret_extraction = server_enable;
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(kt);
+        client_config.enable_secret_extraction = client_enable;
+
+        let client_config = Arc::new(client_config);
+
+        let (client, server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+        assert!(
+            client.extract_secrets().is_err(),
+            "extraction should fail until handshake completes"
+        );
+        assert!(
+            server.extract_secrets().is_err(),
+            "extraction should fail until handshake completes"
+        );
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(server_enable, server.extract_secrets().is_ok());
+        assert_eq!(client_enable, client.extract_secrets().is_ok());
+    }
+}
+
+/// TLS1.3 configs whose suite replaces its keys every `limit` records,
+/// with a client sending at most 59 bytes of plaintext per record.
+#[cfg(feature = "testing")]
+fn confidentiality_limited_configs(limit: u64) -> (ClientConfig<Ring>, ServerConfig<Ring>) {
+    let suite: &'static rustls::Tls13CipherSuite =
+        match rustls::cipher_suite::TLS13_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls13(suite) => {
+                Box::leak(Box::new(suite.with_confidentiality_limit(limit)))
+            }
+            _ => unreachable!(),
+        };
+    let kt = KeyType::Ecdsa;
+
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[SupportedCipherSuite::Tls13(suite)])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap(),
+    );
+
+    let mut client_config = finish_client_config(
+        kt,
+        ClientConfig::<Ring>::builder()
+            .with_cipher_suites(&[SupportedCipherSuite::Tls13(suite)])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap(),
+    );
+    client_config.max_fragment_size = Some(64);
+    (client_config, server_config)
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn coalesced_write_updates_keys_within_buffer_limit() {
+    let (client_config, server_config) = confidentiality_limited_configs(4);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(2048));
+
+    // 18 records: the key is replaced several times part-way through.
+    let data = (0..1024)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    client
+        .writer()
+        .write_all_vectored(&[&data[..512], &data[512..]])
+        .unwrap();
+
+    // This would exceed the buffer limit, so nothing is accepted.
+    assert_eq!(
+        client
+            .writer()
+            .write_all_vectored(&[&data, &data])
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::WriteZero
+    );
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut received = Vec::new();
+    server
+        .reader()
+        .read_to_end(&mut received)
+        .unwrap_err();
+    assert_eq!(received, data);
+}
+
+/// Test that a write spanning more records than a TLS1.3 key may protect
+/// updates the key part-way through.
+#[cfg(all(feature = "secret_extraction", feature = "testing"))]
+#[test]
+fn large_write_updates_keys_at_confidentiality_limit() {
+    let (mut client_config, mut server_config) = confidentiality_limited_configs(16);
+    server_config.enable_secret_extraction = true;
+    client_config.enable_secret_extraction = true;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    // Many more than 16 records' worth.
+    let data = (0..4096)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut received = Vec::new();
+    server
+        .reader()
+        .read_to_end(&mut received)
+        .unwrap_err();
+    assert_eq!(received, data);
+    assert!(client.stats().key_updated);
+
+    let (client_seq, _) = client.extract_secrets().unwrap().tx;
+    assert!(client_seq <= 16);
+    let (server_seq, _) = server.extract_secrets().unwrap().rx;
+    assert!(server_seq <= 16);
+}
+
+/// Test that the TLS1.2 master secret, together with the handshake randoms,
+/// is enough to decrypt a record captured off the wire.
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_tls12_master_secret_extraction() {
+    use ring::{aead, hmac};
+
+    fn prf_sha256(secret: &[u8], label: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let label_seed = [label, seed].concat();
+        let mut a = hmac::sign(&key, &label_seed);
+        let mut out = Vec::new();
+        while out.len() < len {
+            out.extend_from_slice(hmac::sign(&key, &[a.as_ref(), &label_seed].concat()).as_ref());
+            a = hmac::sign(&key, a.as_ref());
+        }
+        out.truncate(len);
+        out
+    }
+
+    let kt = KeyType::Rsa;
+    let mut server_config = ServerConfig::<Ring>::builder()
+        .with_cipher_suites(&[rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256])
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS12])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+    server_config.enable_secret_extraction = true;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_secret_extraction = true;
+
+    let (client, _) = make_pair_for_configs(client_config.clone(), server_config.clone());
+    assert!(client
+        .dangerous_extract_tls12_master_secret()
+        .is_err());
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // The randoms sit just after the record, handshake and version headers
+    // of the (plaintext) ClientHello and ServerHello.
+    let mut client_hello = Vec::new();
+    client
+        .write_tls(&mut client_hello)
+        .unwrap();
+    server
+        .read_tls(&mut &client_hello[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    let mut server_flight = Vec::new();
+    server
+        .write_tls(&mut server_flight)
+        .unwrap();
+    client
+        .read_tls(&mut &server_flight[..])
+        .unwrap();
+    client.process_new_packets().unwrap();
+    let client_random = &client_hello[11..43];
+    let server_random = &server_flight[11..43];
+
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello world")
+        .unwrap();
+    let mut record = Vec::new();
+    client.write_tls(&mut record).unwrap();
+
+    let master_secret = client
+        .dangerous_extract_tls12_master_secret()
+        .unwrap();
+    assert_eq!(
+        master_secret,
+        server
+            .dangerous_extract_tls12_master_secret()
+            .unwrap()
+    );
+
+    let key_block = prf_sha256(
+        &master_secret,
+        b"key expansion",
+        &[server_random, client_random].concat(),
+        40,
+    );
+    let key = aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::AES_128_GCM, &key_block[..16]).unwrap(),
+    );
+    let nonce = [&key_block[32..36], &record[5..13]].concat();
+    let mut ciphertext = record[13..].to_vec();
+    let plaintext_len = (ciphertext.len() - 16) as u16;
+    // The Finished message used sequence number zero.
+    let aad = [
+        &1u64.to_be_bytes()[..],
+        &[0x17, 0x03, 0x03],
+        &plaintext_len.to_be_bytes(),
+    ]
+    .concat();
+    let plaintext = key
+        .open_in_place(
+            aead::Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+            aead::Aad::from(aad),
+            &mut ciphertext,
+        )
+        .unwrap();
+    assert_eq!(plaintext, b"hello world");
 
-        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
-        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
-    }
+    // TLS1.3 has no master secret to offer.
+    let mut server_config = make_server_config(kt);
+    server_config.enable_secret_extraction = true;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.enable_secret_extraction = true;
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client
+        .dangerous_extract_tls12_master_secret()
+        .is_err());
+}
 
-    #[test]
-    fn test_quic_exporter() {
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+#[test]
+fn test_received_plaintext_backpressure() {
+    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    let kt = KeyType::Rsa;
 
-            do_exporter_test(client_config, server_config);
+    let server_config = Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .unwrap(),
+    );
+
+    let client_config = Arc::new(make_client_config(kt));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // Fill the server's received plaintext buffer with 16k bytes
+    let client_buf = [0; 16_385];
+    dbg!(client
+        .writer()
+        .write(&client_buf)
+        .unwrap());
+    let mut network_buf = Vec::with_capacity(32_768);
+    let sent = dbg!(client
+        .write_tls(&mut network_buf)
+        .unwrap());
+    let mut read = 0;
+    while read < sent {
+        let new = dbg!(server
+            .read_tls(&mut &network_buf[read..sent])
+            .unwrap());
+        if new == 4096 {
+            read += new;
+        } else {
+            break;
         }
     }
-} // mod test_quic
+    server.process_new_packets().unwrap();
 
-#[test]
-fn test_client_does_not_offer_sha1() {
-    use rustls::internal::msgs::{
-        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
-    };
-    use rustls::HandshakeType;
+    // Send two more bytes from client to server
+    dbg!(client
+        .writer()
+        .write(&client_buf[..2])
+        .unwrap());
+    let sent = dbg!(client
+        .write_tls(&mut network_buf)
+        .unwrap());
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+    // Get an error because the received plaintext buffer is full
+    assert!(server
+        .read_tls(&mut &network_buf[..sent])
+        .is_err());
 
-            assert!(client.wants_write());
-            let mut buf = [0u8; 262144];
-            let sz = client
-                .write_tls(&mut buf.as_mut())
-                .unwrap();
-            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
-            let msg = Message::try_from(msg.into_plain_message()).unwrap();
-            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+    // Read out some of the plaintext
+    server
+        .reader()
+        .read_exact(&mut [0; 2])
+        .unwrap();
 
-            let client_hello = match msg.payload {
-                MessagePayload::Handshake { parsed, .. } => match parsed.payload {
-                    HandshakePayload::ClientHello(ch) => ch,
-                    _ => unreachable!(),
-                },
-                _ => unreachable!(),
-            };
+    // Now there's room again in the plaintext buffer
+    assert_eq!(
+        server
+            .read_tls(&mut &network_buf[..sent])
+            .unwrap(),
+        24
+    );
+}
 
-            let sigalgs = client_hello
-                .get_sigalgs_extension()
-                .unwrap();
-            assert!(
-                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
-                "sha1 unexpectedly offered"
-            );
-        }
-    }
+#[test]
+fn test_debug_server_name_from_ip() {
+    assert_eq!(
+        format!(
+            "{:?}",
+            rustls::ServerName::IpAddress("127.0.0.1".parse().unwrap())
+        ),
+        "IpAddress(127.0.0.1)"
+    )
 }
 
 #[test]
-fn test_client_config_keyshare() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+fn test_debug_server_name_from_string() {
+    assert_eq!(
+        format!("{:?}", rustls::ServerName::try_from("a.com").unwrap()),
+        "DnsName(\"a.com\")"
+    )
 }
 
 #[test]
-fn test_client_config_keyshare_mismatch() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+fn test_server_name_from_string() {
+    for (input, expected) in [
+        ("example.com", "DnsName(\"example.com\")"),
+        ("192.0.2.1", "IpAddress(192.0.2.1)"),
+        ("[2001:db8::1]", "IpAddress(2001:db8::1)"),
+        ("2001:db8::1", "IpAddress(2001:db8::1)"),
+        (
+            "xn--bcher-kva.example",
+            "DnsName(\"xn--bcher-kva.example\")",
+        ),
+        ("bücher.example", "DnsName(\"xn--bcher-kva.example\")"),
+        ("BÜCHER.example", "DnsName(\"xn--bcher-kva.example\")"),
+        ("ｅｘａｍｐｌｅ.com", "DnsName(\"example.com\")"),
+    ] {
+        assert_eq!(
+            format!("{:?}", rustls::ServerName::try_from(input).unwrap()),
+            expected
+        );
+    }
+
+    for invalid in [
+        "",
+        "not a name",
+        "example..com",
+        "[192.0.2.1]",
+        "[example.com]",
+        "bü cher.example",
+        "\u{301}a.example",
+        "ex\u{ffff}.com",
+    ] {
+        assert!(rustls::ServerName::try_from(invalid).is_err());
+    }
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn test_client_sends_helloretryrequest() {
-    // client sends a secp384r1 key share
-    let mut client_config = make_client_config_with_kx_groups(
-        KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+fn client_sends_a_label_server_name() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let mut client = ClientConnection::new(
+        client_config,
+        rustls::ServerName::try_from("bücher.example").unwrap(),
+    )
+    .unwrap();
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+
+    // Our test certificate isn't valid for this name.
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+            CertificateError::NotValidForName
+        )))
     );
+    assert_eq!(server.server_name(), Some("xn--bcher-kva.example"));
+}
 
-    let storage = Arc::new(ClientStorage::new());
-    client_config.resumption = Resumption::store(storage.clone());
+/// A key exchange offering only X25519 (via *ring*), which counts its uses.
+struct X25519Only(rustls::crypto::ring::KeyExchange);
 
-    // but server only accepts x25519, so a HRR is required
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+static X25519_ONLY_STARTS: AtomicUsize = AtomicUsize::new(0);
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+#[derive(Debug)]
+struct X25519OnlyGroup;
 
-    // client sends hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 1);
+impl SupportedGroup for X25519OnlyGroup {
+    fn name(&self) -> rustls::NamedGroup {
+        rustls::NamedGroup::X25519
     }
+}
 
-    // server sends HRR
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen < 100); // just the hello retry request
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+impl rustls::crypto::KeyExchange for X25519Only {
+    type SupportedGroup = X25519OnlyGroup;
+
+    fn start(
+        name: rustls::NamedGroup,
+        supported: &[&'static X25519OnlyGroup],
+    ) -> Result<Self, KeyExchangeError> {
+        if name != rustls::NamedGroup::X25519 || supported.is_empty() {
+            return Err(KeyExchangeError::UnsupportedGroup);
+        }
+
+        X25519_ONLY_STARTS.fetch_add(1, Ordering::SeqCst);
+        rustls::crypto::ring::KeyExchange::start(name, &[&rustls::kx_group::X25519]).map(Self)
     }
 
-    // client sends fixed hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200); // just the client hello retry
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    fn complete<T>(self, peer: &[u8], f: impl FnOnce(&[u8]) -> Result<T, ()>) -> Result<T, Error> {
+        self.0.complete(peer, f)
     }
 
-    // server completes handshake
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    fn group(&self) -> rustls::NamedGroup {
+        self.0.group()
     }
 
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    fn pub_key(&self) -> &[u8] {
+        self.0.pub_key()
+    }
 
-    // client only did following storage queries:
-    println!("storage {:#?}", storage.ops());
-    assert_eq!(storage.ops().len(), 9);
-    assert!(matches!(
-        storage.ops()[0],
-        ClientStorageOp::TakeTls13Ticket(_, false)
-    ));
-    assert!(matches!(
-        storage.ops()[1],
-        ClientStorageOp::GetTls12Session(_, false)
-    ));
-    assert!(matches!(
-        storage.ops()[2],
-        ClientStorageOp::GetKxHint(_, None)
-    ));
-    assert!(matches!(
-        storage.ops()[3],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
-    ));
-    assert!(matches!(
-        storage.ops()[4],
-        ClientStorageOp::RemoveTls12Session(_)
-    ));
-    // server sends 4 tickets by default
-    assert!(matches!(
-        storage.ops()[5],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[6],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[7],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[8],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
+    fn all_kx_groups() -> &'static [&'static X25519OnlyGroup] {
+        &[&X25519OnlyGroup]
+    }
+}
+
+#[test]
+fn composite_provider_with_custom_key_exchange() {
+    type Composite = CompositeProvider<X25519Only, Ring>;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let before = X25519_ONLY_STARTS.load(Ordering::SeqCst);
+
+            let client_config = finish_client_config(
+                *kt,
+                ClientConfig::<Composite>::builder()
+                    .with_safe_default_cipher_suites()
+                    .with_safe_default_kx_groups()
+                    .with_protocol_versions(&[version])
+                    .unwrap(),
+            );
+            let server_config = finish_server_config(
+                *kt,
+                ServerConfig::<Composite>::builder().with_safe_defaults(),
+            );
+
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(client.protocol_version(), Some(version.version));
+            // One key exchange each for the client and server.
+            assert_eq!(X25519_ONLY_STARTS.load(Ordering::SeqCst), before + 2);
+        }
+    }
 }
 
-#[cfg(feature = "tls12")]
-#[test]
-fn test_client_attempts_to_use_unsupported_kx_group() {
-    // common to both client configs
-    let shared_storage = Arc::new(ClientStorage::new());
+fn is_grease(value: u16) -> bool {
+    let [hi, lo] = value.to_be_bytes();
+    hi == lo && lo & 0x0f == 0x0a
+}
 
-    // first, client sends a x25519 and server agrees. x25519 is inserted
-    //   into kx group cache.
-    let mut client_config_1 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    client_config_1.resumption = Resumption::store(shared_storage.clone());
+fn client_hello_for(client_config: ClientConfig<Ring>) -> ClientHelloPayload {
+    let (mut client, _) = make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    let mut buf = [0u8; 262144];
+    let sz = client
+        .write_tls(&mut buf.as_mut())
+        .unwrap();
+    parse_client_hello(&buf[..sz])
+}
 
-    // second, client only supports secp-384 and so kx group cache
-    //   contains an unusable value.
-    let mut client_config_2 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    client_config_2.resumption = Resumption::store(shared_storage.clone());
+fn parse_client_hello(tls: &[u8]) -> ClientHelloPayload {
+    use rustls::internal::msgs::{codec::Reader, message::OpaqueMessage};
 
-    let server_config = make_server_config(KeyType::Rsa);
+    let msg = OpaqueMessage::read(&mut Reader::init(tls)).unwrap();
+    match Message::try_from(msg.into_plain_message())
+        .unwrap()
+        .payload
+    {
+        MessagePayload::Handshake { parsed, .. } => match parsed.payload {
+            HandshakePayload::ClientHello(ch) => ch,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
 
-    // first handshake
-    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
-    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+#[test]
+fn client_sends_grease_values() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[version]).with_grease(true);
+        let ch = client_hello_for(client_config.clone());
+        assert!(is_grease(ch.cipher_suites[0].get_u16()));
+        assert!(is_grease(ch.extensions[0].get_type().get_u16()));
+        assert!(is_grease(
+            ch.get_namedgroups_extension().unwrap()[0].get_u16()
+        ));
+        assert!(is_grease(ch.get_versions_extension().unwrap()[0].get_u16()));
 
-    let ops = shared_storage.ops();
-    println!("storage {:#?}", ops);
-    assert_eq!(ops.len(), 9);
-    assert!(matches!(
-        ops[3],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
-    ));
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(version.version));
+    }
+}
 
-    // second handshake
-    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
-    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+#[test]
+fn client_sends_no_grease_values_by_default() {
+    let ch = client_hello_for(make_client_config(KeyType::Rsa));
+    assert!(!ch
+        .cipher_suites
+        .iter()
+        .any(|cs| is_grease(cs.get_u16())));
+    assert!(!ch
+        .extensions
+        .iter()
+        .any(|ext| is_grease(ext.get_type().get_u16())));
+}
 
-    let ops = shared_storage.ops();
-    println!("storage {:?} {:#?}", ops.len(), ops);
-    assert_eq!(ops.len(), 17);
-    assert!(matches!(ops[9], ClientStorageOp::TakeTls13Ticket(_, true)));
-    assert!(matches!(
-        ops[10],
-        ClientStorageOp::GetKxHint(_, Some(rustls::NamedGroup::X25519))
-    ));
-    assert!(matches!(
-        ops[11],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::secp384r1)
-    ));
+#[test]
+fn client_sends_session_ticket_extension_unless_tickets_disabled() {
+    let sends_session_ticket = |tls12_resumption| {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.resumption = Resumption::default().tls12_resumption(tls12_resumption);
+        client_hello_for(client_config)
+            .find_extension(ExtensionType::SessionTicket)
+            .is_some()
+    };
+
+    assert!(sends_session_ticket(Tls12Resumption::SessionIdOrTickets));
+    assert!(!sends_session_ticket(Tls12Resumption::SessionIdOnly));
+    assert!(!sends_session_ticket(Tls12Resumption::Disabled));
+    assert!(client_hello_for(make_client_config(KeyType::Rsa))
+        .find_extension(ExtensionType::SessionTicket)
+        .is_some());
 }
 
 #[cfg(feature = "tls12")]
 #[test]
-fn test_tls13_client_resumption_does_not_reuse_tickets() {
-    let shared_storage = Arc::new(ClientStorage::new());
+fn session_id_only_resumption_stores_no_ticket() {
+    use rustls::internal::msgs::handshake::{ClientExtension, ClientSessionTicket};
 
-    let mut client_config = make_client_config(KeyType::Rsa);
-    client_config.resumption = Resumption::store(shared_storage.clone());
-    let client_config = Arc::new(client_config);
+    let store = Arc::new(rustls::client::ClientSessionMemoryCache::new(32));
+    let mut tickets_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    tickets_config.resumption = Resumption::store(store.clone());
+    let mut session_id_config = tickets_config.clone();
+    session_id_config.resumption =
+        Resumption::store(store).tls12_resumption(Tls12Resumption::SessionIdOnly);
 
     let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.send_tls13_tickets = 5;
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
     let server_config = Arc::new(server_config);
 
-    // first handshake: client obtains 5 tickets from server.
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    let offered_ticket = |config: &ClientConfig<Ring>| {
+        client_hello_for(config.clone())
+            .extensions
+            .iter()
+            .any(|ext| {
+                matches!(
+                    ext,
+                    ClientExtension::SessionTicket(ClientSessionTicket::Offer(_))
+                )
+            })
+    };
 
-    let ops = shared_storage.ops_and_reset();
-    println!("storage {:#?}", ops);
-    assert_eq!(ops.len(), 10);
-    assert!(matches!(ops[5], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[6], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[7], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[8], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[9], ClientStorageOp::InsertTls13Ticket(_)));
+    // the server issues a ticket, alongside a session ID...
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(tickets_config.clone()), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(offered_ticket(&tickets_config));
 
-    // 5 subsequent handshakes: all are resumptions
+    // ...which a client using only session IDs resumes, dropping the ticket
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(session_id_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_resumed());
+    assert!(!offered_ticket(&tickets_config));
+}
 
-    // Note: we don't do complete the handshakes, because that means
-    // we get five additional tickets per connection which is unhelpful
-    // in this test.  It also acts to record a "Happy Eyeballs"-type use
-    // case, where a client speculatively makes many connection attempts
-    // in parallel without knowledge of which will work due to underlying
-    // connectivity uncertainty.
-    for _ in 0..5 {
-        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
+#[cfg(feature = "tls12")]
+#[test]
+fn client_ignores_grease_extension_echoed_by_server() {
+    let grease_type = Mutex::new(None);
+    let find_grease_type = |msg: &mut Message| {
+        if let MessagePayload::Handshake { parsed, .. } = &msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &parsed.payload {
+                *grease_type.lock().unwrap() = Some(ch.extensions[0].get_type());
+            }
+        }
+        Altered::InPlace
+    };
+    let echo_grease_type = |msg: &mut Message| {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .push(ServerExtension::Unknown(UnknownExtension {
+                        typ: grease_type.lock().unwrap().unwrap(),
+                        payload: Payload::new(b"echo".to_vec()),
+                    }));
+            }
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    };
 
-        let ops = shared_storage.ops_and_reset();
-        assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, true)));
-    }
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12])
+        .with_grease(true);
+    let (client, server) = make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    let (mut client, mut server) = (client.into(), server.into());
 
-    // 6th subsequent handshake: cannot be resumed; we ran out of tickets
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    transfer(&mut client, &mut server);
+    transfer_altered(&mut client, find_grease_type, &mut server);
     server.process_new_packets().unwrap();
+    transfer_altered(&mut server, echo_grease_type, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.wants_write());
+}
 
-    let ops = shared_storage.ops_and_reset();
-    println!("last {:?}", ops);
-    assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, false)));
+#[test]
+fn client_hello_layout_orders_extensions() {
+    let layout = vec![
+        ExtensionType::ALProtocolNegotiation,
+        ExtensionType::ServerName,
+        ExtensionType::Padding,
+        ExtensionType::SupportedVersions,
+        ExtensionType::KeyShare,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::EllipticCurves,
+    ];
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13])
+            .with_client_hello_layout(rustls::client::ClientHelloLayout::new(layout.clone()));
+    client_config.alpn_protocols = vec![b"proto".to_vec()];
+
+    let ch = client_hello_for(client_config.clone());
+    let sent = ch
+        .extensions
+        .iter()
+        .map(|ext| ext.get_type())
+        .collect::<Vec<_>>();
+    let expected = layout
+        .into_iter()
+        .filter(|typ| *typ != ExtensionType::Padding)
+        .collect::<Vec<_>>();
+    assert_eq!(sent, expected);
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
 }
 
 #[test]
-fn test_client_mtu_reduction() {
-    struct CollectWrites {
-        writevs: Vec<Vec<usize>>,
-    }
+fn client_hello_layout_keeps_pre_shared_key_last() {
+    let layout = vec![
+        ExtensionType::PreSharedKey,
+        ExtensionType::PSKKeyExchangeModes,
+        ExtensionType::SupportedVersions,
+        ExtensionType::KeyShare,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::EllipticCurves,
+    ];
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13])
+            .with_client_hello_layout(rustls::client::ClientHelloLayout::new(layout));
+    client_config.preshared_keys = vec![external_psk(b"shared secret")];
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.preshared_keys = vec![external_psk(b"shared secret")];
 
-    impl io::Write for CollectWrites {
-        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-            panic!()
-        }
-        fn flush(&mut self) -> io::Result<()> {
-            panic!()
-        }
-        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-            let writes = b
-                .iter()
-                .map(|slice| slice.len())
-                .collect::<Vec<usize>>();
-            let len = writes.iter().sum();
-            self.writevs.push(writes);
-            Ok(len)
-        }
-    }
+    let ch = client_hello_for(client_config.clone());
+    assert_eq!(
+        ch.extensions
+            .iter()
+            .map(|ext| ext.get_type())
+            .collect::<Vec<_>>(),
+        vec![
+            ExtensionType::PSKKeyExchangeModes,
+            ExtensionType::SupportedVersions,
+            ExtensionType::KeyShare,
+            ExtensionType::SignatureAlgorithms,
+            ExtensionType::EllipticCurves,
+            ExtensionType::PreSharedKey,
+        ]
+    );
 
-    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
-        let mut collector = CollectWrites { writevs: vec![] };
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_certificates(), None);
+}
+
+#[test]
+fn client_hello_layout_keeps_early_data() {
+    let (client_config, server_config) = early_data_configs();
+    let client_config = Arc::new(
+        ClientConfig::clone(&client_config).with_client_hello_layout(
+            rustls::client::ClientHelloLayout::new(vec![
+                ExtensionType::SupportedVersions,
+                ExtensionType::KeyShare,
+                ExtensionType::SignatureAlgorithms,
+                ExtensionType::EllipticCurves,
+                ExtensionType::PSKKeyExchangeModes,
+                ExtensionType::PreSharedKey,
+            ]),
+        ),
+    );
 
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
         client
-            .write_tls(&mut collector)
-            .unwrap();
-        assert_eq!(collector.writevs.len(), 1);
-        collector.writevs[0].clone()
-    }
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
 
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.max_fragment_size = Some(64);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
-        let writes = collect_write_lengths(&mut client);
-        println!("writes at mtu=64: {:?}", writes);
-        assert!(writes.iter().all(|x| *x <= 64));
-        assert!(writes.len() > 1);
-    }
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .unwrap()
+            .read(&mut received_early_data)
+            .unwrap(),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
 }
 
 #[test]
-fn test_server_mtu_reduction() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.max_fragment_size = Some(64);
-    server_config.send_half_rtt_data = true;
+fn server_reports_client_hello_fingerprint() {
+    let client_config = make_client_config(KeyType::Rsa).with_grease(true);
     let (mut client, mut server) =
-        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    assert_eq!(server.client_hello_fingerprint(), None);
+    do_handshake(&mut client, &mut server);
 
-    let big_data = [0u8; 2048];
-    server
-        .writer()
-        .write_all(&big_data)
+    let fingerprint = server
+        .client_hello_fingerprint()
         .unwrap();
+    let fields = fingerprint
+        .split(',')
+        .collect::<Vec<_>>();
+    assert_eq!(fields.len(), 5);
+    assert_eq!(fields[0], "771");
+    assert!(fields[1].ends_with("-255"));
+    assert_eq!(fields[4], "0");
+    assert!(!fields[1..4]
+        .iter()
+        .flat_map(|field| field.split('-'))
+        .any(|value| is_grease(value.parse().unwrap())));
+}
 
-    let encryption_overhead = 20; // FIXME: see issue #991
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
+#[test]
+fn server_cipher_suite_selector_overrides_preference() {
+    for version in rustls::ALL_VERSIONS {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&seen);
+        let server_config = make_server_config(KeyType::Ecdsa).with_cipher_suite_selector(
+            move |hello: &rustls::server::ClientHello<'_>, suites: &[SupportedCipherSuite]| {
+                assert!(!hello.cipher_suites().is_empty());
+                *recorded.lock().unwrap() = suites.to_vec();
+                suites.last().copied()
+            },
+        );
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
 
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
-    }
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() > 1);
+        assert!(seen
             .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
+            .all(|scs| scs.version() == *version));
+        assert_eq!(client.negotiated_cipher_suite(), seen.last().copied());
+        assert_ne!(client.negotiated_cipher_suite(), seen.first().copied());
     }
-
-    client.process_new_packets().unwrap();
-    check_read(&mut client.reader(), &big_data);
-}
-
-fn check_client_max_fragment_size(size: usize) -> Option<Error> {
-    let mut client_config = make_client_config(KeyType::Ed25519);
-    client_config.max_fragment_size = Some(size);
-    ClientConnection::new(Arc::new(client_config), server_name("localhost")).err()
 }
 
 #[test]
-fn bad_client_max_fragment_sizes() {
-    assert_eq!(
-        check_client_max_fragment_size(31),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(check_client_max_fragment_size(32), None);
-    assert_eq!(check_client_max_fragment_size(64), None);
-    assert_eq!(check_client_max_fragment_size(1460), None);
-    assert_eq!(check_client_max_fragment_size(0x4000), None);
-    assert_eq!(check_client_max_fragment_size(0x4005), None);
-    assert_eq!(
-        check_client_max_fragment_size(0x4006),
-        Some(Error::BadMaxFragmentSize)
-    );
+fn server_cipher_suite_selector_can_abort() {
+    let server_config = make_server_config(KeyType::Rsa).with_cipher_suite_selector(|_, _| None);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
     assert_eq!(
-        check_client_max_fragment_size(0xffff),
-        Some(Error::BadMaxFragmentSize)
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+            PeerIncompatible::NoCipherSuitesInCommon
+        )))
     );
 }
 
-fn assert_lt(left: usize, right: usize) {
-    if left >= right {
-        panic!("expected {} < {}", left, right);
-    }
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_point_formats_are_offered_and_echoed() {
+    use rustls::internal::msgs::enums::ECPointFormat;
+
+    let client_config =
+        make_client_config_with_versions(KeyType::Ecdsa, &[&rustls::version::TLS12]);
+    let ch = client_hello_for(client_config.clone());
+    assert_eq!(
+        ch.get_ecpoints_extension(),
+        Some(&[ECPointFormat::Uncompressed][..])
+    );
+
+    // Our server refuses TLS1.2 ClientHellos without this extension.
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Ecdsa));
+    do_handshake(&mut client, &mut server);
+    assert!(client
+        .peer_extensions()
+        .contains(&ExtensionType::ECPointFormats));
 }
 
 #[test]
-fn connection_types_are_not_huge() {
-    // Arbitrary sizes
-    assert_lt(mem::size_of::<ServerConnection>(), 1600);
-    assert_lt(mem::size_of::<ClientConnection>(), 1600);
+fn client_require_ems_accepts_server_with_ems() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[version]).with_require_ems(true);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+    }
 }
 
-use rustls::internal::msgs::{
-    handshake::ClientExtension, handshake::HandshakePayload, message::Message,
-    message::MessagePayload,
-};
-
+#[cfg(feature = "tls12")]
 #[test]
-fn test_server_rejects_duplicate_sni_names() {
-    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
+fn client_require_ems_rejects_server_without_ems() {
+    fn strip_ems_ack(msg: &mut Message) -> Altered {
         if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.push(snr[0].clone());
-                    }
-                }
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
             }
-
             *encoded = Payload::new(parsed.get_encoding());
         }
         Altered::InPlace
     }
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+    for require_ems in [false, true] {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12])
+                .with_require_ems(require_ems);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let (mut client, mut server) = (client.into(), server.into());
+        transfer_altered(&mut server, strip_ems_ack, &mut client);
+        let result = client.process_new_packets();
+        if require_ems {
+            assert_eq!(
+                result.err(),
+                Some(Error::PeerIncompatible(
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired
+                ))
+            );
+        } else {
+            assert!(result.is_ok());
+        }
+    }
+}
+
+#[test]
+fn oversized_record_is_rejected_with_record_overflow() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut client_hello = Vec::new();
+    client
+        .write_tls(&mut client_hello)
+        .unwrap();
+
+    // A record header claiming 65535 bytes of payload, which is never sent.
+    client
+        .read_tls(&mut &[0x16, 0x03, 0x03, 0xff, 0xff][..])
+        .unwrap();
     assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::DuplicateServerNameTypes
+        client.process_new_packets().err(),
+        Some(Error::InvalidMessage(
+            rustls::InvalidMessage::MessageTooLarge
         ))
     );
+
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert, [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x16]);
 }
 
 #[test]
-fn test_server_rejects_empty_sni_extension() {
-    fn empty_sni_payload(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.clear();
-                    }
-                }
-            }
+fn client_rejects_tls13_server_hello_without_supported_versions() {
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::message::{OpaqueMessage, PlainMessage};
 
-            *encoded = Payload::new(parsed.get_encoding());
-        }
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-        Altered::InPlace
+    // The rest of the server's flight is encrypted, so only alter and
+    // deliver the ServerHello.
+    let mut server_flight = Vec::new();
+    server
+        .write_tls(&mut server_flight)
+        .unwrap();
+    let server_hello = OpaqueMessage::read(&mut Reader::init(&server_flight)).unwrap();
+    let mut server_hello = Message::try_from(server_hello.into_plain_message()).unwrap();
+    if let MessagePayload::Handshake { parsed, encoded } = &mut server_hello.payload {
+        if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+            sh.extensions
+                .retain(|ext| ext.get_type() != ExtensionType::SupportedVersions);
+        }
+        *encoded = Payload::new(parsed.get_encoding());
     }
+    let server_hello = PlainMessage::from(server_hello)
+        .into_unencrypted_opaque()
+        .encode();
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, empty_sni_payload, &mut server);
+    client
+        .read_tls(&mut &server_hello[..])
+        .unwrap();
     assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::ServerNameMustContainOneHostName
-        ))
+        client.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(PeerMisbehaved::MissingExtension(
+            ExtensionType::SupportedVersions
+        )))
     );
+
+    // The server now expects encrypted records, so check the client's
+    // plaintext alert directly.
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert, [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x6d]);
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_server_rejects_clients_without_any_kx_group_overlap() {
-    fn different_kx_group(msg: &mut Message) -> Altered {
+fn client_reports_unsolicited_server_hello_extension() {
+    fn add_unsolicited_extension(msg: &mut Message) -> Altered {
         if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
-                        ngs.clear();
-                    }
-                    if let ClientExtension::KeyShare(ks) = &mut ext {
-                        ks.clear();
-                    }
-                }
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .push(ServerExtension::Unknown(UnknownExtension {
+                        typ: ExtensionType::Unknown(0xfeed),
+                        payload: Payload::new(b"unsolicited".to_vec()),
+                    }));
             }
-
             *encoded = Payload::new(parsed.get_encoding());
         }
         Altered::InPlace
     }
 
-    let (client, server) = make_pair(KeyType::Rsa);
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (client, server) = make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
     let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, different_kx_group, &mut server);
+    transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, add_unsolicited_extension, &mut client);
     assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerIncompatible(
-            PeerIncompatible::NoKxGroupsInCommon
-        ))
+        client.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(PeerMisbehaved::UnsolicitedExtension(
+            ExtensionType::Unknown(0xfeed)
+        )))
     );
 }
 
+const NON_ECDSA_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PKCS1_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA512,
+    SignatureScheme::ED25519,
+];
+
 #[test]
-fn test_client_rejects_illegal_tls13_ccs() {
-    fn corrupt_ccs(msg: &mut Message) -> Altered {
-        if let MessagePayload::ChangeCipherSpec(_) = &mut msg.payload {
-            println!("seen CCS {:?}", msg);
-            return Altered::Raw(vec![0x14, 0x03, 0x03, 0x00, 0x02, 0x01, 0x02]);
+fn client_signature_schemes_can_exclude_ecdsa() {
+    let offered = Mutex::new(Vec::new());
+    let find_offered = |msg: &mut Message| {
+        if let MessagePayload::Handshake { parsed, .. } = &msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &parsed.payload {
+                *offered.lock().unwrap() = ch
+                    .get_sigalgs_extension()
+                    .unwrap()
+                    .to_vec();
+            }
         }
         Altered::InPlace
-    }
+    };
 
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version])
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+        let (client, server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Ecdsa));
+        let (mut client, mut server) = (client.into(), server.into());
+        transfer_altered(&mut client, find_offered, &mut server);
+
+        let offered = offered.lock().unwrap().clone();
+        assert!(!offered.is_empty());
+        assert!(offered
+            .iter()
+            .all(|scheme| NON_ECDSA_SCHEMES.contains(scheme)));
 
-    let (mut server, mut client) = (server.into(), client.into());
+        // so the server cannot use its ECDSA certificate.
+        assert!(server.process_new_packets().is_err());
 
-    transfer_altered(&mut server, corrupt_ccs, &mut client);
-    assert_eq!(
-        client.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::IllegalMiddleboxChangeCipherSpec
-        ))
-    );
+        // ... but can use an RSA one.
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version])
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+    }
 }
 
-/// https://github.com/rustls/rustls/issues/797
 #[cfg(feature = "tls12")]
 #[test]
-fn test_client_tls12_no_resume_after_server_downgrade() {
-    let mut client_config = common::make_client_config(KeyType::Ed25519);
-    let client_storage = Arc::new(ClientStorage::new());
-    client_config.resumption = Resumption::store(client_storage.clone());
-    let client_config = Arc::new(client_config);
-
-    let server_config_1 = Arc::new(common::finish_server_config(
-        KeyType::Ed25519,
-        ServerConfig::<Ring>::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&rustls::version::TLS13])
-            .unwrap(),
-    ));
-
-    let mut server_config_2 = common::finish_server_config(
-        KeyType::Ed25519,
-        ServerConfig::<Ring>::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&rustls::version::TLS12])
-            .unwrap(),
-    );
-    server_config_2.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
-
-    dbg!("handshake 1");
-    let mut client_1 =
-        ClientConnection::new(client_config.clone(), "localhost".try_into().unwrap()).unwrap();
-    let mut server_1 = ServerConnection::new(server_config_1).unwrap();
-    common::do_handshake(&mut client_1, &mut server_1);
-
-    assert_eq!(client_storage.ops().len(), 9);
-    println!("hs1 storage ops: {:#?}", client_storage.ops());
-    assert!(matches!(
-        client_storage.ops()[3],
-        ClientStorageOp::SetKxHint(_, _)
-    ));
-    assert!(matches!(
-        client_storage.ops()[4],
-        ClientStorageOp::RemoveTls12Session(_)
-    ));
-    assert!(matches!(
-        client_storage.ops()[5],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-
-    dbg!("handshake 2");
-    let mut client_2 =
-        ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
-    let mut server_2 = ServerConnection::new(Arc::new(server_config_2)).unwrap();
-    common::do_handshake(&mut client_2, &mut server_2);
-    println!("hs2 storage ops: {:#?}", client_storage.ops());
-    assert_eq!(client_storage.ops().len(), 11);
-
-    // attempt consumes a TLS1.3 ticket
-    assert!(matches!(
-        client_storage.ops()[9],
-        ClientStorageOp::TakeTls13Ticket(_, true)
-    ));
+fn client_rejects_server_signing_with_excluded_scheme() {
+    // The TLS1.2 ServerKeyExchange signature does not cover the ClientHello,
+    // so a server can be tricked into using an ECDSA scheme the client did
+    // not offer.
+    fn offer_ecdsa(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::SignatureAlgorithms(schemes) = ext {
+                        schemes.insert(0, SignatureScheme::ECDSA_NISTP256_SHA256);
+                    }
+                }
+            }
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
 
-    // but ends up with TLS1.2
+    let client_config =
+        make_client_config_with_versions(KeyType::Ecdsa, &[&rustls::version::TLS12])
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+    let (client, server) = make_pair_for_configs(client_config, make_server_config(KeyType::Ecdsa));
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, offer_ecdsa, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, |_| Altered::InPlace, &mut client);
     assert_eq!(
-        client_2.protocol_version(),
-        Some(rustls::ProtocolVersion::TLSv1_2)
+        client.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme
+        ))
     );
 }
 
 #[test]
-fn test_acceptor() {
-    use rustls::server::Acceptor;
-
-    let client_config = Arc::new(make_client_config(KeyType::Ed25519));
-    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
-    let mut buf = Vec::new();
-    client.write_tls(&mut buf).unwrap();
+fn server_signature_schemes_can_exclude_ecdsa() {
+    for version in rustls::ALL_VERSIONS {
+        let server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa)
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    let server_config = Arc::new(make_server_config(KeyType::Ed25519));
-    let mut acceptor = Acceptor::default();
-    acceptor
-        .read_tls(&mut buf.as_slice())
-        .unwrap();
-    let accepted = acceptor.accept().unwrap().unwrap();
-    let ch = accepted.client_hello();
-    assert_eq!(ch.server_name(), Some("localhost"));
+        // An ECDSA certificate is now unusable.
+        let server_config = make_server_config(KeyType::Ecdsa)
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert!(matches!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(_))
+        ));
+    }
 
-    let server = accepted
-        .into_connection(server_config)
-        .unwrap();
-    assert!(server.wants_write());
+    assert!(make_server_config(KeyType::Rsa)
+        .with_signature_schemes(&[])
+        .is_err());
+    assert!(make_client_config(KeyType::Rsa)
+        .with_signature_schemes(&[])
+        .is_err());
+}
 
-    // Reusing an acceptor is not allowed
-    assert_eq!(
-        acceptor
-            .read_tls(&mut [0u8].as_ref())
-            .err()
-            .unwrap()
-            .kind(),
-        io::ErrorKind::Other,
-    );
-    assert_eq!(
-        acceptor.accept().err(),
-        Some(Error::General("Acceptor polled after completion".into()))
+#[test]
+fn client_sends_signature_algorithms_cert_when_sets_differ() {
+    let ch = client_hello_for(make_client_config(KeyType::Rsa));
+    let all_schemes = ch
+        .get_sigalgs_extension()
+        .unwrap()
+        .to_vec();
+    assert!(ch
+        .get_sigalgs_cert_extension()
+        .is_none());
+
+    let ch = client_hello_for(
+        make_client_config(KeyType::Rsa)
+            .with_signature_schemes(&all_schemes)
+            .unwrap(),
     );
+    assert!(ch
+        .get_sigalgs_cert_extension()
+        .is_none());
 
-    let mut acceptor = Acceptor::default();
-    assert!(acceptor.accept().unwrap().is_none());
-    acceptor
-        .read_tls(&mut &buf[..3])
-        .unwrap(); // incomplete message
-    assert!(acceptor.accept().unwrap().is_none());
-    acceptor
-        .read_tls(&mut [0x80, 0x00].as_ref())
-        .unwrap(); // invalid message (len = 32k bytes)
-    assert!(acceptor.accept().is_err());
+    let ch = client_hello_for(
+        make_client_config(KeyType::Rsa)
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap(),
+    );
+    assert!(ch
+        .get_sigalgs_extension()
+        .unwrap()
+        .iter()
+        .all(|scheme| NON_ECDSA_SCHEMES.contains(scheme)));
+    assert_eq!(ch.get_sigalgs_cert_extension(), Some(&all_schemes[..]));
+}
 
-    let mut acceptor = Acceptor::default();
-    // Minimal valid 1-byte application data message is not a handshake message
-    acceptor
-        .read_tls(&mut [0x17, 0x03, 0x03, 0x00, 0x01, 0x00].as_ref())
-        .unwrap();
-    assert!(acceptor.accept().is_err());
+struct ServerRecordCertSchemes {
+    inner: Arc<dyn ResolvesServerCert>,
+    cert_schemes: Mutex<Vec<SignatureScheme>>,
+}
 
-    let mut acceptor = Acceptor::default();
-    // Minimal 1-byte ClientHello message is not a legal handshake message
-    acceptor
-        .read_tls(&mut [0x16, 0x03, 0x03, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00].as_ref())
-        .unwrap();
-    assert!(acceptor.accept().is_err());
+impl ResolvesServerCert for ServerRecordCertSchemes {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        *self.cert_schemes.lock().unwrap() = client_hello
+            .certificate_signature_schemes()
+            .to_vec();
+        self.inner.resolve(client_hello)
+    }
 }
 
-#[derive(Default, Debug)]
-struct LogCounts {
-    trace: usize,
-    debug: usize,
-    info: usize,
-    warn: usize,
-    error: usize,
+#[test]
+fn server_sees_certificate_signature_schemes() {
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        let resolver = Arc::new(ServerRecordCertSchemes {
+            inner: Arc::clone(&server_config.cert_resolver),
+            cert_schemes: Mutex::new(Vec::new()),
+        });
+        server_config.cert_resolver = Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>;
+        let server_config = Arc::new(server_config);
+
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version])
+            .with_signature_schemes(NON_ECDSA_SCHEMES)
+            .unwrap();
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(resolver
+            .cert_schemes
+            .lock()
+            .unwrap()
+            .contains(&SignatureScheme::ECDSA_NISTP256_SHA256));
+
+        // Without the extension, these are the handshake signature schemes.
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(resolver
+            .cert_schemes
+            .lock()
+            .unwrap()
+            .contains(&SignatureScheme::RSA_PSS_SHA256));
+    }
 }
 
-impl LogCounts {
-    fn new() -> Self {
-        Self {
-            ..Default::default()
-        }
+#[test]
+fn client_requires_ocsp_response_for_must_staple_certificate() {
+    let chain: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut io::BufReader::new(
+        KeyType::Rsa.bytes_for("end-must-staple.fullchain"),
+    ))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+    let server_config_with_ocsp = |ocsp: &[u8]| {
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp(chain.clone(), KeyType::Rsa.get_key(), ocsp.to_vec())
+            .unwrap()
+    };
+
+    for version in rustls::ALL_VERSIONS {
+        let server_config = server_config_with_ocsp(KeyType::Rsa.bytes_for("end-must-staple.ocsp"));
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let server_config = server_config_with_ocsp(&[]);
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::MissingOcspResponse
+            )))
+        );
     }
+}
 
-    fn reset(&mut self) {
-        *self = Self::new();
+#[test]
+fn client_rejects_ocsp_response_signed_by_decoy_issuer() {
+    // The chain includes a certificate with the issuer's name but another
+    // key, which path building skips.  A response signed by it must not
+    // count.
+    let chain: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut io::BufReader::new(
+        KeyType::Rsa.bytes_for("end-must-staple-decoy.fullchain"),
+    ))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+
+    for version in rustls::ALL_VERSIONS {
+        let server_config = ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp(
+                chain.clone(),
+                KeyType::Rsa.get_key(),
+                KeyType::Rsa
+                    .bytes_for("end-must-staple-decoy.ocsp")
+                    .to_vec(),
+            )
+            .unwrap();
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::InvalidOcspResponse
+            )))
+        );
     }
+}
 
-    fn add(&mut self, level: log::Level) {
-        match level {
-            log::Level::Trace => self.trace += 1,
-            log::Level::Debug => self.debug += 1,
-            log::Level::Info => self.info += 1,
-            log::Level::Warn => self.warn += 1,
-            log::Level::Error => self.error += 1,
+#[cfg(feature = "tls12")]
+#[test]
+fn client_detects_downgrade_sentinel() {
+    fn add_downgrade_sentinel(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.random.0[24..].copy_from_slice(b"DOWNGRD\x01");
+            }
+            *encoded = Payload::new(parsed.get_encoding());
         }
+        Altered::InPlace
     }
-}
 
-thread_local!(static COUNTS: RefCell<LogCounts> = RefCell::new(LogCounts::new()));
+    // The server only supports TLS1.2, so does not send the sentinel
+    // itself: an attacker is pretending it does not support TLS1.3.
+    let client_config = make_client_config(KeyType::Rsa);
+    let server_config = make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-struct CountingLogger;
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut server, add_downgrade_sentinel, &mut client);
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::AttemptedDowngradeToTls12WhenTls13IsSupported
+        ))
+    );
+    transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::AlertReceived(
+            rustls::AlertDescription::IllegalParameter
+        ))
+    );
 
-static LOGGER: CountingLogger = CountingLogger;
+    // A TLS1.2-only client ignores the sentinel, which a TLS1.3-capable
+    // server sends in every TLS1.2 ServerHello.
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-impl CountingLogger {
-    fn install() {
-        log::set_logger(&LOGGER).unwrap();
-        log::set_max_level(log::LevelFilter::Trace);
-    }
+    let mut server_flight = Vec::new();
+    server
+        .write_tls(&mut server_flight)
+        .unwrap();
+    // The ServerHello random follows the record and handshake headers,
+    // and the legacy version.
+    assert_eq!(&server_flight[35..43], b"DOWNGRD\x01");
 
-    fn reset() {
-        COUNTS.with(|c| {
-            c.borrow_mut().reset();
-        });
-    }
+    client
+        .read_tls(&mut &server_flight[..])
+        .unwrap();
+    client.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_handshaking());
 }
 
-impl log::Log for CountingLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+#[test]
+fn application_data_during_handshake_is_rejected() {
+    // An unencrypted application data record.
+    const APP_DATA: &[u8] = &[0x17, 0x03, 0x03, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o'];
+
+    fn inject(conn: &mut ConnectionCommon<impl SideData>) -> Option<Error> {
+        conn.read_tls(&mut &APP_DATA[..])
+            .unwrap();
+        conn.process_new_packets().err()
     }
 
-    fn log(&self, record: &log::Record) {
-        println!("logging at {:?}: {:?}", record.level(), record.args());
+    // ... to a client awaiting the ServerHello
+    let mut client = ClientConnection::new(
+        Arc::new(make_client_config(KeyType::Rsa)),
+        server_name("localhost"),
+    )
+    .unwrap();
+    client
+        .write_tls(&mut io::sink())
+        .unwrap();
+    assert_eq!(
+        inject(&mut client),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::ApplicationDataDuringHandshake
+        ))
+    );
+    assert!(client
+        .reader()
+        .read(&mut [0u8; 5])
+        .is_err());
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert, &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x0a]);
 
-        COUNTS.with(|c| {
-            c.borrow_mut().add(record.level());
-        });
+    // ... to a TLS1.2 server awaiting the client's key exchange
+    #[cfg(feature = "tls12")]
+    {
+        let (mut client, mut server) = make_pair_for_configs(
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]),
+            make_server_config(KeyType::Rsa),
+        );
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(
+            inject(&mut server),
+            Some(Error::PeerMisbehaved(
+                PeerMisbehaved::ApplicationDataDuringHandshake
+            ))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::AlertReceived(
+                rustls::AlertDescription::UnexpectedMessage
+            ))
+        );
     }
-
-    fn flush(&self) {}
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_no_warning_logging_during_successful_sessions() {
-    CountingLogger::install();
-    CountingLogger::reset();
+fn server_require_sni_rejects_client_without_sni() {
+    for (version, alert) in [
+        (
+            &rustls::version::TLS12,
+            rustls::AlertDescription::HandshakeFailure,
+        ),
+        (
+            &rustls::version::TLS13,
+            rustls::AlertDescription::MissingExtension,
+        ),
+    ] {
+        for require_sni in [false, true] {
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.enable_sni = false;
+            let server_config = make_server_config(KeyType::Rsa).with_require_sni(require_sni);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_configs(client_config, make_server_config(*kt));
-            do_handshake(&mut client, &mut server);
+            let result = do_handshake_until_error(&mut client, &mut server);
+            if !require_sni {
+                assert!(result.is_ok());
+                continue;
+            }
+
+            assert_eq!(
+                result,
+                Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+                    PeerIncompatible::ServerNameExtensionRequired
+                )))
+            );
+            transfer(&mut server, &mut client);
+            assert_eq!(
+                client.process_new_packets().err(),
+                Some(Error::AlertReceived(alert))
+            );
         }
     }
+}
 
-    if cfg!(feature = "logging") {
-        COUNTS.with(|c| {
-            println!("After tests: {:?}", c.borrow());
-            assert_eq!(c.borrow().warn, 0);
-            assert_eq!(c.borrow().error, 0);
-            assert_eq!(c.borrow().info, 0);
-            assert!(c.borrow().trace > 0);
-            assert!(c.borrow().debug > 0);
-        });
-    } else {
-        COUNTS.with(|c| {
-            println!("After tests: {:?}", c.borrow());
-            assert_eq!(c.borrow().warn, 0);
-            assert_eq!(c.borrow().error, 0);
-            assert_eq!(c.borrow().info, 0);
-            assert_eq!(c.borrow().trace, 0);
-            assert_eq!(c.borrow().debug, 0);
-        });
+#[test]
+fn server_require_sni_accepts_client_with_sni() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa).with_require_sni(true);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.server_name(), Some("localhost"));
     }
 }
 
-/// Test that secrets can be extracted and used for encryption/decryption.
-#[cfg(feature = "secret_extraction")]
 #[test]
-fn test_secret_extraction_enabled() {
-    // Normally, secret extraction would be used to configure kTLS (TLS offload
-    // to the kernel). We want this test to run on any platform, though, so
-    // instead we just compare secrets for equality.
-
-    // TLS 1.2 and 1.3 have different mechanisms for key exchange and handshake,
-    // and secrets are stored/extracted differently, so we want to test them both.
-    // We support 3 different AEAD algorithms (AES-128-GCM mode, AES-256-GCM, and
-    // Chacha20Poly1305), so that's 2*3 = 6 combinations to test.
-    let kt = KeyType::Rsa;
-    for suite in [
-        rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
-        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
-        rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ] {
-        let version = suite.version();
-        println!("Testing suite {:?}", suite.suite().as_str());
-
-        // Only offer the cipher suite (and protocol version) that we're testing
-        let mut server_config = ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[version])
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
-            .unwrap();
-        // Opt into secret extraction from both sides
-        server_config.enable_secret_extraction = true;
-        let server_config = Arc::new(server_config);
+fn connections_share_buffer_pool() {
+    let pool = Arc::new(rustls::BufferPool::new(16));
 
-        let mut client_config = make_client_config(kt);
-        client_config.enable_secret_extraction = true;
+    for version in rustls::ALL_VERSIONS {
+        for _ in 0..2 {
+            let client_config = make_client_config_with_versions(KeyType::Rsa, &[version])
+                .with_buffer_pool(pool.clone());
+            let server_config = make_server_config(KeyType::Rsa).with_buffer_pool(pool.clone());
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
 
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            client
+                .writer()
+                .write_all(b"hello")
+                .unwrap();
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+            check_read(&mut server.reader(), b"hello");
+            assert!(!pool.is_empty());
+        }
+    }
+    assert!(pool.len() <= 16);
+}
 
+#[test]
+fn connections_work_without_retained_plaintext_capacity() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version])
+            .with_retained_plaintext_capacity(0);
+        let server_config = make_server_config(KeyType::Rsa).with_retained_plaintext_capacity(0);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
         do_handshake(&mut client, &mut server);
 
-        // The handshake is finished, we're now able to extract traffic secrets
-        let client_secrets = client.extract_secrets().unwrap();
-        let server_secrets = server.extract_secrets().unwrap();
+        for _ in 0..3 {
+            server
+                .writer()
+                .write_all(b"hello")
+                .unwrap();
+            client
+                .writer()
+                .write_all(b"world")
+                .unwrap();
+            transfer(&mut server, &mut client);
+            transfer(&mut client, &mut server);
+            client.process_new_packets().unwrap();
+            server.process_new_packets().unwrap();
+            check_read(&mut client.reader(), b"hello");
+            check_read(&mut server.reader(), b"world");
+        }
+    }
+}
 
-        // Comparing secrets for equality is something you should never have to
-        // do in production code, so ConnectionTrafficSecrets doesn't implement
-        // PartialEq/Eq on purpose. Instead, we have to get creative.
-        fn explode_secrets(s: &ConnectionTrafficSecrets) -> (&[u8], &[u8], &[u8]) {
-            match s {
-                ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => (key, salt, iv),
-                ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => (key, salt, iv),
-                ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => (key, &[], iv),
-                _ => panic!("unexpected secret type"),
+struct RecordUnknownExtensions {
+    seen: Mutex<Vec<(ExtensionType, Vec<u8>)>>,
+    action: rustls::UnknownExtensionAction,
+}
+
+impl rustls::UnknownExtensionHandler for RecordUnknownExtensions {
+    fn handle(&self, typ: ExtensionType, payload: &[u8]) -> rustls::UnknownExtensionAction {
+        self.seen
+            .lock()
+            .unwrap()
+            .push((typ, payload.to_vec()));
+        self.action
+    }
+}
+
+#[test]
+fn server_passes_unknown_extensions_to_handler() {
+    fn add_unknown_extension(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                ch.extensions
+                    .push(ClientExtension::Unknown(UnknownExtension {
+                        typ: ExtensionType::Unknown(0x1234),
+                        payload: Payload::new(b"hello".to_vec()),
+                    }));
             }
+            *encoded = Payload::new(parsed.get_encoding());
         }
+        Altered::InPlace
+    }
 
-        fn assert_secrets_equal(
-            (l_seq, l_sec): (u64, ConnectionTrafficSecrets),
-            (r_seq, r_sec): (u64, ConnectionTrafficSecrets),
-        ) {
-            assert_eq!(l_seq, r_seq);
-            assert_eq!(explode_secrets(&l_sec), explode_secrets(&r_sec));
+    for action in [
+        rustls::UnknownExtensionAction::Ignore,
+        rustls::UnknownExtensionAction::Abort,
+    ] {
+        let handler = Arc::new(RecordUnknownExtensions {
+            seen: Mutex::new(Vec::new()),
+            action,
+        });
+        let server_config = make_server_config(KeyType::Rsa)
+            .with_unknown_extension_handler(Arc::clone(&handler) as _);
+        let (client, server) =
+            make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        let (mut client, mut server) = (client.into(), server.into());
+
+        transfer_altered(&mut client, add_unknown_extension, &mut server);
+        let result = server.process_new_packets();
+        assert_eq!(
+            *handler.seen.lock().unwrap(),
+            vec![(ExtensionType::Unknown(0x1234), b"hello".to_vec())]
+        );
+
+        match action {
+            rustls::UnknownExtensionAction::Ignore => assert!(result.is_ok()),
+            rustls::UnknownExtensionAction::Abort => assert_eq!(
+                result.err(),
+                Some(Error::PeerIncompatible(
+                    PeerIncompatible::UnknownExtensionRejected(ExtensionType::Unknown(0x1234))
+                ))
+            ),
         }
+    }
+}
 
-        assert_secrets_equal(client_secrets.tx, server_secrets.rx);
-        assert_secrets_equal(client_secrets.rx, server_secrets.tx);
+struct ServerResolvesCertifiedKey(Arc<sign::CertifiedKey>);
+
+impl ResolvesServerCert for ServerResolvesCertifiedKey {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
     }
 }
 
-/// Test that secrets cannot be extracted unless explicitly enabled, and until
-/// the handshake is done.
-#[cfg(feature = "secret_extraction")]
 #[test]
-fn test_secret_extraction_disabled_or_too_early() {
-    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
-    let kt = KeyType::Rsa;
+fn client_verifies_delegated_credential_validity() {
+    // end-delegation.cert is valid from 2026-01-01; it is now 2027-06-01.
+    const NOW: u64 = 1_811_808_000;
+    const DAY: u64 = 24 * 60 * 60;
+
+    let chain: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut io::BufReader::new(
+        KeyType::Rsa.bytes_for("end-delegation.fullchain"),
+    ))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+    let cert_key = sign::any_supported_type(&KeyType::Rsa.get_key()).unwrap();
+    let dc_spki = KeyType::Rsa
+        .bytes_for("delegated.spki")
+        .to_vec();
+    let dc_key = sign::any_ecdsa_type(&rustls::PrivateKey(
+        rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
+            KeyType::Rsa.bytes_for("delegated.key"),
+        ))
+        .unwrap()
+        .remove(0),
+    ))
+    .unwrap();
 
-    for (server_enable, client_enable) in [(true, false), (false, true)] {
-        let mut server_config = ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
+    let server_config_for = |spki: &Vec<u8>, expiry: u64| {
+        let certified_key = sign::CertifiedKey::new(chain.clone(), Arc::clone(&cert_key))
+            .with_delegated_credential(
+                sign::DelegatedCredential::new(
+                    &chain[0],
+                    cert_key.as_ref(),
+                    Arc::clone(&dc_key),
+                    spki.clone(),
+                    UnixTime::since_unix_epoch(Duration::from_secs(expiry)),
+                )
+                .unwrap(),
+            );
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
-            .unwrap();
-        server_config.enable_secret_extraction = server_enable;
-        let server_config = Arc::new(server_config);
+            .with_cert_resolver(Arc::new(ServerResolvesCertifiedKey(Arc::new(
+                certified_key,
+            ))))
+    };
+    let client_config = || {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13])
+                .with_delegated_credentials(true);
+        client_config.time_provider = FixedTime::at(NOW);
+        client_config
+    };
 
-        let mut client_config = make_client_config(kt);
-        client_config.enable_secret_extraction = client_enable;
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(), server_config_for(&dc_spki, NOW + 2 * DAY));
+    do_handshake(&mut client, &mut server);
 
-        let client_config = Arc::new(client_config);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(), server_config_for(&dc_spki, NOW - DAY));
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::PeerMisbehaved(
+            PeerMisbehaved::ExpiredDelegatedCredential
+        )))
+    );
 
-        let (client, server) = make_pair_for_arc_configs(&client_config, &server_config);
+    // Clients which don't offer delegated credentials get the certificate's
+    // signature instead.
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config().with_delegated_credentials(false),
+        server_config_for(&dc_spki, NOW - DAY),
+    );
+    do_handshake(&mut client, &mut server);
 
-        assert!(
-            client.extract_secrets().is_err(),
-            "extraction should fail until handshake completes"
-        );
-        assert!(
-            server.extract_secrets().is_err(),
-            "extraction should fail until handshake completes"
-        );
+    // The credential's key is ECDSA P-256, so it can't be presented as an
+    // Ed25519 key: keep the key, but change its AlgorithmIdentifier.
+    let mut ed25519_spki = vec![
+        0x30, 0x4b, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x42, 0x00,
+    ];
+    ed25519_spki.extend_from_slice(&dc_spki[dc_spki.len() - 65..]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(), server_config_for(&ed25519_spki, NOW + DAY));
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+            CertificateError::BadSignature
+        )))
+    );
+}
+
+#[test]
+fn client_never_reuses_key_shares() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS13],
+    ));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
 
+    let mut key_shares = Vec::new();
+    for resumed in [false, true, true] {
         let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
 
-        do_handshake(&mut client, &mut server);
+        let mut client_hello = Vec::new();
+        client
+            .write_tls(&mut client_hello)
+            .unwrap();
+        let ch = parse_client_hello(&client_hello);
+        key_shares.push(
+            ch.get_keyshare_extension().unwrap()[0]
+                .payload
+                .0
+                .clone(),
+        );
+        assert_eq!(ch.get_psk().is_some(), resumed);
 
-        assert_eq!(server_enable, server.extract_secrets().is_ok());
-        assert_eq!(client_enable, client.extract_secrets().is_ok());
+        server
+            .read_tls(&mut &client_hello[..])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.is_resumed(), resumed);
     }
+
+    assert_ne!(key_shares[0], key_shares[1]);
+    assert_ne!(key_shares[0], key_shares[2]);
+    assert_ne!(key_shares[1], key_shares[2]);
 }
 
 #[test]
-fn test_received_plaintext_backpressure() {
-    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
-    let kt = KeyType::Rsa;
+fn client_rejects_too_many_server_certificates() {
+    // The end-entity certificate, then its issuer 49 times.
+    let chain = KeyType::Ecdsa.get_chain();
+    let mut long_chain = vec![chain[0].clone()];
+    long_chain.resize(50, chain[1].clone());
 
     let server_config = Arc::new(
         ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
+            .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
+            .with_single_cert(long_chain, KeyType::Ecdsa.get_key())
             .unwrap(),
     );
 
-    let client_config = Arc::new(make_client_config(kt));
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+    // The server's flight is too big to buffer all at once, so the client
+    // must process it as it arrives.
+    let client_error = |client_config: ClientConfig<Ring>| {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
 
-    // Fill the server's received plaintext buffer with 16k bytes
-    let client_buf = [0; 16_385];
-    dbg!(client
-        .writer()
-        .write(&client_buf)
-        .unwrap());
-    let mut network_buf = Vec::with_capacity(32_768);
-    let sent = dbg!(client
-        .write_tls(&mut network_buf)
-        .unwrap());
-    let mut read = 0;
-    while read < sent {
-        let new = dbg!(server
-            .read_tls(&mut &network_buf[read..sent])
-            .unwrap());
-        if new == 4096 {
-            read += new;
-        } else {
-            break;
+        let mut server_flight = Vec::new();
+        while server.wants_write() {
+            server
+                .write_tls(&mut server_flight)
+                .unwrap();
+        }
+        let mut server_flight = &server_flight[..];
+        while !server_flight.is_empty() {
+            client
+                .read_tls(&mut server_flight)
+                .unwrap();
+            if let Err(err) = client.process_new_packets() {
+                return err;
+            }
         }
+        panic!("client accepted the server's flight");
+    };
+
+    for version in rustls::ALL_VERSIONS {
+        assert_eq!(
+            client_error(make_client_config_with_versions(KeyType::Ecdsa, &[version])),
+            Error::PeerMisbehaved(PeerMisbehaved::TooManyCertificates)
+        );
+
+        // At the limit, the chain gets as far as the verifier, which has
+        // its own limit on chain depth.
+        assert_eq!(
+            client_error(
+                make_client_config_with_versions(KeyType::Ecdsa, &[version])
+                    .with_max_certificate_chain_len(50)
+            ),
+            Error::InvalidCertificate(CertificateError::ChainTooLong)
+        );
     }
-    server.process_new_packets().unwrap();
+}
 
-    // Send two more bytes from client to server
-    dbg!(client
-        .writer()
-        .write(&client_buf[..2])
-        .unwrap());
-    let sent = dbg!(client
-        .write_tls(&mut network_buf)
-        .unwrap());
+#[test]
+fn raw_public_keys_authenticate_both_peers() {
+    let kt = KeyType::Ecdsa;
+    let server_spki = kt.bytes_for("end.spki");
+    let client_spki = kt.bytes_for("client.spki");
+    let server_key = sign::RawPublicKey::new(
+        sign::any_supported_type(&kt.get_key()).unwrap(),
+        server_spki.to_vec(),
+    );
+    let client_key = sign::RawPublicKey::new(
+        sign::any_supported_type(&kt.get_client_key()).unwrap(),
+        client_spki.to_vec(),
+    );
 
-    // Get an error because the received plaintext buffer is full
-    assert!(server
-        .read_tls(&mut &network_buf[..sent])
-        .is_err());
+    let client_config = |pinned: &[u8]| {
+        make_client_config_with_versions(kt, &[&rustls::version::TLS13])
+            .with_pinned_server_keys(vec![pinned.to_vec()])
+            .with_client_raw_public_key(client_key.clone())
+    };
+    let server_config = |pinned: &[u8]| {
+        make_server_config(kt)
+            .with_raw_public_key(server_key.clone())
+            .with_pinned_client_keys(vec![pinned.to_vec()])
+    };
 
-    // Read out some of the plaintext
-    server
-        .reader()
-        .read_exact(&mut [0; 2])
-        .unwrap();
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(server_spki), server_config(client_spki));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.peer_certificates(),
+        Some(&[rustls::Certificate(server_spki.to_vec())][..])
+    );
+    assert_eq!(
+        server.peer_certificates(),
+        Some(&[rustls::Certificate(client_spki.to_vec())][..])
+    );
 
-    // Now there's room again in the plaintext buffer
+    // Each peer rejects a key it didn't pin.
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(client_spki), server_config(client_spki));
     assert_eq!(
-        server
-            .read_tls(&mut &network_buf[..sent])
-            .unwrap(),
-        24
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+            CertificateError::UnknownRawPublicKey
+        )))
     );
-}
 
-#[test]
-fn test_debug_server_name_from_ip() {
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(server_spki), server_config(server_spki));
     assert_eq!(
-        format!(
-            "{:?}",
-            rustls::ServerName::IpAddress("127.0.0.1".parse().unwrap())
-        ),
-        "IpAddress(127.0.0.1)"
-    )
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+            CertificateError::UnknownRawPublicKey
+        )))
+    );
+
+    // Neither falls back to certificates.
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config(server_spki), make_server_config(kt));
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+            PeerIncompatible::NoCertificateTypesInCommon
+        )))
+    );
+
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_versions(kt, &[&rustls::version::TLS13]),
+        server_config(client_spki),
+    );
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+            PeerIncompatible::NoCertificateTypesInCommon
+        )))
+    );
 }
 
 #[test]
-fn test_debug_server_name_from_string() {
+fn certificate_types_follow_preferences() {
+    let kt = KeyType::Ecdsa;
+    let server_spki = kt.bytes_for("end.spki");
+    let server_key = sign::RawPublicKey::new(
+        sign::any_supported_type(&kt.get_key()).unwrap(),
+        server_spki.to_vec(),
+    );
+
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13])
+        .with_pinned_server_keys(vec![server_spki.to_vec()])
+        .with_server_cert_types(vec![CertificateType::RawPublicKey, CertificateType::X509]);
+    let server_config = |types: Vec<CertificateType>| {
+        make_server_config(kt)
+            .with_raw_public_key(server_key.clone())
+            .with_server_cert_types(types)
+    };
+
+    // Both prefer raw public keys.
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config.clone(),
+        server_config(vec![CertificateType::RawPublicKey, CertificateType::X509]),
+    );
+    do_handshake(&mut client, &mut server);
     assert_eq!(
-        format!("{:?}", rustls::ServerName::try_from("a.com").unwrap()),
-        "DnsName(\"a.com\")"
-    )
+        client.peer_certificates(),
+        Some(&[rustls::Certificate(server_spki.to_vec())][..])
+    );
+
+    // Otherwise, the server's preference wins, or its only choice.
+    for types in [
+        vec![CertificateType::X509, CertificateType::RawPublicKey],
+        vec![CertificateType::X509],
+    ] {
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config.clone(), server_config(types));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+    }
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, make_server_config(kt));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
 }
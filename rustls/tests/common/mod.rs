@@ -49,12 +49,15 @@ embed_files! {
     (ECDSA_CLIENT_FULLCHAIN, "ecdsa", "client.fullchain");
     (ECDSA_CLIENT_KEY, "ecdsa", "client.key");
     (ECDSA_CLIENT_REQ, "ecdsa", "client.req");
+    (ECDSA_CLIENT_SPKI, "ecdsa", "client.spki");
     (ECDSA_CLIENT_CRL_PEM, "ecdsa", "client.revoked.crl.pem");
     (ECDSA_END_CERT, "ecdsa", "end.cert");
+    (ECDSA_END_DNS_ONLY_FULLCHAIN, "ecdsa", "end-dns-only.fullchain");
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
     (ECDSA_END_REQ, "ecdsa", "end.req");
+    (ECDSA_END_SPKI, "ecdsa", "end.spki");
     (ECDSA_INTER_CERT, "ecdsa", "inter.cert");
     (ECDSA_INTER_KEY, "ecdsa", "inter.key");
     (ECDSA_INTER_REQ, "ecdsa", "inter.req");
@@ -71,6 +74,7 @@ embed_files! {
     (EDDSA_CLIENT_REQ, "eddsa", "client.req");
     (EDDSA_CLIENT_CRL_PEM, "eddsa", "client.revoked.crl.pem");
     (EDDSA_END_CERT, "eddsa", "end.cert");
+    (EDDSA_END_DNS_ONLY_FULLCHAIN, "eddsa", "end-dns-only.fullchain");
     (EDDSA_END_CHAIN, "eddsa", "end.chain");
     (EDDSA_END_FULLCHAIN, "eddsa", "end.fullchain");
     (EDDSA_END_KEY, "eddsa", "end.key");
@@ -89,10 +93,18 @@ embed_files! {
     (RSA_CLIENT_REQ, "rsa", "client.req");
     (RSA_CLIENT_RSA, "rsa", "client.rsa");
     (RSA_CLIENT_CRL_PEM, "rsa", "client.revoked.crl.pem");
+    (RSA_DELEGATED_KEY, "rsa", "delegated.key");
+    (RSA_DELEGATED_SPKI, "rsa", "delegated.spki");
     (RSA_END_CERT, "rsa", "end.cert");
+    (RSA_END_DELEGATION_FULLCHAIN, "rsa", "end-delegation.fullchain");
+    (RSA_END_DNS_ONLY_FULLCHAIN, "rsa", "end-dns-only.fullchain");
     (RSA_END_CHAIN, "rsa", "end.chain");
     (RSA_END_FULLCHAIN, "rsa", "end.fullchain");
     (RSA_END_KEY, "rsa", "end.key");
+    (RSA_END_MUST_STAPLE_DECOY_FULLCHAIN, "rsa", "end-must-staple-decoy.fullchain");
+    (RSA_END_MUST_STAPLE_DECOY_OCSP, "rsa", "end-must-staple-decoy.ocsp");
+    (RSA_END_MUST_STAPLE_FULLCHAIN, "rsa", "end-must-staple.fullchain");
+    (RSA_END_MUST_STAPLE_OCSP, "rsa", "end-must-staple.ocsp");
     (RSA_END_REQ, "rsa", "end.req");
     (RSA_END_RSA, "rsa", "end.rsa");
     (RSA_INTER_CERT, "rsa", "inter.cert");
@@ -193,7 +205,7 @@ pub enum KeyType {
 pub static ALL_KEY_TYPES: [KeyType; 3] = [KeyType::Rsa, KeyType::Ecdsa, KeyType::Ed25519];
 
 impl KeyType {
-    fn bytes_for(&self, part: &str) -> &'static [u8] {
+    pub fn bytes_for(&self, part: &str) -> &'static [u8] {
         match self {
             Self::Rsa => bytes_for("rsa", part),
             Self::Ecdsa => bytes_for("ecdsa", part),
@@ -209,6 +221,16 @@ impl KeyType {
             .collect()
     }
 
+    pub fn get_dns_only_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            self.bytes_for("end-dns-only.fullchain"),
+        ))
+        .unwrap()
+        .iter()
+        .map(|v| Certificate(v.clone()))
+        .collect()
+    }
+
     pub fn get_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(self.bytes_for("end.key")))
@@ -237,7 +259,7 @@ impl KeyType {
         )
     }
 
-    fn get_client_key(&self) -> PrivateKey {
+    pub fn get_client_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
                 self.bytes_for("client.key"),
@@ -5,14 +5,19 @@
 mod common;
 use crate::common::{
     do_handshake, do_handshake_until_both_error, make_client_config_with_versions,
-    make_pair_for_arc_configs, make_server_config, ErrorFromPeer, ALL_KEY_TYPES,
+    make_pair_for_arc_configs, make_server_config, ErrorFromPeer, KeyType, ALL_KEY_TYPES,
 };
 use rustls::client::{
     HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
 };
-use rustls::DigitallySignedStruct;
-use rustls::{AlertDescription, Certificate, Error, InvalidMessage, SignatureScheme};
-use std::sync::Arc;
+use rustls::crypto::ring::Ring;
+use rustls::server::ResolvesServerCertUsingSni;
+use rustls::{sign, DigitallySignedStruct, ServerConfig};
+use rustls::{
+    AlertDescription, Certificate, CertificateError, Error, InvalidMessage, ProtocolVersion,
+    SignatureScheme,
+};
+use std::sync::{Arc, Mutex};
 
 #[test]
 fn client_can_override_certificate_verification() {
@@ -153,11 +158,130 @@ fn client_can_override_certificate_verification_and_offer_no_signature_schemes()
     }
 }
 
+/// A server config whose certificate has stapled OCSP responses for
+/// the end-entity and for each intermediate.
+#[cfg(feature = "tls12")]
+fn make_server_config_with_chain_ocsp(kt: KeyType) -> Arc<ServerConfig<Ring>> {
+    let chain = kt.get_chain();
+    let mut certified_key = sign::CertifiedKey::new(
+        chain.clone(),
+        sign::any_supported_type(&kt.get_key()).unwrap(),
+    )
+    .with_intermediate_ocsp(vec![b"intermediate ocsp".to_vec(); chain.len() - 1]);
+    certified_key.ocsp = Some(b"end-entity ocsp".to_vec());
+
+    let mut resolver = ResolvesServerCertUsingSni::new();
+    resolver
+        .add("localhost", certified_key)
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    Arc::new(server_config)
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_verifies_intermediate_ocsp_responses_in_tls12() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_chain_ocsp(*kt);
+        let intermediates = kt.get_chain().split_off(1);
+
+        for version in rustls::ALL_VERSIONS {
+            let verifier = Arc::new(MockServerVerifier::accepts_anything());
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(
+                *verifier.ocsp_responses.lock().unwrap(),
+                vec![b"end-entity ocsp".to_vec()]
+            );
+            let expected = match version.version {
+                ProtocolVersion::TLSv1_2 => intermediates
+                    .iter()
+                    .map(|cert| (cert.clone(), b"intermediate ocsp".to_vec()))
+                    .collect(),
+                _ => vec![],
+            };
+            assert_eq!(
+                *verifier
+                    .intermediate_ocsp_responses
+                    .lock()
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_only_requests_intermediate_ocsp_responses_it_checks() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_chain_ocsp(*kt);
+        let verifier = Arc::new(MockServerVerifier::ignores_intermediate_ocsp());
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(
+            *verifier.ocsp_responses.lock().unwrap(),
+            vec![b"end-entity ocsp".to_vec()]
+        );
+        assert!(verifier
+            .intermediate_ocsp_responses
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_can_reject_intermediate_ocsp_responses_in_tls12() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_chain_ocsp(*kt);
+        let verifier = Arc::new(MockServerVerifier::rejects_intermediate_ocsp(
+            Error::InvalidCertificate(CertificateError::Revoked),
+        ));
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(verifier);
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+        assert_eq!(
+            errs,
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCertificate(CertificateError::Revoked)),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::CertificateRevoked)),
+            ]),
+        );
+    }
+}
+
 pub struct MockServerVerifier {
     cert_rejection_error: Option<Error>,
+    intermediate_ocsp_error: Option<Error>,
+    requests_intermediate_ocsp: bool,
     tls12_signature_error: Option<Error>,
     tls13_signature_error: Option<Error>,
     signature_schemes: Vec<SignatureScheme>,
+    ocsp_responses: Mutex<Vec<Vec<u8>>>,
+    intermediate_ocsp_responses: Mutex<Vec<(Certificate, Vec<u8>)>>,
 }
 
 impl ServerCertVerifier for MockServerVerifier {
@@ -173,6 +297,10 @@ impl ServerCertVerifier for MockServerVerifier {
             "verify_server_cert({:?}, {:?}, {:?}, {:?}, {:?})",
             end_entity, intermediates, server_name, oscp_response, now
         );
+        self.ocsp_responses
+            .lock()
+            .unwrap()
+            .push(oscp_response.to_vec());
         if let Some(error) = &self.cert_rejection_error {
             Err(error.clone())
         } else {
@@ -180,6 +308,30 @@ impl ServerCertVerifier for MockServerVerifier {
         }
     }
 
+    fn verify_intermediate_ocsp_response(
+        &self,
+        cert: &Certificate,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<(), Error> {
+        println!(
+            "verify_intermediate_ocsp_response({:?}, {:?}, {:?})",
+            cert, ocsp_response, now
+        );
+        self.intermediate_ocsp_responses
+            .lock()
+            .unwrap()
+            .push((cert.clone(), ocsp_response.to_vec()));
+        match &self.intermediate_ocsp_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn requests_intermediate_ocsp_responses(&self) -> bool {
+        self.requests_intermediate_ocsp
+    }
+
     fn verify_tls12_signature(
         &self,
         message: &[u8],
@@ -234,6 +386,20 @@ impl MockServerVerifier {
         }
     }
 
+    pub fn rejects_intermediate_ocsp(err: Error) -> Self {
+        MockServerVerifier {
+            intermediate_ocsp_error: Some(err),
+            ..Default::default()
+        }
+    }
+
+    pub fn ignores_intermediate_ocsp() -> Self {
+        MockServerVerifier {
+            requests_intermediate_ocsp: false,
+            ..Default::default()
+        }
+    }
+
     pub fn rejects_tls12_signatures(err: Error) -> Self {
         MockServerVerifier {
             tls12_signature_error: Some(err),
@@ -260,9 +426,13 @@ impl Default for MockServerVerifier {
     fn default() -> Self {
         MockServerVerifier {
             cert_rejection_error: None,
+            intermediate_ocsp_error: None,
+            requests_intermediate_ocsp: true,
             tls12_signature_error: None,
             tls13_signature_error: None,
             signature_schemes: WebPkiVerifier::verification_schemes(),
+            ocsp_responses: Mutex::new(Vec::new()),
+            intermediate_ocsp_responses: Mutex::new(Vec::new()),
         }
     }
 }
@@ -156,6 +156,16 @@ for kt in rsa ecdsa eddsa ; do
             -set_serial 456 \
             -extensions v3_end -extfile openssl.cnf
 
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end-dns-only.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 457 \
+            -extensions v3_end_dns_only -extfile openssl.cnf
+
   openssl x509 -req \
             -in $kt/client.req \
             -out $kt/client.cert \
@@ -195,6 +205,7 @@ for kt in rsa ecdsa eddsa ; do
 
   cat $kt/inter.cert $kt/ca.cert > $kt/end.chain
   cat $kt/end.cert $kt/inter.cert $kt/ca.cert > $kt/end.fullchain
+  cat $kt/end-dns-only.cert $kt/inter.cert $kt/ca.cert > $kt/end-dns-only.fullchain
 
   cat $kt/inter.cert $kt/ca.cert > $kt/client.chain
   cat $kt/client.cert $kt/inter.cert $kt/ca.cert > $kt/client.fullchain
@@ -202,6 +213,166 @@ for kt in rsa ecdsa eddsa ; do
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
 
+# A longer ECDSA chain, with three intermediates, for testing chain depth limits.
+for i in 3 4 ; do
+  openssl req -nodes \
+            -newkey ec:ecdsa/nistp256.pem \
+            -keyout ecdsa/inter$i.key \
+            -out ecdsa/inter$i.req \
+            -sha256 \
+            -batch \
+            -subj "/CN=ponytown ECDSA level $i intermediate"
+done
+
+openssl x509 -req \
+          -in ecdsa/inter3.req \
+          -out ecdsa/inter3.cert \
+          -CA ecdsa/inter.cert \
+          -CAkey ecdsa/inter.key \
+          -sha256 \
+          -days 3650 \
+          -set_serial 1003 \
+          -extensions v3_inter -extfile openssl.cnf
+
+openssl x509 -req \
+          -in ecdsa/inter4.req \
+          -out ecdsa/inter4.cert \
+          -CA ecdsa/inter3.cert \
+          -CAkey ecdsa/inter3.key \
+          -sha256 \
+          -days 3650 \
+          -set_serial 1004 \
+          -extensions v3_inter -extfile openssl.cnf
+
+openssl x509 -req \
+          -in ecdsa/end.req \
+          -out ecdsa/end-long-chain.cert \
+          -CA ecdsa/inter4.cert \
+          -CAkey ecdsa/inter4.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 1005 \
+          -extensions v3_end -extfile openssl.cnf
+
+cat ecdsa/end-long-chain.cert ecdsa/inter4.cert ecdsa/inter3.cert ecdsa/inter.cert > ecdsa/end-long-chain.fullchain
+
+# A 1024-bit RSA end-entity certificate, for testing minimum key sizes.
+openssl req -nodes \
+          -newkey rsa:1024 \
+          -keyout rsa/end-weak.key \
+          -out rsa/end-weak.req \
+          -sha256 \
+          -batch \
+          -subj "/CN=testserver.com"
+
+openssl x509 -req \
+          -in rsa/end-weak.req \
+          -out rsa/end-weak.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 458 \
+          -extensions v3_end -extfile openssl.cnf
+
+cat rsa/end-weak.cert rsa/inter.cert rsa/ca.cert > rsa/end-weak.fullchain
+
+# An RSA end-entity certificate with the OCSP must-staple TLS feature, and
+# an OCSP response for it signed by its issuer.
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-must-staple.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 459 \
+          -extensions v3_end_must_staple -extfile openssl.cnf
+
+cat rsa/end-must-staple.cert rsa/inter.cert rsa/ca.cert > rsa/end-must-staple.fullchain
+
+printf 'V\t%s\t\t01CB\tunknown\t/CN=testserver.com\n' \
+  "$(date -u -d "$(openssl x509 -in rsa/end-must-staple.cert -noout -enddate | cut -d= -f2)" +%y%m%d%H%M%SZ)" \
+  > index.txt
+
+openssl ocsp \
+          -issuer rsa/inter.cert \
+          -cert rsa/end-must-staple.cert \
+          -no_nonce \
+          -reqout rsa/end-must-staple.ocsp-req
+
+openssl ocsp \
+          -index index.txt \
+          -rsigner rsa/inter.cert \
+          -rkey rsa/inter.key \
+          -CA rsa/inter.cert \
+          -reqin rsa/end-must-staple.ocsp-req \
+          -resp_no_certs \
+          -ndays 3650 \
+          -respout rsa/end-must-staple.ocsp
+
+rm rsa/end-must-staple.ocsp-req
+
+# A decoy intermediate with the real intermediate's subject but another
+# key, and a "good" OCSP response for end-must-staple signed by it.
+openssl req -nodes \
+          -x509 \
+          -newkey rsa:2048 \
+          -keyout rsa/decoy-inter.key \
+          -out rsa/decoy-inter.cert \
+          -sha256 \
+          -days 2000 \
+          -set_serial 460 \
+          -subj "/CN=ponytown RSA level 2 intermediate" \
+          -extensions v3_inter -config openssl.cnf
+
+cat rsa/end-must-staple.cert rsa/decoy-inter.cert rsa/inter.cert rsa/ca.cert \
+  > rsa/end-must-staple-decoy.fullchain
+
+openssl ocsp \
+          -issuer rsa/decoy-inter.cert \
+          -cert rsa/end-must-staple.cert \
+          -no_nonce \
+          -reqout rsa/end-must-staple-decoy.ocsp-req
+
+openssl ocsp \
+          -index index.txt \
+          -rsigner rsa/decoy-inter.cert \
+          -rkey rsa/decoy-inter.key \
+          -CA rsa/decoy-inter.cert \
+          -reqin rsa/end-must-staple-decoy.ocsp-req \
+          -resp_no_certs \
+          -ndays 3650 \
+          -respout rsa/end-must-staple-decoy.ocsp
+
+rm rsa/end-must-staple-decoy.ocsp-req rsa/decoy-inter.key
+
+# An RSA end-entity certificate which may issue delegated credentials
+# (RFC 9345), valid from a fixed date so tests can work out credential
+# lifetimes, and an ECDSA key to delegate to.
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-delegation.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -not_before 20260101000000Z \
+          -not_after 20310101000000Z \
+          -set_serial 460 \
+          -extensions v3_end_delegation -extfile openssl.cnf
+
+cat rsa/end-delegation.cert rsa/inter.cert rsa/ca.cert > rsa/end-delegation.fullchain
+
+openssl genpkey -algorithm EC \
+          -pkeyopt ec_paramgen_curve:P-256 \
+          -out rsa/delegated.key
+openssl pkey -in rsa/delegated.key -pubout -outform DER -out rsa/delegated.spki
+
+# The ECDSA end-entity and client keys' SubjectPublicKeyInfos, for
+# authenticating with raw public keys (RFC 7250).
+openssl pkey -in ecdsa/end.key -pubout -outform DER -out ecdsa/end.spki
+openssl pkey -in ecdsa/client.key -pubout -outform DER -out ecdsa/client.spki
+
 # Tidy up openssl CA state.
 rm index.txt* || true
 rm crlnumber* || true